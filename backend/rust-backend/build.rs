@@ -0,0 +1,21 @@
+use std::path::PathBuf;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // protox compiles the schema in-process, so no system protoc is needed.
+    let fds = protox::compile(["proto/eeg.proto"], ["proto"])?;
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(fds.clone())?;
+    // The client of the integration tests, kept out of the server's module.
+    let client_dir = PathBuf::from(std::env::var("OUT_DIR")?).join("client");
+    std::fs::create_dir_all(&client_dir)?;
+    tonic_build::configure()
+        .build_server(false)
+        .out_dir(client_dir)
+        .compile_fds(fds)?;
+    println!("cargo:rerun-if-changed=proto/eeg.proto");
+    // sqlx::migrate! embeds the directory; pick up added migration files.
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=sqlite-migrations");
+    Ok(())
+}
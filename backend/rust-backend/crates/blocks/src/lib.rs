@@ -0,0 +1,238 @@
+//! The encoding of `sample_blocks`, the packed storage of raw samples: the
+//! samples of one channel over a span of time in one byte string.
+//!
+//! Timestamps and sample ids are stored as the change of their difference
+//! to the previous one, which is 0 for evenly sampled channels, and values
+//! as their bits XOR-ed with the previous value's, as in Gorilla (Pelkonen
+//! et al., 2015): a repeated value takes one bit, and a slowly varying one
+//! only the bits that changed. Values decode to the same bits.
+//!
+//! A block is a version byte, the number of samples and the first sample's
+//! timestamp and id as varints, the changes of timestamp and id differences
+//! as zigzag varints, then the bit stream of the values.
+
+/// Format written by [`encode`].
+pub const VERSION: u8 = 1;
+
+/// A stored sample of a block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    /// Its id in `eeg_samples` before it was packed.
+    pub id: i32,
+    /// Microseconds since the Unix epoch.
+    pub ts_us: i64,
+    pub value: f64,
+}
+
+/// Packs `points`, oldest first, into a block.
+pub fn encode(points: &[Point]) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    put_varint(&mut out, points.len() as u64);
+    let Some(first) = points.first() else {
+        return out;
+    };
+    put_signed(&mut out, first.ts_us);
+    put_signed(&mut out, first.id as i64);
+    for field in [|p: &Point| p.ts_us, |p: &Point| p.id as i64] {
+        let mut delta = 0i64;
+        for pair in points.windows(2) {
+            let next = field(&pair[1]).wrapping_sub(field(&pair[0]));
+            put_signed(&mut out, next.wrapping_sub(delta));
+            delta = next;
+        }
+    }
+    let mut bits = BitWriter::new(out);
+    bits.put(first.value.to_bits(), 64);
+    // Leading and trailing zero bits of the last value written in full.
+    let mut window: Option<(u32, u32)> = None;
+    for pair in points.windows(2) {
+        let xor = pair[0].value.to_bits() ^ pair[1].value.to_bits();
+        if xor == 0 {
+            bits.put(0, 1);
+            continue;
+        }
+        bits.put(1, 1);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match window {
+            Some((l, t)) if leading >= l && trailing >= t => {
+                bits.put(0, 1);
+                bits.put(xor >> t, 64 - l - t);
+            }
+            _ => {
+                let len = 64 - leading - trailing;
+                bits.put(1, 1);
+                bits.put(leading as u64, 5);
+                bits.put(len as u64 - 1, 6);
+                bits.put(xor >> trailing, len);
+                window = Some((leading, trailing));
+            }
+        }
+    }
+    bits.finish()
+}
+
+/// Unpacks a block written by [`encode`].
+pub fn decode(block: &[u8]) -> Result<Vec<Point>, String> {
+    let mut bytes = Bytes { data: block, at: 0 };
+    match bytes.byte()? {
+        VERSION => {}
+        version => return Err(format!("unknown block version {}", version)),
+    }
+    let count = usize::try_from(bytes.varint()?).map_err(|_| "too many samples".to_string())?;
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    // Each sample after the first takes at least 2 bytes of differences.
+    if count > block.len() {
+        return Err(format!(
+            "a block of {} bytes holds {} samples",
+            block.len(),
+            count
+        ));
+    }
+    let mut ts = Vec::with_capacity(count);
+    let mut ids = Vec::with_capacity(count);
+    ts.push(bytes.signed()?);
+    ids.push(bytes.signed()?);
+    for column in [&mut ts, &mut ids] {
+        let mut delta = 0i64;
+        for _ in 1..count {
+            delta = delta.wrapping_add(bytes.signed()?);
+            let last = column[column.len() - 1];
+            column.push(last.wrapping_add(delta));
+        }
+    }
+    let mut bits = BitReader {
+        data: &block[bytes.at..],
+        at: 0,
+    };
+    let mut value = bits.take(64)?;
+    let mut values = Vec::with_capacity(count);
+    values.push(value);
+    let mut window: Option<(u32, u32)> = None;
+    for _ in 1..count {
+        if bits.take(1)? == 1 {
+            let (leading, trailing) = match (bits.take(1)?, window) {
+                (0, Some(window)) => window,
+                (0, None) => return Err("value reuses a window before the first".into()),
+                _ => {
+                    let leading = bits.take(5)? as u32;
+                    let len = bits.take(6)? as u32 + 1;
+                    if leading + len > 64 {
+                        return Err("value window out of range".into());
+                    }
+                    let window_now = (leading, 64 - leading - len);
+                    window = Some(window_now);
+                    window_now
+                }
+            };
+            value ^= bits.take(64 - leading - trailing)? << trailing;
+        }
+        values.push(value);
+    }
+    Ok((0..count)
+        .map(|i| Point {
+            id: ids[i] as i32,
+            ts_us: ts[i],
+            value: f64::from_bits(values[i]),
+        })
+        .collect())
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn put_signed(out: &mut Vec<u8>, n: i64) {
+    put_varint(out, ((n << 1) ^ (n >> 63)) as u64);
+}
+
+struct Bytes<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl Bytes<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.data.get(self.at).ok_or("block ends early")?;
+        self.at += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err("varint too long".into())
+    }
+
+    fn signed(&mut self) -> Result<i64, String> {
+        let n = self.varint()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+}
+
+/// Bits appended most significant first after the bytes already written.
+struct BitWriter {
+    out: Vec<u8>,
+    /// Bits free in the last byte of `out`.
+    free: u32,
+}
+
+impl BitWriter {
+    fn new(out: Vec<u8>) -> Self {
+        BitWriter { out, free: 0 }
+    }
+
+    /// Appends the low `len` bits of `bits`.
+    fn put(&mut self, bits: u64, mut len: u32) {
+        while len > 0 {
+            if self.free == 0 {
+                self.out.push(0);
+                self.free = 8;
+            }
+            let n = len.min(self.free);
+            let chunk = (bits >> (len - n)) & ((1u64 << n) - 1);
+            *self.out.last_mut().unwrap() |= (chunk as u8) << (self.free - n);
+            self.free -= n;
+            len -= n;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Bits read.
+    at: usize,
+}
+
+impl BitReader<'_> {
+    /// The next `len` bits, at most 64.
+    fn take(&mut self, mut len: u32) -> Result<u64, String> {
+        let mut bits = 0u64;
+        while len > 0 {
+            let byte = *self.data.get(self.at / 8).ok_or("block ends early")?;
+            let free = 8 - (self.at % 8) as u32;
+            let n = len.min(free);
+            let chunk = (byte as u64 >> (free - n)) & ((1u64 << n) - 1);
+            bits = (bits << n) | chunk;
+            self.at += n as usize;
+            len -= n;
+        }
+        Ok(bits)
+    }
+}
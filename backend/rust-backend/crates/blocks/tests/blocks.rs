@@ -0,0 +1,112 @@
+use eeg_blocks::{decode, encode, Point};
+
+/// A second of a 10 Hz sine at 250 Hz plus a little offset noise off a
+/// 24-bit converter, ids interleaved with three other channels.
+fn recording() -> Vec<Point> {
+    (0..250)
+        .map(|n| {
+            let t = n as f64 / 250.0;
+            let counts =
+                (1000.0 * (std::f64::consts::TAU * 10.0 * t).sin()).round() + (n % 3) as f64;
+            Point {
+                id: 1000 + 4 * n,
+                ts_us: 1_700_000_000_000_000 + 4000 * n as i64,
+                value: counts * 0.02235,
+            }
+        })
+        .collect()
+}
+
+fn assert_same(decoded: &[Point], points: &[Point]) {
+    assert_eq!(decoded.len(), points.len());
+    for (d, p) in decoded.iter().zip(points) {
+        assert_eq!((d.id, d.ts_us), (p.id, p.ts_us));
+        assert_eq!(d.value.to_bits(), p.value.to_bits());
+    }
+}
+
+#[test]
+fn evenly_sampled_channels_round_trip_in_fewer_bytes() {
+    let points = recording();
+    let block = encode(&points);
+    assert_same(&decode(&block).unwrap(), &points);
+    // 20 bytes a sample as an id, a timestamp and a value alone; scaled
+    // values keep most of their mantissa bits.
+    assert!(block.len() < points.len() * 12, "{} bytes", block.len());
+}
+
+#[test]
+fn constant_values_take_a_bit_each() {
+    let points: Vec<Point> = (0..1000)
+        .map(|n| Point {
+            id: n,
+            ts_us: 2000 * n as i64,
+            value: -0.0,
+        })
+        .collect();
+    let block = encode(&points);
+    assert_same(&decode(&block).unwrap(), &points);
+    assert!(
+        block.len() < 2 * 999 + 8 + 999 / 8 + 10,
+        "{} bytes",
+        block.len()
+    );
+}
+
+#[test]
+fn uneven_times_ids_and_special_values_round_trip() {
+    let values = [
+        1.5,
+        f64::NAN,
+        f64::INFINITY,
+        -1e300,
+        5e-324,
+        0.0,
+        1.5,
+        1.5,
+        -2.25,
+    ];
+    let points: Vec<Point> = values
+        .iter()
+        .enumerate()
+        .map(|(n, &value)| Point {
+            id: [7, 9, 8, i32::MAX, -3, 12, 13, 40, 41][n],
+            ts_us: [
+                -5,
+                0,
+                0,
+                17,
+                1_000_000,
+                i64::MAX / 2,
+                i64::MAX / 2 + 1,
+                0,
+                3,
+            ][n],
+            value,
+        })
+        .collect();
+    assert_same(&decode(&encode(&points)).unwrap(), &points);
+}
+
+#[test]
+fn empty_and_single_sample_blocks() {
+    assert!(decode(&encode(&[])).unwrap().is_empty());
+    let point = Point {
+        id: 1,
+        ts_us: 42,
+        value: 3.0,
+    };
+    assert_same(&decode(&encode(&[point])).unwrap(), &[point]);
+}
+
+#[test]
+fn damaged_blocks_are_errors() {
+    let block = encode(&recording());
+    assert!(decode(&block[..block.len() / 2]).is_err());
+    assert!(decode(&[]).is_err());
+    let mut other = block.clone();
+    other[0] = 9;
+    assert_eq!(decode(&other).unwrap_err(), "unknown block version 9");
+    // A count no block of this length could hold.
+    assert!(decode(&[1, 0xff, 0xff, 0x03]).is_err());
+}
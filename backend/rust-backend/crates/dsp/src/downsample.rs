@@ -0,0 +1,101 @@
+//! Server-side downsampling for charts (`/samples?points=N`).
+//!
+//! Both methods pick existing samples rather than computing new ones, so the
+//! returned points keep their ids and exact values:
+//!
+//! - [`Method::Lttb`] (Largest-Triangle-Three-Buckets, Steinarsson 2013)
+//!   keeps the first and last sample and, from each of `N - 2` equal buckets
+//!   in between, the sample forming the largest triangle with the previous
+//!   pick and the average of the next bucket. It preserves the visual shape
+//!   of a line well.
+//! - [`Method::MinMax`] keeps the minimum and maximum of each of `N / 2`
+//!   buckets, which guarantees that every peak is drawn.
+
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = DownsampleMethod)]
+pub enum Method {
+    #[default]
+    Lttb,
+    MinMax,
+}
+
+/// Indexes of at most `n` (at least 2) points of `points` (x ascending), in
+/// order.
+pub fn select(method: Method, points: &[(f64, f64)], n: usize) -> Vec<usize> {
+    if points.len() <= n {
+        return (0..points.len()).collect();
+    }
+    match method {
+        Method::Lttb => lttb(points, n),
+        Method::MinMax => min_max(points, n),
+    }
+}
+
+/// Bounds of bucket `i` of `count` equal buckets over `start..end`.
+fn bucket(i: usize, count: usize, start: usize, end: usize) -> (usize, usize) {
+    let len = end - start;
+    (start + i * len / count, start + (i + 1) * len / count)
+}
+
+fn lttb(points: &[(f64, f64)], n: usize) -> Vec<usize> {
+    let last = points.len() - 1;
+    let buckets = n - 2;
+    let mut picked = Vec::with_capacity(n);
+    picked.push(0);
+    let mut a = 0;
+    for i in 0..buckets {
+        let (start, end) = bucket(i, buckets, 1, last);
+        // Average of the next bucket; the last sample after the final one.
+        let (next_start, next_end) = if i + 1 < buckets {
+            bucket(i + 1, buckets, 1, last)
+        } else {
+            (last, last + 1)
+        };
+        let count = (next_end - next_start) as f64;
+        let (sum_x, sum_y) = points[next_start..next_end]
+            .iter()
+            .fold((0.0, 0.0), |(x, y), p| (x + p.0, y + p.1));
+        let (cx, cy) = (sum_x / count, sum_y / count);
+
+        let (ax, ay) = points[a];
+        let mut best = start;
+        let mut best_area = -1.0;
+        for (j, &(x, y)) in points.iter().enumerate().take(end).skip(start) {
+            let area = ((ax - cx) * (y - ay) - (ax - x) * (cy - ay)).abs();
+            if area > best_area {
+                best_area = area;
+                best = j;
+            }
+        }
+        picked.push(best);
+        a = best;
+    }
+    picked.push(last);
+    picked
+}
+
+fn min_max(points: &[(f64, f64)], n: usize) -> Vec<usize> {
+    let buckets = n / 2;
+    let mut picked = Vec::with_capacity(buckets * 2);
+    for i in 0..buckets {
+        let (start, end) = bucket(i, buckets, 0, points.len());
+        let (mut min, mut max) = (start, start);
+        for j in start..end {
+            if points[j].1 < points[min].1 {
+                min = j;
+            }
+            if points[j].1 > points[max].1 {
+                max = j;
+            }
+        }
+        picked.push(min.min(max));
+        if min != max {
+            picked.push(min.max(max));
+        }
+    }
+    picked
+}
@@ -0,0 +1,317 @@
+//! Butterworth IIR filters as cascaded second-order sections.
+//!
+//! Designs follow the usual analog-prototype route (as SciPy's `butter` with
+//! `output="sos"`): Butterworth poles, a band transformation, and the
+//! bilinear transform with prewarped edges, so the -3 dB points land exactly
+//! on the requested frequencies. [`Filter::filtfilt`] runs the cascade
+//! forward and backward, which cancels the phase shift and squares the
+//! magnitude response, starting from the steady state of an odd extension at
+//! both ends to keep edge transients small. Notches are single sections as
+//! SciPy's `iirnotch`; [`Causal`] runs any filter forward only, sample by
+//! sample, for live data.
+
+use std::f64::consts::PI;
+use std::ops::{Add, Div, Mul, Sub};
+
+/// Highest accepted filter order.
+pub const MAX_ORDER: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    fn real(re: f64) -> Self {
+        Self::new(re, 0.0)
+    }
+
+    fn norm(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    fn sqrt(self) -> Self {
+        let r = self.norm().sqrt();
+        let theta = self.im.atan2(self.re) / 2.0;
+        Self::new(r * theta.cos(), r * theta.sin())
+    }
+
+    fn exp_i(theta: f64) -> Self {
+        Self::new(theta.cos(), theta.sin())
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+    fn add(self, o: Self) -> Self {
+        Self::new(self.re + o.re, self.im + o.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+    fn sub(self, o: Self) -> Self {
+        Self::new(self.re - o.re, self.im - o.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+    fn mul(self, o: Self) -> Self {
+        Self::new(
+            self.re * o.re - self.im * o.im,
+            self.re * o.im + self.im * o.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Self;
+    fn div(self, o: Self) -> Self {
+        let d = o.re * o.re + o.im * o.im;
+        Self::new(
+            (self.re * o.re + self.im * o.im) / d,
+            (self.im * o.re - self.re * o.im) / d,
+        )
+    }
+}
+
+/// `b0 + b1 z^-1 + b2 z^-2` over `1 + a1 z^-1 + a2 z^-2`.
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Section {
+    fn response(&self, z_inv: Complex) -> Complex {
+        let z2 = z_inv * z_inv;
+        let num = Complex::real(self.b[0])
+            + Complex::real(self.b[1]) * z_inv
+            + Complex::real(self.b[2]) * z2;
+        let den =
+            Complex::real(1.0) + Complex::real(self.a[0]) * z_inv + Complex::real(self.a[1]) * z2;
+        num / den
+    }
+
+    /// State after a unit step has settled, and the section's DC gain.
+    fn step_state(&self) -> ([f64; 2], f64) {
+        let gain = (self.b[0] + self.b[1] + self.b[2]) / (1.0 + self.a[0] + self.a[1]);
+        let z2 = self.b[2] - self.a[1] * gain;
+        let z1 = self.b[1] - self.a[0] * gain + z2;
+        ([z1, z2], gain)
+    }
+}
+
+/// A digital IIR filter for one sample rate.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    sections: Vec<Section>,
+}
+
+/// Analog Butterworth prototype poles with a cutoff of 1 rad/s.
+fn prototype(order: usize) -> Vec<Complex> {
+    (0..order)
+        .map(|k| Complex::exp_i(PI * (2 * k + 1 + order) as f64 / (2 * order) as f64))
+        .collect()
+}
+
+/// Second-order sections from digital poles and the zeros of each section.
+fn sections(poles: Vec<Complex>, b: [f64; 3]) -> Vec<Section> {
+    const EPS: f64 = 1e-12;
+    let mut sections = Vec::new();
+    let mut real: Vec<f64> = Vec::new();
+    for p in poles {
+        if p.im > EPS {
+            sections.push(Section {
+                b,
+                a: [-2.0 * p.re, p.re * p.re + p.im * p.im],
+            });
+        } else if p.im.abs() <= EPS {
+            real.push(p.re);
+        }
+    }
+    real.sort_by(f64::total_cmp);
+    for pair in real.chunks(2) {
+        let (p1, p2) = (pair[0], pair.get(1).copied().unwrap_or(0.0));
+        sections.push(Section {
+            b,
+            a: [-(p1 + p2), p1 * p2],
+        });
+    }
+    sections
+}
+
+impl Filter {
+    /// Butterworth bandpass of `order` (per edge, so `2 * order` poles)
+    /// between `low` and `high` Hz.
+    pub fn bandpass(order: usize, low: f64, high: f64, rate: f64) -> Result<Self, String> {
+        if !(1..=MAX_ORDER).contains(&order) {
+            return Err(format!("order must be 1 to {}", MAX_ORDER));
+        }
+        let nyquist = rate / 2.0;
+        if !(low > 0.0 && low < high && high < nyquist) {
+            return Err(format!(
+                "bandpass edges must satisfy 0 < low < high < {} Hz (half the sample rate)",
+                nyquist
+            ));
+        }
+        let fs2 = 2.0 * rate;
+        let w1 = fs2 * (PI * low / rate).tan();
+        let w2 = fs2 * (PI * high / rate).tan();
+        let bw = w2 - w1;
+        let w0 = (w1 * w2).sqrt();
+
+        let mut poles = Vec::with_capacity(2 * order);
+        for p in prototype(order) {
+            let half = p * Complex::real(bw / 2.0);
+            let d = (half * half - Complex::real(w0 * w0)).sqrt();
+            for s in [half + d, half - d] {
+                poles.push((Complex::real(fs2) + s) / (Complex::real(fs2) - s));
+            }
+        }
+        // Zeros at z = 1 (DC) and z = -1 (Nyquist), one of each per section.
+        let mut filter = Self {
+            sections: sections(poles, [1.0, 0.0, -1.0]),
+        };
+        filter.normalize(2.0 * (w0 / fs2).atan());
+        Ok(filter)
+    }
+
+    /// Notch at `freq` Hz with quality factor `q` (centre over -3 dB width),
+    /// repeated at the first `harmonics` multiples below half the sample rate.
+    pub fn notch(freq: f64, q: f64, harmonics: usize, rate: f64) -> Result<Self, String> {
+        let nyquist = rate / 2.0;
+        if !(freq > 0.0 && freq < nyquist) {
+            return Err(format!(
+                "notch must be between 0 and {} Hz (half the sample rate)",
+                nyquist
+            ));
+        }
+        let sections = (1..=harmonics)
+            .map(|h| h as f64 * freq)
+            .take_while(|&f| f < nyquist)
+            .map(|f| {
+                let w0 = 2.0 * PI * f / rate;
+                let gain = 1.0 / (1.0 + (w0 / q / 2.0).tan());
+                let c = -2.0 * w0.cos();
+                Section {
+                    b: [gain, gain * c, gain],
+                    a: [gain * c, 2.0 * gain - 1.0],
+                }
+            })
+            .collect();
+        Ok(Self { sections })
+    }
+
+    /// This filter followed by `next`.
+    pub fn then(mut self, next: Filter) -> Self {
+        self.sections.extend(next.sections);
+        self
+    }
+
+    /// Scales the gain to 1 at `omega` (rad/sample).
+    fn normalize(&mut self, omega: f64) {
+        let gain = self.gain(omega);
+        if gain > 0.0 {
+            for b in &mut self.sections[0].b {
+                *b /= gain;
+            }
+        }
+    }
+
+    /// Magnitude response at `omega` (rad/sample).
+    pub fn gain(&self, omega: f64) -> f64 {
+        let z_inv = Complex::exp_i(-omega);
+        self.sections
+            .iter()
+            .fold(Complex::real(1.0), |h, s| h * s.response(z_inv))
+            .norm()
+    }
+
+    /// Runs the cascade over one sample.
+    fn step(&self, mut v: f64, state: &mut [[f64; 2]]) -> f64 {
+        for (s, z) in self.sections.iter().zip(state.iter_mut()) {
+            let y = s.b[0] * v + z[0];
+            z[0] = s.b[1] * v - s.a[0] * y + z[1];
+            z[1] = s.b[2] * v - s.a[1] * y;
+            v = y;
+        }
+        v
+    }
+
+    /// Runs the cascade over `x` in place, starting from `state`.
+    fn run(&self, x: &mut [f64], mut state: Vec<[f64; 2]>) {
+        for value in x.iter_mut() {
+            *value = self.step(*value, &mut state);
+        }
+    }
+
+    /// Initial state for a signal that has been at `value` forever.
+    fn steady_state(&self, value: f64) -> Vec<[f64; 2]> {
+        let mut scale = value;
+        self.sections
+            .iter()
+            .map(|s| {
+                let (z, gain) = s.step_state();
+                let state = [z[0] * scale, z[1] * scale];
+                scale *= gain;
+                state
+            })
+            .collect()
+    }
+
+    /// Zero-phase filtering of `x` (forward, then backward).
+    pub fn filtfilt(&self, x: &[f64]) -> Vec<f64> {
+        let n = x.len();
+        if n < 2 {
+            return x.to_vec();
+        }
+        let pad = (3 * (2 * self.sections.len() + 1)).min(n - 1);
+        let mut ext = Vec::with_capacity(n + 2 * pad);
+        ext.extend((1..=pad).rev().map(|i| 2.0 * x[0] - x[i]));
+        ext.extend_from_slice(x);
+        ext.extend((1..=pad).map(|i| 2.0 * x[n - 1] - x[n - 1 - i]));
+
+        let state = self.steady_state(ext[0]);
+        self.run(&mut ext, state);
+        ext.reverse();
+        let state = self.steady_state(ext[0]);
+        self.run(&mut ext, state);
+        ext.reverse();
+        ext[pad..pad + n].to_vec()
+    }
+}
+
+/// A filter run forward only, keeping its state between samples. It starts
+/// (and restarts after [`reset`](Self::reset)) as if the signal had been at
+/// its first value forever.
+#[derive(Debug, Clone)]
+pub struct Causal {
+    filter: Filter,
+    state: Option<Vec<[f64; 2]>>,
+}
+
+impl Causal {
+    pub fn new(filter: Filter) -> Self {
+        Self {
+            filter,
+            state: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = None;
+    }
+
+    pub fn step(&mut self, x: f64) -> f64 {
+        let filter = &self.filter;
+        let state = self.state.get_or_insert_with(|| filter.steady_state(x));
+        filter.step(x, state)
+    }
+}
@@ -0,0 +1,338 @@
+//! Independent component analysis of multichannel data.
+//!
+//! [`fit`] centres the data, whitens it with the principal components of its
+//! covariance and runs FastICA (symmetric, logcosh contrast), as
+//! scikit-learn's `FastICA(whiten="unit-variance")`. Components are sorted by
+//! the variance they explain in the channels, and each is signed so that its
+//! largest channel weight is positive.
+
+/// Sweeps of the Jacobi eigenvalue method before it gives up converging.
+const JACOBI_SWEEPS: usize = 100;
+
+/// Principal components whose variance is below this share of the largest
+/// are treated as rank deficiency.
+const RANK_TOLERANCE: f64 = 1e-10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Components to extract, at most the number of channels.
+    pub components: usize,
+    pub max_iter: usize,
+    /// Largest change of an unmixing vector (1 - |cos|) that counts as
+    /// converged.
+    pub tol: f64,
+    /// Seed of the random initial unmixing matrix.
+    pub seed: u64,
+}
+
+/// A fitted decomposition of `n` channels into `k` components.
+#[derive(Debug, Clone)]
+pub struct Decomposition {
+    /// Channel means removed before unmixing.
+    pub mean: Vec<f64>,
+    /// `k x n`: component sources are `unmixing * (x - mean)`.
+    pub unmixing: Vec<Vec<f64>>,
+    /// `n x k`: channel `j` is `mean[j] + sum_i mixing[j][i] * source[i]`, so
+    /// column `i` is the topography of component `i`.
+    pub mixing: Vec<Vec<f64>>,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+impl Decomposition {
+    pub fn components(&self) -> usize {
+        self.unmixing.len()
+    }
+
+    /// Share of the explained variance carried by each component.
+    pub fn variance(&self) -> Vec<f64> {
+        let power: Vec<f64> = (0..self.components())
+            .map(|i| self.mixing.iter().map(|row| row[i] * row[i]).sum())
+            .collect();
+        let total: f64 = power.iter().sum();
+        power.iter().map(|p| p / total).collect()
+    }
+
+    /// Component sources at one instant of channel values.
+    pub fn sources(&self, x: &[f64]) -> Vec<f64> {
+        self.unmixing
+            .iter()
+            .map(|w| {
+                w.iter()
+                    .zip(x.iter().zip(&self.mean))
+                    .map(|(w, (x, m))| w * (x - m))
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Channel values at one instant with the `excluded` components removed.
+    pub fn clean(&self, x: &[f64], excluded: &[usize]) -> Vec<f64> {
+        let mut y = x.to_vec();
+        for &i in excluded {
+            let source: f64 = self.unmixing[i]
+                .iter()
+                .zip(x.iter().zip(&self.mean))
+                .map(|(w, (x, m))| w * (x - m))
+                .sum();
+            for (y, row) in y.iter_mut().zip(&self.mixing) {
+                *y -= row[i] * source;
+            }
+        }
+        y
+    }
+}
+
+/// Eigenvalues and eigenvectors (the columns of the second matrix) of a
+/// symmetric matrix, by cyclic Jacobi rotations.
+fn symmetric_eigen(mut a: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = a.len();
+    let mut v: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+    for _ in 0..JACOBI_SWEEPS {
+        let scale: f64 = (0..n).map(|i| a[i][i] * a[i][i]).sum();
+        let off: f64 = (0..n)
+            .flat_map(|p| (p + 1..n).map(move |q| (p, q)))
+            .map(|(p, q)| a[p][q] * a[p][q])
+            .sum();
+        if off <= f64::EPSILON * f64::EPSILON * scale {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q] == 0.0 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for row in a.iter_mut() {
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = c * kp - s * kq;
+                    row[q] = s * kp + c * kq;
+                }
+                let (upper, lower) = a.split_at_mut(q);
+                for (pk, qk) in upper[p].iter_mut().zip(lower[0].iter_mut()) {
+                    (*pk, *qk) = (c * *pk - s * *qk, s * *pk + c * *qk);
+                }
+                for row in v.iter_mut() {
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = c * kp - s * kq;
+                    row[q] = s * kp + c * kq;
+                }
+            }
+        }
+    }
+    ((0..n).map(|i| a[i][i]).collect(), v)
+}
+
+fn multiply(a: &[Vec<f64>], b: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    a.iter()
+        .map(|row| {
+            (0..b[0].len())
+                .map(|j| row.iter().zip(b).map(|(x, b)| x * b[j]).sum())
+                .collect()
+        })
+        .collect()
+}
+
+fn transpose(a: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    (0..a[0].len())
+        .map(|j| a.iter().map(|row| row[j]).collect())
+        .collect()
+}
+
+/// `(W Wᵀ)^(-1/2) W`: the orthogonal matrix nearest to `w`.
+fn decorrelate(w: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+    let (values, vectors) = symmetric_eigen(multiply(&w, &transpose(&w)));
+    let scaled: Vec<Vec<f64>> = vectors
+        .iter()
+        .map(|row| {
+            row.iter()
+                .zip(&values)
+                .map(|(v, d)| v / d.max(f64::MIN_POSITIVE).sqrt())
+                .collect()
+        })
+        .collect();
+    multiply(&multiply(&scaled, &transpose(&vectors)), &w)
+}
+
+/// Standard normal values from a xorshift generator (Box-Muller).
+struct Gaussian(u64);
+
+impl Gaussian {
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        ((self.0 >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+    }
+
+    fn next(&mut self) -> f64 {
+        let (u, v) = (self.uniform(), self.uniform());
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+}
+
+/// Decomposes `rows` (the values of `n` channels at each instant).
+pub fn fit(rows: &[Vec<f64>], n: usize, options: Options) -> Result<Decomposition, String> {
+    let k = options.components;
+    if n < 2 {
+        return Err("ICA needs at least 2 channels".to_string());
+    }
+    if k == 0 || k > n {
+        return Err(format!("components must be 1 to {}", n));
+    }
+    if rows.len() <= n {
+        return Err(format!(
+            "ICA of {} channels needs more than {} instants with every channel, got {}",
+            n,
+            n,
+            rows.len()
+        ));
+    }
+    let count = rows.len() as f64;
+
+    let mut mean = vec![0.0; n];
+    for row in rows {
+        for (m, x) in mean.iter_mut().zip(row) {
+            *m += x;
+        }
+    }
+    for m in &mut mean {
+        *m /= count;
+    }
+    let mut covariance = vec![vec![0.0; n]; n];
+    for row in rows {
+        let centred: Vec<f64> = row.iter().zip(&mean).map(|(x, m)| x - m).collect();
+        for (sums, xi) in covariance.iter_mut().zip(&centred) {
+            for (sum, xj) in sums.iter_mut().zip(&centred) {
+                *sum += xi * xj;
+            }
+        }
+    }
+    for c in covariance.iter_mut().flatten() {
+        *c /= count;
+    }
+
+    // Principal components, largest variance first.
+    let (values, vectors) = symmetric_eigen(covariance);
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+    let largest = values[order[0]];
+    if !largest.is_finite() || largest <= 0.0 {
+        return Err("the channels are constant".to_string());
+    }
+    let rank = order
+        .iter()
+        .filter(|&&i| values[i] > RANK_TOLERANCE * largest)
+        .count();
+    if k > rank {
+        return Err(format!(
+            "the channels span only {} independent signals; ask for at most {} components",
+            rank, rank
+        ));
+    }
+    // whitening: k x n, dewhitening: n x k.
+    let whitening: Vec<Vec<f64>> = order[..k]
+        .iter()
+        .map(|&c| {
+            let scale = values[c].sqrt();
+            (0..n).map(|j| vectors[j][c] / scale).collect()
+        })
+        .collect();
+    let dewhitening: Vec<Vec<f64>> = (0..n)
+        .map(|j| {
+            order[..k]
+                .iter()
+                .map(|&c| vectors[j][c] * values[c].sqrt())
+                .collect()
+        })
+        .collect();
+    let whitened: Vec<Vec<f64>> = rows
+        .iter()
+        .map(|row| {
+            whitening
+                .iter()
+                .map(|w| {
+                    w.iter()
+                        .zip(row.iter().zip(&mean))
+                        .map(|(w, (x, m))| w * (x - m))
+                        .sum()
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut random = Gaussian(options.seed.max(1));
+    let initial: Vec<Vec<f64>> = (0..k)
+        .map(|_| (0..k).map(|_| random.next()).collect())
+        .collect();
+    let mut w = decorrelate(initial);
+    let mut iterations = 0;
+    let mut converged = false;
+    while iterations < options.max_iter {
+        iterations += 1;
+        // E[z g(wᵀz)] - E[g'(wᵀz)] w, with g = tanh.
+        let mut update = vec![vec![0.0; k]; k];
+        let mut slope = vec![0.0; k];
+        for z in &whitened {
+            for i in 0..k {
+                let g = w[i].iter().zip(z).map(|(w, z)| w * z).sum::<f64>().tanh();
+                slope[i] += 1.0 - g * g;
+                for (u, z) in update[i].iter_mut().zip(z) {
+                    *u += z * g;
+                }
+            }
+        }
+        for i in 0..k {
+            for j in 0..k {
+                update[i][j] = update[i][j] / count - slope[i] / count * w[i][j];
+            }
+        }
+        let next = decorrelate(update);
+        let change = next
+            .iter()
+            .zip(&w)
+            .map(|(a, b)| (a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>().abs() - 1.0).abs())
+            .fold(0.0, f64::max);
+        w = next;
+        if change < options.tol {
+            converged = true;
+            break;
+        }
+    }
+
+    let mut unmixing = multiply(&w, &whitening);
+    let mut mixing = multiply(&dewhitening, &transpose(&w));
+    let power: Vec<f64> = (0..k)
+        .map(|i| mixing.iter().map(|row| row[i] * row[i]).sum())
+        .collect();
+    let mut order: Vec<usize> = (0..k).collect();
+    order.sort_by(|&a, &b| power[b].total_cmp(&power[a]));
+    unmixing = order.iter().map(|&i| unmixing[i].clone()).collect();
+    mixing = mixing
+        .iter()
+        .map(|row| order.iter().map(|&i| row[i]).collect())
+        .collect();
+    for i in 0..k {
+        let peak = mixing
+            .iter()
+            .map(|row| row[i])
+            .fold(0.0, |a: f64, b| if b.abs() > a.abs() { b } else { a });
+        if peak < 0.0 {
+            unmixing[i].iter_mut().for_each(|v| *v = -*v);
+            mixing.iter_mut().for_each(|row| row[i] = -row[i]);
+        }
+    }
+
+    Ok(Decomposition {
+        mean,
+        unmixing,
+        mixing,
+        iterations,
+        converged,
+    })
+}
@@ -0,0 +1,11 @@
+//! Signal processing on plain sample arrays, without I/O: Butterworth and
+//! notch [filters](filter), [spectra](spectrum), [independent component
+//! analysis](ica) and [downsampling](downsample) for charts.
+//!
+//! The backend runs these over windows of stored samples; see its `dsp` and
+//! `analysis` modules.
+
+pub mod downsample;
+pub mod filter;
+pub mod ica;
+pub mod spectrum;
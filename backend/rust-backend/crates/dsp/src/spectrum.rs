@@ -0,0 +1,252 @@
+//! Power spectra of evenly spaced samples.
+//!
+//! [`Stft`] cuts a signal into segments of `nperseg` samples overlapping by
+//! `noverlap`, removes each segment's mean, tapers it with a [`Window`] and
+//! zero-pads it to `nfft` (a power of two) for a radix-2 FFT. Powers are
+//! one-sided densities in unit²/Hz, scaled as SciPy's `welch` with
+//! `scaling="density"`, so integrating over frequency gives the variance.
+
+use serde::Deserialize;
+use std::f64::consts::PI;
+use utoipa::ToSchema;
+
+/// Largest accepted `nfft`.
+pub const MAX_NFFT: usize = 65_536;
+
+/// Taper applied to each segment (periodic, as SciPy's `get_window`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    #[default]
+    Hann,
+    Hamming,
+    Blackman,
+    Boxcar,
+}
+
+impl Window {
+    fn coefficients(self, n: usize) -> Vec<f64> {
+        (0..n)
+            .map(|i| {
+                let x = 2.0 * PI * i as f64 / n as f64;
+                match self {
+                    Window::Hann => 0.5 - 0.5 * x.cos(),
+                    Window::Hamming => 0.54 - 0.46 * x.cos(),
+                    Window::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+                    Window::Boxcar => 1.0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// In-place radix-2 FFT of one length.
+struct Fft {
+    twiddles: Vec<(f64, f64)>,
+    reversed: Vec<usize>,
+}
+
+impl Fft {
+    fn new(n: usize) -> Self {
+        let bits = n.trailing_zeros();
+        let twiddles = (0..n / 2)
+            .map(|k| {
+                let theta = -2.0 * PI * k as f64 / n as f64;
+                (theta.cos(), theta.sin())
+            })
+            .collect();
+        let reversed = (0..n)
+            .map(|i| {
+                if bits == 0 {
+                    0
+                } else {
+                    i.reverse_bits() >> (usize::BITS - bits)
+                }
+            })
+            .collect();
+        Self { twiddles, reversed }
+    }
+
+    fn run(&self, re: &mut [f64], im: &mut [f64]) {
+        let n = re.len();
+        for (i, &j) in self.reversed.iter().enumerate() {
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let stride = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (wr, wi) = self.twiddles[k * stride];
+                    let (a, b) = (start + k, start + k + len / 2);
+                    let tr = re[b] * wr - im[b] * wi;
+                    let ti = re[b] * wi + im[b] * wr;
+                    re[b] = re[a] - tr;
+                    im[b] = im[a] - ti;
+                    re[a] += tr;
+                    im[a] += ti;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+/// Segmenting and transform settings shared by spectra and spectrograms.
+pub struct Stft {
+    window: Vec<f64>,
+    /// `sum(w²)`, for density scaling.
+    energy: f64,
+    step: usize,
+    nfft: usize,
+    fft: Fft,
+}
+
+impl Stft {
+    pub fn new(
+        window: Window,
+        nperseg: usize,
+        noverlap: usize,
+        nfft: usize,
+    ) -> Result<Self, String> {
+        if nperseg < 2 {
+            return Err("nperseg must be at least 2".to_string());
+        }
+        if noverlap >= nperseg {
+            return Err("noverlap must be less than nperseg".to_string());
+        }
+        if !nfft.is_power_of_two() || nfft < nperseg || nfft > MAX_NFFT {
+            return Err(format!(
+                "nfft must be a power of two from nperseg to {}",
+                MAX_NFFT
+            ));
+        }
+        let window = window.coefficients(nperseg);
+        let energy = window.iter().map(|w| w * w).sum();
+        Ok(Self {
+            window,
+            energy,
+            step: nperseg - noverlap,
+            nfft,
+            fft: Fft::new(nfft),
+        })
+    }
+
+    pub fn nperseg(&self) -> usize {
+        self.window.len()
+    }
+
+    pub fn nfft(&self) -> usize {
+        self.nfft
+    }
+
+    /// Start of each segment that fits in `len` samples.
+    pub fn starts(&self, len: usize) -> impl Iterator<Item = usize> {
+        let last = len.checked_sub(self.nperseg());
+        let step = self.step;
+        (0..)
+            .map(move |i| i * step)
+            .take_while(move |&s| last.is_some_and(|l| s <= l))
+    }
+
+    /// Frequency of each bin in Hz, from 0 to `rate / 2`.
+    pub fn frequencies(&self, rate: f64) -> Vec<f64> {
+        (0..=self.nfft / 2)
+            .map(|k| k as f64 * rate / self.nfft as f64)
+            .collect()
+    }
+
+    /// FFT of a segment of `nperseg` samples with its mean removed and the
+    /// window applied, as real and imaginary parts of all `nfft` bins.
+    fn transform(&self, segment: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        let mean = segment.iter().sum::<f64>() / segment.len() as f64;
+        let mut re = vec![0.0; self.nfft];
+        let mut im = vec![0.0; self.nfft];
+        for ((r, x), w) in re.iter_mut().zip(segment).zip(&self.window) {
+            *r = (x - mean) * w;
+        }
+        self.fft.run(&mut re, &mut im);
+        (re, im)
+    }
+
+    /// One-sided power density of a segment of `nperseg` samples.
+    pub fn power(&self, segment: &[f64], rate: f64) -> Vec<f64> {
+        let (re, im) = self.transform(segment);
+        let scale = 1.0 / (rate * self.energy);
+        let nyquist = self.nfft / 2;
+        (0..=nyquist)
+            .map(|k| {
+                let p = (re[k] * re[k] + im[k] * im[k]) * scale;
+                if k == 0 || k == nyquist {
+                    p
+                } else {
+                    2.0 * p
+                }
+            })
+            .collect()
+    }
+
+    /// Welch's estimate: the mean power of every segment of every run, and
+    /// the number of segments; `None` if no run holds a full segment.
+    pub fn welch(&self, runs: &[&[f64]], rate: f64) -> Option<(Vec<f64>, usize)> {
+        let mut sum = vec![0.0; self.nfft / 2 + 1];
+        let mut count = 0;
+        for run in runs {
+            for start in self.starts(run.len()) {
+                let power = self.power(&run[start..start + self.nperseg()], rate);
+                for (s, p) in sum.iter_mut().zip(power) {
+                    *s += p;
+                }
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+        for s in &mut sum {
+            *s /= count as f64;
+        }
+        Some((sum, count))
+    }
+
+    /// Complex coherency `Sxy / sqrt(Sxx * Syy)` of each bin from 0 to
+    /// `rate / 2`, averaging the cross and auto spectra over every segment of
+    /// every pair of equally long runs, and the number of segments; `None`
+    /// in a bin without power on either side, and `None` overall if no run
+    /// holds a full segment.
+    #[allow(clippy::type_complexity)]
+    pub fn coherency(&self, runs: &[(&[f64], &[f64])]) -> Option<(Vec<Option<(f64, f64)>>, usize)> {
+        let bins = self.nfft / 2 + 1;
+        let (mut xx, mut yy) = (vec![0.0; bins], vec![0.0; bins]);
+        let (mut xy_re, mut xy_im) = (vec![0.0; bins], vec![0.0; bins]);
+        let mut count = 0;
+        for (x, y) in runs {
+            for start in self.starts(x.len().min(y.len())) {
+                let end = start + self.nperseg();
+                let (xr, xi) = self.transform(&x[start..end]);
+                let (yr, yi) = self.transform(&y[start..end]);
+                for k in 0..bins {
+                    xx[k] += xr[k] * xr[k] + xi[k] * xi[k];
+                    yy[k] += yr[k] * yr[k] + yi[k] * yi[k];
+                    // x * conj(y)
+                    xy_re[k] += xr[k] * yr[k] + xi[k] * yi[k];
+                    xy_im[k] += xi[k] * yr[k] - xr[k] * yi[k];
+                }
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+        let coherency = (0..bins)
+            .map(|k| {
+                let norm = (xx[k] * yy[k]).sqrt();
+                (norm > 0.0).then(|| (xy_re[k] / norm, xy_im[k] / norm))
+            })
+            .collect();
+        Some((coherency, count))
+    }
+}
@@ -0,0 +1,37 @@
+use eeg_dsp::downsample::{select, Method};
+
+fn wave(n: usize) -> Vec<(f64, f64)> {
+    (0..n)
+        .map(|i| (i as f64, ((i * 7919) % 101) as f64 - 50.0))
+        .collect()
+}
+
+#[test]
+fn short_series_are_kept_whole() {
+    let points = wave(10);
+    assert_eq!(
+        select(Method::Lttb, &points, 20),
+        (0..10).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn lttb_keeps_the_ends_and_picks_in_order() {
+    let points = wave(1000);
+    let picked = select(Method::Lttb, &points, 50);
+    assert_eq!(picked.len(), 50);
+    assert_eq!(picked[0], 0);
+    assert_eq!(*picked.last().unwrap(), 999);
+    assert!(picked.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn min_max_keeps_every_peak() {
+    let mut points = wave(1000);
+    points[437].1 = 1000.0;
+    points[612].1 = -1000.0;
+    let picked = select(Method::MinMax, &points, 40);
+    assert!(picked.len() <= 40);
+    assert!(picked.contains(&437) && picked.contains(&612));
+    assert!(picked.windows(2).all(|w| w[0] < w[1]));
+}
@@ -0,0 +1,68 @@
+use eeg_dsp::filter::{Causal, Filter};
+use std::f64::consts::TAU;
+
+const RATE: f64 = 250.0;
+
+fn sine(freq: f64, seconds: f64) -> Vec<f64> {
+    (0..(seconds * RATE) as usize)
+        .map(|i| (TAU * freq * i as f64 / RATE).sin())
+        .collect()
+}
+
+fn rms(x: &[f64]) -> f64 {
+    (x.iter().map(|v| v * v).sum::<f64>() / x.len() as f64).sqrt()
+}
+
+#[test]
+fn bandpass_edges_are_at_minus_3_db() {
+    let filter = Filter::bandpass(4, 8.0, 12.0, RATE).unwrap();
+    for edge in [8.0, 12.0] {
+        let gain = filter.gain(TAU * edge / RATE);
+        assert!(
+            (gain - 0.5f64.sqrt()).abs() < 1e-6,
+            "gain {} at {} Hz",
+            gain,
+            edge
+        );
+    }
+    assert!((filter.gain(TAU * (96.0f64).sqrt() / RATE) - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn filtfilt_keeps_the_passband_and_removes_the_stopband() {
+    let filter = Filter::bandpass(4, 8.0, 12.0, RATE).unwrap();
+    let alpha = sine(10.0, 8.0);
+    let kept = filter.filtfilt(&alpha);
+    // Away from the edges, where the start-up transients live.
+    let middle = 2 * RATE as usize..6 * RATE as usize;
+    assert!((rms(&kept[middle.clone()]) / rms(&alpha[middle.clone()]) - 1.0).abs() < 0.02);
+
+    let mains = filter.filtfilt(&sine(50.0, 8.0));
+    assert!(rms(&mains[middle]) < 1e-3);
+}
+
+#[test]
+fn notch_removes_mains_and_its_harmonics() {
+    let filter = Filter::notch(50.0, 30.0, 2, RATE).unwrap();
+    assert!(filter.gain(TAU * 50.0 / RATE) < 1e-9);
+    assert!(filter.gain(TAU * 100.0 / RATE) < 1e-9);
+    assert!(filter.gain(TAU * 10.0 / RATE) > 0.99);
+}
+
+#[test]
+fn causal_settles_on_the_filtered_signal() {
+    let mut causal = Causal::new(Filter::notch(50.0, 30.0, 1, RATE).unwrap());
+    let out: Vec<f64> = sine(50.0, 4.0)
+        .into_iter()
+        .map(|x| causal.step(x))
+        .collect();
+    assert!(rms(&out[3 * RATE as usize..]) < 0.01);
+}
+
+#[test]
+fn invalid_designs_are_rejected() {
+    assert!(Filter::bandpass(0, 8.0, 12.0, RATE).is_err());
+    assert!(Filter::bandpass(4, 12.0, 8.0, RATE).is_err());
+    assert!(Filter::bandpass(4, 8.0, 125.0, RATE).is_err());
+    assert!(Filter::notch(150.0, 30.0, 1, RATE).is_err());
+}
@@ -0,0 +1,60 @@
+use eeg_dsp::ica::{fit, Options};
+use std::f64::consts::TAU;
+
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+    let (ma, mb) = (mean(a), mean(b));
+    let cov: f64 = a.iter().zip(b).map(|(x, y)| (x - ma) * (y - mb)).sum();
+    let var = |x: &[f64], m: f64| x.iter().map(|v| (v - m) * (v - m)).sum::<f64>();
+    cov / (var(a, ma) * var(b, mb)).sqrt()
+}
+
+#[test]
+fn separates_mixed_sources() {
+    let n = 2000;
+    let sine: Vec<f64> = (0..n)
+        .map(|i| (TAU * 7.0 * i as f64 / 250.0).sin())
+        .collect();
+    let saw: Vec<f64> = (0..n).map(|i| (i % 50) as f64 / 25.0 - 1.0).collect();
+    let rows: Vec<Vec<f64>> = (0..n)
+        .map(|i| vec![sine[i] + 0.5 * saw[i], 0.6 * sine[i] - saw[i] + 1.0])
+        .collect();
+    let options = Options {
+        components: 2,
+        max_iter: 500,
+        tol: 1e-8,
+        seed: 1,
+    };
+    let decomposition = fit(&rows, 2, options).unwrap();
+    assert!(decomposition.converged);
+
+    let sources: Vec<Vec<f64>> = rows.iter().map(|r| decomposition.sources(r)).collect();
+    for truth in [&sine, &saw] {
+        let best = (0..2)
+            .map(|c| {
+                let s: Vec<f64> = sources.iter().map(|s| s[c]).collect();
+                correlation(&s, truth).abs()
+            })
+            .fold(0.0, f64::max);
+        assert!(best > 0.99, "best correlation {}", best);
+    }
+
+    // Removing nothing gives the channels back.
+    let cleaned = decomposition.clean(&rows[10], &[]);
+    assert!(cleaned
+        .iter()
+        .zip(&rows[10])
+        .all(|(a, b)| (a - b).abs() < 1e-12));
+}
+
+#[test]
+fn too_few_instants_are_rejected() {
+    let rows = vec![vec![0.0, 1.0], vec![1.0, 0.0]];
+    let options = Options {
+        components: 2,
+        max_iter: 10,
+        tol: 1e-6,
+        seed: 1,
+    };
+    assert!(fit(&rows, 2, options).is_err());
+}
@@ -0,0 +1,38 @@
+use eeg_dsp::spectrum::{Stft, Window};
+use std::f64::consts::TAU;
+
+const RATE: f64 = 256.0;
+
+#[test]
+fn welch_peaks_at_the_signal_frequency() {
+    let x: Vec<f64> = (0..4096)
+        .map(|i| 3.0 * (TAU * 10.0 * i as f64 / RATE).sin())
+        .collect();
+    let stft = Stft::new(Window::Hann, 256, 128, 256).unwrap();
+    let (power, segments) = stft.welch(&[&x], RATE).unwrap();
+    assert_eq!(segments, 31);
+    let freqs = stft.frequencies(RATE);
+    let peak = (0..power.len())
+        .max_by(|&a, &b| power[a].total_cmp(&power[b]))
+        .unwrap();
+    assert_eq!(freqs[peak], 10.0);
+
+    // A density: its integral is the variance, 3² / 2.
+    let variance: f64 = power.iter().sum::<f64>() * RATE / stft.nfft() as f64;
+    assert!((variance - 4.5).abs() < 0.05, "variance {}", variance);
+}
+
+#[test]
+fn segments_that_do_not_fit_are_skipped() {
+    let stft = Stft::new(Window::Boxcar, 64, 32, 64).unwrap();
+    assert_eq!(stft.starts(160).collect::<Vec<_>>(), vec![0, 32, 64, 96]);
+    assert!(stft.welch(&[&[0.0; 63]], RATE).is_none());
+}
+
+#[test]
+fn invalid_parameters_are_rejected() {
+    assert!(Stft::new(Window::Hann, 1, 0, 64).is_err());
+    assert!(Stft::new(Window::Hann, 64, 64, 64).is_err());
+    assert!(Stft::new(Window::Hann, 64, 0, 100).is_err());
+    assert!(Stft::new(Window::Hann, 128, 0, 64).is_err());
+}
@@ -0,0 +1,9 @@
+//! File-format pieces of session exports that need no database: laying
+//! stored samples out on the evenly spaced [records](records) EDF+, BDF+,
+//! BrainVision and FIF files are made of, and [unit](units) conversion.
+//!
+//! The writers themselves, which read sessions from the database, are the
+//! backend's `export` module.
+
+pub mod records;
+pub mod units;
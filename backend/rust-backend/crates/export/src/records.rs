@@ -0,0 +1,58 @@
+//! Samples placed on the fixed grid of a data record.
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// A stored sample while exporting.
+#[derive(Debug, Clone)]
+pub struct ExportSample {
+    pub channel: String,
+    pub ts: DateTime<Utc>,
+    pub value: f64,
+}
+
+/// Places the samples of the record starting at `begin` on `points[c]`
+/// evenly spaced points over `seconds` for each channel `c` of `index`: a
+/// sample fills the point nearest its timestamp and empty points repeat the
+/// previous value, which `last` carries across records. Returns the values
+/// per channel.
+pub fn place_record(
+    samples: &[ExportSample],
+    index: &HashMap<&str, usize>,
+    begin: DateTime<Utc>,
+    seconds: f64,
+    points: &[usize],
+    last: &mut [Option<f64>],
+) -> Vec<Vec<f64>> {
+    let mut slots: Vec<Vec<Option<f64>>> = points.iter().map(|&n| vec![None; n]).collect();
+    for sample in samples {
+        let Some(&c) = index.get(sample.channel.as_str()) else {
+            continue;
+        };
+        let n = points[c];
+        let position = seconds_between(begin, sample.ts) / seconds * n as f64;
+        slots[c][(position.round().max(0.0) as usize).min(n - 1)] = Some(sample.value);
+    }
+    slots
+        .into_iter()
+        .zip(last.iter_mut())
+        .map(|(slots, last)| {
+            let mut current = last
+                .or_else(|| slots.iter().flatten().next().copied())
+                .unwrap_or(0.0);
+            let values = slots
+                .into_iter()
+                .map(|slot| {
+                    current = slot.unwrap_or(current);
+                    current
+                })
+                .collect();
+            *last = Some(current);
+            values
+        })
+        .collect()
+}
+
+pub fn seconds_between(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6
+}
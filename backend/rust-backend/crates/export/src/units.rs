@@ -0,0 +1,23 @@
+//! Units of stored channels.
+
+/// Factor from `unit` to volts, if it is a voltage.
+pub fn volts(unit: &str) -> Option<f32> {
+    match unit {
+        "nV" => Some(1e-9),
+        "uV" | "µV" => Some(1e-6),
+        "mV" => Some(1e-3),
+        "V" => Some(1.0),
+        _ => None,
+    }
+}
+
+/// Factor from `unit` to microvolts, if it is a voltage.
+pub fn microvolts(unit: &str) -> Option<f64> {
+    match unit {
+        "nV" => Some(1e-3),
+        "uV" | "µV" => Some(1.0),
+        "mV" => Some(1e3),
+        "V" => Some(1e6),
+        _ => None,
+    }
+}
@@ -0,0 +1,64 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use eeg_export::records::{place_record, seconds_between, ExportSample};
+use eeg_export::units::{microvolts, volts};
+use std::collections::HashMap;
+
+fn start() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap()
+}
+
+fn sample(channel: &str, ms: i64, value: f64) -> ExportSample {
+    ExportSample {
+        channel: channel.to_string(),
+        ts: start() + Duration::milliseconds(ms),
+        value,
+    }
+}
+
+#[test]
+fn samples_fill_their_nearest_point() {
+    let index = HashMap::from([("A3", 0), ("A4", 1)]);
+    let samples = [
+        sample("A3", 0, 1.0),
+        sample("A3", 260, 2.0),
+        sample("A3", 740, 3.0),
+        sample("A4", 510, 9.0),
+        sample("X1", 0, 5.0),
+    ];
+    let mut last = [None, None];
+    let values = place_record(&samples, &index, start(), 1.0, &[4, 2], &mut last);
+    assert_eq!(values, vec![vec![1.0, 2.0, 2.0, 3.0], vec![9.0, 9.0]]);
+    assert_eq!(last, [Some(3.0), Some(9.0)]);
+}
+
+#[test]
+fn empty_points_carry_the_previous_record_over() {
+    let index = HashMap::from([("A3", 0)]);
+    let mut last = [Some(7.0)];
+    let next = start() + Duration::seconds(1);
+    let samples = [sample("A3", 1500, 8.0)];
+    let values = place_record(&samples, &index, next, 1.0, &[4], &mut last);
+    assert_eq!(values, vec![vec![7.0, 7.0, 8.0, 8.0]]);
+
+    let mut none = [None];
+    let values = place_record(&[], &index, start(), 1.0, &[3], &mut none);
+    assert_eq!(values, vec![vec![0.0; 3]]);
+}
+
+#[test]
+fn seconds_and_units() {
+    assert_eq!(
+        seconds_between(start(), start() + Duration::microseconds(2_500_001)),
+        2.500001
+    );
+    assert_eq!(
+        seconds_between(start() + Duration::seconds(1), start()),
+        -1.0
+    );
+    assert_eq!(volts("µV"), Some(1e-6));
+    assert_eq!(volts("uV"), Some(1e-6));
+    assert_eq!(volts("mV"), Some(1e-3));
+    assert_eq!(volts("%"), None);
+    assert_eq!(microvolts("mV"), Some(1000.0));
+    assert_eq!(microvolts("counts"), None);
+}
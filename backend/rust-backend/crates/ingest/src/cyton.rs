@@ -0,0 +1,62 @@
+//! Framing of the OpenBCI Cyton serial stream: 33-byte packets at 250 Hz.
+//!
+//! | byte  | content                                        |
+//! |-------|------------------------------------------------|
+//! | 0     | start byte `0xA0`                              |
+//! | 1     | sample number (wraps at 255)                   |
+//! | 2–25  | 8 channels, 24-bit big-endian two's complement |
+//! | 26–31 | aux data (accelerometer), ignored here         |
+//! | 32    | stop byte `0xC0`–`0xC6`                        |
+
+pub const PACKET_LEN: usize = 33;
+pub const CHANNEL_COUNT: usize = 8;
+pub const SAMPLE_RATE_HZ: f64 = 250.0;
+
+const START_BYTE: u8 = 0xA0;
+
+/// One decoded Cyton packet, still in raw ADC counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CytonPacket {
+    pub sample_number: u8,
+    pub counts: [i32; CHANNEL_COUNT],
+}
+
+fn decode_i24(b: &[u8]) -> i32 {
+    let raw = ((b[0] as i32) << 16) | ((b[1] as i32) << 8) | b[2] as i32;
+    // Sign-extend from 24 bits.
+    (raw << 8) >> 8
+}
+
+/// Incremental packet framer that resynchronises on corrupt bytes.
+#[derive(Debug, Default)]
+pub struct CytonParser {
+    buf: Vec<u8>,
+}
+
+impl CytonParser {
+    /// Appends serial bytes and returns every complete packet found.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<CytonPacket> {
+        self.buf.extend_from_slice(bytes);
+        let mut packets = Vec::new();
+        let mut i = 0;
+        while self.buf.len() - i >= PACKET_LEN {
+            let candidate = &self.buf[i..i + PACKET_LEN];
+            if candidate[0] != START_BYTE || candidate[PACKET_LEN - 1] & 0xF0 != 0xC0 {
+                i += 1;
+                continue;
+            }
+            let mut counts = [0i32; CHANNEL_COUNT];
+            for (ch, count) in counts.iter_mut().enumerate() {
+                let at = 2 + ch * 3;
+                *count = decode_i24(&candidate[at..at + 3]);
+            }
+            packets.push(CytonPacket {
+                sample_number: candidate[1],
+                counts,
+            });
+            i += PACKET_LEN;
+        }
+        self.buf.drain(..i);
+        packets
+    }
+}
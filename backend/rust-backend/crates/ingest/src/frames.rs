@@ -0,0 +1,110 @@
+//! Binary ingest payloads: a fixed header followed by packed f32 frames, as
+//! accepted by `POST /samples/binary` and the MQTT bridge.
+
+use crate::NewSample;
+use chrono::{DateTime, Utc};
+
+/// Size in bytes of the header that precedes binary sample frames.
+pub const FRAME_HEADER_LEN: usize = 16;
+
+/// Only supported version of the binary frame header.
+pub const FRAME_VERSION: u8 = 1;
+
+/// Header of a binary ingest payload. All fields are little-endian:
+///
+/// | offset | type | field                               |
+/// |--------|------|-------------------------------------|
+/// | 0      | u8   | version (1)                         |
+/// | 1      | u8   | reserved, must be 0                 |
+/// | 2      | u16  | channel count                       |
+/// | 4      | f32  | sample rate in Hz                   |
+/// | 8      | i64  | start timestamp, µs since Unix epoch |
+///
+/// The header is followed by frames of `channel_count` packed f32 values,
+/// one frame per sample instant.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeader {
+    pub channel_count: u16,
+    pub sample_rate: f32,
+    pub start_us: i64,
+}
+
+impl FrameHeader {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < FRAME_HEADER_LEN {
+            return Err(format!(
+                "payload shorter than the {}-byte header",
+                FRAME_HEADER_LEN
+            ));
+        }
+        if bytes[0] != FRAME_VERSION {
+            return Err(format!("unsupported frame version {}", bytes[0]));
+        }
+        if bytes[1] != 0 {
+            return Err("reserved header byte must be 0".to_string());
+        }
+        let channel_count = u16::from_le_bytes([bytes[2], bytes[3]]);
+        let sample_rate = f32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let mut start = [0u8; 8];
+        start.copy_from_slice(&bytes[8..16]);
+        let start_us = i64::from_le_bytes(start);
+
+        if channel_count == 0 {
+            return Err("channel count must be at least 1".to_string());
+        }
+        if !sample_rate.is_finite() || sample_rate <= 0.0 {
+            return Err("sample rate must be a positive number".to_string());
+        }
+        Ok(Self {
+            channel_count,
+            sample_rate,
+            start_us,
+        })
+    }
+}
+
+/// Converts a µs-since-epoch instant to a sample timestamp.
+pub fn ts_from_micros(us: i64) -> Option<DateTime<Utc>> {
+    DateTime::<Utc>::from_timestamp_micros(us)
+}
+
+/// Decodes a binary payload into rows, mapping frame columns to `channels`.
+pub fn decode_frames(bytes: &[u8], channels: &[String]) -> Result<Vec<NewSample>, String> {
+    let header = FrameHeader::parse(bytes)?;
+    let count = header.channel_count as usize;
+    if channels.len() != count {
+        return Err(format!(
+            "header declares {} channels but {} channel names were given",
+            count,
+            channels.len()
+        ));
+    }
+
+    let body = &bytes[FRAME_HEADER_LEN..];
+    let frame_len = count * 4;
+    if !body.len().is_multiple_of(frame_len) {
+        return Err(format!(
+            "payload body of {} bytes is not a whole number of {}-byte frames",
+            body.len(),
+            frame_len
+        ));
+    }
+
+    let period_us = 1_000_000.0 / header.sample_rate as f64;
+    let mut samples = Vec::with_capacity(body.len() / 4);
+    for (n, frame) in body.chunks_exact(frame_len).enumerate() {
+        let offset = (n as f64 * period_us).round() as i64;
+        let ts = ts_from_micros(header.start_us + offset)
+            .ok_or_else(|| "start timestamp out of range".to_string())?;
+        for (channel, raw) in channels.iter().zip(frame.chunks_exact(4)) {
+            let value = f32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+            samples.push(NewSample {
+                channel: channel.clone(),
+                ts,
+                value: value as f64,
+                session_id: None,
+            });
+        }
+    }
+    Ok(samples)
+}
@@ -0,0 +1,25 @@
+//! A sample as it enters the backend, and decoders of the wire formats
+//! acquisition hardware sends: [binary frames](frames), [series](series)
+//! of values without timestamps and the [OpenBCI Cyton](cyton) serial
+//! stream.
+//!
+//! Validation against the channel registry and storage are left to the
+//! backend's `ingest` module.
+
+pub mod cyton;
+pub mod frames;
+pub mod series;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewSample {
+    pub channel: String,
+    pub ts: DateTime<Utc>,
+    pub value: f64,
+    /// Recording session the sample belongs to, if any.
+    #[serde(default)]
+    pub session_id: Option<i32>,
+}
@@ -0,0 +1,69 @@
+//! Evenly sampled values without per-sample timestamps, as accepted by
+//! `POST /samples/series`: the values of each channel from one start time
+//! at one sample rate.
+
+use crate::frames::ts_from_micros;
+use crate::NewSample;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+/// A batch of channels sampled at the same instants.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Series {
+    /// Time of the first value of every channel. When omitted, each channel
+    /// continues one sample period after its latest sample in the session.
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    /// Hz; by default that registered for the channel.
+    #[serde(default)]
+    pub sample_rate: Option<f64>,
+    /// Recording session every sample belongs to, if any.
+    #[serde(default)]
+    pub session_id: Option<i32>,
+    pub channels: Vec<ChannelValues>,
+}
+
+/// The values of one channel, oldest first. Channels of a batch may have
+/// fewer values than the others, as when the last frames of some were lost.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChannelValues {
+    pub channel: String,
+    pub values: Vec<f64>,
+}
+
+impl ChannelValues {
+    /// The samples of these values at `sample_rate` Hz from `start`. Each
+    /// time is rounded to the microsecond from `start`, so rounding does not
+    /// add up over a batch.
+    pub fn samples(
+        &self,
+        start: DateTime<Utc>,
+        sample_rate: f64,
+        session_id: Option<i32>,
+    ) -> Result<Vec<NewSample>, String> {
+        let start_us = start.timestamp_micros();
+        let period_us = 1_000_000.0 / sample_rate;
+        self.values
+            .iter()
+            .enumerate()
+            .map(|(n, &value)| {
+                let offset = (n as f64 * period_us).round() as i64;
+                let ts = ts_from_micros(start_us + offset)
+                    .ok_or_else(|| format!("channel {:?} runs out of range", self.channel))?;
+                Ok(NewSample {
+                    channel: self.channel.clone(),
+                    ts,
+                    value,
+                    session_id,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The time one period of `sample_rate` Hz after `ts`, where a channel
+/// continues from its sample at `ts`.
+pub fn next_start(ts: DateTime<Utc>, sample_rate: f64) -> Option<DateTime<Utc>> {
+    ts_from_micros(ts.timestamp_micros() + (1_000_000.0 / sample_rate).round() as i64)
+}
@@ -0,0 +1,53 @@
+use eeg_ingest::cyton::{CytonParser, CHANNEL_COUNT, PACKET_LEN};
+
+fn packet(number: u8, counts: [i32; CHANNEL_COUNT], stop: u8) -> Vec<u8> {
+    let mut bytes = vec![0xA0, number];
+    for count in counts {
+        bytes.extend_from_slice(&count.to_be_bytes()[1..]);
+    }
+    bytes.extend_from_slice(&[0; 6]);
+    bytes.push(stop);
+    assert_eq!(bytes.len(), PACKET_LEN);
+    bytes
+}
+
+const COUNTS: [i32; CHANNEL_COUNT] = [0, 1, -1, 8_388_607, -8_388_608, 1000, -1000, 42];
+
+#[test]
+fn packets_decode_to_signed_counts() {
+    let mut parser = CytonParser::default();
+    let packets = parser.push(&packet(7, COUNTS, 0xC0));
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].sample_number, 7);
+    assert_eq!(packets[0].counts, COUNTS);
+}
+
+#[test]
+fn packets_split_across_reads_are_joined() {
+    let mut parser = CytonParser::default();
+    let bytes = [packet(1, COUNTS, 0xC0), packet(2, COUNTS, 0xC3)].concat();
+    assert!(parser.push(&bytes[..20]).is_empty());
+    let packets = parser.push(&bytes[20..50]);
+    assert_eq!(packets.len(), 1);
+    let packets = parser.push(&bytes[50..]);
+    assert_eq!(
+        packets.iter().map(|p| p.sample_number).collect::<Vec<_>>(),
+        [2]
+    );
+}
+
+#[test]
+fn corrupt_bytes_are_skipped() {
+    let mut parser = CytonParser::default();
+    let mut bytes = vec![0x13, 0xA0, 0x55];
+    bytes.extend(packet(3, COUNTS, 0xC0));
+    // A packet without a valid stop byte is not one.
+    bytes.extend(packet(4, COUNTS, 0x00));
+    bytes.extend(packet(5, COUNTS, 0xC6));
+    let numbers: Vec<u8> = parser
+        .push(&bytes)
+        .iter()
+        .map(|p| p.sample_number)
+        .collect();
+    assert_eq!(numbers, [3, 5]);
+}
@@ -0,0 +1,76 @@
+use eeg_ingest::frames::{decode_frames, FrameHeader, FRAME_HEADER_LEN, FRAME_VERSION};
+
+fn payload(channels: u16, rate: f32, start_us: i64, values: &[f32]) -> Vec<u8> {
+    let mut bytes = vec![FRAME_VERSION, 0];
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&rate.to_le_bytes());
+    bytes.extend_from_slice(&start_us.to_le_bytes());
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|n| n.to_string()).collect()
+}
+
+#[test]
+fn frames_map_to_channels_at_the_sample_rate() {
+    let start_us = 1_700_000_000_000_000;
+    let bytes = payload(2, 250.0, start_us, &[1.0, -1.0, 2.5, -2.5, 4.0, -4.0]);
+    let samples = decode_frames(&bytes, &names(&["A3", "A4"])).unwrap();
+    assert_eq!(samples.len(), 6);
+    let got: Vec<(&str, i64, f64)> = samples
+        .iter()
+        .map(|s| {
+            (
+                s.channel.as_str(),
+                s.ts.timestamp_micros() - start_us,
+                s.value,
+            )
+        })
+        .collect();
+    assert_eq!(
+        got,
+        vec![
+            ("A3", 0, 1.0),
+            ("A4", 0, -1.0),
+            ("A3", 4000, 2.5),
+            ("A4", 4000, -2.5),
+            ("A3", 8000, 4.0),
+            ("A4", 8000, -4.0),
+        ]
+    );
+    assert!(samples.iter().all(|s| s.session_id.is_none()));
+}
+
+#[test]
+fn header_alone_decodes_to_nothing() {
+    let bytes = payload(1, 500.0, 0, &[]);
+    assert_eq!(bytes.len(), FRAME_HEADER_LEN);
+    let header = FrameHeader::parse(&bytes).unwrap();
+    assert_eq!((header.channel_count, header.sample_rate), (1, 500.0));
+    assert!(decode_frames(&bytes, &names(&["A3"])).unwrap().is_empty());
+}
+
+#[test]
+fn malformed_payloads_are_rejected() {
+    let good = payload(2, 250.0, 0, &[1.0, 2.0]);
+    let channels = names(&["A3", "A4"]);
+
+    assert!(decode_frames(&good[..FRAME_HEADER_LEN - 1], &channels).is_err());
+    let mut version = good.clone();
+    version[0] = 2;
+    assert!(decode_frames(&version, &channels).is_err());
+    let mut reserved = good.clone();
+    reserved[1] = 1;
+    assert!(decode_frames(&reserved, &channels).is_err());
+    assert!(decode_frames(&payload(0, 250.0, 0, &[]), &[]).is_err());
+    assert!(decode_frames(&payload(2, 0.0, 0, &[1.0, 2.0]), &channels).is_err());
+    assert!(decode_frames(&payload(2, f32::NAN, 0, &[1.0, 2.0]), &channels).is_err());
+    // Partial frame, and a channel list that does not match the header.
+    assert!(decode_frames(&good[..good.len() - 2], &channels).is_err());
+    assert!(decode_frames(&good, &names(&["A3"])).is_err());
+    assert!(decode_frames(&payload(1, 250.0, i64::MAX, &[1.0]), &names(&["A3"])).is_err());
+}
@@ -0,0 +1,48 @@
+use chrono::{DateTime, Utc};
+use eeg_ingest::series::{next_start, ChannelValues};
+
+fn start() -> DateTime<Utc> {
+    "2024-01-01T00:00:00Z".parse().unwrap()
+}
+
+fn offsets_us(values: &ChannelValues, rate: f64) -> Vec<i64> {
+    values
+        .samples(start(), rate, Some(3))
+        .unwrap()
+        .iter()
+        .map(|s| (s.ts - start()).num_microseconds().unwrap())
+        .collect()
+}
+
+#[test]
+fn values_are_spaced_at_the_sample_rate() {
+    let values = ChannelValues {
+        channel: "Fz".to_string(),
+        values: vec![1.0, 2.0, 3.0],
+    };
+    assert_eq!(offsets_us(&values, 250.0), [0, 4000, 8000]);
+    let samples = values.samples(start(), 250.0, Some(3)).unwrap();
+    assert!(samples
+        .iter()
+        .all(|s| s.channel == "Fz" && s.session_id == Some(3)));
+    assert_eq!(samples[2].value, 3.0);
+}
+
+#[test]
+fn rounding_does_not_accumulate_within_a_batch() {
+    let values = ChannelValues {
+        channel: "Fz".to_string(),
+        values: vec![0.0; 257],
+    };
+    let offsets = offsets_us(&values, 256.0);
+    assert_eq!(offsets[1], 3906);
+    assert_eq!(offsets[2], 7813);
+    assert_eq!(offsets[256], 1_000_000);
+}
+
+#[test]
+fn a_batch_continues_one_period_after_the_last_sample() {
+    let last = start() + chrono::Duration::microseconds(8000);
+    let next = next_start(last, 250.0).unwrap();
+    assert_eq!((next - start()).num_microseconds(), Some(12_000));
+}
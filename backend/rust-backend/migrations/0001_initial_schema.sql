@@ -0,0 +1,217 @@
+-- Schema as of the switch to embedded migrations. Every statement only creates what is
+-- missing, so databases set up earlier from data/eeg.sql take it as a no-op.
+
+-- Pseudonymous study subjects; `code` identifies a subject without naming them.
+CREATE TABLE IF NOT EXISTS subjects (
+  id SERIAL PRIMARY KEY,
+  code TEXT NOT NULL UNIQUE,
+  birth_year INTEGER,
+  sex TEXT,
+  handedness TEXT,
+  notes TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Recording sessions that group samples.
+CREATE TABLE IF NOT EXISTS sessions (
+  id SERIAL PRIMARY KEY,
+  subject_id INTEGER REFERENCES subjects(id),
+  device TEXT,
+  started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  ended_at TIMESTAMPTZ,
+  notes TEXT,
+  CHECK (ended_at IS NULL OR ended_at >= started_at)
+);
+
+CREATE TABLE IF NOT EXISTS eeg_samples (
+  id SERIAL,
+  ts TIMESTAMPTZ NOT NULL,
+  channel TEXT NOT NULL,
+  value FLOAT NOT NULL,
+  session_id INTEGER REFERENCES sessions(id),
+  -- TimescaleDB requires the partitioning column in every unique index.
+  PRIMARY KEY (id, ts)
+);
+
+CREATE INDEX IF NOT EXISTS idx_eeg_samples_channel_id
+ON eeg_samples(channel, id);
+
+CREATE INDEX IF NOT EXISTS idx_eeg_samples_channel_ts
+ON eeg_samples(channel, ts DESC);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_subject_id
+ON sessions(subject_id);
+
+CREATE INDEX IF NOT EXISTS idx_eeg_samples_session_channel_id
+ON eeg_samples(session_id, channel, id) WHERE session_id IS NOT NULL;
+
+-- Event markers and annotations; `duration` is in seconds, 0 for instants.
+CREATE TABLE IF NOT EXISTS events (
+  id SERIAL PRIMARY KEY,
+  session_id INTEGER REFERENCES sessions(id),
+  ts TIMESTAMPTZ NOT NULL,
+  duration DOUBLE PRECISION NOT NULL DEFAULT 0 CHECK (duration >= 0),
+  label TEXT NOT NULL,
+  metadata JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX IF NOT EXISTS idx_events_session_ts
+ON events(session_id, ts);
+
+CREATE INDEX IF NOT EXISTS idx_events_ts
+ON events(ts);
+
+-- Channels that ingest accepts samples for, with their acquisition metadata.
+CREATE TABLE IF NOT EXISTS channels (
+  name TEXT PRIMARY KEY,
+  label TEXT,
+  unit TEXT NOT NULL DEFAULT 'uV',
+  sample_rate DOUBLE PRECISION CHECK (sample_rate > 0),
+  hardware_index INTEGER CHECK (hardware_index >= 0),
+  reference TEXT,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+INSERT INTO channels (name, label, hardware_index) VALUES
+  ('A3', 'A3', 0),
+  ('A4', 'A4', 1)
+ON CONFLICT (name) DO NOTHING;
+
+-- Acquisition devices and the stream profile ingest checks them against.
+-- `channel_map` lists the channel names of the inputs in hardware order.
+CREATE TABLE IF NOT EXISTS devices (
+  serial TEXT PRIMARY KEY,
+  model TEXT NOT NULL,
+  channel_map TEXT[] NOT NULL,
+  sample_rate DOUBLE PRECISION NOT NULL CHECK (sample_rate > 0),
+  gain DOUBLE PRECISION CHECK (gain > 0),
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  notes TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Live processing pipelines (see src/dsp/pipeline.rs). `stages` is a JSON array of stages
+-- run in order; `channels` are the channels the pipeline processes by default.
+CREATE TABLE IF NOT EXISTS pipelines (
+  name TEXT PRIMARY KEY,
+  stages JSONB NOT NULL,
+  channels TEXT[] NOT NULL DEFAULT '{}',
+  persist BOOLEAN NOT NULL DEFAULT FALSE,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Montages (see src/montages.rs): named lists of derivations, each a channel and the
+-- `reference` it is returned against, as for the `reference` query parameter.
+CREATE TABLE IF NOT EXISTS montages (
+  name TEXT PRIMARY KEY,
+  description TEXT,
+  derivations JSONB NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO montages (name, description, derivations) VALUES
+('double_banana', 'Longitudinal bipolar (10-20)', '[
+  {"name": "Fp1-F7", "channel": "Fp1", "reference": "F7"},
+  {"name": "F7-T3", "channel": "F7", "reference": "T3"},
+  {"name": "T3-T5", "channel": "T3", "reference": "T5"},
+  {"name": "T5-O1", "channel": "T5", "reference": "O1"},
+  {"name": "Fp2-F8", "channel": "Fp2", "reference": "F8"},
+  {"name": "F8-T4", "channel": "F8", "reference": "T4"},
+  {"name": "T4-T6", "channel": "T4", "reference": "T6"},
+  {"name": "T6-O2", "channel": "T6", "reference": "O2"},
+  {"name": "Fp1-F3", "channel": "Fp1", "reference": "F3"},
+  {"name": "F3-C3", "channel": "F3", "reference": "C3"},
+  {"name": "C3-P3", "channel": "C3", "reference": "P3"},
+  {"name": "P3-O1", "channel": "P3", "reference": "O1"},
+  {"name": "Fp2-F4", "channel": "Fp2", "reference": "F4"},
+  {"name": "F4-C4", "channel": "F4", "reference": "C4"},
+  {"name": "C4-P4", "channel": "C4", "reference": "P4"},
+  {"name": "P4-O2", "channel": "P4", "reference": "O2"},
+  {"name": "Fz-Cz", "channel": "Fz", "reference": "Cz"},
+  {"name": "Cz-Pz", "channel": "Cz", "reference": "Pz"}
+]')
+ON CONFLICT (name) DO NOTHING;
+
+-- Pre-aggregated tiers maintained by the backend (see src/tiers.rs).
+CREATE TABLE IF NOT EXISTS eeg_agg_1s (
+  channel TEXT NOT NULL,
+  bucket TIMESTAMPTZ NOT NULL,
+  min FLOAT NOT NULL,
+  max FLOAT NOT NULL,
+  sum FLOAT NOT NULL,
+  count BIGINT NOT NULL,
+  PRIMARY KEY (channel, bucket)
+);
+
+CREATE TABLE IF NOT EXISTS eeg_agg_10s (LIKE eeg_agg_1s INCLUDING ALL);
+CREATE TABLE IF NOT EXISTS eeg_agg_1m (LIKE eeg_agg_1s INCLUDING ALL);
+
+-- Retention rules enforced hourly by the backend; targets are "raw" or a tier name.
+CREATE TABLE IF NOT EXISTS retention_policies (
+  target TEXT PRIMARY KEY,
+  max_age_seconds BIGINT NOT NULL CHECK (max_age_seconds > 0),
+  enabled BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+INSERT INTO retention_policies (target, max_age_seconds) VALUES
+  ('raw', 604800),
+  ('1s', 31536000),
+  ('10s', 31536000),
+  ('1m', 31536000)
+ON CONFLICT (target) DO NOTHING;
+
+-- Electrode impedance checks (see src/impedances.rs): one row per channel and check, in kΩ.
+CREATE TABLE IF NOT EXISTS impedances (
+  id SERIAL PRIMARY KEY,
+  session_id INTEGER NOT NULL REFERENCES sessions(id),
+  channel TEXT NOT NULL,
+  measured_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  kohm DOUBLE PRECISION NOT NULL CHECK (kohm >= 0)
+);
+
+CREATE INDEX IF NOT EXISTS idx_impedances_channel_measured_at
+ON impedances(channel, measured_at DESC);
+
+CREATE INDEX IF NOT EXISTS idx_impedances_session
+ON impedances(session_id, channel);
+
+-- Stored epoch sets (see src/epoch_sets.rs). Each epoch holds `channels × times` values,
+-- channel-major.
+CREATE TABLE IF NOT EXISTS epoch_sets (
+  id SERIAL PRIMARY KEY,
+  name TEXT,
+  session_id INTEGER REFERENCES sessions(id),
+  channels TEXT[] NOT NULL,
+  sample_rate DOUBLE PRECISION NOT NULL CHECK (sample_rate > 0),
+  times DOUBLE PRECISION[] NOT NULL,
+  epochs INTEGER NOT NULL,
+  rejected INTEGER NOT NULL,
+  params JSONB NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS epochs (
+  set_id INTEGER NOT NULL REFERENCES epoch_sets(id) ON DELETE CASCADE,
+  epoch INTEGER NOT NULL,
+  onset TIMESTAMPTZ NOT NULL,
+  label TEXT,
+  data DOUBLE PRECISION[] NOT NULL,
+  PRIMARY KEY (set_id, epoch)
+);
+
+-- Alert rules evaluated on incoming samples (see src/alerts.rs). `metric` is a JSON object with
+-- a `type`; empty `channels` watch every enabled channel.
+CREATE TABLE IF NOT EXISTS alert_rules (
+  name TEXT PRIMARY KEY,
+  channels TEXT[] NOT NULL DEFAULT '{}',
+  metric JSONB NOT NULL,
+  above DOUBLE PRECISION,
+  below DOUBLE PRECISION,
+  window_seconds DOUBLE PRECISION NOT NULL DEFAULT 1 CHECK (window_seconds > 0),
+  for_seconds DOUBLE PRECISION NOT NULL DEFAULT 0 CHECK (for_seconds >= 0),
+  webhook_url TEXT,
+  enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  CHECK (above IS NOT NULL OR below IS NOT NULL)
+);
@@ -0,0 +1,16 @@
+-- Turns eeg_samples into a TimescaleDB hypertable chunked by ts.
+-- Does nothing on plain Postgres or when the table already is a hypertable.
+DO $$
+BEGIN
+  IF EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = 'timescaledb') THEN
+    CREATE EXTENSION IF NOT EXISTS timescaledb;
+    PERFORM create_hypertable(
+      'eeg_samples',
+      'ts',
+      chunk_time_interval => INTERVAL '1 hour',
+      if_not_exists => TRUE,
+      migrate_data => TRUE
+    );
+  END IF;
+END
+$$;
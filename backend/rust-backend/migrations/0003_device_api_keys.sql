@@ -0,0 +1,17 @@
+-- API keys of acquisition devices (see src/devices/keys.rs). Only the SHA-256
+-- hash of a key is stored; `prefix` is its first characters so admins can tell
+-- keys apart. NULL `channels` or `session_ids` leave that dimension unscoped.
+CREATE TABLE IF NOT EXISTS device_api_keys (
+  id SERIAL PRIMARY KEY,
+  device_serial TEXT NOT NULL REFERENCES devices(serial) ON DELETE CASCADE,
+  name TEXT NOT NULL,
+  prefix TEXT NOT NULL,
+  key_hash TEXT NOT NULL UNIQUE,
+  channels TEXT[],
+  session_ids INTEGER[],
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  last_used_at TIMESTAMPTZ,
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS device_api_keys_device_idx ON device_api_keys (device_serial);
@@ -0,0 +1,8 @@
+-- Roles of authenticated users (see src/roles.rs), keyed by the `sub` claim
+-- of their tokens. A user may hold several roles.
+CREATE TABLE IF NOT EXISTS role_assignments (
+  principal TEXT NOT NULL,
+  role TEXT NOT NULL CHECK (role IN ('admin', 'clinician', 'researcher', 'device')),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (principal, role)
+);
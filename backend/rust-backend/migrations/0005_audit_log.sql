@@ -0,0 +1,19 @@
+-- Authenticated access to data routes (see src/audit.rs). Rows outlive the
+-- subjects and sessions they name, so there are no foreign keys.
+CREATE TABLE IF NOT EXISTS audit_log (
+  id BIGSERIAL PRIMARY KEY,
+  at TIMESTAMPTZ NOT NULL,
+  principal TEXT NOT NULL,
+  action TEXT NOT NULL CHECK (action IN ('read', 'write')),
+  method TEXT NOT NULL,
+  route TEXT NOT NULL,
+  path TEXT NOT NULL,
+  params JSONB NOT NULL DEFAULT '{}',
+  subject_id INTEGER,
+  session_id INTEGER,
+  status SMALLINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_principal_idx ON audit_log (principal, at);
+CREATE INDEX IF NOT EXISTS audit_log_subject_idx ON audit_log (subject_id, at) WHERE subject_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS audit_log_at_idx ON audit_log (at);
@@ -0,0 +1,14 @@
+-- Live streams wake on these instead of polling (see src/notify.rs). Each
+-- inserted row notifies `eeg_samples` with its channel and the writer's
+-- application_name; Postgres folds identical payloads of a transaction, so
+-- a batch notifies once per channel. Runs for COPY as well.
+CREATE OR REPLACE FUNCTION eeg_samples_notify() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify('eeg_samples', NEW.channel || ' ' || current_setting('application_name'));
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER eeg_samples_notify
+  AFTER INSERT ON eeg_samples
+  FOR EACH ROW EXECUTE FUNCTION eeg_samples_notify();
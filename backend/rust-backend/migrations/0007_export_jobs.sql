@@ -0,0 +1,22 @@
+-- Queued exports (see src/export/queue.rs). A worker claims the oldest
+-- queued job and keeps `heartbeat_at` current while it runs; a running job
+-- whose heartbeat stopped, as when its backend died, is claimed again.
+CREATE TABLE IF NOT EXISTS export_jobs (
+  id BIGSERIAL PRIMARY KEY,
+  format TEXT NOT NULL,
+  request JSONB NOT NULL,
+  -- Whether subject details are written, as decided for the requester.
+  identify BOOLEAN NOT NULL,
+  status TEXT NOT NULL DEFAULT 'queued'
+    CHECK (status IN ('queued', 'running', 'done', 'failed')),
+  rows BIGINT,
+  bytes BIGINT NOT NULL DEFAULT 0,
+  error TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  started_at TIMESTAMPTZ,
+  heartbeat_at TIMESTAMPTZ,
+  finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS export_jobs_pending_idx ON export_jobs (id)
+  WHERE status IN ('queued', 'running');
@@ -0,0 +1,13 @@
+-- Sequence numbers of the ingest batches devices sent (see src/ingest.rs),
+-- so that a retried batch is recognised instead of stored twice. Rows older
+-- than `ingest.sequence_window_secs` are pruned by the retention job, except
+-- the latest of each device.
+CREATE TABLE IF NOT EXISTS ingest_batches (
+  device_serial TEXT NOT NULL REFERENCES devices(serial) ON DELETE CASCADE,
+  seq BIGINT NOT NULL CHECK (seq >= 0),
+  samples INTEGER NOT NULL,
+  received_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (device_serial, seq)
+);
+
+CREATE INDEX IF NOT EXISTS ingest_batches_received_idx ON ingest_batches (received_at);
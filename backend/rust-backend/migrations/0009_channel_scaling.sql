@@ -0,0 +1,14 @@
+-- Stored values are in `unit` once multiplied by `scale` (see src/metadata.rs).
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS scale DOUBLE PRECISION NOT NULL DEFAULT 1
+  CHECK (scale <> 0);
+
+-- Sample rate, unit and scale of a channel in one session, where they differ
+-- from the registry; NULL keeps the channel's.
+CREATE TABLE IF NOT EXISTS session_channels (
+  session_id INTEGER NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+  channel TEXT NOT NULL REFERENCES channels(name) ON DELETE CASCADE,
+  sample_rate DOUBLE PRECISION CHECK (sample_rate > 0),
+  unit TEXT,
+  scale DOUBLE PRECISION CHECK (scale <> 0),
+  PRIMARY KEY (session_id, channel)
+);
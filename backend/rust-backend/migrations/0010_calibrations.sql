@@ -0,0 +1,15 @@
+-- Calibrations of a channel, alone or as recorded by one device (see
+-- src/calibration.rs): from `calibrated_at` on, a stored value reads as
+-- `value * gain + offset` until the next calibration.
+CREATE TABLE IF NOT EXISTS calibrations (
+  id SERIAL PRIMARY KEY,
+  channel TEXT NOT NULL REFERENCES channels(name) ON DELETE CASCADE,
+  device TEXT REFERENCES devices(serial) ON DELETE CASCADE,
+  gain DOUBLE PRECISION NOT NULL CHECK (gain <> 0),
+  "offset" DOUBLE PRECISION NOT NULL DEFAULT 0,
+  calibrated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  notes TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS calibrations_channel_idx ON calibrations (channel, calibrated_at);
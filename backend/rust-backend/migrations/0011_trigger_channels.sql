@@ -0,0 +1,17 @@
+-- Channels of kind 'trigger' carry digital trigger codes. Ingest stores
+-- their samples here rather than in eeg_samples, and only where the code
+-- changes (see src/triggers.rs).
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'signal'
+  CHECK (kind IN ('signal', 'trigger'));
+
+CREATE TABLE IF NOT EXISTS trigger_changes (
+  id SERIAL PRIMARY KEY,
+  channel TEXT NOT NULL,
+  session_id INTEGER REFERENCES sessions(id),
+  ts TIMESTAMPTZ NOT NULL,
+  value DOUBLE PRECISION NOT NULL,
+  -- A retried batch finds its changes stored.
+  UNIQUE NULLS NOT DISTINCT (channel, session_id, ts)
+);
+
+CREATE INDEX IF NOT EXISTS trigger_changes_channel_ts_idx ON trigger_changes (channel, ts);
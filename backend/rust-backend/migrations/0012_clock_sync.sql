@@ -0,0 +1,11 @@
+-- Device and server times of ingest batches (see src/devices/clock.rs): the
+-- device's clock when it sent a batch, and when the server received it, from
+-- which the drift of the device's clock is estimated.
+CREATE TABLE IF NOT EXISTS clock_sync (
+  id SERIAL PRIMARY KEY,
+  device_serial TEXT NOT NULL REFERENCES devices(serial) ON DELETE CASCADE,
+  device_time TIMESTAMPTZ NOT NULL,
+  received_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS clock_sync_device_idx ON clock_sync (device_serial, received_at);
@@ -0,0 +1,27 @@
+-- Raw samples packed per channel, session and span of time (see
+-- src/store/blocks.rs): `data` holds the ids, timestamps and values of
+-- `samples` samples, encoded as crates/blocks describes, the first at
+-- `start_ts` and the last at `end_ts`. Their ids run from `first_id` to
+-- `last_id` and their values from `min_value` to `max_value`.
+CREATE TABLE IF NOT EXISTS sample_blocks (
+  id BIGSERIAL PRIMARY KEY,
+  channel TEXT NOT NULL,
+  session_id INTEGER REFERENCES sessions(id),
+  start_ts TIMESTAMPTZ NOT NULL,
+  end_ts TIMESTAMPTZ NOT NULL,
+  first_id INTEGER NOT NULL,
+  last_id INTEGER NOT NULL,
+  samples INTEGER NOT NULL,
+  min_value FLOAT NOT NULL,
+  max_value FLOAT NOT NULL,
+  data BYTEA NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_sample_blocks_channel_ts
+ON sample_blocks(channel, start_ts);
+
+CREATE INDEX IF NOT EXISTS idx_sample_blocks_channel_id
+ON sample_blocks(channel, first_id);
+
+CREATE INDEX IF NOT EXISTS idx_sample_blocks_session_ts
+ON sample_blocks(session_id, start_ts) WHERE session_id IS NOT NULL;
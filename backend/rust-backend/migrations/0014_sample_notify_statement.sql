@@ -0,0 +1,37 @@
+-- Replaces the per-row trigger of 0006 with one per statement: a batch of
+-- inserted samples notifies `eeg_samples` once per distinct channel instead
+-- of queueing a notification for every row. The payload is unchanged.
+--
+-- TimescaleDB does not support transition tables on hypertables, so there
+-- the per-row trigger of 0006 stays.
+DO $$
+BEGIN
+  IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+    IF EXISTS (
+      SELECT 1 FROM timescaledb_information.hypertables
+      WHERE hypertable_name = 'eeg_samples'
+    ) THEN
+      RETURN;
+    END IF;
+  END IF;
+
+  CREATE OR REPLACE FUNCTION eeg_samples_notify_statement() RETURNS trigger AS $fn$
+  DECLARE
+    name TEXT;
+  BEGIN
+    FOR name IN SELECT DISTINCT channel FROM new_rows LOOP
+      PERFORM pg_notify('eeg_samples', name || ' ' || current_setting('application_name'));
+    END LOOP;
+    RETURN NULL;
+  END;
+  $fn$ LANGUAGE plpgsql;
+
+  DROP TRIGGER IF EXISTS eeg_samples_notify ON eeg_samples;
+  DROP FUNCTION IF EXISTS eeg_samples_notify();
+
+  CREATE TRIGGER eeg_samples_notify
+    AFTER INSERT ON eeg_samples
+    REFERENCING NEW TABLE AS new_rows
+    FOR EACH STATEMENT EXECUTE FUNCTION eeg_samples_notify_statement();
+END
+$$;
@@ -0,0 +1,24 @@
+-- On a hypertable, drops the per-row trigger that 0014 left in place: it
+-- queues a notification for every inserted row, which Postgres only folds
+-- at commit. A statement-level trigger cannot replace it there, since
+-- TimescaleDB rejects transition tables on hypertables and without one a
+-- trigger cannot tell which channels a statement wrote. Instead the
+-- backend notifies `eeg_samples` itself after each UNNEST insert and COPY,
+-- once per channel, with the same payload (see src/notify.rs). Samples
+-- inserted by other clients are not announced on a hypertable.
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+    RETURN;
+  END IF;
+  IF NOT EXISTS (
+    SELECT 1 FROM timescaledb_information.hypertables
+    WHERE hypertable_name = 'eeg_samples'
+  ) THEN
+    RETURN;
+  END IF;
+
+  DROP TRIGGER IF EXISTS eeg_samples_notify ON eeg_samples;
+  DROP FUNCTION IF EXISTS eeg_samples_notify();
+END
+$$;
@@ -0,0 +1,15 @@
+-- Samples of the SQLite store (see src/store/sqlite.rs). `ts` is in µs
+-- since the Unix epoch.
+CREATE TABLE IF NOT EXISTS eeg_samples (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  ts INTEGER NOT NULL,
+  channel TEXT NOT NULL,
+  value REAL NOT NULL,
+  session_id INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS idx_eeg_samples_channel_id
+ON eeg_samples(channel, id);
+
+CREATE INDEX IF NOT EXISTS idx_eeg_samples_channel_ts
+ON eeg_samples(channel, ts);
@@ -0,0 +1,519 @@
+//! Time-bucketed aggregation over `eeg_samples.ts`.
+//!
+//! Uses TimescaleDB's `time_bucket` when the extension is installed and
+//! falls back to Postgres' `date_bin` otherwise; both align buckets to the
+//! Unix epoch, so results are identical. `/samples/buckets` instead splits
+//! the requested range into a given number of equal buckets starting at
+//! `from`, for overview strips that need exactly one value per pixel column.
+//! Samples packed into [blocks](crate::store::blocks) are summed up here and
+//! added to the buckets of the rows.
+
+use crate::error::ApiError;
+use crate::store::blocks;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{cache, config, timezone, AppState, SampleFilter};
+use axum::{
+    extract::{Query, RawQuery, State},
+    Json,
+};
+use chrono::{DateTime, Duration as ChronoDuration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use utoipa::IntoParams;
+
+/// Upper bound on buckets returned by one request.
+pub const MAX_BUCKETS: i64 = 10_000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AggregateQuery {
+    channel: Option<String>,
+    /// Bucket width such as `500ms`, `1s`, `10s`, `1m` or `1h`.
+    bucket: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// Zone of local `from` / `to`, documented with [`timezone::TimeQuery`].
+    #[param(ignore)]
+    tz: Option<timezone::Zone>,
+}
+
+impl Validate for AggregateQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        let width = parse_width(self.bucket.as_deref().unwrap_or("1s"));
+        if let Err(e) = &width {
+            errors.add("bucket", e.clone());
+        }
+        let range = check_range(errors, self.from.as_deref(), self.to.as_deref(), self.tz);
+        if let (Ok(width), Some((from, to))) = (width, range) {
+            let span = (to - from).num_milliseconds() as f64 / 1000.0;
+            errors.check(
+                span / width <= MAX_BUCKETS as f64,
+                "bucket",
+                format!("range would produce more than {} buckets", MAX_BUCKETS),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BucketsQuery {
+    channel: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// Number of buckets the range is split into.
+    buckets: Option<i64>,
+    /// Zone of local `from` / `to`, documented with [`timezone::TimeQuery`].
+    #[param(ignore)]
+    tz: Option<timezone::Zone>,
+}
+
+impl Validate for BucketsQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_range(errors, self.from.as_deref(), self.to.as_deref(), self.tz);
+        if let Some(buckets) = self.buckets {
+            errors.check(
+                (1..=MAX_BUCKETS).contains(&buckets),
+                "buckets",
+                format!("must be 1 to {}", MAX_BUCKETS),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bucket {
+    pub ts: String,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub count: i64,
+}
+
+/// Count, mean, spread and range of some samples, which add up across the
+/// rows summed up in SQL and the samples of packed blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: i64,
+    pub mean: f64,
+    /// Sum of the squared differences from `mean`.
+    pub m2: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    pub fn of(value: f64) -> Self {
+        Summary {
+            count: 1,
+            mean: value,
+            m2: 0.0,
+            min: value,
+            max: value,
+        }
+    }
+
+    /// Adds the samples of `other`, combining means and squared differences
+    /// as Chan, Golub and LeVeque (1979) do.
+    pub fn merge(&mut self, other: &Summary) {
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        let weight = other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * delta * self.count as f64 * weight;
+        self.mean += delta * weight;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count = count;
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.mean * self.count as f64
+    }
+
+    /// The population standard deviation.
+    pub fn std(&self) -> f64 {
+        (self.m2 / self.count as f64).sqrt()
+    }
+}
+
+/// Adds `summary` to the one at `key`.
+pub fn merge_into<K: Ord>(summaries: &mut BTreeMap<K, Summary>, key: K, summary: Summary) {
+    summaries
+        .entry(key)
+        .and_modify(|s| s.merge(&summary))
+        .or_insert(summary);
+}
+
+/// Start of the bucket of `width_secs` holding `ts`, aligned to the epoch
+/// as [`bucket_expr`] aligns it.
+pub fn bucket_of(width_secs: f64, ts: DateTime<Utc>) -> DateTime<Utc> {
+    let width_us = ((width_secs * 1e6).round() as i64).max(1);
+    let ts_us = ts.timestamp_micros();
+    DateTime::from_timestamp_micros(ts_us - ts_us.rem_euclid(width_us)).unwrap_or(ts)
+}
+
+/// [`Summary`]s of the packed samples of `channel` that `filter` keeps, by
+/// bucket of `width_secs`.
+pub async fn packed_buckets(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+    width_secs: f64,
+) -> Result<BTreeMap<DateTime<Utc>, Summary>, sqlx::Error> {
+    let mut buckets = BTreeMap::new();
+    blocks::for_each(pool, Some(&[channel.to_string()]), filter, |s| {
+        merge_into(
+            &mut buckets,
+            bucket_of(width_secs, s.ts),
+            Summary::of(s.value),
+        );
+    })
+    .await?;
+    Ok(buckets)
+}
+
+/// A bucket of `/samples/buckets`; the statistics are null if it holds no
+/// samples.
+#[derive(Debug, Serialize)]
+pub struct RangeBucket {
+    pub ts: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    pub count: i64,
+}
+
+/// Reports whether TimescaleDB is installed in the connected database.
+pub async fn detect_timescale(pool: &PgPool) -> bool {
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb')",
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap_or(false)
+}
+
+/// Parses a bucket width like `250ms`, `10s`, `5m` or `1h` into seconds.
+pub fn parse_width(width: &str) -> Result<f64, String> {
+    let width = width.trim();
+    let split = width
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(width.len());
+    let (number, unit) = width.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", width))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "d" => number * 86_400.0,
+        _ => return Err(format!("unknown duration unit {:?}", unit)),
+    };
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(format!("duration {:?} must be positive", width));
+    }
+    Ok(seconds)
+}
+
+/// Parses optional bounds as [`timezone::Instant`]s, local times in `zone`,
+/// defaulting to the hour before `to` (or now).
+pub fn parse_range(
+    from: Option<&str>,
+    to: Option<&str>,
+    zone: timezone::Zone,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), FieldErrors> {
+    let mut errors = FieldErrors::default();
+    check_range(&mut errors, from, to, Some(zone)).ok_or(errors)
+}
+
+/// [`parse_range`] for a [`Validate`] impl: adds a message for `from` or
+/// `to` if it does not parse, or for `to` unless it is after `from`.
+pub fn check_range(
+    errors: &mut FieldErrors,
+    from: Option<&str>,
+    to: Option<&str>,
+    zone: Option<timezone::Zone>,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let zone = zone.unwrap_or_default();
+    let mut parse = |name: &str, value: &str| match value
+        .parse::<timezone::Instant>()
+        .and_then(|instant| instant.resolve(zone))
+    {
+        Ok(time) => Some(time),
+        Err(e) => {
+            errors.add(name, e);
+            None
+        }
+    };
+    let to = match to {
+        Some(to) => parse("to", to),
+        None => Some(Utc::now()),
+    };
+    let from = match from {
+        Some(from) => parse("from", from),
+        None => to.map(|to| to - ChronoDuration::hours(1)),
+    };
+    let (from, to) = (from?, to?);
+    errors.check(from < to, "to", "must be after from");
+    (from < to).then_some((from, to))
+}
+
+/// SQL expression bucketing `column` by the width bound (in seconds) at `param`.
+pub fn bucket_expr(timescale: bool, param: &str, column: &str) -> String {
+    if timescale {
+        format!("time_bucket(make_interval(secs => {}), {})", param, column)
+    } else {
+        format!(
+            "date_bin(make_interval(secs => {}), {}, TIMESTAMPTZ 'epoch')",
+            param, column
+        )
+    }
+}
+
+/// Min/max/avg/count per bucket of `width_secs` for one channel and range.
+pub async fn fetch_buckets(
+    pool: &PgPool,
+    timescale: bool,
+    channel: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    width_secs: f64,
+) -> Result<Vec<Bucket>, sqlx::Error> {
+    let sql = format!(
+        "SELECT {} AS bucket, MIN(value), MAX(value), AVG(value), COUNT(*) \
+         FROM eeg_samples \
+         WHERE channel = $1 AND ts >= $2 AND ts < $3 \
+         GROUP BY bucket ORDER BY bucket",
+        bucket_expr(timescale, "$4", "ts")
+    );
+    let rows: Vec<(DateTime<Utc>, f64, f64, f64, i64)> = sqlx::query_as(&sql)
+        .bind(channel)
+        .bind(from)
+        .bind(to)
+        .bind(width_secs)
+        .fetch_all(pool)
+        .await?;
+    let filter = SampleFilter {
+        from: Some(from),
+        to: Some(to),
+        ..SampleFilter::default()
+    };
+    let mut buckets = packed_buckets(pool, channel, &filter, width_secs).await?;
+    for (ts, min, max, mean, count) in rows {
+        let summary = Summary {
+            count,
+            mean,
+            m2: 0.0,
+            min,
+            max,
+        };
+        merge_into(&mut buckets, ts, summary);
+    }
+
+    Ok(buckets
+        .into_iter()
+        .map(|(ts, s)| Bucket {
+            ts: ts.to_rfc3339_opts(SecondsFormat::Millis, true),
+            min: s.min,
+            max: s.max,
+            avg: s.mean,
+            count: s.count,
+        })
+        .collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/samples/aggregate",
+    tag = "samples",
+    params(AggregateQuery, timezone::TimeQuery),
+    responses(
+        (status = 200, description = "`{ channel, bucket_seconds, from, to, buckets: [{ ts, min, max, avg, count }] }`", body = serde_json::Value),
+        (status = 422, description = "Invalid bucket or range, or more than 10000 buckets, named in `details.fields`")
+    )
+)]
+pub async fn get_aggregate(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<AggregateQuery>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let channel = params.channel.unwrap_or_else(|| "A3".to_string());
+    let width =
+        parse_width(params.bucket.as_deref().unwrap_or("1s")).map_err(ApiError::bad_request)?;
+    let (from, to) = parse_range(
+        params.from.as_deref(),
+        params.to.as_deref(),
+        times.tz.unwrap_or_default(),
+    )?;
+
+    let key = cache::Key {
+        route: "aggregate",
+        channels: std::slice::from_ref(&channel),
+        session_id: None,
+        query: query.as_deref().unwrap_or_default(),
+    };
+    let mut body = cache::cached(key, config::get().cache.aggregate_ttl_secs, async {
+        let buckets =
+            fetch_buckets(state.reader(), state.timescale, &channel, from, to, width).await?;
+        Ok(serde_json::json!({
+            "channel": channel,
+            "bucket_seconds": width,
+            "from": from.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "to": to.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "buckets": buckets,
+        }))
+    })
+    .await?;
+    times.apply(&mut body);
+    Ok(Json(body))
+}
+
+/// Min/max/mean of `buckets` equal buckets between `from` and `to`, empty
+/// buckets included.
+#[utoipa::path(
+    get,
+    path = "/samples/buckets",
+    tag = "samples",
+    params(BucketsQuery, timezone::TimeQuery),
+    responses(
+        (status = 200, description = "`{ channel, bucket_seconds, from, to, buckets: [{ ts, min, max, mean, count }] }`", body = serde_json::Value),
+        (status = 422, description = "Invalid range or bucket count, named in `details.fields`")
+    )
+)]
+pub async fn get_buckets(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<BucketsQuery>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let channel = params.channel.unwrap_or_else(|| "A3".to_string());
+    let (from, to) = parse_range(
+        params.from.as_deref(),
+        params.to.as_deref(),
+        times.tz.unwrap_or_default(),
+    )?;
+    let count = params.buckets.unwrap_or(500);
+
+    let key = cache::Key {
+        route: "buckets",
+        channels: std::slice::from_ref(&channel),
+        session_id: None,
+        query: query.as_deref().unwrap_or_default(),
+    };
+    let mut body = cache::cached(key, config::get().cache.aggregate_ttl_secs, async {
+        let span_us = (to - from).num_microseconds().unwrap_or(i64::MAX);
+        let rows: Vec<(i32, f64, f64, f64, i64)> = sqlx::query_as(
+            "SELECT LEAST(FLOOR(EXTRACT(EPOCH FROM ts - $2) * 1e6 * $4 / $5)::int, $4 - 1) AS bucket, \
+             MIN(value), MAX(value), AVG(value), COUNT(*) \
+             FROM eeg_samples \
+             WHERE channel = $1 AND ts >= $2 AND ts < $3 \
+             GROUP BY bucket ORDER BY bucket",
+        )
+        .bind(&channel)
+        .bind(from)
+        .bind(to)
+        .bind(count as i32)
+        .bind(span_us as f64)
+        .fetch_all(state.reader())
+        .await?;
+        let filter = SampleFilter {
+            from: Some(from),
+            to: Some(to),
+            ..SampleFilter::default()
+        };
+        let mut summaries = BTreeMap::new();
+        blocks::for_each(state.reader(), Some(std::slice::from_ref(&channel)), &filter, |s| {
+            // The bucket as the query above computes it.
+            let offset_us = (s.ts - from).num_microseconds().unwrap_or(0);
+            let bucket = ((offset_us * count) as f64 / span_us as f64).floor() as i64;
+            merge_into(&mut summaries, bucket.min(count - 1), Summary::of(s.value));
+        })
+        .await?;
+        for (bucket, min, max, mean, count) in rows {
+            let summary = Summary {
+                count,
+                mean,
+                m2: 0.0,
+                min,
+                max,
+            };
+            merge_into(&mut summaries, bucket as i64, summary);
+        }
+
+        let buckets: Vec<RangeBucket> = (0..count)
+            .map(|i| {
+                let ts = from
+                    + ChronoDuration::microseconds(
+                        (span_us as i128 * i as i128 / count as i128) as i64,
+                    );
+                let ts = ts.to_rfc3339_opts(SecondsFormat::Micros, true);
+                match summaries.get(&i) {
+                    Some(s) => RangeBucket {
+                        ts,
+                        min: Some(s.min),
+                        max: Some(s.max),
+                        mean: Some(s.mean),
+                        count: s.count,
+                    },
+                    None => RangeBucket {
+                        ts,
+                        min: None,
+                        max: None,
+                        mean: None,
+                        count: 0,
+                    },
+                }
+            })
+            .collect();
+
+        Ok(serde_json::json!({
+            "channel": channel,
+            "bucket_seconds": span_us as f64 / 1e6 / count as f64,
+            "from": from.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "to": to.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "buckets": buckets,
+        }))
+    })
+    .await?;
+    times.apply(&mut body);
+    Ok(Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summaries_merge_as_if_summed_at_once() {
+        let values = [1.0, 4.0, -2.0, 8.5, 3.0, 3.0];
+        let mut left = Summary::of(values[0]);
+        values[1..2]
+            .iter()
+            .for_each(|v| left.merge(&Summary::of(*v)));
+        let mut right = Summary::of(values[2]);
+        values[3..]
+            .iter()
+            .for_each(|v| right.merge(&Summary::of(*v)));
+        left.merge(&right);
+
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+        assert_eq!(left.count, 6);
+        assert!((left.mean - mean).abs() < 1e-12);
+        assert!((left.std() - variance.sqrt()).abs() < 1e-12);
+        assert!((left.sum() - values.iter().sum::<f64>()).abs() < 1e-12);
+        assert_eq!((left.min, left.max), (-2.0, 8.5));
+    }
+
+    #[test]
+    fn buckets_align_to_the_epoch() {
+        let ts = |us| DateTime::from_timestamp_micros(us).unwrap();
+        assert_eq!(bucket_of(1.0, ts(2_500_000)), ts(2_000_000));
+        assert_eq!(bucket_of(0.25, ts(2_600_000)), ts(2_500_000));
+        assert_eq!(bucket_of(10.0, ts(-1)), ts(-10_000_000));
+    }
+}
@@ -0,0 +1,544 @@
+//! Alert rules (`alert_rules` table) evaluated on incoming samples.
+//!
+//! A rule watches a [`Metric`] of the latest `window_seconds` of each of its
+//! channels (every enabled channel if it lists none), ending at the
+//! channel's newest sample. Once a second, a background task evaluates each
+//! enabled rule on the channels that received samples since its last
+//! evaluation. The condition holds when the metric is above `above` or
+//! below `below`; an alert fires once it has held at every evaluation for
+//! `for_seconds` of sample time. Firing stores an event labelled with the
+//! rule's name (metadata `source: "alert"`) and posts to the rule's webhook.
+//! The alert resolves at the first evaluation where the condition no longer
+//! holds; the event then gets its duration and the webhook a `resolved`
+//! notification.
+//!
+//! Alert states live in memory: they start over when the backend restarts
+//! or the rule changes, and stay as they are while a channel receives no
+//! samples.
+
+use crate::channels::{self, validate_name};
+use crate::dsp::channel_rate;
+use crate::dsp::metric::Metric;
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{fetch_window_samples, webhooks, AppState, SampleFilter};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::types::Json as Jsonb;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+const EVALUATE_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_WINDOW_SECONDS: f64 = 1.0;
+const MAX_WINDOW_SECONDS: f64 = 60.0;
+const MAX_FOR_SECONDS: f64 = 3600.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, sqlx::FromRow, ToSchema)]
+pub struct AlertRule {
+    pub name: String,
+    /// Watched channels; every enabled channel when empty.
+    pub channels: Vec<String>,
+    #[schema(value_type = Metric)]
+    pub metric: Jsonb<Metric>,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    pub window_seconds: f64,
+    /// How long the condition must hold before the alert fires.
+    pub for_seconds: f64,
+    pub webhook_url: Option<String>,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AlertRule {
+    fn holds(&self, value: f64) -> bool {
+        self.above.is_some_and(|above| value > above)
+            || self.below.is_some_and(|below| value < below)
+    }
+}
+
+/// Body of `POST /alerts/rules` (with `name`) and `PUT /alerts/rules/{name}`,
+/// which replaces the rule's settings.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AlertRuleInput {
+    name: Option<String>,
+    #[serde(default)]
+    channels: Vec<String>,
+    metric: Metric,
+    above: Option<f64>,
+    below: Option<f64>,
+    window_seconds: Option<f64>,
+    for_seconds: Option<f64>,
+    webhook_url: Option<String>,
+    enabled: Option<bool>,
+}
+
+impl Validate for AlertRuleInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(Err(e)) = self.name.as_deref().map(validate_name) {
+            errors.add("name", e);
+        }
+        let max = crate::config::get().limits.max_query_channels;
+        errors.check(
+            self.channels.len() <= max,
+            "channels",
+            format!("must list at most {} channels", max),
+        );
+        for channel in &self.channels {
+            if let Err(e) = validate_name(channel) {
+                errors.add("channels", format!("{:?}: {}", channel, e));
+            }
+        }
+        if let Err(e) = self.metric.check() {
+            errors.add("metric", e);
+        }
+        errors.check(
+            self.above.is_some() || self.below.is_some(),
+            "above",
+            "give above and/or below",
+        );
+        for (field, value) in [("above", self.above), ("below", self.below)] {
+            if let Some(value) = value {
+                errors.check(value.is_finite(), field, "must be a finite number");
+            }
+        }
+        if let (Some(above), Some(below)) = (self.above, self.below) {
+            errors.check(below < above, "below", "must be less than above");
+        }
+        let window = self.window_seconds.unwrap_or(DEFAULT_WINDOW_SECONDS);
+        errors.check(
+            window > 0.0 && window <= MAX_WINDOW_SECONDS,
+            "window_seconds",
+            format!("must be above 0 and at most {}", MAX_WINDOW_SECONDS),
+        );
+        let duration = self.for_seconds.unwrap_or(0.0);
+        errors.check(
+            (0.0..=MAX_FOR_SECONDS).contains(&duration),
+            "for_seconds",
+            format!("must be 0 to {}", MAX_FOR_SECONDS),
+        );
+        if let Some(Err(e)) = self.webhook_url.as_deref().map(webhooks::check_url) {
+            errors.add("webhook_url", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[schema(as = AlertStatus)]
+pub enum Status {
+    Ok,
+    /// The condition holds, for less than `for_seconds` so far.
+    Pending,
+    Firing,
+}
+
+/// A rule's state on one channel.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AlertState {
+    pub rule: String,
+    pub channel: String,
+    pub status: Status,
+    /// Metric at the latest evaluation; null without enough samples.
+    pub value: Option<f64>,
+    /// Since when the condition holds.
+    pub since: Option<DateTime<Utc>>,
+    /// Newest sample at the latest evaluation.
+    pub evaluated_at: DateTime<Utc>,
+    /// Event stored when the alert fired.
+    pub event_id: Option<i32>,
+}
+
+/// Current states per rule and channel.
+pub type AlertStates = Arc<Mutex<BTreeMap<(String, String), AlertState>>>;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AlertListQuery {
+    rule: Option<String>,
+    status: Option<Status>,
+}
+
+const COLUMNS: &str =
+    "name, channels, metric, above, below, window_seconds, for_seconds, webhook_url, enabled, created_at";
+
+fn not_found(name: &str) -> ApiError {
+    ApiError::not_found(format!("unknown alert rule {:?}", name))
+}
+
+#[utoipa::path(
+    get,
+    path = "/alerts/rules",
+    tag = "alerts",
+    responses((status = 200, body = Vec<AlertRule>))
+)]
+pub async fn list_rules(State(state): State<AppState>) -> Result<Json<Vec<AlertRule>>, ApiError> {
+    let rules = sqlx::query_as(&format!(
+        "SELECT {} FROM alert_rules ORDER BY name",
+        COLUMNS
+    ))
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(Json(rules))
+}
+
+#[utoipa::path(
+    get,
+    path = "/alerts/rules/{name}",
+    tag = "alerts",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = AlertRule),
+        (status = 404, description = "Unknown rule")
+    )
+)]
+pub async fn get_rule(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<Json<AlertRule>, ApiError> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM alert_rules WHERE name = $1",
+        COLUMNS
+    ))
+    .bind(&name)
+    .fetch_optional(&state.pool)
+    .await?
+    .map(Json)
+    .ok_or_else(|| not_found(&name))
+}
+
+#[utoipa::path(
+    post,
+    path = "/alerts/rules",
+    tag = "alerts",
+    request_body = AlertRuleInput,
+    responses(
+        (status = 201, body = AlertRule),
+        (status = 409, description = "Name taken"),
+        (status = 422, description = "Missing or invalid name, or invalid settings, named in `details.fields`")
+    )
+)]
+pub async fn create_rule(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<AlertRuleInput>>,
+) -> Result<(StatusCode, Json<AlertRule>), ApiError> {
+    let Some(name) = input.name.clone() else {
+        return Err(FieldErrors::single("name", "is required").into());
+    };
+    let rule: Option<AlertRule> = sqlx::query_as(&format!(
+        "INSERT INTO alert_rules \
+         (name, channels, metric, above, below, window_seconds, for_seconds, webhook_url, enabled) \
+         VALUES ($1, $2, $3, $4, $5, COALESCE($6, $7), COALESCE($8, 0), $9, COALESCE($10, TRUE)) \
+         ON CONFLICT (name) DO NOTHING RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(&input.channels)
+    .bind(Jsonb(&input.metric))
+    .bind(input.above)
+    .bind(input.below)
+    .bind(input.window_seconds)
+    .bind(DEFAULT_WINDOW_SECONDS)
+    .bind(input.for_seconds)
+    .bind(&input.webhook_url)
+    .bind(input.enabled)
+    .fetch_optional(&state.pool)
+    .await?;
+    let rule =
+        rule.ok_or_else(|| ApiError::conflict(format!("alert rule {:?} already exists", name)))?;
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+/// Replaces the rule's settings; its alert states start over.
+#[utoipa::path(
+    put,
+    path = "/alerts/rules/{name}",
+    tag = "alerts",
+    params(("name" = String, Path)),
+    request_body = AlertRuleInput,
+    responses(
+        (status = 200, body = AlertRule),
+        (status = 400, description = "Another name in the body"),
+        (status = 404, description = "Unknown rule"),
+        (status = 422, description = "Invalid settings, named in `details.fields`")
+    )
+)]
+pub async fn update_rule(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+    Valid(Json(input)): Valid<Json<AlertRuleInput>>,
+) -> Result<Json<AlertRule>, ApiError> {
+    if input.name.as_deref().is_some_and(|n| n != name) {
+        return Err(ApiError::bad_request("alert rules cannot be renamed"));
+    }
+    sqlx::query_as(&format!(
+        "UPDATE alert_rules SET channels = $2, metric = $3, above = $4, below = $5, \
+         window_seconds = COALESCE($6, $7), for_seconds = COALESCE($8, 0), webhook_url = $9, \
+         enabled = COALESCE($10, TRUE) WHERE name = $1 RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(&input.channels)
+    .bind(Jsonb(&input.metric))
+    .bind(input.above)
+    .bind(input.below)
+    .bind(input.window_seconds)
+    .bind(DEFAULT_WINDOW_SECONDS)
+    .bind(input.for_seconds)
+    .bind(&input.webhook_url)
+    .bind(input.enabled)
+    .fetch_optional(&state.pool)
+    .await?
+    .map(Json)
+    .ok_or_else(|| not_found(&name))
+}
+
+/// Deletes a rule; events it stored are kept.
+#[utoipa::path(
+    delete,
+    path = "/alerts/rules/{name}",
+    tag = "alerts",
+    params(("name" = String, Path)),
+    responses(
+        (status = 204, description = "Deleted; events the rule stored are kept"),
+        (status = 404, description = "Unknown rule")
+    )
+)]
+pub async fn delete_rule(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM alert_rules WHERE name = $1")
+        .bind(&name)
+        .execute(&state.pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(not_found(&name));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Current alert states, by rule and channel.
+#[utoipa::path(
+    get,
+    path = "/alerts",
+    tag = "alerts",
+    params(AlertListQuery),
+    responses((status = 200, body = Vec<AlertState>))
+)]
+pub async fn list_alerts(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<AlertListQuery>>,
+) -> Json<Vec<AlertState>> {
+    let states = state.alerts.lock().unwrap();
+    Json(
+        states
+            .values()
+            .filter(|s| params.rule.as_ref().is_none_or(|r| *r == s.rule))
+            .filter(|s| params.status.is_none_or(|status| status == s.status))
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Posts a `firing` or `resolved` notification in the background.
+fn notify(rule: &AlertRule, state: &AlertState, status: &str) {
+    let Some(url) = rule.webhook_url.clone() else {
+        return;
+    };
+    let body = json!({
+        "status": status,
+        "rule": rule.name,
+        "channel": state.channel,
+        "metric": rule.metric,
+        "above": rule.above,
+        "below": rule.below,
+        "value": state.value,
+        "since": state.since,
+        "at": state.evaluated_at,
+        "event_id": state.event_id,
+    });
+    let name = rule.name.clone();
+    tokio::spawn(async move {
+        if let Err(e) = webhooks::post_json(&url, &body).await {
+            tracing::warn!("alert rule {:?}: webhook failed: {}", name, e);
+        }
+    });
+}
+
+/// Evaluates `rule` on `channel` if it has new samples, updating `state`.
+async fn evaluate(
+    pool: &PgPool,
+    rule: &AlertRule,
+    channel: &str,
+    state: Option<AlertState>,
+) -> Result<Option<AlertState>, String> {
+    let latest: Option<(DateTime<Utc>, Option<i32>)> = sqlx::query_as(
+        "SELECT ts, session_id FROM eeg_samples WHERE channel = $1 ORDER BY ts DESC LIMIT 1",
+    )
+    .bind(channel)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| e.to_string())?;
+    let Some((latest, session_id)) = latest else {
+        return Ok(state);
+    };
+    if state.as_ref().is_some_and(|s| s.evaluated_at >= latest) {
+        return Ok(state);
+    }
+
+    let window = SampleFilter {
+        from: Some(latest - chrono::Duration::microseconds((rule.window_seconds * 1e6) as i64)),
+        to: Some(latest + chrono::Duration::microseconds(1)),
+        ..SampleFilter::default()
+    };
+    let samples = fetch_window_samples(pool, channel, &window)
+        .await
+        .map_err(|e| e.message)?;
+    let rate = if rule.metric.0.spectral() {
+        channel_rate(pool, channel, &samples)
+            .await
+            .map_err(|e| e.to_string())?
+    } else {
+        None
+    };
+    let value = rule.metric.0.measure(&samples, rate);
+    let holds = value.is_some_and(|v| rule.holds(v));
+
+    let mut state = state.unwrap_or_else(|| AlertState {
+        rule: rule.name.clone(),
+        channel: channel.to_string(),
+        status: Status::Ok,
+        value: None,
+        since: None,
+        evaluated_at: latest,
+        event_id: None,
+    });
+    state.value = value;
+    state.evaluated_at = latest;
+    match (state.status, holds) {
+        (Status::Ok, true) => {
+            state.status = Status::Pending;
+            state.since = Some(latest);
+        }
+        (Status::Pending, false) => {
+            state.status = Status::Ok;
+            state.since = None;
+        }
+        (Status::Firing, false) => {
+            state.status = Status::Ok;
+            if let (Some(id), Some(since)) = (state.event_id, state.since) {
+                let duration = (latest - since).num_microseconds().unwrap_or(0) as f64 / 1e6;
+                sqlx::query("UPDATE events SET duration = $2 WHERE id = $1")
+                    .bind(id)
+                    .bind(duration)
+                    .execute(pool)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            notify(rule, &state, "resolved");
+            state.since = None;
+            state.event_id = None;
+        }
+        _ => {}
+    }
+
+    let held = state
+        .since
+        .map(|since| (latest - since).num_microseconds().unwrap_or(0) as f64 / 1e6);
+    if state.status == Status::Pending && held.is_some_and(|held| held >= rule.for_seconds) {
+        state.status = Status::Firing;
+        let (id,): (i32,) = sqlx::query_as(
+            "INSERT INTO events (session_id, ts, duration, label, metadata) \
+             VALUES ($1, $2, 0, $3, $4) RETURNING id",
+        )
+        .bind(session_id)
+        .bind(state.since)
+        .bind(&rule.name)
+        .bind(json!({
+            "source": "alert",
+            "channel": channel,
+            "metric": rule.metric,
+            "above": rule.above,
+            "below": rule.below,
+            "value": value,
+        }))
+        .fetch_one(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        state.event_id = Some(id);
+        notify(rule, &state, "firing");
+    }
+    Ok(Some(state))
+}
+
+/// One pass over the enabled rules. `evaluated` holds the rules as of the
+/// previous pass, so that states of changed rules are dropped.
+async fn evaluate_all(
+    pool: &PgPool,
+    evaluated: &mut BTreeMap<String, AlertRule>,
+    states: &AlertStates,
+) -> Result<(), sqlx::Error> {
+    let rules: Vec<AlertRule> = sqlx::query_as(&format!(
+        "SELECT {} FROM alert_rules WHERE enabled ORDER BY name",
+        COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+    evaluated.retain(|name, previous| rules.iter().any(|r| r.name == *name && r == previous));
+    states
+        .lock()
+        .unwrap()
+        .retain(|(name, _), _| evaluated.contains_key(name));
+
+    for rule in rules {
+        let channels = if rule.channels.is_empty() {
+            channels::enabled()
+        } else {
+            rule.channels.clone()
+        };
+        states
+            .lock()
+            .unwrap()
+            .retain(|(name, channel), _| *name != rule.name || channels.contains(channel));
+        for channel in channels {
+            let key = (rule.name.clone(), channel.clone());
+            let previous = states.lock().unwrap().get(&key).cloned();
+            match evaluate(pool, &rule, &channel, previous).await {
+                Ok(Some(state)) => {
+                    states.lock().unwrap().insert(key, state);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!("alert rule {:?} on {:?} failed: {}", rule.name, channel, e)
+                }
+            }
+        }
+        evaluated.insert(rule.name.clone(), rule);
+    }
+    Ok(())
+}
+
+/// Starts the task that evaluates alert rules.
+pub fn spawn(pool: PgPool, states: AlertStates) {
+    tokio::spawn(async move {
+        let mut evaluated = BTreeMap::new();
+        let mut ticker = tokio::time::interval(EVALUATE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = evaluate_all(&pool, &mut evaluated, &states).await {
+                tracing::error!("alert rules failed: {}", e);
+            }
+        }
+    });
+}
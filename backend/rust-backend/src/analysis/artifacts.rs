@@ -0,0 +1,467 @@
+//! Automatic artifact detection (`POST /analysis/artifacts`) and the
+//! `artifacts=exclude|mark` flag of `/samples` and `/samples/filter`.
+//!
+//! Each gap-free run of a channel is scanned by three heuristics, with
+//! thresholds in the channel's unit (µV by default):
+//!
+//! - `pop`: an electrode pop, a jump between consecutive samples above
+//!   `pop_threshold`; marked for [`POP_SECONDS`] from the jump.
+//! - `blink`: an eye blink, the 0.5–4 Hz band (zero-phase Butterworth)
+//!   beyond ±`blink_threshold` for 50 ms to 1 s, unless it overlaps a pop.
+//! - `muscle`: muscle activity, an RMS of the 20–100 Hz band (capped below
+//!   half the sample rate) above `muscle_threshold` over 250 ms windows;
+//!   neighbouring windows merge into one span.
+//!
+//! Findings are written as `artifact:<kind>` events tagged with the channel
+//! and `"source": "artifacts"`; a new pass over a window replaces the events
+//! of earlier passes there. Any event labelled `artifact:...` (including
+//! manual ones) counts for `artifacts=exclude|mark`, on the channel named in
+//! its metadata or, without one, on every channel.
+
+use crate::dsp::filter::Filter;
+use crate::dsp::{estimate_rate, registered_rate, segments};
+use crate::error::ApiError;
+use crate::events::{self, Detected};
+use crate::store::blocks;
+use crate::validation::{Parsed, Valid};
+use crate::{config, AppState, ChannelQuery, EegSample, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, QueryBuilder};
+use std::ops::Range;
+use utoipa::{IntoParams, ToSchema};
+
+/// `source` of the events written by this pass.
+const SOURCE: &str = "artifacts";
+
+const LABEL_PREFIX: &str = "artifact:";
+
+const DEFAULT_POP_THRESHOLD: f64 = 150.0;
+const DEFAULT_BLINK_THRESHOLD: f64 = 100.0;
+const DEFAULT_MUSCLE_THRESHOLD: f64 = 15.0;
+
+/// Span marked after an electrode pop.
+pub const POP_SECONDS: f64 = 0.5;
+const BLINK_MIN_SECONDS: f64 = 0.05;
+const BLINK_MAX_SECONDS: f64 = 1.0;
+const BLINK_BAND: (f64, f64) = (0.5, 4.0);
+const MUSCLE_BAND: (f64, f64) = (20.0, 100.0);
+const MUSCLE_WINDOW_SECONDS: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Blink,
+    Muscle,
+    Pop,
+}
+
+impl Kind {
+    fn label(self) -> String {
+        let name = match self {
+            Kind::Blink => "blink",
+            Kind::Muscle => "muscle",
+            Kind::Pop => "pop",
+        };
+        format!("{}{}", LABEL_PREFIX, name)
+    }
+}
+
+/// A finding over `range` of a channel's samples; `peak` is the largest
+/// jump, deflection or RMS that triggered it.
+struct Finding {
+    kind: Kind,
+    range: Range<usize>,
+    peak: f64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DetectQuery {
+    pop_threshold: Option<f64>,
+    blink_threshold: Option<f64>,
+    muscle_threshold: Option<f64>,
+    /// Report findings without writing events.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+struct Thresholds {
+    pop: f64,
+    blink: f64,
+    muscle: f64,
+}
+
+impl DetectQuery {
+    fn thresholds(&self) -> Result<Thresholds, String> {
+        let check = |name: &str, value: Option<f64>, default: f64| match value {
+            Some(v) if !(v.is_finite() && v > 0.0) => Err(format!("{} must be positive", name)),
+            v => Ok(v.unwrap_or(default)),
+        };
+        Ok(Thresholds {
+            pop: check("pop_threshold", self.pop_threshold, DEFAULT_POP_THRESHOLD)?,
+            blink: check(
+                "blink_threshold",
+                self.blink_threshold,
+                DEFAULT_BLINK_THRESHOLD,
+            )?,
+            muscle: check(
+                "muscle_threshold",
+                self.muscle_threshold,
+                DEFAULT_MUSCLE_THRESHOLD,
+            )?,
+        })
+    }
+}
+
+/// Jumps between consecutive samples above `threshold`.
+fn pops(values: &[f64], rate: f64, threshold: f64) -> Vec<Finding> {
+    let span = ((POP_SECONDS * rate).ceil() as usize).max(1);
+    let mut findings: Vec<Finding> = Vec::new();
+    for i in 1..values.len() {
+        let jump = (values[i] - values[i - 1]).abs();
+        if jump <= threshold {
+            continue;
+        }
+        match findings.last_mut() {
+            Some(last) if i < last.range.end => last.peak = last.peak.max(jump),
+            _ => findings.push(Finding {
+                kind: Kind::Pop,
+                range: i - 1..(i + span).min(values.len()),
+                peak: jump,
+            }),
+        }
+    }
+    findings
+}
+
+/// Runs of the 0.5–4 Hz band beyond ±`threshold` lasting like a blink.
+fn blinks(values: &[f64], rate: f64, threshold: f64) -> Vec<Finding> {
+    let Ok(filter) = Filter::bandpass(2, BLINK_BAND.0, BLINK_BAND.1.min(rate * 0.45), rate) else {
+        return Vec::new();
+    };
+    let low = filter.filtfilt(values);
+    let (min_len, max_len) = (BLINK_MIN_SECONDS * rate, BLINK_MAX_SECONDS * rate);
+    let mut findings = Vec::new();
+    let mut start = None;
+    for i in 0..=low.len() {
+        let above = i < low.len() && low[i].abs() > threshold;
+        match (above, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                let len = (i - s) as f64;
+                if len >= min_len && len <= max_len {
+                    let peak = low[s..i].iter().fold(0.0, |m: f64, v| m.max(v.abs()));
+                    findings.push(Finding {
+                        kind: Kind::Blink,
+                        range: s..i,
+                        peak,
+                    });
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    findings
+}
+
+/// 250 ms windows whose 20–100 Hz RMS exceeds `threshold`, merged.
+fn muscle(values: &[f64], rate: f64, threshold: f64) -> Vec<Finding> {
+    let high = MUSCLE_BAND.1.min(rate * 0.45);
+    if high <= MUSCLE_BAND.0 {
+        return Vec::new();
+    }
+    let Ok(filter) = Filter::bandpass(4, MUSCLE_BAND.0, high, rate) else {
+        return Vec::new();
+    };
+    let band = filter.filtfilt(values);
+    let window = ((MUSCLE_WINDOW_SECONDS * rate).round() as usize).max(2);
+    let mut findings: Vec<Finding> = Vec::new();
+    for start in (0..band.len()).step_by(window) {
+        let chunk = &band[start..(start + window).min(band.len())];
+        let rms = (chunk.iter().map(|v| v * v).sum::<f64>() / chunk.len() as f64).sqrt();
+        if rms <= threshold {
+            continue;
+        }
+        let end = start + chunk.len();
+        match findings.last_mut() {
+            Some(last) if last.range.end == start => {
+                last.range.end = end;
+                last.peak = last.peak.max(rms);
+            }
+            _ => findings.push(Finding {
+                kind: Kind::Muscle,
+                range: start..end,
+                peak: rms,
+            }),
+        }
+    }
+    findings
+}
+
+#[derive(sqlx::FromRow)]
+struct Row {
+    id: i32,
+    ts: DateTime<Utc>,
+    channel: String,
+    value: f64,
+    session_id: Option<i32>,
+}
+
+/// Samples of the window with their session, rows and blocks, oldest
+/// first.
+async fn fetch_samples(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+) -> Result<(Vec<EegSample>, Vec<Option<i32>>), ApiError> {
+    let max = config::get().limits.max_window_rows;
+    let too_many = || {
+        ApiError::bad_request(format!(
+            "channel {:?} has more than {} samples in the window; narrow from/to",
+            channel, max
+        ))
+    };
+    let mut query = QueryBuilder::new(
+        "SELECT id, ts, channel, value, session_id FROM eeg_samples WHERE channel = ",
+    );
+    query.push_bind(channel);
+    filter.push_to(&mut query);
+    query.push(" ORDER BY ts, id LIMIT ").push_bind(max + 1);
+    let rows: Vec<Row> = query.build_query_as().fetch_all(pool).await?;
+    if rows.len() as i64 > max {
+        return Err(too_many());
+    }
+    let mut samples: Vec<(EegSample, Option<i32>)> = rows
+        .into_iter()
+        .map(|r| {
+            let sample = EegSample {
+                id: r.id,
+                ts: r.ts,
+                channel: r.channel,
+                value: r.value,
+            };
+            (sample, r.session_id)
+        })
+        .collect();
+
+    let mut packed = blocks::select(Some(&[channel.to_string()]), filter);
+    let mut packed_blocks = packed.build_query_as::<blocks::Block>().fetch(pool);
+    while let Some(block) = packed_blocks.next().await.transpose()? {
+        let session_id = block.session_id;
+        samples.extend(block.unpack(filter)?.into_iter().map(|s| (s, session_id)));
+        if samples.len() as i64 > max {
+            return Err(too_many());
+        }
+    }
+    samples.sort_by_key(|(s, _)| (s.ts, s.id));
+    Ok(samples.into_iter().unzip())
+}
+
+/// Scans each channel's window and records the findings as events,
+/// replacing those of earlier passes over the same window.
+#[utoipa::path(
+    post,
+    path = "/analysis/artifacts",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        DetectQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ channels: [{ channel, removed, events }] }`; the findings are stored as `artifact:*` events unless `dry_run`", body = serde_json::Value),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn detect_artifacts(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<DetectQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let thresholds = params.thresholds().map_err(bad_request)?;
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let (samples, sessions) = fetch_samples(state.reader(), channel, &filter).await?;
+        let rate = match registered_rate(state.reader(), channel).await? {
+            Some(rate) => Some(rate),
+            None => estimate_rate(&samples.iter().map(|s| s.ts).collect::<Vec<_>>()),
+        };
+        let (Some(rate), Some(first), Some(last)) = (rate, samples.first(), samples.last()) else {
+            results.push(json!({ "channel": channel, "removed": 0, "events": [] }));
+            continue;
+        };
+
+        let period = 1.0 / rate;
+        let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+        let mut detected = Vec::new();
+        for run in segments(&samples, rate) {
+            let values = &values[run.clone()];
+            let mut findings = pops(values, rate, thresholds.pop);
+            // The step of a pop also deflects the blink band.
+            let blinks: Vec<Finding> = blinks(values, rate, thresholds.blink)
+                .into_iter()
+                .filter(|b| {
+                    !findings
+                        .iter()
+                        .any(|p| p.range.start < b.range.end && b.range.start < p.range.end)
+                })
+                .collect();
+            findings.extend(blinks);
+            findings.extend(muscle(values, rate, thresholds.muscle));
+            for finding in findings {
+                let (start, end) = (
+                    run.start + finding.range.start,
+                    run.start + finding.range.end,
+                );
+                let span = (samples[end - 1].ts - samples[start].ts)
+                    .num_microseconds()
+                    .unwrap_or(0) as f64
+                    / 1e6;
+                detected.push(Detected {
+                    session_id: sessions[start],
+                    ts: samples[start].ts,
+                    duration: span + period,
+                    label: finding.kind.label(),
+                    metadata: json!({
+                        "source": SOURCE,
+                        "channel": channel,
+                        "kind": finding.kind,
+                        "peak": finding.peak,
+                    }),
+                });
+            }
+        }
+        detected.sort_by_key(|d| d.ts);
+
+        let body = if params.dry_run {
+            let events: Vec<_> = detected
+                .iter()
+                .map(|d| {
+                    json!({
+                        "session_id": d.session_id,
+                        "ts": d.ts,
+                        "duration": d.duration,
+                        "label": d.label,
+                        "metadata": d.metadata,
+                    })
+                })
+                .collect();
+            json!({ "channel": channel, "removed": 0, "events": events })
+        } else {
+            let window = SampleFilter {
+                from: Some(filter.from.unwrap_or(first.ts)),
+                to: Some(filter.to.unwrap_or(last.ts + Duration::microseconds(1))),
+                ..filter
+            };
+            let (removed, events) =
+                events::replace_detected(&state.pool, SOURCE, channel, &window, &detected).await?;
+            json!({ "channel": channel, "removed": removed, "events": events })
+        };
+        results.push(body);
+    }
+
+    if results.len() == 1 {
+        return Ok(Json(results.remove(0)));
+    }
+    Ok(Json(json!({ "channels": results })))
+}
+
+/// What to do with samples inside artifact events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = ArtifactMode)]
+pub enum Mode {
+    /// Leave them out of the response.
+    Exclude,
+    /// Return them with `"artifact": true` (others get `false`).
+    Mark,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ArtifactQuery {
+    pub artifacts: Option<Mode>,
+}
+
+/// Artifact spans on `channel` overlapping the samples, as `[start, end)`.
+async fn spans(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+    samples: &[EegSample],
+) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>, sqlx::Error> {
+    let first = samples.iter().map(|s| s.ts).min();
+    let last = samples.iter().map(|s| s.ts).max();
+    let (Some(first), Some(last)) = (first, last) else {
+        return Ok(Vec::new());
+    };
+    let events = events::overlapping(pool, filter, first, last + Duration::microseconds(1)).await?;
+    Ok(events
+        .into_iter()
+        .filter(|e| e.label.starts_with(LABEL_PREFIX))
+        .filter(|e| {
+            e.metadata
+                .get("channel")
+                .and_then(|c| c.as_str())
+                .is_none_or(|c| c == channel)
+        })
+        .map(|e| {
+            let end = e.ts + Duration::microseconds((e.duration * 1e6) as i64);
+            (e.ts, end.max(e.ts + Duration::microseconds(1)))
+        })
+        .collect())
+}
+
+/// Applies `mode` to a channel's samples: drops those in artifact spans, or
+/// returns a flag per sample for [`to_json`].
+#[tracing::instrument(name = "artifacts", skip_all, fields(channel = channel, ?mode))]
+pub async fn apply(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+    samples: &mut Vec<EegSample>,
+    mode: Mode,
+) -> Result<Option<Vec<bool>>, ApiError> {
+    let spans = spans(pool, channel, filter, samples).await?;
+    let inside = |s: &EegSample| spans.iter().any(|(from, to)| s.ts >= *from && s.ts < *to);
+    Ok(match mode {
+        Mode::Exclude => {
+            samples.retain(|s| !inside(s));
+            None
+        }
+        Mode::Mark => Some(samples.iter().map(inside).collect()),
+    })
+}
+
+/// Samples as JSON, with `"artifact"` added when marked.
+pub fn to_json(samples: &[EegSample], marks: Option<&[bool]>) -> serde_json::Value {
+    let Some(marks) = marks else {
+        return json!(samples);
+    };
+    samples
+        .iter()
+        .zip(marks)
+        .map(|(s, artifact)| {
+            json!({
+                "id": s.id,
+                "ts": s.ts,
+                "channel": s.channel,
+                "value": s.value,
+                "artifact": artifact,
+            })
+        })
+        .collect()
+}
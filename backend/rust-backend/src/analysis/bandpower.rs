@@ -0,0 +1,299 @@
+//! `GET /analysis/bandpower`: absolute and relative power per EEG band.
+//!
+//! Band power is the Welch PSD (Hann segments of two seconds by default)
+//! summed over the bins of each band times the bin width. Relative power is
+//! a band's share of the power between the lowest and the highest band edge.
+
+use crate::aggregate::parse_width;
+use crate::dsp::spectrum::{Stft, Window};
+use crate::dsp::{channel_rate, segments};
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Valid, Validate};
+use crate::{fetch_window_samples, AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, QueryBuilder};
+use utoipa::IntoParams;
+
+pub(super) const DEFAULT_WINDOW: &str = "4s";
+
+/// Default segment length in seconds.
+pub(super) const SEGMENT_SECONDS: f64 = 2.0;
+
+/// Longest accepted `window`.
+pub(super) const MAX_WINDOW_SECONDS: f64 = 3600.0;
+
+/// Clinical bands in Hz, `low <= f < high`.
+const DEFAULT_BANDS: [(&str, f64, f64); 5] = [
+    ("delta", 1.0, 4.0),
+    ("theta", 4.0, 8.0),
+    ("alpha", 8.0, 13.0),
+    ("beta", 13.0, 30.0),
+    ("gamma", 30.0, 45.0),
+];
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BandpowerQuery {
+    /// Length of the analysed window, e.g. `4s` or `500ms`.
+    window: Option<String>,
+    /// Samples per Welch segment.
+    nperseg: Option<usize>,
+    /// `name:low-high,...` in Hz, replacing the default bands.
+    bands: Option<String>,
+}
+
+impl Validate for BandpowerQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Err(e) = parse_window(self.window.as_deref()) {
+            errors.add("window", e);
+        }
+        super::check_nperseg(errors, self.nperseg);
+        if let Some(Err(e)) = self.bands.as_deref().map(parse_bands) {
+            errors.add("bands", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Band {
+    pub name: String,
+    pub low: f64,
+    pub high: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct BandPower {
+    #[serde(flatten)]
+    band: Band,
+    /// unit².
+    absolute: f64,
+    /// Share of `total`, 0 to 1; null if there is no power at all.
+    relative: Option<f64>,
+}
+
+/// The default bands.
+pub(crate) fn default_bands() -> Vec<Band> {
+    DEFAULT_BANDS
+        .iter()
+        .map(|&(name, low, high)| Band {
+            name: name.to_string(),
+            low,
+            high,
+        })
+        .collect()
+}
+
+/// Parses `name:low-high` bands, or names of default bands, separated by
+/// commas.
+pub(crate) fn parse_bands(text: &str) -> Result<Vec<Band>, String> {
+    let mut bands: Vec<Band> = Vec::new();
+    for entry in text.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let invalid = || {
+            format!(
+                "invalid band {:?}, expected name:low-high or a default band name",
+                entry
+            )
+        };
+        let (name, range) = match entry.split_once(':') {
+            Some(parts) => parts,
+            None => {
+                let band = default_bands()
+                    .into_iter()
+                    .find(|b| b.name == entry)
+                    .ok_or_else(invalid)?;
+                if bands.iter().any(|b| b.name == band.name) {
+                    return Err(format!("band {:?} is given twice", band.name));
+                }
+                bands.push(band);
+                continue;
+            }
+        };
+        let (low, high) = range.split_once('-').ok_or_else(invalid)?;
+        let parse = |v: &str| v.trim().parse::<f64>().ok().filter(|f| f.is_finite());
+        let (Some(low), Some(high)) = (parse(low), parse(high)) else {
+            return Err(invalid());
+        };
+        let name = name.trim();
+        if name.is_empty() || !(0.0 <= low && low < high) {
+            return Err(invalid());
+        }
+        if bands.iter().any(|b| b.name == name) {
+            return Err(format!("band {:?} is given twice", name));
+        }
+        bands.push(Band {
+            name: name.to_string(),
+            low,
+            high,
+        });
+    }
+    if bands.is_empty() {
+        return Err("bands must name at least one band".to_string());
+    }
+    Ok(bands)
+}
+
+/// Timestamp of the channel's newest sample matching `filter`.
+async fn latest_ts(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let mut query = QueryBuilder::new("SELECT max(ts) FROM eeg_samples WHERE channel = ");
+    query.push_bind(channel);
+    filter.push_to(&mut query);
+    let (latest,): (Option<DateTime<Utc>>,) = query.build_query_as().fetch_one(pool).await?;
+    Ok(latest)
+}
+
+/// The analysed range: `from` / `to`, or the `window` starting at `from`
+/// or ending at `to`, or the latest `window` of `channel`; `None` if the
+/// channel has no samples.
+pub(super) async fn window_range(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+    window: Duration,
+) -> Result<Option<(DateTime<Utc>, DateTime<Utc>)>, ApiError> {
+    Ok(match (filter.from, filter.to) {
+        (Some(from), Some(to)) => Some((from, to)),
+        (Some(from), None) => Some((from, from + window)),
+        (None, Some(to)) => Some((to - window, to)),
+        (None, None) => latest_ts(pool, channel, filter).await?.map(|last| {
+            let to = last + Duration::microseconds(1);
+            (to - window, to)
+        }),
+    })
+}
+
+/// Parses `window`, by default [`DEFAULT_WINDOW`].
+pub(super) fn parse_window(window: Option<&str>) -> Result<Duration, String> {
+    let seconds = parse_width(window.unwrap_or(DEFAULT_WINDOW))?;
+    if seconds > MAX_WINDOW_SECONDS {
+        return Err(format!("must be at most {}s", MAX_WINDOW_SECONDS));
+    }
+    Ok(Duration::microseconds((seconds * 1e6) as i64))
+}
+
+/// Band power of each channel over `from` / `to`, or the `window` before
+/// whichever bound is given, or the latest `window` of samples.
+#[utoipa::path(
+    get,
+    path = "/analysis/bandpower",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        BandpowerQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ channels: [{ channel, sample_rate, from, to, samples, segments, resolution, total, bands: [{ name, low, high, absolute, relative }] }] }`", body = serde_json::Value),
+        (status = 400, description = "A channel without samples, or without a gap-free run holding a segment"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_bandpower(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<BandpowerQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let window = parse_window(params.window.as_deref()).map_err(ApiError::internal)?;
+    let bands = match params.bands.as_deref() {
+        Some(text) => parse_bands(text).map_err(ApiError::internal)?,
+        None => default_bands(),
+    };
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let range = window_range(state.reader(), channel, &filter, window).await?;
+        let no_data = || {
+            bad_request(format!(
+                "channel {:?} has no samples in the window",
+                channel
+            ))
+        };
+        let (from, to) = range.ok_or_else(no_data)?;
+        let window_filter = SampleFilter {
+            from: Some(from),
+            to: Some(to),
+            ..filter
+        };
+        let samples = fetch_window_samples(state.reader(), channel, &window_filter).await?;
+        let rate = channel_rate(state.reader(), channel, &samples)
+            .await?
+            .ok_or_else(no_data)?;
+
+        let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+        let runs: Vec<&[f64]> = segments(&samples, rate)
+            .into_iter()
+            .map(|r| &values[r])
+            .collect();
+        let longest = runs.iter().map(|r| r.len()).max().unwrap_or(0);
+        let nperseg = params
+            .nperseg
+            .unwrap_or_else(|| ((SEGMENT_SECONDS * rate).round() as usize).min(longest));
+        let stft = Stft::new(
+            Window::Hann,
+            nperseg,
+            nperseg / 2,
+            nperseg.next_power_of_two(),
+        )
+        .map_err(bad_request)?;
+        let (psd, count) = stft.welch(&runs, rate).ok_or_else(|| {
+            bad_request(format!(
+                "channel {:?} has no run of {} samples without gaps in the window",
+                channel, nperseg
+            ))
+        })?;
+
+        let frequencies = stft.frequencies(rate);
+        let resolution = rate / stft.nfft() as f64;
+        let power = |low: f64, high: f64| {
+            frequencies
+                .iter()
+                .zip(&psd)
+                .filter(|(f, _)| **f >= low && **f < high)
+                .map(|(_, p)| p * resolution)
+                .sum::<f64>()
+        };
+        let low = bands.iter().map(|b| b.low).fold(f64::INFINITY, f64::min);
+        let high = bands.iter().map(|b| b.high).fold(0.0, f64::max);
+        let total = power(low, high);
+        let powers: Vec<BandPower> = bands
+            .iter()
+            .map(|band| {
+                let absolute = power(band.low, band.high);
+                BandPower {
+                    band: band.clone(),
+                    absolute,
+                    relative: (total > 0.0).then(|| absolute / total),
+                }
+            })
+            .collect();
+
+        results.push(json!({
+            "channel": channel,
+            "sample_rate": rate,
+            "from": from,
+            "to": to,
+            "samples": samples.len(),
+            "segments": count,
+            "resolution": resolution,
+            "total": total,
+            "bands": powers,
+        }));
+    }
+
+    if results.len() == 1 {
+        return Ok(Json(results.remove(0)));
+    }
+    Ok(Json(json!({ "channels": results })))
+}
@@ -0,0 +1,235 @@
+//! `GET /analysis/coherence`: coherence between each pair of channels.
+//!
+//! The cross and auto spectra of the two channels are averaged over Welch
+//! segments (Hann, two seconds by default) of the samples both channels have
+//! at the same timestamps. Per bin, the coherency is `Sxy / sqrt(Sxx * Syy)`:
+//! its squared magnitude is the magnitude-squared coherence, and its
+//! imaginary part the imaginary coherence, which ignores zero-lag coupling
+//! such as volume conduction. Band values are means over the band's bins.
+
+use super::bandpower::{default_bands, parse_bands, parse_window, window_range, SEGMENT_SECONDS};
+use crate::dsp::spectrum::{Stft, Window};
+use crate::dsp::{channel_rate, segments};
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Valid, Validate};
+use crate::{fetch_window_samples, AppState, ChannelQuery, EegSample, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::IntoParams;
+
+/// Most channels per request; `n` channels give `n * (n - 1) / 2` pairs.
+const MAX_CHANNELS: usize = 16;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CoherenceQuery {
+    /// Length of the analysed window, e.g. `4s` or `500ms`.
+    window: Option<String>,
+    /// Samples per Welch segment.
+    nperseg: Option<usize>,
+    /// Band names or `name:low-high,...` in Hz; all default bands otherwise.
+    band: Option<String>,
+    /// Also return the imaginary coherence.
+    #[serde(default)]
+    imaginary: bool,
+}
+
+impl Validate for CoherenceQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Err(e) = parse_window(self.window.as_deref()) {
+            errors.add("window", e);
+        }
+        super::check_nperseg(errors, self.nperseg);
+        if let Some(Err(e)) = self.band.as_deref().map(parse_bands) {
+            errors.add("band", e);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BandCoherence {
+    name: String,
+    low: f64,
+    high: f64,
+    /// Mean magnitude-squared coherence, 0 to 1; null without bins with power.
+    coherence: Option<f64>,
+    /// Mean imaginary coherence, -1 to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imaginary: Option<Option<f64>>,
+}
+
+/// The samples of `a` with a sample of `b` at the same timestamp, and the
+/// values of those samples of `b`.
+fn align(a: &[EegSample], b: &[EegSample]) -> (Vec<EegSample>, Vec<f64>) {
+    let (mut aligned, mut values) = (Vec::new(), Vec::new());
+    let mut j = 0;
+    for sample in a {
+        while j < b.len() && b[j].ts < sample.ts {
+            j += 1;
+        }
+        if j < b.len() && b[j].ts == sample.ts {
+            aligned.push(EegSample {
+                id: sample.id,
+                ts: sample.ts,
+                channel: String::new(),
+                value: sample.value,
+            });
+            values.push(b[j].value);
+            j += 1;
+        }
+    }
+    (aligned, values)
+}
+
+/// Coherence of every pair of the requested channels over `from` / `to`,
+/// or the `window` before whichever bound is given, or the latest `window`
+/// of the first channel.
+#[utoipa::path(
+    get,
+    path = "/analysis/coherence",
+    tag = "analysis",
+    params(
+        ("channel" = Vec<String>, Query, description = "At least two channels, comma-separated or repeated"),
+        CoherenceQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ from, to, pairs: [{ channels, sample_rate, samples, segments, resolution, bands: [{ name, low, high, coherence, imaginary }] }] }`", body = serde_json::Value),
+        (status = 400, description = "Fewer than two or more than 16 channels, or a channel without samples"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_coherence(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<CoherenceQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let mut channels: Vec<String> = Vec::new();
+    for name in raw
+        .values("channels")
+        .into_iter()
+        .chain(raw.values("channel"))
+    {
+        if !channels.iter().any(|c| c == name) {
+            channels.push(name.to_string());
+        }
+    }
+    if channels.len() < 2 || channels.len() > MAX_CHANNELS {
+        return Err(bad_request(format!(
+            "channels must list 2 to {} channels",
+            MAX_CHANNELS
+        )));
+    }
+    let window = parse_window(params.window.as_deref()).map_err(ApiError::internal)?;
+    let bands = match params.band.as_deref() {
+        Some(text) => parse_bands(text).map_err(ApiError::internal)?,
+        None => default_bands(),
+    };
+
+    let no_data = |channel: &str| {
+        bad_request(format!(
+            "channel {:?} has no samples in the window",
+            channel
+        ))
+    };
+    let (from, to) = window_range(state.reader(), &channels[0], &filter, window)
+        .await?
+        .ok_or_else(|| no_data(&channels[0]))?;
+    let window_filter = SampleFilter {
+        from: Some(from),
+        to: Some(to),
+        ..filter
+    };
+    let mut signals = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let samples = fetch_window_samples(state.reader(), channel, &window_filter).await?;
+        let rate = channel_rate(state.reader(), channel, &samples)
+            .await?
+            .ok_or_else(|| no_data(channel))?;
+        signals.push((samples, rate));
+    }
+
+    let mut results = Vec::new();
+    for i in 0..channels.len() {
+        for j in i + 1..channels.len() {
+            let ((a, rate), (b, other_rate)) = (&signals[i], &signals[j]);
+            if rate != other_rate {
+                return Err(bad_request(format!(
+                    "channels {:?} and {:?} have different sample rates ({} and {} Hz)",
+                    channels[i], channels[j], rate, other_rate
+                )));
+            }
+            let rate = *rate;
+            let (aligned, other) = align(a, b);
+            let values: Vec<f64> = aligned.iter().map(|s| s.value).collect();
+            let runs: Vec<(&[f64], &[f64])> = segments(&aligned, rate)
+                .into_iter()
+                .map(|r| (&values[r.clone()], &other[r]))
+                .collect();
+            let longest = runs.iter().map(|(r, _)| r.len()).max().unwrap_or(0);
+            let nperseg = params
+                .nperseg
+                .unwrap_or_else(|| ((SEGMENT_SECONDS * rate).round() as usize).min(longest / 2));
+            let stft = Stft::new(
+                Window::Hann,
+                nperseg,
+                nperseg / 2,
+                nperseg.next_power_of_two(),
+            )
+            .map_err(bad_request)?;
+            let (coherency, count) = match stft.coherency(&runs) {
+                Some((coherency, count)) if count >= 2 => (coherency, count),
+                _ => {
+                    return Err(bad_request(format!(
+                        "channels {:?} and {:?} share fewer than 2 segments of {} samples \
+                         without gaps in the window",
+                        channels[i], channels[j], nperseg
+                    )))
+                }
+            };
+
+            let frequencies = stft.frequencies(rate);
+            let mean = |low: f64, high: f64, value: fn((f64, f64)) -> f64| {
+                let values: Vec<f64> = frequencies
+                    .iter()
+                    .zip(&coherency)
+                    .filter(|(f, _)| **f >= low && **f < high)
+                    .filter_map(|(_, c)| c.map(value))
+                    .collect();
+                (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+            };
+            let bands: Vec<BandCoherence> = bands
+                .iter()
+                .map(|band| BandCoherence {
+                    name: band.name.clone(),
+                    low: band.low,
+                    high: band.high,
+                    coherence: mean(band.low, band.high, |(re, im)| re * re + im * im),
+                    imaginary: params
+                        .imaginary
+                        .then(|| mean(band.low, band.high, |(_, im)| im)),
+                })
+                .collect();
+            results.push(json!({
+                "channels": [channels[i], channels[j]],
+                "sample_rate": rate,
+                "samples": aligned.len(),
+                "segments": count,
+                "resolution": rate / stft.nfft() as f64,
+                "bands": bands,
+            }));
+        }
+    }
+
+    Ok(Json(json!({
+        "from": from,
+        "to": to,
+        "pairs": results,
+    })))
+}
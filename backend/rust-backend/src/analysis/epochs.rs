@@ -0,0 +1,477 @@
+//! Epoching: cutting continuous data into trials (`GET /analysis/epochs`,
+//! [`/analysis/erp`](super::erp) and stored [epoch sets](crate::epoch_sets)).
+//!
+//! Epochs are either locked to events (`event_label`, the onsets), spanning
+//! `pre` before and `post` after each onset, or cut back to back from the
+//! window (`length`, every `step`). An epoch is aligned on the stored sample
+//! nearest its onset (within half a sample period) and cut only from runs
+//! without gaps; the mean of the `baseline` before the onset is subtracted,
+//! and epochs whose peak-to-peak amplitude is above `reject` or below `flat`
+//! on any channel are rejected.
+
+use crate::aggregate::parse_width;
+use crate::dsp::channel_rate;
+use crate::error::ApiError;
+use crate::store::blocks;
+use crate::validation::{Parsed, Valid};
+use crate::{
+    config, events, fetch_window_samples, AppState, ChannelQuery, EegSample, SampleFilter,
+};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::QueryBuilder;
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_PRE: &str = "200ms";
+const DEFAULT_POST: &str = "800ms";
+
+/// Longest accepted epoch, in seconds.
+const MAX_EPOCH_SECONDS: f64 = 60.0;
+
+/// Most onsets per request.
+const MAX_EPOCHS: usize = 10_000;
+
+/// Query parameters shared by the epoching endpoints.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EpochQuery {
+    /// Comma-separated labels of the events to lock epochs to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_label: Option<String>,
+    /// Epoch start before each onset, e.g. `200ms`, or `0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pre: Option<String>,
+    /// Epoch end after each onset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post: Option<String>,
+    /// Interval before the onset whose mean is subtracted, or `none`; `pre`
+    /// by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    baseline: Option<String>,
+    /// Largest peak-to-peak amplitude of a kept epoch, after baseline
+    /// correction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reject: Option<f64>,
+    /// Smallest peak-to-peak amplitude of a kept epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flat: Option<f64>,
+    /// Length of fixed-length epochs, instead of event-locked ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<String>,
+    /// Offset between fixed-length epochs; `length` by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<String>,
+}
+
+/// Where epochs start.
+#[derive(Debug, Clone)]
+pub enum Layout {
+    /// Around the events with one of these labels.
+    Events(Vec<String>),
+    /// Back to back over the window, `step` seconds apart.
+    Fixed { step: f64 },
+}
+
+/// Epoch span and rejection criteria, in seconds relative to the onset.
+#[derive(Debug, Clone)]
+pub struct EpochOptions {
+    pub layout: Layout,
+    pub pre: f64,
+    /// Events: last sample after the onset. Fixed: the epoch length.
+    pub post: f64,
+    pub baseline: Option<f64>,
+    pub reject: Option<f64>,
+    pub flat: Option<f64>,
+}
+
+/// Parses a non-negative duration; `0` is accepted.
+fn parse_offset(text: &str) -> Result<f64, String> {
+    match text.trim() {
+        "0" => Ok(0.0),
+        text => parse_width(text),
+    }
+}
+
+impl EpochQuery {
+    pub fn options(&self) -> Result<EpochOptions, String> {
+        let amplitude = |name: &str, value: Option<f64>| match value {
+            Some(v) if !(v.is_finite() && v > 0.0) => {
+                Err(format!("{} must be a positive amplitude", name))
+            }
+            _ => Ok(value),
+        };
+        let reject = amplitude("reject", self.reject)?;
+        let flat = amplitude("flat", self.flat)?;
+
+        let (layout, pre, post) = match (&self.event_label, &self.length) {
+            (Some(_), Some(_)) => {
+                return Err("give either event_label or length, not both".to_string())
+            }
+            (None, None) => return Err("event_label or length is required".to_string()),
+            (Some(labels), None) => {
+                let labels: Vec<String> = labels
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if labels.is_empty() {
+                    return Err("event_label must name at least one label".to_string());
+                }
+                let pre = parse_offset(self.pre.as_deref().unwrap_or(DEFAULT_PRE))?;
+                let post = parse_width(self.post.as_deref().unwrap_or(DEFAULT_POST))?;
+                (Layout::Events(labels), pre, post)
+            }
+            (None, Some(length)) => {
+                if self.pre.is_some() || self.post.is_some() || self.baseline.is_some() {
+                    return Err(
+                        "pre, post and baseline apply to event-locked epochs only".to_string()
+                    );
+                }
+                let length = parse_width(length)?;
+                let step = match self.step.as_deref() {
+                    Some(step) => parse_width(step)?,
+                    None => length,
+                };
+                (Layout::Fixed { step }, 0.0, length)
+            }
+        };
+        if pre + post > MAX_EPOCH_SECONDS {
+            return Err(format!("epochs must span at most {}s", MAX_EPOCH_SECONDS));
+        }
+        let baseline = match self.baseline.as_deref().map(str::trim) {
+            Some("none") => None,
+            Some(text) => Some(parse_width(text)?),
+            None => (pre > 0.0).then_some(pre),
+        };
+        if baseline.is_some_and(|b| b > pre) {
+            return Err("baseline must not be longer than pre".to_string());
+        }
+        Ok(EpochOptions {
+            layout,
+            pre,
+            post,
+            baseline,
+            reject,
+            flat,
+        })
+    }
+}
+
+impl EpochOptions {
+    /// Samples before and after the onset sample at `rate`.
+    fn lengths(&self, rate: f64) -> (usize, usize) {
+        let before = (self.pre * rate).round() as usize;
+        let after = match self.layout {
+            Layout::Events(_) => (self.post * rate).round() as usize,
+            Layout::Fixed { .. } => ((self.post * rate).round() as usize).max(1) - 1,
+        };
+        (before, after)
+    }
+
+    /// Offset of each epoch sample from the onset, in seconds.
+    pub fn times(&self, rate: f64) -> Vec<f64> {
+        let (before, after) = self.lengths(rate);
+        (0..=before + after)
+            .map(|k| (k as f64 - before as f64) / rate)
+            .collect()
+    }
+
+    /// Peak-to-peak amplitude of `values` within `reject` and `flat`.
+    fn accepts(&self, values: &[f64]) -> bool {
+        let (low, high) = values
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        let range = high - low;
+        self.reject.is_none_or(|r| range <= r) && self.flat.is_none_or(|f| range >= f)
+    }
+
+    /// The baseline-corrected epoch of `samples` (oldest first) around
+    /// `onset`; `None` if the data do not cover it without gaps or it is
+    /// rejected.
+    pub fn epoch(
+        &self,
+        samples: &[EegSample],
+        rate: f64,
+        onset: DateTime<Utc>,
+    ) -> Option<Vec<f64>> {
+        let (before, after) = self.lengths(rate);
+        let half_period_us = (0.5e6 / rate) as i64;
+        let max_gap_us = (2e6 / rate) as i64;
+        let distance = |i: usize| {
+            (samples[i].ts - onset)
+                .num_microseconds()
+                .map_or(i64::MAX, i64::abs)
+        };
+        let next = samples.partition_point(|s| s.ts < onset);
+        let nearest = [next.checked_sub(1), (next < samples.len()).then_some(next)]
+            .into_iter()
+            .flatten()
+            .min_by_key(|&i| distance(i))?;
+        if distance(nearest) > half_period_us {
+            return None;
+        }
+        let start = nearest.checked_sub(before)?;
+        let end = nearest + after + 1;
+        if end > samples.len() {
+            return None;
+        }
+        let samples = &samples[start..end];
+        let continuous = samples.windows(2).all(|w| {
+            (w[1].ts - w[0].ts)
+                .num_microseconds()
+                .is_some_and(|dt| dt <= max_gap_us)
+        });
+        if !continuous {
+            return None;
+        }
+
+        let mut values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+        if let (Some(baseline), true) = (self.baseline, before > 0) {
+            let count = ((baseline * rate).round() as usize).clamp(1, before);
+            let mean = values[before - count..before].iter().sum::<f64>() / count as f64;
+            for v in &mut values {
+                *v -= mean;
+            }
+        }
+        self.accepts(&values).then_some(values)
+    }
+}
+
+/// An epoch onset and the label of its event.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Onset {
+    pub onset: DateTime<Utc>,
+    pub label: Option<String>,
+}
+
+/// First and last sample of `channel` within `filter`, in rows or blocks.
+async fn span(
+    state: &AppState,
+    channel: &str,
+    filter: &SampleFilter,
+) -> Result<Option<(DateTime<Utc>, DateTime<Utc>)>, ApiError> {
+    let mut query = QueryBuilder::new("SELECT min(ts), max(ts) FROM eeg_samples WHERE channel = ");
+    query.push_bind(channel);
+    filter.push_to(&mut query);
+    let (first, last): (Option<DateTime<Utc>>, Option<DateTime<Utc>>) =
+        query.build_query_as().fetch_one(state.reader()).await?;
+    let packed = blocks::span(state.reader(), Some(&[channel.to_string()]), filter).await?;
+    Ok(match (first.zip(last), packed) {
+        (Some((first, last)), Some((packed_first, packed_last))) => {
+            Some((first.min(packed_first), last.max(packed_last)))
+        }
+        (rows, packed) => rows.or(packed),
+    })
+}
+
+/// The onsets of `options`' epochs: the matching events within the scope of
+/// `filter`, or fixed-length epochs from `from` to `to` (by default the span
+/// of `channel`'s samples).
+pub async fn onsets(
+    state: &AppState,
+    channel: &str,
+    filter: &SampleFilter,
+    options: &EpochOptions,
+) -> Result<Vec<Onset>, ApiError> {
+    let too_many = || {
+        ApiError::bad_request(format!(
+            "more than {} epochs; narrow from / to or the session",
+            MAX_EPOCHS
+        ))
+    };
+    match &options.layout {
+        Layout::Events(labels) => {
+            let events =
+                events::labelled(state.reader(), labels, filter, MAX_EPOCHS as i64 + 1).await?;
+            if events.len() > MAX_EPOCHS {
+                return Err(too_many());
+            }
+            Ok(events
+                .into_iter()
+                .map(|e| Onset {
+                    onset: e.ts,
+                    label: Some(e.label),
+                })
+                .collect())
+        }
+        Layout::Fixed { step } => {
+            let (first, last) = match (filter.from, filter.to) {
+                (Some(from), Some(to)) => (from, to),
+                _ => match span(state, channel, filter).await? {
+                    // `to` is exclusive; the last sample is included.
+                    Some((first, last)) => (
+                        filter.from.unwrap_or(first),
+                        filter.to.unwrap_or(last + Duration::microseconds(1)),
+                    ),
+                    None => return Ok(Vec::new()),
+                },
+            };
+            let length = Duration::microseconds((options.post * 1e6) as i64);
+            let step = Duration::microseconds((step * 1e6) as i64);
+            let mut onsets = Vec::new();
+            let mut onset = first;
+            while onset + length <= last {
+                if onsets.len() == MAX_EPOCHS {
+                    return Err(too_many());
+                }
+                onsets.push(Onset { onset, label: None });
+                onset += step;
+            }
+            Ok(onsets)
+        }
+    }
+}
+
+/// The stored samples of `channel` covering every epoch around `onsets`
+/// (in time order), and the channel's sample rate; `None` without samples.
+pub async fn epoch_samples(
+    state: &AppState,
+    channel: &str,
+    filter: &SampleFilter,
+    onsets: &[Onset],
+    options: &EpochOptions,
+) -> Result<Option<(Vec<EegSample>, f64)>, ApiError> {
+    let (Some(first), Some(last)) = (onsets.first(), onsets.last()) else {
+        return Ok(None);
+    };
+    // A second of margin on each side for the nearest-sample match.
+    let margin = |seconds: f64| Duration::microseconds((seconds * 1e6) as i64 + 1_000_000);
+    let window = SampleFilter {
+        from: Some(first.onset - margin(options.pre)),
+        to: Some(last.onset + margin(options.post)),
+        ..*filter
+    };
+    let samples = fetch_window_samples(state.reader(), channel, &window).await?;
+    let rate = channel_rate(state.reader(), channel, &samples).await?;
+    Ok(rate.map(|rate| (samples, rate)))
+}
+
+/// Multichannel epochs with a shared time axis.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Epochs {
+    pub channels: Vec<String>,
+    pub sample_rate: Option<f64>,
+    /// Seconds from the onset of each epoch sample.
+    pub times: Vec<f64>,
+    /// Onsets considered.
+    pub candidates: usize,
+    /// Onsets without a complete epoch on every channel, or rejected.
+    pub rejected: usize,
+    pub epochs: Vec<Epoch>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Epoch {
+    #[serde(flatten)]
+    pub onset: Onset,
+    /// `data[channel][sample]`.
+    pub data: Vec<Vec<f64>>,
+}
+
+/// Cuts the epochs of `options` from `channels`, which must share a sample
+/// rate. `from` / `to`, `session_id` and `subject_id` select the events (or
+/// the window of fixed-length epochs) and the samples.
+pub async fn extract(
+    state: &AppState,
+    channels: Vec<String>,
+    filter: &SampleFilter,
+    options: &EpochOptions,
+) -> Result<Epochs, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let onsets = onsets(state, &channels[0], filter, options).await?;
+    let sample_filter = SampleFilter {
+        from: None,
+        to: None,
+        ..*filter
+    };
+
+    let mut signals = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        match epoch_samples(state, channel, &sample_filter, &onsets, options).await? {
+            Some(signal) => signals.push(signal),
+            None => {
+                return Ok(Epochs {
+                    channels,
+                    sample_rate: None,
+                    times: Vec::new(),
+                    candidates: onsets.len(),
+                    rejected: onsets.len(),
+                    epochs: Vec::new(),
+                })
+            }
+        }
+    }
+    let rate = signals[0].1;
+    if let Some(i) = signals.iter().position(|(_, r)| *r != rate) {
+        return Err(bad_request(format!(
+            "channels {:?} and {:?} have different sample rates ({} and {} Hz)",
+            channels[0], channels[i], rate, signals[i].1
+        )));
+    }
+    let times = options.times(rate);
+    let values = onsets.len() * channels.len() * times.len();
+    if values as i64 > config::get().limits.max_window_rows {
+        return Err(bad_request(format!(
+            "{} epochs of {} channels and {} samples exceed {} values; narrow the window",
+            onsets.len(),
+            channels.len(),
+            times.len(),
+            config::get().limits.max_window_rows
+        )));
+    }
+
+    let candidates = onsets.len();
+    let epochs: Vec<Epoch> = onsets
+        .into_iter()
+        .filter_map(|onset| {
+            let data = signals
+                .iter()
+                .map(|(samples, _)| options.epoch(samples, rate, onset.onset))
+                .collect::<Option<Vec<_>>>()?;
+            Some(Epoch { onset, data })
+        })
+        .collect();
+    Ok(Epochs {
+        channels,
+        sample_rate: Some(rate),
+        times,
+        candidates,
+        rejected: candidates - epochs.len(),
+        epochs,
+    })
+}
+
+/// Epochs of the requested channels as arrays.
+#[utoipa::path(
+    get,
+    path = "/analysis/epochs",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        EpochQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, body = Epochs),
+        (status = 400, description = "Invalid parameters, or channels with different sample rates"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_epochs(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<EpochQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<Epochs>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let options = params.options().map_err(bad_request)?;
+    extract(&state, channels, &filter, &options).await.map(Json)
+}
@@ -0,0 +1,103 @@
+//! `GET /analysis/erp`: event-related potentials averaged over events.
+//!
+//! Epochs are cut around each event as by [`/analysis/epochs`](super::epochs),
+//! per channel; the response is the sample-wise mean of a channel's kept
+//! epochs.
+
+use super::epochs::{epoch_samples, onsets, EpochQuery};
+use crate::error::ApiError;
+use crate::validation::{Parsed, Valid};
+use crate::{AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde_json::json;
+
+/// Average evoked response of each channel to the events labelled
+/// `event_label`; `from` / `to`, `session_id` and `subject_id` select the
+/// events and the samples.
+#[utoipa::path(
+    get,
+    path = "/analysis/erp",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        EpochQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ event_label, events, pre, post, baseline, channels: [{ channel, sample_rate, trials, rejected, times, erp }] }`", body = serde_json::Value),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_erp(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<EpochQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    if params.event_label.is_none() {
+        return Err(bad_request("event_label is required".to_string()));
+    }
+    let options = params.options().map_err(bad_request)?;
+    let onsets = onsets(&state, &channels[0], &filter, &options).await?;
+    let sample_filter = SampleFilter {
+        from: None,
+        to: None,
+        ..filter
+    };
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let Some((samples, rate)) =
+            epoch_samples(&state, channel, &sample_filter, &onsets, &options).await?
+        else {
+            results.push(json!({
+                "channel": channel,
+                "sample_rate": null,
+                "trials": 0,
+                "rejected": onsets.len(),
+                "times": [],
+                "erp": [],
+            }));
+            continue;
+        };
+        let times = options.times(rate);
+        let mut sum = vec![0.0; times.len()];
+        let mut trials = 0;
+        for onset in &onsets {
+            if let Some(values) = options.epoch(&samples, rate, onset.onset) {
+                for (s, v) in sum.iter_mut().zip(values) {
+                    *s += v;
+                }
+                trials += 1;
+            }
+        }
+        let erp: Vec<f64> = if trials == 0 {
+            Vec::new()
+        } else {
+            sum.iter().map(|s| s / trials as f64).collect()
+        };
+        results.push(json!({
+            "channel": channel,
+            "sample_rate": rate,
+            "trials": trials,
+            "rejected": onsets.len() - trials,
+            "times": if trials == 0 { Vec::new() } else { times },
+            "erp": erp,
+        }));
+    }
+
+    Ok(Json(json!({
+        "event_label": params.event_label,
+        "events": onsets.len(),
+        "pre": options.pre,
+        "post": options.post,
+        "baseline": options.baseline,
+        "channels": results,
+    })))
+}
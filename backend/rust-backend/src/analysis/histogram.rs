@@ -0,0 +1,205 @@
+//! `GET /analysis/histogram`: the distribution of sample values.
+//!
+//! Counted in the database, as [`crate::analysis::stats`] is, samples packed
+//! into [blocks](crate::store::blocks) as they are read. Bins are of
+//! equal width between `min` and `max`, the range of the values unless
+//! given; the last bin includes `max`. A pile-up in the outer bins points
+//! at clipping, two peaks at bimodal noise.
+
+use crate::error::ApiError;
+use crate::store::blocks;
+use crate::validation::{FieldErrors, Valid, Validate};
+use crate::{AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::QueryBuilder;
+use utoipa::IntoParams;
+
+const DEFAULT_BINS: i32 = 50;
+
+/// Upper bound on `bins`.
+const MAX_BINS: i32 = 1000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistogramQuery {
+    /// Number of bins, 1 to 1000; 50 by default.
+    bins: Option<i32>,
+    /// Lower edge of the first bin; the smallest value by default.
+    min: Option<f64>,
+    /// Upper edge of the last bin; the largest value by default.
+    max: Option<f64>,
+}
+
+impl Validate for HistogramQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(bins) = self.bins {
+            errors.check(
+                (1..=MAX_BINS).contains(&bins),
+                "bins",
+                format!("must be 1 to {}", MAX_BINS),
+            );
+        }
+        for (name, value) in [("min", self.min), ("max", self.max)] {
+            errors.check(value.is_none_or(f64::is_finite), name, "must be finite");
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            errors.check(min < max, "max", "must be above min");
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Bin {
+    /// Lower and upper edge.
+    start: f64,
+    end: f64,
+    count: i64,
+}
+
+/// The histogram of each channel's filtered samples.
+#[utoipa::path(
+    get,
+    path = "/analysis/histogram",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        HistogramQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ channel, count, min, max, bin_width, below, above, bins: [{ start, end, count }] }`", body = serde_json::Value),
+        (status = 400, description = "A channel without samples in the window, or a range without them"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_histogram(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<HistogramQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let bins = params.bins.unwrap_or(DEFAULT_BINS);
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let mut query = QueryBuilder::new(
+            "SELECT COUNT(*), MIN(value), MAX(value) FROM eeg_samples WHERE channel = ",
+        );
+        query.push_bind(channel);
+        filter.push_to(&mut query);
+        let (mut count, mut lowest, mut highest): (i64, Option<f64>, Option<f64>) =
+            query.build_query_as().fetch_one(state.reader()).await?;
+        let packed = [channel.clone()];
+        blocks::for_each(state.reader(), Some(&packed), &filter, |s| {
+            count += 1;
+            lowest = Some(lowest.map_or(s.value, |v| v.min(s.value)));
+            highest = Some(highest.map_or(s.value, |v| v.max(s.value)));
+        })
+        .await?;
+        let (Some(lowest), Some(highest)) = (lowest, highest) else {
+            return Err(bad_request(format!(
+                "channel {:?} has no samples in the window",
+                channel
+            )));
+        };
+        let (mut min, mut max) = (params.min.unwrap_or(lowest), params.max.unwrap_or(highest));
+        if min >= max {
+            if params.min.is_some() || params.max.is_some() {
+                return Err(bad_request(format!(
+                    "channel {:?} has no samples between min and max",
+                    channel
+                )));
+            }
+            // A constant channel: one value in the middle of the range.
+            (min, max) = (min - 0.5, max + 0.5);
+        }
+
+        // Bucket 0 is below `min`, `bins + 1` above `max`.
+        let mut query = QueryBuilder::new("SELECT CASE WHEN value = ");
+        query
+            .push_bind(max)
+            .push(" THEN ")
+            .push_bind(bins)
+            .push(" ELSE width_bucket(value, ")
+            .push_bind(min)
+            .push(", ")
+            .push_bind(max)
+            .push(", ")
+            .push_bind(bins)
+            .push(") END AS bin, COUNT(*) FROM eeg_samples WHERE channel = ")
+            .push_bind(channel);
+        filter.push_to(&mut query);
+        query.push(" GROUP BY 1");
+        let rows: Vec<(i32, i64)> = query.build_query_as().fetch_all(state.reader()).await?;
+
+        let mut counts = vec![0i64; bins as usize + 2];
+        for (bin, n) in rows {
+            counts[bin as usize] = n;
+        }
+        blocks::for_each(state.reader(), Some(&packed), &filter, |s| {
+            counts[bin_of(s.value, min, max, bins)] += 1;
+        })
+        .await?;
+        let width = (max - min) / bins as f64;
+        let histogram: Vec<Bin> = (1..=bins as usize)
+            .map(|i| Bin {
+                start: min + width * (i - 1) as f64,
+                end: if i == bins as usize {
+                    max
+                } else {
+                    min + width * i as f64
+                },
+                count: counts[i],
+            })
+            .collect();
+
+        results.push(json!({
+            "channel": channel,
+            "count": count,
+            "min": min,
+            "max": max,
+            "bin_width": width,
+            "below": counts[0],
+            "above": counts[bins as usize + 1],
+            "bins": histogram,
+        }));
+    }
+
+    if results.len() == 1 {
+        return Ok(Json(results.remove(0)));
+    }
+    Ok(Json(json!({ "channels": results })))
+}
+
+/// The bin of `value` as the query above puts it: 0 below `min`, `bins + 1`
+/// above `max`, and the last bin at `max`.
+fn bin_of(value: f64, min: f64, max: f64, bins: i32) -> usize {
+    if value < min {
+        0
+    } else if value > max {
+        bins as usize + 1
+    } else {
+        (((value - min) / (max - min) * bins as f64).floor() as usize + 1).min(bins as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bins_match_width_bucket() {
+        assert_eq!(bin_of(-0.1, 0.0, 10.0, 5), 0);
+        assert_eq!(bin_of(0.0, 0.0, 10.0, 5), 1);
+        assert_eq!(bin_of(3.9, 0.0, 10.0, 5), 2);
+        assert_eq!(bin_of(10.0, 0.0, 10.0, 5), 5);
+        assert_eq!(bin_of(10.1, 0.0, 10.0, 5), 6);
+    }
+}
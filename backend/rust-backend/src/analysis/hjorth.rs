@@ -0,0 +1,172 @@
+//! `GET /analysis/hjorth`: Hjorth parameters over sliding windows.
+//!
+//! For a window `x` with derivatives `x'` and `x''` (first differences
+//! times the sample rate): activity is `var(x)`, mobility is
+//! `sqrt(var(x') / var(x))` (in 1/s, about 2π times the mean frequency) and
+//! complexity is the mobility of `x'` over that of `x` (1 for a pure sine).
+
+use crate::aggregate::parse_width;
+use crate::dsp::{channel_rate, segments};
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Valid, Validate};
+use crate::{fetch_window_samples, AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::IntoParams;
+
+const DEFAULT_WINDOW: &str = "2s";
+
+/// Upper bound on windows returned per channel.
+const MAX_WINDOWS: usize = 100_000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HjorthQuery {
+    /// Window length, e.g. `2s` or `500ms`.
+    window: Option<String>,
+    /// Offset between window starts; the window length by default.
+    step: Option<String>,
+}
+
+impl Validate for HjorthQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        for (field, value) in [("window", &self.window), ("step", &self.step)] {
+            if let Some(Err(e)) = value.as_deref().map(parse_width) {
+                errors.add(field, e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Parameters {
+    /// First and last sample of the window.
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// unit².
+    activity: f64,
+    /// 1/s; null for a constant window.
+    mobility: Option<f64>,
+    /// Null for a constant window or derivative.
+    complexity: Option<f64>,
+}
+
+fn variance(values: &[f64]) -> f64 {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64
+}
+
+fn derivative(values: &[f64], rate: f64) -> Vec<f64> {
+    values.windows(2).map(|w| (w[1] - w[0]) * rate).collect()
+}
+
+/// Activity, mobility and complexity of one window of at least 3 values.
+fn hjorth(values: &[f64], rate: f64) -> (f64, Option<f64>, Option<f64>) {
+    let first = derivative(values, rate);
+    let second = derivative(&first, rate);
+    let (activity, slope, curvature) = (variance(values), variance(&first), variance(&second));
+    let mobility = (activity > 0.0).then(|| (slope / activity).sqrt());
+    let complexity = mobility
+        .filter(|_| slope > 0.0)
+        .map(|mobility| (curvature / slope).sqrt() / mobility);
+    (activity, mobility, complexity)
+}
+
+/// Hjorth parameters of each window of each channel's filtered window.
+/// Windows are cut from runs without gaps; a run's remainder shorter than a
+/// window is left out.
+#[utoipa::path(
+    get,
+    path = "/analysis/hjorth",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        HjorthQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ channels: [{ channel, sample_rate, window_samples, step_samples, windows: [{ start, end, activity, mobility, complexity }] }] }`", body = serde_json::Value),
+        (status = 400, description = "A window shorter than 3 samples, or too many windows"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_hjorth(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<HjorthQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let window_seconds = parse_width(params.window.as_deref().unwrap_or(DEFAULT_WINDOW))
+        .map_err(ApiError::internal)?;
+    let step_seconds = params
+        .step
+        .as_deref()
+        .map(parse_width)
+        .transpose()
+        .map_err(ApiError::internal)?
+        .unwrap_or(window_seconds);
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let samples = fetch_window_samples(state.reader(), channel, &filter).await?;
+        let rate = channel_rate(state.reader(), channel, &samples)
+            .await?
+            .ok_or_else(|| {
+                bad_request(format!(
+                    "channel {:?} has no samples in the window",
+                    channel
+                ))
+            })?;
+        let length = (window_seconds * rate).round() as usize;
+        let step = ((step_seconds * rate).round() as usize).max(1);
+        if length < 3 {
+            return Err(bad_request(format!(
+                "window must span at least 3 samples at {} Hz",
+                rate
+            )));
+        }
+
+        let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+        let mut windows = Vec::new();
+        for run in segments(&samples, rate) {
+            let mut start = run.start;
+            while start + length <= run.end {
+                if windows.len() == MAX_WINDOWS {
+                    return Err(bad_request(format!(
+                        "channel {:?} has more than {} windows; narrow the range or raise step",
+                        channel, MAX_WINDOWS
+                    )));
+                }
+                let (activity, mobility, complexity) = hjorth(&values[start..start + length], rate);
+                windows.push(Parameters {
+                    start: samples[start].ts,
+                    end: samples[start + length - 1].ts,
+                    activity,
+                    mobility,
+                    complexity,
+                });
+                start += step;
+            }
+        }
+
+        results.push(json!({
+            "channel": channel,
+            "sample_rate": rate,
+            "window_samples": length,
+            "step_samples": step,
+            "windows": windows,
+        }));
+    }
+
+    if results.len() == 1 {
+        return Ok(Json(results.remove(0)));
+    }
+    Ok(Json(json!({ "channels": results })))
+}
@@ -0,0 +1,760 @@
+//! ICA artifact removal for recorded sessions (`/analysis/ica`).
+//!
+//! `POST /analysis/ica` starts a background job that decomposes the channels
+//! of a session (optionally within `from` / `to`) into independent
+//! components, see [`crate::dsp::ica`]. Once the job is done it lists each
+//! component's topography (its weight in every channel);
+//! `GET /analysis/ica/{id}/components` returns component time courses for a
+//! window. Components marked with `PUT /analysis/ica/{id}/exclude` are
+//! subtracted by `POST /analysis/ica/{id}/apply`, which stores the cleaned
+//! channels as a new session. Jobs are kept in memory for [`MAX_AGE`] after
+//! they finish.
+//!
+//! Only instants where every channel has a sample (timestamps that match
+//! exactly, as for multichannel devices and imports) can be unmixed; the fit
+//! skips the others and `apply` copies them unchanged.
+
+use crate::auth::Principal;
+use crate::channels::validate_name;
+use crate::dsp::ica::{self, Decomposition, Options};
+use crate::error::ApiError;
+use crate::import::{self, ImportChannel, ImportQuery, ImportSummary};
+use crate::ingest::NewSample;
+use crate::pipeline::SampleCopy;
+use crate::sessions::Session;
+use crate::store::blocks;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{notify, AppState, ChannelQuery, EegSample, SampleFilter};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_MAX_ITER: usize = 200;
+const MAX_MAX_ITER: usize = 10_000;
+const DEFAULT_TOL: f64 = 1e-4;
+const DEFAULT_SEED: u64 = 42;
+const DEFAULT_SUFFIX: &str = "_ica";
+
+/// Instants the fit runs on; longer recordings are subsampled evenly to
+/// between this many and twice as many.
+const MAX_FIT_INSTANTS: usize = 100_000;
+
+/// Most instants returned by `/components`.
+const MAX_SOURCE_INSTANTS: usize = 100_000;
+
+/// Samples buffered before they are sent to the COPY.
+const ROWS_PER_SEND: usize = 10_000;
+
+/// How long finished jobs are kept.
+const MAX_AGE: chrono::Duration = chrono::Duration::hours(24);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = IcaJobStatus)]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[schema(as = IcaComponent)]
+pub struct Component {
+    pub index: usize,
+    /// Share of the explained variance, 0 to 1.
+    pub variance: f64,
+    /// Weight of the component in each channel, in job channel order.
+    pub topography: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[schema(as = IcaJob)]
+pub struct Job {
+    pub id: u64,
+    pub status: JobStatus,
+    pub session_id: i32,
+    pub channels: Vec<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Instants read so far.
+    pub instants: u64,
+    /// Instants where every channel has a sample.
+    pub complete_instants: u64,
+    /// Instants the fit ran on.
+    pub fitted_instants: u64,
+    pub iterations: Option<usize>,
+    pub converged: Option<bool>,
+    /// Sorted by explained variance, largest first; empty until done.
+    pub components: Vec<Component>,
+    /// Components removed by `apply`.
+    pub excluded: Vec<usize>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    model: Option<Arc<Decomposition>>,
+}
+
+pub type Jobs = Arc<Mutex<BTreeMap<u64, Job>>>;
+
+/// Typed part of `POST /analysis/ica`; the channels are read as for
+/// `/samples` and `session_id` is required.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IcaQuery {
+    /// Components to extract; as many as channels by default.
+    components: Option<usize>,
+    max_iter: Option<usize>,
+    tol: Option<f64>,
+    seed: Option<u64>,
+}
+
+impl Validate for IcaQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(components) = self.components {
+            errors.check(components >= 1, "components", "must be at least 1");
+        }
+        if let Some(max_iter) = self.max_iter {
+            errors.check(
+                (1..=MAX_MAX_ITER).contains(&max_iter),
+                "max_iter",
+                format!("must be 1 to {}", MAX_MAX_ITER),
+            );
+        }
+        if let Some(tol) = self.tol {
+            errors.check(tol.is_finite() && tol > 0.0, "tol", "must be positive");
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ExcludeInput {
+    components: Vec<usize>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ApplyQuery {
+    /// Appended to each channel name to name the cleaned channel.
+    suffix: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApplySummary {
+    #[serde(flatten)]
+    summary: ImportSummary,
+    /// Instants copied unchanged because a channel had no sample.
+    uncleaned_instants: u64,
+}
+
+/// Forgets jobs that finished more than [`MAX_AGE`] ago.
+fn expire(jobs: &Jobs) {
+    let cutoff = Utc::now() - MAX_AGE;
+    jobs.lock()
+        .unwrap()
+        .retain(|_, job| job.finished_at.is_none_or(|at| at > cutoff));
+}
+
+fn not_found(id: u64) -> ApiError {
+    ApiError::not_found(format!("unknown ICA job {}", id))
+}
+
+/// The queries for the samples of the job's channels in its session and
+/// window: rows, and the blocks to merge in.
+struct InstantQuery {
+    rows: QueryBuilder<'static, Postgres>,
+    blocks: QueryBuilder<'static, Postgres>,
+    filter: SampleFilter,
+    channels: Vec<String>,
+}
+
+impl InstantQuery {
+    fn new(job: &Job) -> Self {
+        let filter = SampleFilter {
+            from: job.from,
+            to: job.to,
+            session_id: Some(job.session_id),
+            subject_id: None,
+        };
+        let mut rows = QueryBuilder::new(
+            "SELECT id, ts, channel, value FROM eeg_samples WHERE channel = ANY(",
+        );
+        rows.push_bind(job.channels.clone()).push(")");
+        filter.push_to(&mut rows);
+        rows.push(" ORDER BY ts, id");
+        Self {
+            rows,
+            blocks: blocks::select(Some(&job.channels), &filter),
+            filter,
+            channels: job.channels.clone(),
+        }
+    }
+
+    /// The samples by instant.
+    fn instants<'a>(&'a mut self, pool: &'a PgPool) -> Instants<'a> {
+        let merged = blocks::Merged::new(
+            self.rows.build_query_as().fetch(pool),
+            self.blocks.build_query_as().fetch(pool),
+            self.filter,
+        );
+        Instants::new(merged, &self.channels)
+    }
+}
+
+/// Groups a time-ordered stream of samples into one row of channel values
+/// per instant.
+struct Instants<'a> {
+    samples: blocks::Merged<'a>,
+    index: HashMap<String, usize>,
+    row: Vec<Option<f64>>,
+    current: Option<DateTime<Utc>>,
+    done: bool,
+}
+
+impl<'a> Instants<'a> {
+    fn new(samples: blocks::Merged<'a>, channels: &[String]) -> Self {
+        Self {
+            samples,
+            index: channels
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (c.clone(), i))
+                .collect(),
+            row: vec![None; channels.len()],
+            current: None,
+            done: false,
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<(DateTime<Utc>, Vec<Option<f64>>)>, sqlx::Error> {
+        if self.done {
+            return Ok(None);
+        }
+        while let Some(EegSample {
+            ts, channel, value, ..
+        }) = self.samples.next().await?
+        {
+            let previous = match self.current {
+                Some(current) if current == ts => None,
+                _ => self.current.replace(ts).map(|current| {
+                    let row = std::mem::replace(&mut self.row, vec![None; self.index.len()]);
+                    (current, row)
+                }),
+            };
+            // A channel sampled twice at one instant keeps the later value.
+            if let Some(&i) = self.index.get(&channel) {
+                self.row[i] = Some(value);
+            }
+            if previous.is_some() {
+                return Ok(previous);
+            }
+        }
+        self.done = true;
+        Ok(self
+            .current
+            .take()
+            .map(|ts| (ts, std::mem::take(&mut self.row))))
+    }
+}
+
+/// All values of a row, if every channel has one.
+fn complete(row: &[Option<f64>]) -> Option<Vec<f64>> {
+    row.iter().copied().collect()
+}
+
+/// Reads the job's instants and fits the decomposition.
+async fn run(
+    pool: &PgPool,
+    jobs: &Jobs,
+    job: &Job,
+    options: Options,
+) -> Result<Decomposition, String> {
+    let progress = |instants, complete| {
+        if let Some(job) = jobs.lock().unwrap().get_mut(&job.id) {
+            job.instants = instants;
+            job.complete_instants = complete;
+        }
+    };
+    let mut query = InstantQuery::new(job);
+    let mut instants = query.instants(pool);
+    let mut rows = Vec::new();
+    let (mut read, mut complete_rows, mut stride) = (0u64, 0u64, 1u64);
+    while let Some((_, row)) = instants.next().await.map_err(|e| e.to_string())? {
+        read += 1;
+        let Some(row) = complete(&row) else {
+            continue;
+        };
+        if complete_rows % stride == 0 {
+            rows.push(row);
+            if rows.len() == 2 * MAX_FIT_INSTANTS {
+                // Keep every other row and halve the rate from now on.
+                rows = rows.into_iter().step_by(2).collect();
+                stride *= 2;
+            }
+        }
+        complete_rows += 1;
+        if read % 100_000 == 0 {
+            progress(read, complete_rows);
+        }
+    }
+    progress(read, complete_rows);
+    if let Some(job) = jobs.lock().unwrap().get_mut(&job.id) {
+        job.fitted_instants = rows.len() as u64;
+    }
+
+    let channels = job.channels.len();
+    tokio::task::spawn_blocking(move || ica::fit(&rows, channels, options))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+async fn fetch_session(pool: &PgPool, id: i32) -> Result<Session, ApiError> {
+    sqlx::query_as(
+        "SELECT id, subject_id, device, started_at, ended_at, notes FROM sessions WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("unknown session {}", id)))
+}
+
+/// Starts an ICA job; `202` with the job.
+#[utoipa::path(
+    post,
+    path = "/analysis/ica",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        IcaQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 202, description = "The job, which runs in the background", body = Job),
+        (status = 404, description = "Unknown session"),
+        (status = 422, description = "Parameters that do not parse or are out of range, or no `session_id`, named in `details.fields`")
+    )
+)]
+pub async fn create_ica(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<IcaQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let channels = raw.channels().map_err(ApiError::bad_request)?;
+    let components = params.components.unwrap_or(channels.len());
+    let mut errors = FieldErrors::default();
+    errors.check(filter.session_id.is_some(), "session_id", "is required");
+    errors.check(
+        channels.len() >= 2,
+        "channel",
+        "ICA needs at least 2 channels",
+    );
+    errors.check(
+        components <= channels.len(),
+        "components",
+        format!("must be 1 to {}", channels.len()),
+    );
+    let Some(session_id) = filter.session_id.filter(|_| errors.is_empty()) else {
+        return Err(errors.into());
+    };
+    let options = Options {
+        components,
+        max_iter: params.max_iter.unwrap_or(DEFAULT_MAX_ITER),
+        tol: params.tol.unwrap_or(DEFAULT_TOL),
+        seed: params.seed.unwrap_or(DEFAULT_SEED),
+    };
+    fetch_session(&state.pool, session_id).await?;
+
+    expire(&state.ica);
+    let job = Job {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        status: JobStatus::Running,
+        session_id,
+        channels,
+        from: filter.from,
+        to: filter.to,
+        instants: 0,
+        complete_instants: 0,
+        fitted_instants: 0,
+        iterations: None,
+        converged: None,
+        components: Vec::new(),
+        excluded: Vec::new(),
+        error: None,
+        created_at: Utc::now(),
+        finished_at: None,
+        model: None,
+    };
+    state.ica.lock().unwrap().insert(job.id, job.clone());
+
+    let jobs = state.ica.clone();
+    let pool = state.pool.clone();
+    let running = job.clone();
+    tokio::spawn(async move {
+        let result = run(&pool, &jobs, &running, options).await;
+
+        let mut jobs = jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&running.id) else {
+            // Deleted while running.
+            return;
+        };
+        job.finished_at = Some(Utc::now());
+        match result {
+            Ok(model) => {
+                job.status = JobStatus::Done;
+                job.iterations = Some(model.iterations);
+                job.converged = Some(model.converged);
+                job.components = model
+                    .variance()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, variance)| Component {
+                        index,
+                        variance,
+                        topography: model.mixing.iter().map(|row| row[index]).collect(),
+                    })
+                    .collect();
+                job.model = Some(Arc::new(model));
+            }
+            Err(e) => {
+                tracing::error!("ICA job {} failed: {}", running.id, e);
+                job.status = JobStatus::Failed;
+                job.error = Some(e);
+            }
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/analysis/ica",
+    tag = "analysis",
+    responses((status = 200, description = "Jobs, newest first", body = Vec<Job>))
+)]
+pub async fn list_ica(State(state): State<AppState>) -> Json<Vec<Job>> {
+    expire(&state.ica);
+    Json(state.ica.lock().unwrap().values().rev().cloned().collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/analysis/ica/{id}",
+    tag = "analysis",
+    params(("id" = u64, Path)),
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown job")
+    )
+)]
+pub async fn get_ica(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<u64>>,
+) -> Result<Json<Job>, ApiError> {
+    state
+        .ica
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| not_found(id))
+}
+
+/// Forgets a job; a running job finishes and is discarded.
+#[utoipa::path(
+    delete,
+    path = "/analysis/ica/{id}",
+    tag = "analysis",
+    params(("id" = u64, Path)),
+    responses(
+        (status = 204, description = "Forgotten"),
+        (status = 404, description = "Unknown job")
+    )
+)]
+pub async fn delete_ica(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<u64>>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .ica
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or_else(|| not_found(id))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The job and its model; `409` unless the job is done.
+fn done_job(state: &AppState, id: u64) -> Result<(Job, Arc<Decomposition>), ApiError> {
+    let job = state
+        .ica
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| not_found(id))?;
+    match (&job.status, job.model.clone()) {
+        (JobStatus::Done, Some(model)) => Ok((job, model)),
+        (JobStatus::Failed, _) => Err(ApiError::conflict(format!("ICA job {} failed", id))),
+        _ => Err(ApiError::conflict(format!(
+            "ICA job {} is still running",
+            id
+        ))),
+    }
+}
+
+/// Component time courses over `from` / `to` (within the job's window):
+/// `sources[i][t]` for each component `i` at each of `times`.
+#[utoipa::path(
+    get,
+    path = "/analysis/ica/{id}/components",
+    tag = "analysis",
+    params(("id" = u64, Path), SampleFilter),
+    responses(
+        (status = 200, description = "`{ id, session_id, times, sources }` with `sources[i][t]`", body = serde_json::Value),
+        (status = 400, description = "Window outside the job's"),
+        (status = 404, description = "Unknown job"),
+        (status = 409, description = "Job not done"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_components(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<u64>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (mut job, model) = done_job(&state, id)?;
+    job.from = match (job.from, filter.from) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    };
+    job.to = match (job.to, filter.to) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+
+    let mut query = InstantQuery::new(&job);
+    let mut instants = query.instants(&state.pool);
+    let mut times = Vec::new();
+    let mut sources: Vec<Vec<f64>> = vec![Vec::new(); model.components()];
+    while let Some((ts, row)) = instants.next().await? {
+        let Some(row) = complete(&row) else {
+            continue;
+        };
+        if times.len() == MAX_SOURCE_INSTANTS {
+            return Err(ApiError::bad_request(format!(
+                "the window has more than {} instants; narrow from/to",
+                MAX_SOURCE_INSTANTS
+            )));
+        }
+        times.push(ts);
+        for (source, value) in sources.iter_mut().zip(model.sources(&row)) {
+            source.push(value);
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "id": id,
+        "session_id": job.session_id,
+        "times": times,
+        "sources": sources,
+    })))
+}
+
+/// Sets the components `apply` removes.
+#[utoipa::path(
+    put,
+    path = "/analysis/ica/{id}/exclude",
+    tag = "analysis",
+    params(("id" = u64, Path)),
+    request_body = ExcludeInput,
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown job"),
+        (status = 409, description = "Job not done"),
+        (status = 422, description = "Unknown component, named in `details.fields`")
+    )
+)]
+pub async fn set_excluded(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<u64>>,
+    Parsed(Json(input)): Parsed<Json<ExcludeInput>>,
+) -> Result<Json<Job>, ApiError> {
+    let (job, model) = done_job(&state, id)?;
+    let mut excluded = input.components;
+    let mut errors = FieldErrors::default();
+    for (i, index) in excluded.iter().enumerate() {
+        errors.check(
+            *index < model.components(),
+            &format!("components[{}]", i),
+            format!("job {} has {} components", job.id, model.components()),
+        );
+    }
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+    excluded.sort_unstable();
+    excluded.dedup();
+    let mut jobs = state.ica.lock().unwrap();
+    let job = jobs.get_mut(&id).ok_or_else(|| not_found(id))?;
+    job.excluded = excluded;
+    Ok(Json(job.clone()))
+}
+
+/// Stores the job's window with the excluded components removed as a new
+/// session of the same subject and device, in channels named with `suffix`;
+/// `201` with the import summary.
+#[utoipa::path(
+    post,
+    path = "/analysis/ica/{id}/apply",
+    tag = "analysis",
+    params(("id" = u64, Path), ApplyQuery),
+    responses(
+        (status = 201, description = "The new session and its channels", body = ApplySummary),
+        (status = 400, description = "Invalid suffix"),
+        (status = 403, description = "New channels and no `admin` role"),
+        (status = 404, description = "Unknown job"),
+        (status = 409, description = "Job not done")
+    )
+)]
+pub async fn apply_ica(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Parsed(Path(id)): Parsed<Path<u64>>,
+    Parsed(Query(params)): Parsed<Query<ApplyQuery>>,
+) -> Result<(StatusCode, Json<ApplySummary>), ApiError> {
+    let bad_request = ApiError::bad_request;
+    let (job, model) = done_job(&state, id)?;
+    if job.excluded.is_empty() {
+        return Err(bad_request(format!(
+            "ICA job {} has no excluded components",
+            id
+        )));
+    }
+    let source = fetch_session(&state.pool, job.session_id).await?;
+    let suffix = params.suffix.as_deref().unwrap_or(DEFAULT_SUFFIX);
+
+    let registered: Vec<(String, Option<String>, String, Option<f64>)> =
+        sqlx::query_as("SELECT name, label, unit, sample_rate FROM channels WHERE name = ANY($1)")
+            .bind(&job.channels)
+            .fetch_all(&state.pool)
+            .await?;
+    let mut channels = Vec::with_capacity(job.channels.len());
+    for name in &job.channels {
+        let (_, label, unit, sample_rate) = registered
+            .iter()
+            .find(|r| &r.0 == name)
+            .ok_or_else(|| bad_request(format!("unknown channel {:?}", name)))?;
+        let cleaned = format!("{}{}", name, suffix);
+        validate_name(&cleaned).map_err(|e| bad_request(format!("channel {}", e)))?;
+        channels.push(ImportChannel {
+            name: cleaned,
+            label: format!("{} (ICA)", label.as_deref().unwrap_or(name)),
+            unit: unit.clone(),
+            sample_rate: *sample_rate,
+        });
+    }
+
+    let removed: Vec<String> = job.excluded.iter().map(|i| i.to_string()).collect();
+    let notes = format!(
+        "ICA-cleaned copy of session {} (components {} removed, job {})",
+        source.id,
+        removed.join(", "),
+        id
+    );
+    let mut tx = state.pool.begin().await.map_err(import::db_error)?;
+    let session = import::create_session(
+        &mut tx,
+        &ImportQuery {
+            subject_id: source.subject_id,
+            device: source.device.clone(),
+            notes: None,
+        },
+        source.started_at,
+        None,
+        None,
+        notes,
+    )
+    .await?;
+    let created_channels =
+        import::register_channels(&mut tx, principal.as_deref(), &channels).await?;
+
+    let mut copy = SampleCopy::start(&mut tx).await.map_err(import::db_error)?;
+    let written = async {
+        let mut query = InstantQuery::new(&job);
+        let mut instants = query.instants(&state.pool);
+        let mut batch: Vec<NewSample> = Vec::with_capacity(ROWS_PER_SEND);
+        let mut ended_at = source.started_at;
+        let mut uncleaned = 0u64;
+        while let Some((ts, row)) = instants.next().await? {
+            let values: Vec<Option<f64>> = match complete(&row) {
+                Some(values) => model
+                    .clean(&values, &job.excluded)
+                    .into_iter()
+                    .map(Some)
+                    .collect(),
+                None => {
+                    uncleaned += 1;
+                    row
+                }
+            };
+            for (channel, value) in channels.iter().zip(values) {
+                if let Some(value) = value {
+                    batch.push(NewSample {
+                        channel: channel.name.clone(),
+                        ts,
+                        value,
+                        session_id: Some(session.id),
+                    });
+                }
+            }
+            ended_at = ended_at.max(ts);
+            if batch.len() >= ROWS_PER_SEND {
+                copy.send(&batch).await.map_err(import::db_error)?;
+                batch.clear();
+            }
+        }
+        copy.send(&batch).await.map_err(import::db_error)?;
+        Ok::<_, ApiError>((ended_at, uncleaned))
+    }
+    .await;
+    let (ended_at, uncleaned_instants) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = copy.abort(e.message.clone()).await;
+            return Err(e);
+        }
+    };
+    let (samples, written) = copy.finish().await.map_err(import::db_error)?;
+    notify::written(&mut *tx, written.iter().map(String::as_str))
+        .await
+        .map_err(import::db_error)?;
+    let session = import::finish(tx, session, ended_at, &created_channels).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ApplySummary {
+            summary: ImportSummary {
+                session,
+                channels: channels.into_iter().map(|c| c.name).collect(),
+                created_channels,
+                samples,
+                events: 0,
+            },
+            uncleaned_instants,
+        }),
+    ))
+}
@@ -0,0 +1,82 @@
+//! Analysis of stored windows (`/analysis/*`).
+//!
+//! Windows are read as for `/samples/filter` and analysed per channel at its
+//! registered or estimated sample rate; only runs without gaps longer than
+//! two sample periods are cut into segments.
+
+pub mod artifacts;
+pub mod bandpower;
+pub mod coherence;
+pub mod epochs;
+pub mod erp;
+pub mod histogram;
+pub mod hjorth;
+pub mod ica;
+pub mod psd;
+pub mod spectrogram;
+pub mod stats;
+
+use crate::dsp::spectrum::{Stft, Window, MAX_NFFT};
+use crate::validation::{FieldErrors, Validate};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+const DEFAULT_NPERSEG: usize = 256;
+
+/// Segmenting parameters shared by the spectral endpoints.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SpectralQuery {
+    #[serde(default)]
+    window: Window,
+    /// Samples per segment.
+    nperseg: Option<usize>,
+    /// Samples shared by consecutive segments; half a segment by default.
+    noverlap: Option<usize>,
+    /// FFT length, a power of two at least `nperseg`; by default the next
+    /// power of two from `nperseg`.
+    nfft: Option<usize>,
+}
+
+impl SpectralQuery {
+    fn nperseg(&self) -> usize {
+        self.nperseg.unwrap_or(DEFAULT_NPERSEG)
+    }
+
+    /// The segmenting the parameters describe; it only fails for parameters
+    /// that break the rules of the [`Validate`] impl.
+    pub fn stft(&self) -> Result<Stft, String> {
+        let nperseg = self.nperseg();
+        let noverlap = self.noverlap.unwrap_or(nperseg / 2);
+        let nfft = self.nfft.unwrap_or(nperseg.next_power_of_two());
+        Stft::new(self.window, nperseg, noverlap, nfft)
+    }
+}
+
+impl Validate for SpectralQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_nperseg(errors, self.nperseg);
+        let nperseg = self.nperseg();
+        if let Some(noverlap) = self.noverlap {
+            errors.check(noverlap < nperseg, "noverlap", "must be less than nperseg");
+        }
+        if let Some(nfft) = self.nfft {
+            errors.check(
+                nfft.is_power_of_two() && (nperseg..=MAX_NFFT).contains(&nfft),
+                "nfft",
+                format!("must be a power of two from nperseg to {}", MAX_NFFT),
+            );
+        }
+    }
+}
+
+/// Checks a requested number of samples per segment.
+fn check_nperseg(errors: &mut FieldErrors, nperseg: Option<usize>) {
+    if let Some(nperseg) = nperseg {
+        errors.check(
+            (2..=MAX_NFFT).contains(&nperseg),
+            "nperseg",
+            format!("must be 2 to {}", MAX_NFFT),
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! `GET /analysis/psd`: power spectral density per channel.
+
+use super::SpectralQuery;
+use crate::dsp::{channel_rate, segments};
+use crate::error::ApiError;
+use crate::validation::{Parsed, Valid};
+use crate::{cache, config, fetch_window_samples, AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, RawQuery, State},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = PsdMethod)]
+pub enum Method {
+    /// Mean of the periodograms of overlapping tapered segments.
+    #[default]
+    Welch,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PsdQuery {
+    #[serde(default)]
+    method: Method,
+}
+
+/// Welch PSD of each channel's window: `frequencies` in Hz and `power` in
+/// unit²/Hz, plus the number of segments averaged.
+#[utoipa::path(
+    get,
+    path = "/analysis/psd",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        PsdQuery,
+        SpectralQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ channel, sample_rate, nfft, segments, frequencies, power }`, or `{ channels: [...] }` of those", body = serde_json::Value),
+        (status = 400, description = "No gap-free run holds a segment"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_psd(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<PsdQuery>>,
+    Valid(Query(spectral)): Valid<Query<SpectralQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let stft = spectral.stft().map_err(ApiError::internal)?;
+
+    let key = cache::Key {
+        route: "psd",
+        channels: &channels,
+        session_id: filter.session_id,
+        query: query.as_deref().unwrap_or_default(),
+    };
+    let body = cache::cached(key, config::get().cache.psd_ttl_secs, async {
+        let mut results = Vec::with_capacity(channels.len());
+        for channel in &channels {
+            let samples = fetch_window_samples(state.reader(), channel, &filter).await?;
+            let rate = channel_rate(state.reader(), channel, &samples).await?;
+            let too_short = || {
+                bad_request(format!(
+                    "channel {:?} has no run of {} samples without gaps in the window",
+                    channel,
+                    stft.nperseg()
+                ))
+            };
+            let rate = rate.ok_or_else(too_short)?;
+            let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+            let runs: Vec<&[f64]> = segments(&samples, rate)
+                .into_iter()
+                .map(|r| &values[r])
+                .collect();
+            let estimate = match params.method {
+                Method::Welch => stft.welch(&runs, rate),
+            };
+            let (power, count) = estimate.ok_or_else(too_short)?;
+            results.push(json!({
+                "channel": channel,
+                "sample_rate": rate,
+                "nfft": stft.nfft(),
+                "segments": count,
+                "frequencies": stft.frequencies(rate),
+                "power": power,
+            }));
+        }
+
+        if results.len() == 1 {
+            return Ok(results.remove(0));
+        }
+        Ok(json!({ "channels": results }))
+    })
+    .await?;
+    Ok(Json(body))
+}
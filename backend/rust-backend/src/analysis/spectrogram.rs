@@ -0,0 +1,212 @@
+//! `GET /analysis/spectrogram`: short-time power spectra for heatmaps.
+
+use super::SpectralQuery;
+use crate::dsp::{channel_rate, segments};
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Valid, Validate};
+use crate::{fetch_window_samples, AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_MAX_TIMES: usize = 1000;
+const MAX_TIMES: usize = 10_000;
+
+/// Upper bound on returned matrix cells per channel.
+const MAX_CELLS: usize = 2_000_000;
+
+/// Floor for `scale=db`, so empty bins stay finite.
+const DB_FLOOR: f64 = 1e-20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    #[default]
+    Linear,
+    /// `10 * log10(power)`.
+    Db,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SpectrogramQuery {
+    #[serde(default)]
+    scale: Scale,
+    /// Lowest frequency kept, in Hz.
+    fmin: Option<f64>,
+    /// Highest frequency kept, in Hz.
+    fmax: Option<f64>,
+    /// Average consecutive segments down to at most this many columns.
+    max_times: Option<usize>,
+    /// Average neighbouring bins down to at most this many rows.
+    max_frequencies: Option<usize>,
+}
+
+impl Validate for SpectrogramQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(max_times) = self.max_times {
+            errors.check(
+                (1..=MAX_TIMES).contains(&max_times),
+                "max_times",
+                format!("must be 1 to {}", MAX_TIMES),
+            );
+        }
+        errors.check(
+            self.max_frequencies != Some(0),
+            "max_frequencies",
+            "must be positive",
+        );
+        if let (Some(fmin), Some(fmax)) = (self.fmin, self.fmax) {
+            errors.check(fmin <= fmax, "fmax", "must not be below fmin");
+        }
+    }
+}
+
+/// Means of `groups` near-equal runs of `values`.
+fn decimate(values: &[f64], groups: usize) -> Vec<f64> {
+    if values.len() <= groups {
+        return values.to_vec();
+    }
+    (0..groups)
+        .map(|i| {
+            let run = &values[i * values.len() / groups..(i + 1) * values.len() / groups];
+            run.iter().sum::<f64>() / run.len() as f64
+        })
+        .collect()
+}
+
+/// STFT power of each channel's window: `power[t][f]` for each of `times`
+/// (segment centres) and `frequencies`.
+#[utoipa::path(
+    get,
+    path = "/analysis/spectrogram",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        SpectrogramQuery,
+        SpectralQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ channel, sample_rate, nfft, segments, scale, times, frequencies, power }` with `power[t][f]`, or `{ channels: [...] }` of those", body = serde_json::Value),
+        (status = 400, description = "No gap-free run holds a segment, no bins between `fmin` and `fmax`, or too many cells"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_spectrogram(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<SpectrogramQuery>>,
+    Valid(Query(spectral)): Valid<Query<SpectralQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let stft = spectral.stft().map_err(ApiError::internal)?;
+    let max_times = params.max_times.unwrap_or(DEFAULT_MAX_TIMES);
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let samples = fetch_window_samples(state.reader(), channel, &filter).await?;
+        let too_short = || {
+            bad_request(format!(
+                "channel {:?} has no run of {} samples without gaps in the window",
+                channel,
+                stft.nperseg()
+            ))
+        };
+        let rate = channel_rate(state.reader(), channel, &samples)
+            .await?
+            .ok_or_else(too_short)?;
+
+        let frequencies = stft.frequencies(rate);
+        let bins: Vec<usize> = (0..frequencies.len())
+            .filter(|&k| {
+                params.fmin.is_none_or(|f| frequencies[k] >= f)
+                    && params.fmax.is_none_or(|f| frequencies[k] <= f)
+            })
+            .collect();
+        if bins.is_empty() {
+            return Err(bad_request(format!(
+                "no frequency bins between fmin and fmax at {} Hz",
+                rate
+            )));
+        }
+        let rows = params.max_frequencies.unwrap_or(bins.len()).min(bins.len());
+
+        let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+        let mut starts = Vec::new();
+        for run in segments(&samples, rate) {
+            starts.extend(stft.starts(run.len()).map(|s| run.start + s));
+        }
+        if starts.is_empty() {
+            return Err(too_short());
+        }
+        let columns = starts.len().min(max_times);
+        if columns * rows > MAX_CELLS {
+            return Err(bad_request(format!(
+                "channel {:?} would return {} x {} cells (at most {}); lower max_times, max_frequencies or narrow fmin/fmax",
+                channel, columns, rows, MAX_CELLS
+            )));
+        }
+
+        let centre = stft.nperseg() / 2;
+        let times: Vec<i64> = starts
+            .iter()
+            .map(|&s| samples[s + centre].ts.timestamp_micros())
+            .collect();
+        let spectra: Vec<Vec<f64>> = starts
+            .iter()
+            .map(|&s| {
+                let power = stft.power(&values[s..s + stft.nperseg()], rate);
+                let kept: Vec<f64> = bins.iter().map(|&k| power[k]).collect();
+                decimate(&kept, rows)
+            })
+            .collect();
+        // Columns are averaged in power before any log scaling.
+        let group = |i: usize| i * starts.len() / columns..(i + 1) * starts.len() / columns;
+        let power: Vec<Vec<f64>> = (0..columns)
+            .map(|i| {
+                let group = &spectra[group(i)];
+                (0..rows)
+                    .map(|r| {
+                        let p = group.iter().map(|s| s[r]).sum::<f64>() / group.len() as f64;
+                        match params.scale {
+                            Scale::Linear => p,
+                            Scale::Db => 10.0 * p.max(DB_FLOOR).log10(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let times: Vec<Option<DateTime<Utc>>> = (0..columns)
+            .map(|i| {
+                let group = &times[group(i)];
+                let middle = (group[0] + group[group.len() - 1]) / 2;
+                DateTime::from_timestamp_micros(middle)
+            })
+            .collect();
+        let kept: Vec<f64> = bins.iter().map(|&k| frequencies[k]).collect();
+
+        results.push(json!({
+            "channel": channel,
+            "sample_rate": rate,
+            "nfft": stft.nfft(),
+            "segments": starts.len(),
+            "scale": params.scale,
+            "times": times,
+            "frequencies": decimate(&kept, rows),
+            "power": power,
+        }));
+    }
+
+    if results.len() == 1 {
+        return Ok(Json(results.remove(0)));
+    }
+    Ok(Json(json!({ "channels": results })))
+}
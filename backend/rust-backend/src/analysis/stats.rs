@@ -0,0 +1,143 @@
+//! `GET /analysis/stats`: amplitude statistics over fixed windows.
+//!
+//! Computed in the database, with packed [blocks](crate::store::blocks)
+//! summed up as they are read, so the range is not bound by
+//! `limits.max_window_rows`. Windows are aligned to the Unix epoch as the
+//! buckets of `/samples/aggregate` are; `std` is the population standard
+//! deviation and `ptp` the peak-to-peak amplitude, `max - min`.
+
+use crate::aggregate::{self, bucket_expr, parse_width, Summary, MAX_BUCKETS};
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Valid, Validate};
+use crate::{AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::QueryBuilder;
+use utoipa::IntoParams;
+
+const DEFAULT_WINDOW: &str = "1s";
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsQuery {
+    /// Window length, e.g. `1s` or `250ms`.
+    window: Option<String>,
+}
+
+impl Validate for StatsQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(Err(e)) = self.window.as_deref().map(parse_width) {
+            errors.add("window", e);
+        }
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct Stats {
+    /// Start of the window.
+    start: DateTime<Utc>,
+    count: i64,
+    mean: f64,
+    std: f64,
+    min: f64,
+    max: f64,
+    ptp: f64,
+}
+
+/// Mean, standard deviation, minimum, maximum and peak-to-peak amplitude of
+/// each window of each channel's filtered samples. Windows without samples
+/// are left out.
+#[utoipa::path(
+    get,
+    path = "/analysis/stats",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        StatsQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ channel, window_seconds, windows: [{ start, count, mean, std, min, max, ptp }] }`", body = serde_json::Value),
+        (status = 400, description = "More than 10000 windows"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_stats(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<StatsQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = raw.channels().map_err(bad_request)?;
+    let window_seconds = parse_width(params.window.as_deref().unwrap_or(DEFAULT_WINDOW))
+        .map_err(ApiError::internal)?;
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        let bucket = bucket_expr(
+            state.timescale,
+            &format!("{}::float8", window_seconds),
+            "ts",
+        );
+        let mut query = QueryBuilder::new(format!(
+            "SELECT {} AS start, COUNT(*) AS count, AVG(value) AS mean, \
+             STDDEV_POP(value) AS std, MIN(value) AS min, MAX(value) AS max, \
+             MAX(value) - MIN(value) AS ptp \
+             FROM eeg_samples WHERE channel = ",
+            bucket
+        ));
+        query.push_bind(channel);
+        filter.push_to(&mut query);
+        query
+            .push(" GROUP BY 1 ORDER BY 1 LIMIT ")
+            .push_bind(MAX_BUCKETS + 1);
+        let rows: Vec<Stats> = query.build_query_as().fetch_all(state.reader()).await?;
+        let mut summaries =
+            aggregate::packed_buckets(state.reader(), channel, &filter, window_seconds).await?;
+        for row in rows {
+            let summary = Summary {
+                count: row.count,
+                mean: row.mean,
+                m2: row.std * row.std * row.count as f64,
+                min: row.min,
+                max: row.max,
+            };
+            aggregate::merge_into(&mut summaries, row.start, summary);
+        }
+        let windows: Vec<Stats> = summaries
+            .into_iter()
+            .map(|(start, s)| Stats {
+                start,
+                count: s.count,
+                mean: s.mean,
+                std: s.std(),
+                min: s.min,
+                max: s.max,
+                ptp: s.max - s.min,
+            })
+            .collect();
+        if windows.len() as i64 > MAX_BUCKETS {
+            return Err(bad_request(format!(
+                "channel {:?} has more than {} windows; narrow from/to or widen window",
+                channel, MAX_BUCKETS
+            )));
+        }
+
+        results.push(json!({
+            "channel": channel,
+            "window_seconds": window_seconds,
+            "windows": windows,
+        }));
+    }
+
+    if results.len() == 1 {
+        return Ok(Json(results.remove(0)));
+    }
+    Ok(Json(json!({ "channels": results })))
+}
@@ -0,0 +1,755 @@
+//! Newly stored samples: polled on `/live`, or pushed over `/live/ws` and
+//! `/live/sse`.
+
+use super::{ChannelQuery, SampleFilter};
+use crate::encoding::{self, Encoded};
+use crate::error::ApiError;
+use crate::validation::{check_limit, FieldErrors, Parsed, Valid, Validate};
+use crate::{
+    calibration, channels, config, dsp, fetch_live_points, metadata, metrics, notify, quality,
+    shutdown, timezone, AppState, LivePoint,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use std::convert::Infallible;
+use utoipa::IntoParams;
+
+/// Typed part of `/live`; `channel` and `since_id` are read by [`ChannelQuery`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LivePollQuery {
+    pub limit: Option<i32>,
+}
+
+impl Validate for LivePollQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_limit(errors, self.limit.map(i64::from));
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LiveQuery {
+    channel: Option<String>,
+    since_id: Option<i32>,
+    limit: Option<i32>,
+    /// Also send the channel's signal quality every `streaming.quality_interval_ms`.
+    #[serde(default)]
+    quality: bool,
+    /// Add the RMS and line noise of the last second to each batch of points.
+    #[serde(default)]
+    stats: bool,
+}
+
+impl Validate for LiveQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(channel) = &self.channel {
+            if let Err(e) = channels::validate_name(channel) {
+                errors.add("channel", e);
+            }
+        }
+        check_limit(errors, self.limit.map(i64::from));
+    }
+}
+
+/// Messages a WebSocket client may send on `/live/ws`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsClientMessage {
+    Subscribe {
+        channel: String,
+        since_id: Option<i32>,
+        /// Switches the stream to a stored pipeline, or `none`.
+        pipeline: Option<String>,
+        /// Switches the stream to its own pipeline stages.
+        stages: Option<Vec<dsp::pipeline::Stage>>,
+        /// Switches the stream's reference channels, or `none`.
+        reference: Option<String>,
+        /// Streams the derivation of this montage named by `channel`.
+        montage: Option<String>,
+        /// Turns rolling statistics on or off.
+        stats: Option<bool>,
+    },
+}
+
+/// Messages pushed to WebSocket clients on `/live/ws`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsServerMessage {
+    Subscribed {
+        channel: String,
+        since_id: i32,
+        /// Stored pipeline processing the stream, if any.
+        pipeline: Option<String>,
+        /// Stages processing the stream; empty for raw output.
+        stages: Vec<dsp::pipeline::Stage>,
+        /// Channels the stream is re-referenced to; empty for stored values.
+        reference: Vec<String>,
+        /// Whether points carry rolling statistics.
+        stats: bool,
+    },
+    Points {
+        channel: String,
+        points: Vec<LivePoint>,
+        last_id: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stats: Option<dsp::rolling::Stats>,
+    },
+    Quality(quality::Quality),
+    Error {
+        message: String,
+    },
+}
+
+/// New points of a stream, re-referenced to `reference` and through its
+/// notch when it has one, and the id of the last stored sample read.
+async fn fetch_stream_points(
+    pool: &PgPool,
+    channel: &str,
+    since_id: i32,
+    filter: &SampleFilter,
+    reference: &[String],
+    notch: Option<&mut dsp::notch::Notch>,
+    limit: i32,
+) -> Result<(Vec<LivePoint>, Option<i32>), ApiError> {
+    let mut points = fetch_live_points(pool, channel, since_id, filter, limit).await?;
+    let last_id = points.last().map(|p| p.id);
+    dsp::reference::apply(pool, reference, filter, &mut points).await?;
+    if let Some(notch) = notch {
+        notch.apply(pool, channel, filter, &mut points).await?;
+    }
+    Ok((points, last_id))
+}
+
+/// Polls one or more channels (`channel=A3,A4` or repeated `channel`).
+///
+/// With several channels the response is grouped per channel, each with its
+/// own `last_id`; `since_id` takes one value for all or one per channel.
+#[allow(clippy::too_many_arguments)]
+#[utoipa::path(
+    get,
+    path = "/live",
+    tag = "live",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        ("since_id" = Option<Vec<i32>>, Query, description = "Return samples after this id; one value for all channels or one per channel"),
+        LivePollQuery,
+        SampleFilter,
+        dsp::notch::NotchQuery,
+        dsp::reference::ReferenceQuery,
+        calibration::CalibrationQuery,
+        metadata::UnitQuery,
+        timezone::TimeQuery
+    ),
+    responses(
+        (status = 200, description = "`{ points, last_id, channel, metadata }` with the channel's sample rate, unit and scale, or `{ channels: [...] }` grouped per channel; MessagePack or CBOR when `Accept` asks for it", content((serde_json::Value = "application/json"), (serde_json::Value = "application/msgpack"), (serde_json::Value = "application/cbor"))),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_live(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<LivePollQuery>>,
+    Valid(Query(notch)): Valid<Query<dsp::notch::NotchQuery>>,
+    Parsed(Query(reference)): Parsed<Query<dsp::reference::ReferenceQuery>>,
+    Parsed(Query(calibrated)): Parsed<Query<calibration::CalibrationQuery>>,
+    Parsed(Query(units)): Parsed<Query<metadata::UnitQuery>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    headers: HeaderMap,
+) -> Result<Encoded<serde_json::Value>, ApiError> {
+    let format = encoding::Format::from_headers(&headers);
+    let bad_request = ApiError::bad_request;
+    let derivations = reference.derivations(state.reader(), &raw).await?;
+    calibrated
+        .check(derivations.iter().any(|d| !d.reference.is_empty()))
+        .map_err(|e| FieldErrors::single("calibrated", e))?;
+    let since_ids = raw.ids("since_id", derivations.len())?;
+    let limit = params.limit.unwrap_or(200);
+
+    let mut batches = futures::future::try_join_all(derivations.iter().zip(&since_ids).map(
+        |(derivation, since_id)| {
+            state
+                .store
+                .since(&derivation.channel, since_id.unwrap_or(0), &filter, limit)
+        },
+    ))
+    .await?;
+    // The last stored id, even if re-referencing drops that point.
+    let last_ids: Vec<i32> = batches
+        .iter()
+        .zip(&since_ids)
+        .map(|(points, since_id)| points.last().map(|p| p.id).unwrap_or(since_id.unwrap_or(0)))
+        .collect();
+    for (derivation, points) in derivations.iter().zip(&mut batches) {
+        dsp::reference::apply(state.reader(), &derivation.reference, &filter, points).await?;
+        if let Some(mut notch) = dsp::notch::Notch::new(notch) {
+            notch
+                .apply(state.reader(), &derivation.channel, &filter, points)
+                .await?;
+        }
+        calibration::apply(
+            state.reader(),
+            calibrated,
+            &derivation.channel,
+            &filter,
+            points,
+        )
+        .await?;
+    }
+
+    let names: Vec<String> = derivations.iter().map(|d| d.channel.clone()).collect();
+    let mut metadata = metadata::of(state.reader(), &names, filter.session_id).await?;
+    if let Some(unit) = units.unit {
+        for ((derivation, points), metadata) in
+            derivations.iter().zip(&mut batches).zip(&mut metadata)
+        {
+            let factor = metadata
+                .convert(&derivation.channel, unit)
+                .map_err(bad_request)?;
+            for point in points.iter_mut() {
+                point.value *= factor;
+            }
+        }
+    }
+    let mut grouped: Vec<_> = derivations
+        .into_iter()
+        .zip(last_ids)
+        .zip(batches)
+        .zip(metadata)
+        .map(|(((derivation, last_id), points), metadata)| {
+            json!({
+                "points": points,
+                "last_id": last_id,
+                "channel": derivation.name,
+                "metadata": metadata,
+            })
+        })
+        .collect();
+
+    let mut body = if grouped.len() == 1 {
+        grouped.remove(0)
+    } else {
+        json!({ "channels": grouped })
+    };
+    times.apply(&mut body);
+    Ok(Encoded(format, body))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[utoipa::path(
+    get,
+    path = "/live/ws",
+    tag = "live",
+    params(
+        LiveQuery,
+        SampleFilter,
+        dsp::notch::NotchQuery,
+        metadata::UnitQuery,
+        timezone::TimeQuery,
+        dsp::pipeline::PipelineQuery,
+        dsp::reference::ReferenceQuery,
+        quality::QualityQuery
+    ),
+    responses(
+        (status = 101, description = "WebSocket of `points` messages; clients may send `subscribe`"),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn live_ws(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<LiveQuery>>,
+    Valid(Query(notch)): Valid<Query<dsp::notch::NotchQuery>>,
+    Parsed(Query(pipeline)): Parsed<Query<dsp::pipeline::PipelineQuery>>,
+    Parsed(Query(reference)): Parsed<Query<dsp::reference::ReferenceQuery>>,
+    Parsed(Query(quality)): Parsed<Query<quality::QualityQuery>>,
+    Parsed(Query(units)): Parsed<Query<metadata::UnitQuery>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let quality = match quality.options() {
+        Ok(options) => options,
+        Err(e) => return ApiError::bad_request(e).into_response(),
+    };
+    let rolling = params
+        .stats
+        .then(|| dsp::rolling::Rolling::new(quality.line));
+    let channel = params.channel.unwrap_or_else(|| "A3".to_string());
+    let derivation = match reference.stream(state.reader(), &channel).await {
+        Ok(derivation) => derivation,
+        Err(e) => return e.into_response(),
+    };
+    let since_id = params.since_id.unwrap_or(0);
+    let limit = params.limit.unwrap_or(200);
+    let notch = dsp::notch::Notch::new(notch);
+    let selection = match dsp::pipeline::Selection::new(pipeline.pipeline, None) {
+        Ok(selection) => selection,
+        Err(e) => return ApiError::bad_request(e).into_response(),
+    };
+    let live = match selection.resolve(state.reader(), &derivation.channel).await {
+        Ok(live) => live,
+        Err(e) => return e.into_response(),
+    };
+    let factor = match unit_factor(state.reader(), &derivation.channel, &filter, units.unit).await {
+        Ok(factor) => factor,
+        Err(e) => return e.into_response(),
+    };
+    let stream = LiveStream {
+        derivation,
+        since_id,
+        filter,
+        reference,
+        notch,
+        selection,
+        live,
+        quality: params.quality.then_some(quality),
+        line: quality.line,
+        rolling,
+        limit,
+        unit: units.unit,
+        factor,
+        times,
+    };
+    ws.on_upgrade(move |socket| live_ws_session(socket, state, stream))
+}
+
+/// What a `/live/ws` connection is subscribed to.
+struct LiveStream {
+    /// The channel, or the montage derivation, streamed.
+    derivation: dsp::reference::Derivation,
+    since_id: i32,
+    filter: SampleFilter,
+    /// How the derivation is chosen when the channel changes.
+    reference: dsp::reference::ReferenceQuery,
+    notch: Option<dsp::notch::Notch>,
+    /// How the pipeline is chosen when the channel changes.
+    selection: dsp::pipeline::Selection,
+    live: Option<dsp::pipeline::Live>,
+    /// Set when the client asked for quality reports.
+    quality: Option<quality::Options>,
+    /// Mains frequency of the line noise in rolling statistics.
+    line: f64,
+    /// Set when the client asked for rolling statistics.
+    rolling: Option<dsp::rolling::Rolling>,
+    limit: i32,
+    unit: Option<metadata::Unit>,
+    /// From stored values to `unit`, for the current channel.
+    factor: f64,
+    times: timezone::TimeQuery,
+}
+
+impl LiveStream {
+    fn subscribed(&self) -> WsServerMessage {
+        WsServerMessage::Subscribed {
+            channel: self.derivation.name.clone(),
+            since_id: self.since_id,
+            pipeline: self.live.as_ref().and_then(|l| l.name.clone()),
+            stages: self
+                .live
+                .as_ref()
+                .map(|l| l.stages.clone())
+                .unwrap_or_default(),
+            reference: self.derivation.reference.clone(),
+            stats: self.rolling.is_some(),
+        }
+    }
+
+    /// New points through the reference, notch and pipeline; `None` if
+    /// there are no new samples or none of them were kept.
+    async fn poll(&mut self, pool: &PgPool) -> Result<Option<WsServerMessage>, String> {
+        let (points, last) = fetch_stream_points(
+            pool,
+            &self.derivation.channel,
+            self.since_id,
+            &self.filter,
+            &self.derivation.reference,
+            self.notch.as_mut(),
+            self.limit,
+        )
+        .await
+        .map_err(|e| e.message)?;
+        let Some(last) = last else {
+            return Ok(None);
+        };
+        self.since_id = last;
+        let points = match self.live.as_mut() {
+            Some(live) => live
+                .apply(pool, &self.derivation.channel, &self.filter, points)
+                .await
+                .map_err(|e| e.message)?,
+            None => points,
+        };
+        let points = scaled(points, self.factor);
+        if points.is_empty() {
+            return Ok(None);
+        }
+        let stats = self.rolling.as_mut().and_then(|r| r.push(&points));
+        Ok(Some(WsServerMessage::Points {
+            channel: self.derivation.name.clone(),
+            points,
+            last_id: self.since_id,
+            stats,
+        }))
+    }
+
+    /// Quality of the stored channel the stream reads, if requested.
+    async fn report_quality(&self, pool: &PgPool) -> Option<WsServerMessage> {
+        let options = self.quality.as_ref()?;
+        Some(
+            match quality::measure(pool, &self.derivation.channel, &self.filter, options).await {
+                Ok(quality) => WsServerMessage::Quality(quality),
+                Err(ApiError { message, .. }) => WsServerMessage::Error { message },
+            },
+        )
+    }
+}
+
+/// Factor from the stored values of `channel` to `unit`.
+async fn unit_factor(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+    unit: Option<metadata::Unit>,
+) -> Result<f64, ApiError> {
+    let channels = [channel.to_string()];
+    let factors = metadata::factors(pool, &channels, filter.session_id, unit).await?;
+    Ok(factors.get(channel).copied().unwrap_or(1.0))
+}
+
+/// `points` with their values multiplied by `factor`.
+fn scaled(mut points: Vec<LivePoint>, factor: f64) -> Vec<LivePoint> {
+    if factor != 1.0 {
+        for point in &mut points {
+            point.value *= factor;
+        }
+    }
+    points
+}
+
+/// The derivation, pipeline and reference settings a `subscribe` message
+/// switches to, and the factor to the stream's unit on the new channel.
+async fn subscribe(
+    pool: &PgPool,
+    channel: &str,
+    selection: dsp::pipeline::Selection,
+    reference: dsp::reference::ReferenceQuery,
+    stream: &LiveStream,
+) -> Result<
+    (
+        dsp::reference::Derivation,
+        dsp::pipeline::Selection,
+        Option<dsp::pipeline::Live>,
+        dsp::reference::ReferenceQuery,
+        f64,
+    ),
+    String,
+> {
+    let derivation = reference
+        .stream(pool, channel)
+        .await
+        .map_err(|e| e.message)?;
+    let live = selection
+        .resolve(pool, &derivation.channel)
+        .await
+        .map_err(|e| e.message)?;
+    let factor = unit_factor(pool, &derivation.channel, &stream.filter, stream.unit)
+        .await
+        .map_err(|e| e.message)?;
+    Ok((derivation, selection, live, reference, factor))
+}
+
+/// Pushes new points for the subscribed channel until the client disconnects.
+///
+/// Clients switch channels by sending `{"type":"subscribe","channel":"A4"}`;
+/// `since_id` is optional and defaults to 0, `pipeline` or `stages`
+/// switch the stream's pipeline, `reference` or `montage` its reference, and
+/// `stats` its rolling statistics.
+async fn live_ws_session(mut socket: WebSocket, state: AppState, mut stream: LiveStream) {
+    let _client = metrics::LiveClient::connect("/live/ws");
+    let mut wakeup = notify::Wakeup::default();
+    let mut quality_ticker = tokio::time::interval(config::get().streaming.quality_interval());
+    let mut stopping = std::pin::pin!(shutdown::requested());
+
+    loop {
+        let reply = tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<WsClientMessage>(&text) {
+                        Ok(WsClientMessage::Subscribe {
+                            channel,
+                            since_id,
+                            pipeline,
+                            stages,
+                            reference,
+                            montage,
+                            stats,
+                        }) => {
+                            let selection = if pipeline.is_some() || stages.is_some() {
+                                dsp::pipeline::Selection::new(pipeline, stages)
+                            } else {
+                                Ok(stream.selection.clone())
+                            };
+                            let reference = if reference.is_some() || montage.is_some() {
+                                dsp::reference::ReferenceQuery {
+                                    reference,
+                                    bipolar: None,
+                                    montage,
+                                }
+                            } else {
+                                stream.reference.clone()
+                            };
+                            let live = match selection {
+                                Ok(selection) => {
+                                    subscribe(state.reader(), &channel, selection, reference, &stream)
+                                        .await
+                                }
+                                Err(message) => Err(message),
+                            };
+                            match live {
+                                Ok((derivation, selection, live, reference, factor)) => {
+                                    stream.derivation = derivation;
+                                    stream.factor = factor;
+                                    stream.since_id = since_id.unwrap_or(0);
+                                    stream.selection = selection;
+                                    stream.live = live;
+                                    stream.reference = reference;
+                                    if let Some(notch) = stream.notch.as_mut() {
+                                        notch.reset();
+                                    }
+                                    match stats {
+                                        Some(true) => {
+                                            stream.rolling =
+                                                Some(dsp::rolling::Rolling::new(stream.line))
+                                        }
+                                        Some(false) => stream.rolling = None,
+                                        None => {
+                                            if let Some(rolling) = stream.rolling.as_mut() {
+                                                rolling.reset();
+                                            }
+                                        }
+                                    }
+                                    wakeup.again();
+                                    Some(stream.subscribed())
+                                }
+                                Err(message) => Some(WsServerMessage::Error { message }),
+                            }
+                        }
+                        Err(e) => Some(WsServerMessage::Error { message: e.to_string() }),
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => None,
+            },
+            _ = wakeup.wait(&stream.derivation.channel) => {
+                let since_id = stream.since_id;
+                let reply = match stream.poll(state.reader()).await {
+                    Ok(points) => points,
+                    Err(message) => Some(WsServerMessage::Error { message }),
+                };
+                if stream.since_id != since_id {
+                    wakeup.again();
+                }
+                reply
+            }
+            _ = quality_ticker.tick(), if stream.quality.is_some() => {
+                stream.report_quality(state.reader()).await
+            }
+            _ = &mut stopping => {
+                let _ = socket.send(shutdown::going_away()).await;
+                break;
+            }
+        };
+
+        if let Some(reply) = reply {
+            let text = match stream.times.json(&reply).map(|reply| reply.to_string()) {
+                Ok(text) => text,
+                Err(e) => {
+                    tracing::error!("failed to encode websocket message: {}", e);
+                    continue;
+                }
+            };
+            if socket.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Streams new points as SSE `points` events whose id is the batch's `last_id`.
+///
+/// Reconnecting clients resume from the `Last-Event-ID` header, which takes
+/// precedence over the `since_id` query parameter.
+#[utoipa::path(
+    get,
+    path = "/live/sse",
+    tag = "live",
+    params(
+        ("Last-Event-ID" = Option<i32>, Header, description = "Resume after this id; wins over `since_id`"),
+        LiveQuery,
+        SampleFilter,
+        dsp::notch::NotchQuery,
+        metadata::UnitQuery,
+        timezone::TimeQuery,
+        dsp::reference::ReferenceQuery,
+        quality::QualityQuery
+    ),
+    responses(
+        (status = 200, description = "Server-sent `points` events, with `quality` events when asked for", content_type = "text/event-stream", body = String),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn live_sse(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<LiveQuery>>,
+    Valid(Query(notch)): Valid<Query<dsp::notch::NotchQuery>>,
+    Parsed(Query(reference)): Parsed<Query<dsp::reference::ReferenceQuery>>,
+    Parsed(Query(quality)): Parsed<Query<quality::QualityQuery>>,
+    Parsed(Query(units)): Parsed<Query<metadata::UnitQuery>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let quality = quality.options().map_err(ApiError::bad_request)?;
+    let rolling = params
+        .stats
+        .then(|| dsp::rolling::Rolling::new(quality.line));
+    // Options and when the next report is due.
+    let quality = params
+        .quality
+        .then(|| (quality, tokio::time::Instant::now()));
+    let notch = dsp::notch::Notch::new(notch);
+    let channel = params.channel.unwrap_or_else(|| "A3".to_string());
+    let derivation = reference.stream(state.reader(), &channel).await?;
+    let factor = unit_factor(state.reader(), &derivation.channel, &filter, units.unit).await?;
+    let since_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<i32>().ok())
+        .or(params.since_id)
+        .unwrap_or(0);
+    let limit = params.limit.unwrap_or(200);
+
+    let wakeup = notify::Wakeup::default();
+    let events = stream::unfold(
+        (state, derivation, since_id, notch, quality, rolling, wakeup),
+        move |(
+            state,
+            derivation,
+            mut since_id,
+            mut notch,
+            mut quality,
+            mut rolling,
+            mut wakeup,
+        )| async move {
+            loop {
+                match quality.as_ref().map(|(_, due)| *due) {
+                    Some(due) => tokio::select! {
+                        _ = wakeup.wait(&derivation.channel) => {}
+                        _ = tokio::time::sleep_until(due) => {}
+                    },
+                    None => wakeup.wait(&derivation.channel).await,
+                }
+                if shutdown::is_requested() {
+                    return None;
+                }
+                if let Some((options, due)) = quality.as_mut() {
+                    if tokio::time::Instant::now() >= *due {
+                        *due += config::get().streaming.quality_interval();
+                        let event = match quality::measure(
+                            state.reader(),
+                            &derivation.channel,
+                            &filter,
+                            options,
+                        )
+                        .await
+                        {
+                            Ok(report) => times
+                                .json(&report)
+                                .map_err(axum::Error::new)
+                                .and_then(|report| {
+                                    Event::default().event("quality").json_data(report)
+                                })
+                                .unwrap_or_else(|e| {
+                                    Event::default().event("error").data(e.to_string())
+                                }),
+                            Err(ApiError { message, .. }) => {
+                                Event::default().event("error").data(message)
+                            }
+                        };
+                        return Some((
+                            Ok(event),
+                            (state, derivation, since_id, notch, quality, rolling, wakeup),
+                        ));
+                    }
+                }
+                let event = match fetch_stream_points(
+                    state.reader(),
+                    &derivation.channel,
+                    since_id,
+                    &filter,
+                    &derivation.reference,
+                    notch.as_mut(),
+                    limit,
+                )
+                .await
+                {
+                    Ok((_, None)) => continue,
+                    Ok((points, Some(last_id))) if points.is_empty() => {
+                        since_id = last_id;
+                        wakeup.again();
+                        continue;
+                    }
+                    Ok((points, Some(last_id))) => {
+                        since_id = last_id;
+                        wakeup.again();
+                        let points = scaled(points, factor);
+                        let mut body = json!({
+                            "points": points,
+                            "last_id": since_id,
+                            "channel": derivation.name,
+                        });
+                        if let Some(stats) = rolling.as_mut().and_then(|r| r.push(&points)) {
+                            body["stats"] = json!(stats);
+                        }
+                        times.apply(&mut body);
+                        Event::default()
+                            .event("points")
+                            .id(since_id.to_string())
+                            .json_data(body)
+                            .unwrap_or_else(|e| Event::default().event("error").data(e.to_string()))
+                    }
+                    Err(ApiError { message, .. }) => Event::default().event("error").data(message),
+                };
+                return Some((
+                    Ok(event),
+                    (state, derivation, since_id, notch, quality, rolling, wakeup),
+                ));
+            }
+        },
+    );
+
+    let client = metrics::LiveClient::connect("/live/sse");
+    let events = events.map(move |event| {
+        let _ = &client;
+        event
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
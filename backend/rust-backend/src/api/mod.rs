@@ -0,0 +1,561 @@
+//! The route table ([`routes`], [`public`]) and the routes of the sample
+//! data itself: ingest and pages in [`samples`], polls and streams of new
+//! samples in [`live`], and the query parameters every sample route shares.
+//! Other routes live in the module of what they serve.
+
+pub mod live;
+pub mod samples;
+
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Validate};
+use crate::{
+    aggregate, alerts, analysis, audit, calibration, channels, config, devices, dsp, epoch_sets,
+    events, export, feedback, gaps, graphql, health, impedances, import, jobs, metadata, metrics,
+    montages, openapi, pipeline, quality, replay, retention, roles, sessions, subjects, tiers,
+    timezone, triggers, AppState,
+};
+use axum::{
+    extract::{DefaultBodyLimit, State},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Postgres, QueryBuilder};
+use utoipa::IntoParams;
+
+/// Row filters accepted by `/samples` and every `/live` variant.
+///
+/// `from` / `to` are RFC 3339 with any offset, epoch milliseconds, or local
+/// times in the query's `tz`; see [`timezone`].
+#[derive(Debug, Clone, Copy, Default, Deserialize, IntoParams)]
+#[serde(try_from = "RawSampleFilter")]
+#[into_params(parameter_in = Query)]
+pub struct SampleFilter {
+    /// Only samples with `ts >= from` (RFC 3339, epoch milliseconds, or a
+    /// local time in `tz`).
+    #[param(value_type = Option<String>)]
+    pub from: Option<DateTime<Utc>>,
+    /// Only samples with `ts < to`, given as `from` is.
+    #[param(value_type = Option<String>)]
+    pub to: Option<DateTime<Utc>>,
+    /// Only samples recorded in this session.
+    pub session_id: Option<i32>,
+    /// Only samples recorded in sessions of this subject.
+    pub subject_id: Option<i32>,
+}
+
+/// [`SampleFilter`] as sent, before local times are resolved in `tz`.
+#[derive(Deserialize)]
+struct RawSampleFilter {
+    from: Option<timezone::Instant>,
+    to: Option<timezone::Instant>,
+    session_id: Option<i32>,
+    subject_id: Option<i32>,
+    tz: Option<timezone::Zone>,
+}
+
+impl TryFrom<RawSampleFilter> for SampleFilter {
+    type Error = String;
+
+    fn try_from(raw: RawSampleFilter) -> Result<Self, String> {
+        let zone = raw.tz.unwrap_or_default();
+        let resolve =
+            |instant: Option<timezone::Instant>| instant.map(|i| i.resolve(zone)).transpose();
+        Ok(SampleFilter {
+            from: resolve(raw.from)?,
+            to: resolve(raw.to)?,
+            session_id: raw.session_id,
+            subject_id: raw.subject_id,
+        })
+    }
+}
+
+impl Validate for SampleFilter {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            errors.check(from < to, "to", "must be after from");
+        }
+    }
+}
+
+impl SampleFilter {
+    /// Appends a predicate for each filter that is set. Leaving absent
+    /// filters out of the SQL (rather than `$n IS NULL OR ...`) keeps the
+    /// predicates sargable, so windows are served from the `ts` indexes.
+    pub fn push_to(&self, query: &mut QueryBuilder<'_, Postgres>) {
+        if let Some(from) = self.from {
+            query.push(" AND ts >= ").push_bind(from);
+        }
+        if let Some(to) = self.to {
+            query.push(" AND ts < ").push_bind(to);
+        }
+        if let Some(session_id) = self.session_id {
+            query.push(" AND session_id = ").push_bind(session_id);
+        }
+        if let Some(subject_id) = self.subject_id {
+            query
+                .push(" AND session_id IN (SELECT id FROM sessions WHERE subject_id = ")
+                .push_bind(subject_id)
+                .push(")");
+        }
+    }
+}
+
+/// Raw query pairs, for parameters given as `a,b,c` or repeated.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct ChannelQuery(pub Vec<(String, String)>);
+
+/// Checks the channel names and that the id lists are integers; whether an
+/// id list matches the number of channels is up to [`ChannelQuery::ids`].
+impl Validate for ChannelQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        let channels = self.values("channel");
+        for name in &channels {
+            if let Err(e) = channels::validate_name(name) {
+                errors.add("channel", format!("{:?}: {}", name, e));
+            }
+        }
+        let max = config::get().limits.max_query_channels;
+        errors.check(
+            channels.len() <= max,
+            "channel",
+            format!("at most {} channels per request", max),
+        );
+        for key in ["before_id", "after_id", "since_id"] {
+            let ok = self.values(key).iter().all(|v| v.parse::<i32>().is_ok());
+            errors.check(ok, key, "must be integers");
+        }
+    }
+}
+
+impl ChannelQuery {
+    /// Values of `key`, splitting comma-separated entries.
+    pub fn values(&self, key: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == key)
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    /// Requested channels, defaulting to `A3`.
+    pub fn channels(&self) -> Result<Vec<String>, String> {
+        let mut channels: Vec<String> = Vec::new();
+        for name in self.values("channel") {
+            if !channels.iter().any(|c| c == name) {
+                channels.push(name.to_string());
+            }
+        }
+        if channels.is_empty() {
+            channels.push("A3".to_string());
+        }
+        if channels.len() > config::get().limits.max_query_channels {
+            return Err(format!(
+                "at most {} channels per request",
+                config::get().limits.max_query_channels
+            ));
+        }
+        Ok(channels)
+    }
+
+    /// Per-channel ids for `key`: absent, one value for every channel, or
+    /// one value per channel in the order the channels were given. The
+    /// values are integers once [`Validate`] has passed; their number is
+    /// only known here, after a montage has been resolved.
+    pub fn ids(&self, key: &str, channels: usize) -> Result<Vec<Option<i32>>, FieldErrors> {
+        let ids: Vec<i32> = self
+            .values(key)
+            .into_iter()
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        match ids.len() {
+            0 => Ok(vec![None; channels]),
+            1 => Ok(vec![Some(ids[0]); channels]),
+            n if n == channels => Ok(ids.into_iter().map(Some).collect()),
+            n => Err(FieldErrors::single(
+                key,
+                format!("has {} values for {} channels", n, channels),
+            )),
+        }
+    }
+}
+
+/// Routes served without authentication or a version prefix.
+pub fn public() -> Router<AppState> {
+    Router::new()
+        .route("/", get(root))
+        .route("/health", get(health::healthz))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/api-docs/openapi.json", get(openapi::openapi_json))
+        .route("/swagger-ui", get(openapi::swagger_ui))
+}
+
+/// The versioned routes, each under `/v1` and, when enabled, its deprecated
+/// unversioned alias.
+pub fn routes(schema: graphql::EegSchema) -> Router<AppState> {
+    Router::new()
+        .route("/dbtest", get(dbtest))
+        .route(
+            "/samples",
+            get(samples::get_samples).post(samples::create_sample),
+        )
+        .route("/samples/batch", post(samples::create_samples_batch))
+        .route("/samples/binary", post(samples::create_samples_binary))
+        .route("/samples/series", post(samples::create_samples_series))
+        .route("/samples/aggregate", get(aggregate::get_aggregate))
+        .route("/samples/overview", get(tiers::get_overview))
+        .route("/samples/buckets", get(aggregate::get_buckets))
+        .route("/samples/filter", get(dsp::get_filtered))
+        .route(
+            "/pipelines",
+            get(dsp::pipeline::list_pipelines).post(dsp::pipeline::create_pipeline),
+        )
+        .route(
+            "/pipelines/:name",
+            get(dsp::pipeline::get_pipeline)
+                .put(dsp::pipeline::update_pipeline)
+                .delete(dsp::pipeline::delete_pipeline),
+        )
+        .route(
+            "/epoch-sets",
+            get(epoch_sets::list_epoch_sets).post(epoch_sets::create_epoch_set),
+        )
+        .route(
+            "/epoch-sets/:id",
+            get(epoch_sets::get_epoch_set).delete(epoch_sets::delete_epoch_set),
+        )
+        .route(
+            "/epoch-sets/:id/epochs",
+            get(epoch_sets::get_epoch_set_epochs),
+        )
+        .route(
+            "/montages",
+            get(montages::list_montages).post(montages::create_montage),
+        )
+        .route(
+            "/montages/:name",
+            get(montages::get_montage)
+                .put(montages::update_montage)
+                .delete(montages::delete_montage),
+        )
+        .route("/quality", get(quality::get_quality))
+        .route("/analysis/psd", get(analysis::psd::get_psd))
+        .route(
+            "/analysis/artifacts",
+            post(analysis::artifacts::detect_artifacts),
+        )
+        .route(
+            "/analysis/bandpower",
+            get(analysis::bandpower::get_bandpower),
+        )
+        .route(
+            "/analysis/coherence",
+            get(analysis::coherence::get_coherence),
+        )
+        .route("/analysis/epochs", get(analysis::epochs::get_epochs))
+        .route("/analysis/erp", get(analysis::erp::get_erp))
+        .route(
+            "/analysis/histogram",
+            get(analysis::histogram::get_histogram),
+        )
+        .route("/analysis/hjorth", get(analysis::hjorth::get_hjorth))
+        .route(
+            "/analysis/ica",
+            get(analysis::ica::list_ica).post(analysis::ica::create_ica),
+        )
+        .route(
+            "/analysis/ica/:id",
+            get(analysis::ica::get_ica).delete(analysis::ica::delete_ica),
+        )
+        .route(
+            "/analysis/ica/:id/components",
+            get(analysis::ica::get_components),
+        )
+        .route(
+            "/analysis/ica/:id/exclude",
+            put(analysis::ica::set_excluded),
+        )
+        .route("/analysis/ica/:id/apply", post(analysis::ica::apply_ica))
+        .route(
+            "/analysis/spectrogram",
+            get(analysis::spectrogram::get_spectrogram),
+        )
+        .route("/analysis/stats", get(analysis::stats::get_stats))
+        .route("/samples/export.csv", get(export::csv::export_csv))
+        .route(
+            "/samples/export.parquet",
+            post(export::parquet::create_export),
+        )
+        .route("/live", get(live::get_live))
+        .route("/live/ws", get(live::live_ws))
+        .route("/live/sse", get(live::live_sse))
+        .route("/live/feedback", get(feedback::feedback_ws))
+        .route(
+            "/channels",
+            get(channels::list_channels).post(channels::create_channel),
+        )
+        .route(
+            "/channels/:name",
+            get(channels::get_channel)
+                .put(channels::update_channel)
+                .delete(channels::delete_channel),
+        )
+        .route(
+            "/channels/:name/calibrations",
+            get(calibration::list_calibrations).post(calibration::create_calibration),
+        )
+        .route(
+            "/channels/:name/calibrations/:id",
+            delete(calibration::delete_calibration),
+        )
+        .route(
+            "/devices",
+            get(devices::registry::list_devices).post(devices::registry::create_device),
+        )
+        .route(
+            "/devices/:serial",
+            get(devices::registry::get_device)
+                .put(devices::registry::update_device)
+                .delete(devices::registry::delete_device),
+        )
+        .route("/devices/:serial/clock", get(devices::clock::get_clock))
+        .route(
+            "/events",
+            get(events::list_events).post(events::create_event),
+        )
+        .route(
+            "/events/:id",
+            get(events::get_event)
+                .patch(events::update_event)
+                .delete(events::delete_event),
+        )
+        .route("/triggers", get(triggers::list_triggers))
+        .route("/alerts", get(alerts::list_alerts))
+        .route(
+            "/alerts/rules",
+            get(alerts::list_rules).post(alerts::create_rule),
+        )
+        .route(
+            "/alerts/rules/:name",
+            get(alerts::get_rule)
+                .put(alerts::update_rule)
+                .delete(alerts::delete_rule),
+        )
+        .route(
+            "/subjects",
+            get(subjects::list_subjects).post(subjects::create_subject),
+        )
+        .route(
+            "/subjects/:id",
+            get(subjects::get_subject)
+                .patch(subjects::update_subject)
+                .delete(subjects::delete_subject),
+        )
+        .route(
+            "/sessions",
+            get(sessions::list_sessions).post(sessions::create_session),
+        )
+        .route(
+            "/sessions/:id",
+            get(sessions::get_session).patch(sessions::update_session),
+        )
+        .route(
+            "/sessions/:id/impedances",
+            get(impedances::list_impedances).post(impedances::create_impedances),
+        )
+        .route(
+            "/sessions/:id/channels",
+            get(metadata::list_session_channels),
+        )
+        .route(
+            "/sessions/:id/channels/:channel",
+            put(metadata::put_session_channel).delete(metadata::delete_session_channel),
+        )
+        .route("/sessions/:id/gaps", get(gaps::list_gaps))
+        .route("/sessions/:id/export", get(export::export_session))
+        .route("/sessions/:id/replay", get(replay::replay_ws))
+        .route(
+            "/import/edf",
+            post(import::edf::import_edf).layer(DefaultBodyLimit::max(import::max_upload_bytes())),
+        )
+        .route(
+            "/import/csv",
+            post(import::csv::import_csv).layer(DefaultBodyLimit::max(import::max_upload_bytes())),
+        )
+        .route("/imports", get(import::csv::list_imports))
+        .route("/imports/:id", get(import::csv::get_import))
+        .route(
+            "/exports",
+            get(export::queue::list_exports).post(export::queue::create_export),
+        )
+        .route(
+            "/exports/:id",
+            get(export::queue::get_export).delete(export::queue::delete_export),
+        )
+        .route("/exports/:id/file", get(export::queue::download_export))
+        .route("/ingest/metrics", get(pipeline::get_metrics))
+        .route("/admin/jobs", get(jobs::list_jobs))
+        .route("/admin/jobs/:name/run", post(jobs::run_job))
+        .route("/admin/retention", get(retention::list_policies))
+        .route("/admin/retention/:target", put(retention::update_policy))
+        .route(
+            "/admin/api-keys",
+            get(devices::keys::list_keys).post(devices::keys::create_key),
+        )
+        .route("/admin/api-keys/:id", delete(devices::keys::revoke_key))
+        .route("/admin/audit", get(audit::list_entries))
+        .route("/admin/roles", get(roles::list_assignments))
+        .route("/admin/roles/:principal", put(roles::put_assignment))
+        .merge(graphql::routes(schema))
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "health",
+    security(()),
+    responses((status = 200, description = "Name of the backend", body = String))
+)]
+pub async fn root() -> &'static str {
+    "Rust EEG Backend"
+}
+
+#[utoipa::path(
+    get,
+    path = "/dbtest",
+    tag = "health",
+    responses(
+        (status = 200, description = "`{ ok, value }` from `SELECT 1`", body = serde_json::Value),
+        (status = 500, description = "Database unreachable")
+    )
+)]
+pub async fn dbtest(State(state): State<AppState>) -> Result<Json<serde_json::Value>, ApiError> {
+    let row: (i32,) = sqlx::query_as("SELECT 1").fetch_one(&state.pool).await?;
+    Ok(Json(json!({"ok": true, "value": row.0})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation;
+
+    fn query(pairs: &[(&str, &str)]) -> ChannelQuery {
+        ChannelQuery(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn windows_end_after_they_start() {
+        let at = |secs| DateTime::from_timestamp(secs, 0);
+        let filter = |from, to| SampleFilter {
+            from,
+            to,
+            ..SampleFilter::default()
+        };
+        assert!(validation::check(&filter(at(10), at(20))).is_ok());
+        assert!(validation::check(&filter(at(10), None)).is_ok());
+        for to in [at(10), at(5)] {
+            let errors = validation::check(&filter(at(10), to)).unwrap_err();
+            assert_eq!(errors.to_string(), "to: must be after from");
+        }
+    }
+
+    fn filter(query: &str) -> Result<SampleFilter, String> {
+        serde_urlencoded::from_str(query).map_err(|e| e.to_string())
+    }
+
+    fn utc(ts: &str) -> Option<DateTime<Utc>> {
+        Some(ts.parse().unwrap())
+    }
+
+    #[test]
+    fn local_times_resolve_in_the_zone_of_the_query() {
+        let berlin = filter("from=2026-07-01T12:00:00&to=2026-07-02&tz=Europe/Berlin").unwrap();
+        assert_eq!(berlin.from, utc("2026-07-01T10:00:00Z"));
+        assert_eq!(berlin.to, utc("2026-07-01T22:00:00Z"));
+        let winter = filter("from=2026-01-01+10:00:00&tz=Europe/Berlin").unwrap();
+        assert_eq!(winter.from, utc("2026-01-01T09:00:00Z"));
+
+        let fixed = filter("from=2026-07-01T12:00:00.5&tz=-03:30").unwrap();
+        assert_eq!(fixed.from, utc("2026-07-01T15:30:00.5Z"));
+        let plain = filter("from=2026-07-01T12:00&session_id=4").unwrap();
+        assert_eq!(plain.from, utc("2026-07-01T12:00:00Z"));
+        assert_eq!(plain.session_id, Some(4));
+    }
+
+    #[test]
+    fn offsets_and_epoch_times_ignore_the_zone() {
+        let query = "from=2026-07-01T12:00:00%2B02:00&to=1782907200000&tz=America/New_York";
+        let offset = filter(query).unwrap();
+        assert_eq!(offset.from, utc("2026-07-01T10:00:00Z"));
+        assert_eq!(offset.to, utc("2026-07-01T12:00:00Z"));
+        // An unencoded `+` of an offset reaches us as a space.
+        let spaced = filter("from=2026-07-01T12:00:00+02:00&tz=UTC").unwrap();
+        assert_eq!(spaced.from, utc("2026-07-01T10:00:00Z"));
+    }
+
+    #[test]
+    fn local_times_the_zone_skips_or_repeats_are_rejected() {
+        let skipped = filter("from=2026-03-29T02:30:00&tz=Europe/Berlin").unwrap_err();
+        assert!(
+            skipped.contains("2026-03-29 02:30:00 does not exist in Europe/Berlin"),
+            "{}",
+            skipped
+        );
+        let repeated = filter("to=2026-10-25T02:30:00&tz=Europe/Berlin").unwrap_err();
+        assert!(
+            repeated.contains("2026-10-25 02:30:00 is ambiguous in Europe/Berlin"),
+            "{}",
+            repeated
+        );
+        let unknown = filter("from=2026-07-01&tz=Mars/Olympus").unwrap_err();
+        assert!(
+            unknown.contains("unknown time zone \"Mars/Olympus\""),
+            "{}",
+            unknown
+        );
+    }
+
+    #[test]
+    fn channel_queries_check_names_and_ids() {
+        let ok = query(&[
+            ("channel", "Fz, Cz"),
+            ("channel", "Oz"),
+            ("after_id", "3,4,5"),
+        ]);
+        assert!(validation::check(&ok).is_ok());
+        assert_eq!(ok.channels().unwrap(), ["Fz", "Cz", "Oz"]);
+        assert_eq!(ok.ids("after_id", 3).unwrap(), [Some(3), Some(4), Some(5)]);
+        assert_eq!(ok.ids("before_id", 3).unwrap(), [None, None, None]);
+        assert_eq!(
+            ok.ids("after_id", 2).unwrap_err().to_string(),
+            "after_id: has 3 values for 2 channels"
+        );
+        assert_eq!(query(&[]).channels().unwrap(), ["A3"]);
+
+        let bad = query(&[("channel", "Fz,F z"), ("since_id", "7"), ("before_id", "x")]);
+        assert_eq!(
+            validation::check(&bad).unwrap_err().to_string(),
+            "channel: \"F z\": name may only contain letters, digits, '_', '-' and '.'; \
+             before_id: must be integers"
+        );
+        let many = vec!["A"; 65].join(",");
+        assert_eq!(
+            validation::check(&query(&[("channel", &many)]))
+                .unwrap_err()
+                .to_string(),
+            "channel: at most 64 channels per request"
+        );
+    }
+}
@@ -0,0 +1,757 @@
+//! Ingest (`POST /samples`, `/samples/batch`, `/samples/binary`,
+//! `/samples/series`) and pages and windows of stored samples
+//! (`GET /samples`).
+//!
+//! Ingest requests may number their batch with `seq`, per device: a batch
+//! whose number the device has used before is answered with
+//! `already_seen` and not stored again, so devices can retry safely.
+
+use super::{ChannelQuery, SampleFilter};
+use crate::encoding::{self, Encoded};
+use crate::error::ApiError;
+use crate::ingest::{self, NewSample, Sequenced, Series};
+use crate::validation::{check_limit, media_type, FieldErrors, Parsed, Valid, Validate};
+use crate::{
+    analysis, calibration, config, devices, downsample, dsp, events, fetch_window_samples,
+    metadata, timezone, AppState, EegSample,
+};
+use axum::{
+    body::Bytes,
+    extract::{Extension, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use utoipa::IntoParams;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BinaryIngestQuery {
+    /// Comma-separated channel names, one per frame column.
+    channels: String,
+    /// Recording session every decoded sample belongs to.
+    session_id: Option<i32>,
+    /// Registered device the frames come from; checked against its profile.
+    device: Option<String>,
+    /// Sequence number of the batch, so a retry is not stored twice.
+    seq: Option<i64>,
+    /// The device's clock when it sent the batch, RFC 3339 or epoch
+    /// milliseconds; recorded with the time of receipt to estimate the
+    /// device's clock drift, see [`devices::clock`]. Needs `device` or a
+    /// device API key.
+    #[param(value_type = Option<String>)]
+    device_time: Option<timezone::Instant>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeviceQuery {
+    /// Registered device the samples come from; checked against its profile.
+    device: Option<String>,
+    /// Sequence number of the batch, so a retry is not stored twice; needs
+    /// `device` or a device API key.
+    seq: Option<i64>,
+    /// The device's clock when it sent the batch, RFC 3339 or epoch
+    /// milliseconds; recorded with the time of receipt to estimate the
+    /// device's clock drift, see [`devices::clock`]. Needs `device` or a
+    /// device API key.
+    #[param(value_type = Option<String>)]
+    device_time: Option<timezone::Instant>,
+}
+
+impl Validate for DeviceQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_seq(errors, self.seq);
+    }
+}
+
+impl Validate for BinaryIngestQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_seq(errors, self.seq);
+    }
+}
+
+fn check_seq(errors: &mut FieldErrors, seq: Option<i64>) {
+    errors.check(
+        seq.is_none_or(|seq| seq >= 0),
+        "seq",
+        "must not be negative",
+    );
+}
+
+/// Stores `samples`, at most once per sequence number of the device when
+/// the request has one, and records `device_time` with the time of receipt.
+async fn store(
+    state: &AppState,
+    device: Option<&str>,
+    key: Option<&devices::keys::ApiKey>,
+    seq: Option<i64>,
+    device_time: Option<timezone::Instant>,
+    samples: Vec<NewSample>,
+) -> Result<Sequenced, ApiError> {
+    let received_at = Utc::now();
+    let serial = device.or(key.map(|k| k.device_serial.as_str()));
+    let device_time = match (device_time, serial) {
+        (None, _) => None,
+        (Some(_), None) => {
+            return Err(ApiError::bad_request(
+                "device_time needs device or a device API key",
+            ))
+        }
+        (Some(time), Some(serial)) => Some((
+            serial,
+            time.resolve(timezone::Zone::default())
+                .map_err(ApiError::bad_request)?,
+        )),
+    };
+    let stored = match (seq, serial) {
+        (None, _) => Sequenced::Stored(state.store.insert(samples).await?),
+        (Some(_), None) => {
+            return Err(ApiError::bad_request(
+                "seq needs device or a device API key",
+            ))
+        }
+        (Some(seq), Some(serial)) => state.store.insert_once(serial, seq, samples).await?,
+    };
+    if let (Sequenced::Stored(_), Some((serial, device_time))) = (&stored, device_time) {
+        devices::clock::record(&state.pool, serial, device_time, received_at).await?;
+    }
+    Ok(stored)
+}
+
+/// `201` with `{ inserted, ids }`, or `200` with `already_seen` and the
+/// device's `last_seq` for a batch stored before.
+fn stored_batch(stored: Sequenced) -> (StatusCode, Json<serde_json::Value>) {
+    match stored {
+        Sequenced::Stored(ids) => (
+            StatusCode::CREATED,
+            Json(json!({"inserted": ids.len(), "ids": ids})),
+        ),
+        Sequenced::AlreadySeen { last_seq } => (
+            StatusCode::OK,
+            Json(json!({"inserted": 0, "ids": [], "already_seen": true, "last_seq": last_seq})),
+        ),
+    }
+}
+
+/// Checks the channels (and sample rate, when known) of an ingest request
+/// against the profile of the named device. Requests with an API key are
+/// checked against its scope and name its device by default.
+pub fn check_device(
+    device: Option<&str>,
+    key: Option<&devices::keys::ApiKey>,
+    samples: &[NewSample],
+    sample_rate: Option<f64>,
+) -> Result<(), ApiError> {
+    if let Some(key) = key {
+        key.check(device, samples)
+            .map_err(|e| ApiError::new(StatusCode::FORBIDDEN, e))?;
+    }
+    let Some(serial) = device.or(key.map(|k| k.device_serial.as_str())) else {
+        return Ok(());
+    };
+    let mut channels: Vec<String> = Vec::new();
+    for sample in samples {
+        if !channels.contains(&sample.channel) {
+            channels.push(sample.channel.clone());
+        }
+    }
+    let stream = devices::StreamProfile {
+        channels,
+        sample_rate,
+        gain: None,
+    };
+    devices::registry::check_stream(serial, &stream).map_err(ApiError::bad_request)
+}
+
+/// Typed part of `/samples`; `channel`, `before_id` and `after_id` may be
+/// lists and are read by [`ChannelQuery`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SamplesQuery {
+    limit: Option<i32>,
+    /// Downsample the window to at most this many points per channel.
+    points: Option<usize>,
+    /// Method used with `points`; LTTB by default.
+    #[serde(default)]
+    downsample: downsample::Method,
+    /// Resample the window to this rate in Hz.
+    resample: Option<f64>,
+    /// Also return the events overlapping the window of the returned samples.
+    #[serde(default)]
+    include_events: bool,
+}
+
+impl Validate for SamplesQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_limit(errors, self.limit.map(i64::from));
+        if let Some(points) = self.points {
+            let max = config::get().limits.max_points;
+            errors.check(
+                (2..=max).contains(&points),
+                "points",
+                format!("must be 2 to {}", max),
+            );
+        }
+        if let Err(e) = dsp::resample::check(self.resample) {
+            errors.add("resample", e);
+        }
+    }
+}
+
+/// The channels and cursors of `/samples`. `points` and `resample` return
+/// whole windows, which have no page to continue from, so they cannot be
+/// combined with `before_id` or `after_id`.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct PagedChannels(ChannelQuery);
+
+impl Validate for PagedChannels {
+    fn validate(&self, errors: &mut FieldErrors) {
+        self.0.validate(errors);
+        let window = ["points", "resample"]
+            .iter()
+            .any(|key| !self.0.values(key).is_empty());
+        for key in ["before_id", "after_id"] {
+            errors.check(
+                !window || self.0.values(key).is_empty(),
+                key,
+                "cannot be combined with points or resample",
+            );
+        }
+    }
+}
+
+/// Pages through a channel by id: newest first, or oldest first when only
+/// `after_id` is given. `next_cursor` is the value to pass back in the same
+/// parameter for the next page, or null once the history is exhausted.
+///
+/// Several channels (`channel=A3,A4` or repeated `channel`) are paged
+/// independently and returned grouped; `before_id` / `after_id` then take
+/// one value for all channels or one per channel.
+///
+/// With `points`, each channel's samples in the filtered window are instead
+/// downsampled to at most that many, returned oldest first and without a
+/// cursor. With `notch`, values are filtered before downsampling, and with
+/// `artifacts=exclude` artifacted samples are dropped before downsampling.
+/// `resample` likewise returns the whole window, resampled (before any
+/// downsampling) to that rate; see [`dsp::resample`].
+///
+/// `reference` and `bipolar` return the channels re-referenced; see
+/// [`dsp::reference`]. `clock_corrected` adds each sample's time corrected
+/// for the drift of the session's device, and the estimate as `clock`; see
+/// [`devices::clock`].
+#[allow(clippy::too_many_arguments)]
+#[utoipa::path(
+    get,
+    path = "/samples",
+    tag = "samples",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        ("before_id" = Option<Vec<i32>>, Query, description = "Page newest first below this id; one value for all channels or one per channel"),
+        ("after_id" = Option<Vec<i32>>, Query, description = "Page oldest first above this id; one value for all channels or one per channel"),
+        SamplesQuery,
+        SampleFilter,
+        dsp::notch::NotchQuery,
+        analysis::artifacts::ArtifactQuery,
+        dsp::reference::ReferenceQuery,
+        calibration::CalibrationQuery,
+        metadata::UnitQuery,
+        devices::clock::ClockQuery,
+        timezone::TimeQuery
+    ),
+    responses(
+        (status = 200, description = "`{ points, next_cursor, metadata }` with the channel's sample rate, unit and scale, or `{ channels: [...] }` grouped per channel; MessagePack or CBOR when `Accept` asks for it", content((serde_json::Value = "application/json"), (serde_json::Value = "application/msgpack"), (serde_json::Value = "application/cbor"))),
+        (status = 400, description = "Invalid reference or montage, or a channel that cannot be resampled"),
+        (status = 404, description = "Unknown montage"),
+        (status = 422, description = "Parameters that do not parse, are out of range or conflict, named in `details.fields`")
+    )
+)]
+pub async fn get_samples(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<SamplesQuery>>,
+    Valid(Query(notch)): Valid<Query<dsp::notch::NotchQuery>>,
+    Parsed(Query(artifacts)): Parsed<Query<analysis::artifacts::ArtifactQuery>>,
+    Parsed(Query(reference)): Parsed<Query<dsp::reference::ReferenceQuery>>,
+    Parsed(Query(calibrated)): Parsed<Query<calibration::CalibrationQuery>>,
+    Parsed(Query(units)): Parsed<Query<metadata::UnitQuery>>,
+    Parsed(Query(clock)): Parsed<Query<devices::clock::ClockQuery>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(PagedChannels(raw))): Valid<Query<PagedChannels>>,
+    headers: HeaderMap,
+) -> Result<Encoded<serde_json::Value>, ApiError> {
+    let format = encoding::Format::from_headers(&headers);
+    if clock.clock_corrected && filter.session_id.is_none() {
+        return Err(FieldErrors::single("clock_corrected", "needs session_id").into());
+    }
+    let derivations = reference.derivations(state.reader(), &raw).await?;
+    calibrated
+        .check(derivations.iter().any(|d| !d.reference.is_empty()))
+        .map_err(|e| FieldErrors::single("calibrated", e))?;
+    let before_ids = raw.ids("before_id", derivations.len())?;
+    let after_ids = raw.ids("after_id", derivations.len())?;
+    let limit = params.limit.unwrap_or(100);
+    // Whole windows rather than pages.
+    let window = params.points.is_some() || params.resample.is_some();
+
+    let mut pages = if window {
+        futures::future::try_join_all(derivations.iter().map(|derivation| async {
+            let channel = &derivation.channel;
+            let mut samples = fetch_window_samples(state.reader(), channel, &filter).await?;
+            dsp::reference::apply(state.reader(), &derivation.reference, &filter, &mut samples)
+                .await?;
+            if let Some(mut notch) = dsp::notch::Notch::new(notch) {
+                notch
+                    .apply(state.reader(), channel, &filter, &mut samples)
+                    .await?;
+            }
+            if artifacts.artifacts == Some(analysis::artifacts::Mode::Exclude) {
+                analysis::artifacts::apply(
+                    state.reader(),
+                    channel,
+                    &filter,
+                    &mut samples,
+                    analysis::artifacts::Mode::Exclude,
+                )
+                .await?;
+            }
+            if let Some(target) = params.resample {
+                let rate = dsp::channel_rate(state.reader(), channel, &samples).await?;
+                if let Some(rate) = rate {
+                    samples = dsp::resample::resample(&samples, rate, target).map_err(|e| {
+                        ApiError::bad_request(format!("channel {:?}: {}", channel, e))
+                    })?;
+                }
+            }
+            if let Some(points) = params.points {
+                samples = downsample_samples(samples, points, params.downsample);
+            }
+            Ok::<_, ApiError>((samples, None))
+        }))
+        .await?
+    } else {
+        futures::future::try_join_all(derivations.iter().enumerate().map(|(i, derivation)| {
+            state.store.page(
+                &derivation.channel,
+                before_ids[i],
+                after_ids[i],
+                &filter,
+                limit,
+            )
+        }))
+        .await?
+    };
+    if !window {
+        for (derivation, (samples, _)) in derivations.iter().zip(&mut pages) {
+            dsp::reference::apply(state.reader(), &derivation.reference, &filter, samples).await?;
+            if let Some(mut notch) = dsp::notch::Notch::new(notch) {
+                notch
+                    .apply(state.reader(), &derivation.channel, &filter, samples)
+                    .await?;
+            }
+        }
+    }
+    for (derivation, (samples, _)) in derivations.iter().zip(&mut pages) {
+        calibration::apply(
+            state.reader(),
+            calibrated,
+            &derivation.channel,
+            &filter,
+            samples,
+        )
+        .await?;
+        if derivation.name != derivation.channel {
+            for sample in samples.iter_mut() {
+                sample.channel.clone_from(&derivation.name);
+            }
+        }
+    }
+    let mut marks = vec![None; pages.len()];
+    if let Some(mode) = artifacts.artifacts {
+        for ((derivation, (samples, _)), marks) in
+            derivations.iter().zip(&mut pages).zip(&mut marks)
+        {
+            if mode == analysis::artifacts::Mode::Mark || !window {
+                let channel = &derivation.channel;
+                *marks =
+                    analysis::artifacts::apply(state.reader(), channel, &filter, samples, mode)
+                        .await?;
+            }
+        }
+    }
+
+    let names: Vec<String> = derivations.iter().map(|d| d.channel.clone()).collect();
+    let mut metadata = metadata::of(state.reader(), &names, filter.session_id).await?;
+    if let Some(target) = params.resample {
+        for metadata in &mut metadata {
+            metadata.sample_rate = Some(target);
+        }
+    }
+    if let Some(unit) = units.unit {
+        for ((derivation, (samples, _)), metadata) in
+            derivations.iter().zip(&mut pages).zip(&mut metadata)
+        {
+            let factor = metadata
+                .convert(&derivation.channel, unit)
+                .map_err(ApiError::bad_request)?;
+            for sample in samples.iter_mut() {
+                sample.value *= factor;
+            }
+        }
+    }
+
+    let to_json = if params.resample.is_some() {
+        dsp::resample::to_json
+    } else {
+        analysis::artifacts::to_json
+    };
+    let mut body = if let ([(samples, next_cursor)], [marks]) = (pages.as_slice(), marks.as_slice())
+    {
+        json!({
+            "samples": to_json(samples, marks.as_deref()),
+            "next_cursor": next_cursor,
+            "metadata": metadata[0],
+        })
+    } else {
+        let grouped: Vec<_> = derivations
+            .iter()
+            .zip(&pages)
+            .zip(&marks)
+            .zip(&metadata)
+            .map(
+                |(((derivation, (samples, next_cursor)), marks), metadata)| {
+                    json!({
+                        "channel": derivation.name,
+                        "samples": to_json(samples, marks.as_deref()),
+                        "next_cursor": next_cursor,
+                        "metadata": metadata,
+                    })
+                },
+            )
+            .collect();
+        json!({ "channels": grouped })
+    };
+
+    if let (true, Some(session_id)) = (clock.clock_corrected, filter.session_id) {
+        let estimate = devices::clock::for_session(state.reader(), session_id).await?;
+        devices::clock::annotate(&mut body, estimate.as_ref());
+        body["clock"] = json!(estimate);
+    }
+    if params.include_events {
+        body["events"] = json!(sample_window_events(state.reader(), &filter, &pages).await?);
+    }
+    times.apply(&mut body);
+    Ok(Encoded(format, body))
+}
+
+/// Events overlapping the requested `from` / `to` window, with missing
+/// bounds taken from the earliest and latest returned sample.
+async fn sample_window_events(
+    pool: &PgPool,
+    filter: &SampleFilter,
+    pages: &[(Vec<EegSample>, Option<i32>)],
+) -> Result<Vec<events::Event>, sqlx::Error> {
+    let mut span: Option<(DateTime<Utc>, DateTime<Utc>)> = None;
+    for sample in pages.iter().flat_map(|(samples, _)| samples) {
+        let (first, last) = span.get_or_insert((sample.ts, sample.ts));
+        *first = (*first).min(sample.ts);
+        *last = (*last).max(sample.ts);
+    }
+    let (from, to) = match (filter.from, filter.to, span) {
+        (Some(from), Some(to), _) => (from, to),
+        (from, to, Some((first, last))) => (
+            from.unwrap_or(first),
+            to.unwrap_or(last + chrono::Duration::microseconds(1)),
+        ),
+        _ => return Ok(Vec::new()),
+    };
+    events::overlapping(pool, filter, from, to).await
+}
+
+/// At most `points` of `samples` (oldest first), picked by `method`.
+#[tracing::instrument(skip_all, fields(samples = samples.len(), points = points))]
+pub fn downsample_samples(
+    samples: Vec<EegSample>,
+    points: usize,
+    method: downsample::Method,
+) -> Vec<EegSample> {
+    let Some(first) = samples.first().map(|s| s.ts) else {
+        return samples;
+    };
+    let xy: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|s| {
+            (
+                (s.ts - first).num_microseconds().unwrap_or(i64::MAX) as f64,
+                s.value,
+            )
+        })
+        .collect();
+    let picked = downsample::select(method, &xy, points);
+    let mut samples: Vec<Option<EegSample>> = samples.into_iter().map(Some).collect();
+    picked
+        .into_iter()
+        .filter_map(|i| samples[i].take())
+        .collect()
+}
+
+#[utoipa::path(
+    post,
+    path = "/samples",
+    tag = "samples",
+    security(("api_key" = []), ("bearer" = [])),
+    params(DeviceQuery),
+    request_body = NewSample,
+    responses(
+        (status = 201, description = "`{ id }` of the stored sample", body = serde_json::Value),
+        (status = 200, description = "`{ id: null, already_seen: true, last_seq }`: the device sent `seq` before, and nothing was stored", body = serde_json::Value),
+        (status = 400, description = "Invalid sample, unknown session or mismatching device"),
+        (status = 403, description = "Outside the scope of the API key"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn create_sample(
+    State(state): State<AppState>,
+    key: Option<Extension<devices::keys::ApiKey>>,
+    Valid(Query(params)): Valid<Query<DeviceQuery>>,
+    Valid(Json(sample)): Valid<Json<NewSample>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let device = params.device.as_deref();
+    check_device(device, key.as_deref(), std::slice::from_ref(&sample), None)?;
+
+    match store(
+        &state,
+        device,
+        key.as_deref(),
+        params.seq,
+        params.device_time,
+        vec![sample],
+    )
+    .await?
+    {
+        // Null for a sample of a trigger channel.
+        Sequenced::Stored(ids) => Ok((StatusCode::CREATED, Json(json!({"id": ids.first()})))),
+        Sequenced::AlreadySeen { last_seq } => Ok((
+            StatusCode::OK,
+            Json(json!({"id": null, "already_seen": true, "last_seq": last_seq})),
+        )),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/samples/batch",
+    tag = "samples",
+    security(("api_key" = []), ("bearer" = [])),
+    params(DeviceQuery),
+    request_body = Vec<NewSample>,
+    responses(
+        (status = 201, description = "`{ inserted, ids }`", body = serde_json::Value),
+        (status = 200, description = "`{ inserted: 0, ids: [], already_seen: true, last_seq }`: the device sent `seq` before, and nothing was stored", body = serde_json::Value),
+        (status = 400, description = "Invalid samples, unknown session or mismatching device"),
+        (status = 403, description = "Outside the scope of the API key"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn create_samples_batch(
+    State(state): State<AppState>,
+    key: Option<Extension<devices::keys::ApiKey>>,
+    Valid(Query(params)): Valid<Query<DeviceQuery>>,
+    Valid(Json(samples)): Valid<Json<Vec<NewSample>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let device = params.device.as_deref();
+    check_device(device, key.as_deref(), &samples, None)?;
+
+    let stored = store(
+        &state,
+        device,
+        key.as_deref(),
+        params.seq,
+        params.device_time,
+        samples,
+    )
+    .await?;
+    Ok(stored_batch(stored))
+}
+
+/// Relative difference tolerated between the sample rate of a series and
+/// that registered for its channels.
+const RATE_TOLERANCE: f64 = 1e-6;
+
+/// The samples of `series`, each channel from the series' start or else one
+/// period after its latest sample in the session, at the series' rate or
+/// else the channel's registered one.
+async fn series_samples(state: &AppState, series: &Series) -> Result<Vec<NewSample>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let names: Vec<String> = series.channels.iter().map(|c| c.channel.clone()).collect();
+    let registered = metadata::resolve(&state.pool, &names, series.session_id).await?;
+    let latest: HashMap<String, DateTime<Utc>> = match series.start {
+        Some(_) => HashMap::new(),
+        None => sqlx::query_as(
+            "SELECT channel, max(ts) FROM ( \
+               SELECT channel, max(ts) AS ts FROM eeg_samples \
+               WHERE channel = ANY($1) AND session_id IS NOT DISTINCT FROM $2 GROUP BY channel \
+               UNION ALL SELECT channel, max(end_ts) FROM sample_blocks \
+               WHERE channel = ANY($1) AND session_id IS NOT DISTINCT FROM $2 GROUP BY channel \
+             ) latest GROUP BY channel",
+        )
+        .bind(&names)
+        .bind(series.session_id)
+        .fetch_all(&state.pool)
+        .await?
+        .into_iter()
+        .collect(),
+    };
+
+    let mut samples = Vec::new();
+    for values in &series.channels {
+        let channel = &values.channel;
+        let known = registered.get(channel).and_then(|m| m.sample_rate);
+        let rate = match (series.sample_rate, known) {
+            (Some(rate), Some(known)) if ((rate - known) / known).abs() > RATE_TOLERANCE => {
+                return Err(bad_request(format!(
+                    "sample_rate {} does not match the {} Hz of channel {:?}",
+                    rate, known, channel
+                )));
+            }
+            (Some(rate), _) | (None, Some(rate)) => rate,
+            (None, None) => {
+                return Err(bad_request(format!(
+                    "channel {:?} has no sample rate; give sample_rate",
+                    channel
+                )));
+            }
+        };
+        let start = match series.start {
+            Some(start) => start,
+            None => latest
+                .get(channel)
+                .and_then(|ts| ingest::next_start(*ts, rate))
+                .ok_or_else(|| {
+                    bad_request(format!(
+                        "channel {:?} has no earlier sample in the session; give start",
+                        channel
+                    ))
+                })?,
+        };
+        samples.extend(
+            values
+                .samples(start, rate, series.session_id)
+                .map_err(bad_request)?,
+        );
+    }
+    Ok(samples)
+}
+
+/// Accepts values without timestamps, spaced evenly at the sample rate.
+#[utoipa::path(
+    post,
+    path = "/samples/series",
+    tag = "samples",
+    security(("api_key" = []), ("bearer" = [])),
+    params(DeviceQuery),
+    request_body = Series,
+    responses(
+        (status = 201, description = "`{ inserted, ids }`, ids channel by channel", body = serde_json::Value),
+        (status = 200, description = "`{ inserted: 0, ids: [], already_seen: true, last_seq }`: the device sent `seq` before, and nothing was stored", body = serde_json::Value),
+        (status = 400, description = "No sample rate or one other than registered, no `start` for a channel without earlier samples, unknown session or mismatching device"),
+        (status = 403, description = "Outside the scope of the API key"),
+        (status = 422, description = "Parameters or values that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn create_samples_series(
+    State(state): State<AppState>,
+    key: Option<Extension<devices::keys::ApiKey>>,
+    Valid(Query(params)): Valid<Query<DeviceQuery>>,
+    Valid(Json(series)): Valid<Json<Series>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let samples = series_samples(&state, &series).await?;
+    let device = params.device.as_deref();
+    check_device(device, key.as_deref(), &samples, series.sample_rate)?;
+
+    let stored = store(
+        &state,
+        device,
+        key.as_deref(),
+        params.seq,
+        params.device_time,
+        samples,
+    )
+    .await?;
+    Ok(stored_batch(stored))
+}
+
+/// Accepts packed little-endian f32 frames; see [`ingest::FrameHeader`].
+#[utoipa::path(
+    post,
+    path = "/samples/binary",
+    tag = "samples",
+    security(("api_key" = []), ("bearer" = [])),
+    params(BinaryIngestQuery),
+    request_body(
+        content = Vec<u8>,
+        content_type = "application/octet-stream",
+        description = "16-byte frame header, then frames of packed little-endian f32 values"
+    ),
+    responses(
+        (status = 201, description = "`{ inserted, ids }`", body = serde_json::Value),
+        (status = 200, description = "`{ inserted: 0, ids: [], already_seen: true, last_seq }`: the device sent `seq` before, and nothing was stored", body = serde_json::Value),
+        (status = 400, description = "Malformed frames, unknown session or mismatching device"),
+        (status = 403, description = "Outside the scope of the API key"),
+        (status = 415, description = "Content-Type is not application/octet-stream"),
+        (status = 422, description = "A negative `seq`, named in `details.fields`")
+    )
+)]
+pub async fn create_samples_binary(
+    State(state): State<AppState>,
+    key: Option<Extension<devices::keys::ApiKey>>,
+    Valid(Query(params)): Valid<Query<BinaryIngestQuery>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(media_type);
+    if content_type.as_deref() != Some("application/octet-stream") {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected Content-Type: application/octet-stream",
+        ));
+    }
+
+    let channels: Vec<String> = params
+        .channels
+        .split(',')
+        .map(|c| c.trim().to_string())
+        .collect();
+    let header = ingest::FrameHeader::parse(&body).map_err(ApiError::bad_request)?;
+    let mut samples = ingest::decode_frames(&body, &channels).map_err(ApiError::bad_request)?;
+    for sample in &mut samples {
+        sample.session_id = params.session_id;
+    }
+    ingest::validate_batch(&samples).map_err(ApiError::bad_request)?;
+    check_device(
+        params.device.as_deref(),
+        key.as_deref(),
+        &samples,
+        Some(header.sample_rate as f64),
+    )?;
+
+    let device = params.device.as_deref();
+    let stored = store(
+        &state,
+        device,
+        key.as_deref(),
+        params.seq,
+        params.device_time,
+        samples,
+    )
+    .await?;
+    Ok(stored_batch(stored))
+}
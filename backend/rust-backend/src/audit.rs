@@ -0,0 +1,335 @@
+//! Audit log of authenticated data access (`audit_log` table).
+//!
+//! [`auth::require`](crate::auth::require) records every request a user
+//! makes to a data route, including those refused for lack of a role: who
+//! made it, the route, its query parameters (without `access_token`), the
+//! subject and session it names and the response status. Requests naming
+//! only a session are attributed to the session's subject. Accepted gRPC
+//! calls are recorded under their method path with status 200. Sample
+//! ingest with a device API key is not recorded; key use shows in
+//! `last_used_at` instead. Nothing is recorded while authentication is off.
+//!
+//! Entries are queued and written in batches by a background task, so
+//! recording does not add a database round trip to requests; [`close`]
+//! writes what is queued on shutdown.
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::validation::{check_limit, FieldErrors, Valid, Validate};
+use crate::{versioning, AppState};
+use axum::{
+    extract::{MatchedPath, Query, Request, State},
+    http::{Method, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use utoipa::{IntoParams, ToSchema};
+
+/// Entries queued before requests wait for the writer.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Entries written per statement.
+const BATCH_SIZE: usize = 500;
+
+static WRITER: Mutex<Option<(mpsc::Sender<Entry>, JoinHandle<()>)>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub struct Entry {
+    at: DateTime<Utc>,
+    principal: String,
+    action: &'static str,
+    method: String,
+    route: String,
+    path: String,
+    params: serde_json::Value,
+    subject_id: Option<i32>,
+    session_id: Option<i32>,
+    status: u16,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub at: DateTime<Utc>,
+    pub principal: String,
+    pub action: String,
+    pub method: String,
+    pub route: String,
+    pub path: String,
+    pub params: serde_json::Value,
+    pub subject_id: Option<i32>,
+    pub session_id: Option<i32>,
+    pub status: i16,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    principal: Option<String>,
+    subject_id: Option<i32>,
+    session_id: Option<i32>,
+    /// `read` or `write`.
+    action: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+impl Validate for AuditQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(action) = self.action.as_deref() {
+            errors.check(
+                matches!(action, "read" | "write"),
+                "action",
+                "must be read or write",
+            );
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            errors.check(from < to, "to", "must be after from");
+        }
+        check_limit(errors, self.limit);
+    }
+}
+
+/// Query parameters as a JSON object; repeated names become arrays.
+fn params(request: &Request) -> serde_json::Value {
+    let pairs = Query::<Vec<(String, String)>>::try_from_uri(request.uri())
+        .map(|Query(pairs)| pairs)
+        .unwrap_or_default();
+    let mut params = serde_json::Map::new();
+    for (name, value) in pairs {
+        if name == "access_token" {
+            continue;
+        }
+        match params.get_mut(&name) {
+            Some(serde_json::Value::Array(values)) => values.push(json!(value)),
+            Some(first) => *first = json!([first.take(), value]),
+            None => {
+                params.insert(name, json!(value));
+            }
+        }
+    }
+    serde_json::Value::Object(params)
+}
+
+/// The id in the path segment after `prefix` when the route is `prefix/:id...`.
+fn path_id(route: &str, path: &str, prefix: &str) -> Option<i32> {
+    let rest = route.strip_prefix(prefix)?;
+    if !rest.starts_with("/:") {
+        return None;
+    }
+    path.strip_prefix(prefix)?
+        .trim_start_matches('/')
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn param_id(params: &serde_json::Value, name: &str) -> Option<i32> {
+    match &params[name] {
+        serde_json::Value::String(text) => text.parse().ok(),
+        value => value.as_i64()?.try_into().ok(),
+    }
+}
+
+impl Entry {
+    /// An entry for `request`, made by `principal`, to complete with its
+    /// status once it is answered.
+    pub fn start(principal: &Principal, request: &Request) -> Self {
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_default();
+        let path = request.uri().path().to_string();
+        let params = params(request);
+        let (unversioned_route, unversioned_path) = (
+            versioning::unversioned(&route),
+            versioning::unversioned(&path),
+        );
+        Entry {
+            at: Utc::now(),
+            principal: principal.subject.clone(),
+            action: if matches!(*request.method(), Method::GET | Method::HEAD) {
+                "read"
+            } else {
+                "write"
+            },
+            method: request.method().to_string(),
+            subject_id: path_id(unversioned_route, unversioned_path, "/subjects")
+                .or(param_id(&params, "subject_id")),
+            session_id: path_id(unversioned_route, unversioned_path, "/sessions")
+                .or(param_id(&params, "session_id")),
+            route,
+            path,
+            params,
+            status: 0,
+        }
+    }
+
+    /// Queues the entry with the status the request was answered with.
+    pub async fn finish(mut self, status: StatusCode) {
+        self.status = status.as_u16();
+        queue(self).await;
+    }
+}
+
+/// Records a gRPC call made by `principal` that was accepted. `params` are
+/// the request fields that select data.
+pub async fn record_call(
+    principal: &Principal,
+    rpc: &str,
+    action: &'static str,
+    params: serde_json::Value,
+) {
+    let route = format!("/eeg.v1.EegService/{}", rpc);
+    queue(Entry {
+        at: Utc::now(),
+        principal: principal.subject.clone(),
+        action,
+        method: "POST".to_string(),
+        subject_id: param_id(&params, "subject_id"),
+        session_id: param_id(&params, "session_id"),
+        path: route.clone(),
+        route,
+        params,
+        status: StatusCode::OK.as_u16(),
+    })
+    .await;
+}
+
+async fn queue(entry: Entry) {
+    let sender = WRITER.lock().unwrap().as_ref().map(|(tx, _)| tx.clone());
+    match sender {
+        Some(tx) => {
+            if tx.send(entry).await.is_err() {
+                tracing::error!("audit log writer stopped; entry dropped");
+            }
+        }
+        None => tracing::error!("audit log writer not running; entry dropped"),
+    }
+}
+
+async fn write(pool: &PgPool, entries: &mut Vec<Entry>) {
+    if entries.is_empty() {
+        return;
+    }
+    let mut query: QueryBuilder<Postgres> = QueryBuilder::new(
+        "INSERT INTO audit_log \
+         (at, principal, action, method, route, path, params, subject_id, session_id, status) \
+         SELECT e.at, e.principal, e.action, e.method, e.route, e.path, e.params, \
+         COALESCE(e.subject_id, s.subject_id), e.session_id, e.status FROM (",
+    );
+    query.push_values(entries.iter(), |mut row, entry| {
+        row.push_bind(entry.at)
+            .push_bind(&entry.principal)
+            .push_bind(entry.action)
+            .push_bind(&entry.method)
+            .push_bind(&entry.route)
+            .push_bind(&entry.path)
+            .push_bind(&entry.params)
+            .push_bind(entry.subject_id)
+            .push_bind(entry.session_id)
+            .push_bind(entry.status as i16);
+    });
+    query.push(
+        ") AS e(at, principal, action, method, route, path, params, subject_id, session_id, status) \
+         LEFT JOIN sessions s ON s.id = e.session_id",
+    );
+    if let Err(e) = query.build().execute(pool).await {
+        tracing::error!("cannot write {} audit log entries: {}", entries.len(), e);
+    }
+    entries.clear();
+}
+
+/// Starts the task that writes queued entries.
+pub fn spawn(pool: PgPool) {
+    let (tx, mut rx) = mpsc::channel::<Entry>(QUEUE_CAPACITY);
+    let writer = tokio::spawn(async move {
+        let mut entries = Vec::with_capacity(BATCH_SIZE);
+        while let Some(entry) = rx.recv().await {
+            entries.push(entry);
+            while entries.len() < BATCH_SIZE {
+                match rx.try_recv() {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => break,
+                }
+            }
+            write(&pool, &mut entries).await;
+        }
+    });
+    *WRITER.lock().unwrap() = Some((tx, writer));
+}
+
+/// Stops taking entries and waits until the queued ones are written.
+pub async fn close() {
+    let writer = WRITER.lock().unwrap().take();
+    if let Some((tx, writer)) = writer {
+        drop(tx);
+        let _ = writer.await;
+    }
+}
+
+/// Entries newest first; `next_cursor` is the `before_id` of the next page.
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "`{ entries: [AuditEntry], next_cursor }`, newest first; `next_cursor` is the `before_id` of the next page", body = serde_json::Value),
+        (status = 400, description = "Invalid `action`"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn list_entries(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<AuditQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let limit = params.limit.unwrap_or(100);
+    let mut query: QueryBuilder<Postgres> = QueryBuilder::new(
+        "SELECT id, at, principal, action, method, route, path, params, subject_id, session_id, \
+         status FROM audit_log WHERE TRUE",
+    );
+    if let Some(principal) = &params.principal {
+        query.push(" AND principal = ").push_bind(principal);
+    }
+    if let Some(subject_id) = params.subject_id {
+        query.push(" AND subject_id = ").push_bind(subject_id);
+    }
+    if let Some(session_id) = params.session_id {
+        query.push(" AND session_id = ").push_bind(session_id);
+    }
+    if let Some(action) = &params.action {
+        query.push(" AND action = ").push_bind(action);
+    }
+    if let Some(from) = params.from {
+        query.push(" AND at >= ").push_bind(from);
+    }
+    if let Some(to) = params.to {
+        query.push(" AND at < ").push_bind(to);
+    }
+    if let Some(before_id) = params.before_id {
+        query.push(" AND id < ").push_bind(before_id);
+    }
+    query.push(" ORDER BY id DESC LIMIT ").push_bind(limit + 1);
+    let mut entries: Vec<AuditEntry> = query.build_query_as().fetch_all(&state.pool).await?;
+
+    let next_cursor = if entries.len() > limit as usize {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    Ok(Json(
+        json!({ "entries": entries, "next_cursor": next_cursor }),
+    ))
+}
@@ -0,0 +1,497 @@
+//! JWT bearer authentication of the data routes.
+//!
+//! Authentication is on once `auth.secret`, `auth.public_key` or
+//! `auth.jwks_url` is configured; without any key every route stays open.
+//! Tokens come in `Authorization: Bearer <token>`, or in the `access_token`
+//! query parameter for browser WebSocket and SSE clients, which cannot set
+//! headers. A token must be signed by a configured key, carry `exp` and
+//! `sub`, and match `auth.issuer` and `auth.audience` when those are set.
+//!
+//! Tokens with a `kid` are checked against the JWKS, which is fetched at
+//! startup, every `auth.jwks_refresh_secs`, and again (at most every
+//! [`JWKS_MIN_REFETCH`]) when a token names a key it does not hold yet.
+//! Accepted requests carry a [`Principal`] in their extensions and must
+//! hold a role the route allows (see [`roles`]), or get `403`; both are
+//! recorded in the [`audit`] log. gRPC calls are checked the same way from
+//! their `authorization` metadata.
+//!
+//! Acquisition devices authenticate with an API key in `X-API-Key` instead
+//! (see [`keys`]), whether or not JWT authentication is on. Keys are only
+//! accepted on the [`INGEST_ROUTES`]; requests with one carry the
+//! [`keys::ApiKey`] and no [`Principal`].
+
+use crate::audit;
+use crate::config::AuthConfig;
+use crate::devices::keys;
+use crate::error::ApiError;
+use crate::roles::{self, Role};
+use crate::versioning;
+use crate::webhooks;
+use axum::{
+    extract::{MatchedPath, Query, Request},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// Shortest time between JWKS fetches caused by unknown key ids.
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(30);
+
+/// Routes that accept `POST` with an API key, in every version.
+pub const INGEST_ROUTES: &[&str] = &[
+    "/samples",
+    "/samples/batch",
+    "/samples/binary",
+    "/samples/series",
+];
+
+/// Header, and gRPC metadata key, carrying a device API key.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+static AUTH: OnceLock<Authenticator> = OnceLock::new();
+
+/// Who is asking: the verified token's subject and its assigned roles.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub subject: String,
+    pub roles: Vec<Role>,
+}
+
+struct Key {
+    decoding: DecodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+struct Authenticator {
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    /// Keys of `auth.secret` and `auth.public_key`, for tokens without `kid`.
+    keys: Vec<Key>,
+    jwks_url: Option<String>,
+    jwks: RwLock<HashMap<String, Key>>,
+    fetched: tokio::sync::Mutex<Option<Instant>>,
+}
+
+fn hmac_key(secret: &str) -> Key {
+    Key {
+        decoding: DecodingKey::from_secret(secret.as_bytes()),
+        algorithms: vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+    }
+}
+
+fn pem_key(pem: &[u8]) -> Result<Key, String> {
+    if let Ok(decoding) = DecodingKey::from_rsa_pem(pem) {
+        return Ok(Key {
+            decoding,
+            algorithms: vec![
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ],
+        });
+    }
+    if let Ok(decoding) = DecodingKey::from_ec_pem(pem) {
+        return Ok(Key {
+            decoding,
+            algorithms: vec![Algorithm::ES256, Algorithm::ES384],
+        });
+    }
+    if let Ok(decoding) = DecodingKey::from_ed_pem(pem) {
+        return Ok(Key {
+            decoding,
+            algorithms: vec![Algorithm::EdDSA],
+        });
+    }
+    Err("not an RSA, EC or Ed25519 public key in PEM format".to_string())
+}
+
+/// The key of a JWKS entry, limited to its `alg` when it names one.
+fn jwk_key(jwk: &Jwk) -> Option<Key> {
+    let mut algorithms = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(ec) => match ec.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => return None,
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        AlgorithmParameters::OctetKey(_) => return None,
+    };
+    if let Some(alg) = jwk.common.key_algorithm {
+        let name = format!("{:?}", alg);
+        algorithms.retain(|a| format!("{:?}", a) == name);
+    }
+    let decoding = DecodingKey::from_jwk(jwk).ok()?;
+    (!algorithms.is_empty()).then_some(Key {
+        decoding,
+        algorithms,
+    })
+}
+
+fn rejection(error: &jsonwebtoken::errors::Error) -> String {
+    use jsonwebtoken::errors::ErrorKind;
+    match error.kind() {
+        ErrorKind::InvalidSignature => "invalid signature".to_string(),
+        ErrorKind::ExpiredSignature => "token expired".to_string(),
+        ErrorKind::ImmatureSignature => "token not valid yet".to_string(),
+        ErrorKind::InvalidIssuer => "wrong issuer".to_string(),
+        ErrorKind::InvalidAudience => "wrong audience".to_string(),
+        ErrorKind::MissingRequiredClaim(claim) => format!("missing claim {}", claim),
+        _ => format!("invalid token: {}", error),
+    }
+}
+
+impl Authenticator {
+    fn new(config: &AuthConfig) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        if let Some(secret) = &config.secret {
+            keys.push(hmac_key(secret));
+        }
+        if let Some(path) = &config.public_key {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            keys.push(pem_key(&pem).map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+        if let Some(url) = &config.jwks_url {
+            webhooks::check_url(url).map_err(|e| format!("auth.jwks_url: {}", e))?;
+        }
+        Ok(Self {
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway: config.leeway_secs,
+            keys,
+            jwks_url: config.jwks_url.clone(),
+            jwks: RwLock::default(),
+            fetched: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Fetches the JWKS, unless it was fetched less than `min_age` ago.
+    async fn fetch_jwks(&self, min_age: Duration) {
+        let Some(url) = &self.jwks_url else {
+            return;
+        };
+        let mut fetched = self.fetched.lock().await;
+        if fetched.is_some_and(|at| at.elapsed() < min_age) {
+            return;
+        }
+        *fetched = Some(Instant::now());
+        let set = match webhooks::get_json(url).await {
+            Ok(document) => serde_json::from_value::<JwkSet>(document).map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match set {
+            Ok(set) => {
+                let keys: HashMap<String, Key> = set
+                    .keys
+                    .iter()
+                    .filter_map(|jwk| Some((jwk.common.key_id.clone()?, jwk_key(jwk)?)))
+                    .collect();
+                tracing::info!("loaded {} signing keys from {}", keys.len(), url);
+                *self.jwks.write().unwrap() = keys;
+            }
+            Err(e) => tracing::error!("cannot load JWKS from {}: {}", url, e),
+        }
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        match &self.issuer {
+            Some(issuer) => validation.set_issuer(&[issuer]),
+            None => validation.iss = None,
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        validation
+    }
+
+    fn decode(&self, token: &str, key: &Key, algorithm: Algorithm) -> Result<Principal, String> {
+        if !key.algorithms.contains(&algorithm) {
+            return Err(format!("{:?} is not accepted for this key", algorithm));
+        }
+        let data = jsonwebtoken::decode::<serde_json::Value>(
+            token,
+            &key.decoding,
+            &self.validation(algorithm),
+        )
+        .map_err(|e| rejection(&e))?;
+        let subject = data.claims["sub"]
+            .as_str()
+            .ok_or("sub must be a string")?
+            .to_string();
+        Ok(Principal {
+            roles: roles::of(&subject),
+            subject,
+        })
+    }
+
+    /// Verifies `token`, fetching the JWKS first if it names an unknown key.
+    async fn verify(&self, token: &str) -> Result<Principal, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| rejection(&e))?;
+        if let Some(kid) = &header.kid {
+            if self.jwks_url.is_some() && !self.jwks.read().unwrap().contains_key(kid) {
+                self.fetch_jwks(JWKS_MIN_REFETCH).await;
+            }
+        }
+        self.verify_loaded(token)
+    }
+
+    /// Verifies `token` with the keys loaded so far.
+    fn verify_loaded(&self, token: &str) -> Result<Principal, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| rejection(&e))?;
+        match header.kid {
+            Some(kid) if self.jwks_url.is_some() => {
+                let jwks = self.jwks.read().unwrap();
+                let key = jwks
+                    .get(&kid)
+                    .ok_or_else(|| format!("unknown key id {:?}", kid))?;
+                self.decode(token, key, header.alg)
+            }
+            _ => {
+                let mut error = "no key for tokens without a key id".to_string();
+                for key in self
+                    .keys
+                    .iter()
+                    .filter(|k| k.algorithms.contains(&header.alg))
+                {
+                    match self.decode(token, key, header.alg) {
+                        Ok(principal) => return Ok(principal),
+                        Err(e) => error = e,
+                    }
+                }
+                Err(error)
+            }
+        }
+    }
+}
+
+/// Loads the keys when authentication is configured and starts refreshing
+/// the JWKS. Returns whether authentication is on.
+pub async fn init(config: &AuthConfig) -> Result<bool, String> {
+    if !config.enabled() {
+        return Ok(false);
+    }
+    let auth = Authenticator::new(config)?;
+    let auth = AUTH.get_or_init(|| auth);
+    auth.fetch_jwks(Duration::ZERO).await;
+    if auth.jwks_url.is_some() {
+        let every = Duration::from_secs(config.jwks_refresh_secs);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(every);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                auth.fetch_jwks(JWKS_MIN_REFETCH).await;
+            }
+        });
+    }
+    Ok(true)
+}
+
+/// Whether authentication is on.
+pub fn enabled() -> bool {
+    AUTH.get().is_some()
+}
+
+/// Verifies `token` without waiting for a JWKS fetch, for callers that cannot
+/// wait, like the gRPC interceptor. Unknown key ids are picked up by the
+/// next refresh.
+pub fn verify_now(token: &str) -> Result<Principal, String> {
+    match AUTH.get() {
+        Some(auth) => auth.verify_loaded(token),
+        None => Err("authentication is off".to_string()),
+    }
+}
+
+fn token(request: &Request) -> Option<String> {
+    if let Some(value) = request.headers().get(header::AUTHORIZATION) {
+        let value = value.to_str().ok()?;
+        return value
+            .strip_prefix("Bearer ")
+            .or_else(|| value.strip_prefix("bearer "))
+            .map(|t| t.trim().to_string());
+    }
+    let Query(params) = Query::<HashMap<String, String>>::try_from_uri(request.uri()).ok()?;
+    params.get("access_token").cloned()
+}
+
+fn unauthorized(message: String) -> Response {
+    let challenge = if message == "missing bearer token" {
+        "Bearer".to_string()
+    } else {
+        format!(
+            "Bearer error=\"invalid_token\", error_description=\"{}\"",
+            message.replace(['"', '\\'], "'")
+        )
+    };
+    (
+        [(header::WWW_AUTHENTICATE, challenge)],
+        ApiError::new(StatusCode::UNAUTHORIZED, message),
+    )
+        .into_response()
+}
+
+fn is_ingest(request: &Request) -> bool {
+    request.method() == Method::POST
+        && request
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|path| INGEST_ROUTES.contains(&versioning::unversioned(path.as_str())))
+}
+
+/// Checks the API key of a device request and attaches its [`keys::ApiKey`].
+async fn require_api_key(mut request: Request, next: Next, key: &str) -> Response {
+    let Some(key) = keys::lookup(key) else {
+        return ApiError::new(StatusCode::UNAUTHORIZED, "invalid API key").into_response();
+    };
+    if !is_ingest(&request) {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            "API keys are only accepted on the ingest routes",
+        )
+        .into_response();
+    }
+    tracing::Span::current().record(
+        "enduser.id",
+        format!("device:{}", key.device_serial).as_str(),
+    );
+    request.extensions_mut().insert(key);
+    next.run(request).await
+}
+
+/// Rejects requests without a valid token or API key with `401` and those
+/// without a role the route allows with `403`, and attaches the
+/// [`Principal`] or [`keys::ApiKey`] to the others.
+pub async fn require(mut request: Request, next: Next) -> Response {
+    if let Some(value) = request.headers().get(API_KEY_HEADER) {
+        let key = value.to_str().unwrap_or_default().to_string();
+        return require_api_key(request, next, &key).await;
+    }
+    let Some(auth) = AUTH.get() else {
+        return next.run(request).await;
+    };
+    let Some(token) = token(&request) else {
+        return unauthorized("missing bearer token".to_string());
+    };
+    match auth.verify(&token).await {
+        Ok(principal) => {
+            tracing::Span::current().record("enduser.id", principal.subject.as_str());
+            let entry = audit::Entry::start(&principal, &request);
+            let path = request
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| path.as_str())
+                .unwrap_or_default();
+            if let Err(message) = roles::check(&principal, roles::allowed(request.method(), path)) {
+                entry.finish(StatusCode::FORBIDDEN).await;
+                return ApiError::new(StatusCode::FORBIDDEN, message).into_response();
+            }
+            request.extensions_mut().insert(principal);
+            let response = next.run(request).await;
+            entry.finish(response.status()).await;
+            response
+        }
+        Err(message) => unauthorized(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            secret: Some("s3cret".to_string()),
+            issuer: Some("https://idp.example".to_string()),
+            ..AuthConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn sign(claims: Value, secret: &str) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&Header::new(Algorithm::HS384), &claims, &key).unwrap()
+    }
+
+    fn claims(sub: Value, exp_in: i64) -> Value {
+        let exp = chrono::Utc::now().timestamp() + exp_in;
+        json!({ "sub": sub, "exp": exp, "iss": "https://idp.example" })
+    }
+
+    #[test]
+    fn tokens_signed_with_the_secret_name_their_subject() {
+        let token = sign(claims(json!("user-1"), 600), "s3cret");
+        let principal = authenticator().verify_loaded(&token).unwrap();
+        assert_eq!(principal.subject, "user-1");
+        assert_eq!(principal.roles, roles::of("user-1"));
+    }
+
+    #[test]
+    fn tokens_are_rejected_with_the_reason() {
+        let auth = authenticator();
+        let reason = |token: String| auth.verify_loaded(&token).unwrap_err();
+        let valid = claims(json!("user-1"), 600);
+        assert_eq!(reason(sign(valid.clone(), "other")), "invalid signature");
+        assert_eq!(
+            reason(sign(claims(json!("user-1"), -600), "s3cret")),
+            "token expired"
+        );
+        let mut foreign = valid.clone();
+        foreign["iss"] = json!("https://other.example");
+        assert_eq!(reason(sign(foreign, "s3cret")), "wrong issuer");
+        let mut anonymous = valid;
+        anonymous.as_object_mut().unwrap().remove("sub");
+        assert_eq!(reason(sign(anonymous, "s3cret")), "missing claim sub");
+        assert!(reason("not.a.token".to_string()).starts_with("invalid token"));
+    }
+
+    #[test]
+    fn tokens_come_from_the_header_or_the_query() {
+        let request = |uri: &str, authorization: Option<&str>| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some(value) = authorization {
+                builder = builder.header(header::AUTHORIZATION, value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let token = |uri, authorization| super::token(&request(uri, authorization));
+        assert_eq!(
+            token("/samples", Some("Bearer abc ")),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            token("/samples", Some("bearer abc")),
+            Some("abc".to_string())
+        );
+        assert_eq!(token("/samples", Some("Basic abc")), None);
+        assert_eq!(
+            token("/live/ws?access_token=abc&channel=Cz", None),
+            Some("abc".to_string())
+        );
+        assert_eq!(token("/live/ws?access_token=abc", Some("Basic x")), None);
+        assert_eq!(token("/samples", None), None);
+    }
+}
@@ -0,0 +1,265 @@
+//! Optional Redis cache of aggregates and spectra.
+//!
+//! With `cache.redis_url` set, `/samples/aggregate`, `/samples/buckets` and
+//! `/analysis/psd` keep their answers in Redis for `cache.aggregate_ttl_secs`
+//! or `cache.psd_ttl_secs`, keyed by route, channels and parameters, so
+//! dashboards refreshing the same view share one computation, across every
+//! backend using that Redis.
+//!
+//! Stored samples invalidate the answers computed from them: each channel,
+//! and each channel within a session, has a generation counter in Redis that
+//! ingest increments and that is part of the keys; retention increments a
+//! global one. While Redis does not answer, requests are computed as if
+//! there were no cache, and invalidations made meanwhile are lost, so
+//! answers cached before may be served until they expire.
+
+use crate::config::CacheConfig;
+use crate::error::ApiError;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::AsyncCommands;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Longest wait for an answer from Redis before computing without it.
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(250);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Generation of everything, moved when retention removes samples.
+const GLOBAL_GENERATION: &str = "eeg:gen";
+
+enum Message {
+    Invalidate(Vec<String>),
+    Flush(oneshot::Sender<()>),
+}
+
+struct Cache {
+    conn: ConnectionManager,
+    messages: mpsc::UnboundedSender<Message>,
+}
+
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the latest request to Redis failed.
+static DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Connects to `cache.redis_url`, if set. The connection is made on first
+/// use, so a Redis that is down at startup does not stop the backend.
+pub fn init(config: &CacheConfig) -> Result<(), String> {
+    let Some(url) = &config.redis_url else {
+        return Ok(());
+    };
+    let client =
+        redis::Client::open(url.as_str()).map_err(|e| format!("invalid cache.redis_url: {}", e))?;
+    let options = ConnectionManagerConfig::new()
+        .set_response_timeout(Some(RESPONSE_TIMEOUT))
+        .set_connection_timeout(Some(CONNECT_TIMEOUT))
+        .set_number_of_retries(1);
+    let conn = ConnectionManager::new_lazy_with_config(client, options)
+        .map_err(|e| format!("invalid cache.redis_url: {}", e))?;
+    let (messages, inbox) = mpsc::unbounded_channel();
+    tokio::spawn(invalidator(conn.clone(), inbox));
+    let _ = CACHE.set(Cache { conn, messages });
+    Ok(())
+}
+
+fn failed(e: impl fmt::Display) {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    if !DOWN.swap(true, Ordering::Relaxed) {
+        tracing::warn!("cache unavailable, computing without it: {}", e);
+    }
+}
+
+fn answered() {
+    if DOWN.swap(false, Ordering::Relaxed) {
+        tracing::info!("cache is available again");
+    }
+}
+
+fn generation_key(channel: &str, session_id: Option<i32>) -> String {
+    match session_id {
+        Some(session_id) => format!("{}:{}:{}", GLOBAL_GENERATION, channel, session_id),
+        None => format!("{}:{}", GLOBAL_GENERATION, channel),
+    }
+}
+
+/// Invalidates the answers computed from `channel`, and from `channel`
+/// within each session, for every `(channel, session_id)` stored.
+pub fn invalidate<'a>(stored: impl IntoIterator<Item = (&'a str, Option<i32>)>) {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+    let stored: BTreeSet<_> = stored.into_iter().collect();
+    let mut keys = BTreeSet::new();
+    for (channel, session_id) in stored {
+        keys.insert(generation_key(channel, None));
+        if session_id.is_some() {
+            keys.insert(generation_key(channel, session_id));
+        }
+    }
+    let _ = cache
+        .messages
+        .send(Message::Invalidate(keys.into_iter().collect()));
+}
+
+/// Invalidates every cached answer.
+pub fn invalidate_all() {
+    if let Some(cache) = CACHE.get() {
+        let _ = cache
+            .messages
+            .send(Message::Invalidate(vec![GLOBAL_GENERATION.to_string()]));
+    }
+}
+
+/// Waits until the invalidations made so far have reached Redis, or failed
+/// to; for commands that exit right after storing samples.
+pub async fn flush() {
+    if let Some(cache) = CACHE.get() {
+        let (done, flushed) = oneshot::channel();
+        if cache.messages.send(Message::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+/// Increments the generations named by [`invalidate`], merging what
+/// accumulates while Redis is busy into one round trip.
+async fn invalidator(mut conn: ConnectionManager, mut inbox: mpsc::UnboundedReceiver<Message>) {
+    while let Some(message) = inbox.recv().await {
+        let mut keys = BTreeSet::new();
+        let mut waiting = Vec::new();
+        let mut next = Some(message);
+        while let Some(message) = next {
+            match message {
+                Message::Invalidate(batch) => keys.extend(batch),
+                Message::Flush(done) => waiting.push(done),
+            }
+            next = inbox.try_recv().ok();
+        }
+        if !keys.is_empty() {
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.incr(key, 1).ignore();
+            }
+            match pipe.query_async::<()>(&mut conn).await {
+                Ok(()) => answered(),
+                Err(e) => failed(e),
+            }
+        }
+        for done in waiting {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// What a cached answer depends on.
+pub struct Key<'a> {
+    /// Route name, such as `psd`.
+    pub route: &'static str,
+    pub channels: &'a [String],
+    pub session_id: Option<i32>,
+    /// The query string; parameters are compared regardless of their order,
+    /// except among repetitions of one.
+    pub query: &'a str,
+}
+
+impl Key<'_> {
+    /// The Redis key under the current generations.
+    async fn current(&self, conn: &mut ConnectionManager) -> redis::RedisResult<String> {
+        let mut generations = vec![GLOBAL_GENERATION.to_string()];
+        generations.extend(
+            self.channels
+                .iter()
+                .map(|channel| generation_key(channel, self.session_id)),
+        );
+        let generations: Vec<Option<u64>> = redis::cmd("MGET")
+            .arg(&generations)
+            .query_async(conn)
+            .await?;
+        let generations: Vec<String> = generations
+            .into_iter()
+            .map(|g| g.unwrap_or(0).to_string())
+            .collect();
+
+        let mut pairs: Vec<(String, String)> = form_urlencoded::parse(self.query.as_bytes())
+            .into_owned()
+            .collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(pairs)
+            .finish();
+        Ok(format!(
+            "eeg:cache:{}:{}:{}",
+            self.route,
+            generations.join("."),
+            query
+        ))
+    }
+}
+
+/// The cached answer for `key`, else the outcome of `compute`, which is
+/// cached for `ttl_secs` when it succeeds.
+pub async fn cached<F>(
+    key: Key<'_>,
+    ttl_secs: u64,
+    compute: F,
+) -> Result<serde_json::Value, ApiError>
+where
+    F: Future<Output = Result<serde_json::Value, ApiError>>,
+{
+    let Some(cache) = CACHE.get().filter(|_| ttl_secs > 0) else {
+        return compute.await;
+    };
+    let mut conn = cache.conn.clone();
+    let key = match key.current(&mut conn).await {
+        Ok(key) => key,
+        Err(e) => {
+            failed(e);
+            return compute.await;
+        }
+    };
+    match conn.get::<_, Option<Vec<u8>>>(&key).await {
+        Ok(hit) => {
+            answered();
+            if let Some(value) = hit.and_then(|bytes| serde_json::from_slice(&bytes).ok()) {
+                HITS.fetch_add(1, Ordering::Relaxed);
+                return Ok(value);
+            }
+        }
+        Err(e) => {
+            failed(e);
+            return compute.await;
+        }
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    let value = compute.await?;
+    if let Ok(bytes) = serde_json::to_vec(&value) {
+        if let Err(e) = conn.set_ex::<_, _, ()>(&key, bytes, ttl_secs).await {
+            failed(e);
+        }
+    }
+    Ok(value)
+}
+
+/// Answers served from the cache, computed and stored, and failed requests
+/// to Redis, when the cache is configured.
+pub fn stats() -> Option<(u64, u64, u64)> {
+    CACHE.get().map(|_| {
+        (
+            HITS.load(Ordering::Relaxed),
+            MISSES.load(Ordering::Relaxed),
+            ERRORS.load(Ordering::Relaxed),
+        )
+    })
+}
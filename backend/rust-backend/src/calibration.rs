@@ -0,0 +1,276 @@
+//! Gain and offset calibrations of channels (`calibrations` table).
+//!
+//! A calibration holds from its `calibrated_at` until the next one: a stored
+//! value then reads as `value * gain + offset`, still in the channel's
+//! stored units (see [`crate::metadata`]). It is for a channel as recorded
+//! by one device, or for the channel whatever records it when `device` is
+//! null. Samples are stored as sent; reads apply the calibrations with
+//! `calibrated=true`, the device being that of the requested session.
+
+use crate::channels;
+use crate::dsp::notch::Point;
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{AppState, SampleFilter};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Calibration {
+    pub id: i32,
+    pub channel: String,
+    /// Null for every device.
+    pub device: Option<String>,
+    pub gain: f64,
+    pub offset: f64,
+    pub calibrated_at: DateTime<Utc>,
+    pub notes: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `POST /channels/{name}/calibrations`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CalibrationInput {
+    /// Serial of the device the calibration is for; all devices if omitted.
+    device: Option<String>,
+    gain: f64,
+    /// Defaults to 0.
+    offset: Option<f64>,
+    /// Defaults to now.
+    calibrated_at: Option<DateTime<Utc>>,
+    notes: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CalibrationListQuery {
+    /// Only calibrations for this device.
+    device: Option<String>,
+}
+
+/// Whether reads apply the channels' calibrations.
+#[derive(Debug, Clone, Copy, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CalibrationQuery {
+    /// Return `value * gain + offset` of the calibration in effect at each
+    /// sample instead of the stored value.
+    #[serde(default)]
+    pub calibrated: bool,
+}
+
+impl CalibrationQuery {
+    /// Calibrations do not carry over to a reference's channels.
+    pub fn check(&self, referenced: bool) -> Result<(), String> {
+        if self.calibrated && referenced {
+            return Err("cannot be combined with reference, bipolar or montage".to_string());
+        }
+        Ok(())
+    }
+}
+
+const COLUMNS: &str = "id, channel, device, gain, \"offset\", calibrated_at, notes, created_at";
+
+impl Validate for CalibrationInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            self.gain.is_finite() && self.gain != 0.0,
+            "gain",
+            "must be a finite non-zero number",
+        );
+        errors.check(
+            self.offset.is_none_or(f64::is_finite),
+            "offset",
+            "must be a finite number",
+        );
+    }
+}
+
+/// The calibrations in effect over time for one channel, oldest first.
+#[derive(Debug, Default)]
+pub struct Calibrations(Vec<(DateTime<Utc>, f64, f64)>);
+
+impl Calibrations {
+    /// Gain and offset in effect at `ts`, if any calibration precedes it.
+    pub fn at(&self, ts: DateTime<Utc>) -> Option<(f64, f64)> {
+        let after = self.0.partition_point(|(from, ..)| *from <= ts);
+        after.checked_sub(1).map(|i| (self.0[i].1, self.0[i].2))
+    }
+
+    /// Calibrates `points`; those before the first calibration keep their
+    /// stored value.
+    pub fn apply<P: Point>(&self, points: &mut [P]) {
+        if self.0.is_empty() {
+            return;
+        }
+        for point in points {
+            if let Some((gain, offset)) = self.at(point.ts()) {
+                let value = point.value_mut();
+                *value = *value * gain + offset;
+            }
+        }
+    }
+}
+
+/// Calibrations of `channel` for the device of session `session_id`, or the
+/// channel-wide ones if there are none for it or no session is given.
+pub async fn load(
+    pool: &PgPool,
+    channel: &str,
+    session_id: Option<i32>,
+) -> Result<Calibrations, sqlx::Error> {
+    let rows: Vec<(DateTime<Utc>, f64, f64, bool)> = sqlx::query_as(
+        "SELECT calibrated_at, gain, \"offset\", device IS NOT NULL FROM calibrations \
+         WHERE channel = $1 \
+         AND (device IS NULL OR device = (SELECT device FROM sessions WHERE id = $2)) \
+         ORDER BY calibrated_at, id",
+    )
+    .bind(channel)
+    .bind(session_id)
+    .fetch_all(pool)
+    .await?;
+    let for_device = rows.iter().any(|row| row.3);
+    Ok(Calibrations(
+        rows.into_iter()
+            .filter(|row| row.3 == for_device)
+            .map(|(from, gain, offset, _)| (from, gain, offset))
+            .collect(),
+    ))
+}
+
+/// Calibrates `points` of `channel` when `query` asks for it.
+pub async fn apply<P: Point>(
+    pool: &PgPool,
+    query: CalibrationQuery,
+    channel: &str,
+    filter: &SampleFilter,
+    points: &mut [P],
+) -> Result<(), sqlx::Error> {
+    if query.calibrated && !points.is_empty() {
+        load(pool, channel, filter.session_id).await?.apply(points);
+    }
+    Ok(())
+}
+
+fn unknown_channel(name: &str) -> ApiError {
+    ApiError::not_found(format!("unknown channel {:?}", name))
+}
+
+/// Calibrations of channel `name`, newest first.
+#[utoipa::path(
+    get,
+    path = "/channels/{name}/calibrations",
+    tag = "channels",
+    params(("name" = String, Path), CalibrationListQuery),
+    responses(
+        (status = 200, description = "Newest first", body = Vec<Calibration>),
+        (status = 404, description = "Unknown channel")
+    )
+)]
+pub async fn list_calibrations(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+    Parsed(Query(params)): Parsed<Query<CalibrationListQuery>>,
+) -> Result<Json<Vec<Calibration>>, ApiError> {
+    if !channels::is_known(&name) {
+        return Err(unknown_channel(&name));
+    }
+    let calibrations = sqlx::query_as(&format!(
+        "SELECT {} FROM calibrations \
+         WHERE channel = $1 AND ($2::text IS NULL OR device = $2) \
+         ORDER BY calibrated_at DESC, id DESC",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(&params.device)
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(Json(calibrations))
+}
+
+/// Records a calibration of channel `name`.
+#[utoipa::path(
+    post,
+    path = "/channels/{name}/calibrations",
+    tag = "channels",
+    params(("name" = String, Path)),
+    request_body = CalibrationInput,
+    responses(
+        (status = 201, body = Calibration),
+        (status = 400, description = "Unknown device"),
+        (status = 404, description = "Unknown channel"),
+        (status = 422, description = "Invalid gain or offset, named in `details.fields`")
+    )
+)]
+pub async fn create_calibration(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+    Valid(Json(input)): Valid<Json<CalibrationInput>>,
+) -> Result<(StatusCode, Json<Calibration>), ApiError> {
+    if !channels::is_known(&name) {
+        return Err(unknown_channel(&name));
+    }
+
+    let calibration = sqlx::query_as(&format!(
+        "INSERT INTO calibrations (channel, device, gain, \"offset\", calibrated_at, notes) \
+         VALUES ($1, $2, $3, COALESCE($4, 0), COALESCE($5, now()), $6) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(&input.device)
+    .bind(input.gain)
+    .bind(input.offset)
+    .bind(input.calibrated_at)
+    .bind(&input.notes)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| match &e {
+        sqlx::Error::Database(db) if db.constraint() == Some("calibrations_device_fkey") => {
+            ApiError::bad_request(format!(
+                "unknown device {:?}",
+                input.device.as_deref().unwrap_or_default()
+            ))
+        }
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            unknown_channel(&name)
+        }
+        _ => e.into(),
+    })?;
+    Ok((StatusCode::CREATED, Json(calibration)))
+}
+
+/// Deletes calibration `id` of channel `name`; reads fall back to the one
+/// before it.
+#[utoipa::path(
+    delete,
+    path = "/channels/{name}/calibrations/{id}",
+    tag = "channels",
+    params(("name" = String, Path), ("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "Unknown calibration")
+    )
+)]
+pub async fn delete_calibration(
+    State(state): State<AppState>,
+    Parsed(Path((name, id))): Parsed<Path<(String, i32)>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM calibrations WHERE id = $1 AND channel = $2")
+        .bind(id)
+        .bind(&name)
+        .execute(&state.pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::not_found(format!(
+            "unknown calibration {} of channel {:?}",
+            id, name
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
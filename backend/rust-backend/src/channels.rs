@@ -0,0 +1,374 @@
+//! Channel metadata (`channels` table) and the registry ingest checks against.
+//!
+//! Every ingest path validates samples through [`NewSample::validate`], which
+//! only accepts channels that are registered and enabled. The registry is
+//! cached in memory, updated by the CRUD handlers below and reloaded
+//! periodically so edits made by other instances are picked up. Samples of
+//! `trigger` channels are stored as [`crate::triggers`] changes.
+//!
+//! [`NewSample::validate`]: crate::ingest::NewSample::validate
+
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_NAME_LEN: usize = 64;
+
+/// Registered channel names and whether each is enabled.
+static REGISTRY: RwLock<BTreeMap<String, bool>> = RwLock::new(BTreeMap::new());
+
+/// Registered channels of kind `trigger`.
+static TRIGGERS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+/// Set by [`accept_any`].
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Channel {
+    pub name: String,
+    pub label: Option<String>,
+    pub unit: String,
+    /// Stored values times `scale` are in `unit`.
+    pub scale: f64,
+    pub sample_rate: Option<f64>,
+    pub hardware_index: Option<i32>,
+    pub reference: Option<String>,
+    pub enabled: bool,
+    /// `signal`, or `trigger` for digital trigger codes.
+    pub kind: String,
+}
+
+/// Body of `POST /channels` (with `name`) and `PUT /channels/{name}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChannelInput {
+    name: Option<String>,
+    label: Option<String>,
+    unit: Option<String>,
+    scale: Option<f64>,
+    sample_rate: Option<f64>,
+    hardware_index: Option<i32>,
+    reference: Option<String>,
+    enabled: Option<bool>,
+    /// `signal` (default) or `trigger`.
+    kind: Option<String>,
+}
+
+/// Checks that samples may be stored for `name`.
+pub fn check_accepted(name: &str) -> Result<(), String> {
+    if OPEN.load(Ordering::Relaxed) {
+        return validate_name(name);
+    }
+    match REGISTRY.read().unwrap().get(name) {
+        Some(true) => Ok(()),
+        Some(false) => Err(format!("channel {:?} is disabled", name)),
+        None => Err(format!("unknown channel {:?}", name)),
+    }
+}
+
+/// Accepts samples for any valid channel name from now on, for stores
+/// without a `channels` table such as the SQLite one.
+pub fn accept_any() {
+    OPEN.store(true, Ordering::Relaxed);
+}
+
+/// Names of the enabled registered channels, in order.
+pub fn enabled() -> Vec<String> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, enabled)| **enabled)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Whether `name` is registered, enabled or not.
+pub fn is_known(name: &str) -> bool {
+    REGISTRY.read().unwrap().contains_key(name)
+}
+
+/// Whether `name` is a registered trigger channel.
+pub fn is_trigger(name: &str) -> bool {
+    TRIGGERS.read().unwrap().contains(name)
+}
+
+/// Caches `channel` after a create or update.
+fn cache(channel: &Channel) {
+    REGISTRY
+        .write()
+        .unwrap()
+        .insert(channel.name.clone(), channel.enabled);
+    let mut triggers = TRIGGERS.write().unwrap();
+    if channel.kind == "trigger" {
+        triggers.insert(channel.name.clone());
+    } else {
+        triggers.remove(&channel.name);
+    }
+}
+
+pub(crate) fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!("name must be 1 to {} characters", MAX_NAME_LEN));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err("name may only contain letters, digits, '_', '-' and '.'".to_string());
+    }
+    Ok(())
+}
+
+impl Validate for ChannelInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(Err(e)) = self.name.as_deref().map(validate_name) {
+            errors.add("name", e);
+        }
+        if let Some(unit) = &self.unit {
+            errors.check(!unit.trim().is_empty(), "unit", "must not be empty");
+        }
+        if let Some(scale) = self.scale {
+            errors.check(
+                scale.is_finite() && scale != 0.0,
+                "scale",
+                "must be a finite non-zero number",
+            );
+        }
+        if let Some(rate) = self.sample_rate {
+            errors.check(
+                rate.is_finite() && rate > 0.0,
+                "sample_rate",
+                "must be a positive number",
+            );
+        }
+        if let Some(index) = self.hardware_index {
+            errors.check(index >= 0, "hardware_index", "must not be negative");
+        }
+        if let Some(Err(e)) = self.reference.as_deref().map(validate_name) {
+            errors.add("reference", e);
+        }
+        if let Some(kind) = &self.kind {
+            errors.check(
+                kind == "signal" || kind == "trigger",
+                "kind",
+                "must be signal or trigger",
+            );
+        }
+    }
+}
+
+/// Replaces the cached registry with the contents of `channels`.
+pub async fn load(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let rows: Vec<(String, bool, String)> =
+        sqlx::query_as("SELECT name, enabled, kind FROM channels")
+            .fetch_all(pool)
+            .await?;
+    let count = rows.len();
+    *TRIGGERS.write().unwrap() = rows
+        .iter()
+        .filter(|(_, _, kind)| kind == "trigger")
+        .map(|(name, ..)| name.clone())
+        .collect();
+    *REGISTRY.write().unwrap() = rows
+        .into_iter()
+        .map(|(name, enabled, _)| (name, enabled))
+        .collect();
+    Ok(count)
+}
+
+/// Adds enabled channels created outside the handlers below, such as by an
+/// import, to the cached registry.
+pub fn register(names: &[String]) {
+    let mut registry = REGISTRY.write().unwrap();
+    for name in names {
+        registry.insert(name.clone(), true);
+    }
+}
+
+/// Starts the task that periodically reloads the registry.
+pub fn spawn_reload(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = load(&pool).await {
+                tracing::error!("channel registry reload failed: {}", e);
+            }
+        }
+    });
+}
+
+const COLUMNS: &str =
+    "name, label, unit, scale, sample_rate, hardware_index, reference, enabled, kind";
+
+#[utoipa::path(
+    get,
+    path = "/channels",
+    tag = "channels",
+    responses((status = 200, body = Vec<Channel>))
+)]
+pub async fn list_channels(State(state): State<AppState>) -> Result<Json<Vec<Channel>>, ApiError> {
+    let channels = sqlx::query_as(&format!(
+        "SELECT {} FROM channels ORDER BY hardware_index NULLS LAST, name",
+        COLUMNS
+    ))
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(Json(channels))
+}
+
+#[utoipa::path(
+    get,
+    path = "/channels/{name}",
+    tag = "channels",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = Channel),
+        (status = 404, description = "Unknown channel")
+    )
+)]
+pub async fn get_channel(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<Json<Channel>, ApiError> {
+    sqlx::query_as(&format!("SELECT {} FROM channels WHERE name = $1", COLUMNS))
+        .bind(&name)
+        .fetch_optional(&state.pool)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("unknown channel {:?}", name)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/channels",
+    tag = "channels",
+    request_body = ChannelInput,
+    responses(
+        (status = 201, body = Channel),
+        (status = 409, description = "Channel already registered"),
+        (status = 422, description = "Missing or invalid name, or invalid field, named in `details.fields`")
+    )
+)]
+pub async fn create_channel(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<ChannelInput>>,
+) -> Result<(StatusCode, Json<Channel>), ApiError> {
+    let Some(name) = input.name.clone() else {
+        return Err(FieldErrors::single("name", "is required").into());
+    };
+
+    let channel: Option<Channel> = sqlx::query_as(&format!(
+        "INSERT INTO channels ({}) \
+         VALUES ($1, $2, COALESCE($3, 'uV'), COALESCE($4, 1), $5, $6, $7, COALESCE($8, TRUE), \
+         COALESCE($9, 'signal')) \
+         ON CONFLICT (name) DO NOTHING RETURNING {}",
+        COLUMNS, COLUMNS
+    ))
+    .bind(&name)
+    .bind(&input.label)
+    .bind(&input.unit)
+    .bind(input.scale)
+    .bind(input.sample_rate)
+    .bind(input.hardware_index)
+    .bind(&input.reference)
+    .bind(input.enabled)
+    .bind(&input.kind)
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let channel =
+        channel.ok_or_else(|| ApiError::conflict(format!("channel {:?} already exists", name)))?;
+    cache(&channel);
+    Ok((StatusCode::CREATED, Json(channel)))
+}
+
+/// Updates the given fields; omitted fields keep their value.
+#[utoipa::path(
+    put,
+    path = "/channels/{name}",
+    tag = "channels",
+    params(("name" = String, Path)),
+    request_body = ChannelInput,
+    responses(
+        (status = 200, body = Channel),
+        (status = 400, description = "Another name in the body"),
+        (status = 404, description = "Unknown channel"),
+        (status = 422, description = "Invalid field, named in `details.fields`")
+    )
+)]
+pub async fn update_channel(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+    Valid(Json(input)): Valid<Json<ChannelInput>>,
+) -> Result<Json<Channel>, ApiError> {
+    if input.name.as_deref().is_some_and(|n| n != name) {
+        return Err(ApiError::bad_request("channels cannot be renamed"));
+    }
+
+    let channel: Channel = sqlx::query_as(&format!(
+        "UPDATE channels SET \
+         label = COALESCE($2, label), unit = COALESCE($3, unit), scale = COALESCE($4, scale), \
+         sample_rate = COALESCE($5, sample_rate), hardware_index = COALESCE($6, hardware_index), \
+         reference = COALESCE($7, reference), enabled = COALESCE($8, enabled), \
+         kind = COALESCE($9, kind) \
+         WHERE name = $1 RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(&input.label)
+    .bind(&input.unit)
+    .bind(input.scale)
+    .bind(input.sample_rate)
+    .bind(input.hardware_index)
+    .bind(&input.reference)
+    .bind(input.enabled)
+    .bind(&input.kind)
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| ApiError::not_found(format!("unknown channel {:?}", name)))?;
+
+    cache(&channel);
+    Ok(Json(channel))
+}
+
+/// Unregisters a channel; its stored samples are kept.
+#[utoipa::path(
+    delete,
+    path = "/channels/{name}",
+    tag = "channels",
+    params(("name" = String, Path)),
+    responses(
+        (status = 204, description = "Unregistered; stored samples are kept"),
+        (status = 404, description = "Unknown channel")
+    )
+)]
+pub async fn delete_channel(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM channels WHERE name = $1")
+        .bind(&name)
+        .execute(&state.pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::not_found(format!("unknown channel {:?}", name)));
+    }
+    REGISTRY.write().unwrap().remove(&name);
+    TRIGGERS.write().unwrap().remove(&name);
+    Ok(StatusCode::NO_CONTENT)
+}
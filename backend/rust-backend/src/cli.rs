@@ -0,0 +1,496 @@
+//! Command line: `serve` (the default) and operational subcommands that
+//! would otherwise need `psql` or one-off scripts.
+
+use crate::export::{self, SessionExport};
+use crate::ingest::NewSample;
+use crate::pipeline::SampleCopy;
+use crate::roles::{self, Role};
+use crate::validation;
+use crate::{aggregate, channels, migrations, notify, ChannelQuery, SampleFilter};
+use chrono::{DateTime, Utc};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand};
+use sqlx::PgPool;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// Tables the backend reads and writes.
+const TABLES: [&str; 25] = [
+    "subjects",
+    "sessions",
+    "eeg_samples",
+    "sample_blocks",
+    "events",
+    "trigger_changes",
+    "channels",
+    "session_channels",
+    "calibrations",
+    "devices",
+    "device_api_keys",
+    "ingest_batches",
+    "clock_sync",
+    "role_assignments",
+    "audit_log",
+    "pipelines",
+    "montages",
+    "eeg_agg_1s",
+    "eeg_agg_10s",
+    "eeg_agg_1m",
+    "retention_policies",
+    "impedances",
+    "epoch_sets",
+    "epochs",
+    "alert_rules",
+];
+
+/// Samples per COPY message while seeding.
+const SEED_CHUNK: usize = 10_000;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "rust_backend",
+    about = "EEG backend server and maintenance commands"
+)]
+pub struct Cli {
+    /// Configuration file (default: EEG_CONFIG, else eeg.toml if present)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    /// The subcommand; `serve` when none is given.
+    pub fn command(self) -> Command {
+        self.command.unwrap_or(Command::Serve(ServeArgs::default()))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the REST, WebSocket and gRPC servers (default)
+    Serve(ServeArgs),
+    /// Apply pending schema migrations
+    ///
+    /// Applies the pending schema migrations built into this binary, as
+    /// `serve` does on startup.
+    Migrate,
+    /// Store a synthetic recording for trying the API
+    ///
+    /// Registers the channels and stores a synthetic recording (10 Hz alpha,
+    /// 22 Hz beta and noise) in a new session, ending now.
+    Seed(SeedArgs),
+    /// Write samples or a session to a file
+    ///
+    /// Writes samples as CSV (as GET /samples/export.csv) or a session in one
+    /// of the formats of GET /sessions/{id}/export.
+    Export(ExportArgs),
+    /// Check the database connection and schema
+    ///
+    /// Connects to the configured database, reports the server version,
+    /// migrations, registered channels and latest sample, and fails if a
+    /// table of the schema is missing.
+    CheckDb,
+    /// Grant roles to a user
+    ///
+    /// Adds roles to the user whose tokens carry PRINCIPAL as `sub`, for
+    /// example to assign the first admin. Running servers pick the change
+    /// up within 30 seconds.
+    Grant(RoleArgs),
+    /// Revoke roles from a user
+    Revoke(RoleArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct RoleArgs {
+    /// The user's `sub` claim
+    principal: String,
+    /// admin, clinician, researcher or device
+    #[arg(required = true)]
+    roles: Vec<Role>,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// Stream synthetic EEG into the channels as a device would, whatever
+    /// DEVICE_SOURCE says (see SIMULATOR_CHANNELS and SIMULATOR_RATE)
+    #[arg(long)]
+    pub simulate: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct SeedArgs {
+    /// Channel to record; repeatable or comma-separated
+    #[arg(long = "channel", value_name = "NAME", default_value = "A3,A4")]
+    channels: Vec<String>,
+    /// Length of the recording in seconds, at most 86400
+    #[arg(long, value_name = "N", default_value_t = 60.0, value_parser = seed_seconds)]
+    seconds: f64,
+    /// Sample rate in Hz, at most 10000
+    #[arg(long, value_name = "HZ", default_value_t = 250.0, value_parser = seed_rate)]
+    rate: f64,
+}
+
+fn positive_up_to(text: &str, max: f64) -> Result<f64, String> {
+    let value: f64 = text.parse().map_err(|e| format!("{}", e))?;
+    if !(value > 0.0 && value <= max) {
+        return Err(format!("must be above 0 and at most {}", max));
+    }
+    Ok(value)
+}
+
+fn seed_seconds(text: &str) -> Result<f64, String> {
+    positive_up_to(text, 86_400.0)
+}
+
+fn seed_rate(text: &str) -> Result<f64, String> {
+    positive_up_to(text, 10_000.0)
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// csv, edf, bdf, brainvision, xdf, fif or nwb [default: edf for
+    /// --session alone, else csv]
+    #[arg(long, value_parser = ["csv", "edf", "bdf", "brainvision", "xdf", "fif", "nwb"])]
+    format: Option<String>,
+    /// Session to export; for csv, only its samples
+    #[arg(long, value_name = "ID")]
+    session: Option<i32>,
+    /// csv: channel to export; repeatable or comma-separated [default: A3]
+    #[arg(long = "channel", value_name = "NAME")]
+    channels: Vec<String>,
+    /// csv: only samples at or after TIME (RFC 3339)
+    #[arg(long, value_name = "TIME")]
+    from: Option<DateTime<Utc>>,
+    /// csv: only samples before TIME (RFC 3339)
+    #[arg(long, value_name = "TIME")]
+    to: Option<DateTime<Utc>>,
+    /// csv: only samples of this subject's sessions
+    #[arg(long, value_name = "ID")]
+    subject: Option<i32>,
+    /// Output file [default: standard output]
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+impl ExportArgs {
+    fn csv_only(&self) -> bool {
+        !self.channels.is_empty()
+            || self.from.is_some()
+            || self.to.is_some()
+            || self.subject.is_some()
+    }
+
+    fn format(&self) -> &str {
+        match &self.format {
+            Some(format) => format,
+            None if self.session.is_some() && !self.csv_only() => "edf",
+            None => "csv",
+        }
+    }
+
+    fn filter(&self) -> SampleFilter {
+        SampleFilter {
+            from: self.from,
+            to: self.to,
+            session_id: self.session,
+            subject_id: self.subject,
+        }
+    }
+
+    /// Checks the option combinations clap cannot express.
+    fn check(&self) -> Result<(), String> {
+        let format = self.format();
+        if format == "csv" {
+            return validation::check(&self.filter()).map_err(|e| e.to_string());
+        }
+        if self.session.is_none() {
+            return Err(format!("--format {} needs --session", format));
+        }
+        if self.csv_only() {
+            return Err("--channel, --from, --to and --subject only apply to csv".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// `--channel` values as query pairs, or `channel=default` without any.
+/// They are checked by [`ChannelQuery`] once the configuration, which sets
+/// the channel limit, is loaded.
+fn channel_pairs(values: &[String], default: &str) -> Vec<(String, String)> {
+    if values.is_empty() {
+        return vec![("channel".to_string(), default.to_string())];
+    }
+    values
+        .iter()
+        .map(|value| ("channel".to_string(), value.clone()))
+        .collect()
+}
+
+/// Parses the process arguments, exiting with the usage on errors and
+/// after `--help`.
+pub fn parse() -> Cli {
+    let cli = Cli::parse();
+    if let Some(Command::Export(args)) = &cli.command {
+        if let Err(message) = args.check() {
+            let mut command = <Cli as CommandFactory>::command();
+            command.build();
+            command
+                .find_subcommand_mut("export")
+                .expect("export is a subcommand")
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+    }
+    cli
+}
+
+/// Runs a command other than [`Command::Serve`].
+pub async fn run(command: Command, pool: &PgPool) -> Result<(), String> {
+    match command {
+        Command::Serve(_) => Err("serve is run by main".to_string()),
+        Command::Migrate => migrate(pool).await,
+        Command::Seed(args) => seed(pool, args).await,
+        Command::Export(args) => export(pool, args).await,
+        Command::CheckDb => check_db(pool).await,
+        Command::Grant(args) => {
+            roles::grant(pool, &args.principal, &args.roles)
+                .await
+                .map_err(|e| e.to_string())?;
+            println!(
+                "{} now holds {}",
+                args.principal,
+                role_list(pool, &args.principal).await?
+            );
+            Ok(())
+        }
+        Command::Revoke(args) => {
+            roles::revoke(pool, &args.principal, &args.roles)
+                .await
+                .map_err(|e| e.to_string())?;
+            println!(
+                "{} now holds {}",
+                args.principal,
+                role_list(pool, &args.principal).await?
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn migrate(pool: &PgPool) -> Result<(), String> {
+    let applied = migrations::run(pool)
+        .await
+        .map_err(|e| format!("migration failed: {}", e))?;
+    if applied.is_empty() {
+        println!("schema is up to date");
+    }
+    for version in applied {
+        println!("applied {}", migrations::describe(version));
+    }
+    Ok(())
+}
+
+/// Deterministic noise in `[-1, 1)` (xorshift).
+fn noise(state: &mut u64) -> f64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+}
+
+async fn seed(pool: &PgPool, args: SeedArgs) -> Result<(), String> {
+    let channels = ChannelQuery(channel_pairs(&args.channels, "A3,A4")).channels()?;
+    let count = (args.seconds * args.rate).round() as i64;
+    let step_us = 1e6 / args.rate;
+    let start = Utc::now() - chrono::Duration::microseconds((count as f64 * step_us) as i64);
+    notify::detect(pool).await.map_err(|e| e.to_string())?;
+
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    for name in &channels {
+        channels::validate_name(name)?;
+        sqlx::query(
+            "INSERT INTO channels (name, label, sample_rate) VALUES ($1, $1, $2) \
+             ON CONFLICT (name) DO NOTHING",
+        )
+        .bind(name)
+        .bind(args.rate)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+    let session: i32 = sqlx::query_scalar(
+        "INSERT INTO sessions (device, started_at, ended_at, notes) \
+         VALUES ('seed', $1, $2, 'Synthetic recording from rust_backend seed') RETURNING id",
+    )
+    .bind(start)
+    .bind(start + chrono::Duration::microseconds((count as f64 * step_us) as i64))
+    .fetch_one(&mut tx)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut copy = SampleCopy::start(&mut tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut batch = Vec::with_capacity(SEED_CHUNK);
+    for (c, name) in channels.iter().enumerate() {
+        let mut state = 0x9E37_79B9_7F4A_7C15 ^ (c as u64 + 1);
+        let phase = c as f64 * 0.7;
+        for i in 0..count {
+            let t = i as f64 / args.rate;
+            let tau = std::f64::consts::TAU;
+            batch.push(NewSample {
+                channel: name.clone(),
+                ts: start + chrono::Duration::microseconds((i as f64 * step_us) as i64),
+                value: 20.0 * (tau * 10.0 * t + phase).sin()
+                    + 5.0 * (tau * 22.0 * t).sin()
+                    + 3.0 * noise(&mut state),
+                session_id: Some(session),
+            });
+            if batch.len() == SEED_CHUNK {
+                copy.send(&batch).await.map_err(|e| e.to_string())?;
+                batch.clear();
+            }
+        }
+    }
+    copy.send(&batch).await.map_err(|e| e.to_string())?;
+    let (rows, written) = copy.finish().await.map_err(|e| e.to_string())?;
+    notify::written(&mut *tx, written.iter().map(String::as_str))
+        .await
+        .map_err(|e| e.to_string())?;
+    tx.commit().await.map_err(|e| e.to_string())?;
+    println!(
+        "stored {} samples of {} in session {}",
+        rows,
+        channels.join(", "),
+        session
+    );
+    Ok(())
+}
+
+async fn export(pool: &PgPool, args: ExportArgs) -> Result<(), String> {
+    let (tx, mut rx) = mpsc::channel(8);
+    let pool = pool.clone();
+    let format = args.format().to_string();
+    let writer = if format == "csv" {
+        let channels = ChannelQuery(channel_pairs(&args.channels, "A3")).channels()?;
+        tokio::spawn(export::csv::write(
+            pool,
+            channels,
+            args.filter(),
+            Default::default(),
+            tx,
+        ))
+    } else {
+        let id = args.session.ok_or("a session is required")?;
+        let session = SessionExport::load(&pool, id)
+            .await
+            .map_err(|e| e.message)?
+            .ok_or_else(|| format!("unknown session {}", id))?;
+        tokio::spawn(async move {
+            match format.as_str() {
+                "bdf" => export::edf::write(pool, session, export::edf::Variant::Bdf, tx).await,
+                "brainvision" => export::brainvision::write(pool, session, tx).await,
+                "xdf" => export::xdf::write(pool, session, tx).await,
+                "fif" => export::fif::write(pool, session, tx).await,
+                "nwb" => export::nwb::write(pool, session, tx).await,
+                _ => export::edf::write(pool, session, export::edf::Variant::Edf, tx).await,
+            }
+        })
+    };
+
+    let mut out: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = match &args.output {
+        Some(path) => Box::new(
+            tokio::fs::File::create(path)
+                .await
+                .map_err(|e| format!("cannot create {}: {}", path.display(), e))?,
+        ),
+        None => Box::new(tokio::io::stdout()),
+    };
+    let mut bytes = 0;
+    while let Some(chunk) = rx.recv().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        out.write_all(&chunk).await.map_err(|e| e.to_string())?;
+        bytes += chunk.len();
+    }
+    out.flush().await.map_err(|e| e.to_string())?;
+    writer.await.map_err(|e| e.to_string())??;
+    if let Some(path) = &args.output {
+        eprintln!("wrote {} bytes to {}", bytes, path.display());
+    }
+    Ok(())
+}
+
+/// The roles `principal` holds, for printing.
+async fn role_list(pool: &PgPool, principal: &str) -> Result<String, String> {
+    let held: Vec<String> =
+        sqlx::query_scalar("SELECT role FROM role_assignments WHERE principal = $1 ORDER BY role")
+            .bind(principal)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+    if held.is_empty() {
+        return Ok("no roles".to_string());
+    }
+    Ok(held.join(", "))
+}
+
+async fn check_db(pool: &PgPool) -> Result<(), String> {
+    let version: String = sqlx::query_scalar("SHOW server_version")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| format!("query failed: {}", e))?;
+    println!("connected: PostgreSQL {}", version);
+    println!(
+        "TimescaleDB: {}",
+        if aggregate::detect_timescale(pool).await {
+            "installed"
+        } else {
+            "not installed"
+        }
+    );
+
+    let status = migrations::status(pool).await.map_err(|e| e.to_string())?;
+    println!(
+        "migrations: {} applied, {} pending",
+        status.applied.len(),
+        status.pending.len()
+    );
+    for version in &status.failed {
+        println!("  failed: {}", migrations::describe(*version));
+    }
+    for version in &status.unknown {
+        println!("  applied by a newer backend: {}", version);
+    }
+
+    let mut missing = Vec::new();
+    for table in TABLES {
+        let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+            .bind(table)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !exists {
+            missing.push(table);
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!(
+            "missing tables: {}; run `rust_backend migrate`",
+            missing.join(", ")
+        ));
+    }
+    println!("schema: all {} tables present", TABLES.len());
+
+    let (channels, latest): (i64, Option<DateTime<Utc>>) =
+        sqlx::query_as("SELECT (SELECT count(*) FROM channels), (SELECT max(ts) FROM eeg_samples)")
+            .fetch_one(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+    println!(
+        "{} channels registered, latest sample at {}",
+        channels,
+        latest.map_or("-".to_string(), |ts| ts.to_rfc3339())
+    );
+    Ok(())
+}
@@ -0,0 +1,1128 @@
+//! Backend configuration: built-in defaults, then an optional TOML file, then
+//! environment variables.
+//!
+//! The file is the one given with `--config`, else `EEG_CONFIG`, else
+//! `eeg.toml` in the working directory if there is one. Every section and key is optional:
+//!
+//! ```toml
+//! [server]
+//! bind = "0.0.0.0:8000"
+//! grpc_port = 50051
+//!
+//! [database]
+//! url = "postgres://eeg_user:secret@db:5432/eeg"
+//!
+//! [ingest]
+//! flush_rows = 10000
+//!
+//! [rate_limit]
+//! requests_per_second = 50
+//! burst = 100
+//!
+//! [device]
+//! source = "openbci"
+//!
+//! [device.openbci]
+//! port = "/dev/ttyUSB0"
+//! ```
+//!
+//! The gRPC server listens on `grpc_port` at the address of `bind`, so
+//! `bind = "127.0.0.1:8000"` keeps both servers off other interfaces.
+//!
+//! Environment variables keep their meaning and win over the file, e.g.
+//! `DATABASE_URL` over `database.url`. Unknown keys and invalid values are
+//! errors, so typos do not go unnoticed.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// File read when `EEG_CONFIG` is unset, if it exists.
+const DEFAULT_FILE: &str = "eeg.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub ingest: IngestConfig,
+    pub storage: StorageConfig,
+    pub streaming: StreamingConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+    pub cache: CacheConfig,
+    pub jobs: JobsConfig,
+    pub lsl: LslConfig,
+    pub mqtt: MqttConfig,
+    pub udp: UdpConfig,
+    pub device: DeviceConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// REST address (`BIND_ADDR`).
+    pub bind: SocketAddr,
+    /// gRPC port, on the interface of `bind` (`GRPC_PORT`).
+    pub grpc_port: u16,
+    /// Directory of export files; the system temp directory's `eeg-exports`
+    /// when unset (`EXPORT_DIR`).
+    pub export_dir: Option<PathBuf>,
+    /// Export jobs this backend runs at a time (`EXPORT_WORKERS`).
+    pub export_workers: usize,
+    /// Largest accepted import upload (`IMPORT_MAX_BYTES`).
+    pub import_max_bytes: usize,
+    /// How long a shutdown waits for requests, streams and ingest flushes
+    /// (`SHUTDOWN_TIMEOUT_MS`).
+    pub shutdown_timeout_ms: u64,
+    /// Compress responses with gzip or brotli when the client accepts it
+    /// (`COMPRESSION`).
+    pub compression: bool,
+    /// Smallest response body that is compressed
+    /// (`COMPRESSION_MIN_BYTES`).
+    pub compression_min_bytes: u16,
+    /// Keep serving the data routes at their unversioned paths, next to
+    /// `/v1` (`LEGACY_ROUTES`).
+    pub legacy_routes: bool,
+    /// End of the deprecation window of the unversioned paths, announced in
+    /// their `Sunset` header (`LEGACY_SUNSET`, RFC 3339).
+    pub legacy_sunset: Option<DateTime<Utc>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8000)),
+            grpc_port: 50051,
+            export_dir: None,
+            export_workers: 2,
+            import_max_bytes: 1 << 30,
+            shutdown_timeout_ms: 8000,
+            compression: true,
+            compression_min_bytes: 1024,
+            legacy_routes: true,
+            legacy_sunset: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `DATABASE_URL`; a `sqlite:` URL serves the portable backend from that
+    /// file instead (see [`crate::portable`]).
+    pub url: String,
+    /// Read replica for the read-only handlers (`DATABASE_READ_URL`); it
+    /// shares the pool settings of the primary.
+    pub read_url: Option<String>,
+    /// Pool size (`DATABASE_MAX_CONNECTIONS`).
+    pub max_connections: u32,
+    /// Connections kept open even when idle (`DATABASE_MIN_CONNECTIONS`).
+    pub min_connections: u32,
+    /// How long a query waits for a free connection before failing with
+    /// `503` (`DATABASE_ACQUIRE_TIMEOUT_MS`).
+    pub acquire_timeout_ms: u64,
+    /// How long the first connection at startup may take
+    /// (`DATABASE_CONNECT_TIMEOUT_MS`).
+    pub connect_timeout_ms: u64,
+    /// Idle connections above `min_connections` are closed after this long;
+    /// 0 keeps them (`DATABASE_IDLE_TIMEOUT_SECS`).
+    pub idle_timeout_secs: u64,
+    /// Connections are replaced after this long; 0 keeps them
+    /// (`DATABASE_MAX_LIFETIME_SECS`).
+    pub max_lifetime_secs: u64,
+    /// Prepared statements cached per connection; 0 turns the cache off, as
+    /// PgBouncer in transaction mode needs (`DATABASE_STATEMENT_CACHE_CAPACITY`).
+    pub statement_cache_capacity: usize,
+    /// Apply pending migrations when serving (`DATABASE_RUN_MIGRATIONS`).
+    pub run_migrations: bool,
+}
+
+impl DatabaseConfig {
+    pub fn is_sqlite(&self) -> bool {
+        self.url.starts_with("sqlite:")
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: "postgres://eeg_user:secret@db:5432/eeg".to_string(),
+            read_url: None,
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_ms: 10_000,
+            connect_timeout_ms: 5000,
+            idle_timeout_secs: 600,
+            max_lifetime_secs: 1800,
+            statement_cache_capacity: 100,
+            run_migrations: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestConfig {
+    /// Buffered samples of a background source that trigger a flush
+    /// (`INGEST_FLUSH_ROWS`).
+    pub flush_rows: usize,
+    /// Longest time between flushes (`INGEST_FLUSH_MS`).
+    pub flush_ms: u64,
+    /// How long batch sequence numbers are remembered for retries
+    /// (`INGEST_SEQUENCE_WINDOW_SECS`).
+    pub sequence_window_secs: u64,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            flush_rows: 10_000,
+            flush_ms: 250,
+            sequence_window_secs: 86_400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Age at which the `blocks` job packs raw samples into blocks
+    /// (`STORAGE_BLOCK_AFTER_SECS`).
+    pub block_after_secs: u64,
+    /// Span of time of a block, 10 to 1000 ms (`STORAGE_BLOCK_MS`).
+    pub block_ms: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            block_after_secs: 86_400,
+            block_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamingConfig {
+    /// How often live subscribers (WebSocket, SSE, gRPC, GraphQL) check for
+    /// new samples (`LIVE_POLL_MS`).
+    pub poll_interval_ms: u64,
+    /// How often live streams with `quality=true` report signal quality
+    /// (`LIVE_QUALITY_MS`).
+    pub quality_interval_ms: u64,
+    /// Latest samples of each channel kept in memory for live polls; 0
+    /// reads every poll from the database (`LIVE_BUFFER_SAMPLES`).
+    pub buffer_samples: usize,
+    /// Wake live subscribers on Postgres notifications of new samples
+    /// rather than polling (`LIVE_NOTIFY`).
+    pub notify: bool,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 100,
+            quality_interval_ms: 2000,
+            buffer_samples: 10_000,
+            notify: true,
+        }
+    }
+}
+
+impl StreamingConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn quality_interval(&self) -> Duration {
+        Duration::from_millis(self.quality_interval_ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Channels named in one request (`MAX_QUERY_CHANNELS`).
+    pub max_query_channels: usize,
+    /// Upper bound on `points` in `/samples` (`MAX_POINTS`).
+    pub max_points: usize,
+    /// Samples per channel read for a whole window (`MAX_WINDOW_ROWS`).
+    pub max_window_rows: i64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_query_channels: 64,
+            max_points: 10_000,
+            max_window_rows: 2_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Required `iss` claim (`JWT_ISSUER`).
+    pub issuer: Option<String>,
+    /// Required `aud` claim (`JWT_AUDIENCE`).
+    pub audience: Option<String>,
+    /// HMAC secret of HS256, HS384 and HS512 tokens (`JWT_SECRET`).
+    pub secret: Option<String>,
+    /// PEM file with an RSA, EC or Ed25519 public key (`JWT_PUBLIC_KEY`).
+    pub public_key: Option<PathBuf>,
+    /// JWKS document of the identity provider (`JWT_JWKS_URL`).
+    pub jwks_url: Option<String>,
+    /// How often the JWKS is fetched again (`JWT_JWKS_REFRESH_SECS`).
+    pub jwks_refresh_secs: u64,
+    /// Clock skew allowed on `exp` and `nbf` (`JWT_LEEWAY_SECS`).
+    pub leeway_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            audience: None,
+            secret: None,
+            public_key: None,
+            jwks_url: None,
+            jwks_refresh_secs: 300,
+            leeway_secs: 60,
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Whether any key is configured, which turns authentication on.
+    pub fn enabled(&self) -> bool {
+        self.secret.is_some() || self.public_key.is_some() || self.jwks_url.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Sustained requests per second of a client address; 0 turns the limit
+    /// off (`RATE_LIMIT_RPS`).
+    pub requests_per_second: f64,
+    /// Requests a client address may make at once (`RATE_LIMIT_BURST`).
+    pub burst: u32,
+    /// Sustained requests per second of a device API key; 0 turns the limit
+    /// off (`RATE_LIMIT_API_KEY_RPS`).
+    pub api_key_requests_per_second: f64,
+    /// Requests a device API key may make at once (`RATE_LIMIT_API_KEY_BURST`).
+    pub api_key_burst: u32,
+    /// Take the client address from the last `X-Forwarded-For` entry, for a
+    /// backend behind a reverse proxy (`RATE_LIMIT_TRUST_FORWARDED_FOR`).
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 50.0,
+            burst: 100,
+            api_key_requests_per_second: 200.0,
+            api_key_burst: 400,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins that may call the API from a browser, as `https://host:port`,
+    /// or `*` for any; CORS is off when empty (`CORS_ALLOWED_ORIGINS`).
+    pub allowed_origins: Vec<String>,
+    /// `CORS_ALLOWED_METHODS`.
+    pub allowed_methods: Vec<String>,
+    /// Request headers besides the CORS-safelisted ones
+    /// (`CORS_ALLOWED_HEADERS`).
+    pub allowed_headers: Vec<String>,
+    /// Allow cookies and `Authorization` on cross-origin requests
+    /// (`CORS_ALLOW_CREDENTIALS`).
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response (`CORS_MAX_AGE_SECS`).
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: ["authorization", "content-type", "x-api-key"]
+                .map(String::from)
+                .to_vec(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Redis instance caching aggregates and spectra; nothing is cached
+    /// without it (`REDIS_URL`).
+    pub redis_url: Option<String>,
+    /// How long `/samples/aggregate` and `/samples/buckets` answers are
+    /// kept; 0 does not cache them (`CACHE_AGGREGATE_TTL_SECS`).
+    pub aggregate_ttl_secs: u64,
+    /// How long `/analysis/psd` answers are kept; 0 does not cache them
+    /// (`CACHE_PSD_TTL_SECS`).
+    pub psd_ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            redis_url: None,
+            aggregate_ttl_secs: 30,
+            psd_ttl_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// How often the aggregate tiers catch up with new samples
+    /// (`JOB_TIERS_SECS`).
+    pub tiers_secs: u64,
+    /// How often retention policies are enforced (`JOB_RETENTION_SECS`).
+    pub retention_secs: u64,
+    /// How often the quality of recently written channels is measured; 0
+    /// turns the scan off (`JOB_QUALITY_SECS`).
+    pub quality_secs: u64,
+    /// How often recently written sessions are checked for gaps; 0 turns
+    /// the check off (`JOB_GAPS_SECS`).
+    pub gaps_secs: u64,
+    /// How often export jobs finished a day ago are removed with their
+    /// files (`JOB_EXPORTS_SECS`).
+    pub exports_secs: u64,
+    /// How often old raw samples are packed into blocks; 0, the default,
+    /// keeps them as rows (`JOB_BLOCKS_SECS`).
+    pub blocks_secs: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            tiers_secs: 5,
+            retention_secs: 3600,
+            quality_secs: 60,
+            gaps_secs: 300,
+            exports_secs: 600,
+            blocks_secs: 0,
+        }
+    }
+}
+
+impl JobsConfig {
+    pub fn tiers_interval(&self) -> Duration {
+        Duration::from_secs(self.tiers_secs)
+    }
+
+    pub fn retention_interval(&self) -> Duration {
+        Duration::from_secs(self.retention_secs)
+    }
+
+    pub fn quality_interval(&self) -> Duration {
+        Duration::from_secs(self.quality_secs)
+    }
+
+    pub fn gaps_interval(&self) -> Duration {
+        Duration::from_secs(self.gaps_secs)
+    }
+
+    pub fn exports_interval(&self) -> Duration {
+        Duration::from_secs(self.exports_secs)
+    }
+
+    pub fn blocks_interval(&self) -> Duration {
+        Duration::from_secs(self.blocks_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LslConfig {
+    /// Start the Lab Streaming Layer recorder (`LSL_ENABLED`).
+    pub enabled: bool,
+    /// Value of the stream `type` property to record (`LSL_STREAM_TYPE`).
+    pub stream_type: String,
+    /// Path or name of liblsl for the dynamic loader (`LSL_LIBRARY`).
+    pub library: String,
+}
+
+impl Default for LslConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stream_type: "EEG".to_string(),
+            library: "liblsl.so".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Broker to subscribe to; the bridge is off without one (`MQTT_HOST`).
+    pub host: Option<String>,
+    /// `MQTT_PORT`.
+    pub port: u16,
+    /// `MQTT_CLIENT_ID`.
+    pub client_id: String,
+    /// Subscription filter (`MQTT_TOPIC`).
+    pub topic: String,
+    /// `MQTT_USERNAME`.
+    pub username: Option<String>,
+    /// `MQTT_PASSWORD`.
+    pub password: Option<String>,
+    /// Channel names of binary frames; empty takes them from the topic
+    /// (`MQTT_BINARY_CHANNELS`).
+    pub binary_channels: Vec<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 1883,
+            client_id: "eeg-rust-backend".to_string(),
+            topic: "eeg/+/samples".to_string(),
+            username: None,
+            password: None,
+            binary_channels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    /// Address of the datagram listener; it is off without one (`UDP_BIND`).
+    pub bind: Option<SocketAddr>,
+    /// Spacing of the values of a datagram, in Hz (`UDP_SAMPLE_RATE`).
+    pub sample_rate: f64,
+    /// Channel names indexed by channel id (`UDP_CHANNELS`).
+    pub channels: Vec<String>,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            sample_rate: 250.0,
+            channels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// `openbci`, `brainflow`, `simulator` or `none`; `openbci` when unset
+    /// and `openbci.port` is (`DEVICE_SOURCE`).
+    pub source: Option<String>,
+    /// Registered device whose profile the stream is checked against
+    /// (`DEVICE_SERIAL`).
+    pub serial: Option<String>,
+    pub openbci: OpenBciConfig,
+    pub brainflow: BrainFlowConfig,
+    pub simulator: SimulatorConfig,
+}
+
+impl DeviceConfig {
+    /// The selected source, `none` included, or `simulator` with `simulate`.
+    pub fn selected(&self, simulate: bool) -> Option<&str> {
+        if simulate {
+            return Some("simulator");
+        }
+        match (self.source.as_deref(), &self.openbci.port) {
+            (Some(source), _) => Some(source),
+            (None, Some(_)) => Some("openbci"),
+            (None, None) => None,
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        use crate::devices::{brainflow, simulator};
+        match self.source.as_deref() {
+            None | Some("") | Some("none") | Some("brainflow") | Some("simulator") => {}
+            Some("openbci") if self.openbci.port.is_none() => {
+                return Err("device.source openbci needs device.openbci.port".to_string())
+            }
+            Some("openbci") => {}
+            Some(other) => return Err(format!("unknown device.source {:?}", other)),
+        }
+        if !(self.openbci.gain.is_finite() && self.openbci.gain > 0.0) {
+            return Err("device.openbci.gain must be positive".to_string());
+        }
+        if brainflow::board_id(&self.brainflow.board).is_none() {
+            return Err(format!(
+                "unknown device.brainflow.board {:?}",
+                self.brainflow.board
+            ));
+        }
+        if self.simulator.channels.is_empty() {
+            return Err("device.simulator.channels names no channel".to_string());
+        }
+        let rate = self.simulator.sample_rate;
+        if !(rate > 0.0 && rate <= simulator::MAX_RATE) {
+            return Err(format!(
+                "device.simulator.sample_rate must be above 0 and at most {}",
+                simulator::MAX_RATE
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenBciConfig {
+    /// Serial device of the Cyton dongle (`OPENBCI_PORT`).
+    pub port: Option<String>,
+    /// ADS1299 gain configured on the board (`OPENBCI_GAIN`).
+    pub gain: f64,
+    /// Names of the 8 inputs, in board order; `CH1` to `CH8` when empty
+    /// (`OPENBCI_CHANNELS`).
+    pub channels: Vec<String>,
+}
+
+impl Default for OpenBciConfig {
+    fn default() -> Self {
+        Self {
+            port: None,
+            gain: 24.0,
+            channels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrainFlowConfig {
+    /// Board name or numeric BrainFlow id (`BRAINFLOW_BOARD`).
+    pub board: String,
+    /// `BRAINFLOW_SERIAL_PORT`.
+    pub serial_port: String,
+    /// `BRAINFLOW_MAC_ADDRESS`.
+    pub mac_address: String,
+    /// Channel names; the board's EEG names when empty
+    /// (`BRAINFLOW_CHANNELS`).
+    pub channels: Vec<String>,
+    /// `BRAINFLOW_LIBRARY`.
+    pub library: String,
+}
+
+impl Default for BrainFlowConfig {
+    fn default() -> Self {
+        Self {
+            board: "synthetic".to_string(),
+            serial_port: String::new(),
+            mac_address: String::new(),
+            channels: Vec::new(),
+            library: "libBoardController.so".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulatorConfig {
+    /// `SIMULATOR_CHANNELS`.
+    pub channels: Vec<String>,
+    /// Sample rate in Hz (`SIMULATOR_RATE`).
+    pub sample_rate: f64,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            channels: crate::devices::simulator::DEFAULT_CHANNELS
+                .split(',')
+                .map(String::from)
+                .collect(),
+            sample_rate: 250.0,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        use axum::http::{HeaderName, HeaderValue, Method};
+        for origin in self.allowed_origins.iter().filter(|o| *o != "*") {
+            // Browsers send `scheme://host[:port]`, without a path or a
+            // trailing slash; anything else would never match.
+            let host = origin
+                .strip_prefix("https://")
+                .or_else(|| origin.strip_prefix("http://"));
+            let valid = HeaderValue::from_str(origin).is_ok()
+                && host.is_some_and(|host| !host.is_empty() && !host.contains('/'));
+            if !valid {
+                return Err(format!("invalid cors.allowed_origins entry {:?}", origin));
+            }
+        }
+        if self.allowed_origins.len() > 1 && self.allowed_origins.iter().any(|o| o == "*") {
+            return Err("cors.allowed_origins may not combine `*` with origins".to_string());
+        }
+        if let Some(method) = self
+            .allowed_methods
+            .iter()
+            .find(|m| m.parse::<Method>().is_err())
+        {
+            return Err(format!("invalid cors.allowed_methods entry {:?}", method));
+        }
+        if let Some(header) = self
+            .allowed_headers
+            .iter()
+            .find(|h| h.parse::<HeaderName>().is_err())
+        {
+            return Err(format!("invalid cors.allowed_headers entry {:?}", header));
+        }
+        if self.allow_credentials && self.allowed_origins.iter().any(|o| o == "*") {
+            return Err(
+                "cors.allow_credentials needs explicit cors.allowed_origins, not `*`".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Overrides `target` with the environment variable `name`, if set.
+fn env<T: FromStr>(target: &mut T, name: &str) -> Result<(), String>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Some(text) = std::env::var(name).ok().filter(|v| !v.is_empty()) {
+        *target = text
+            .parse()
+            .map_err(|e| format!("invalid {} {:?}: {}", name, text, e))?;
+    }
+    Ok(())
+}
+
+/// Like [`env`], for settings without a default.
+fn env_opt<T: FromStr>(target: &mut Option<T>, name: &str) -> Result<(), String>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Some(text) = std::env::var(name).ok().filter(|v| !v.is_empty()) {
+        let value = text
+            .parse()
+            .map_err(|e| format!("invalid {} {:?}: {}", name, text, e))?;
+        *target = Some(value);
+    }
+    Ok(())
+}
+
+/// Like [`env`], for switches that also accept `1`/`0` and `yes`/`no`.
+fn env_flag(target: &mut bool, name: &str) -> Result<(), String> {
+    if let Some(text) = std::env::var(name).ok().filter(|v| !v.is_empty()) {
+        *target = match text.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => true,
+            "0" | "false" | "no" => false,
+            _ => {
+                return Err(format!(
+                    "invalid {} {:?}: expected true or false",
+                    name, text
+                ))
+            }
+        };
+    }
+    Ok(())
+}
+
+/// Like [`env`], for comma-separated lists.
+fn env_list(target: &mut Vec<String>, name: &str) {
+    if let Ok(text) = std::env::var(name) {
+        *target = text
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
+
+fn to_json(value: &toml_edit::Value) -> serde_json::Value {
+    use toml_edit::Value;
+    match value {
+        Value::String(s) => serde_json::json!(s.value()),
+        Value::Integer(i) => serde_json::json!(i.value()),
+        Value::Float(f) => serde_json::json!(f.value()),
+        Value::Boolean(b) => serde_json::json!(b.value()),
+        Value::Datetime(d) => serde_json::json!(d.value().to_string()),
+        Value::Array(array) => array.iter().map(to_json).collect(),
+        Value::InlineTable(table) => table
+            .iter()
+            .map(|(key, value)| (key.to_string(), to_json(value)))
+            .collect(),
+    }
+}
+
+fn item_to_json(item: &toml_edit::Item) -> serde_json::Value {
+    use toml_edit::Item;
+    match item {
+        Item::None => serde_json::Value::Null,
+        Item::Value(value) => to_json(value),
+        Item::Table(table) => table
+            .iter()
+            .map(|(key, item)| (key.to_string(), item_to_json(item)))
+            .collect(),
+        Item::ArrayOfTables(tables) => tables
+            .iter()
+            .map(|table| {
+                table
+                    .iter()
+                    .map(|(key, item)| (key.to_string(), item_to_json(item)))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into()
+            })
+            .collect::<Vec<serde_json::Value>>()
+            .into(),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let document: toml_edit::DocumentMut = text
+        .parse()
+        .map_err(|e| format!("invalid TOML in {}: {}", path.display(), e))?;
+    serde_json::from_value(item_to_json(document.as_item()))
+        .map_err(|e| format!("invalid configuration in {}: {}", path.display(), e))
+}
+
+impl Config {
+    /// Defaults, the configuration file and the environment, in that order.
+    fn load(file: Option<&Path>) -> Result<Self, String> {
+        let path = match (file, std::env::var("EEG_CONFIG")) {
+            (Some(file), _) => Some(file.to_path_buf()),
+            (None, Ok(path)) if !path.is_empty() => Some(PathBuf::from(path)),
+            _ => Some(PathBuf::from(DEFAULT_FILE)).filter(|p| p.is_file()),
+        };
+        let mut config = match &path {
+            Some(path) => read_file(path)?,
+            None => Config::default(),
+        };
+
+        let server = &mut config.server;
+        env(&mut server.bind, "BIND_ADDR")?;
+        env(&mut server.grpc_port, "GRPC_PORT")?;
+        env_opt(&mut server.export_dir, "EXPORT_DIR")?;
+        env(&mut server.export_workers, "EXPORT_WORKERS")?;
+        env(&mut server.import_max_bytes, "IMPORT_MAX_BYTES")?;
+        env(&mut server.shutdown_timeout_ms, "SHUTDOWN_TIMEOUT_MS")?;
+        env(&mut server.compression, "COMPRESSION")?;
+        env(&mut server.compression_min_bytes, "COMPRESSION_MIN_BYTES")?;
+        env(&mut server.legacy_routes, "LEGACY_ROUTES")?;
+        env_opt(&mut server.legacy_sunset, "LEGACY_SUNSET")?;
+        let database = &mut config.database;
+        env(&mut database.url, "DATABASE_URL")?;
+        env_opt(&mut database.read_url, "DATABASE_READ_URL")?;
+        env(&mut database.max_connections, "DATABASE_MAX_CONNECTIONS")?;
+        env(&mut database.min_connections, "DATABASE_MIN_CONNECTIONS")?;
+        env(
+            &mut database.acquire_timeout_ms,
+            "DATABASE_ACQUIRE_TIMEOUT_MS",
+        )?;
+        env(
+            &mut database.connect_timeout_ms,
+            "DATABASE_CONNECT_TIMEOUT_MS",
+        )?;
+        env(
+            &mut database.idle_timeout_secs,
+            "DATABASE_IDLE_TIMEOUT_SECS",
+        )?;
+        env(
+            &mut database.max_lifetime_secs,
+            "DATABASE_MAX_LIFETIME_SECS",
+        )?;
+        env(
+            &mut database.statement_cache_capacity,
+            "DATABASE_STATEMENT_CACHE_CAPACITY",
+        )?;
+        env(&mut database.run_migrations, "DATABASE_RUN_MIGRATIONS")?;
+        env(&mut config.ingest.flush_rows, "INGEST_FLUSH_ROWS")?;
+        env(&mut config.ingest.flush_ms, "INGEST_FLUSH_MS")?;
+        env(
+            &mut config.ingest.sequence_window_secs,
+            "INGEST_SEQUENCE_WINDOW_SECS",
+        )?;
+        env(
+            &mut config.storage.block_after_secs,
+            "STORAGE_BLOCK_AFTER_SECS",
+        )?;
+        env(&mut config.storage.block_ms, "STORAGE_BLOCK_MS")?;
+        env(&mut config.streaming.poll_interval_ms, "LIVE_POLL_MS")?;
+        env(&mut config.streaming.quality_interval_ms, "LIVE_QUALITY_MS")?;
+        env(&mut config.streaming.buffer_samples, "LIVE_BUFFER_SAMPLES")?;
+        env(&mut config.streaming.notify, "LIVE_NOTIFY")?;
+        let limits = &mut config.limits;
+        env(&mut limits.max_query_channels, "MAX_QUERY_CHANNELS")?;
+        env(&mut limits.max_points, "MAX_POINTS")?;
+        env(&mut limits.max_window_rows, "MAX_WINDOW_ROWS")?;
+        let auth = &mut config.auth;
+        env_opt(&mut auth.issuer, "JWT_ISSUER")?;
+        env_opt(&mut auth.audience, "JWT_AUDIENCE")?;
+        env_opt(&mut auth.secret, "JWT_SECRET")?;
+        env_opt(&mut auth.public_key, "JWT_PUBLIC_KEY")?;
+        env_opt(&mut auth.jwks_url, "JWT_JWKS_URL")?;
+        env(&mut auth.jwks_refresh_secs, "JWT_JWKS_REFRESH_SECS")?;
+        env(&mut auth.leeway_secs, "JWT_LEEWAY_SECS")?;
+        let rate_limit = &mut config.rate_limit;
+        env(&mut rate_limit.requests_per_second, "RATE_LIMIT_RPS")?;
+        env(&mut rate_limit.burst, "RATE_LIMIT_BURST")?;
+        env(
+            &mut rate_limit.api_key_requests_per_second,
+            "RATE_LIMIT_API_KEY_RPS",
+        )?;
+        env(&mut rate_limit.api_key_burst, "RATE_LIMIT_API_KEY_BURST")?;
+        env(
+            &mut rate_limit.trust_forwarded_for,
+            "RATE_LIMIT_TRUST_FORWARDED_FOR",
+        )?;
+        let cors = &mut config.cors;
+        env_list(&mut cors.allowed_origins, "CORS_ALLOWED_ORIGINS");
+        env_list(&mut cors.allowed_methods, "CORS_ALLOWED_METHODS");
+        env_list(&mut cors.allowed_headers, "CORS_ALLOWED_HEADERS");
+        env(&mut cors.allow_credentials, "CORS_ALLOW_CREDENTIALS")?;
+        env(&mut cors.max_age_secs, "CORS_MAX_AGE_SECS")?;
+        let cache = &mut config.cache;
+        env_opt(&mut cache.redis_url, "REDIS_URL")?;
+        env(&mut cache.aggregate_ttl_secs, "CACHE_AGGREGATE_TTL_SECS")?;
+        env(&mut cache.psd_ttl_secs, "CACHE_PSD_TTL_SECS")?;
+        let jobs = &mut config.jobs;
+        env(&mut jobs.tiers_secs, "JOB_TIERS_SECS")?;
+        env(&mut jobs.retention_secs, "JOB_RETENTION_SECS")?;
+        env(&mut jobs.quality_secs, "JOB_QUALITY_SECS")?;
+        env(&mut jobs.gaps_secs, "JOB_GAPS_SECS")?;
+        env(&mut jobs.exports_secs, "JOB_EXPORTS_SECS")?;
+        env(&mut jobs.blocks_secs, "JOB_BLOCKS_SECS")?;
+        let lsl = &mut config.lsl;
+        env_flag(&mut lsl.enabled, "LSL_ENABLED")?;
+        env(&mut lsl.stream_type, "LSL_STREAM_TYPE")?;
+        env(&mut lsl.library, "LSL_LIBRARY")?;
+        let mqtt = &mut config.mqtt;
+        env_opt(&mut mqtt.host, "MQTT_HOST")?;
+        env(&mut mqtt.port, "MQTT_PORT")?;
+        env(&mut mqtt.client_id, "MQTT_CLIENT_ID")?;
+        env(&mut mqtt.topic, "MQTT_TOPIC")?;
+        env_opt(&mut mqtt.username, "MQTT_USERNAME")?;
+        env_opt(&mut mqtt.password, "MQTT_PASSWORD")?;
+        env_list(&mut mqtt.binary_channels, "MQTT_BINARY_CHANNELS");
+        let udp = &mut config.udp;
+        env_opt(&mut udp.bind, "UDP_BIND")?;
+        env(&mut udp.sample_rate, "UDP_SAMPLE_RATE")?;
+        env_list(&mut udp.channels, "UDP_CHANNELS");
+        let device = &mut config.device;
+        env_opt(&mut device.source, "DEVICE_SOURCE")?;
+        env_opt(&mut device.serial, "DEVICE_SERIAL")?;
+        env_opt(&mut device.openbci.port, "OPENBCI_PORT")?;
+        env(&mut device.openbci.gain, "OPENBCI_GAIN")?;
+        env_list(&mut device.openbci.channels, "OPENBCI_CHANNELS");
+        env(&mut device.brainflow.board, "BRAINFLOW_BOARD")?;
+        env(&mut device.brainflow.serial_port, "BRAINFLOW_SERIAL_PORT")?;
+        env(&mut device.brainflow.mac_address, "BRAINFLOW_MAC_ADDRESS")?;
+        env_list(&mut device.brainflow.channels, "BRAINFLOW_CHANNELS");
+        env(&mut device.brainflow.library, "BRAINFLOW_LIBRARY")?;
+        env_list(&mut device.simulator.channels, "SIMULATOR_CHANNELS");
+        env(&mut device.simulator.sample_rate, "SIMULATOR_RATE")?;
+        config.check()?;
+        Ok(config)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let positive = [
+            (
+                "database.max_connections",
+                self.database.max_connections as i64,
+            ),
+            (
+                "database.acquire_timeout_ms",
+                self.database.acquire_timeout_ms as i64,
+            ),
+            (
+                "database.connect_timeout_ms",
+                self.database.connect_timeout_ms as i64,
+            ),
+            ("ingest.flush_rows", self.ingest.flush_rows as i64),
+            ("ingest.flush_ms", self.ingest.flush_ms as i64),
+            (
+                "ingest.sequence_window_secs",
+                self.ingest.sequence_window_secs as i64,
+            ),
+            (
+                "streaming.poll_interval_ms",
+                self.streaming.poll_interval_ms as i64,
+            ),
+            (
+                "streaming.quality_interval_ms",
+                self.streaming.quality_interval_ms as i64,
+            ),
+            (
+                "limits.max_query_channels",
+                self.limits.max_query_channels as i64,
+            ),
+            ("limits.max_window_rows", self.limits.max_window_rows),
+            ("auth.jwks_refresh_secs", self.auth.jwks_refresh_secs as i64),
+            ("jobs.tiers_secs", self.jobs.tiers_secs as i64),
+            ("jobs.retention_secs", self.jobs.retention_secs as i64),
+            ("jobs.exports_secs", self.jobs.exports_secs as i64),
+            ("server.export_workers", self.server.export_workers as i64),
+        ];
+        if let Some((name, _)) = positive.iter().find(|(_, value)| *value <= 0) {
+            return Err(format!("{} must be positive", name));
+        }
+        let rate_limit = &self.rate_limit;
+        let rates = [
+            (
+                "rate_limit.requests_per_second",
+                rate_limit.requests_per_second,
+                rate_limit.burst,
+            ),
+            (
+                "rate_limit.api_key_requests_per_second",
+                rate_limit.api_key_requests_per_second,
+                rate_limit.api_key_burst,
+            ),
+        ];
+        for (name, rate, burst) in rates {
+            if !rate.is_finite() || rate < 0.0 {
+                return Err(format!("{} must not be negative", name));
+            }
+            if rate > 0.0 && burst == 0 {
+                return Err(format!("the burst of {} must be at least 1", name));
+            }
+        }
+        if self.database.min_connections > self.database.max_connections {
+            return Err(
+                "database.min_connections must not exceed database.max_connections".to_string(),
+            );
+        }
+        if !(10..=1000).contains(&self.storage.block_ms) {
+            return Err("storage.block_ms must be 10 to 1000".to_string());
+        }
+        if self.limits.max_points < 2 {
+            return Err("limits.max_points must be at least 2".to_string());
+        }
+        if self.mqtt.host.as_deref() == Some("") {
+            return Err("mqtt.host must not be empty".to_string());
+        }
+        if !(self.udp.sample_rate.is_finite() && self.udp.sample_rate > 0.0) {
+            return Err("udp.sample_rate must be positive".to_string());
+        }
+        self.device.check()?;
+        self.cors.check()
+    }
+}
+
+/// Loads the configuration from `file`, else as described above; must run
+/// before [`get`] is first used.
+pub fn init(file: Option<&Path>) -> Result<&'static Config, String> {
+    let config = Config::load(file)?;
+    CONFIG
+        .set(config)
+        .map_err(|_| "configuration used before it was loaded".to_string())?;
+    Ok(get())
+}
+
+/// The loaded configuration, or the defaults if [`init`] was not called.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let path = std::env::temp_dir().join(format!("eeg-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, text).unwrap();
+        let config = read_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        config.and_then(|config| config.check().map(|()| config))
+    }
+
+    #[test]
+    fn defaults_pass_the_checks() {
+        Config::default().check().unwrap();
+    }
+
+    #[test]
+    fn ingest_sources_come_from_the_file() {
+        let config = parse(
+            r#"
+            [mqtt]
+            host = "broker"
+            binary_channels = ["A3", "A4"]
+
+            [udp]
+            bind = "127.0.0.1:9000"
+
+            [device.openbci]
+            port = "/dev/ttyUSB0"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mqtt.host.as_deref(), Some("broker"));
+        assert_eq!(config.mqtt.port, 1883);
+        assert_eq!(
+            config.udp.bind,
+            Some(SocketAddr::from(([127, 0, 0, 1], 9000)))
+        );
+        assert!(!config.lsl.enabled);
+        // A port alone selects the Cyton driver.
+        assert_eq!(config.device.selected(false), Some("openbci"));
+        assert_eq!(config.device.selected(true), Some("simulator"));
+    }
+
+    #[test]
+    fn invalid_ingest_sources_are_rejected() {
+        for (text, message) in [
+            ("[device]\nsource = \"openbci\"", "device.openbci.port"),
+            ("[device]\nsource = \"eeg\"", "unknown device.source"),
+            (
+                "[device.brainflow]\nboard = \"nope\"",
+                "device.brainflow.board",
+            ),
+            ("[device.simulator]\nchannels = []", "names no channel"),
+            ("[device.simulator]\nsample_rate = 20000", "sample_rate"),
+            ("[udp]\nsample_rate = 0", "udp.sample_rate"),
+            ("[mqtt]\nport = \"x\"", "invalid configuration"),
+            ("[lsl]\nenable = true", "unknown field"),
+        ] {
+            let error = parse(text).unwrap_err();
+            assert!(error.contains(message), "{}: {}", text, error);
+        }
+    }
+}
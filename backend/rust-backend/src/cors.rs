@@ -0,0 +1,52 @@
+//! Cross-origin requests from browsers, for a frontend served from another
+//! origin than the API.
+//!
+//! Off unless `cors.allowed_origins` is set ([`CorsConfig`]). The layer wraps
+//! every route, so preflight requests are answered before authentication and
+//! rate limiting, and refusals (`401`, `403`, `429`) carry the CORS headers
+//! the browser needs to show them to the page.
+
+use crate::config::CorsConfig;
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Response headers scripts may read besides the CORS-safelisted ones.
+const EXPOSED_HEADERS: [HeaderName; 2] = [header::RETRY_AFTER, header::WWW_AUTHENTICATE];
+
+/// The CORS layer, if CORS is on. The configuration was checked when it was
+/// loaded, so invalid entries cannot occur here and are skipped.
+pub fn layer(config: &CorsConfig) -> Option<CorsLayer> {
+    if !config.enabled() {
+        return None;
+    }
+    let origins = if config.allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+    let methods: Vec<Method> = config
+        .allowed_methods
+        .iter()
+        .filter_map(|method| method.parse().ok())
+        .collect();
+    let headers: Vec<HeaderName> = config
+        .allowed_headers
+        .iter()
+        .filter_map(|name| name.parse().ok())
+        .collect();
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(config.allow_credentials)
+            .expose_headers(EXPOSED_HEADERS)
+            .max_age(Duration::from_secs(config.max_age_secs)),
+    )
+}
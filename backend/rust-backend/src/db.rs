@@ -0,0 +1,160 @@
+//! The Postgres connection pools, built from [`DatabaseConfig`]: the
+//! primary, and optionally a read replica.
+//!
+//! Startup opens one connection before building the pool, so an unreachable
+//! database, a wrong password or a missing database stops the backend at
+//! once with the reason, within `database.connect_timeout_ms`, instead of
+//! the pool retrying until its acquire timeout and reporting only that it
+//! timed out.
+//!
+//! With `database.read_url` set, the read-only handlers (`/samples`, `/live`
+//! and its streams, analysis) read from the replica through
+//! `AppState::reader`, while writes and everything else stay on the
+//! primary. The replica is checked every few seconds, and reads fall back to
+//! the primary while it does not answer. Reads from the replica may lag
+//! behind the primary by its replication delay.
+
+use crate::config::DatabaseConfig;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{ConnectOptions, Connection, PgPool};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// Where `url` points, without credentials or parameters, for messages.
+fn describe(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let rest = rest.rsplit_once('@').map_or(rest, |(_, host)| host);
+    rest.split('?').next().unwrap_or(rest)
+}
+
+/// `application_name` of this backend's connections: `rust_backend` and an
+/// id of this process, by which [`notify`](crate::notify) tells the samples
+/// it stored from those stored by others.
+pub fn application_name() -> &'static str {
+    static NAME: OnceLock<String> = OnceLock::new();
+    NAME.get_or_init(|| {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        format!("rust_backend {}", &id[..12])
+    })
+}
+
+/// `0` as never, for the timeouts that may be turned off.
+fn optional_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Connects to the database and builds the pool.
+pub async fn connect(config: &DatabaseConfig) -> Result<PgPool, String> {
+    let failed = |e: &dyn std::fmt::Display| {
+        format!(
+            "cannot connect to the database at {}: {}",
+            describe(&config.url),
+            e
+        )
+    };
+    let options = PgConnectOptions::from_str(&config.url)
+        .map_err(|e| format!("invalid database.url: {}", e))?
+        .statement_cache_capacity(config.statement_cache_capacity)
+        .application_name(application_name());
+
+    let timeout = Duration::from_millis(config.connect_timeout_ms);
+    let probe = tokio::time::timeout(timeout, options.connect())
+        .await
+        .map_err(|_| failed(&format!("no answer within {:?}", timeout)))?
+        .map_err(|e| failed(&e))?;
+    probe.close().await.ok();
+
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(Duration::from_millis(config.acquire_timeout_ms))
+        .idle_timeout(optional_secs(config.idle_timeout_secs))
+        .max_lifetime(optional_secs(config.max_lifetime_secs))
+        .connect_with(options)
+        .await
+        .map_err(|e| failed(&e))
+}
+
+/// How often the replica is checked.
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest wait for the replica before a check counts as failed.
+const REPLICA_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A read replica and whether it answered its latest check.
+#[derive(Clone)]
+pub struct Replica {
+    pub pool: PgPool,
+    up: Arc<AtomicBool>,
+}
+
+impl Replica {
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+}
+
+/// `replica` while it answered its latest check, else `primary`.
+pub fn reader<'a>(primary: &'a PgPool, replica: Option<&'a Replica>) -> &'a PgPool {
+    match replica {
+        Some(replica) if replica.is_up() => &replica.pool,
+        _ => primary,
+    }
+}
+
+/// The pool of `database.read_url`, if set. It connects lazily, so a replica
+/// that is down at startup does not stop the backend; it counts as down, and
+/// reads go to the primary, until a check reaches it.
+pub fn replica(config: &DatabaseConfig) -> Result<Option<Replica>, String> {
+    let Some(url) = &config.read_url else {
+        return Ok(None);
+    };
+    let options = PgConnectOptions::from_str(url)
+        .map_err(|e| format!("invalid database.read_url: {}", e))?
+        .statement_cache_capacity(config.statement_cache_capacity)
+        .application_name(application_name());
+    let pool = PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(Duration::from_millis(config.acquire_timeout_ms))
+        .idle_timeout(optional_secs(config.idle_timeout_secs))
+        .max_lifetime(optional_secs(config.max_lifetime_secs))
+        .connect_lazy_with(options);
+    let replica = Replica {
+        pool,
+        up: Arc::new(AtomicBool::new(false)),
+    };
+    tokio::spawn(monitor(replica.clone(), describe(url).to_string()));
+    Ok(Some(replica))
+}
+
+/// Checks the replica every [`REPLICA_CHECK_INTERVAL`] and logs when it goes
+/// down or comes back.
+async fn monitor(replica: Replica, name: String) {
+    let mut ticker = tokio::time::interval(REPLICA_CHECK_INTERVAL);
+    let mut first = true;
+    loop {
+        ticker.tick().await;
+        let check = sqlx::query_scalar::<_, i32>("SELECT 1").fetch_one(&replica.pool);
+        let result = match tokio::time::timeout(REPLICA_CHECK_TIMEOUT, check).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {:?}", REPLICA_CHECK_TIMEOUT)),
+        };
+        let was_up = replica.up.swap(result.is_ok(), Ordering::Relaxed);
+        match result {
+            Ok(()) if !was_up => tracing::info!("read replica {} is up; serving reads", name),
+            Err(e) if was_up || first => {
+                tracing::warn!(
+                    "read replica {} is down, reading from the primary: {}",
+                    name,
+                    e
+                )
+            }
+            _ => {}
+        }
+        first = false;
+    }
+}
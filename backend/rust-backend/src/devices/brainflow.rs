@@ -0,0 +1,327 @@
+//! BrainFlow-backed [`DeviceSource`] for Muse, Ganglion, Cyton and other boards.
+//!
+//! BrainFlow's `BoardController` library is loaded at runtime, so the backend
+//! builds without it; it only needs to be installed where this source is used.
+
+use super::{DeviceSource, StreamProfile};
+use crate::config;
+use crate::ingest::{self, NewSample};
+use libloading::{Library, Symbol};
+use std::ffi::{c_char, c_double, c_int, CString};
+use std::time::Duration;
+
+const STATUS_OK: c_int = 0;
+const DEFAULT_PRESET: c_int = 0;
+const RING_BUFFER_SIZE: c_int = 45_000;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct BrainFlowConfig {
+    pub board_id: i32,
+    /// Serial port for dongle-based boards (Cyton, Ganglion).
+    pub serial_port: String,
+    /// MAC address for BLE boards; empty lets BrainFlow discover the device.
+    pub mac_address: String,
+    /// Optional channel names; defaults to the board's EEG channel names.
+    pub channels: Option<Vec<String>>,
+    pub library: String,
+}
+
+/// Maps the board names accepted in `BRAINFLOW_BOARD` to BrainFlow board ids.
+pub fn board_id(name: &str) -> Option<i32> {
+    match name {
+        "synthetic" => Some(-1),
+        "cyton" => Some(0),
+        "ganglion" => Some(1),
+        "cyton_daisy" => Some(2),
+        "muse_2" => Some(38),
+        "muse_s" => Some(39),
+        "muse_2016" => Some(41),
+        other => other.parse().ok(),
+    }
+}
+
+impl BrainFlowConfig {
+    /// The source of the `[device.brainflow]` settings.
+    pub fn from_config(config: &config::BrainFlowConfig) -> Result<Self, String> {
+        let board_id = board_id(&config.board)
+            .ok_or_else(|| format!("unknown device.brainflow.board {:?}", config.board))?;
+        Ok(Self {
+            board_id,
+            serial_port: config.serial_port.clone(),
+            mac_address: config.mac_address.clone(),
+            channels: Some(config.channels.clone()).filter(|c| !c.is_empty()),
+            library: config.library.clone(),
+        })
+    }
+
+    fn input_params(&self) -> String {
+        serde_json::json!({
+            "serial_port": self.serial_port,
+            "mac_address": self.mac_address,
+            "ip_address": "",
+            "ip_port": 0,
+            "ip_protocol": 0,
+            "other_info": "",
+            "timeout": 0,
+            "serial_number": "",
+            "file": "",
+            "master_board": -100,
+        })
+        .to_string()
+    }
+}
+
+/// The subset of the BoardController C API used by the source.
+struct BoardController {
+    _lib: Library,
+    prepare_session: unsafe extern "C" fn(c_int, *const c_char) -> c_int,
+    start_stream: unsafe extern "C" fn(c_int, *const c_char, c_int, *const c_char) -> c_int,
+    stop_stream: unsafe extern "C" fn(c_int, *const c_char) -> c_int,
+    release_session: unsafe extern "C" fn(c_int, *const c_char) -> c_int,
+    get_board_data_count: unsafe extern "C" fn(c_int, *mut c_int, c_int, *const c_char) -> c_int,
+    get_board_data:
+        unsafe extern "C" fn(c_int, c_int, *mut c_double, c_int, *const c_char) -> c_int,
+    get_num_rows: unsafe extern "C" fn(c_int, c_int, *mut c_int) -> c_int,
+    get_eeg_channels: unsafe extern "C" fn(c_int, c_int, *mut c_int, *mut c_int) -> c_int,
+    get_timestamp_channel: unsafe extern "C" fn(c_int, c_int, *mut c_int) -> c_int,
+    get_eeg_names: unsafe extern "C" fn(c_int, c_int, *mut c_char, *mut c_int) -> c_int,
+}
+
+impl BoardController {
+    fn load(path: &str) -> Result<Self, String> {
+        // SAFETY: symbol signatures mirror board_controller.h and
+        // board_info_getter.h from BrainFlow 5.
+        unsafe {
+            let lib = Library::new(path).map_err(|e| e.to_string())?;
+            macro_rules! sym {
+                ($name:literal) => {{
+                    let s: Symbol<_> = lib.get($name).map_err(|e| e.to_string())?;
+                    *s
+                }};
+            }
+            Ok(Self {
+                prepare_session: sym!(b"prepare_session\0"),
+                start_stream: sym!(b"start_stream\0"),
+                stop_stream: sym!(b"stop_stream\0"),
+                release_session: sym!(b"release_session\0"),
+                get_board_data_count: sym!(b"get_board_data_count\0"),
+                get_board_data: sym!(b"get_board_data\0"),
+                get_num_rows: sym!(b"get_num_rows\0"),
+                get_eeg_channels: sym!(b"get_eeg_channels\0"),
+                get_timestamp_channel: sym!(b"get_timestamp_channel\0"),
+                get_eeg_names: sym!(b"get_eeg_names\0"),
+                _lib: lib,
+            })
+        }
+    }
+}
+
+fn check(code: c_int, what: &str) -> Result<(), String> {
+    if code == STATUS_OK {
+        Ok(())
+    } else {
+        Err(format!("{} failed with BrainFlow status {}", what, code))
+    }
+}
+
+/// Board layout resolved once the session is prepared.
+struct Layout {
+    num_rows: usize,
+    timestamp_row: usize,
+    eeg_rows: Vec<usize>,
+    names: Vec<String>,
+}
+
+pub struct BrainFlowSource {
+    config: BrainFlowConfig,
+    params: CString,
+    controller: Option<BoardController>,
+    layout: Option<Layout>,
+    prepared: bool,
+}
+
+impl BrainFlowSource {
+    pub fn new(config: BrainFlowConfig) -> Self {
+        let params = CString::new(config.input_params()).unwrap_or_default();
+        Self {
+            config,
+            params,
+            controller: None,
+            layout: None,
+            prepared: false,
+        }
+    }
+
+    fn layout(&self, bc: &BoardController) -> Result<Layout, String> {
+        let id = self.config.board_id;
+        let mut num_rows: c_int = 0;
+        let mut timestamp_row: c_int = 0;
+        let mut eeg = vec![0 as c_int; 512];
+        let mut eeg_len: c_int = 0;
+        // SAFETY: output buffers are large enough for any BrainFlow board.
+        unsafe {
+            check(
+                (bc.get_num_rows)(id, DEFAULT_PRESET, &mut num_rows),
+                "get_num_rows",
+            )?;
+            check(
+                (bc.get_timestamp_channel)(id, DEFAULT_PRESET, &mut timestamp_row),
+                "get_timestamp_channel",
+            )?;
+            check(
+                (bc.get_eeg_channels)(id, DEFAULT_PRESET, eeg.as_mut_ptr(), &mut eeg_len),
+                "get_eeg_channels",
+            )?;
+        }
+        eeg.truncate(eeg_len.max(0) as usize);
+        let eeg_rows: Vec<usize> = eeg.into_iter().map(|r| r as usize).collect();
+
+        let names = match &self.config.channels {
+            Some(names) if names.len() == eeg_rows.len() => names.clone(),
+            Some(names) => {
+                return Err(format!(
+                    "BRAINFLOW_CHANNELS names {} channels but the board has {}",
+                    names.len(),
+                    eeg_rows.len()
+                ))
+            }
+            None => {
+                let mut buf = vec![0 as c_char; 4096];
+                let mut len: c_int = 0;
+                // SAFETY: `buf` holds BrainFlow's comma-separated name list.
+                let code =
+                    unsafe { (bc.get_eeg_names)(id, DEFAULT_PRESET, buf.as_mut_ptr(), &mut len) };
+                let bytes: Vec<u8> = buf[..len.max(0) as usize]
+                    .iter()
+                    .map(|&c| c as u8)
+                    .collect();
+                let listed: Vec<String> = String::from_utf8_lossy(&bytes)
+                    .split(',')
+                    .map(|n| n.trim().to_string())
+                    .filter(|n| !n.is_empty())
+                    .collect();
+                if code == STATUS_OK && listed.len() == eeg_rows.len() {
+                    listed
+                } else {
+                    eeg_rows.iter().map(|r| format!("CH{}", r)).collect()
+                }
+            }
+        };
+
+        Ok(Layout {
+            num_rows: num_rows as usize,
+            timestamp_row: timestamp_row as usize,
+            eeg_rows,
+            names,
+        })
+    }
+}
+
+impl DeviceSource for BrainFlowSource {
+    fn name(&self) -> String {
+        format!("BrainFlow board {}", self.config.board_id)
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        if self.controller.is_none() {
+            self.controller = Some(BoardController::load(&self.config.library)?);
+        }
+        let bc = self.controller.as_ref().unwrap();
+        let id = self.config.board_id;
+        let empty = CString::default();
+        let layout = self.layout(bc)?;
+        // SAFETY: params are NUL-terminated JSON strings owned by `self`.
+        unsafe {
+            check(
+                (bc.prepare_session)(id, self.params.as_ptr()),
+                "prepare_session",
+            )?;
+            self.prepared = true;
+            check(
+                (bc.start_stream)(RING_BUFFER_SIZE, empty.as_ptr(), id, self.params.as_ptr()),
+                "start_stream",
+            )?;
+        }
+        self.layout = Some(layout);
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Vec<NewSample>, String> {
+        std::thread::sleep(POLL_INTERVAL);
+        let bc = self.controller.as_ref().ok_or("device not started")?;
+        let layout = self.layout.as_ref().ok_or("device not started")?;
+        let id = self.config.board_id;
+
+        let mut count: c_int = 0;
+        // SAFETY: the session is prepared and streaming; the data buffer holds
+        // `num_rows * count` doubles laid out row by row.
+        unsafe {
+            check(
+                (bc.get_board_data_count)(DEFAULT_PRESET, &mut count, id, self.params.as_ptr()),
+                "get_board_data_count",
+            )?;
+        }
+        let count = count.max(0) as usize;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut data = vec![0f64; layout.num_rows * count];
+        unsafe {
+            check(
+                (bc.get_board_data)(
+                    count as c_int,
+                    DEFAULT_PRESET,
+                    data.as_mut_ptr(),
+                    id,
+                    self.params.as_ptr(),
+                ),
+                "get_board_data",
+            )?;
+        }
+
+        let row = |r: usize| &data[r * count..(r + 1) * count];
+        let stamps = row(layout.timestamp_row);
+        let mut samples = Vec::with_capacity(count * layout.eeg_rows.len());
+        for (i, &stamp) in stamps.iter().enumerate() {
+            let Some(ts) = ingest::ts_from_micros((stamp * 1_000_000.0).round() as i64) else {
+                continue;
+            };
+            for (&r, name) in layout.eeg_rows.iter().zip(&layout.names) {
+                samples.push(NewSample {
+                    channel: name.clone(),
+                    ts,
+                    value: row(r)[i],
+                    session_id: None,
+                });
+            }
+        }
+        Ok(samples)
+    }
+
+    fn profile(&self) -> StreamProfile {
+        StreamProfile {
+            channels: self
+                .layout
+                .as_ref()
+                .map(|l| l.names.clone())
+                .unwrap_or_default(),
+            ..StreamProfile::default()
+        }
+    }
+
+    fn stop(&mut self) {
+        let Some(bc) = self.controller.as_ref() else {
+            return;
+        };
+        if self.prepared {
+            // SAFETY: stopping an idle session only returns an error code.
+            unsafe {
+                (bc.stop_stream)(self.config.board_id, self.params.as_ptr());
+                (bc.release_session)(self.config.board_id, self.params.as_ptr());
+            }
+            self.prepared = false;
+        }
+        self.layout = None;
+    }
+}
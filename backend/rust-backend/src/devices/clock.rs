@@ -0,0 +1,245 @@
+//! Drift of device clocks against the server (`clock_sync` table).
+//!
+//! Samples carry the timestamps of the device's clock, which drifts against
+//! server time over long recordings. Ingest requests with `device_time`,
+//! the device's clock when it sent the batch, record it next to the time
+//! the server received the batch. Their difference is fitted, by least
+//! squares, as a line over the device's clock: its slope is the drift, and
+//! it includes the mean network delay. A device time `t` reads as
+//! `t + offset + drift * (t - last)`, `offset` being the difference at the
+//! last pair. Each batch refines the estimate of the reads that follow.
+//!
+//! Samples are stored with the device's timestamps. `/samples` with
+//! `clock_corrected=true` adds the corrected `ts_corrected` to each sample,
+//! from the pairs the session's device recorded during the session.
+
+use crate::error::ApiError;
+use crate::validation::Parsed;
+use crate::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use utoipa::IntoParams;
+
+/// Pairs `GET /devices/{serial}/clock` fits without `session_id`.
+const RECENT_SECS: i64 = 3600;
+
+/// The fitted offset and drift of one device's clock.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Estimate {
+    /// Pairs of device and server time fitted.
+    pub points: i64,
+    /// Device times of the first and the last pair.
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    /// Server minus device time at `last`, in milliseconds.
+    pub offset_ms: f64,
+    /// How much faster server time runs than the device's, in parts per
+    /// million; 0 from a single pair.
+    pub drift_ppm: f64,
+}
+
+impl Estimate {
+    /// The server time of device time `ts`.
+    pub fn correct(&self, ts: DateTime<Utc>) -> DateTime<Utc> {
+        let since_last = (ts - self.last).num_microseconds().unwrap_or(0) as f64 / 1e6;
+        let offset_us = self.offset_ms * 1e3 + self.drift_ppm * since_last;
+        ts + Duration::microseconds(offset_us.round() as i64)
+    }
+}
+
+/// Whether reads add corrected timestamps.
+#[derive(Debug, Clone, Copy, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ClockQuery {
+    /// Add `ts_corrected`, the sample's time on the server's clock, to each
+    /// sample; needs `session_id`.
+    #[serde(default)]
+    pub clock_corrected: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EstimateQuery {
+    /// Fit the pairs recorded during this session rather than the last hour.
+    session_id: Option<i32>,
+}
+
+/// Records that device `serial` sent a batch at `device_time` on its clock,
+/// received at `received_at`.
+pub async fn record(
+    pool: &PgPool,
+    serial: &str,
+    device_time: DateTime<Utc>,
+    received_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO clock_sync (device_serial, device_time, received_at) VALUES ($1, $2, $3)",
+    )
+    .bind(serial)
+    .bind(device_time)
+    .bind(received_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Aggregates of a device's pairs, offsets in seconds.
+#[derive(sqlx::FromRow)]
+struct Fit {
+    points: i64,
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+    slope: Option<f64>,
+    intercept: Option<f64>,
+    mean: Option<f64>,
+}
+
+/// The fit of the pairs of device `serial` received from `from` until `to`,
+/// or `None` without any.
+pub async fn estimate(
+    pool: &PgPool,
+    serial: &str,
+    from: DateTime<Utc>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Option<Estimate>, sqlx::Error> {
+    // Device times are taken relative to the last pair, so the intercept is
+    // the offset there.
+    let fit: Fit = sqlx::query_as(
+        "WITH pairs AS ( \
+           SELECT device_time, \
+             EXTRACT(EPOCH FROM received_at - device_time)::float8 AS diff \
+           FROM clock_sync WHERE device_serial = $1 AND received_at >= $2 \
+           AND ($3::timestamptz IS NULL OR received_at <= $3)), \
+         span AS (SELECT max(device_time) AS last FROM pairs) \
+         SELECT count(*) AS points, min(device_time) AS first, max(device_time) AS last, \
+           regr_slope(p.diff, EXTRACT(EPOCH FROM device_time - s.last)::float8) AS slope, \
+           regr_intercept(p.diff, EXTRACT(EPOCH FROM device_time - s.last)::float8) AS intercept, \
+           avg(p.diff) AS mean \
+         FROM pairs p, span s",
+    )
+    .bind(serial)
+    .bind(from)
+    .bind(to)
+    .fetch_one(pool)
+    .await?;
+    let (Some(first), Some(last), Some(mean)) = (fit.first, fit.last, fit.mean) else {
+        return Ok(None);
+    };
+    // No slope from one pair, or from pairs all sent at the same time.
+    let (drift, offset) = match (fit.slope, fit.intercept) {
+        (Some(slope), Some(intercept)) => (slope, intercept),
+        _ => (0.0, mean),
+    };
+    Ok(Some(Estimate {
+        points: fit.points,
+        first,
+        last,
+        offset_ms: offset * 1e3,
+        drift_ppm: drift * 1e6,
+    }))
+}
+
+/// Device, start and end of session `session_id`, if it exists.
+async fn session(
+    pool: &PgPool,
+    session_id: i32,
+) -> Result<Option<(Option<String>, DateTime<Utc>, Option<DateTime<Utc>>)>, sqlx::Error> {
+    sqlx::query_as("SELECT device, started_at, ended_at FROM sessions WHERE id = $1")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// The fit for the device of session `session_id` over the pairs it
+/// recorded during the session, or `None` for a session without a device
+/// or pairs.
+pub async fn for_session(pool: &PgPool, session_id: i32) -> Result<Option<Estimate>, sqlx::Error> {
+    let Some((Some(device), started_at, ended_at)) = session(pool, session_id).await? else {
+        return Ok(None);
+    };
+    estimate(pool, &device, started_at, ended_at).await
+}
+
+/// Adds `ts_corrected` next to the `ts` of every sample in `body`, the
+/// `samples` of a `/samples` response or of each of its `channels`. Without
+/// an estimate, `ts_corrected` is `ts`.
+pub fn annotate(body: &mut Value, estimate: Option<&Estimate>) {
+    let lists: Vec<&mut Value> = if body.get("channels").is_some_and(Value::is_array) {
+        body["channels"]
+            .as_array_mut()
+            .into_iter()
+            .flatten()
+            .filter_map(|c| c.get_mut("samples"))
+            .collect()
+    } else {
+        body.get_mut("samples").into_iter().collect()
+    };
+    for sample in lists
+        .into_iter()
+        .filter_map(|list| list.as_array_mut())
+        .flatten()
+    {
+        let Some(ts) = sample["ts"]
+            .as_str()
+            .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+        else {
+            continue;
+        };
+        let ts = ts.to_utc();
+        let corrected = estimate.map_or(ts, |estimate| estimate.correct(ts));
+        sample["ts_corrected"] = json!(corrected.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+    }
+}
+
+/// The estimated offset and drift of a device's clock.
+#[utoipa::path(
+    get,
+    path = "/devices/{serial}/clock",
+    tag = "devices",
+    params(("serial" = String, Path), EstimateQuery),
+    responses(
+        (status = 200, description = "`{ device, session_id, clock }`, `clock` being null without pairs", body = serde_json::Value),
+        (status = 400, description = "A session of another device"),
+        (status = 404, description = "Unknown device or session")
+    )
+)]
+pub async fn get_clock(
+    State(state): State<AppState>,
+    Parsed(Path(serial)): Parsed<Path<String>>,
+    Parsed(Query(params)): Parsed<Query<EstimateQuery>>,
+) -> Result<Json<Value>, ApiError> {
+    if super::registry::get(&serial).is_none() {
+        return Err(ApiError::not_found(format!("unknown device {:?}", serial)));
+    }
+    let (from, to) = match params.session_id {
+        Some(session_id) => {
+            let Some((device, started_at, ended_at)) = session(state.reader(), session_id).await?
+            else {
+                return Err(ApiError::not_found(format!(
+                    "unknown session {}",
+                    session_id
+                )));
+            };
+            if device.as_deref() != Some(serial.as_str()) {
+                return Err(ApiError::bad_request(format!(
+                    "session {} is not recorded by {:?}",
+                    session_id, serial
+                )));
+            }
+            (started_at, ended_at)
+        }
+        None => (Utc::now() - Duration::seconds(RECENT_SECS), None),
+    };
+    let clock = estimate(state.reader(), &serial, from, to).await?;
+    Ok(Json(json!({
+        "device": serial,
+        "session_id": params.session_id,
+        "clock": clock,
+    })))
+}
@@ -0,0 +1,338 @@
+//! API keys of acquisition devices (`device_api_keys` table).
+//!
+//! Devices cannot go through an OAuth flow, so each registered device can be
+//! given long-lived keys instead. A key is sent in the `X-API-Key` header (or
+//! `x-api-key` gRPC metadata) and is only accepted on the ingest routes. It
+//! belongs to one device, whose profile every sample is checked against, and
+//! may be scoped further to some channels and sessions.
+//!
+//! Keys are shown once when created; only their SHA-256 hash is stored. Like
+//! the device registry, active keys are cached in memory and reloaded
+//! periodically, so a key revoked through another replica stops working
+//! within [`RELOAD_INTERVAL`]. `last_used_at` is written on reload.
+
+use crate::error::ApiError;
+use crate::ingest::NewSample;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Characters of a key kept in `prefix`, including the `eeg_` tag.
+const PREFIX_LEN: usize = 12;
+
+/// Active keys by hash.
+static KEYS: RwLock<BTreeMap<String, ApiKey>> = RwLock::new(BTreeMap::new());
+
+/// Ids of keys used since `last_used_at` was last written.
+static USED: Mutex<BTreeSet<i32>> = Mutex::new(BTreeSet::new());
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct ApiKey {
+    pub id: i32,
+    pub device_serial: String,
+    pub name: String,
+    pub prefix: String,
+    /// Channels the key may write; any channel of the device when `None`.
+    pub channels: Option<Vec<String>>,
+    /// Sessions the key may write to; any, or none, when `None`.
+    pub session_ids: Option<Vec<i32>>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A cached row: the key and its hash, which is never served.
+#[derive(sqlx::FromRow)]
+struct Stored {
+    key_hash: String,
+    #[sqlx(flatten)]
+    key: ApiKey,
+}
+
+/// Response of `POST /admin/api-keys`, the only one that holds the key.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    #[serde(rename = "key")]
+    secret: String,
+}
+
+/// Body of `POST /admin/api-keys`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct KeyInput {
+    device: String,
+    name: String,
+    channels: Option<Vec<String>>,
+    session_ids: Option<Vec<i32>>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct KeysQuery {
+    device: Option<String>,
+    /// Include revoked keys.
+    #[serde(default)]
+    revoked: bool,
+}
+
+impl Validate for KeyInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.name.trim().is_empty(), "name", "must not be empty");
+        if let Some(channels) = &self.channels {
+            errors.check(
+                !channels.is_empty(),
+                "channels",
+                "must not be empty; omit it to allow every channel",
+            );
+            for (i, name) in channels.iter().enumerate() {
+                errors.check(
+                    !name.trim().is_empty(),
+                    &format!("channels[{}]", i),
+                    "must not be empty",
+                );
+            }
+        }
+        if let Some(sessions) = &self.session_ids {
+            errors.check(
+                !sessions.is_empty(),
+                "session_ids",
+                "must not be empty; omit it to allow every session",
+            );
+        }
+    }
+}
+
+impl ApiKey {
+    /// Checks that a request naming `device` (if it does) may write `samples`
+    /// with this key: the device is the key's, and every sample's channel and
+    /// session are within its scope. A key scoped to sessions does not accept
+    /// samples without one.
+    pub fn check(&self, device: Option<&str>, samples: &[NewSample]) -> Result<(), String> {
+        if let Some(device) = device.filter(|d| *d != self.device_serial) {
+            return Err(format!(
+                "API key {} belongs to device {:?}, not {:?}",
+                self.prefix, self.device_serial, device
+            ));
+        }
+        for (i, sample) in samples.iter().enumerate() {
+            if let Some(channels) = &self.channels {
+                if !channels.contains(&sample.channel) {
+                    return Err(format!(
+                        "sample {}: API key {} may not write channel {:?}",
+                        i, self.prefix, sample.channel
+                    ));
+                }
+            }
+            if let Some(sessions) = &self.session_ids {
+                match sample.session_id {
+                    Some(id) if sessions.contains(&id) => {}
+                    Some(id) => {
+                        return Err(format!(
+                            "sample {}: API key {} may not write to session {}",
+                            i, self.prefix, id
+                        ))
+                    }
+                    None => {
+                        return Err(format!(
+                            "sample {}: API key {} requires a session_id",
+                            i, self.prefix
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// The active key `key`, if there is one. Marks it as used.
+pub fn lookup(key: &str) -> Option<ApiKey> {
+    let found = KEYS.read().unwrap().get(&hash(key.trim())).cloned()?;
+    USED.lock().unwrap().insert(found.id);
+    Some(found)
+}
+
+/// Drops the cached keys of a device that was deleted.
+pub fn forget_device(serial: &str) {
+    KEYS.write()
+        .unwrap()
+        .retain(|_, key| key.device_serial != serial);
+}
+
+const COLUMNS: &str = "id, device_serial, name, prefix, channels, session_ids, created_at, \
+                       last_used_at, revoked_at";
+
+/// Writes `last_used_at` of the keys used since the last call.
+async fn record_use(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let used: Vec<i32> = std::mem::take(&mut *USED.lock().unwrap())
+        .into_iter()
+        .collect();
+    if used.is_empty() {
+        return Ok(());
+    }
+    sqlx::query("UPDATE device_api_keys SET last_used_at = now() WHERE id = ANY($1)")
+        .bind(&used)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Replaces the cached keys with the active keys in `device_api_keys`.
+pub async fn load(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let rows: Vec<Stored> = sqlx::query_as(&format!(
+        "SELECT {}, key_hash FROM device_api_keys WHERE revoked_at IS NULL",
+        COLUMNS
+    ))
+    .fetch_all(pool)
+    .await?;
+    let count = rows.len();
+    *KEYS.write().unwrap() = rows
+        .into_iter()
+        .map(|row| (row.key_hash, row.key))
+        .collect();
+    Ok(count)
+}
+
+/// Starts the task that periodically records key use and reloads the keys.
+pub fn spawn_reload(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = record_use(&pool).await {
+                tracing::error!("cannot record API key use: {}", e);
+            }
+            if let Err(e) = load(&pool).await {
+                tracing::error!("API key reload failed: {}", e);
+            }
+        }
+    });
+}
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    // 23503: foreign_key_violation on device_serial.
+    match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            ApiError::bad_request("unknown device")
+        }
+        _ => e.into(),
+    }
+}
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::not_found(format!("unknown API key {}", id))
+}
+
+/// Keys and their scopes, never the keys themselves.
+#[utoipa::path(
+    get,
+    path = "/admin/api-keys",
+    tag = "admin",
+    params(KeysQuery),
+    responses((status = 200, description = "Keys and their scopes, never the keys themselves", body = Vec<ApiKey>))
+)]
+pub async fn list_keys(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<KeysQuery>>,
+) -> Result<Json<Vec<ApiKey>>, ApiError> {
+    let keys = sqlx::query_as(&format!(
+        "SELECT {} FROM device_api_keys \
+         WHERE ($1::text IS NULL OR device_serial = $1) AND ($2 OR revoked_at IS NULL) \
+         ORDER BY id",
+        COLUMNS
+    ))
+    .bind(&params.device)
+    .bind(params.revoked)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(keys))
+}
+
+/// Creates a key; the response is the only place the key appears.
+#[utoipa::path(
+    post,
+    path = "/admin/api-keys",
+    tag = "admin",
+    request_body = KeyInput,
+    responses(
+        (status = 201, description = "The key, shown only in this response", body = CreatedKey),
+        (status = 400, description = "Unknown device"),
+        (status = 422, description = "Invalid name or scope, named in `details.fields`")
+    )
+)]
+pub async fn create_key(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<KeyInput>>,
+) -> Result<(StatusCode, Json<CreatedKey>), ApiError> {
+    let secret = format!(
+        "eeg_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let key: ApiKey = sqlx::query_as(&format!(
+        "INSERT INTO device_api_keys (device_serial, name, prefix, key_hash, channels, session_ids) \
+         VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&input.device)
+    .bind(input.name.trim())
+    .bind(&secret[..PREFIX_LEN])
+    .bind(hash(&secret))
+    .bind(&input.channels)
+    .bind(&input.session_ids)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    KEYS.write().unwrap().insert(hash(&secret), key.clone());
+    Ok((StatusCode::CREATED, Json(CreatedKey { key, secret })))
+}
+
+/// Revokes a key. Requests with it are rejected from then on.
+#[utoipa::path(
+    delete,
+    path = "/admin/api-keys/{id}",
+    tag = "admin",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Revoked"),
+        (status = 404, description = "Unknown key")
+    )
+)]
+pub async fn revoke_key(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<StatusCode, ApiError> {
+    let hash: Option<String> = sqlx::query_scalar(
+        "UPDATE device_api_keys SET revoked_at = COALESCE(revoked_at, now()) \
+         WHERE id = $1 RETURNING key_hash",
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?;
+    let hash = hash.ok_or_else(|| not_found(id))?;
+    KEYS.write().unwrap().remove(&hash);
+    Ok(StatusCode::NO_CONTENT)
+}
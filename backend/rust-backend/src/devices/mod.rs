@@ -0,0 +1,164 @@
+//! Acquisition hardware feeding `eeg_samples` through the shared ingest path.
+//!
+//! Each device is a [`DeviceSource`]; [`spawn`] drives one on a blocking
+//! thread and reopens it after failures. `device.source` (`DEVICE_SOURCE`)
+//! selects the source:
+//!
+//! - `openbci` — native Cyton serial driver, see [`openbci`]
+//! - `brainflow` — any BrainFlow-supported board, see [`brainflow`]
+//! - `simulator` — synthetic EEG, see [`simulator`]; also `serve --simulate`
+//!
+//! For compatibility, setting `device.openbci.port` alone selects `openbci`.
+//! With `device.serial` set, the stream is checked against that device's
+//! registered profile (see [`registry`]) before samples are written.
+
+pub mod brainflow;
+pub mod clock;
+pub mod keys;
+pub mod openbci;
+pub mod registry;
+pub mod simulator;
+
+use crate::ingest::{self, NewSample};
+use crate::{channels, config};
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// What a stream carries, for comparison with a registered device profile.
+#[derive(Debug, Clone, Default)]
+pub struct StreamProfile {
+    pub channels: Vec<String>,
+    pub sample_rate: Option<f64>,
+    pub gain: Option<f64>,
+}
+
+/// A blocking source of samples from one piece of hardware.
+pub trait DeviceSource: Send {
+    /// Human-readable name used in logs, e.g. `OpenBCI Cyton on /dev/ttyUSB0`.
+    fn name(&self) -> String;
+
+    /// Opens the device and starts streaming.
+    fn start(&mut self) -> Result<(), String>;
+
+    /// Blocks until samples are available; may return an empty batch on timeout.
+    fn read(&mut self) -> Result<Vec<NewSample>, String>;
+
+    /// Channels, sample rate and gain of the running stream.
+    fn profile(&self) -> StreamProfile;
+
+    /// Stops streaming and releases the device. Must be safe to call twice.
+    fn stop(&mut self);
+
+    /// Whether the profile's channels are registered when missing, for
+    /// sources whose channels are not chosen by whoever wires up a device.
+    fn registers_channels(&self) -> bool {
+        false
+    }
+}
+
+/// Builds the source selected by `device.source`, if any, or the simulator
+/// when `simulate` is set.
+pub fn from_config(
+    config: &config::DeviceConfig,
+    simulate: bool,
+) -> Result<Option<Box<dyn DeviceSource>>, String> {
+    match config.selected(simulate) {
+        None | Some("") | Some("none") => Ok(None),
+        Some("openbci") => {
+            let cyton = openbci::CytonConfig::from_config(&config.openbci)
+                .ok_or_else(|| "device.source openbci needs device.openbci.port".to_string())?;
+            Ok(Some(Box::new(openbci::CytonSource::new(cyton)?)))
+        }
+        Some("brainflow") => {
+            let board = brainflow::BrainFlowConfig::from_config(&config.brainflow)?;
+            Ok(Some(Box::new(brainflow::BrainFlowSource::new(board))))
+        }
+        Some("simulator") => {
+            let simulator = simulator::SimulatorConfig::from_config(&config.simulator);
+            Ok(Some(Box::new(simulator::SimulatorSource::new(simulator))))
+        }
+        Some(other) => Err(format!("unknown device.source {:?}", other)),
+    }
+}
+
+/// Registers the channels of a source that
+/// [registers its own](DeviceSource::registers_channels), then runs `source`
+/// on its own thread until the ingest writer shuts down.
+///
+/// When `serial` is given, a stream that does not match the registered
+/// profile is stopped and retried after the reconnect delay.
+pub async fn spawn(
+    source: Box<dyn DeviceSource>,
+    serial: Option<String>,
+    pool: PgPool,
+) -> Result<(), String> {
+    if source.registers_channels() {
+        register_channels(&pool, &source.profile())
+            .await
+            .map_err(|e| format!("cannot register the channels of {}: {}", source.name(), e))?;
+    }
+    run(source, serial, ingest::spawn_writer(pool, "device driver"));
+    Ok(())
+}
+
+/// Runs `source` on its own thread, sending its batches to `tx` until the
+/// receiving writer shuts down; `serial` is checked as for [`spawn`].
+pub fn run(
+    mut source: Box<dyn DeviceSource>,
+    serial: Option<String>,
+    tx: mpsc::Sender<Vec<NewSample>>,
+) {
+    std::thread::spawn(move || {
+        let name = source.name();
+        while !tx.is_closed() {
+            let started = source.start().and_then(|()| match &serial {
+                Some(serial) => registry::check_stream(serial, &source.profile()),
+                None => Ok(()),
+            });
+            match started {
+                Ok(()) => {
+                    tracing::info!("{} streaming", name);
+                    loop {
+                        match source.read() {
+                            Ok(samples) if samples.is_empty() => continue,
+                            Ok(samples) => {
+                                if tx.blocking_send(samples).is_err() {
+                                    break;
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!("{}: {}", name, e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => tracing::error!("{}: cannot start: {}", name, e),
+            }
+            source.stop();
+            std::thread::sleep(RECONNECT_DELAY);
+        }
+    });
+}
+
+/// Adds the channels of `profile` that are missing from `channels`, enabled
+/// and at its sample rate, and reloads the registry.
+async fn register_channels(pool: &PgPool, profile: &StreamProfile) -> Result<(), String> {
+    for name in &profile.channels {
+        channels::validate_name(name)?;
+        sqlx::query(
+            "INSERT INTO channels (name, label, sample_rate) VALUES ($1, $1, $2) \
+             ON CONFLICT (name) DO NOTHING",
+        )
+        .bind(name)
+        .bind(profile.sample_rate)
+        .execute(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+    channels::load(pool).await.map_err(|e| e.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! OpenBCI Cyton serial acquisition driver.
+//!
+//! The Cyton streams 33-byte packets at 250 Hz once it receives `b`; they
+//! are framed by [`eeg_ingest::cyton`].
+
+use super::{DeviceSource, StreamProfile};
+use crate::config;
+use crate::ingest::{self, NewSample};
+use eeg_ingest::cyton::{CytonPacket, CytonParser, CHANNEL_COUNT, SAMPLE_RATE_HZ};
+use serialport::SerialPort;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BAUD_RATE: u32 = 115_200;
+const ADS1299_VREF: f64 = 4.5;
+
+/// Frames accumulated before a batch is handed to the ingest writer.
+const FRAMES_PER_BATCH: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct CytonConfig {
+    /// Serial device, e.g. `/dev/ttyUSB0`.
+    pub port: String,
+    /// ADS1299 programmable gain; the board default is 24.
+    pub gain: f64,
+    /// Channel names for the 8 inputs, in board order.
+    pub channels: Vec<String>,
+}
+
+impl CytonConfig {
+    /// The driver of the `[device.openbci]` settings; `None` without a port.
+    pub fn from_config(config: &config::OpenBciConfig) -> Option<Self> {
+        let channels = if config.channels.is_empty() {
+            (1..=CHANNEL_COUNT).map(|i| format!("CH{}", i)).collect()
+        } else {
+            config.channels.clone()
+        };
+        Some(Self {
+            port: config.port.clone()?,
+            gain: config.gain,
+            channels,
+        })
+    }
+
+    /// Microvolts per ADC count for the configured gain.
+    pub fn scale_uv(&self) -> f64 {
+        ADS1299_VREF / self.gain / ((1 << 23) - 1) as f64 * 1_000_000.0
+    }
+}
+
+fn now_us() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as i64)
+        .unwrap_or(0)
+}
+
+/// [`DeviceSource`] reading a Cyton over its serial dongle.
+pub struct CytonSource {
+    config: CytonConfig,
+    scale: f64,
+    port: Option<Box<dyn SerialPort>>,
+    parser: CytonParser,
+    // Timestamps follow the board clock: the first packet anchors the stream
+    // and dropped packets (gaps in the sample number) advance it.
+    anchor: Option<(i64, u64)>,
+    last_number: Option<u8>,
+    index: u64,
+}
+
+impl CytonSource {
+    pub fn new(config: CytonConfig) -> Result<Self, String> {
+        if config.channels.len() != CHANNEL_COUNT {
+            return Err(format!(
+                "OPENBCI_CHANNELS must name {} channels",
+                CHANNEL_COUNT
+            ));
+        }
+        Ok(Self {
+            scale: config.scale_uv(),
+            config,
+            port: None,
+            parser: CytonParser::default(),
+            anchor: None,
+            last_number: None,
+            index: 0,
+        })
+    }
+
+    fn samples_for(&mut self, packet: &CytonPacket, out: &mut Vec<NewSample>) {
+        if let Some(prev) = self.last_number {
+            self.index += packet.sample_number.wrapping_sub(prev).max(1) as u64;
+        }
+        self.last_number = Some(packet.sample_number);
+        let (start, base) = *self.anchor.get_or_insert((now_us(), self.index));
+        let period_us = 1_000_000.0 / SAMPLE_RATE_HZ;
+        let offset = ((self.index - base) as f64 * period_us).round() as i64;
+        let Some(ts) = ingest::ts_from_micros(start + offset) else {
+            return;
+        };
+        for (name, &count) in self.config.channels.iter().zip(&packet.counts) {
+            out.push(NewSample {
+                channel: name.clone(),
+                ts,
+                value: count as f64 * self.scale,
+                session_id: None,
+            });
+        }
+    }
+}
+
+impl DeviceSource for CytonSource {
+    fn name(&self) -> String {
+        format!("OpenBCI Cyton on {}", self.config.port)
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        let mut port = serialport::new(&self.config.port, BAUD_RATE)
+            .timeout(Duration::from_secs(1))
+            .open()
+            .map_err(|e| e.to_string())?;
+        // Stop any running stream, then start streaming.
+        port.write_all(b"s").map_err(|e| e.to_string())?;
+        std::thread::sleep(Duration::from_millis(100));
+        port.clear(serialport::ClearBuffer::Input)
+            .map_err(|e| e.to_string())?;
+        port.write_all(b"b").map_err(|e| e.to_string())?;
+
+        self.port = Some(port);
+        self.parser = CytonParser::default();
+        self.anchor = None;
+        self.last_number = None;
+        self.index = 0;
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Vec<NewSample>, String> {
+        let mut batch = Vec::with_capacity(FRAMES_PER_BATCH * CHANNEL_COUNT);
+        let mut read_buf = [0u8; 1024];
+        while batch.len() < FRAMES_PER_BATCH * CHANNEL_COUNT {
+            let port = self.port.as_mut().ok_or("device not started")?;
+            let n = match port.read(&mut read_buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.to_string()),
+            };
+            for packet in self.parser.push(&read_buf[..n]) {
+                self.samples_for(&packet, &mut batch);
+            }
+        }
+        Ok(batch)
+    }
+
+    fn profile(&self) -> StreamProfile {
+        StreamProfile {
+            channels: self.config.channels.clone(),
+            sample_rate: Some(SAMPLE_RATE_HZ),
+            gain: Some(self.config.gain),
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut port) = self.port.take() {
+            let _ = port.write_all(b"s");
+        }
+    }
+}
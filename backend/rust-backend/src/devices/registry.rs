@@ -0,0 +1,349 @@
+//! Registered acquisition devices (`devices` table) and their stream profiles.
+//!
+//! A device is registered by serial number with its model, channel map (the
+//! channel names of its inputs, in hardware order), sample rate and gain.
+//! Ingest paths that name a device (`device=` on the REST ingest routes,
+//! `DEVICE_SERIAL` for the drivers in this module) are checked against the
+//! profile with [`check_stream`]. Like the channel registry, profiles are
+//! cached in memory and reloaded periodically.
+
+use super::StreamProfile;
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_SERIAL_LEN: usize = 64;
+
+/// Relative difference tolerated between a stream's and the registered sample rate.
+const RATE_TOLERANCE: f64 = 1e-6;
+
+static REGISTRY: RwLock<BTreeMap<String, Device>> = RwLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Device {
+    pub serial: String,
+    pub model: String,
+    pub channel_map: Vec<String>,
+    pub sample_rate: f64,
+    pub gain: Option<f64>,
+    pub enabled: bool,
+    pub notes: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `POST /devices` (with `serial`) and `PUT /devices/{serial}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeviceInput {
+    serial: Option<String>,
+    model: Option<String>,
+    channel_map: Option<Vec<String>>,
+    sample_rate: Option<f64>,
+    gain: Option<f64>,
+    enabled: Option<bool>,
+    notes: Option<String>,
+}
+
+fn check_serial(errors: &mut FieldErrors, serial: &str) {
+    if serial.is_empty() || serial.len() > MAX_SERIAL_LEN {
+        errors.add(
+            "serial",
+            format!("must be 1 to {} characters", MAX_SERIAL_LEN),
+        );
+    }
+    errors.check(
+        serial
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':')),
+        "serial",
+        "may only contain letters, digits, '_', '-', '.' and ':'",
+    );
+}
+
+impl Validate for DeviceInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(serial) = &self.serial {
+            check_serial(errors, serial);
+        }
+        if let Some(model) = &self.model {
+            errors.check(!model.trim().is_empty(), "model", "must not be empty");
+        }
+        if let Some(map) = &self.channel_map {
+            errors.check(!map.is_empty(), "channel_map", "must not be empty");
+            for (i, name) in map.iter().enumerate() {
+                let field = format!("channel_map[{}]", i);
+                errors.check(!name.trim().is_empty(), &field, "must not be empty");
+                errors.check(
+                    !map[..i].contains(name),
+                    &field,
+                    format!("names {:?} twice", name),
+                );
+            }
+        }
+        if let Some(rate) = self.sample_rate {
+            errors.check(
+                rate.is_finite() && rate > 0.0,
+                "sample_rate",
+                "must be a positive number",
+            );
+        }
+        if let Some(gain) = self.gain {
+            errors.check(
+                gain.is_finite() && gain > 0.0,
+                "gain",
+                "must be a positive number",
+            );
+        }
+    }
+}
+
+/// The cached profile of `serial`, if registered.
+pub fn get(serial: &str) -> Option<Device> {
+    REGISTRY.read().unwrap().get(serial).cloned()
+}
+
+/// Checks that a stream from `serial` matches its registered profile: the
+/// device is registered and enabled, every channel is in its channel map,
+/// and the sample rate and gain agree where the stream reports them.
+pub fn check_stream(serial: &str, stream: &StreamProfile) -> Result<(), String> {
+    let registry = REGISTRY.read().unwrap();
+    let device = registry
+        .get(serial)
+        .ok_or_else(|| format!("unknown device {:?}", serial))?;
+    if !device.enabled {
+        return Err(format!("device {:?} is disabled", serial));
+    }
+    if let Some(channel) = stream
+        .channels
+        .iter()
+        .find(|c| !device.channel_map.contains(c))
+    {
+        return Err(format!(
+            "channel {:?} is not in the channel map of device {:?}",
+            channel, serial
+        ));
+    }
+    if let Some(rate) = stream.sample_rate {
+        if (rate - device.sample_rate).abs() > device.sample_rate * RATE_TOLERANCE {
+            return Err(format!(
+                "sample rate {} Hz does not match {} Hz registered for device {:?}",
+                rate, device.sample_rate, serial
+            ));
+        }
+    }
+    if let (Some(gain), Some(registered)) = (stream.gain, device.gain) {
+        if gain != registered {
+            return Err(format!(
+                "gain {} does not match {} registered for device {:?}",
+                gain, registered, serial
+            ));
+        }
+    }
+    Ok(())
+}
+
+const COLUMNS: &str = "serial, model, channel_map, sample_rate, gain, enabled, notes, created_at";
+
+/// Replaces the cached profiles with the contents of `devices`.
+pub async fn load(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let devices: Vec<Device> = sqlx::query_as(&format!("SELECT {} FROM devices", COLUMNS))
+        .fetch_all(pool)
+        .await?;
+    let count = devices.len();
+    *REGISTRY.write().unwrap() = devices.into_iter().map(|d| (d.serial.clone(), d)).collect();
+    Ok(count)
+}
+
+/// Starts the task that periodically reloads the profiles.
+pub fn spawn_reload(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = load(&pool).await {
+                tracing::error!("device registry reload failed: {}", e);
+            }
+        }
+    });
+}
+
+fn not_found(serial: &str) -> ApiError {
+    ApiError::not_found(format!("unknown device {:?}", serial))
+}
+
+#[utoipa::path(
+    get,
+    path = "/devices",
+    tag = "devices",
+    responses((status = 200, body = Vec<Device>))
+)]
+pub async fn list_devices(State(state): State<AppState>) -> Result<Json<Vec<Device>>, ApiError> {
+    let devices = sqlx::query_as(&format!("SELECT {} FROM devices ORDER BY serial", COLUMNS))
+        .fetch_all(&state.pool)
+        .await?;
+    Ok(Json(devices))
+}
+
+/// The registered profile of one device.
+#[utoipa::path(
+    get,
+    path = "/devices/{serial}",
+    tag = "devices",
+    params(("serial" = String, Path)),
+    responses(
+        (status = 200, body = Device),
+        (status = 404, description = "Unknown device")
+    )
+)]
+pub async fn get_device(
+    State(state): State<AppState>,
+    Parsed(Path(serial)): Parsed<Path<String>>,
+) -> Result<Json<Device>, ApiError> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM devices WHERE serial = $1",
+        COLUMNS
+    ))
+    .bind(&serial)
+    .fetch_optional(&state.pool)
+    .await?
+    .map(Json)
+    .ok_or_else(|| not_found(&serial))
+}
+
+#[utoipa::path(
+    post,
+    path = "/devices",
+    tag = "devices",
+    request_body = DeviceInput,
+    responses(
+        (status = 201, body = Device),
+        (status = 409, description = "Device already registered"),
+        (status = 422, description = "Missing or invalid serial, a missing required field, or an invalid field, named in `details.fields`")
+    )
+)]
+pub async fn create_device(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<DeviceInput>>,
+) -> Result<(StatusCode, Json<Device>), ApiError> {
+    let mut errors = FieldErrors::default();
+    errors.check(input.serial.is_some(), "serial", "is required");
+    errors.check(input.model.is_some(), "model", "is required");
+    errors.check(input.channel_map.is_some(), "channel_map", "is required");
+    errors.check(input.sample_rate.is_some(), "sample_rate", "is required");
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+    let serial = input.serial.clone().unwrap_or_default();
+
+    let device: Option<Device> = sqlx::query_as(&format!(
+        "INSERT INTO devices (serial, model, channel_map, sample_rate, gain, enabled, notes) \
+         VALUES ($1, $2, $3, $4, $5, COALESCE($6, TRUE), $7) \
+         ON CONFLICT (serial) DO NOTHING RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&serial)
+    .bind(&input.model)
+    .bind(&input.channel_map)
+    .bind(input.sample_rate)
+    .bind(input.gain)
+    .bind(input.enabled)
+    .bind(&input.notes)
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let device =
+        device.ok_or_else(|| ApiError::conflict(format!("device {:?} already exists", serial)))?;
+    REGISTRY
+        .write()
+        .unwrap()
+        .insert(device.serial.clone(), device.clone());
+    Ok((StatusCode::CREATED, Json(device)))
+}
+
+/// Updates the given fields; omitted fields keep their value.
+#[utoipa::path(
+    put,
+    path = "/devices/{serial}",
+    tag = "devices",
+    params(("serial" = String, Path)),
+    request_body = DeviceInput,
+    responses(
+        (status = 200, body = Device),
+        (status = 400, description = "Another serial in the body"),
+        (status = 404, description = "Unknown device"),
+        (status = 422, description = "Invalid field, named in `details.fields`")
+    )
+)]
+pub async fn update_device(
+    State(state): State<AppState>,
+    Parsed(Path(serial)): Parsed<Path<String>>,
+    Valid(Json(input)): Valid<Json<DeviceInput>>,
+) -> Result<Json<Device>, ApiError> {
+    if input.serial.as_deref().is_some_and(|s| s != serial) {
+        return Err(ApiError::bad_request("devices cannot be renamed"));
+    }
+
+    let device: Device = sqlx::query_as(&format!(
+        "UPDATE devices SET \
+         model = COALESCE($2, model), channel_map = COALESCE($3, channel_map), \
+         sample_rate = COALESCE($4, sample_rate), gain = COALESCE($5, gain), \
+         enabled = COALESCE($6, enabled), notes = COALESCE($7, notes) \
+         WHERE serial = $1 RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&serial)
+    .bind(&input.model)
+    .bind(&input.channel_map)
+    .bind(input.sample_rate)
+    .bind(input.gain)
+    .bind(input.enabled)
+    .bind(&input.notes)
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or_else(|| not_found(&serial))?;
+
+    REGISTRY
+        .write()
+        .unwrap()
+        .insert(device.serial.clone(), device.clone());
+    Ok(Json(device))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/devices/{serial}",
+    tag = "devices",
+    params(("serial" = String, Path)),
+    responses(
+        (status = 204, description = "Unregistered"),
+        (status = 404, description = "Unknown device")
+    )
+)]
+pub async fn delete_device(
+    State(state): State<AppState>,
+    Parsed(Path(serial)): Parsed<Path<String>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM devices WHERE serial = $1")
+        .bind(&serial)
+        .execute(&state.pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(not_found(&serial));
+    }
+    REGISTRY.write().unwrap().remove(&serial);
+    super::keys::forget_device(&serial);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,267 @@
+//! Synthetic multichannel EEG, streamed in real time as from a device, for
+//! trying the frontend and load testing without hardware.
+//!
+//! Each channel carries its own pink (1/f) background noise; bursts of
+//! 8–12 Hz alpha come and go, strongest over occipital and parietal sites;
+//! and now and then an eye blink adds a slow positive deflection, strongest
+//! over the frontal poles. Sites are recognized from 10-20 style names
+//! (`Fp1`, `F3`, `C4`, `O2`, ...); other names get middling weights.
+
+use super::{DeviceSource, StreamProfile};
+use crate::config;
+use crate::ingest::NewSample;
+use chrono::{DateTime, Utc};
+use std::f64::consts::{PI, TAU};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a batch is produced.
+const BATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Highest `SIMULATOR_RATE`, as for `seed`.
+pub const MAX_RATE: f64 = 10_000.0;
+
+pub const DEFAULT_CHANNELS: &str = "Fp1,Fp2,C3,C4,P3,P4,O1,O2";
+
+/// RMS of the background noise in µV.
+const NOISE_UV: f64 = 8.0;
+/// Peak of an alpha burst at a weight of 1, in µV.
+const ALPHA_UV: f64 = 30.0;
+/// Peak of a blink at a weight of 1, in µV.
+const BLINK_UV: f64 = 150.0;
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub channels: Vec<String>,
+    pub sample_rate: f64,
+}
+
+impl SimulatorConfig {
+    /// The simulator of the `[device.simulator]` settings, checked when
+    /// they were loaded.
+    pub fn from_config(config: &config::SimulatorConfig) -> Self {
+        Self {
+            channels: config.channels.clone(),
+            sample_rate: config.sample_rate,
+        }
+    }
+}
+
+/// Weights of alpha and blinks at the site of `name`.
+fn site_weights(name: &str) -> (f64, f64) {
+    let site = name.to_ascii_lowercase();
+    let prefix = |p: &str| site.starts_with(p);
+    if prefix("fp") {
+        (0.1, 1.0)
+    } else if prefix("af") {
+        (0.15, 0.7)
+    } else if prefix("f") {
+        (0.2, 0.4)
+    } else if prefix("c") || prefix("t") {
+        (0.4, 0.15)
+    } else if prefix("p") {
+        (0.8, 0.05)
+    } else if prefix("o") {
+        (1.0, 0.02)
+    } else {
+        (0.5, 0.1)
+    }
+}
+
+/// Xorshift generator, seeded from the clock.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self(nanos | 1)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn between(&mut self, low: f64, high: f64) -> f64 {
+        low + (high - low) * self.next()
+    }
+}
+
+/// Paul Kellet's three-pole approximation of pink noise from white noise.
+#[derive(Default)]
+struct Pink([f64; 3]);
+
+impl Pink {
+    /// The next value, with an RMS of about 1.
+    fn next(&mut self, rng: &mut Rng) -> f64 {
+        let white = rng.between(-1.0, 1.0);
+        let b = &mut self.0;
+        b[0] = 0.99765 * b[0] + white * 0.099_046;
+        b[1] = 0.963 * b[1] + white * 0.296_516_4;
+        b[2] = 0.57 * b[2] + white * 1.052_691_3;
+        (b[0] + b[1] + b[2] + white * 0.1848) / 1.7
+    }
+}
+
+/// Recurring bursts with random gaps and lengths, in seconds, each
+/// rising and falling smoothly.
+struct Bursts {
+    gap: (f64, f64),
+    length: (f64, f64),
+    start: u64,
+    len: u64,
+}
+
+impl Bursts {
+    fn new(gap: (f64, f64), length: (f64, f64)) -> Self {
+        Self {
+            gap,
+            length,
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Level from 0 to 1 at sample `i`, scheduling the next burst when the
+    /// current one is over.
+    fn level(&mut self, i: u64, rate: f64, rng: &mut Rng) -> f64 {
+        if i >= self.start + self.len {
+            self.start = i + (rng.between(self.gap.0, self.gap.1) * rate) as u64;
+            self.len = ((rng.between(self.length.0, self.length.1) * rate) as u64).max(1);
+        }
+        if i < self.start {
+            return 0.0;
+        }
+        (PI * (i - self.start) as f64 / self.len as f64)
+            .sin()
+            .powi(2)
+    }
+}
+
+struct Channel {
+    name: String,
+    pink: Pink,
+    alpha_weight: f64,
+    blink_weight: f64,
+    /// Phase of this channel's alpha against the shared rhythm.
+    alpha_phase: f64,
+}
+
+/// Simulated headset streaming `SIMULATOR_CHANNELS` at `SIMULATOR_RATE`.
+pub struct SimulatorSource {
+    config: SimulatorConfig,
+    rng: Rng,
+    channels: Vec<Channel>,
+    alpha: Bursts,
+    blinks: Bursts,
+    /// Frequency of the current alpha burst in Hz, and the rhythm's phase.
+    alpha_hz: f64,
+    phase: f64,
+    /// When streaming started, by the monotonic and the wall clock.
+    started: Option<(Instant, DateTime<Utc>)>,
+    /// Samples produced per channel since then.
+    produced: u64,
+}
+
+impl SimulatorSource {
+    pub fn new(config: SimulatorConfig) -> Self {
+        let mut rng = Rng::new();
+        let channels = config
+            .channels
+            .iter()
+            .map(|name| {
+                let (alpha_weight, blink_weight) = site_weights(name);
+                Channel {
+                    name: name.clone(),
+                    pink: Pink::default(),
+                    alpha_weight,
+                    blink_weight,
+                    alpha_phase: rng.between(-0.3, 0.3),
+                }
+            })
+            .collect();
+        Self {
+            config,
+            rng,
+            channels,
+            alpha: Bursts::new((1.0, 4.0), (0.5, 2.5)),
+            blinks: Bursts::new((2.0, 10.0), (0.25, 0.45)),
+            alpha_hz: 10.0,
+            phase: 0.0,
+            started: None,
+            produced: 0,
+        }
+    }
+}
+
+impl DeviceSource for SimulatorSource {
+    fn name(&self) -> String {
+        format!(
+            "EEG simulator ({} channels at {} Hz)",
+            self.channels.len(),
+            self.config.sample_rate
+        )
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        self.started = Some((Instant::now(), Utc::now()));
+        self.produced = 0;
+        self.alpha = Bursts::new(self.alpha.gap, self.alpha.length);
+        self.blinks = Bursts::new(self.blinks.gap, self.blinks.length);
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Vec<NewSample>, String> {
+        let (clock, start) = self.started.ok_or("simulator is not started")?;
+        std::thread::sleep(BATCH_INTERVAL);
+        let rate = self.config.sample_rate;
+        let due = (clock.elapsed().as_secs_f64() * rate) as u64;
+        let mut samples = Vec::with_capacity((due - self.produced) as usize * self.channels.len());
+        for i in self.produced..due {
+            let ts = start + chrono::Duration::microseconds((i as f64 * 1e6 / rate).round() as i64);
+            let burst = self.alpha.start;
+            let alpha = self.alpha.level(i, rate, &mut self.rng);
+            if self.alpha.start != burst {
+                self.alpha_hz = self.rng.between(8.5, 11.5);
+            }
+            self.phase = (self.phase + TAU * self.alpha_hz / rate) % TAU;
+            let blink = self.blinks.level(i, rate, &mut self.rng);
+            for channel in &mut self.channels {
+                let value = NOISE_UV * channel.pink.next(&mut self.rng)
+                    + ALPHA_UV
+                        * alpha
+                        * channel.alpha_weight
+                        * (self.phase + channel.alpha_phase).sin()
+                    + BLINK_UV * blink * channel.blink_weight;
+                samples.push(NewSample {
+                    channel: channel.name.clone(),
+                    ts,
+                    value,
+                    session_id: None,
+                });
+            }
+        }
+        self.produced = due;
+        Ok(samples)
+    }
+
+    fn profile(&self) -> StreamProfile {
+        StreamProfile {
+            channels: self.config.channels.clone(),
+            sample_rate: Some(self.config.sample_rate),
+            gain: None,
+        }
+    }
+
+    fn stop(&mut self) {
+        self.started = None;
+    }
+
+    fn registers_channels(&self) -> bool {
+        true
+    }
+}
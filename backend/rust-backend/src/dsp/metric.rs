@@ -0,0 +1,133 @@
+//! Scalar metrics of a window of samples, shared by [alert
+//! rules](crate::alerts) and the [neurofeedback stream](crate::feedback).
+//!
+//! Spectral metrics use the Welch PSD of the window's runs without gaps
+//! (one-second Hann segments, shorter if the longest run is), summed over a
+//! band's bins times the bin width as for `/analysis/bandpower`.
+
+use super::segments;
+use super::spectrum::{Stft, Window};
+use crate::analysis::bandpower::{default_bands, parse_bands, Band};
+use crate::EegSample;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What is measured over a window.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Metric {
+    /// Largest absolute value.
+    Amplitude,
+    /// Largest minus smallest value.
+    PeakToPeak,
+    /// Root mean square after removing the mean.
+    Rms,
+    /// Power of `band`, a default band name or `name:low-high`; with
+    /// `relative`, its share of the power spanned by the default bands.
+    Bandpower {
+        band: String,
+        #[serde(default)]
+        relative: bool,
+    },
+    /// Power of `band` divided by the power of `over`, e.g. theta / beta.
+    BandRatio { band: String, over: String },
+}
+
+fn single_band(text: &str) -> Result<Band, String> {
+    let mut bands = parse_bands(text)?;
+    if bands.len() != 1 {
+        return Err(format!("{:?} must name a single band", text));
+    }
+    Ok(bands.remove(0))
+}
+
+impl Metric {
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Metric::Bandpower { band, .. } => single_band(band).map(|_| ()),
+            Metric::BandRatio { band, over } => {
+                single_band(band)?;
+                single_band(over).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether [`measure`](Self::measure) needs the sample rate.
+    pub fn spectral(&self) -> bool {
+        matches!(self, Metric::Bandpower { .. } | Metric::BandRatio { .. })
+    }
+
+    /// The metric over `samples`; `None` without samples, for spectral
+    /// metrics without a sample rate or a long enough run, and for
+    /// ratios without power in `over`.
+    pub fn measure(&self, samples: &[EegSample], rate: Option<f64>) -> Option<f64> {
+        let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+        if values.is_empty() {
+            return None;
+        }
+        let (low, high) = values
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        let power = |bands: &[(f64, f64)]| -> Option<Vec<f64>> {
+            let rate = rate?;
+            let runs: Vec<&[f64]> = segments(samples, rate)
+                .into_iter()
+                .map(|r| &values[r])
+                .collect();
+            let longest = runs.iter().map(|r| r.len()).max().unwrap_or(0);
+            let nperseg = (rate.round() as usize).min(longest);
+            let stft = Stft::new(
+                Window::Hann,
+                nperseg,
+                nperseg / 2,
+                nperseg.next_power_of_two(),
+            )
+            .ok()?;
+            let (psd, _) = stft.welch(&runs, rate)?;
+            let frequencies = stft.frequencies(rate);
+            let resolution = rate / stft.nfft() as f64;
+            Some(
+                bands
+                    .iter()
+                    .map(|&(low, high)| {
+                        frequencies
+                            .iter()
+                            .zip(&psd)
+                            .filter(|(f, _)| **f >= low && **f < high)
+                            .map(|(_, p)| p * resolution)
+                            .sum::<f64>()
+                    })
+                    .collect(),
+            )
+        };
+        match self {
+            Metric::Amplitude => Some(low.abs().max(high.abs())),
+            Metric::PeakToPeak => Some(high - low),
+            Metric::Rms => {
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                let power = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>()
+                    / values.len() as f64;
+                Some(power.sqrt())
+            }
+            Metric::Bandpower { band, relative } => {
+                let band = single_band(band).ok()?;
+                if !relative {
+                    return power(&[(band.low, band.high)]).map(|p| p[0]);
+                }
+                let bands = default_bands();
+                let low = bands.iter().map(|b| b.low).fold(f64::INFINITY, f64::min);
+                let high = bands.iter().map(|b| b.high).fold(0.0, f64::max);
+                let p = power(&[(band.low, band.high), (low, high)])?;
+                (p[1] > 0.0).then(|| p[0] / p[1])
+            }
+            Metric::BandRatio { band, over } => {
+                let (band, over) = (single_band(band).ok()?, single_band(over).ok()?);
+                let p = power(&[(band.low, band.high), (over.low, over.high)])?;
+                (p[1] > 0.0).then(|| p[0] / p[1])
+            }
+        }
+    }
+}
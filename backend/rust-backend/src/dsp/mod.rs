@@ -0,0 +1,308 @@
+//! Signal processing over windows of stored samples (`/samples/filter`).
+//!
+//! A window is processed per channel at the channel's registered
+//! `sample_rate`, or the rate estimated from the median interval between
+//! samples. Samples are treated as evenly spaced; a gap longer than two
+//! sample periods splits the window into segments that are processed on
+//! their own, so filters do not ring across missing data.
+//!
+//! [`notch`] applies mains notches causally to samples read in pages or
+//! streamed live, and [`reference`] re-references them. [`resample`]
+//! resamples windows to another rate; [`rolling`] keeps statistics of the
+//! last second of a live stream.
+
+pub mod metric;
+pub mod notch;
+pub mod pipeline;
+pub mod reference;
+pub mod resample;
+pub mod rolling;
+
+pub use eeg_dsp::{filter, ica, spectrum};
+
+use crate::analysis::artifacts::{self, ArtifactQuery};
+use crate::api::samples::downsample_samples;
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{downsample, fetch_window_samples, AppState, ChannelQuery, EegSample, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use filter::Filter;
+use notch::NotchQuery;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use std::ops::Range;
+use utoipa::IntoParams;
+
+const DEFAULT_ORDER: usize = 4;
+
+/// Stored history causal filters run over before their first sample.
+pub const WARMUP_MS: i64 = 2_000;
+
+/// Typed part of `/samples/filter`; the channels and the window are read as
+/// for `/samples`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FilterQuery {
+    /// `low,high` in Hz.
+    bandpass: Option<String>,
+    order: Option<usize>,
+    /// Downsample the filtered window to at most this many points.
+    points: Option<usize>,
+    #[serde(default)]
+    downsample: downsample::Method,
+    /// Resample the filtered window to this rate in Hz.
+    resample: Option<f64>,
+}
+
+impl Validate for FilterQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(Err(e)) = self.bandpass.as_deref().map(parse_band) {
+            errors.add("bandpass", e);
+        }
+        if let Some(points) = self.points {
+            let max = crate::config::get().limits.max_points;
+            errors.check(
+                (2..=max).contains(&points),
+                "points",
+                format!("must be 2 to {}", max),
+            );
+        }
+        if let Err(e) = resample::check(self.resample) {
+            errors.add("resample", e);
+        }
+    }
+}
+
+/// Parses `low,high` frequencies in Hz.
+fn parse_band(text: &str) -> Result<(f64, f64), String> {
+    let parse = |v: &str| v.trim().parse::<f64>().ok().filter(|f| f.is_finite());
+    match text.split_once(',') {
+        Some((low, high)) => match (parse(low), parse(high)) {
+            (Some(low), Some(high)) => Ok((low, high)),
+            _ => Err(format!("invalid frequencies {:?}", text)),
+        },
+        None => Err(format!("expected low,high in Hz, got {:?}", text)),
+    }
+}
+
+/// Sample rate from the median interval between consecutive timestamps.
+pub fn estimate_rate(ts: &[DateTime<Utc>]) -> Option<f64> {
+    let mut intervals: Vec<i64> = ts
+        .windows(2)
+        .filter_map(|w| (w[1] - w[0]).num_microseconds())
+        .filter(|&dt| dt > 0)
+        .collect();
+    if intervals.is_empty() {
+        return None;
+    }
+    let middle = intervals.len() / 2;
+    let (_, median, _) = intervals.select_nth_unstable(middle);
+    Some(1e6 / *median as f64)
+}
+
+/// The channel's registered sample rate, if any.
+#[tracing::instrument(skip_all, fields(otel.kind = "client", db.system = "postgresql", channel = channel))]
+pub async fn registered_rate(pool: &PgPool, channel: &str) -> Result<Option<f64>, sqlx::Error> {
+    let registered: Option<Option<f64>> =
+        sqlx::query_scalar("SELECT sample_rate FROM channels WHERE name = $1")
+            .bind(channel)
+            .fetch_optional(pool)
+            .await?;
+    Ok(registered.flatten())
+}
+
+/// The channel's registered sample rate, else the one estimated from
+/// `samples` (oldest first).
+pub async fn channel_rate(
+    pool: &PgPool,
+    channel: &str,
+    samples: &[EegSample],
+) -> Result<Option<f64>, sqlx::Error> {
+    Ok(registered_rate(pool, channel).await?.or_else(|| {
+        let ts: Vec<DateTime<Utc>> = samples.iter().map(|s| s.ts).collect();
+        estimate_rate(&ts)
+    }))
+}
+
+/// The [`WARMUP_MS`] of stored samples before the first of `ts` (in
+/// timestamp order), and the channel's registered sample rate, else the one
+/// estimated from the history and `ts`.
+pub async fn history(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+    ts: &[DateTime<Utc>],
+) -> Result<(Vec<EegSample>, Option<f64>), ApiError> {
+    let Some(&first) = ts.first() else {
+        return Ok((Vec::new(), None));
+    };
+    let window = SampleFilter {
+        from: Some(first - chrono::Duration::milliseconds(WARMUP_MS)),
+        to: Some(first),
+        ..*filter
+    };
+    let warmup = fetch_window_samples(pool, channel, &window).await?;
+    let rate = registered_rate(pool, channel).await?.or_else(|| {
+        let all: Vec<DateTime<Utc>> = warmup
+            .iter()
+            .map(|s| s.ts)
+            .chain(ts.iter().copied())
+            .collect();
+        estimate_rate(&all)
+    });
+    Ok((warmup, rate))
+}
+
+/// Runs of `samples` without gaps longer than two sample periods.
+pub fn segments(samples: &[EegSample], rate: f64) -> Vec<Range<usize>> {
+    let max_gap_us = (2e6 / rate) as i64;
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for i in 1..=samples.len() {
+        let split = i == samples.len()
+            || (samples[i].ts - samples[i - 1].ts)
+                .num_microseconds()
+                .is_none_or(|dt| dt > max_gap_us);
+        if split {
+            segments.push(start..i);
+            start = i;
+        }
+    }
+    segments
+}
+
+/// Applies `filter` to each segment of `samples`.
+fn apply(filter: &Filter, samples: &mut [EegSample], rate: f64) {
+    for segment in segments(samples, rate) {
+        let values: Vec<f64> = samples[segment.clone()].iter().map(|s| s.value).collect();
+        for (sample, value) in samples[segment].iter_mut().zip(filter.filtfilt(&values)) {
+            sample.value = value;
+        }
+    }
+}
+
+/// Samples of the window after a zero-phase Butterworth bandpass and/or
+/// mains notch, oldest first. Ids and timestamps are those of the stored
+/// samples, unless the window is resampled.
+#[utoipa::path(
+    get,
+    path = "/samples/filter",
+    tag = "samples",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        FilterQuery,
+        SampleFilter,
+        NotchQuery,
+        ArtifactQuery,
+        reference::ReferenceQuery
+    ),
+    responses(
+        (status = 200, description = "`{ sample_rate, samples }` oldest first, or `{ channels: [...] }` grouped per channel", body = serde_json::Value),
+        (status = 400, description = "No filter given, a filter the sample rate cannot take, or too many samples in the window"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_filtered(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<FilterQuery>>,
+    Valid(Query(notch)): Valid<Query<NotchQuery>>,
+    Parsed(Query(artifacts)): Parsed<Query<ArtifactQuery>>,
+    Parsed(Query(reference)): Parsed<Query<reference::ReferenceQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let derivations = reference.derivations(state.reader(), &raw).await?;
+    let band = params
+        .bandpass
+        .as_deref()
+        .map(parse_band)
+        .transpose()
+        .map_err(ApiError::internal)?;
+    if band.is_none() && !notch.is_set() && params.resample.is_none() {
+        return Err(bad_request(
+            "bandpass, notch or resample is required".to_string(),
+        ));
+    }
+    let order = params.order.unwrap_or(DEFAULT_ORDER);
+
+    let mut results = Vec::with_capacity(derivations.len());
+    for derivation in &derivations {
+        let channel = &derivation.channel;
+        let mut samples = fetch_window_samples(state.reader(), channel, &filter).await?;
+        reference::apply(state.reader(), &derivation.reference, &filter, &mut samples).await?;
+        if derivation.name != *channel {
+            for sample in &mut samples {
+                sample.channel.clone_from(&derivation.name);
+            }
+        }
+        let rate = channel_rate(state.reader(), channel, &samples).await?;
+        if let Some(rate) = rate {
+            let channel_error = |e| bad_request(format!("channel {:?}: {}", channel, e));
+            let bandpass = band
+                .map(|(low, high)| Filter::bandpass(order, low, high, rate))
+                .transpose()
+                .map_err(channel_error)?;
+            let notches = notch.design(rate).transpose().map_err(channel_error)?;
+            if let Some(filter) = bandpass.into_iter().chain(notches).reduce(Filter::then) {
+                apply(&filter, &mut samples, rate);
+            }
+        }
+        if artifacts.artifacts == Some(artifacts::Mode::Exclude) {
+            artifacts::apply(
+                state.reader(),
+                channel,
+                &filter,
+                &mut samples,
+                artifacts::Mode::Exclude,
+            )
+            .await?;
+        }
+        let mut rate = rate;
+        if let (Some(target), Some(native)) = (params.resample, rate) {
+            samples = resample::resample(&samples, native, target)
+                .map_err(|e| bad_request(format!("channel {:?}: {}", channel, e)))?;
+            rate = Some(target);
+        }
+        if let Some(points) = params.points {
+            samples = downsample_samples(samples, points, params.downsample);
+        }
+        let marks = match artifacts.artifacts {
+            Some(artifacts::Mode::Mark) => {
+                artifacts::apply(
+                    state.reader(),
+                    channel,
+                    &filter,
+                    &mut samples,
+                    artifacts::Mode::Mark,
+                )
+                .await?
+            }
+            _ => None,
+        };
+        let to_json = if params.resample.is_some() {
+            resample::to_json
+        } else {
+            artifacts::to_json
+        };
+        results.push((&derivation.name, rate, to_json(&samples, marks.as_deref())));
+    }
+
+    let body = if let [(_, rate, samples)] = results.as_slice() {
+        json!({ "sample_rate": rate, "samples": samples })
+    } else {
+        let grouped: Vec<_> = results
+            .iter()
+            .map(|(channel, rate, samples)| {
+                json!({ "channel": channel, "sample_rate": rate, "samples": samples })
+            })
+            .collect();
+        json!({ "channels": grouped })
+    };
+    Ok(Json(body))
+}
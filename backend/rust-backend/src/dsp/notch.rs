@@ -0,0 +1,194 @@
+//! Mains notch (`notch=50` / `notch=60`) on `/samples` and the live endpoints.
+//!
+//! These deliver samples a page or a batch at a time, so the notch runs
+//! causally: a [`Notch`] keeps the filter state from one call to the next
+//! and, before the first sample it filters, runs over the
+//! [`WARMUP_MS`](super::WARMUP_MS) of stored samples preceding it so the
+//! output starts settled. A gap longer than two sample periods, or a sample
+//! older than the previous one, restarts the filter.
+
+use super::filter::{Causal, Filter};
+use super::history;
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Validate};
+use crate::{EegSample, LivePoint, SampleFilter};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use utoipa::IntoParams;
+
+pub const DEFAULT_Q: f64 = 30.0;
+
+/// Highest accepted `notch_harmonics`.
+pub const MAX_HARMONICS: usize = 10;
+
+/// Notch parameters shared by `/samples`, `/samples/filter` and `/live*`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct NotchQuery {
+    /// Mains frequency in Hz, usually 50 or 60.
+    notch: Option<f64>,
+    /// Number of multiples of `notch` removed, the fundamental included.
+    notch_harmonics: Option<usize>,
+    /// Quality factor: the notch frequency over its -3 dB width.
+    notch_q: Option<f64>,
+}
+
+impl NotchQuery {
+    pub fn is_set(&self) -> bool {
+        self.notch.is_some()
+    }
+
+    /// The notch filter at `rate`, if one was asked for.
+    pub fn design(&self, rate: f64) -> Option<Result<Filter, String>> {
+        self.notch.map(|freq| {
+            Filter::notch(
+                freq,
+                self.notch_q.unwrap_or(DEFAULT_Q),
+                self.notch_harmonics.unwrap_or(1),
+                rate,
+            )
+        })
+    }
+}
+
+impl Validate for NotchQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(freq) = self.notch {
+            errors.check(
+                freq.is_finite() && freq > 0.0,
+                "notch",
+                "must be a positive frequency in Hz",
+            );
+        }
+        if let Some(harmonics) = self.notch_harmonics {
+            errors.check(
+                (1..=MAX_HARMONICS).contains(&harmonics),
+                "notch_harmonics",
+                format!("must be 1 to {}", MAX_HARMONICS),
+            );
+        }
+        if let Some(q) = self.notch_q {
+            errors.check(q.is_finite() && q > 0.0, "notch_q", "must be positive");
+        }
+    }
+}
+
+/// A sample whose value the notch rewrites.
+pub trait Point {
+    fn ts(&self) -> DateTime<Utc>;
+    fn value_mut(&mut self) -> &mut f64;
+}
+
+impl Point for EegSample {
+    fn ts(&self) -> DateTime<Utc> {
+        self.ts
+    }
+
+    fn value_mut(&mut self) -> &mut f64 {
+        &mut self.value
+    }
+}
+
+impl Point for LivePoint {
+    fn ts(&self) -> DateTime<Utc> {
+        self.ts
+    }
+
+    fn value_mut(&mut self) -> &mut f64 {
+        &mut self.value
+    }
+}
+
+/// The filter once the channel's rate is known.
+struct Running {
+    causal: Causal,
+    max_gap_us: i64,
+    last: Option<DateTime<Utc>>,
+}
+
+impl Running {
+    fn step(&mut self, ts: DateTime<Utc>, value: f64) -> f64 {
+        let contiguous = self.last.is_some_and(|last| {
+            (ts - last)
+                .num_microseconds()
+                .is_some_and(|dt| dt > 0 && dt <= self.max_gap_us)
+        });
+        if !contiguous {
+            self.causal.reset();
+        }
+        self.last = Some(ts);
+        self.causal.step(value)
+    }
+}
+
+/// Causal notch state for one channel.
+pub struct Notch {
+    query: NotchQuery,
+    running: Option<Running>,
+}
+
+impl Notch {
+    /// `None` unless `query` asks for a notch.
+    pub fn new(query: NotchQuery) -> Option<Self> {
+        query.is_set().then_some(Self {
+            query,
+            running: None,
+        })
+    }
+
+    /// Forgets the state, e.g. when a stream switches channels.
+    pub fn reset(&mut self) {
+        self.running = None;
+    }
+
+    /// Filters `points` of `channel` in timestamp order, continuing from the
+    /// previous call. Until the sample rate is known (registered, or
+    /// estimated from the warm-up and `points`) values are left as they are.
+    #[tracing::instrument(name = "notch", skip_all, fields(channel = channel, points = points.len()))]
+    pub async fn apply<P: Point>(
+        &mut self,
+        pool: &PgPool,
+        channel: &str,
+        filter: &SampleFilter,
+        points: &mut [P],
+    ) -> Result<(), ApiError> {
+        let mut order: Vec<usize> = (0..points.len()).collect();
+        order.sort_by_key(|&i| points[i].ts());
+        if order.is_empty() {
+            return Ok(());
+        }
+
+        if self.running.is_none() {
+            let ts: Vec<DateTime<Utc>> = order.iter().map(|&i| points[i].ts()).collect();
+            let (warmup, rate) = history(pool, channel, filter, &ts).await?;
+            let Some(rate) = rate else {
+                return Ok(());
+            };
+            let design = self
+                .query
+                .design(rate)
+                .unwrap_or_else(|| Err("notch is not set".to_string()));
+            let notch = design
+                .map_err(|e| ApiError::bad_request(format!("channel {:?}: {}", channel, e)))?;
+            let mut running = Running {
+                causal: Causal::new(notch),
+                max_gap_us: (2e6 / rate) as i64,
+                last: None,
+            };
+            for sample in &warmup {
+                running.step(sample.ts, sample.value);
+            }
+            self.running = Some(running);
+        }
+
+        if let Some(running) = self.running.as_mut() {
+            for i in order {
+                let ts = points[i].ts();
+                let value = points[i].value_mut();
+                *value = running.step(ts, *value);
+            }
+        }
+        Ok(())
+    }
+}
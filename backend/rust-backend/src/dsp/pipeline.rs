@@ -0,0 +1,750 @@
+//! Live processing pipelines (`/pipelines`): ordered stages run causally on
+//! live output.
+//!
+//! A pipeline is a list of [`Stage`]s (detrend, notch, bandpass,
+//! downsample) applied in order, each at the rate the previous one leaves.
+//! Pipelines are stored in the `pipelines` table; one attached to a channel
+//! (`channels`) processes that channel's `/live/ws` output unless the stream
+//! picks another pipeline or `pipeline=none`, and a stream may also bring its
+//! own stages. With `persist`, a background task runs the pipeline over new
+//! samples of each attached channel and stores the output in the derived
+//! channel `<channel>_<pipeline>`, from the samples that arrive after the
+//! backend starts.
+//!
+//! Stages keep their state between batches like [`super::notch::Notch`]: a
+//! [`Live`] warms up on the stored history before its first sample, and a
+//! gap longer than two sample periods restarts every stage.
+
+use super::filter::{Causal, Filter, MAX_ORDER};
+use super::history;
+use super::notch::{Point, DEFAULT_Q, MAX_HARMONICS};
+use crate::channels::{self, validate_name};
+use crate::error::ApiError;
+use crate::ingest::{self, NewSample};
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{AppState, SampleFilter};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as Jsonb;
+use sqlx::{PgConnection, PgPool};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use utoipa::{IntoParams, ToSchema};
+
+const MAX_STAGES: usize = 16;
+const DEFAULT_DETREND_SECONDS: f64 = 1.0;
+const MAX_DETREND_SECONDS: f64 = 60.0;
+const DEFAULT_BANDPASS_ORDER: usize = 4;
+const MAX_DOWNSAMPLE_FACTOR: usize = 100;
+
+/// `pipeline` value that turns processing off for a stream.
+const NONE: &str = "none";
+
+/// How often persisted pipelines look for new samples.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most samples a persisted pipeline reads per channel and pass.
+const PERSIST_BATCH: i64 = 5_000;
+
+/// One processing step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Stage {
+    /// Subtracts the mean of the last `seconds` of input.
+    Detrend { seconds: Option<f64> },
+    /// Mains notch as `notch=` on `/samples`.
+    Notch {
+        freq: f64,
+        q: Option<f64>,
+        harmonics: Option<usize>,
+    },
+    /// Butterworth bandpass of `order` between `low` and `high` Hz.
+    Bandpass {
+        low: f64,
+        high: f64,
+        order: Option<usize>,
+    },
+    /// Keeps every `factor`-th sample.
+    Downsample { factor: usize },
+}
+
+/// Checks what can be checked before the sample rate is known.
+pub fn check(stages: &[Stage]) -> Result<(), String> {
+    if stages.is_empty() || stages.len() > MAX_STAGES {
+        return Err(format!("stages must list 1 to {} stages", MAX_STAGES));
+    }
+    for stage in stages {
+        match *stage {
+            Stage::Detrend { seconds } => {
+                let seconds = seconds.unwrap_or(DEFAULT_DETREND_SECONDS);
+                if !(seconds > 0.0 && seconds <= MAX_DETREND_SECONDS) {
+                    return Err(format!(
+                        "detrend seconds must be above 0 and at most {}",
+                        MAX_DETREND_SECONDS
+                    ));
+                }
+            }
+            Stage::Notch { freq, q, harmonics } => {
+                if !(freq.is_finite() && freq > 0.0) {
+                    return Err("notch freq must be a positive frequency in Hz".to_string());
+                }
+                if q.is_some_and(|q| !(q.is_finite() && q > 0.0)) {
+                    return Err("notch q must be positive".to_string());
+                }
+                if harmonics.is_some_and(|h| !(1..=MAX_HARMONICS).contains(&h)) {
+                    return Err(format!("notch harmonics must be 1 to {}", MAX_HARMONICS));
+                }
+            }
+            Stage::Bandpass { low, high, order } => {
+                if !(low.is_finite() && high.is_finite() && 0.0 < low && low < high) {
+                    return Err("bandpass needs 0 < low < high in Hz".to_string());
+                }
+                if order.is_some_and(|o| !(1..=MAX_ORDER).contains(&o)) {
+                    return Err(format!("bandpass order must be 1 to {}", MAX_ORDER));
+                }
+            }
+            Stage::Downsample { factor } => {
+                if !(1..=MAX_DOWNSAMPLE_FACTOR).contains(&factor) {
+                    return Err(format!(
+                        "downsample factor must be 1 to {}",
+                        MAX_DOWNSAMPLE_FACTOR
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Output rate of `stages` for an input at `rate`.
+fn output_rate(stages: &[Stage], rate: f64) -> f64 {
+    stages.iter().fold(rate, |rate, stage| match stage {
+        Stage::Downsample { factor } => rate / *factor as f64,
+        _ => rate,
+    })
+}
+
+/// A stage designed for its input rate.
+enum Step {
+    Detrend {
+        window: VecDeque<f64>,
+        len: usize,
+        sum: f64,
+    },
+    Filter(Causal),
+    Downsample {
+        factor: usize,
+        count: usize,
+    },
+}
+
+impl Step {
+    fn reset(&mut self) {
+        match self {
+            Step::Detrend { window, sum, .. } => {
+                window.clear();
+                *sum = 0.0;
+            }
+            Step::Filter(causal) => causal.reset(),
+            Step::Downsample { count, .. } => *count = 0,
+        }
+    }
+
+    fn step(&mut self, x: f64) -> Option<f64> {
+        match self {
+            Step::Detrend { window, len, sum } => {
+                window.push_back(x);
+                *sum += x;
+                if window.len() > *len {
+                    *sum -= window.pop_front().unwrap_or(0.0);
+                }
+                Some(x - *sum / window.len() as f64)
+            }
+            Step::Filter(causal) => Some(causal.step(x)),
+            Step::Downsample { factor, count } => {
+                let keep = *count % *factor == 0;
+                *count += 1;
+                keep.then_some(x)
+            }
+        }
+    }
+}
+
+/// The stages of a pipeline at a known input rate.
+struct Chain {
+    steps: Vec<Step>,
+    max_gap_us: i64,
+    last: Option<DateTime<Utc>>,
+}
+
+impl Chain {
+    fn new(stages: &[Stage], rate: f64) -> Result<Self, String> {
+        let mut steps = Vec::with_capacity(stages.len());
+        let mut at = rate;
+        for stage in stages {
+            steps.push(match *stage {
+                Stage::Detrend { seconds } => Step::Detrend {
+                    window: VecDeque::new(),
+                    len: ((seconds.unwrap_or(DEFAULT_DETREND_SECONDS) * at).round() as usize)
+                        .max(1),
+                    sum: 0.0,
+                },
+                Stage::Notch { freq, q, harmonics } => Step::Filter(Causal::new(Filter::notch(
+                    freq,
+                    q.unwrap_or(DEFAULT_Q),
+                    harmonics.unwrap_or(1),
+                    at,
+                )?)),
+                Stage::Bandpass { low, high, order } => Step::Filter(Causal::new(
+                    Filter::bandpass(order.unwrap_or(DEFAULT_BANDPASS_ORDER), low, high, at)?,
+                )),
+                Stage::Downsample { factor } => {
+                    at /= factor as f64;
+                    Step::Downsample { factor, count: 0 }
+                }
+            });
+        }
+        Ok(Self {
+            steps,
+            max_gap_us: (2e6 / rate) as i64,
+            last: None,
+        })
+    }
+
+    fn step(&mut self, ts: DateTime<Utc>, value: f64) -> Option<f64> {
+        let contiguous = self.last.is_some_and(|last| {
+            (ts - last)
+                .num_microseconds()
+                .is_some_and(|dt| dt > 0 && dt <= self.max_gap_us)
+        });
+        if !contiguous {
+            self.steps.iter_mut().for_each(Step::reset);
+        }
+        self.last = Some(ts);
+        self.steps
+            .iter_mut()
+            .try_fold(value, |value, step| step.step(value))
+    }
+}
+
+/// Pipeline state for one channel of a stream.
+pub struct Live {
+    /// Name of the stored pipeline; `None` for stages given by the stream.
+    pub name: Option<String>,
+    pub stages: Vec<Stage>,
+    chain: Option<Chain>,
+}
+
+impl Live {
+    pub fn new(name: Option<String>, stages: Vec<Stage>) -> Self {
+        Self {
+            name,
+            stages,
+            chain: None,
+        }
+    }
+
+    /// Runs `points` of `channel` through the stages in timestamp order,
+    /// continuing from the previous call, and returns the points the
+    /// stages keep. Until the sample rate is known (registered, or estimated
+    /// from the warm-up and `points`) points pass unchanged.
+    pub async fn apply<P: Point>(
+        &mut self,
+        pool: &PgPool,
+        channel: &str,
+        filter: &SampleFilter,
+        mut points: Vec<P>,
+    ) -> Result<Vec<P>, ApiError> {
+        points.sort_by_key(|p| p.ts());
+        if points.is_empty() {
+            return Ok(points);
+        }
+        if self.chain.is_none() {
+            let ts: Vec<DateTime<Utc>> = points.iter().map(|p| p.ts()).collect();
+            let (warmup, rate) = history(pool, channel, filter, &ts).await?;
+            let Some(rate) = rate else {
+                return Ok(points);
+            };
+            let mut chain = Chain::new(&self.stages, rate)
+                .map_err(|e| ApiError::bad_request(format!("channel {:?}: {}", channel, e)))?;
+            for sample in &warmup {
+                chain.step(sample.ts, sample.value);
+            }
+            self.chain = Some(chain);
+        }
+        let Some(chain) = self.chain.as_mut() else {
+            return Ok(points);
+        };
+        points.retain_mut(|point| {
+            let ts = point.ts();
+            let value = point.value_mut();
+            match chain.step(ts, *value) {
+                Some(output) => {
+                    *value = output;
+                    true
+                }
+                None => false,
+            }
+        });
+        Ok(points)
+    }
+}
+
+/// How a stream picks its pipeline.
+#[derive(Debug, Clone)]
+pub enum Selection {
+    /// The pipeline attached to the channel, if any.
+    Attached,
+    /// No processing.
+    Raw,
+    Named(String),
+    Inline(Vec<Stage>),
+}
+
+impl Selection {
+    /// From a `pipeline` parameter and optional `stages` given by a stream.
+    pub fn new(pipeline: Option<String>, stages: Option<Vec<Stage>>) -> Result<Self, String> {
+        match (pipeline, stages) {
+            (Some(_), Some(_)) => Err("give either pipeline or stages".to_string()),
+            (_, Some(stages)) => {
+                check(&stages)?;
+                Ok(Selection::Inline(stages))
+            }
+            (Some(name), None) if name == NONE => Ok(Selection::Raw),
+            (Some(name), None) => Ok(Selection::Named(name)),
+            (None, None) => Ok(Selection::Attached),
+        }
+    }
+
+    /// The pipeline state for a stream of `channel`.
+    pub async fn resolve(&self, pool: &PgPool, channel: &str) -> Result<Option<Live>, ApiError> {
+        match self {
+            Selection::Raw => Ok(None),
+            Selection::Inline(stages) => Ok(Some(Live::new(None, stages.clone()))),
+            Selection::Named(name) => {
+                let (stages,): (Jsonb<Vec<Stage>>,) =
+                    sqlx::query_as("SELECT stages FROM pipelines WHERE name = $1")
+                        .bind(name)
+                        .fetch_optional(pool)
+                        .await?
+                        .ok_or_else(|| {
+                            ApiError::not_found(format!("unknown pipeline {:?}", name))
+                        })?;
+                Ok(Some(Live::new(Some(name.clone()), stages.0)))
+            }
+            Selection::Attached => {
+                let attached: Option<(String, Jsonb<Vec<Stage>>)> = sqlx::query_as(
+                    "SELECT name, stages FROM pipelines WHERE enabled AND $1 = ANY(channels)",
+                )
+                .bind(channel)
+                .fetch_optional(pool)
+                .await?;
+                Ok(attached.map(|(name, stages)| Live::new(Some(name), stages.0)))
+            }
+        }
+    }
+}
+
+/// `pipeline` query parameter of `/live/ws`: a stored pipeline, or `none`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PipelineQuery {
+    pub pipeline: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Pipeline {
+    pub name: String,
+    #[schema(value_type = Vec<Stage>)]
+    pub stages: Jsonb<Vec<Stage>>,
+    /// Channels whose live output the pipeline processes by default.
+    pub channels: Vec<String>,
+    /// Whether outputs are stored as `<channel>_<name>`.
+    pub persist: bool,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `POST /pipelines` (with `name`) and `PUT /pipelines/{name}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PipelineInput {
+    name: Option<String>,
+    stages: Option<Vec<Stage>>,
+    channels: Option<Vec<String>>,
+    persist: Option<bool>,
+    enabled: Option<bool>,
+}
+
+impl Validate for PipelineInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(name) = &self.name {
+            if let Err(e) = validate_name(name) {
+                errors.add("name", e);
+            }
+            errors.check(
+                name != NONE,
+                "name",
+                format!("{:?} is reserved for streams without a pipeline", NONE),
+            );
+        }
+        if let Some(Err(e)) = self.stages.as_deref().map(check) {
+            errors.add("stages", e);
+        }
+        for channel in self.channels.iter().flatten() {
+            if let Err(e) = validate_name(channel) {
+                errors.add("channels", format!("{:?}: {}", channel, e));
+            }
+        }
+    }
+}
+
+const COLUMNS: &str = "name, stages, channels, persist, enabled, created_at";
+
+/// Derived channel storing `channel` processed by `pipeline`.
+fn derived_name(channel: &str, pipeline: &str) -> String {
+    format!("{}_{}", channel, pipeline)
+}
+
+/// Checks that no other enabled pipeline is attached to the pipeline's
+/// channels and, for a persisted pipeline, creates the derived channels that
+/// do not exist yet; returns those to register once committed.
+async fn attach(conn: &mut PgConnection, pipeline: &Pipeline) -> Result<Vec<String>, ApiError> {
+    if pipeline.enabled {
+        let taken: Option<(String, String)> = sqlx::query_as(
+            "SELECT name, channel FROM pipelines, UNNEST(channels) AS channel \
+             WHERE enabled AND name <> $1 AND channel = ANY($2) LIMIT 1",
+        )
+        .bind(&pipeline.name)
+        .bind(&pipeline.channels)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some((other, channel)) = taken {
+            return Err(ApiError::conflict(format!(
+                "channel {:?} is already attached to pipeline {:?}",
+                channel, other
+            )));
+        }
+    }
+    if !pipeline.persist {
+        return Ok(Vec::new());
+    }
+    let derived: Vec<String> = pipeline
+        .channels
+        .iter()
+        .map(|c| derived_name(c, &pipeline.name))
+        .collect();
+    for name in &derived {
+        validate_name(name).map_err(|e| ApiError::bad_request(format!("derived channel {}", e)))?;
+    }
+    // Derived rates only exist for sources with a registered rate.
+    let factor = 1.0 / output_rate(&pipeline.stages, 1.0);
+    let created: Vec<(String,)> = sqlx::query_as(
+        "INSERT INTO channels (name, label, unit, sample_rate, enabled) \
+         SELECT d.name, COALESCE(c.label, c.name) || ' (' || $3 || ')', c.unit, c.sample_rate / $4, TRUE \
+         FROM UNNEST($1::text[], $2::text[]) AS d(source, name) JOIN channels c ON c.name = d.source \
+         ON CONFLICT (name) DO NOTHING RETURNING name",
+    )
+    .bind(&pipeline.channels)
+    .bind(&derived)
+    .bind(&pipeline.name)
+    .bind(factor)
+    .fetch_all(conn)
+    .await
+    ?;
+    Ok(created.into_iter().map(|(name,)| name).collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/pipelines",
+    tag = "pipelines",
+    responses((status = 200, body = Vec<Pipeline>))
+)]
+pub async fn list_pipelines(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Pipeline>>, ApiError> {
+    let pipelines = sqlx::query_as(&format!("SELECT {} FROM pipelines ORDER BY name", COLUMNS))
+        .fetch_all(&state.pool)
+        .await?;
+    Ok(Json(pipelines))
+}
+
+fn not_found(name: &str) -> ApiError {
+    ApiError::not_found(format!("unknown pipeline {:?}", name))
+}
+
+#[utoipa::path(
+    get,
+    path = "/pipelines/{name}",
+    tag = "pipelines",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = Pipeline),
+        (status = 404, description = "Unknown pipeline")
+    )
+)]
+pub async fn get_pipeline(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<Json<Pipeline>, ApiError> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM pipelines WHERE name = $1",
+        COLUMNS
+    ))
+    .bind(&name)
+    .fetch_optional(&state.pool)
+    .await?
+    .map(Json)
+    .ok_or_else(|| not_found(&name))
+}
+
+#[utoipa::path(
+    post,
+    path = "/pipelines",
+    tag = "pipelines",
+    request_body = PipelineInput,
+    responses(
+        (status = 201, body = Pipeline),
+        (status = 400, description = "A derived channel name is invalid"),
+        (status = 409, description = "Name taken, or a channel has another enabled pipeline"),
+        (status = 422, description = "Missing or invalid name, stages or channels, named in `details.fields`")
+    )
+)]
+pub async fn create_pipeline(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<PipelineInput>>,
+) -> Result<(StatusCode, Json<Pipeline>), ApiError> {
+    let mut errors = FieldErrors::default();
+    errors.check(input.name.is_some(), "name", "is required");
+    errors.check(input.stages.is_some(), "stages", "is required");
+    let (Some(name), Some(stages)) = (input.name, input.stages) else {
+        return Err(errors.into());
+    };
+
+    let mut tx = state.pool.begin().await?;
+    let pipeline: Option<Pipeline> = sqlx::query_as(&format!(
+        "INSERT INTO pipelines (name, stages, channels, persist, enabled) \
+         VALUES ($1, $2, COALESCE($3, '{{}}'), COALESCE($4, FALSE), COALESCE($5, TRUE)) \
+         ON CONFLICT (name) DO NOTHING RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(Jsonb(stages))
+    .bind(&input.channels)
+    .bind(input.persist)
+    .bind(input.enabled)
+    .fetch_optional(&mut tx)
+    .await?;
+    let pipeline = pipeline
+        .ok_or_else(|| ApiError::conflict(format!("pipeline {:?} already exists", name)))?;
+    let created = attach(&mut tx, &pipeline).await?;
+    tx.commit().await?;
+    channels::register(&created);
+    Ok((StatusCode::CREATED, Json(pipeline)))
+}
+
+/// Updates the given fields; omitted fields keep their value.
+#[utoipa::path(
+    put,
+    path = "/pipelines/{name}",
+    tag = "pipelines",
+    params(("name" = String, Path)),
+    request_body = PipelineInput,
+    responses(
+        (status = 200, body = Pipeline),
+        (status = 400, description = "Another name in the body, or a derived channel name is invalid"),
+        (status = 404, description = "Unknown pipeline"),
+        (status = 409, description = "A channel has another enabled pipeline"),
+        (status = 422, description = "Invalid stages or channels, named in `details.fields`")
+    )
+)]
+pub async fn update_pipeline(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+    Valid(Json(input)): Valid<Json<PipelineInput>>,
+) -> Result<Json<Pipeline>, ApiError> {
+    if input.name.as_deref().is_some_and(|n| n != name) {
+        return Err(ApiError::bad_request("pipelines cannot be renamed"));
+    }
+    let mut tx = state.pool.begin().await?;
+    let current: Pipeline = sqlx::query_as(&format!(
+        "SELECT {} FROM pipelines WHERE name = $1 FOR UPDATE",
+        COLUMNS
+    ))
+    .bind(&name)
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| not_found(&name))?;
+    let updated = Pipeline {
+        stages: input.stages.map(Jsonb).unwrap_or(current.stages),
+        channels: input.channels.unwrap_or(current.channels),
+        persist: input.persist.unwrap_or(current.persist),
+        enabled: input.enabled.unwrap_or(current.enabled),
+        ..current
+    };
+    let created = attach(&mut tx, &updated).await?;
+    sqlx::query("UPDATE pipelines SET stages = $2, channels = $3, persist = $4, enabled = $5 WHERE name = $1")
+        .bind(&name)
+        .bind(&updated.stages)
+        .bind(&updated.channels)
+        .bind(updated.persist)
+        .bind(updated.enabled)
+        .execute(&mut tx)
+        .await
+        ?;
+    tx.commit().await?;
+    channels::register(&created);
+    Ok(Json(updated))
+}
+
+/// Deletes a pipeline; derived channels and their samples are kept.
+#[utoipa::path(
+    delete,
+    path = "/pipelines/{name}",
+    tag = "pipelines",
+    params(("name" = String, Path)),
+    responses(
+        (status = 204, description = "Deleted; derived channels are kept"),
+        (status = 404, description = "Unknown pipeline")
+    )
+)]
+pub async fn delete_pipeline(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM pipelines WHERE name = $1")
+        .bind(&name)
+        .execute(&state.pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(not_found(&name));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A stored sample read by a persisted pipeline.
+#[derive(sqlx::FromRow)]
+struct Source {
+    id: i32,
+    ts: DateTime<Utc>,
+    value: f64,
+    session_id: Option<i32>,
+}
+
+impl Point for Source {
+    fn ts(&self) -> DateTime<Utc> {
+        self.ts
+    }
+
+    fn value_mut(&mut self) -> &mut f64 {
+        &mut self.value
+    }
+}
+
+/// A persisted pipeline running on one channel.
+struct Persisted {
+    live: Live,
+    since_id: i32,
+}
+
+/// One pass over the persisted pipelines: new samples of each attached
+/// channel are processed and sent to `writer`.
+async fn persist(
+    pool: &PgPool,
+    writer: &mpsc::Sender<Vec<NewSample>>,
+    running: &mut HashMap<(String, String), Persisted>,
+) -> Result<(), sqlx::Error> {
+    let pipelines: Vec<(String, Jsonb<Vec<Stage>>, Vec<String>)> =
+        sqlx::query_as("SELECT name, stages, channels FROM pipelines WHERE enabled AND persist")
+            .fetch_all(pool)
+            .await?;
+    running.retain(|(name, channel), persisted| {
+        pipelines.iter().any(|(n, stages, channels)| {
+            n == name && stages.0 == persisted.live.stages && channels.contains(channel)
+        })
+    });
+
+    for (name, stages, channels) in pipelines {
+        for channel in channels {
+            let key = (name.clone(), channel.clone());
+            if !running.contains_key(&key) {
+                // Start from the newest sample: derived channels are not backfilled.
+                let (since_id,): (i32,) = sqlx::query_as(
+                    "SELECT COALESCE(max(id), 0) FROM eeg_samples WHERE channel = $1",
+                )
+                .bind(&channel)
+                .fetch_one(pool)
+                .await?;
+                running.insert(
+                    key.clone(),
+                    Persisted {
+                        live: Live::new(Some(name.clone()), stages.0.clone()),
+                        since_id,
+                    },
+                );
+            }
+            let Some(persisted) = running.get_mut(&key) else {
+                continue;
+            };
+            let sources: Vec<Source> = sqlx::query_as(
+                "SELECT id, ts, value, session_id FROM eeg_samples \
+                 WHERE channel = $1 AND id > $2 ORDER BY id LIMIT $3",
+            )
+            .bind(&channel)
+            .bind(persisted.since_id)
+            .bind(PERSIST_BATCH)
+            .fetch_all(pool)
+            .await?;
+            let Some(last) = sources.last().map(|s| s.id) else {
+                continue;
+            };
+            persisted.since_id = last;
+            let derived = derived_name(&channel, &name);
+            let outputs = match persisted
+                .live
+                .apply(pool, &channel, &SampleFilter::default(), sources)
+                .await
+            {
+                Ok(outputs) => outputs,
+                Err(ApiError { message: e, .. }) => {
+                    tracing::error!("pipeline {:?} on {:?} failed: {}", name, channel, e);
+                    continue;
+                }
+            };
+            let samples: Vec<NewSample> = outputs
+                .into_iter()
+                .map(|s| NewSample {
+                    channel: derived.clone(),
+                    ts: s.ts,
+                    value: s.value,
+                    session_id: s.session_id,
+                })
+                .collect();
+            if !samples.is_empty() && writer.send(samples).await.is_err() {
+                tracing::error!("pipeline writer stopped");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Starts the task that stores the output of persisted pipelines.
+pub fn spawn(pool: PgPool) {
+    let writer = ingest::spawn_writer(pool.clone(), "pipelines");
+    tokio::spawn(async move {
+        let mut running = HashMap::new();
+        let mut ticker = tokio::time::interval(PERSIST_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = persist(&pool, &writer, &mut running).await {
+                tracing::error!("persisted pipelines failed: {}", e);
+            }
+        }
+    });
+}
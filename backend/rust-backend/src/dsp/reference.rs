@@ -0,0 +1,310 @@
+//! Re-referencing (`reference=` / `bipolar=`) on `/samples`,
+//! `/samples/filter` and the live endpoints.
+//!
+//! Samples are stored against whatever reference the amplifier used. A
+//! re-referenced sample is the stored value minus the mean of the reference
+//! channels' samples at the same timestamp, so it needs every reference
+//! channel to have a sample at exactly that instant; samples without one are
+//! dropped. The reference is subtracted before any notch, artifact or
+//! pipeline processing.
+//!
+//! - `reference=average`: common average reference over the requested
+//!   channels.
+//! - `reference=M1,M2`: the mean of the listed channels, e.g. linked
+//!   mastoids; a single channel gives that channel's reference.
+//! - `bipolar=Fp1-F3,F3-C3`: each pair's first channel minus its second,
+//!   returned under the pair's name instead of the `channel` list.
+//! - `reference=none`: the stored values.
+//! - `montage=double_banana`: the derivations of a stored
+//!   [montage](crate::montages), all of them or those named by `channel`.
+
+use super::notch::Point;
+use crate::error::ApiError;
+use crate::store::blocks;
+use crate::{channels, config, montages, ChannelQuery, SampleFilter};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sqlx::{PgPool, QueryBuilder};
+use std::collections::HashMap;
+use utoipa::IntoParams;
+
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReferenceQuery {
+    /// `average`, or the comma-separated reference channels.
+    pub reference: Option<String>,
+    /// Comma-separated `anode-cathode` channel pairs.
+    pub bipolar: Option<String>,
+    /// Stored montage whose derivations are returned.
+    pub montage: Option<String>,
+}
+
+/// One returned signal: `channel` minus the mean of `reference`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    /// Name the signal is returned under.
+    pub name: String,
+    /// Channel whose samples (ids, timestamps, events) the signal follows.
+    pub channel: String,
+    /// Reference channels; empty for the stored values.
+    pub reference: Vec<String>,
+}
+
+/// Reference channels for `text`: `none`, `average` (the mean of
+/// `channels`) or a comma-separated list.
+pub fn parse_reference(text: &str, channels: &[String]) -> Result<Vec<String>, String> {
+    match text.trim() {
+        "none" => Ok(Vec::new()),
+        "average" if channels.len() < 2 => {
+            Err("reference=average needs at least 2 channels".to_string())
+        }
+        "average" => Ok(channels.to_vec()),
+        list => parse_channels(list, "reference"),
+    }
+}
+
+/// Distinct comma-separated channel names.
+fn parse_channels(text: &str, what: &str) -> Result<Vec<String>, String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in text.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    if names.is_empty() {
+        return Err(format!("{} needs at least one channel", what));
+    }
+    if names.len() > config::get().limits.max_query_channels {
+        return Err(format!(
+            "{} accepts at most {} channels",
+            what,
+            config::get().limits.max_query_channels
+        ));
+    }
+    Ok(names)
+}
+
+/// Splits `anode-cathode`. Channel names may contain `-`, so with several
+/// the split is the one whose halves are both registered channels.
+fn parse_pair(pair: &str) -> Result<(String, String), String> {
+    let splits: Vec<(&str, &str)> = pair
+        .match_indices('-')
+        .map(|(i, _)| (&pair[..i], &pair[i + 1..]))
+        .filter(|(anode, cathode)| !anode.is_empty() && !cathode.is_empty())
+        .collect();
+    let known: Vec<&(&str, &str)> = splits
+        .iter()
+        .filter(|(anode, cathode)| channels::is_known(anode) && channels::is_known(cathode))
+        .collect();
+    let split = match (splits.as_slice(), known.as_slice()) {
+        (_, [split]) => **split,
+        ([split], []) => *split,
+        ([], _) => {
+            return Err(format!(
+                "bipolar pair {:?} must be two channels joined by '-'",
+                pair
+            ))
+        }
+        _ => return Err(format!("bipolar pair {:?} is ambiguous", pair)),
+    };
+    Ok((split.0.to_string(), split.1.to_string()))
+}
+
+/// The derivation of `montage` named `name`.
+fn find(derivations: &[Derivation], montage: &str, name: &str) -> Result<Derivation, ApiError> {
+    derivations
+        .iter()
+        .find(|d| d.name == name)
+        .cloned()
+        .ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "montage {:?} has no derivation {:?}",
+                montage, name
+            ))
+        })
+}
+
+impl ReferenceQuery {
+    /// The signals a multichannel request returns for its `channel` values.
+    pub async fn derivations(
+        &self,
+        pool: &PgPool,
+        raw: &ChannelQuery,
+    ) -> Result<Vec<Derivation>, ApiError> {
+        let Some(montage) = &self.montage else {
+            return self.direct(raw).map_err(ApiError::bad_request);
+        };
+        let derivations = self.montage_derivations(pool, montage).await?;
+        let mut picked: Vec<Derivation> = Vec::new();
+        for name in raw.values("channel") {
+            if !picked.iter().any(|d| d.name == name) {
+                picked.push(find(&derivations, montage, name)?);
+            }
+        }
+        Ok(if picked.is_empty() {
+            derivations
+        } else {
+            picked
+        })
+    }
+
+    /// The derivation a single-channel stream of `channel` carries: with a
+    /// montage, the one `channel` names; otherwise `channel` against
+    /// `reference`, where a bipolar pair is the anode with its cathode as
+    /// `reference`.
+    pub async fn stream(&self, pool: &PgPool, channel: &str) -> Result<Derivation, ApiError> {
+        let bad_request = ApiError::bad_request;
+        if let Some(montage) = &self.montage {
+            let derivations = self.montage_derivations(pool, montage).await?;
+            return find(&derivations, montage, channel);
+        }
+        if self.bipolar.is_some() {
+            return Err(bad_request(
+                "streams take one channel; use reference=<cathode> for a bipolar pair".to_string(),
+            ));
+        }
+        let reference = match self.reference.as_deref() {
+            None => Vec::new(),
+            Some(text) if text.trim() == "average" => {
+                return Err(bad_request(
+                    "reference=average needs several channels; list the reference channels \
+                     instead"
+                        .to_string(),
+                ))
+            }
+            Some(text) => parse_reference(text, &[]).map_err(bad_request)?,
+        };
+        Ok(Derivation {
+            name: channel.to_string(),
+            channel: channel.to_string(),
+            reference,
+        })
+    }
+
+    async fn montage_derivations(
+        &self,
+        pool: &PgPool,
+        montage: &str,
+    ) -> Result<Vec<Derivation>, ApiError> {
+        if self.reference.is_some() || self.bipolar.is_some() {
+            return Err(ApiError::bad_request(
+                "montage cannot be combined with reference or bipolar",
+            ));
+        }
+        montages::derivations(pool, montage).await
+    }
+
+    /// Derivations of `reference` / `bipolar` without a montage.
+    fn direct(&self, raw: &ChannelQuery) -> Result<Vec<Derivation>, String> {
+        if let Some(bipolar) = &self.bipolar {
+            if self.reference.is_some() {
+                return Err("bipolar cannot be combined with reference".to_string());
+            }
+            if !raw.values("channel").is_empty() {
+                return Err("bipolar replaces channel; give one or the other".to_string());
+            }
+            let pairs = parse_channels(bipolar, "bipolar")?;
+            return pairs
+                .iter()
+                .map(|pair| {
+                    let (anode, cathode) = parse_pair(pair)?;
+                    Ok(Derivation {
+                        name: pair.clone(),
+                        channel: anode,
+                        reference: vec![cathode],
+                    })
+                })
+                .collect();
+        }
+        let channels = raw.channels()?;
+        let reference = match self.reference.as_deref() {
+            None => Vec::new(),
+            Some(text) => parse_reference(text, &channels)?,
+        };
+        Ok(channels
+            .into_iter()
+            .map(|channel| Derivation {
+                name: channel.clone(),
+                channel,
+                reference: reference.clone(),
+            })
+            .collect())
+    }
+}
+
+/// Subtracts the mean of the `reference` channels at each point's
+/// timestamp, dropping points where any of them has no sample.
+#[tracing::instrument(name = "reference", skip_all, fields(references = reference.len(), points = points.len()))]
+pub async fn apply<P: Point>(
+    pool: &PgPool,
+    reference: &[String],
+    filter: &SampleFilter,
+    points: &mut Vec<P>,
+) -> Result<(), ApiError> {
+    let span = points.iter().map(|p| p.ts()).fold(None, |span, ts| {
+        let (first, last) = span.unwrap_or((ts, ts));
+        Some((ts.min(first), ts.max(last)))
+    });
+    let Some((first, last)) = span else {
+        return Ok(());
+    };
+    if reference.is_empty() {
+        return Ok(());
+    }
+
+    // The window of the points, as rows and blocks are read alike.
+    let window = SampleFilter {
+        from: Some(filter.from.map_or(first, |from| from.max(first))),
+        to: Some(filter.to.map_or(last, |to| to.min(last)) + Duration::microseconds(1)),
+        ..*filter
+    };
+    let index: HashMap<&str, usize> = reference
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.as_str(), i))
+        .collect();
+    // Per timestamp: sum and count of the reference samples, and which
+    // reference channels have one.
+    let mut sums: HashMap<DateTime<Utc>, (f64, i64, Vec<bool>)> = HashMap::new();
+    let mut add = |ts: DateTime<Utc>, channel: &str, sum: f64, count: i64| {
+        let Some(&i) = index.get(channel) else {
+            return;
+        };
+        let entry = sums
+            .entry(ts)
+            .or_insert_with(|| (0.0, 0, vec![false; reference.len()]));
+        entry.0 += sum;
+        entry.1 += count;
+        entry.2[i] = true;
+    };
+
+    let mut query = QueryBuilder::new(
+        "SELECT ts, channel, sum(value), count(*) FROM eeg_samples WHERE channel = ANY(",
+    );
+    query.push_bind(reference.to_vec()).push(")");
+    window.push_to(&mut query);
+    query.push(" GROUP BY ts, channel");
+    let rows: Vec<(DateTime<Utc>, String, f64, i64)> =
+        query.build_query_as().fetch_all(pool).await?;
+    for (ts, channel, sum, count) in rows {
+        add(ts, &channel, sum, count);
+    }
+    blocks::for_each(pool, Some(reference), &window, |s| {
+        add(s.ts, &s.channel, s.value, 1)
+    })
+    .await?;
+    let means: HashMap<DateTime<Utc>, f64> = sums
+        .into_iter()
+        .filter(|(_, (_, _, seen))| seen.iter().all(|&seen| seen))
+        .map(|(ts, (sum, count, _))| (ts, sum / count as f64))
+        .collect();
+
+    points.retain_mut(|point| match means.get(&point.ts()) {
+        Some(mean) => {
+            *point.value_mut() -= mean;
+            true
+        }
+        None => false,
+    });
+    Ok(())
+}
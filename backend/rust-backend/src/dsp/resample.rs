@@ -0,0 +1,125 @@
+//! Resampling to an arbitrary rate (`resample=100`) on `/samples` and
+//! `/samples/filter`.
+//!
+//! Output instants lie on a grid of multiples of `1 / resample` seconds since
+//! the Unix epoch, so channels resampled to the same rate line up whatever
+//! their native rates and start times. Each output value is a windowed-sinc
+//! interpolation of the input, with the cutoff at [`ROLLOFF`] times the lower
+//! of the two Nyquist frequencies, so downsampling is anti-aliased by the
+//! same kernel. Input positions are read from the stored timestamps rather
+//! than a nominal rate, so clock drift over long windows does not shift the
+//! output. Segments split by gaps (see [`segments`](super::segments)) are
+//! resampled on their own and no instants are produced across a gap.
+
+use super::segments;
+use crate::{config, EegSample};
+use std::f64::consts::PI;
+
+/// Highest accepted `resample`, in Hz.
+pub const MAX_RATE: f64 = 20_000.0;
+
+/// Cutoff as a share of the lower Nyquist frequency.
+const ROLLOFF: f64 = 0.9;
+
+/// Zero crossings of the sinc on each side of the kernel centre.
+const ZERO_CROSSINGS: f64 = 16.0;
+
+/// Checks a requested output rate.
+pub fn check(rate: Option<f64>) -> Result<(), String> {
+    match rate {
+        Some(rate) if !(rate.is_finite() && rate > 0.0 && rate <= MAX_RATE) => Err(format!(
+            "must be a rate in Hz above 0 and at most {}",
+            MAX_RATE
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over `-1..=1`.
+fn blackman(x: f64) -> f64 {
+    let x = PI * (x + 1.0);
+    0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos()
+}
+
+/// `samples` (oldest first, of a channel at `rate` Hz) at `target` Hz. The
+/// returned samples keep the channel name and have no id (`0`). Fails if
+/// the output would exceed `limits.max_window_rows`.
+#[tracing::instrument(skip_all, fields(samples = samples.len(), rate = rate, target = target))]
+pub fn resample(samples: &[EegSample], rate: f64, target: f64) -> Result<Vec<EegSample>, String> {
+    let Some(channel) = samples.first().map(|s| s.channel.clone()) else {
+        return Ok(Vec::new());
+    };
+    let period_us = 1e6 / target;
+    let cutoff = ROLLOFF * 0.5 * rate.min(target) / rate;
+    let half = ZERO_CROSSINGS / (2.0 * cutoff);
+
+    let mut output = Vec::new();
+    for segment in segments(samples, rate) {
+        let samples = &samples[segment];
+        let micros: Vec<f64> = samples
+            .iter()
+            .map(|s| s.ts.timestamp_micros() as f64)
+            .collect();
+        let (first, last) = (micros[0], micros[micros.len() - 1]);
+        let mut k = (first / period_us).ceil() as i64;
+        let end = (last / period_us).floor() as i64;
+        if output.len() as i64 + (end - k + 1) > config::get().limits.max_window_rows {
+            return Err(format!(
+                "resampling to {} Hz gives more than {} samples per channel; narrow the window",
+                target,
+                config::get().limits.max_window_rows
+            ));
+        }
+        let mut next = 0;
+        while k <= end {
+            let t = k as f64 * period_us;
+            while next + 1 < micros.len() && micros[next + 1] <= t {
+                next += 1;
+            }
+            // Fractional input index of t, interpolating the timestamps.
+            let position = if next + 1 < micros.len() && micros[next + 1] > micros[next] {
+                next as f64 + (t - micros[next]) / (micros[next + 1] - micros[next])
+            } else {
+                next as f64
+            };
+            let low = (position - half).ceil().max(0.0) as usize;
+            let high = ((position + half).floor() as usize).min(samples.len() - 1);
+            let (mut sum, mut weights) = (0.0, 0.0);
+            for (i, sample) in samples.iter().enumerate().take(high + 1).skip(low) {
+                let d = i as f64 - position;
+                let w = sinc(2.0 * cutoff * d) * blackman(d / half);
+                sum += w * sample.value;
+                weights += w;
+            }
+            output.push(EegSample {
+                id: 0,
+                ts: chrono::DateTime::from_timestamp_micros(t.round() as i64)
+                    .unwrap_or(samples[next].ts),
+                channel: channel.clone(),
+                value: if weights != 0.0 { sum / weights } else { 0.0 },
+            });
+            k += 1;
+        }
+    }
+    Ok(output)
+}
+
+/// Resampled samples as JSON, with `"id": null` and `"artifact"` added when
+/// marked.
+pub fn to_json(samples: &[EegSample], marks: Option<&[bool]>) -> serde_json::Value {
+    let mut value = crate::analysis::artifacts::to_json(samples, marks);
+    if let Some(samples) = value.as_array_mut() {
+        for sample in samples {
+            sample["id"] = serde_json::Value::Null;
+        }
+    }
+    value
+}
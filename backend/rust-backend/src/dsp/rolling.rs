@@ -0,0 +1,101 @@
+//! Rolling statistics pushed with each batch of `/live/ws` and `/live/sse`
+//! (`stats=true`).
+//!
+//! They cover the points streamed in the [`WINDOW_MS`] up to the newest
+//! one, as sent: after the reference, notch, pipeline and unit. A point
+//! older than the previous one, as after a change of channel, starts the
+//! window over.
+
+use crate::LivePoint;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Length of the window, in milliseconds.
+pub const WINDOW_MS: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Stats {
+    /// Points in the window.
+    pub samples: usize,
+    /// Root mean square after removing the mean.
+    pub rms: f64,
+    /// Root mean square of the component at the mains frequency, from the
+    /// window's Fourier coefficient there; null for fewer than 3 points or
+    /// a mains frequency at or above the window's Nyquist frequency.
+    pub line_noise: Option<f64>,
+}
+
+/// The window of one stream.
+#[derive(Debug, Clone)]
+pub struct Rolling {
+    /// Mains frequency in Hz.
+    line: f64,
+    points: VecDeque<(DateTime<Utc>, f64)>,
+}
+
+impl Rolling {
+    pub fn new(line: f64) -> Self {
+        Rolling {
+            line,
+            points: VecDeque::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.points.clear();
+    }
+
+    /// Adds `points` to the window and returns its statistics, or `None`
+    /// while it is empty.
+    pub fn push(&mut self, points: &[LivePoint]) -> Option<Stats> {
+        for point in points {
+            if self.points.back().is_some_and(|(ts, _)| point.ts < *ts) {
+                self.points.clear();
+            }
+            self.points.push_back((point.ts, point.value));
+        }
+        let newest = self.points.back()?.0;
+        let start = newest - Duration::milliseconds(WINDOW_MS);
+        while self.points.front().is_some_and(|(ts, _)| *ts <= start) {
+            self.points.pop_front();
+        }
+        Some(self.stats())
+    }
+
+    fn stats(&self) -> Stats {
+        let n = self.points.len() as f64;
+        let mean = self.points.iter().map(|(_, v)| v).sum::<f64>() / n;
+        let rms = (self
+            .points
+            .iter()
+            .map(|(_, v)| (v - mean) * (v - mean))
+            .sum::<f64>()
+            / n)
+            .sqrt();
+        Stats {
+            samples: self.points.len(),
+            rms,
+            line_noise: self.line_noise(mean),
+        }
+    }
+
+    /// `sqrt(2) |X(line)| / n` with `X` taken at the points' own times, so
+    /// uneven spacing does not shift the frequency.
+    fn line_noise(&self, mean: f64) -> Option<f64> {
+        let (first, last) = (self.points.front()?.0, self.points.back()?.0);
+        let span = (last - first).num_microseconds()? as f64 / 1e6;
+        let n = self.points.len();
+        if n < 3 || span <= 0.0 || self.line >= (n - 1) as f64 / span / 2.0 {
+            return None;
+        }
+        let (mut re, mut im) = (0.0, 0.0);
+        for (ts, value) in &self.points {
+            let t = (*ts - first).num_microseconds()? as f64 / 1e6;
+            let phase = std::f64::consts::TAU * self.line * t;
+            re += (value - mean) * phase.cos();
+            im -= (value - mean) * phase.sin();
+        }
+        Some(std::f64::consts::SQRT_2 * re.hypot(im) / n as f64)
+    }
+}
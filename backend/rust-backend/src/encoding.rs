@@ -0,0 +1,107 @@
+//! Binary encodings of the sample responses, picked by the `Accept` header.
+//!
+//! `/samples` and `/live` answer in JSON unless the client asks for
+//! MessagePack (`application/msgpack`) or CBOR (`application/cbor`). Both
+//! carry the same structure as the JSON body, with the same field names and
+//! timestamps as RFC 3339 strings, so a client can switch by changing its
+//! decoder. Values are 64-bit floats and ids integers, which is where most of
+//! the saving over JSON text comes from. Errors stay JSON.
+
+use crate::error::ApiError;
+use axum::{
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MsgPack)
+            }
+            "application/cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The format with the highest `q` among those `Accept` lists, the first
+    /// listed on a tie. JSON when there is no `Accept` or it names none of
+    /// them.
+    pub fn from_headers(headers: &HeaderMap) -> Format {
+        let mut best = (Format::Json, 0.0);
+        for range in headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let mut parts = range.split(';');
+            let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let Some(format) = Format::from_media_type(&media_type) else {
+                continue;
+            };
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+}
+
+/// A response body in the negotiated format.
+pub struct Encoded<T>(pub Format, pub T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let Encoded(format, body) = self;
+        let bytes = match format {
+            Format::Json => {
+                let mut response = Json(body).into_response();
+                response
+                    .headers_mut()
+                    .insert(header::VARY, HeaderValue::from_static("accept"));
+                return response;
+            }
+            Format::MsgPack => rmp_serde::to_vec_named(&body).map_err(ApiError::internal),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(&body, &mut bytes)
+                    .map(|()| bytes)
+                    .map_err(ApiError::internal)
+            }
+        };
+        match bytes {
+            Ok(bytes) => (
+                [
+                    (header::CONTENT_TYPE, format.content_type()),
+                    (header::VARY, "accept"),
+                ],
+                bytes,
+            )
+                .into_response(),
+            Err(e) => e.into_response(),
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Stored epoch sets (`epoch_sets` and `epochs` tables): epochs cut by
+//! [`analysis::epochs`](crate::analysis::epochs) and kept as a derived
+//! dataset, e.g. for training models on the same trials repeatedly.
+//!
+//! `POST /epoch-sets` takes the parameters of `GET /analysis/epochs`; the
+//! set records them with the channels, sample rate and time axis, and each
+//! epoch is stored as one row of `channels × times` values (channel-major).
+
+use crate::analysis::epochs::{extract, EpochQuery};
+use crate::error::ApiError;
+use crate::validation::{check_limit, FieldErrors, Parsed, Valid, Validate};
+use crate::{AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::types::Json as Jsonb;
+use utoipa::{IntoParams, ToSchema};
+
+const MAX_NAME_LEN: usize = 128;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct EpochSet {
+    pub id: i32,
+    pub name: Option<String>,
+    pub session_id: Option<i32>,
+    pub channels: Vec<String>,
+    pub sample_rate: f64,
+    /// Seconds from the onset of each epoch sample.
+    pub times: Vec<f64>,
+    /// Stored epochs.
+    pub epochs: i32,
+    pub rejected: i32,
+    /// Epoching parameters and window the set was cut with.
+    #[schema(value_type = Object)]
+    pub params: Jsonb<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EpochSetQuery {
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EpochPage {
+    offset: Option<i64>,
+    limit: Option<i64>,
+}
+
+impl Validate for EpochPage {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            self.offset.is_none_or(|offset| offset >= 0),
+            "offset",
+            "must not be negative",
+        );
+        check_limit(errors, self.limit);
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredEpoch {
+    epoch: i32,
+    onset: DateTime<Utc>,
+    label: Option<String>,
+    data: Vec<f64>,
+}
+
+const COLUMNS: &str =
+    "id, name, session_id, channels, sample_rate, times, epochs, rejected, params, created_at";
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    // 23503: foreign_key_violation on session_id.
+    match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            ApiError::bad_request("unknown session_id")
+        }
+        _ => e.into(),
+    }
+}
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::not_found(format!("unknown epoch set {}", id))
+}
+
+async fn fetch(state: &AppState, id: i32) -> Result<EpochSet, ApiError> {
+    sqlx::query_as(&format!("SELECT {} FROM epoch_sets WHERE id = $1", COLUMNS))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| not_found(id))
+}
+
+/// Cuts epochs as `GET /analysis/epochs` does and stores them; `201` with
+/// the set, `400` if no epoch was kept.
+#[utoipa::path(
+    post,
+    path = "/epoch-sets",
+    tag = "epoch sets",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        EpochSetQuery,
+        EpochQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 201, body = EpochSet),
+        (status = 400, description = "Invalid parameters, unknown session, or no epoch kept"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn create_epoch_set(
+    State(state): State<AppState>,
+    Parsed(Query(set)): Parsed<Query<EpochSetQuery>>,
+    Parsed(Query(params)): Parsed<Query<EpochQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<(StatusCode, Json<EpochSet>), ApiError> {
+    let bad_request = ApiError::bad_request;
+    if let Some(name) = &set.name {
+        if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(bad_request(format!(
+                "name must be 1 to {} characters",
+                MAX_NAME_LEN
+            )));
+        }
+    }
+    let channels = raw.channels().map_err(bad_request)?;
+    let options = params.options().map_err(bad_request)?;
+    let extracted = extract(&state, channels, &filter, &options).await?;
+    let Some(sample_rate) = extracted
+        .sample_rate
+        .filter(|_| !extracted.epochs.is_empty())
+    else {
+        return Err(bad_request(format!(
+            "no epoch was kept of {} candidates",
+            extracted.candidates
+        )));
+    };
+
+    let mut recorded = serde_json::to_value(&params).map_err(|e| bad_request(e.to_string()))?;
+    recorded["from"] = json!(filter.from);
+    recorded["to"] = json!(filter.to);
+    recorded["subject_id"] = json!(filter.subject_id);
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+    let stored: EpochSet = sqlx::query_as(&format!(
+        "INSERT INTO epoch_sets \
+         (name, session_id, channels, sample_rate, times, epochs, rejected, params) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&set.name)
+    .bind(filter.session_id)
+    .bind(&extracted.channels)
+    .bind(sample_rate)
+    .bind(&extracted.times)
+    .bind(extracted.epochs.len() as i32)
+    .bind(extracted.rejected as i32)
+    .bind(Jsonb(recorded))
+    .fetch_one(&mut tx)
+    .await
+    .map_err(db_error)?;
+
+    let per_epoch = (extracted.channels.len() * extracted.times.len()) as i32;
+    let indices: Vec<i32> = (0..extracted.epochs.len() as i32).collect();
+    let onsets: Vec<DateTime<Utc>> = extracted.epochs.iter().map(|e| e.onset.onset).collect();
+    let labels: Vec<Option<String>> = extracted
+        .epochs
+        .iter()
+        .map(|e| e.onset.label.clone())
+        .collect();
+    let values: Vec<f64> = extracted
+        .epochs
+        .iter()
+        .flat_map(|e| e.data.iter().flatten().copied())
+        .collect();
+    sqlx::query(
+        "INSERT INTO epochs (set_id, epoch, onset, label, data) \
+         SELECT $1, t.epoch, t.onset, t.label, ($5::float8[])[t.epoch * $6 + 1 : (t.epoch + 1) * $6] \
+         FROM UNNEST($2::int4[], $3::timestamptz[], $4::text[]) AS t (epoch, onset, label)",
+    )
+    .bind(stored.id)
+    .bind(&indices)
+    .bind(&onsets)
+    .bind(&labels)
+    .bind(&values)
+    .bind(per_epoch)
+    .execute(&mut tx)
+    .await
+    .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+    Ok((StatusCode::CREATED, Json(stored)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/epoch-sets",
+    tag = "epoch sets",
+    responses((status = 200, body = Vec<EpochSet>))
+)]
+pub async fn list_epoch_sets(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<EpochSet>>, ApiError> {
+    let sets = sqlx::query_as(&format!(
+        "SELECT {} FROM epoch_sets ORDER BY id DESC",
+        COLUMNS
+    ))
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(sets))
+}
+
+#[utoipa::path(
+    get,
+    path = "/epoch-sets/{id}",
+    tag = "epoch sets",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, body = EpochSet),
+        (status = 404, description = "Unknown epoch set")
+    )
+)]
+pub async fn get_epoch_set(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<Json<EpochSet>, ApiError> {
+    fetch(&state, id).await.map(Json)
+}
+
+/// A page of the set's epochs, in onset order: `data[channel][sample]`.
+#[utoipa::path(
+    get,
+    path = "/epoch-sets/{id}/epochs",
+    tag = "epoch sets",
+    params(("id" = i32, Path), EpochPage),
+    responses(
+        (status = 200, description = "`{ id, channels, sample_rate, times, total, offset, epochs: [{ epoch, onset, label, data }] }` with `data[channel][sample]`", body = serde_json::Value),
+        (status = 404, description = "Unknown epoch set"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_epoch_set_epochs(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Valid(Query(page)): Valid<Query<EpochPage>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let set = fetch(&state, id).await?;
+    let offset = page.offset.unwrap_or(0);
+    let limit = page.limit.unwrap_or(100);
+    let stored: Vec<StoredEpoch> = sqlx::query_as(
+        "SELECT epoch, onset, label, data FROM epochs WHERE set_id = $1 \
+         ORDER BY epoch OFFSET $2 LIMIT $3",
+    )
+    .bind(id)
+    .bind(offset)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let width = set.times.len().max(1);
+    let epochs: Vec<serde_json::Value> = stored
+        .into_iter()
+        .map(|e| {
+            let data: Vec<&[f64]> = e.data.chunks(width).collect();
+            json!({ "epoch": e.epoch, "onset": e.onset, "label": e.label, "data": data })
+        })
+        .collect();
+    Ok(Json(json!({
+        "id": set.id,
+        "channels": set.channels,
+        "sample_rate": set.sample_rate,
+        "times": set.times,
+        "total": set.epochs,
+        "offset": offset,
+        "epochs": epochs,
+    })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/epoch-sets/{id}",
+    tag = "epoch sets",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Deleted with its epochs"),
+        (status = 404, description = "Unknown epoch set")
+    )
+)]
+pub async fn delete_epoch_set(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM epoch_sets WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(not_found(id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
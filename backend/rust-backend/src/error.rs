@@ -0,0 +1,154 @@
+//! Error responses of the REST routes.
+//!
+//! Handlers fail with an [`ApiError`], answered as
+//! `{"code": "not_found", "message": "unknown session 7", "details": null}`
+//! with the matching status. `code` is the status's reason phrase in snake
+//! case (`bad_request`, `conflict`, ...), `message` says what went wrong
+//! in words meant for people, and `details`, when not null, holds data a
+//! client can act on, such as the violated constraint or how long to wait.
+//!
+//! Database errors are mapped by [`From<sqlx::Error>`]: a missing row is
+//! `404`, unique and foreign key violations `409`, check violations and
+//! invalid values `400`, and a pool that runs out of connections `503`.
+//! The remaining errors are `500`s: they are logged, and the client only
+//! sees `internal error`, so no SQL, constraint or connection details leak.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    /// The status's reason phrase in snake case, e.g. `not_found`.
+    pub code: String,
+    pub message: String,
+    /// Machine-readable context, or null.
+    #[schema(value_type = Object)]
+    pub details: Option<serde_json::Value>,
+}
+
+/// The message of every `500`.
+const INTERNAL_MESSAGE: &str = "internal error";
+
+/// `status`'s reason phrase in snake case.
+fn code(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_ascii_lowercase()
+        .replace(['-', ' '], "_")
+        .replace('\'', "")
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code: code(status),
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    /// A `500` for `error`, which is logged rather than sent.
+    pub fn internal(error: impl std::fmt::Display) -> Self {
+        tracing::error!("internal error: {}", error);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, INTERNAL_MESSAGE)
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => ApiError::not_found("no such row"),
+            sqlx::Error::PoolTimedOut => {
+                ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "database busy; try again")
+            }
+            sqlx::Error::Database(db) => {
+                let status = match db.code().as_deref() {
+                    // unique_violation, foreign_key_violation
+                    Some("23505") | Some("23503") => StatusCode::CONFLICT,
+                    // check_violation, not_null_violation
+                    Some("23514") | Some("23502") => StatusCode::BAD_REQUEST,
+                    // data exceptions: out of range, invalid text, ...
+                    Some(code) if code.starts_with("22") => StatusCode::BAD_REQUEST,
+                    _ => return ApiError::internal(e),
+                };
+                let error = ApiError::new(status, db.message());
+                match db.constraint() {
+                    Some(constraint) => error.with_details(json!({ "constraint": constraint })),
+                    None => error,
+                }
+            }
+            _ => ApiError::internal(e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        // 500s were logged with the error they stand for.
+        if self.status.is_server_error() && self.message != INTERNAL_MESSAGE {
+            tracing::error!(status = self.status.as_u16(), "{}", self.message);
+        }
+        (self.status, Json(&self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_snake_case_reason_phrases() {
+        assert_eq!(code(StatusCode::NOT_FOUND), "not_found");
+        assert_eq!(
+            code(StatusCode::UNPROCESSABLE_ENTITY),
+            "unprocessable_entity"
+        );
+        assert_eq!(code(StatusCode::IM_A_TEAPOT), "im_a_teapot");
+    }
+
+    #[test]
+    fn internal_errors_hide_their_cause() {
+        let error = ApiError::internal("relation \"eeg_samples\" does not exist");
+        assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.message, "internal error");
+        let error = ApiError::from(sqlx::Error::PoolClosed);
+        assert_eq!(error.message, "internal error");
+        assert_eq!(
+            ApiError::from(sqlx::Error::RowNotFound).status,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,364 @@
+//! Event markers and annotations (`events` table).
+//!
+//! An event marks an instant (`duration` 0) or a span starting at `ts`, such
+//! as a stimulus onset, an artifact or a seizure onset, optionally within a
+//! session and with free-form JSON `metadata`. `/samples?include_events=true`
+//! returns the events overlapping the sample window alongside the samples.
+
+use crate::error::ApiError;
+use crate::validation::{check_limit, FieldErrors, Parsed, Valid, Validate};
+use crate::{timezone, AppState, SampleFilter};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use utoipa::{IntoParams, ToSchema};
+
+const MAX_LABEL_LEN: usize = 128;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Event {
+    pub id: i32,
+    pub session_id: Option<i32>,
+    pub ts: DateTime<Utc>,
+    /// Length in seconds; 0 for instantaneous markers.
+    pub duration: f64,
+    pub label: String,
+    pub metadata: serde_json::Value,
+}
+
+/// Body of `POST /events` and `PATCH /events/{id}`; omitted fields are left
+/// unchanged on update.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EventInput {
+    session_id: Option<i32>,
+    ts: Option<DateTime<Utc>>,
+    duration: Option<f64>,
+    label: Option<String>,
+    metadata: Option<serde_json::Value>,
+}
+
+/// Typed part of `GET /events`; `from`, `to`, `session_id` and `subject_id`
+/// are read as a [`SampleFilter`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventListQuery {
+    label: Option<String>,
+    limit: Option<i64>,
+}
+
+impl Validate for EventListQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_limit(errors, self.limit);
+    }
+}
+
+const COLUMNS: &str = "id, session_id, ts, duration, label, metadata";
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    // 23503: foreign_key_violation on session_id.
+    match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            ApiError::bad_request("unknown session_id")
+        }
+        _ => e.into(),
+    }
+}
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::not_found(format!("unknown event {}", id))
+}
+
+impl Validate for EventInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(label) = &self.label {
+            errors.check(
+                !label.trim().is_empty() && label.len() <= MAX_LABEL_LEN,
+                "label",
+                format!("must be 1 to {} characters", MAX_LABEL_LEN),
+            );
+        }
+        if let Some(duration) = self.duration {
+            errors.check(
+                duration.is_finite() && duration >= 0.0,
+                "duration",
+                "must be a non-negative number of seconds",
+            );
+        }
+        if let Some(metadata) = &self.metadata {
+            errors.check(metadata.is_object(), "metadata", "must be a JSON object");
+        }
+    }
+}
+
+/// Appends the predicates selecting events that overlap `filter`'s window
+/// and belong to its session or subject.
+fn push_overlap(query: &mut QueryBuilder<'_, Postgres>, filter: &SampleFilter) {
+    if let Some(to) = filter.to {
+        query.push(" AND ts < ").push_bind(to);
+    }
+    if let Some(from) = filter.from {
+        query
+            .push(" AND ts + make_interval(secs => duration) >= ")
+            .push_bind(from);
+    }
+    if let Some(session_id) = filter.session_id {
+        query.push(" AND session_id = ").push_bind(session_id);
+    }
+    if let Some(subject_id) = filter.subject_id {
+        query
+            .push(" AND session_id IN (SELECT id FROM sessions WHERE subject_id = ")
+            .push_bind(subject_id)
+            .push(")");
+    }
+}
+
+/// An event produced by an analysis pass, tagged in `metadata` with the
+/// pass (`source`) and the channel it was found on.
+#[derive(Debug, Clone)]
+pub struct Detected {
+    pub session_id: Option<i32>,
+    pub ts: DateTime<Utc>,
+    pub duration: f64,
+    pub label: String,
+    pub metadata: serde_json::Value,
+}
+
+/// Replaces the events `source` found on `channel` earlier within the
+/// window and scope of `filter` by `events`, in one transaction. Returns the
+/// number of events removed and the inserted ones.
+pub async fn replace_detected(
+    pool: &PgPool,
+    source: &str,
+    channel: &str,
+    filter: &SampleFilter,
+    events: &[Detected],
+) -> Result<(u64, Vec<Event>), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut query = QueryBuilder::new("DELETE FROM events WHERE metadata->>'source' = ");
+    query
+        .push_bind(source)
+        .push(" AND metadata->>'channel' = ")
+        .push_bind(channel);
+    push_overlap(&mut query, filter);
+    let removed = query.build().execute(&mut tx).await?.rows_affected();
+
+    let sessions: Vec<Option<i32>> = events.iter().map(|e| e.session_id).collect();
+    let ts: Vec<DateTime<Utc>> = events.iter().map(|e| e.ts).collect();
+    let durations: Vec<f64> = events.iter().map(|e| e.duration).collect();
+    let labels: Vec<&str> = events.iter().map(|e| e.label.as_str()).collect();
+    let metadata: Vec<serde_json::Value> = events.iter().map(|e| e.metadata.clone()).collect();
+    let inserted = sqlx::query_as(&format!(
+        "INSERT INTO events (session_id, ts, duration, label, metadata) \
+         SELECT * FROM UNNEST($1::int4[], $2::timestamptz[], $3::float8[], $4::text[], $5::jsonb[]) \
+         RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&sessions)
+    .bind(&ts)
+    .bind(&durations)
+    .bind(&labels)
+    .bind(&metadata)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok((removed, inserted))
+}
+
+/// Events overlapping `from <= t < to` within the scope of `filter`, oldest first.
+#[tracing::instrument(skip_all, fields(otel.kind = "client", db.system = "postgresql"))]
+pub async fn overlapping(
+    pool: &PgPool,
+    filter: &SampleFilter,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<Event>, sqlx::Error> {
+    let window = SampleFilter {
+        from: Some(from),
+        to: Some(to),
+        ..*filter
+    };
+    let mut query = QueryBuilder::new(format!("SELECT {} FROM events WHERE TRUE", COLUMNS));
+    push_overlap(&mut query, &window);
+    query.push(" ORDER BY ts, id");
+    query.build_query_as().fetch_all(pool).await
+}
+
+/// Up to `limit` events with one of `labels` overlapping the window and
+/// within the scope of `filter`, oldest first.
+pub async fn labelled(
+    pool: &PgPool,
+    labels: &[String],
+    filter: &SampleFilter,
+    limit: i64,
+) -> Result<Vec<Event>, sqlx::Error> {
+    let mut query = QueryBuilder::new(format!("SELECT {} FROM events WHERE label = ANY(", COLUMNS));
+    query.push_bind(labels).push(")");
+    push_overlap(&mut query, filter);
+    query.push(" ORDER BY ts, id LIMIT ").push_bind(limit);
+    query.build_query_as().fetch_all(pool).await
+}
+
+/// Events ordered by time; `from` / `to` select events overlapping the window.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "events",
+    params(EventListQuery, SampleFilter, timezone::TimeQuery),
+    responses(
+        (status = 200, description = "Oldest first; `from` / `to` select events overlapping the window", body = Vec<Event>),
+        (status = 400, description = "Invalid filter"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn list_events(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<EventListQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut query = QueryBuilder::new(format!("SELECT {} FROM events WHERE TRUE", COLUMNS));
+    push_overlap(&mut query, &filter);
+    if let Some(label) = &params.label {
+        query.push(" AND label = ").push_bind(label);
+    }
+    query
+        .push(" ORDER BY ts, id LIMIT ")
+        .push_bind(params.limit.unwrap_or(100));
+    let events: Vec<Event> = query
+        .build_query_as()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(times.json(&events).map_err(ApiError::internal)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/events/{id}",
+    tag = "events",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, body = Event),
+        (status = 404, description = "Unknown event")
+    )
+)]
+pub async fn get_event(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<Json<Event>, ApiError> {
+    sqlx::query_as(&format!("SELECT {} FROM events WHERE id = $1", COLUMNS))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| not_found(id))
+}
+
+#[utoipa::path(
+    post,
+    path = "/events",
+    tag = "events",
+    request_body = EventInput,
+    responses(
+        (status = 201, body = Event),
+        (status = 400, description = "Unknown `session_id`"),
+        (status = 422, description = "Missing `ts` or `label`, or invalid field, named in `details.fields`")
+    )
+)]
+pub async fn create_event(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<EventInput>>,
+) -> Result<(StatusCode, Json<Event>), ApiError> {
+    let mut errors = FieldErrors::default();
+    errors.check(input.ts.is_some(), "ts", "is required");
+    errors.check(input.label.is_some(), "label", "is required");
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+
+    let event = sqlx::query_as(&format!(
+        "INSERT INTO events (session_id, ts, duration, label, metadata) \
+         VALUES ($1, $2, COALESCE($3, 0), $4, COALESCE($5, '{{}}'::jsonb)) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(input.session_id)
+    .bind(input.ts)
+    .bind(input.duration)
+    .bind(&input.label)
+    .bind(&input.metadata)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok((StatusCode::CREATED, Json(event)))
+}
+
+/// Updates the given fields; `metadata` replaces the stored object.
+#[utoipa::path(
+    patch,
+    path = "/events/{id}",
+    tag = "events",
+    params(("id" = i32, Path)),
+    request_body = EventInput,
+    responses(
+        (status = 200, body = Event),
+        (status = 400, description = "Unknown `session_id`"),
+        (status = 422, description = "Invalid field, named in `details.fields`"),
+        (status = 404, description = "Unknown event")
+    )
+)]
+pub async fn update_event(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Valid(Json(input)): Valid<Json<EventInput>>,
+) -> Result<Json<Event>, ApiError> {
+    sqlx::query_as(&format!(
+        "UPDATE events SET \
+         session_id = COALESCE($2, session_id), ts = COALESCE($3, ts), \
+         duration = COALESCE($4, duration), label = COALESCE($5, label), \
+         metadata = COALESCE($6, metadata) \
+         WHERE id = $1 RETURNING {}",
+        COLUMNS
+    ))
+    .bind(id)
+    .bind(input.session_id)
+    .bind(input.ts)
+    .bind(input.duration)
+    .bind(&input.label)
+    .bind(&input.metadata)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .map(Json)
+    .ok_or_else(|| not_found(id))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/events/{id}",
+    tag = "events",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "Unknown event")
+    )
+)]
+pub async fn delete_event(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM events WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(not_found(id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
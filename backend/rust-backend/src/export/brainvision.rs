@@ -0,0 +1,269 @@
+//! BrainVision Core Data Format writer
+//! (<https://www.brainproducts.com/support-resources/brainvision-core-data-format-1-0/>).
+//!
+//! A recording is a triplet of files that reference each other by name: the
+//! header (`.vhdr`), the markers (`.vmrk`) and the binary data (`.eeg`). The
+//! export sends them as one uncompressed tar archive, header and markers
+//! first, so the data is streamed last without knowing more than its size.
+//!
+//! BrainVision has a single sampling interval for all channels: the data is
+//! written on a grid at the highest channel rate, in the one-second records
+//! used by the EDF+ writer, as multiplexed little-endian 32-bit floats in the
+//! channel units. A sample fills the point nearest its timestamp and empty
+//! points repeat the previous value, so slower channels are held between
+//! their samples. Seconds without any sample are left out; a `New Segment`
+//! marker with the start time opens the file and every stretch after a gap.
+//! Events become `Comment` markers at the point of their onset.
+
+use super::{
+    fetch_window, place_record, seconds_between, seconds_with_samples, Chunks, ExportSample,
+    SessionExport,
+};
+use axum::body::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Records (seconds) read from the database and sent per chunk.
+const RECORDS_PER_CHUNK: usize = 30;
+
+const TAR_BLOCK: usize = 512;
+
+/// Text of a header or marker field; commas are coded as `\1`.
+fn text(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '\r' | '\n' => ' ',
+            c => c,
+        })
+        .collect::<String>()
+        .replace(',', "\\1")
+}
+
+/// `New Segment` date: `YYYYMMDDhhmmssuuuuuu`.
+fn segment_date(ts: DateTime<Utc>) -> String {
+    ts.format("%Y%m%d%H%M%S%6f").to_string()
+}
+
+fn vhdr(export: &SessionExport, name: &str, points_per_second: usize) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "Brain Vision Data Exchange Header File Version 1.0\r\n\
+         ; Session {}\r\n\
+         \r\n\
+         [Common Infos]\r\n\
+         Codepage=UTF-8\r\n\
+         DataFile={name}.eeg\r\n\
+         MarkerFile={name}.vmrk\r\n\
+         DataFormat=BINARY\r\n\
+         DataOrientation=MULTIPLEXED\r\n\
+         NumberOfChannels={}\r\n\
+         ; Sampling interval in microseconds\r\n\
+         SamplingInterval={}\r\n\
+         \r\n\
+         [Binary Infos]\r\n\
+         BinaryFormat=IEEE_FLOAT_32\r\n\
+         \r\n\
+         [Channel Infos]\r\n\
+         ; Ch<number>=<name>,<reference>,<resolution in unit>,<unit>\r\n",
+        export.session.id,
+        export.channels.len(),
+        1e6 / points_per_second as f64,
+    );
+    for (i, channel) in export.channels.iter().enumerate() {
+        let _ = write!(
+            out,
+            "Ch{}={},{},1,{}\r\n",
+            i + 1,
+            text(&channel.name),
+            text(channel.reference.as_deref().unwrap_or("")),
+            text(&channel.unit)
+        );
+    }
+    out
+}
+
+fn vmrk(name: &str, markers: &[(usize, String)]) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "Brain Vision Data Exchange Marker File, Version 1.0\r\n\
+         \r\n\
+         [Common Infos]\r\n\
+         Codepage=UTF-8\r\n\
+         DataFile={name}.eeg\r\n\
+         \r\n\
+         [Marker Infos]\r\n\
+         ; Mk<number>=<type>,<description>,<position>,<points>,<channel>[,<date>]\r\n"
+    );
+    for (i, (_, marker)) in markers.iter().enumerate() {
+        let _ = write!(out, "Mk{}={}\r\n", i + 1, marker);
+    }
+    out
+}
+
+/// A ustar header for a regular file. Sizes from 8 GiB up use the base-256
+/// encoding understood by GNU tar, bsdtar and Python's `tarfile`.
+fn tar_header(name: &str, size: u64, mtime: i64) -> [u8; TAR_BLOCK] {
+    fn octal(out: &mut [u8], value: u64) {
+        let digits = format!("{:0width$o}", value, width = out.len() - 1);
+        out[..digits.len()].copy_from_slice(digits.as_bytes());
+    }
+
+    let mut header = [0u8; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    if size < 1 << 33 {
+        octal(&mut header[124..136], size);
+    } else {
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+    octal(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    let digits = format!("{:06o}\0 ", checksum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+    header
+}
+
+/// Zero bytes that pad a member of `size` bytes to a whole block.
+fn tar_padding(size: u64) -> usize {
+    (TAR_BLOCK - (size % TAR_BLOCK as u64) as usize) % TAR_BLOCK
+}
+
+/// Writes `export` as a tar archive of a BrainVision triplet into `tx`.
+pub async fn write(pool: PgPool, export: SessionExport, tx: Chunks) -> Result<(), String> {
+    let id = export.session.id;
+    let factors = export.factors();
+    let name = format!("session-{}", id);
+    let start = Utc
+        .timestamp_opt(export.first_ts.timestamp(), 0)
+        .single()
+        .ok_or("invalid start time")?;
+    let rate = export
+        .channels
+        .iter()
+        .map(|c| c.sample_rate)
+        .fold(0.0, f64::max);
+    let points_per_second = (rate.round() as usize).max(1);
+
+    let written = seconds_with_samples(&pool, id, start)
+        .await
+        .map_err(|e| e.to_string())?;
+    let record_start = |r: i64| start + chrono::Duration::microseconds((r as f64 * 1e6) as i64);
+
+    // Markers with their 1-based position, ordered by position.
+    let mut markers: Vec<(usize, String)> = Vec::new();
+    for (i, &record) in written.iter().enumerate() {
+        if i == 0 || written[i - 1] + 1 != record {
+            let position = i * points_per_second + 1;
+            markers.push((
+                position,
+                format!(
+                    "New Segment,,{},1,0,{}",
+                    position,
+                    segment_date(record_start(record))
+                ),
+            ));
+        }
+    }
+    let total_points = written.len() * points_per_second;
+    for event in &export.events {
+        let offset = seconds_between(start, event.ts);
+        let record = offset.floor() as i64;
+        let index = written.partition_point(|&r| r <= record);
+        let position = if index == 0 {
+            1
+        } else if written[index - 1] == record {
+            let within = ((offset - record as f64) * points_per_second as f64).round() as usize;
+            (index - 1) * points_per_second + within.min(points_per_second - 1) + 1
+        } else {
+            // In a gap: the last point before it.
+            index * points_per_second
+        };
+        let points = ((event.duration * points_per_second as f64).round() as usize).max(1);
+        markers.push((
+            position,
+            format!("Comment,{},{},{},0", text(&event.label), position, points),
+        ));
+    }
+    markers.sort_by_key(|(position, _)| *position);
+
+    let mtime = Utc::now().timestamp();
+    let eeg_size = (total_points * export.channels.len() * 4) as u64;
+    let mut head = Vec::new();
+    for (suffix, content) in [
+        ("vhdr", vhdr(&export, &name, points_per_second)),
+        ("vmrk", vmrk(&name, &markers)),
+    ] {
+        let size = content.len() as u64;
+        head.extend_from_slice(&tar_header(&format!("{}.{}", name, suffix), size, mtime));
+        head.extend_from_slice(content.as_bytes());
+        head.resize(head.len() + tar_padding(size), 0);
+    }
+    head.extend_from_slice(&tar_header(&format!("{}.eeg", name), eeg_size, mtime));
+    if tx.send(Ok(Bytes::from(head))).await.is_err() {
+        return Ok(());
+    }
+
+    let index: HashMap<&str, usize> = export
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.name.as_str(), i))
+        .collect();
+    let channels = export.channels.len();
+    let points = vec![points_per_second; channels];
+    let mut last: Vec<Option<f64>> = vec![None; channels];
+    for chunk in written.chunks(RECORDS_PER_CHUNK) {
+        let samples = fetch_window(
+            &pool,
+            id,
+            &factors,
+            record_start(chunk[0]),
+            record_start(chunk[chunk.len() - 1] + 1),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut out = Vec::with_capacity(chunk.len() * points_per_second * channels * 4);
+        let mut rest: &[ExportSample] = &samples;
+        for &record in chunk {
+            let split = rest.partition_point(|s| s.ts < record_start(record + 1));
+            let (in_record, tail) = rest.split_at(split);
+            rest = tail;
+
+            let values = place_record(
+                in_record,
+                &index,
+                record_start(record),
+                1.0,
+                &points,
+                &mut last,
+            );
+            for point in 0..points_per_second {
+                for channel in &values {
+                    out.extend_from_slice(&(channel[point] as f32).to_le_bytes());
+                }
+            }
+        }
+        if tx.send(Ok(Bytes::from(out))).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    let mut tail = vec![0u8; tar_padding(eeg_size)];
+    tail.resize(tail.len() + 2 * TAR_BLOCK, 0);
+    let _ = tx.send(Ok(Bytes::from(tail))).await;
+    Ok(())
+}
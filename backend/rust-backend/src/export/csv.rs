@@ -0,0 +1,112 @@
+//! CSV export of samples (`GET /samples/export.csv`).
+//!
+//! Rows are read with a single streaming query, merged with the packed
+//! [blocks](crate::store::blocks) of the range as they are reached, and sent
+//! in chunks of about [`CHUNK_BYTES`], so the response never holds more than
+//! one chunk and a few blocks in memory however long the requested range is.
+
+use super::{download, Chunks};
+use crate::error::ApiError;
+use crate::store::blocks;
+use crate::validation::{Parsed, Valid};
+use crate::{metadata, AppState, ChannelQuery, EegSample, SampleFilter};
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    response::Response,
+};
+use chrono::SecondsFormat;
+use sqlx::{PgPool, QueryBuilder};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Quotes `text` if it contains a delimiter, quote or line break.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Writes `id,ts,channel,value` rows of `channels` ordered by time into `tx`,
+/// values multiplied by the [factors](metadata::factors) of their channels.
+pub async fn write(
+    pool: PgPool,
+    channels: Vec<String>,
+    filter: SampleFilter,
+    factors: HashMap<String, f64>,
+    tx: Chunks,
+) -> Result<(), String> {
+    let mut query =
+        QueryBuilder::new("SELECT id, ts, channel, value FROM eeg_samples WHERE channel = ANY(");
+    query.push_bind(channels.clone()).push(")");
+    filter.push_to(&mut query);
+    query.push(" ORDER BY ts, id");
+
+    let mut packed = blocks::select(Some(&channels), &filter);
+    let mut rows = blocks::Merged::new(
+        query.build_query_as::<EegSample>().fetch(&pool),
+        packed.build_query_as().fetch(&pool),
+        filter,
+    );
+    let mut out = String::with_capacity(CHUNK_BYTES + 256);
+    out.push_str("id,ts,channel,value\n");
+    while let Some(sample) = rows.next().await.map_err(|e| e.to_string())? {
+        let _ = writeln!(
+            out,
+            "{},{},{},{}",
+            sample.id,
+            sample.ts.to_rfc3339_opts(SecondsFormat::Micros, true),
+            csv_field(&sample.channel),
+            sample.value * factors.get(&sample.channel).copied().unwrap_or(1.0)
+        );
+        if out.len() >= CHUNK_BYTES {
+            let chunk = std::mem::replace(&mut out, String::with_capacity(CHUNK_BYTES + 256));
+            if tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    if !out.is_empty() {
+        let _ = tx.send(Ok(Bytes::from(out))).await;
+    }
+    Ok(())
+}
+
+/// Streams `id,ts,channel,value` rows ordered by time. Takes the channel and
+/// window parameters of `/samples` (`channel`, `from`, `to`, `session_id`,
+/// `subject_id`) but no limit.
+#[utoipa::path(
+    get,
+    path = "/samples/export.csv",
+    tag = "samples",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        SampleFilter,
+        metadata::UnitQuery
+    ),
+    responses(
+        (status = 200, description = "`id,ts,channel,value` rows ordered by time", content_type = "text/csv", body = String),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn export_csv(
+    State(state): State<AppState>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    Parsed(Query(units)): Parsed<Query<metadata::UnitQuery>>,
+) -> Result<Response, ApiError> {
+    let channels = raw.channels().map_err(ApiError::bad_request)?;
+    let factors = metadata::factors(&state.pool, &channels, filter.session_id, units.unit).await?;
+
+    let pool = state.pool.clone();
+    Ok(download(
+        "samples.csv".to_string(),
+        "text/csv; charset=utf-8",
+        move |tx| write(pool, channels, filter, factors, tx),
+    ))
+}
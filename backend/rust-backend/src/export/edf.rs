@@ -0,0 +1,397 @@
+//! EDF+ and BDF+ writer (<https://www.edfplus.info/specs/edfplus.html>,
+//! <https://www.edfplus.info/specs/bdfplus.html>).
+//!
+//! Both formats share one layout and differ in sample width: EDF stores
+//! 16-bit samples, BDF (BioSemi) 24-bit ones, which keeps the resolution of
+//! high-dynamic-range recordings.
+//!
+//! Data records are one second long, starting at the whole second before the
+//! first sample. Each channel becomes a signal with `round(sample_rate)`
+//! samples per record, scaled between the channel's minimum and maximum; a
+//! sample fills the slot nearest its timestamp and empty slots repeat the
+//! previous value. Seconds without any sample are not written, which makes
+//! the file EDF+D / BDF+D ("discontinuous"); otherwise it is EDF+C / BDF+C.
+//! Every record carries an annotations signal with its start time, and events
+//! are annotated in the record they start in (or the closest earlier record
+//! that was written).
+//!
+//! Header times are UTC, since EDF has no notion of time zones.
+
+use super::{
+    fetch_window, place_record, seconds_between, seconds_with_samples, Chunks, ExportSample,
+    SessionExport,
+};
+use axum::body::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+const RECORD_SECONDS: f64 = 1.0;
+
+/// Data records read from the database and sent per chunk.
+const RECORDS_PER_CHUNK: usize = 30;
+
+/// Separators of a time-stamped annotations list (TAL).
+pub(crate) const TAL_DURATION: u8 = 0x15;
+pub(crate) const TAL_TEXT: u8 = 0x14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    /// European Data Format, 16-bit samples.
+    Edf,
+    /// BioSemi Data Format, 24-bit samples.
+    Bdf,
+}
+
+impl Variant {
+    pub(crate) fn sample_bytes(self) -> usize {
+        match self {
+            Variant::Edf => 2,
+            Variant::Bdf => 3,
+        }
+    }
+
+    fn digital_range(self) -> (i32, i32) {
+        match self {
+            Variant::Edf => (-32_768, 32_767),
+            Variant::Bdf => (-8_388_608, 8_388_607),
+        }
+    }
+
+    /// The 8-byte version field.
+    fn version(self) -> &'static [u8; 8] {
+        match self {
+            Variant::Edf => b"0       ",
+            Variant::Bdf => b"\xffBIOSEMI",
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Variant::Edf => "EDF",
+            Variant::Bdf => "BDF",
+        }
+    }
+}
+
+struct Signal {
+    label: String,
+    unit: String,
+    samples_per_record: usize,
+    physical_min: f64,
+    physical_max: f64,
+}
+
+impl Signal {
+    /// Digital value of `value`, clamped to the digital range of `variant`.
+    fn digital(&self, value: f64, variant: Variant) -> i32 {
+        let (min, max) = variant.digital_range();
+        let span = self.physical_max - self.physical_min;
+        let ratio = (value - self.physical_min) / span;
+        let digital = (ratio * (max - min) as f64 + min as f64).round();
+        digital.clamp(min as f64, max as f64) as i32
+    }
+}
+
+/// Appends `text` as a fixed-width header field: printable ASCII, truncated
+/// and padded with spaces.
+fn field(out: &mut Vec<u8>, text: &str, width: usize) {
+    let mut bytes: Vec<u8> = text
+        .chars()
+        .map(|c| {
+            if (' '..='~').contains(&c) {
+                c as u8
+            } else {
+                b'_'
+            }
+        })
+        .take(width)
+        .collect();
+    bytes.resize(width, b' ');
+    out.extend_from_slice(&bytes);
+}
+
+/// A subfield of the patient or recording identification: spaces are not
+/// allowed inside one, and unknown values are written as `X`.
+fn subfield(value: Option<&str>) -> String {
+    match value.map(str::trim) {
+        Some(v) if !v.is_empty() => v.replace(' ', "_"),
+        _ => "X".to_string(),
+    }
+}
+
+fn trim_decimals(mut text: String) -> String {
+    if text.contains('.') {
+        while text.ends_with('0') {
+            text.pop();
+        }
+        if text.ends_with('.') {
+            text.pop();
+        }
+    }
+    if text == "-0" {
+        text = "0".to_string();
+    }
+    text
+}
+
+/// `value` in at most 8 characters, rounded down (`up == false`) or up so a
+/// physical range written with it still contains the data.
+fn fit8(value: f64, up: bool) -> f64 {
+    for decimals in (0..=6).rev() {
+        let scale = 10f64.powi(decimals);
+        let rounded = if up {
+            (value * scale).ceil() / scale
+        } else {
+            (value * scale).floor() / scale
+        };
+        if trim_decimals(format!("{:.*}", decimals as usize, rounded)).len() <= 8 {
+            return rounded;
+        }
+    }
+    if up {
+        99_999_999.0
+    } else {
+        -9_999_999.0
+    }
+}
+
+fn number(value: f64) -> String {
+    trim_decimals(format!("{:.6}", value))
+}
+
+/// Seconds relative to the file start as a TAL onset: `+1.5`, `-0.25`.
+fn onset(seconds: f64) -> String {
+    let text = number(seconds);
+    if text.starts_with('-') {
+        text
+    } else {
+        format!("+{}", text)
+    }
+}
+
+fn tal_text(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\0' | '\u{14}' | '\u{15}' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+fn header(
+    variant: Variant,
+    export: &SessionExport,
+    start: DateTime<Utc>,
+    signals: &[Signal],
+    annotation_samples: usize,
+    records: usize,
+    continuous: bool,
+) -> Vec<u8> {
+    let subject = export.subject.as_ref();
+    let sex = match subject.and_then(|s| s.sex.as_deref()) {
+        Some("female") => "F",
+        Some("male") => "M",
+        _ => "X",
+    };
+    let patient = format!(
+        "{} {} X X",
+        subfield(subject.and_then(|s| s.code.as_deref())),
+        sex
+    );
+    let recording = format!(
+        "Startdate {} session-{} X {}",
+        start.format("%d-%b-%Y").to_string().to_uppercase(),
+        export.session.id,
+        subfield(export.session.device.as_deref())
+    );
+    let ns = signals.len() + 1;
+
+    let mut out = Vec::with_capacity(256 * (ns + 1));
+    out.extend_from_slice(variant.version());
+    field(&mut out, &patient, 80);
+    field(&mut out, &recording, 80);
+    field(&mut out, &start.format("%d.%m.%y").to_string(), 8);
+    field(&mut out, &start.format("%H.%M.%S").to_string(), 8);
+    field(&mut out, &(256 * (ns + 1)).to_string(), 8);
+    let kind = if continuous { "C" } else { "D" };
+    field(&mut out, &format!("{}+{}", variant.name(), kind), 44);
+    field(&mut out, &records.to_string(), 8);
+    field(&mut out, &number(RECORD_SECONDS), 8);
+    field(&mut out, &ns.to_string(), 4);
+
+    let annotation = Signal {
+        label: format!("{} Annotations", variant.name()),
+        unit: String::new(),
+        samples_per_record: annotation_samples,
+        physical_min: -1.0,
+        physical_max: 1.0,
+    };
+    let all: Vec<&Signal> = signals.iter().chain(std::iter::once(&annotation)).collect();
+    for s in &all {
+        field(&mut out, &s.label, 16);
+    }
+    for _ in &all {
+        field(&mut out, "", 80);
+    }
+    for s in &all {
+        field(&mut out, &s.unit, 8);
+    }
+    for s in &all {
+        field(&mut out, &number(s.physical_min), 8);
+    }
+    for s in &all {
+        field(&mut out, &number(s.physical_max), 8);
+    }
+    let (digital_min, digital_max) = variant.digital_range();
+    for _ in &all {
+        field(&mut out, &digital_min.to_string(), 8);
+    }
+    for _ in &all {
+        field(&mut out, &digital_max.to_string(), 8);
+    }
+    for _ in &all {
+        field(&mut out, "", 80);
+    }
+    for s in &all {
+        field(&mut out, &s.samples_per_record.to_string(), 8);
+    }
+    for _ in &all {
+        field(&mut out, "", 32);
+    }
+    out
+}
+
+/// Writes `export` as EDF+ or BDF+ into `tx`.
+pub async fn write(
+    pool: PgPool,
+    export: SessionExport,
+    variant: Variant,
+    tx: Chunks,
+) -> Result<(), String> {
+    let id = export.session.id;
+    let factors = export.factors();
+    let width = variant.sample_bytes();
+    let start = Utc
+        .timestamp_opt(export.first_ts.timestamp(), 0)
+        .single()
+        .ok_or("invalid start time")?;
+
+    let written = seconds_with_samples(&pool, id, start)
+        .await
+        .map_err(|e| e.to_string())?;
+    let continuous = written.last().map(|&last| last + 1) == Some(written.len() as i64);
+
+    let mut tals: Vec<Vec<u8>> = written
+        .iter()
+        .map(|&r| format!("{}\u{14}\u{14}\0", onset(r as f64 * RECORD_SECONDS)).into_bytes())
+        .collect();
+    for event in &export.events {
+        let offset = seconds_between(start, event.ts);
+        let record = (offset / RECORD_SECONDS).floor() as i64;
+        let index = written.partition_point(|&r| r <= record).saturating_sub(1);
+        let tal = &mut tals[index];
+        tal.extend_from_slice(onset(offset).as_bytes());
+        if event.duration > 0.0 {
+            tal.push(TAL_DURATION);
+            tal.extend_from_slice(number(event.duration).as_bytes());
+        }
+        tal.push(TAL_TEXT);
+        tal.extend_from_slice(tal_text(&event.label).as_bytes());
+        tal.push(TAL_TEXT);
+        tal.push(0);
+    }
+    let annotation_samples = tals
+        .iter()
+        .map(|t| t.len().div_ceil(width))
+        .max()
+        .unwrap_or(1);
+
+    let signals: Vec<Signal> = export
+        .channels
+        .iter()
+        .map(|c| {
+            let (mut min, mut max) = (c.min, c.max);
+            if min == max {
+                min -= 1.0;
+                max += 1.0;
+            }
+            Signal {
+                label: format!("EEG {}", c.name),
+                unit: c.unit.clone(),
+                samples_per_record: ((c.sample_rate * RECORD_SECONDS).round() as usize).max(1),
+                physical_min: fit8(min, false),
+                physical_max: fit8(max, true),
+            }
+        })
+        .collect();
+    let index: HashMap<&str, usize> = export
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.name.as_str(), i))
+        .collect();
+
+    let head = header(
+        variant,
+        &export,
+        start,
+        &signals,
+        annotation_samples,
+        written.len(),
+        continuous,
+    );
+    if tx.send(Ok(Bytes::from(head))).await.is_err() {
+        return Ok(());
+    }
+
+    let record_bytes =
+        width * (signals.iter().map(|s| s.samples_per_record).sum::<usize>() + annotation_samples);
+    let points: Vec<usize> = signals.iter().map(|s| s.samples_per_record).collect();
+    let mut last: Vec<Option<f64>> = vec![None; signals.len()];
+    for (chunk_index, chunk) in written.chunks(RECORDS_PER_CHUNK).enumerate() {
+        let record_start = |r: i64| {
+            start + chrono::Duration::microseconds((r as f64 * RECORD_SECONDS * 1e6) as i64)
+        };
+        let samples = fetch_window(
+            &pool,
+            id,
+            &factors,
+            record_start(chunk[0]),
+            record_start(chunk[chunk.len() - 1] + 1),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut out = Vec::with_capacity(record_bytes * chunk.len());
+        let mut rest: &[ExportSample] = &samples;
+        for (i, &record) in chunk.iter().enumerate() {
+            let end = record_start(record + 1);
+            let split = rest.partition_point(|s| s.ts < end);
+            let (in_record, tail) = rest.split_at(split);
+            rest = tail;
+
+            let values = place_record(
+                in_record,
+                &index,
+                record_start(record),
+                RECORD_SECONDS,
+                &points,
+                &mut last,
+            );
+            for (signal, values) in signals.iter().zip(values) {
+                for value in values {
+                    // Little-endian two's complement; BDF keeps the low 3 bytes.
+                    out.extend_from_slice(&signal.digital(value, variant).to_le_bytes()[..width]);
+                }
+            }
+            let tal = &tals[chunk_index * RECORDS_PER_CHUNK + i];
+            out.extend_from_slice(tal);
+            out.resize(out.len() + width * annotation_samples - tal.len(), 0);
+        }
+        if tx.send(Ok(Bytes::from(out))).await.is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
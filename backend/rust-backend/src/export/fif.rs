@@ -0,0 +1,337 @@
+//! FIF writer for raw data as read by `mne.io.read_raw_fif`.
+//!
+//! A FIF file is a sequence of big-endian tags (kind, type, size, next,
+//! data) nested into blocks; `next` is always 0 ("sequential"), so readers
+//! build the directory by scanning the file. The layout follows what MNE
+//! writes for raw data:
+//!
+//! - `FIFFB_MEAS` with the measurement info (channel count, sample rate,
+//!   measurement date, one `FIFF_CH_INFO` per channel, the subject) and an
+//!   annotations block holding the events;
+//! - `FIFFB_RAW_DATA` with one float buffer per one-second record, on a grid
+//!   at the highest channel rate like the BrainVision export. Seconds without
+//!   samples become `FIFF_DATA_SKIP` entries, which MNE reads as zeros
+//!   annotated `BAD_ACQ_SKIP`.
+//!
+//! Values are stored in the channel unit with the factor to volts as the
+//! channel calibration. Channels in a voltage unit are EEG (or EOG, ECG, EMG
+//! when the name starts with that), the others MISC. Electrode positions are
+//! not stored for channels, so the location vectors are zero, which MNE
+//! treats as unknown.
+
+use super::{
+    fetch_window, place_record, seconds_between, seconds_with_samples, Chunks, ExportSample,
+    SessionExport,
+};
+use axum::body::Bytes;
+use chrono::{TimeZone, Utc};
+use eeg_export::units::volts;
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+/// Records (seconds) read from the database and sent per chunk.
+const RECORDS_PER_CHUNK: usize = 30;
+
+// Tag kinds.
+const FIFF_FILE_ID: i32 = 100;
+const FIFF_DIR_POINTER: i32 = 101;
+const FIFF_BLOCK_ID: i32 = 103;
+const FIFF_BLOCK_START: i32 = 104;
+const FIFF_BLOCK_END: i32 = 105;
+const FIFF_FREE_LIST: i32 = 106;
+const FIFF_NOP: i32 = 108;
+const FIFF_NCHAN: i32 = 200;
+const FIFF_SFREQ: i32 = 201;
+const FIFF_CH_INFO: i32 = 203;
+const FIFF_MEAS_DATE: i32 = 204;
+const FIFF_COMMENT: i32 = 206;
+const FIFF_FIRST_SAMPLE: i32 = 208;
+const FIFF_LOWPASS: i32 = 219;
+const FIFF_HIGHPASS: i32 = 223;
+const FIFF_DATA_BUFFER: i32 = 300;
+const FIFF_DATA_SKIP: i32 = 301;
+const FIFF_SUBJ_SEX: i32 = 405;
+const FIFF_SUBJ_HAND: i32 = 406;
+const FIFF_SUBJ_HIS_ID: i32 = 410;
+const FIFF_MNE_BASELINE_MIN: i32 = 3568;
+const FIFF_MNE_BASELINE_MAX: i32 = 3569;
+
+// Block kinds.
+const FIFFB_MEAS: i32 = 100;
+const FIFFB_MEAS_INFO: i32 = 101;
+const FIFFB_RAW_DATA: i32 = 102;
+const FIFFB_SUBJECT: i32 = 106;
+const FIFFB_MNE_ANNOTATIONS: i32 = 3810;
+
+// Data types.
+const FIFFT_VOID: i32 = 0;
+const FIFFT_INT: i32 = 3;
+const FIFFT_FLOAT: i32 = 4;
+const FIFFT_DOUBLE: i32 = 5;
+const FIFFT_STRING: i32 = 10;
+const FIFFT_CH_INFO_STRUCT: i32 = 30;
+const FIFFT_ID_STRUCT: i32 = 31;
+
+// Channel kinds, coils and units.
+const FIFFV_EEG_CH: i32 = 2;
+const FIFFV_EOG_CH: i32 = 202;
+const FIFFV_EMG_CH: i32 = 302;
+const FIFFV_ECG_CH: i32 = 402;
+const FIFFV_MISC_CH: i32 = 502;
+const FIFFV_COIL_NONE: i32 = 0;
+const FIFFV_COIL_EEG: i32 = 1;
+const FIFF_UNIT_NONE: i32 = -1;
+const FIFF_UNIT_V: i32 = 107;
+
+const FIFFC_VERSION: i32 = (1 << 16) | 3;
+const FIFFV_NEXT_SEQ: i32 = 0;
+const FIFFV_NEXT_NONE: i32 = -1;
+
+/// FIFF kind of a voltage channel, from the conventional name prefixes.
+fn voltage_kind(name: &str) -> i32 {
+    let name = name.to_ascii_uppercase();
+    if name.starts_with("EOG") {
+        FIFFV_EOG_CH
+    } else if name.starts_with("ECG") || name.starts_with("EKG") {
+        FIFFV_ECG_CH
+    } else if name.starts_with("EMG") {
+        FIFFV_EMG_CH
+    } else {
+        FIFFV_EEG_CH
+    }
+}
+
+fn tag(out: &mut Vec<u8>, kind: i32, data_type: i32, data: &[u8]) {
+    tag_with_next(out, kind, data_type, data, FIFFV_NEXT_SEQ);
+}
+
+fn tag_with_next(out: &mut Vec<u8>, kind: i32, data_type: i32, data: &[u8], next: i32) {
+    out.extend_from_slice(&kind.to_be_bytes());
+    out.extend_from_slice(&data_type.to_be_bytes());
+    out.extend_from_slice(&(data.len() as i32).to_be_bytes());
+    out.extend_from_slice(&next.to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+fn ints(out: &mut Vec<u8>, kind: i32, values: &[i32]) {
+    let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+    tag(out, kind, FIFFT_INT, &data);
+}
+
+fn floats(out: &mut Vec<u8>, kind: i32, values: &[f32]) {
+    let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+    tag(out, kind, FIFFT_FLOAT, &data);
+}
+
+fn string(out: &mut Vec<u8>, kind: i32, text: &str) {
+    tag(out, kind, FIFFT_STRING, text.as_bytes());
+}
+
+fn start_block(out: &mut Vec<u8>, block: i32) {
+    ints(out, FIFF_BLOCK_START, &[block]);
+}
+
+fn end_block(out: &mut Vec<u8>, block: i32) {
+    ints(out, FIFF_BLOCK_END, &[block]);
+}
+
+/// A file or block id: version, machine id, time.
+fn id(out: &mut Vec<u8>, kind: i32, machine: i32) {
+    let now = Utc::now();
+    let data: Vec<u8> = [
+        FIFFC_VERSION,
+        machine,
+        0,
+        now.timestamp() as i32,
+        now.timestamp_subsec_micros() as i32,
+    ]
+    .iter()
+    .flat_map(|v| v.to_be_bytes())
+    .collect();
+    tag(out, kind, FIFFT_ID_STRUCT, &data);
+}
+
+fn ch_info(out: &mut Vec<u8>, number: i32, name: &str, unit: &str) {
+    let (kind, coil, fiff_unit, cal) = match volts(unit) {
+        Some(cal) => (voltage_kind(name), FIFFV_COIL_EEG, FIFF_UNIT_V, cal),
+        None => (FIFFV_MISC_CH, FIFFV_COIL_NONE, FIFF_UNIT_NONE, 1.0),
+    };
+    let mut data = Vec::with_capacity(96);
+    data.extend_from_slice(&number.to_be_bytes());
+    data.extend_from_slice(&number.to_be_bytes());
+    data.extend_from_slice(&kind.to_be_bytes());
+    data.extend_from_slice(&1f32.to_be_bytes());
+    data.extend_from_slice(&cal.to_be_bytes());
+    data.extend_from_slice(&coil.to_be_bytes());
+    data.extend_from_slice(&[0; 48]);
+    data.extend_from_slice(&fiff_unit.to_be_bytes());
+    data.extend_from_slice(&0i32.to_be_bytes());
+    // NUL-terminated within 16 bytes.
+    let mut label = [0u8; 16];
+    let bytes = name.as_bytes();
+    label[..bytes.len().min(15)].copy_from_slice(&bytes[..bytes.len().min(15)]);
+    data.extend_from_slice(&label);
+    tag(out, FIFF_CH_INFO, FIFFT_CH_INFO_STRUCT, &data);
+}
+
+/// Writes `export` as a FIF raw file into `tx`.
+pub async fn write(pool: PgPool, export: SessionExport, tx: Chunks) -> Result<(), String> {
+    let id_number = export.session.id;
+    let start = Utc
+        .timestamp_opt(export.first_ts.timestamp(), 0)
+        .single()
+        .ok_or("invalid start time")?;
+    let rate = export
+        .channels
+        .iter()
+        .map(|c| c.sample_rate)
+        .fold(0.0, f64::max);
+    let points_per_second = (rate.round() as usize).max(1);
+    let channels = export.channels.len();
+
+    let mut out = Vec::new();
+    id(&mut out, FIFF_FILE_ID, id_number);
+    ints(&mut out, FIFF_DIR_POINTER, &[-1]);
+    ints(&mut out, FIFF_FREE_LIST, &[-1]);
+    start_block(&mut out, FIFFB_MEAS);
+    id(&mut out, FIFF_BLOCK_ID, id_number);
+
+    start_block(&mut out, FIFFB_MEAS_INFO);
+    ints(&mut out, FIFF_NCHAN, &[channels as i32]);
+    floats(&mut out, FIFF_SFREQ, &[points_per_second as f32]);
+    floats(&mut out, FIFF_HIGHPASS, &[0.0]);
+    floats(&mut out, FIFF_LOWPASS, &[points_per_second as f32 / 2.0]);
+    ints(&mut out, FIFF_MEAS_DATE, &[start.timestamp() as i32, 0]);
+    let mut description = format!("session-{}", id_number);
+    if let Some(device) = &export.session.device {
+        description.push_str(&format!(" device {}", device));
+    }
+    string(&mut out, FIFF_COMMENT, &description);
+    for (i, channel) in export.channels.iter().enumerate() {
+        ch_info(&mut out, i as i32 + 1, &channel.name, &channel.unit);
+    }
+    if let Some(subject) = &export.subject {
+        start_block(&mut out, FIFFB_SUBJECT);
+        if let Some(code) = &subject.code {
+            string(&mut out, FIFF_SUBJ_HIS_ID, code);
+        }
+        let sex = match subject.sex.as_deref() {
+            Some("male") => 1,
+            Some("female") => 2,
+            _ => 0,
+        };
+        ints(&mut out, FIFF_SUBJ_SEX, &[sex]);
+        let hand = match subject.handedness.as_deref() {
+            Some("right") => Some(1),
+            Some("left") => Some(2),
+            Some("ambidextrous") => Some(3),
+            _ => None,
+        };
+        if let Some(hand) = hand {
+            ints(&mut out, FIFF_SUBJ_HAND, &[hand]);
+        }
+        end_block(&mut out, FIFFB_SUBJECT);
+    }
+    end_block(&mut out, FIFFB_MEAS_INFO);
+
+    if !export.events.is_empty() {
+        let onsets: Vec<f32> = export
+            .events
+            .iter()
+            .map(|e| seconds_between(start, e.ts) as f32)
+            .collect();
+        let ends: Vec<f32> = export
+            .events
+            .iter()
+            .zip(&onsets)
+            .map(|(e, onset)| onset + e.duration as f32)
+            .collect();
+        // Descriptions are a `:`-separated name list.
+        let descriptions: Vec<String> = export
+            .events
+            .iter()
+            .map(|e| e.label.replace(':', ";"))
+            .collect();
+        start_block(&mut out, FIFFB_MNE_ANNOTATIONS);
+        floats(&mut out, FIFF_MNE_BASELINE_MIN, &onsets);
+        floats(&mut out, FIFF_MNE_BASELINE_MAX, &ends);
+        string(&mut out, FIFF_COMMENT, &descriptions.join(":"));
+        let orig_time: Vec<u8> = [start.timestamp() as f64, 0.0]
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        tag(&mut out, FIFF_MEAS_DATE, FIFFT_DOUBLE, &orig_time);
+        end_block(&mut out, FIFFB_MNE_ANNOTATIONS);
+    }
+
+    start_block(&mut out, FIFFB_RAW_DATA);
+    ints(&mut out, FIFF_FIRST_SAMPLE, &[0]);
+    if tx.send(Ok(Bytes::from(out))).await.is_err() {
+        return Ok(());
+    }
+
+    let written = seconds_with_samples(&pool, id_number, start)
+        .await
+        .map_err(|e| e.to_string())?;
+    let record_start = |r: i64| start + chrono::Duration::seconds(r);
+    let factors = export.factors();
+    let index: HashMap<&str, usize> = export
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.name.as_str(), i))
+        .collect();
+    let points = vec![points_per_second; channels];
+    let mut last: Vec<Option<f64>> = vec![None; channels];
+    let mut previous = -1;
+    for chunk in written.chunks(RECORDS_PER_CHUNK) {
+        let samples = fetch_window(
+            &pool,
+            id_number,
+            &factors,
+            record_start(chunk[0]),
+            record_start(chunk[chunk.len() - 1] + 1),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut out = Vec::with_capacity(chunk.len() * (16 + points_per_second * channels * 4));
+        let mut rest: &[ExportSample] = &samples;
+        for &record in chunk {
+            let split = rest.partition_point(|s| s.ts < record_start(record + 1));
+            let (in_record, tail) = rest.split_at(split);
+            rest = tail;
+
+            if record > previous + 1 {
+                // Buffers of the same size missing before this one.
+                ints(&mut out, FIFF_DATA_SKIP, &[(record - previous - 1) as i32]);
+            }
+            previous = record;
+            let values = place_record(
+                in_record,
+                &index,
+                record_start(record),
+                1.0,
+                &points,
+                &mut last,
+            );
+            let mut data = Vec::with_capacity(points_per_second * channels * 4);
+            for point in 0..points_per_second {
+                for channel in &values {
+                    data.extend_from_slice(&(channel[point] as f32).to_be_bytes());
+                }
+            }
+            tag(&mut out, FIFF_DATA_BUFFER, FIFFT_FLOAT, &data);
+        }
+        if tx.send(Ok(Bytes::from(out))).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    let mut out = Vec::new();
+    end_block(&mut out, FIFFB_RAW_DATA);
+    end_block(&mut out, FIFFB_MEAS);
+    tag_with_next(&mut out, FIFF_NOP, FIFFT_VOID, &[], FIFFV_NEXT_NONE);
+    let _ = tx.send(Ok(Bytes::from(out))).await;
+    Ok(())
+}
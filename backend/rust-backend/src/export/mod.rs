@@ -0,0 +1,408 @@
+//! Session export (`GET /sessions/{id}/export?format=...`).
+//!
+//! [`SessionExport::load`] gathers what a writer needs before it reads any
+//! samples (session, subject, channels with their ranges and rates, events);
+//! writers then read the samples in time windows with [`fetch_window`] and
+//! stream the file to the client as it is produced.
+//!
+//! - `edf` — EDF+ with an annotations signal, see [`edf`]
+//! - `bdf` — BDF+, the same layout with 24-bit samples
+//! - `brainvision` — a tar archive of the BrainVision `.vhdr` / `.vmrk` / `.eeg`
+//!   triplet, see [`brainvision`]
+//! - `xdf` — XDF with EEG, auxiliary and marker streams, see [`xdf`]
+//! - `fif` — FIF raw data for MNE-Python, see [`fif`]
+//! - `nwb` — NWB 2 (HDF5) with subject, electrodes and events, see [`nwb`]
+//!
+//! Samples across sessions are exported by [`csv`] (streamed) and [`parquet`].
+//! Any of these formats can also be [queued](queue) as a background job.
+
+pub mod brainvision;
+pub mod csv;
+pub mod edf;
+pub mod fif;
+pub mod nwb;
+pub mod parquet;
+pub mod queue;
+pub mod xdf;
+
+use crate::auth::Principal;
+use crate::devices::registry;
+use crate::error::ApiError;
+use crate::events::Event;
+use crate::sessions::Session;
+use crate::store::blocks;
+use crate::subjects::Subject;
+use crate::validation::Parsed;
+use crate::{metadata, roles, AppState, EegSample, SampleFilter};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use utoipa::IntoParams;
+
+pub use eeg_export::records::{place_record, seconds_between, ExportSample};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    format: Option<String>,
+}
+
+/// A channel recorded in the session.
+#[derive(Debug, Clone)]
+pub struct ExportChannel {
+    pub name: String,
+    pub unit: String,
+    /// Rate of the channel in the session (see [`crate::metadata`]) or of the
+    /// session's device, otherwise estimated from the stored samples.
+    pub sample_rate: f64,
+    /// Registered reference electrode.
+    pub reference: Option<String>,
+    /// Smallest and largest value in the session, in `unit`.
+    pub min: f64,
+    pub max: f64,
+    /// Multiplier from stored values to `unit`; 1 unless
+    /// [converted](SessionExport::convert).
+    pub factor: f64,
+    scale: f64,
+}
+
+/// Everything known about a session before its samples are read.
+#[derive(Debug, Clone)]
+pub struct SessionExport {
+    pub session: Session,
+    pub subject: Option<Subject>,
+    pub channels: Vec<ExportChannel>,
+    /// Time of the first sample.
+    pub first_ts: DateTime<Utc>,
+    pub events: Vec<Event>,
+}
+
+type ChannelRow = (
+    String,
+    String,
+    Option<f64>,
+    Option<String>,
+    f64,
+    f64,
+    DateTime<Utc>,
+    f64,
+);
+
+impl SessionExport {
+    /// Loads session `id`; `Ok(None)` if the session does not exist. Fails
+    /// with a message when the session has no samples.
+    pub async fn load(pool: &PgPool, id: i32) -> Result<Option<Self>, ApiError> {
+        let session: Option<Session> = sqlx::query_as(
+            "SELECT id, subject_id, device, started_at, ended_at, notes FROM sessions WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+        let Some(session) = session else {
+            return Ok(None);
+        };
+
+        let subject: Option<Subject> = match session.subject_id {
+            Some(subject_id) => {
+                sqlx::query_as(
+                    "SELECT id, code, birth_year, sex, handedness, notes, created_at \
+                 FROM subjects WHERE id = $1",
+                )
+                .bind(subject_id)
+                .fetch_optional(pool)
+                .await?
+            }
+            None => None,
+        };
+
+        let rows: Vec<ChannelRow> = sqlx::query_as(
+            "WITH s AS ( \
+               SELECT channel, session_id, MIN(value) AS low, MAX(value) AS high, MIN(ts) AS first \
+               FROM eeg_samples WHERE session_id = $1 GROUP BY channel, session_id \
+               UNION ALL \
+               SELECT channel, session_id, MIN(min_value), MAX(max_value), MIN(start_ts) \
+               FROM sample_blocks WHERE session_id = $1 GROUP BY channel, session_id) \
+             SELECT s.channel, COALESCE(o.unit, c.unit, 'uV'), COALESCE(o.sample_rate, c.sample_rate), \
+             c.reference, MIN(s.low), MAX(s.high), MIN(s.first), COALESCE(o.scale, c.scale, 1) \
+             FROM s LEFT JOIN channels c ON c.name = s.channel \
+             LEFT JOIN session_channels o ON o.session_id = s.session_id AND o.channel = s.channel \
+             GROUP BY s.channel, o.unit, c.unit, o.sample_rate, c.sample_rate, o.scale, c.scale, \
+             c.reference, c.hardware_index \
+             ORDER BY c.hardware_index NULLS LAST, s.channel",
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+        if rows.is_empty() {
+            return Err(ApiError::bad_request(format!(
+                "session {} has no samples",
+                id
+            )));
+        }
+
+        let device_rate = session
+            .device
+            .as_deref()
+            .and_then(registry::get)
+            .map(|d| d.sample_rate);
+        let estimated = if device_rate.is_none() && rows.iter().any(|r| r.2.is_none()) {
+            estimate_rates(pool, id).await?
+        } else {
+            HashMap::new()
+        };
+        let first_ts = rows.iter().map(|r| r.6).min().unwrap();
+        let channels = rows
+            .into_iter()
+            .map(|(name, unit, rate, reference, min, max, _, scale)| {
+                let sample_rate = rate
+                    .or(device_rate)
+                    .or_else(|| estimated.get(&name).copied())
+                    .unwrap_or(1.0);
+                ExportChannel {
+                    name,
+                    unit,
+                    sample_rate,
+                    reference,
+                    min,
+                    max,
+                    factor: 1.0,
+                    scale,
+                }
+            })
+            .collect();
+
+        let events: Vec<Event> = sqlx::query_as(
+            "SELECT id, session_id, ts, duration, label, metadata FROM events \
+             WHERE session_id = $1 ORDER BY ts, id",
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Some(Self {
+            session,
+            subject,
+            channels,
+            first_ts,
+            events,
+        }))
+    }
+
+    /// Converts the channels to `unit` (see [`metadata::Unit`]): their scale
+    /// applied, their unit, range and [`factors`](Self::factors) changed.
+    /// Fails for channels whose unit is not a voltage.
+    pub fn convert(&mut self, unit: Option<metadata::Unit>) -> Result<(), String> {
+        let Some(unit) = unit else {
+            return Ok(());
+        };
+        for channel in &mut self.channels {
+            let mut metadata = metadata::Metadata {
+                sample_rate: Some(channel.sample_rate),
+                unit: channel.unit.clone(),
+                scale: channel.scale,
+            };
+            let factor = metadata.convert(&channel.name, unit)?;
+            let (min, max) = (channel.min * factor, channel.max * factor);
+            channel.min = min.min(max);
+            channel.max = min.max(max);
+            channel.unit = metadata.unit;
+            channel.factor = factor;
+        }
+        Ok(())
+    }
+
+    /// Factors of the channels that were converted, for [`fetch_window`].
+    pub fn factors(&self) -> HashMap<String, f64> {
+        self.channels
+            .iter()
+            .filter(|c| c.factor != 1.0)
+            .map(|c| (c.name.clone(), c.factor))
+            .collect()
+    }
+}
+
+/// Sample rate per channel from the median interval between consecutive
+/// samples, which gaps in the recording do not skew. A packed block counts
+/// as one interval, its mean.
+async fn estimate_rates(pool: &PgPool, id: i32) -> Result<HashMap<String, f64>, sqlx::Error> {
+    let rows: Vec<(String, f64)> = sqlx::query_as(
+        "SELECT channel, percentile_cont(0.5) WITHIN GROUP (ORDER BY dt) FROM ( \
+           SELECT channel, extract(epoch FROM ts - lag(ts) OVER (PARTITION BY channel ORDER BY ts))::float8 AS dt \
+           FROM eeg_samples WHERE session_id = $1 \
+           UNION ALL \
+           SELECT channel, extract(epoch FROM end_ts - start_ts)::float8 / (samples - 1) \
+           FROM sample_blocks WHERE session_id = $1 AND samples > 1 \
+         ) intervals WHERE dt > 0 GROUP BY channel",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(channel, interval)| (channel, 1.0 / interval))
+        .collect())
+}
+
+/// Samples of session `id` with `from <= ts < to`, ordered by time, their
+/// values multiplied by the [factors](SessionExport::factors) of their
+/// channels.
+pub async fn fetch_window(
+    pool: &PgPool,
+    id: i32,
+    factors: &HashMap<String, f64>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<ExportSample>, sqlx::Error> {
+    let filter = SampleFilter {
+        from: Some(from),
+        to: Some(to),
+        session_id: Some(id),
+        subject_id: None,
+    };
+    let mut packed = blocks::select(None, &filter);
+    let rows = sqlx::query_as(
+        "SELECT id, ts, channel, value FROM eeg_samples \
+         WHERE session_id = $1 AND ts >= $2 AND ts < $3 ORDER BY ts, id",
+    )
+    .bind(id)
+    .bind(from)
+    .bind(to)
+    .fetch(pool);
+    let mut samples = blocks::Merged::new(rows, packed.build_query_as().fetch(pool), filter);
+    let mut window = Vec::new();
+    while let Some(EegSample {
+        channel, ts, value, ..
+    }) = samples.next().await?
+    {
+        let value = value * factors.get(&channel).copied().unwrap_or(1.0);
+        window.push(ExportSample { channel, ts, value });
+    }
+    Ok(window)
+}
+
+/// Whole seconds since `start` that hold at least one sample of session
+/// `id`, ascending. A block spans less than a second, so the seconds of its
+/// first and last sample are those it holds samples in.
+pub async fn seconds_with_samples(
+    pool: &PgPool,
+    id: i32,
+    start: DateTime<Utc>,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let rows: Vec<(i64,)> = sqlx::query_as(
+        "SELECT floor(extract(epoch FROM ts - $2))::int8 AS second \
+         FROM eeg_samples WHERE session_id = $1 \
+         UNION SELECT floor(extract(epoch FROM start_ts - $2))::int8 \
+         FROM sample_blocks WHERE session_id = $1 \
+         UNION SELECT floor(extract(epoch FROM end_ts - $2))::int8 \
+         FROM sample_blocks WHERE session_id = $1 ORDER BY second",
+    )
+    .bind(id)
+    .bind(start)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|(s,)| s).collect())
+}
+
+/// Sender half handed to writers; the receiver becomes the response body.
+pub type Chunks = mpsc::Sender<Result<Bytes, std::io::Error>>;
+
+/// Streams what `write` sends as a download named `filename`. Errors after
+/// the response has started abort the transfer.
+pub fn download<F, Fut>(filename: String, content_type: &'static str, write: F) -> Response
+where
+    F: FnOnce(Chunks) -> Fut,
+    Fut: std::future::Future<Output = Result<(), String>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(8);
+    let task = write(tx.clone());
+    let name = filename.clone();
+    tokio::spawn(async move {
+        if let Err(e) = task.await {
+            tracing::error!("export of {} failed: {}", name, e);
+            let _ = tx.send(Err(std::io::Error::other(e))).await;
+        }
+    });
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/{id}/export",
+    tag = "sessions",
+    params(("id" = i32, Path), ExportQuery, metadata::UnitQuery),
+    responses(
+        (status = 200, description = "Download of the session: EDF+, BDF+, XDF or FIF as `application/octet-stream`, BrainVision as `application/x-tar`, NWB as `application/x-hdf5`", content_type = "application/octet-stream"),
+        (status = 400, description = "Unsupported format, or a unit the channels do not convert to"),
+        (status = 404, description = "Unknown session")
+    )
+)]
+pub async fn export_session(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Parsed(Query(params)): Parsed<Query<ExportQuery>>,
+    Parsed(Query(units)): Parsed<Query<metadata::UnitQuery>>,
+) -> Result<Response, ApiError> {
+    let format = params.format.as_deref().unwrap_or("edf");
+    if !matches!(
+        format,
+        "edf" | "bdf" | "brainvision" | "xdf" | "fif" | "nwb"
+    ) {
+        return Err(ApiError::bad_request(format!(
+            "unsupported format {:?}; expected edf, bdf, brainvision, xdf, fif or nwb",
+            format
+        )));
+    }
+    let mut export = SessionExport::load(&state.pool, id)
+        .await?
+        .ok_or_else(|| ApiError::not_found(format!("unknown session {}", id)))?;
+    export.convert(units.unit).map_err(ApiError::bad_request)?;
+    if !roles::may_identify(principal.as_deref()) {
+        if let Some(subject) = export.subject.as_mut() {
+            subject.redact();
+        }
+    }
+
+    let pool = state.pool.clone();
+    let filename = |extension: &str| format!("session-{}.{}", id, extension);
+    Ok(match format {
+        "brainvision" => download(filename("tar"), "application/x-tar", move |tx| {
+            brainvision::write(pool, export, tx)
+        }),
+        "fif" => download(filename("fif"), "application/octet-stream", move |tx| {
+            fif::write(pool, export, tx)
+        }),
+        "nwb" => download(filename("nwb"), "application/x-hdf5", move |tx| {
+            nwb::write(pool, export, tx)
+        }),
+        "xdf" => download(filename("xdf"), "application/octet-stream", move |tx| {
+            xdf::write(pool, export, tx)
+        }),
+        "bdf" => download(filename("bdf"), "application/octet-stream", move |tx| {
+            edf::write(pool, export, edf::Variant::Bdf, tx)
+        }),
+        _ => download(filename("edf"), "application/octet-stream", move |tx| {
+            edf::write(pool, export, edf::Variant::Edf, tx)
+        }),
+    })
+}
@@ -0,0 +1,527 @@
+//! NWB 2 writer (<https://nwb-schema.readthedocs.io/>) for data sharing,
+//! readable by PyNWB and MatNWB.
+//!
+//! NWB is a schema on top of HDF5; the mapping is:
+//!
+//! - the session is the `NWBFile`: its notes are the session description, its
+//!   start time the session start and the reference for all timestamps;
+//! - the subject is `general/subject`, with the age in whole years from the
+//!   birth year and the handedness in the description;
+//! - the device is `general/devices/<serial>`, described by its model, with
+//!   one `ElectrodeGroup` of the same name holding every electrode;
+//! - channels in a voltage unit are the rows of the `electrodes` table and the
+//!   columns of `acquisition/ElectricalSeries`, stored as 32-bit floats in the
+//!   channel units with a per-channel factor to volts (`channel_conversion`);
+//!   other channels are one `TimeSeries` each in `acquisition`, named after
+//!   the channel and in its unit;
+//! - events are the `intervals/events` table with their label.
+//!
+//! Like the XDF export, series store the exact sample times (`timestamps`)
+//! rather than a rate, so gaps need no special handling; an
+//! `ElectricalSeries` row is one instant of all electrodes, NaN for those
+//! without a sample at that instant.
+//!
+//! An HDF5 file cannot be sent while it is being written, since the format
+//! updates earlier parts of the file as objects grow. The file is built in
+//! `EXPORT_DIR`, sent once complete and removed afterwards.
+
+use super::parquet::{export_dir, send_file};
+use super::{
+    fetch_window, seconds_between, seconds_with_samples, Chunks, ExportSample, SessionExport,
+};
+use crate::devices::registry;
+use chrono::{DateTime, Datelike, SecondsFormat, TimeZone, Utc};
+use eeg_export::units::volts;
+use rust_hdf5::{DatatypeMessage, H5Dataset, H5File, H5Group, Hdf5Error, VarLenUnicode};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const NWB_VERSION: &str = "2.7.0";
+
+/// Seconds read from the database and appended at a time.
+const SECONDS_PER_CHUNK: usize = 30;
+
+/// Rows per HDF5 chunk of the series datasets.
+const CHUNK_ROWS: usize = 4096;
+
+const ELECTRODES: &str = "/general/extracellular_ephys/electrodes";
+
+type H5Result<T> = Result<T, Hdf5Error>;
+
+/// Sets the attributes NWB readers use to map an HDF5 group to its type.
+fn neurodata_group(group: &H5Group, namespace: &str, kind: &str) -> H5Result<()> {
+    group.set_attr_string("namespace", namespace)?;
+    group.set_attr_string("neurodata_type", kind)?;
+    group.set_attr_string("object_id", &Uuid::new_v4().to_string())
+}
+
+fn string_attr(dataset: &H5Dataset, name: &str, value: &str) -> H5Result<()> {
+    dataset
+        .new_attr::<VarLenUnicode>()
+        .shape(())
+        .create(name)?
+        .write_string(value)
+}
+
+fn numeric_attr<T: rust_hdf5::H5Type + 'static>(
+    dataset: &H5Dataset,
+    name: &str,
+    value: T,
+) -> H5Result<()> {
+    dataset
+        .new_attr::<T>()
+        .shape(())
+        .create(name)?
+        .write_numeric(&value)
+}
+
+fn neurodata_dataset(dataset: &H5Dataset, namespace: &str, kind: &str) -> H5Result<()> {
+    string_attr(dataset, "namespace", namespace)?;
+    string_attr(dataset, "neurodata_type", kind)?;
+    string_attr(dataset, "object_id", &Uuid::new_v4().to_string())
+}
+
+/// A scalar text dataset.
+fn text(group: &H5Group, name: &str, value: &str) -> H5Result<H5Dataset> {
+    let bytes = if value.is_empty() {
+        vec![0]
+    } else {
+        value.as_bytes().to_vec()
+    };
+    let dataset = group
+        .new_dataset::<u8>()
+        .scalar()
+        .datatype(DatatypeMessage::fixed_string_utf8(bytes.len() as u32))
+        .create(name)?;
+    dataset.write_raw_bytes(&bytes)?;
+    Ok(dataset)
+}
+
+/// A `VectorData` column of text.
+fn text_column(table: &H5Group, name: &str, description: &str, values: &[&str]) -> H5Result<()> {
+    let column = table.write_vlen_strings(name, values)?;
+    string_attr(&column, "description", description)?;
+    neurodata_dataset(&column, "hdmf-common", "VectorData")
+}
+
+/// A `VectorData` column of numbers.
+fn number_column<T: rust_hdf5::H5Type>(
+    table: &H5Group,
+    name: &str,
+    description: &str,
+    values: &[T],
+) -> H5Result<()> {
+    let column = table
+        .new_dataset::<T>()
+        .shape([values.len()])
+        .create(name)?;
+    column.write_raw(values)?;
+    string_attr(&column, "description", description)?;
+    neurodata_dataset(&column, "hdmf-common", "VectorData")
+}
+
+/// The `id` column of a table with `rows` rows.
+fn table_ids(table: &H5Group, rows: usize) -> H5Result<()> {
+    let ids: Vec<i32> = (0..rows as i32).collect();
+    let id = table.new_dataset::<i32>().shape([rows]).create("id")?;
+    id.write_raw(&ids)?;
+    neurodata_dataset(&id, "hdmf-common", "ElementIdentifiers")
+}
+
+/// Link names cannot contain `/`.
+fn link_name(name: &str) -> String {
+    name.replace('/', "_")
+}
+
+/// A sample series being appended to.
+struct Series {
+    data: H5Dataset,
+    timestamps: H5Dataset,
+}
+
+impl Series {
+    /// Creates `data` with `columns` values per row (a 1-D dataset for 0)
+    /// and the `timestamps` next to it in `group`.
+    fn create<T: rust_hdf5::H5Type>(
+        group: &H5Group,
+        columns: usize,
+        unit: &str,
+        conversion: f32,
+    ) -> H5Result<Self> {
+        let builder = group.new_dataset::<T>();
+        let builder = if columns == 0 {
+            builder
+                .shape([0usize])
+                .chunk(&[CHUNK_ROWS])
+                .max_shape(&[None])
+        } else {
+            builder
+                .shape([0usize, columns])
+                .chunk(&[CHUNK_ROWS, columns])
+                .max_shape(&[None, Some(columns)])
+        };
+        let data = builder.deflate(4).create("data")?;
+        string_attr(&data, "unit", unit)?;
+        numeric_attr(&data, "conversion", conversion)?;
+        numeric_attr(&data, "offset", 0f32)?;
+        numeric_attr(&data, "resolution", -1f32)?;
+
+        let timestamps = group
+            .new_dataset::<f64>()
+            .shape([0usize])
+            .chunk(&[CHUNK_ROWS])
+            .max_shape(&[None])
+            .deflate(4)
+            .create("timestamps")?;
+        numeric_attr(&timestamps, "interval", 1i32)?;
+        string_attr(&timestamps, "unit", "seconds")?;
+        Ok(Self { data, timestamps })
+    }
+}
+
+/// Where the samples of a channel go.
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    /// Column of the `ElectricalSeries`.
+    Electrode(usize),
+    /// Index into the auxiliary series.
+    Aux(usize),
+}
+
+struct Nwb {
+    file: H5File,
+    reference: DateTime<Utc>,
+    targets: HashMap<String, Target>,
+    electrodes: usize,
+    eeg: Option<Series>,
+    aux: Vec<Series>,
+}
+
+fn create_subject(general: &H5Group, export: &SessionExport) -> H5Result<()> {
+    let Some(subject) = &export.subject else {
+        return Ok(());
+    };
+    let group = general.create_group("subject")?;
+    neurodata_group(&group, "core", "Subject")?;
+    // Redacted subjects are named by their id.
+    let id = subject
+        .code
+        .clone()
+        .unwrap_or_else(|| subject.id.to_string());
+    text(&group, "subject_id", &id)?;
+    text(&group, "species", "Homo sapiens")?;
+    let sex = match subject.sex.as_deref() {
+        Some("male") => "M",
+        Some("female") => "F",
+        Some(_) => "O",
+        None => "U",
+    };
+    text(&group, "sex", sex)?;
+    if let Some(year) = subject.birth_year {
+        let age = text(
+            &group,
+            "age",
+            &format!("P{}Y", export.session.started_at.year() - year),
+        )?;
+        string_attr(&age, "reference", "birth")?;
+    }
+    let mut description = Vec::new();
+    if let Some(handedness) = &subject.handedness {
+        description.push(format!("{}-handed", handedness));
+    }
+    description.extend(subject.notes.clone());
+    if !description.is_empty() {
+        text(&group, "description", &description.join("; "))?;
+    }
+    Ok(())
+}
+
+/// Creates the device, the electrode group and the electrodes table for
+/// `electrodes` (indexes into the export's channels).
+fn create_electrodes(
+    general: &H5Group,
+    export: &SessionExport,
+    electrodes: &[usize],
+) -> H5Result<()> {
+    let serial = export.session.device.as_deref().unwrap_or("unknown");
+    let name = link_name(serial);
+    let devices = general.create_group("devices")?;
+    let device = devices.create_group(&name)?;
+    neurodata_group(&device, "core", "Device")?;
+    let model = registry::get(serial).map(|d| d.model);
+    device.set_attr_string(
+        "description",
+        model.as_deref().unwrap_or("unregistered device"),
+    )?;
+
+    let ephys = general.create_group("extracellular_ephys")?;
+    let electrode_group = ephys.create_group(&name)?;
+    neurodata_group(&electrode_group, "core", "ElectrodeGroup")?;
+    electrode_group.set_attr_string("description", &format!("EEG electrodes of {}", serial))?;
+    electrode_group.set_attr_string("location", "scalp")?;
+    electrode_group.create_soft_link("device", &format!("/general/devices/{}", name))?;
+
+    let table = ephys.create_group("electrodes")?;
+    neurodata_group(&table, "hdmf-common", "DynamicTable")?;
+    table.set_attr_string("description", "EEG electrodes")?;
+    table.set_attr_string_array(
+        "colnames",
+        &["location", "group", "group_name", "reference"],
+    )?;
+    table_ids(&table, electrodes.len())?;
+    let channels: Vec<_> = electrodes.iter().map(|&c| &export.channels[c]).collect();
+    let locations: Vec<&str> = channels.iter().map(|c| c.name.as_str()).collect();
+    text_column(&table, "location", "Electrode position", &locations)?;
+    let group_path = format!("/general/extracellular_ephys/{}", name);
+    let groups = table
+        .new_dataset::<u64>()
+        .object_references()
+        .shape([electrodes.len()])
+        .create("group")?;
+    groups.write_object_references(&vec![group_path.as_str(); electrodes.len()])?;
+    string_attr(&groups, "description", "Electrode group of the electrode")?;
+    neurodata_dataset(&groups, "hdmf-common", "VectorData")?;
+    text_column(
+        &table,
+        "group_name",
+        "Name of the electrode group",
+        &vec![name.as_str(); electrodes.len()],
+    )?;
+    let references: Vec<&str> = channels
+        .iter()
+        .map(|c| c.reference.as_deref().unwrap_or(""))
+        .collect();
+    text_column(&table, "reference", "Reference electrode", &references)
+}
+
+fn create_events(file: &H5File, export: &SessionExport, reference: DateTime<Utc>) -> H5Result<()> {
+    if export.events.is_empty() {
+        return Ok(());
+    }
+    let table = file.create_group("intervals")?.create_group("events")?;
+    neurodata_group(&table, "core", "TimeIntervals")?;
+    table.set_attr_string("description", "Events of the session")?;
+    table.set_attr_string_array("colnames", &["start_time", "stop_time", "label"])?;
+    table_ids(&table, export.events.len())?;
+    let starts: Vec<f64> = export
+        .events
+        .iter()
+        .map(|e| seconds_between(reference, e.ts))
+        .collect();
+    let stops: Vec<f64> = export
+        .events
+        .iter()
+        .zip(&starts)
+        .map(|(e, start)| start + e.duration)
+        .collect();
+    number_column(&table, "start_time", "Start time of the event", &starts)?;
+    number_column(&table, "stop_time", "Stop time of the event", &stops)?;
+    let labels: Vec<&str> = export.events.iter().map(|e| e.label.as_str()).collect();
+    text_column(&table, "label", "Label of the event", &labels)
+}
+
+impl Nwb {
+    fn create(path: &Path, export: &SessionExport) -> H5Result<Self> {
+        let session = &export.session;
+        // Samples may precede the recorded start of the session.
+        let reference = session.started_at.min(export.first_ts);
+        let start = reference.to_rfc3339_opts(SecondsFormat::Micros, false);
+
+        let file = H5File::create(path)?;
+        let root = file.root_group();
+        neurodata_group(&root, "core", "NWBFile")?;
+        root.set_attr_string("nwb_version", NWB_VERSION)?;
+        let created = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, false);
+        file.write_vlen_strings("file_create_date", &[created.as_str()])?;
+        text(&root, "identifier", &Uuid::new_v4().to_string())?;
+        let description = session
+            .notes
+            .clone()
+            .unwrap_or_else(|| format!("Session {}", session.id));
+        text(&root, "session_description", &description)?;
+        text(&root, "session_start_time", &start)?;
+        text(&root, "timestamps_reference_time", &start)?;
+
+        let general = file.create_group("general")?;
+        text(&general, "session_id", &session.id.to_string())?;
+        create_subject(&general, export)?;
+
+        let (electrodes, aux): (Vec<usize>, Vec<usize>) =
+            (0..export.channels.len()).partition(|&c| volts(&export.channels[c].unit).is_some());
+        let acquisition = file.create_group("acquisition")?;
+        let eeg = if electrodes.is_empty() {
+            None
+        } else {
+            create_electrodes(&general, export, &electrodes)?;
+            let group = acquisition.create_group("ElectricalSeries")?;
+            neurodata_group(&group, "core", "ElectricalSeries")?;
+            group.set_attr_string("description", "EEG")?;
+            group.set_attr_string("comments", "no comments")?;
+            let series = Series::create::<f32>(&group, electrodes.len(), "volts", 1.0)?;
+
+            let region: Vec<i32> = (0..electrodes.len() as i32).collect();
+            let dataset = group
+                .new_dataset::<i32>()
+                .shape([region.len()])
+                .create("electrodes")?;
+            dataset.write_raw(&region)?;
+            string_attr(&dataset, "description", "Electrodes of the columns")?;
+            neurodata_dataset(&dataset, "hdmf-common", "DynamicTableRegion")?;
+            dataset
+                .new_attr::<u64>()
+                .shape(())
+                .create("table")?
+                .write_object_references(&[ELECTRODES])?;
+
+            let factors: Vec<f32> = electrodes
+                .iter()
+                .map(|&c| volts(&export.channels[c].unit).unwrap())
+                .collect();
+            let dataset = group
+                .new_dataset::<f32>()
+                .shape([factors.len()])
+                .create("channel_conversion")?;
+            dataset.write_raw(&factors)?;
+            numeric_attr(&dataset, "axis", 1i32)?;
+            Some(series)
+        };
+        let aux_series = aux
+            .iter()
+            .map(|&c| {
+                let channel = &export.channels[c];
+                let group = acquisition.create_group(&link_name(&channel.name))?;
+                neurodata_group(&group, "core", "TimeSeries")?;
+                group.set_attr_string("description", &format!("Channel {}", channel.name))?;
+                group.set_attr_string("comments", "no comments")?;
+                Series::create::<f64>(&group, 0, &channel.unit, 1.0)
+            })
+            .collect::<H5Result<Vec<_>>>()?;
+
+        file.create_group("analysis")?;
+        file.create_group("processing")?;
+        let stimulus = file.create_group("stimulus")?;
+        stimulus.create_group("presentation")?;
+        stimulus.create_group("templates")?;
+        create_events(&file, export, reference)?;
+
+        let targets = electrodes
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| (export.channels[c].name.clone(), Target::Electrode(i)))
+            .chain(
+                aux.iter()
+                    .enumerate()
+                    .map(|(i, &c)| (export.channels[c].name.clone(), Target::Aux(i))),
+            )
+            .collect();
+        Ok(Self {
+            file,
+            reference,
+            targets,
+            electrodes: electrodes.len(),
+            eeg,
+            aux: aux_series,
+        })
+    }
+
+    /// Appends samples ordered by time.
+    fn append(&self, samples: &[ExportSample]) -> H5Result<()> {
+        let mut rows: Vec<f32> = Vec::new();
+        let mut row_ts: Vec<f64> = Vec::new();
+        let mut last_ts = None;
+        let mut aux: Vec<(Vec<f64>, Vec<f64>)> = vec![Default::default(); self.aux.len()];
+        for sample in samples {
+            let ts = seconds_between(self.reference, sample.ts);
+            match self.targets.get(&sample.channel) {
+                Some(&Target::Electrode(column)) => {
+                    if last_ts != Some(sample.ts) {
+                        last_ts = Some(sample.ts);
+                        row_ts.push(ts);
+                        rows.resize(rows.len() + self.electrodes, f32::NAN);
+                    }
+                    let row = rows.len() - self.electrodes;
+                    rows[row + column] = sample.value as f32;
+                }
+                Some(&Target::Aux(i)) => {
+                    aux[i].0.push(sample.value);
+                    aux[i].1.push(ts);
+                }
+                None => {}
+            }
+        }
+        if let Some(eeg) = &self.eeg {
+            if !row_ts.is_empty() {
+                eeg.data.append(&rows)?;
+                eeg.timestamps.append(&row_ts)?;
+            }
+        }
+        for (series, (values, ts)) in self.aux.iter().zip(aux) {
+            if !ts.is_empty() {
+                series.data.append(&values)?;
+                series.timestamps.append(&ts)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs HDF5 work off the async runtime.
+async fn blocking<T, F>(f: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce() -> H5Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+async fn build(pool: &PgPool, export: SessionExport, path: PathBuf) -> Result<(), String> {
+    let id = export.session.id;
+    let factors = export.factors();
+    let start = Utc
+        .timestamp_opt(export.first_ts.timestamp(), 0)
+        .single()
+        .ok_or("invalid start time")?;
+    let written = seconds_with_samples(pool, id, start)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut nwb = blocking(move || Nwb::create(&path, &export)).await?;
+    let window_start = |s: i64| start + chrono::Duration::seconds(s);
+    for window in written.chunks(SECONDS_PER_CHUNK) {
+        let samples = fetch_window(
+            pool,
+            id,
+            &factors,
+            window_start(window[0]),
+            window_start(window[window.len() - 1] + 1),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+        nwb = blocking(move || nwb.append(&samples).map(|_| nwb)).await?;
+    }
+    blocking(move || nwb.file.close()).await
+}
+
+/// Writes `export` as NWB into `tx`.
+pub async fn write(pool: PgPool, export: SessionExport, tx: Chunks) -> Result<(), String> {
+    let dir = export_dir();
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let path = dir.join(format!(
+        "session-{}-{}.nwb",
+        export.session.id,
+        Uuid::new_v4()
+    ));
+    let result = match build(&pool, export, path.clone()).await {
+        Ok(()) => send_file(path.clone(), tx).await,
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&path).await;
+    result
+}
@@ -0,0 +1,297 @@
+//! Parquet export of samples, as [queued](super::queue) jobs
+//! (`POST /samples/export.parquet`, or `POST /exports` with `format`
+//! `parquet`).
+//!
+//! The file has one row per sample instant: a `ts` column (microseconds,
+//! UTC) followed by one nullable `DOUBLE` column per requested channel, null
+//! where that channel has no sample at the instant.
+
+use super::queue::{self, ExportRequest, Job};
+use super::Chunks;
+use crate::error::ApiError;
+use crate::store::blocks;
+use crate::validation::{Parsed, Valid};
+use crate::{config, AppState, ChannelQuery, EegSample, SampleFilter};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::Deserialize;
+use sqlx::{PgPool, QueryBuilder};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use utoipa::IntoParams;
+
+const DEFAULT_ROW_GROUP_SIZE: usize = 100_000;
+const MIN_ROW_GROUP_SIZE: usize = 1_000;
+const MAX_ROW_GROUP_SIZE: usize = 1_000_000;
+
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Typed part of `POST /samples/export.parquet`; the channels and the window
+/// are read as for `/samples`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ParquetQuery {
+    /// Rows per row group.
+    pub(super) row_group_size: Option<usize>,
+    /// `snappy` (default), `zstd` or `none`.
+    pub(super) compression: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Options {
+    row_group_size: usize,
+    compression: Compression,
+}
+
+impl ParquetQuery {
+    pub(super) fn options(&self) -> Result<Options, String> {
+        let row_group_size = self.row_group_size.unwrap_or(DEFAULT_ROW_GROUP_SIZE);
+        if !(MIN_ROW_GROUP_SIZE..=MAX_ROW_GROUP_SIZE).contains(&row_group_size) {
+            return Err(format!(
+                "row_group_size must be between {} and {}",
+                MIN_ROW_GROUP_SIZE, MAX_ROW_GROUP_SIZE
+            ));
+        }
+        let compression = match self.compression.as_deref().unwrap_or("snappy") {
+            "snappy" => Compression::SNAPPY,
+            "zstd" => Compression::ZSTD(ZstdLevel::default()),
+            "none" => Compression::UNCOMPRESSED,
+            other => {
+                return Err(format!(
+                    "unsupported compression {:?}; expected snappy, zstd or none",
+                    other
+                ))
+            }
+        };
+        Ok(Options {
+            row_group_size,
+            compression,
+        })
+    }
+}
+
+pub(super) fn export_dir() -> PathBuf {
+    config::get()
+        .server
+        .export_dir
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("eeg-exports"))
+}
+
+/// Rows of the row group being built, one column per channel.
+struct Batch {
+    ts: Vec<i64>,
+    columns: Vec<Vec<Option<f64>>>,
+}
+
+impl Batch {
+    fn new(channels: usize, capacity: usize) -> Self {
+        Self {
+            ts: Vec::with_capacity(capacity),
+            columns: (0..channels)
+                .map(|_| Vec::with_capacity(capacity))
+                .collect(),
+        }
+    }
+
+    /// Appends the instant `ts` and clears `row` for the next one.
+    fn push(&mut self, ts: i64, row: &mut [Option<f64>]) {
+        self.ts.push(ts);
+        for (column, value) in self.columns.iter_mut().zip(row.iter_mut()) {
+            column.push(value.take());
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ts.len()
+    }
+
+    fn take(&mut self, schema: &Arc<Schema>) -> Result<RecordBatch, String> {
+        let capacity = self.ts.capacity();
+        let ts = std::mem::replace(&mut self.ts, Vec::with_capacity(capacity));
+        let mut arrays: Vec<ArrayRef> = vec![Arc::new(
+            TimestampMicrosecondArray::from(ts).with_timezone("UTC"),
+        )];
+        for column in &mut self.columns {
+            let values = std::mem::replace(column, Vec::with_capacity(capacity));
+            arrays.push(Arc::new(Float64Array::from(values)));
+        }
+        RecordBatch::try_new(schema.clone(), arrays).map_err(|e| e.to_string())
+    }
+}
+
+/// Writes `batch` on the blocking pool, handing the writer back.
+async fn write_batch(
+    mut writer: ArrowWriter<File>,
+    batch: RecordBatch,
+) -> Result<ArrowWriter<File>, String> {
+    tokio::task::spawn_blocking(move || writer.write(&batch).map(|_| writer))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Writes the samples, multiplied by the [factors](crate::metadata::factors)
+/// of their channels, to `path`, reporting the rows written after each row
+/// group, and returns the file size.
+pub(super) async fn write(
+    pool: &PgPool,
+    path: PathBuf,
+    channels: &[String],
+    filter: SampleFilter,
+    factors: &HashMap<String, f64>,
+    options: Options,
+    progress: impl Fn(u64),
+) -> Result<u64, String> {
+    let mut fields = vec![Field::new(
+        "ts",
+        DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        false,
+    )];
+    fields.extend(
+        channels
+            .iter()
+            .map(|c| Field::new(c, DataType::Float64, true)),
+    );
+    let schema = Arc::new(Schema::new(fields));
+    let properties = WriterProperties::builder()
+        .set_compression(options.compression)
+        .set_max_row_group_row_count(Some(options.row_group_size))
+        .build();
+    let file = File::create(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut writer =
+        ArrowWriter::try_new(file, schema.clone(), Some(properties)).map_err(|e| e.to_string())?;
+
+    let index: HashMap<&str, usize> = channels
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.as_str(), i))
+        .collect();
+    let factors: Vec<f64> = channels
+        .iter()
+        .map(|c| factors.get(c).copied().unwrap_or(1.0))
+        .collect();
+    let mut query =
+        QueryBuilder::new("SELECT id, ts, channel, value FROM eeg_samples WHERE channel = ANY(");
+    query.push_bind(channels.to_vec()).push(")");
+    filter.push_to(&mut query);
+    query.push(" ORDER BY ts, id");
+    let mut packed = blocks::select(Some(channels), &filter);
+    let mut samples = blocks::Merged::new(
+        query.build_query_as().fetch(pool),
+        packed.build_query_as().fetch(pool),
+        filter,
+    );
+
+    let mut batch = Batch::new(channels.len(), options.row_group_size);
+    let mut row = vec![None; channels.len()];
+    let mut current = None;
+    let mut rows = 0;
+    while let Some(EegSample {
+        ts, channel, value, ..
+    }) = samples.next().await.map_err(|e| e.to_string())?
+    {
+        let ts = ts.timestamp_micros();
+        if current != Some(ts) {
+            if let Some(previous) = current {
+                batch.push(previous, &mut row);
+            }
+            current = Some(ts);
+            if batch.len() == options.row_group_size {
+                writer = write_batch(writer, batch.take(&schema)?).await?;
+                rows += options.row_group_size as u64;
+                progress(rows);
+            }
+        }
+        // A channel sampled twice at one instant keeps the later value.
+        let i = index[channel.as_str()];
+        row[i] = Some(value * factors[i]);
+    }
+    if let Some(previous) = current {
+        batch.push(previous, &mut row);
+    }
+    if batch.len() > 0 {
+        rows += batch.len() as u64;
+        writer = write_batch(writer, batch.take(&schema)?).await?;
+        progress(rows);
+    }
+    tokio::task::spawn_blocking(move || writer.close())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(metadata.len())
+}
+
+/// Queues an export job; `202` with the job. The same as `POST /exports`
+/// with `format` `parquet`.
+#[utoipa::path(
+    post,
+    path = "/samples/export.parquet",
+    tag = "exports",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; `A3` by default"),
+        ParquetQuery,
+        SampleFilter,
+        crate::metadata::UnitQuery
+    ),
+    responses(
+        (status = 202, description = "The job, queued", body = Job),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn create_export(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<ParquetQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    Parsed(Query(units)): Parsed<Query<crate::metadata::UnitQuery>>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let request = ExportRequest {
+        format: "parquet".to_string(),
+        session_id: filter.session_id,
+        channels: raw.channels().map_err(ApiError::bad_request)?,
+        from: filter.from,
+        to: filter.to,
+        subject_id: filter.subject_id,
+        row_group_size: params.row_group_size,
+        compression: params.compression,
+        unit: units.unit,
+    };
+    let job = queue::enqueue(&state.pool, request, true).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+pub(super) async fn send_file(path: PathBuf, tx: Chunks) -> Result<(), String> {
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut buf = vec![0u8; CHUNK_BYTES];
+    loop {
+        let n = file.read(&mut buf).await.map_err(|e| e.to_string())?;
+        if n == 0
+            || tx
+                .send(Ok(Bytes::copy_from_slice(&buf[..n])))
+                .await
+                .is_err()
+        {
+            return Ok(());
+        }
+    }
+}
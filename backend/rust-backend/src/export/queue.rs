@@ -0,0 +1,711 @@
+//! Export jobs (`POST /exports`), queued in `export_jobs` and run by
+//! background workers.
+//!
+//! Large exports outlast any request timeout, so the request only queues a
+//! job and returns it; `GET /exports/{id}` reports its status and progress,
+//! and `download_url` once the file can be fetched from
+//! `GET /exports/{id}/file`. Any format can be queued: `parquet` and `csv`
+//! for samples across sessions, or one of the session formats of
+//! `GET /sessions/{id}/export`.
+//!
+//! `server.export_workers` workers per backend claim the oldest queued job
+//! with `FOR UPDATE SKIP LOCKED`, so several backends share one queue, and
+//! write the file to `EXPORT_DIR`, which they must share too. A running job
+//! records its progress every [`HEARTBEAT`]; one whose heartbeat stopped
+//! for [`STALE_AFTER`], because its backend stopped, is run again from the
+//! start. Finished jobs and their files are removed by `DELETE
+//! /exports/{id}`, or by the `exports` [job](crate::jobs) [`MAX_AGE`] after
+//! they finished.
+
+use super::parquet::{self, export_dir, send_file, ParquetQuery};
+use super::{brainvision, csv, download, edf, fif, nwb, xdf, Chunks, SessionExport};
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{config, jobs, metadata, roles, versioning, AppState, SampleFilter};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Response,
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as Jsonb;
+use sqlx::PgPool;
+use std::path::{Path as FilePath, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Notify};
+use utoipa::ToSchema;
+
+/// How often a running job records its progress.
+pub const HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// How long a running job may go without a heartbeat before it is run again.
+pub const STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// How often idle workers look for jobs queued by other backends.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long finished jobs and their files are kept.
+pub const MAX_AGE: Duration = Duration::from_secs(24 * 3600);
+
+/// Jobs listed by `GET /exports`.
+const LIST_LIMIT: i64 = 100;
+
+/// Formats of one session, as for `GET /sessions/{id}/export`.
+const SESSION_FORMATS: [&str; 6] = ["edf", "bdf", "brainvision", "xdf", "fif", "nwb"];
+
+/// Wakes a worker of this backend when a job is queued.
+static QUEUED: Notify = Notify::const_new();
+
+/// Body of `POST /exports`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ExportRequest {
+    /// `parquet` or `csv` for samples across sessions, or a session format:
+    /// `edf`, `bdf`, `brainvision`, `xdf`, `fif` or `nwb`.
+    pub format: String,
+    /// Session to export, for the session formats; otherwise only the
+    /// samples recorded in it.
+    pub session_id: Option<i32>,
+    /// Channels of a `parquet` or `csv` export; `["A3"]` by default.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Only samples with `ts >= from`, for `parquet` and `csv`.
+    pub from: Option<DateTime<Utc>>,
+    /// Only samples with `ts < to`, for `parquet` and `csv`.
+    pub to: Option<DateTime<Utc>>,
+    /// Only samples recorded in sessions of this subject, for `parquet` and
+    /// `csv`.
+    pub subject_id: Option<i32>,
+    /// Rows per row group, for `parquet`.
+    pub row_group_size: Option<usize>,
+    /// `snappy` (default), `zstd` or `none`, for `parquet`.
+    pub compression: Option<String>,
+    /// `uV` or `mV` to convert the values to; as stored by default.
+    pub unit: Option<metadata::Unit>,
+}
+
+impl ExportRequest {
+    fn is_session_format(&self) -> bool {
+        SESSION_FORMATS.contains(&self.format.as_str())
+    }
+
+    fn filter(&self) -> SampleFilter {
+        SampleFilter {
+            from: self.from,
+            to: self.to,
+            session_id: self.session_id,
+            subject_id: self.subject_id,
+        }
+    }
+
+    fn parquet(&self) -> ParquetQuery {
+        ParquetQuery {
+            row_group_size: self.row_group_size,
+            compression: self.compression.clone(),
+        }
+    }
+
+    /// The channels without repetitions, `A3` when none are given.
+    fn normalize(&mut self) {
+        let mut channels: Vec<String> = Vec::new();
+        for channel in self.channels.drain(..) {
+            let channel = channel.trim().to_string();
+            if !channel.is_empty() && !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+        if channels.is_empty() && !self.is_session_format() {
+            channels.push("A3".to_string());
+        }
+        self.channels = channels;
+    }
+}
+
+impl Validate for ExportRequest {
+    fn validate(&self, errors: &mut FieldErrors) {
+        let samples = matches!(self.format.as_str(), "parquet" | "csv");
+        if !samples && !self.is_session_format() {
+            errors.add(
+                "format",
+                "must be parquet, csv, edf, bdf, brainvision, xdf, fif or nwb",
+            );
+            return;
+        }
+        if self.is_session_format() {
+            errors.check(
+                self.session_id.is_some(),
+                "session_id",
+                format!("is required for {}", self.format),
+            );
+            let only_samples = "only applies to parquet and csv exports";
+            errors.check(self.channels.is_empty(), "channels", only_samples);
+            errors.check(self.from.is_none(), "from", only_samples);
+            errors.check(self.to.is_none(), "to", only_samples);
+            errors.check(self.subject_id.is_none(), "subject_id", only_samples);
+        }
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            errors.check(from < to, "to", "must be after from");
+        }
+        let max_channels = config::get().limits.max_query_channels;
+        errors.check(
+            self.channels.len() <= max_channels,
+            "channels",
+            format!("at most {} channels per export", max_channels),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = ExportJobStatus)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    fn parse(status: &str) -> Self {
+        match status {
+            "running" => JobStatus::Running,
+            "done" => JobStatus::Done,
+            "failed" => JobStatus::Failed,
+            _ => JobStatus::Queued,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[schema(as = ExportJob)]
+pub struct Job {
+    pub id: i64,
+    pub format: String,
+    pub status: JobStatus,
+    pub session_id: Option<i32>,
+    /// Channels of a `parquet` or `csv` export.
+    pub channels: Vec<String>,
+    /// Rows written so far, for `parquet`.
+    pub rows: Option<i64>,
+    /// Bytes written so far, or the file size once the job is done.
+    pub bytes: i64,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Where the file is downloaded once the job is done.
+    pub download_url: Option<String>,
+}
+
+type JobRow = (
+    i64,
+    Jsonb<ExportRequest>,
+    String,
+    Option<i64>,
+    i64,
+    Option<String>,
+    DateTime<Utc>,
+    Option<DateTime<Utc>>,
+    Option<DateTime<Utc>>,
+);
+
+const COLUMNS: &str =
+    "id, request, status, rows, bytes, error, created_at, started_at, finished_at";
+
+impl From<JobRow> for Job {
+    fn from(row: JobRow) -> Self {
+        let (id, request, status, rows, bytes, error, created_at, started_at, finished_at) = row;
+        let status = JobStatus::parse(&status);
+        let request = request.0;
+        Job {
+            id,
+            format: request.format,
+            status,
+            session_id: request.session_id,
+            channels: request.channels,
+            rows,
+            bytes,
+            error,
+            created_at,
+            started_at,
+            finished_at,
+            download_url: (status == JobStatus::Done)
+                .then(|| format!("/{}/exports/{}/file", versioning::CURRENT, id)),
+        }
+    }
+}
+
+/// File extension and content type of `format`.
+fn file_type(format: &str) -> (&'static str, &'static str) {
+    match format {
+        "parquet" => ("parquet", "application/vnd.apache.parquet"),
+        "csv" => ("csv", "text/csv; charset=utf-8"),
+        "brainvision" => ("tar", "application/x-tar"),
+        "nwb" => ("nwb", "application/x-hdf5"),
+        "bdf" => ("bdf", "application/octet-stream"),
+        "xdf" => ("xdf", "application/octet-stream"),
+        "fif" => ("fif", "application/octet-stream"),
+        _ => ("edf", "application/octet-stream"),
+    }
+}
+
+fn file_path(id: i64, format: &str) -> PathBuf {
+    export_dir().join(format!("export-{}.{}", id, file_type(format).0))
+}
+
+/// Name the file is downloaded under.
+fn download_name(id: i64, request: &ExportRequest) -> String {
+    let extension = file_type(&request.format).0;
+    match request.session_id {
+        Some(session_id) if request.is_session_format() => {
+            format!("session-{}.{}", session_id, extension)
+        }
+        _ => format!("samples-{}.{}", id, extension),
+    }
+}
+
+/// Queues `request`, after checking what can be checked before it runs.
+pub async fn enqueue(
+    pool: &PgPool,
+    mut request: ExportRequest,
+    identify: bool,
+) -> Result<Job, ApiError> {
+    request.normalize();
+    if request.format == "parquet" {
+        request.parquet().options().map_err(ApiError::bad_request)?;
+    }
+    if !request.is_session_format() {
+        metadata::factors(pool, &request.channels, request.session_id, request.unit).await?;
+    }
+    if let (true, Some(session_id)) = (request.is_session_format(), request.session_id) {
+        let exists: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM sessions WHERE id = $1)")
+                .bind(session_id)
+                .fetch_one(pool)
+                .await?;
+        if !exists {
+            return Err(ApiError::not_found(format!(
+                "unknown session {}",
+                session_id
+            )));
+        }
+        metadata::check_session(pool, session_id, request.unit).await?;
+    }
+    let row: JobRow = sqlx::query_as(&format!(
+        "INSERT INTO export_jobs (format, request, identify) VALUES ($1, $2, $3) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&request.format)
+    .bind(Jsonb(&request))
+    .bind(identify)
+    .fetch_one(pool)
+    .await?;
+    QUEUED.notify_one();
+    Ok(row.into())
+}
+
+/// Starts the workers of this backend.
+pub fn spawn(pool: PgPool) {
+    for _ in 0..config::get().server.export_workers {
+        tokio::spawn(work(pool.clone()));
+    }
+}
+
+async fn work(pool: PgPool) {
+    loop {
+        match claim(&pool).await {
+            Ok(Some(claimed)) => run(&pool, claimed).await,
+            Ok(None) => {
+                tokio::select! {
+                    _ = QUEUED.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+            Err(e) => {
+                tracing::warn!("cannot claim export jobs: {}", e);
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+struct Claimed {
+    id: i64,
+    request: ExportRequest,
+    identify: bool,
+}
+
+/// Marks the oldest queued job, or a stale running one, as running here.
+async fn claim(pool: &PgPool) -> Result<Option<Claimed>, sqlx::Error> {
+    let claimed: Option<(i64, Jsonb<ExportRequest>, bool)> = sqlx::query_as(
+        "UPDATE export_jobs SET status = 'running', started_at = now(), heartbeat_at = now(), \
+         rows = NULL, bytes = 0 \
+         WHERE id = ( \
+           SELECT id FROM export_jobs \
+           WHERE status = 'queued' \
+              OR (status = 'running' AND heartbeat_at < now() - make_interval(secs => $1)) \
+           ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED \
+         ) RETURNING id, request, identify",
+    )
+    .bind(STALE_AFTER.as_secs_f64())
+    .fetch_optional(pool)
+    .await?;
+    Ok(claimed.map(|(id, request, identify)| Claimed {
+        id,
+        request: request.0,
+        identify,
+    }))
+}
+
+/// Records the rows and bytes written; `false` once the job was deleted.
+async fn heartbeat(
+    pool: &PgPool,
+    id: i64,
+    path: &FilePath,
+    rows: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let bytes = tokio::fs::metadata(path)
+        .await
+        .map_or(0, |m| m.len() as i64);
+    let result = sqlx::query(
+        "UPDATE export_jobs SET heartbeat_at = now(), rows = $2, bytes = $3 \
+         WHERE id = $1 AND status = 'running'",
+    )
+    .bind(id)
+    .bind(rows)
+    .bind(bytes)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Writes what `writer` sends to `path`.
+async fn to_file<Fut>(path: &FilePath, writer: impl FnOnce(Chunks) -> Fut) -> Result<(), String>
+where
+    Fut: std::future::Future<Output = Result<(), String>> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(8);
+    let writer = tokio::spawn(writer(tx));
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    while let Some(chunk) = rx.recv().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+    }
+    file.flush().await.map_err(|e| e.to_string())?;
+    writer.await.map_err(|e| e.to_string())?
+}
+
+/// Writes the file of `request`, reporting parquet rows as they are written.
+async fn write(
+    pool: &PgPool,
+    path: &FilePath,
+    request: &ExportRequest,
+    identify: bool,
+    rows: &AtomicI64,
+) -> Result<(), String> {
+    let pool = pool.clone();
+    match request.format.as_str() {
+        "parquet" => {
+            let options = request.parquet().options()?;
+            let progress = |written: u64| rows.store(written as i64, Ordering::Relaxed);
+            let factors =
+                metadata::factors(&pool, &request.channels, request.session_id, request.unit)
+                    .await
+                    .map_err(|e| e.message)?;
+            parquet::write(
+                &pool,
+                path.to_path_buf(),
+                &request.channels,
+                request.filter(),
+                &factors,
+                options,
+                progress,
+            )
+            .await
+            .map(|_| ())
+        }
+        "csv" => {
+            let (channels, filter) = (request.channels.clone(), request.filter());
+            let factors = metadata::factors(&pool, &channels, request.session_id, request.unit)
+                .await
+                .map_err(|e| e.message)?;
+            to_file(path, move |tx| {
+                csv::write(pool, channels, filter, factors, tx)
+            })
+            .await
+        }
+        format => {
+            let session_id = request.session_id.unwrap_or_default();
+            let mut export = SessionExport::load(&pool, session_id)
+                .await
+                .map_err(|e| e.message)?
+                .ok_or_else(|| format!("unknown session {}", session_id))?;
+            export.convert(request.unit)?;
+            if !identify {
+                if let Some(subject) = export.subject.as_mut() {
+                    subject.redact();
+                }
+            }
+            match format {
+                "bdf" => {
+                    to_file(path, move |tx| {
+                        edf::write(pool, export, edf::Variant::Bdf, tx)
+                    })
+                    .await
+                }
+                "brainvision" => {
+                    to_file(path, move |tx| brainvision::write(pool, export, tx)).await
+                }
+                "xdf" => to_file(path, move |tx| xdf::write(pool, export, tx)).await,
+                "fif" => to_file(path, move |tx| fif::write(pool, export, tx)).await,
+                "nwb" => to_file(path, move |tx| nwb::write(pool, export, tx)).await,
+                _ => {
+                    to_file(path, move |tx| {
+                        edf::write(pool, export, edf::Variant::Edf, tx)
+                    })
+                    .await
+                }
+            }
+        }
+    }
+}
+
+/// Runs a claimed job, recording its progress until it finishes or is
+/// deleted.
+async fn run(pool: &PgPool, job: Claimed) {
+    let id = job.id;
+    let path = file_path(id, &job.request.format);
+    let rows = AtomicI64::new(0);
+    let counts_rows = job.request.format == "parquet";
+    let written = || counts_rows.then(|| rows.load(Ordering::Relaxed));
+
+    let outcome = match tokio::fs::create_dir_all(export_dir()).await {
+        Ok(()) => {
+            let writing = write(pool, &path, &job.request, job.identify, &rows);
+            tokio::pin!(writing);
+            let mut beat = tokio::time::interval(HEARTBEAT);
+            beat.tick().await;
+            loop {
+                tokio::select! {
+                    outcome = &mut writing => break Some(outcome),
+                    _ = beat.tick() => match heartbeat(pool, id, &path, written()).await {
+                        Ok(true) => {}
+                        Ok(false) => break None,
+                        Err(e) => tracing::warn!("export {}: cannot record progress: {}", id, e),
+                    },
+                }
+            }
+        }
+        Err(e) => Some(Err(format!("{}: {}", export_dir().display(), e))),
+    };
+    let Some(outcome) = outcome else {
+        tracing::info!("export {} was deleted while running", id);
+        let _ = tokio::fs::remove_file(&path).await;
+        return;
+    };
+
+    if let Err(e) = &outcome {
+        tracing::error!("export {} failed: {}", id, e);
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+    let bytes = match &outcome {
+        Ok(()) => tokio::fs::metadata(&path)
+            .await
+            .map_or(0, |m| m.len() as i64),
+        Err(_) => 0,
+    };
+    let finished = sqlx::query(
+        "UPDATE export_jobs SET status = $2, rows = $3, bytes = $4, error = $5, \
+         finished_at = now(), heartbeat_at = now() \
+         WHERE id = $1 AND status = 'running'",
+    )
+    .bind(id)
+    .bind(if outcome.is_ok() { "done" } else { "failed" })
+    .bind(written())
+    .bind(bytes)
+    .bind(outcome.err())
+    .execute(pool)
+    .await;
+    match finished {
+        Ok(result) if result.rows_affected() == 0 => {
+            // Deleted while finishing.
+            let _ = tokio::fs::remove_file(&path).await;
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!("export {}: cannot record the outcome: {}", id, e),
+    }
+}
+
+/// The `exports` [job](crate::jobs): removes jobs that finished more than
+/// [`MAX_AGE`] ago, and their files.
+pub struct Expire {
+    pub pool: PgPool,
+}
+
+#[axum::async_trait]
+impl jobs::Job for Expire {
+    async fn run(&mut self) -> Result<String, String> {
+        let expired: Vec<(i64, String)> = sqlx::query_as(
+            "DELETE FROM export_jobs WHERE finished_at < now() - make_interval(secs => $1) \
+             RETURNING id, format",
+        )
+        .bind(MAX_AGE.as_secs_f64())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        for (id, format) in &expired {
+            let _ = tokio::fs::remove_file(file_path(*id, format)).await;
+        }
+        Ok(format!("{} exports removed", expired.len()))
+    }
+}
+
+/// Queues an export; `202` with the job.
+#[utoipa::path(
+    post,
+    path = "/exports",
+    tag = "exports",
+    request_body = ExportRequest,
+    responses(
+        (status = 202, description = "The job, queued", body = Job),
+        (status = 400, description = "Invalid Parquet options, or a unit the channels do not convert to"),
+        (status = 404, description = "Unknown session"),
+        (status = 422, description = "Fields that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn create_export(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Valid(Json(request)): Valid<Json<ExportRequest>>,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let identify = roles::may_identify(principal.as_deref());
+    let job = enqueue(&state.pool, request, identify).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Export jobs, newest first.
+#[utoipa::path(
+    get,
+    path = "/exports",
+    tag = "exports",
+    responses((status = 200, description = "The latest 100 export jobs, newest first", body = Vec<Job>))
+)]
+pub async fn list_exports(State(state): State<AppState>) -> Result<Json<Vec<Job>>, ApiError> {
+    let rows: Vec<JobRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM export_jobs ORDER BY id DESC LIMIT $1",
+        COLUMNS
+    ))
+    .bind(LIST_LIMIT)
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(Json(rows.into_iter().map(Job::from).collect()))
+}
+
+fn not_found(id: i64) -> ApiError {
+    ApiError::not_found(format!("unknown export {}", id))
+}
+
+async fn load(pool: &PgPool, id: i64) -> Result<JobRow, ApiError> {
+    let row: Option<JobRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM export_jobs WHERE id = $1",
+        COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    row.ok_or_else(|| not_found(id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/exports/{id}",
+    tag = "exports",
+    params(("id" = i64, Path)),
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown job")
+    )
+)]
+pub async fn get_export(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i64>>,
+) -> Result<Json<Job>, ApiError> {
+    Ok(Json(load(&state.pool, id).await?.into()))
+}
+
+/// The file of a finished job; `409` while it is queued or running, or if
+/// it failed.
+#[utoipa::path(
+    get,
+    path = "/exports/{id}/file",
+    tag = "exports",
+    params(("id" = i64, Path)),
+    responses(
+        (status = 200, description = "The file, with the content type of its format", content_type = "application/octet-stream"),
+        (status = 404, description = "Unknown job"),
+        (status = 409, description = "Job queued, running or failed")
+    )
+)]
+pub async fn download_export(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i64>>,
+) -> Result<Response, ApiError> {
+    let row = load(&state.pool, id).await?;
+    let (request, status) = (row.1 .0, JobStatus::parse(&row.2));
+    match status {
+        JobStatus::Done => {
+            let path = file_path(id, &request.format);
+            if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                return Err(ApiError::not_found(format!(
+                    "the file of export {} is gone",
+                    id
+                )));
+            }
+            Ok(download(
+                download_name(id, &request),
+                file_type(&request.format).1,
+                move |tx| send_file(path, tx),
+            ))
+        }
+        JobStatus::Queued => Err(ApiError::conflict(format!("export {} is queued", id))),
+        JobStatus::Running => Err(ApiError::conflict(format!(
+            "export {} is still running",
+            id
+        ))),
+        JobStatus::Failed => Err(ApiError::conflict(format!("export {} failed", id))),
+    }
+}
+
+/// Forgets a job and removes its file; a running job stops at its next
+/// heartbeat and removes what it wrote.
+#[utoipa::path(
+    delete,
+    path = "/exports/{id}",
+    tag = "exports",
+    params(("id" = i64, Path)),
+    responses(
+        (status = 204, description = "Forgotten and its file removed"),
+        (status = 404, description = "Unknown job")
+    )
+)]
+pub async fn delete_export(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i64>>,
+) -> Result<StatusCode, ApiError> {
+    let deleted: Option<(String, String)> =
+        sqlx::query_as("DELETE FROM export_jobs WHERE id = $1 RETURNING format, status")
+            .bind(id)
+            .fetch_optional(&state.pool)
+            .await?;
+    let (format, status) = deleted.ok_or_else(|| not_found(id))?;
+    if status == "done" {
+        let _ = tokio::fs::remove_file(file_path(id, &format)).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,331 @@
+//! XDF writer (<https://github.com/sccn/xdf/wiki/Specifications>).
+//!
+//! XDF holds several streams with their own rates and formats side by side,
+//! which keeps auxiliary signals and markers with the EEG they belong to:
+//!
+//! - channels in a voltage unit (`uV`, `mV`, ...) form `EEG` streams and all
+//!   other channels (accelerometer, temperature, ...) `AUX` streams, one
+//!   stream per kind and sample rate;
+//! - events form a `Markers` stream of strings (the labels) without a
+//!   nominal rate.
+//!
+//! Every sample carries its timestamp in seconds since the Unix epoch. A
+//! stream sample is one instant of all its channels; channels without a
+//! value at that instant are NaN. Stored timestamps are already on the
+//! server's UTC clock (the LSL recorder applies `time_correction` on ingest),
+//! so the ClockOffset chunks written for every stream and window carry an
+//! offset of 0, which readers such as pyxdf apply as-is.
+
+use super::{fetch_window, seconds_with_samples, Chunks, ExportChannel, SessionExport};
+use axum::body::Bytes;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Seconds read from the database and sent per chunk.
+const SECONDS_PER_CHUNK: usize = 30;
+
+const TAG_FILE_HEADER: u16 = 1;
+const TAG_STREAM_HEADER: u16 = 2;
+const TAG_SAMPLES: u16 = 3;
+const TAG_CLOCK_OFFSET: u16 = 4;
+const TAG_BOUNDARY: u16 = 5;
+const TAG_STREAM_FOOTER: u16 = 6;
+
+/// Fixed content of boundary chunks, which let readers resynchronize.
+const BOUNDARY: [u8; 16] = [
+    0x43, 0xA5, 0x46, 0xDC, 0xCB, 0xF5, 0x41, 0x0F, 0xB3, 0x0E, 0xD5, 0x46, 0x73, 0x83, 0xCB, 0xE4,
+];
+
+const VOLTAGE_UNITS: &[&str] = &["nV", "uV", "µV", "mV", "V"];
+
+struct Stream {
+    id: u32,
+    name: String,
+    kind: &'static str,
+    /// Nominal rate in Hz; 0 for irregular streams.
+    rate: f64,
+    /// Indexes into the export's channels; empty for the marker stream.
+    channels: Vec<usize>,
+    first: Option<f64>,
+    last: f64,
+    count: u64,
+    offsets: Vec<f64>,
+}
+
+impl Stream {
+    fn new(kind: &'static str, rate: f64, channels: Vec<usize>) -> Self {
+        Self {
+            id: 0,
+            name: String::new(),
+            kind,
+            rate,
+            channels,
+            first: None,
+            last: 0.0,
+            count: 0,
+            offsets: Vec::new(),
+        }
+    }
+
+    fn saw(&mut self, ts: f64) {
+        self.first.get_or_insert(ts);
+        self.last = ts;
+        self.count += 1;
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn seconds(ts: DateTime<Utc>) -> f64 {
+    ts.timestamp_micros() as f64 / 1e6
+}
+
+/// Appends a variable-length integer: its byte count (1, 4 or 8) and the
+/// little-endian value.
+fn varlen(out: &mut Vec<u8>, value: u64) {
+    if let Ok(v) = u8::try_from(value) {
+        out.extend_from_slice(&[1, v]);
+    } else if let Ok(v) = u32::try_from(value) {
+        out.push(4);
+        out.extend_from_slice(&v.to_le_bytes());
+    } else {
+        out.push(8);
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn chunk(out: &mut Vec<u8>, tag: u16, content: &[u8]) {
+    varlen(out, content.len() as u64 + 2);
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(content);
+}
+
+fn stream_chunk(out: &mut Vec<u8>, tag: u16, stream: u32, content: &[u8]) {
+    let mut body = Vec::with_capacity(content.len() + 4);
+    body.extend_from_slice(&stream.to_le_bytes());
+    body.extend_from_slice(content);
+    chunk(out, tag, &body);
+}
+
+fn clock_offset(out: &mut Vec<u8>, stream: &mut Stream, at: f64) {
+    let mut content = Vec::with_capacity(16);
+    content.extend_from_slice(&at.to_le_bytes());
+    content.extend_from_slice(&0f64.to_le_bytes());
+    stream_chunk(out, TAG_CLOCK_OFFSET, stream.id, &content);
+    stream.offsets.push(at);
+}
+
+fn stream_header(stream: &Stream, channels: &[ExportChannel], source: &str) -> String {
+    let format = if stream.channels.is_empty() {
+        "string"
+    } else {
+        "double64"
+    };
+    let mut xml = format!(
+        "<?xml version=\"1.0\"?><info><name>{}</name><type>{}</type>\
+         <channel_count>{}</channel_count><nominal_srate>{}</nominal_srate>\
+         <channel_format>{}</channel_format><source_id>{}</source_id><desc><channels>",
+        escape(&stream.name),
+        stream.kind,
+        stream.channels.len().max(1),
+        stream.rate,
+        format,
+        escape(source)
+    );
+    for &c in &stream.channels {
+        let channel = &channels[c];
+        let _ = write!(
+            xml,
+            "<channel><label>{}</label><unit>{}</unit><type>{}</type></channel>",
+            escape(&channel.name),
+            escape(&channel.unit),
+            stream.kind
+        );
+    }
+    xml.push_str("</channels></desc></info>");
+    xml
+}
+
+fn stream_footer(stream: &Stream) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\"?><info><first_timestamp>{}</first_timestamp>\
+         <last_timestamp>{}</last_timestamp><sample_count>{}</sample_count><clock_offsets>",
+        stream.first.unwrap_or(0.0),
+        stream.last,
+        stream.count
+    );
+    for at in &stream.offsets {
+        let _ = write!(xml, "<offset><time>{}</time><value>0</value></offset>", at);
+    }
+    xml.push_str("</clock_offsets></info>");
+    xml
+}
+
+/// Groups the channels into streams; the marker stream comes last.
+fn streams(export: &SessionExport) -> Vec<Stream> {
+    let mut streams: Vec<Stream> = Vec::new();
+    for (c, channel) in export.channels.iter().enumerate() {
+        let kind = if VOLTAGE_UNITS.contains(&channel.unit.as_str()) {
+            "EEG"
+        } else {
+            "AUX"
+        };
+        match streams
+            .iter_mut()
+            .find(|s| s.kind == kind && s.rate == channel.sample_rate)
+        {
+            Some(stream) => stream.channels.push(c),
+            None => streams.push(Stream::new(kind, channel.sample_rate, vec![c])),
+        }
+    }
+    streams.push(Stream::new("Markers", 0.0, Vec::new()));
+
+    let session = export.session.id;
+    for i in 0..streams.len() {
+        let kind = streams[i].kind;
+        let several = streams.iter().filter(|s| s.kind == kind).count() > 1;
+        let stream = &mut streams[i];
+        stream.id = i as u32 + 1;
+        stream.name = if several {
+            format!("session-{} {} {} Hz", session, kind, stream.rate)
+        } else {
+            format!("session-{} {}", session, kind)
+        };
+    }
+    streams
+}
+
+/// Writes `export` as XDF into `tx`.
+pub async fn write(pool: PgPool, export: SessionExport, tx: Chunks) -> Result<(), String> {
+    let id = export.session.id;
+    let factors = export.factors();
+    let start = Utc
+        .timestamp_opt(export.first_ts.timestamp(), 0)
+        .single()
+        .ok_or("invalid start time")?;
+    let source = export
+        .session
+        .device
+        .clone()
+        .unwrap_or_else(|| format!("session-{}", id));
+    let mut streams = streams(&export);
+    let markers = streams.len() - 1;
+    // Channel name -> (stream, position in the stream).
+    let channels = &export.channels;
+    let placement: HashMap<&str, (usize, usize)> = streams
+        .iter()
+        .enumerate()
+        .flat_map(|(s, stream)| {
+            stream
+                .channels
+                .iter()
+                .enumerate()
+                .map(move |(p, &c)| (channels[c].name.as_str(), (s, p)))
+        })
+        .collect();
+
+    let mut out = b"XDF:".to_vec();
+    chunk(
+        &mut out,
+        TAG_FILE_HEADER,
+        format!(
+            "<?xml version=\"1.0\"?><info><version>1.0</version><datetime>{}</datetime></info>",
+            export.first_ts.to_rfc3339_opts(SecondsFormat::Micros, true)
+        )
+        .as_bytes(),
+    );
+    for stream in &streams {
+        let header = stream_header(stream, &export.channels, &source);
+        stream_chunk(&mut out, TAG_STREAM_HEADER, stream.id, header.as_bytes());
+    }
+
+    if !export.events.is_empty() {
+        let stream = &mut streams[markers];
+        let mut content = Vec::new();
+        varlen(&mut content, export.events.len() as u64);
+        for event in &export.events {
+            let ts = seconds(event.ts);
+            content.push(8);
+            content.extend_from_slice(&ts.to_le_bytes());
+            varlen(&mut content, event.label.len() as u64);
+            content.extend_from_slice(event.label.as_bytes());
+            stream.saw(ts);
+        }
+        stream_chunk(&mut out, TAG_SAMPLES, stream.id, &content);
+        let (first, last) = (stream.first.unwrap_or(0.0), stream.last);
+        clock_offset(&mut out, stream, first);
+        clock_offset(&mut out, stream, last);
+    }
+    if tx.send(Ok(Bytes::from(out))).await.is_err() {
+        return Ok(());
+    }
+
+    let written = seconds_with_samples(&pool, id, start)
+        .await
+        .map_err(|e| e.to_string())?;
+    let window_start = |s: i64| start + chrono::Duration::seconds(s);
+    for window in written.chunks(SECONDS_PER_CHUNK) {
+        let samples = fetch_window(
+            &pool,
+            id,
+            &factors,
+            window_start(window[0]),
+            window_start(window[window.len() - 1] + 1),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+        // Instants of each stream with one value per channel.
+        let mut rows: Vec<Vec<(DateTime<Utc>, Vec<f64>)>> = vec![Vec::new(); markers];
+        for sample in &samples {
+            let Some(&(s, p)) = placement.get(sample.channel.as_str()) else {
+                continue;
+            };
+            let rows = &mut rows[s];
+            if rows.last().map(|(ts, _)| *ts) != Some(sample.ts) {
+                rows.push((sample.ts, vec![f64::NAN; streams[s].channels.len()]));
+            }
+            rows.last_mut().unwrap().1[p] = sample.value;
+        }
+
+        let mut out = Vec::new();
+        chunk(&mut out, TAG_BOUNDARY, &BOUNDARY);
+        let collected = seconds(window_start(window[window.len() - 1] + 1));
+        for (stream, rows) in streams.iter_mut().zip(rows) {
+            if rows.is_empty() {
+                continue;
+            }
+            let mut content = Vec::with_capacity(rows.len() * (9 + 8 * stream.channels.len()));
+            varlen(&mut content, rows.len() as u64);
+            for (ts, values) in rows {
+                let ts = seconds(ts);
+                content.push(8);
+                content.extend_from_slice(&ts.to_le_bytes());
+                for value in values {
+                    content.extend_from_slice(&value.to_le_bytes());
+                }
+                stream.saw(ts);
+            }
+            stream_chunk(&mut out, TAG_SAMPLES, stream.id, &content);
+            clock_offset(&mut out, stream, collected);
+        }
+        if tx.send(Ok(Bytes::from(out))).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    let mut out = Vec::new();
+    for stream in &streams {
+        let footer = stream_footer(stream);
+        stream_chunk(&mut out, TAG_STREAM_FOOTER, stream.id, footer.as_bytes());
+    }
+    let _ = tx.send(Ok(Bytes::from(out))).await;
+    Ok(())
+}
@@ -0,0 +1,369 @@
+//! `GET /live/feedback`: a WebSocket streaming a [`Metric`] of the latest
+//! samples of each channel, for neurofeedback displays.
+//!
+//! `rate` times a second, the stream measures the metric over the `window`
+//! ending at each channel's newest sample and sends the channels whose
+//! newest sample moved since the previous message. With `smoothing`, values
+//! go through an exponential moving average with that time constant, in
+//! seconds of sample time. Clients retune a running stream with `configure`
+//! messages.
+
+use crate::aggregate::parse_width;
+use crate::dsp::channel_rate;
+use crate::dsp::metric::Metric;
+use crate::error::ApiError;
+use crate::validation::{Parsed, Valid};
+use crate::{
+    fetch_window_samples, metrics, quality, shutdown, AppState, ChannelQuery, SampleFilter,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior};
+use utoipa::IntoParams;
+
+/// Most channels per stream; each is read `rate` times a second.
+const MAX_CHANNELS: usize = 16;
+const DEFAULT_RATE: f64 = 5.0;
+const MAX_RATE: f64 = 20.0;
+const DEFAULT_WINDOW_SECONDS: f64 = 1.0;
+const MAX_WINDOW_SECONDS: f64 = 10.0;
+const MAX_SMOOTHING_SECONDS: f64 = 60.0;
+const DEFAULT_BAND: &str = "alpha";
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FeedbackQuery {
+    /// `amplitude`, `peak_to_peak`, `rms`, `bandpower` or `band_ratio`.
+    metric: Option<String>,
+    /// Band of `bandpower` and `band_ratio`.
+    band: Option<String>,
+    /// Denominator band of `band_ratio`.
+    over: Option<String>,
+    #[serde(default)]
+    relative: bool,
+    /// Window ending at the newest sample, e.g. `1s` or `500ms`.
+    window: Option<String>,
+    /// Messages per second.
+    rate: Option<f64>,
+    /// Time constant of the moving average, in seconds.
+    smoothing: Option<f64>,
+}
+
+/// Messages a client may send.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ClientMessage {
+    /// Replaces the given settings; smoothing starts over from the newest
+    /// samples.
+    Configure {
+        metric: Option<Metric>,
+        window: Option<String>,
+        rate: Option<f64>,
+        smoothing: Option<f64>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct ChannelValue {
+    channel: String,
+    /// Newest sample of the window.
+    ts: DateTime<Utc>,
+    /// Smoothed metric; null when it cannot be computed.
+    value: Option<f64>,
+    /// Metric of this window alone.
+    raw: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Configured {
+        channels: Vec<String>,
+        metric: Metric,
+        window_seconds: f64,
+        rate: f64,
+        smoothing: f64,
+    },
+    Feedback {
+        values: Vec<ChannelValue>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Settings of a stream.
+struct Settings {
+    metric: Metric,
+    window_seconds: f64,
+    rate: f64,
+    smoothing: f64,
+}
+
+impl Settings {
+    fn check(&self) -> Result<(), String> {
+        self.metric.check()?;
+        if self.window_seconds > MAX_WINDOW_SECONDS {
+            return Err(format!("window must be at most {}s", MAX_WINDOW_SECONDS));
+        }
+        if !(self.rate >= 1.0 && self.rate <= MAX_RATE) {
+            return Err(format!("rate must be 1 to {} Hz", MAX_RATE));
+        }
+        if !(0.0..=MAX_SMOOTHING_SECONDS).contains(&self.smoothing) {
+            return Err(format!(
+                "smoothing must be 0 to {} seconds",
+                MAX_SMOOTHING_SECONDS
+            ));
+        }
+        Ok(())
+    }
+
+    fn ticker(&self) -> Interval {
+        let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / self.rate));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        ticker
+    }
+}
+
+impl FeedbackQuery {
+    fn settings(&self) -> Result<Settings, String> {
+        let kind = self.metric.as_deref().unwrap_or("bandpower");
+        let spectral = matches!(kind, "bandpower" | "band_ratio");
+        if (self.band.is_some() && !spectral)
+            || (self.over.is_some() && kind != "band_ratio")
+            || (self.relative && kind != "bandpower")
+        {
+            return Err(format!(
+                "metric {:?} does not take band, over or relative",
+                kind
+            ));
+        }
+        let mut metric = json!({ "type": kind });
+        if spectral {
+            metric["band"] = json!(self.band.as_deref().unwrap_or(DEFAULT_BAND));
+        }
+        if let Some(over) = &self.over {
+            metric["over"] = json!(over);
+        }
+        if self.relative {
+            metric["relative"] = json!(true);
+        }
+        let metric: Metric =
+            serde_json::from_value(metric).map_err(|e| format!("invalid metric: {}", e))?;
+        let settings = Settings {
+            metric,
+            window_seconds: match self.window.as_deref() {
+                Some(text) => parse_width(text)?,
+                None => DEFAULT_WINDOW_SECONDS,
+            },
+            rate: self.rate.unwrap_or(DEFAULT_RATE),
+            smoothing: self.smoothing.unwrap_or(0.0),
+        };
+        settings.check()?;
+        Ok(settings)
+    }
+}
+
+/// Smoothing state of one channel.
+#[derive(Default)]
+struct Track {
+    latest: Option<DateTime<Utc>>,
+    smoothed: Option<f64>,
+}
+
+struct Feedback {
+    channels: Vec<String>,
+    filter: SampleFilter,
+    settings: Settings,
+    tracks: Vec<Track>,
+}
+
+impl Feedback {
+    fn configured(&self) -> ServerMessage {
+        ServerMessage::Configured {
+            channels: self.channels.clone(),
+            metric: self.settings.metric.clone(),
+            window_seconds: self.settings.window_seconds,
+            rate: self.settings.rate,
+            smoothing: self.settings.smoothing,
+        }
+    }
+
+    fn configure(
+        &mut self,
+        metric: Option<Metric>,
+        window: Option<String>,
+        rate: Option<f64>,
+        smoothing: Option<f64>,
+    ) -> Result<(), String> {
+        let settings = Settings {
+            metric: metric.unwrap_or_else(|| self.settings.metric.clone()),
+            window_seconds: match window.as_deref() {
+                Some(text) => parse_width(text)?,
+                None => self.settings.window_seconds,
+            },
+            rate: rate.unwrap_or(self.settings.rate),
+            smoothing: smoothing.unwrap_or(self.settings.smoothing),
+        };
+        settings.check()?;
+        self.settings = settings;
+        self.tracks.iter_mut().for_each(|t| *t = Track::default());
+        Ok(())
+    }
+
+    /// Values of the channels with new samples, if any.
+    async fn measure(&mut self, state: &AppState) -> Result<Option<ServerMessage>, String> {
+        let window = chrono::Duration::microseconds((self.settings.window_seconds * 1e6) as i64);
+        let mut values = Vec::new();
+        for (channel, track) in self.channels.iter().zip(&mut self.tracks) {
+            let latest = quality::latest_ts(&state.pool, channel, &self.filter)
+                .await
+                .map_err(|e| e.to_string())?;
+            let Some(latest) = latest.filter(|ts| track.latest.is_none_or(|last| *ts > last))
+            else {
+                continue;
+            };
+            let window_filter = SampleFilter {
+                from: Some(latest - window),
+                to: Some(latest + chrono::Duration::microseconds(1)),
+                ..self.filter
+            };
+            let samples = fetch_window_samples(&state.pool, channel, &window_filter)
+                .await
+                .map_err(|e| e.message)?;
+            let rate = if self.settings.metric.spectral() {
+                channel_rate(&state.pool, channel, &samples)
+                    .await
+                    .map_err(|e| e.to_string())?
+            } else {
+                None
+            };
+            let raw = self.settings.metric.measure(&samples, rate);
+            let elapsed = track
+                .latest
+                .map(|last| (latest - last).num_microseconds().unwrap_or(0) as f64 / 1e6);
+            track.smoothed = match (raw, track.smoothed, elapsed) {
+                (Some(raw), Some(previous), Some(elapsed)) if self.settings.smoothing > 0.0 => {
+                    let alpha = 1.0 - (-elapsed / self.settings.smoothing).exp();
+                    Some(previous + alpha * (raw - previous))
+                }
+                (raw, _, _) => raw,
+            };
+            track.latest = Some(latest);
+            values.push(ChannelValue {
+                channel: channel.clone(),
+                ts: latest,
+                value: track.smoothed,
+                raw,
+            });
+        }
+        Ok((!values.is_empty()).then_some(ServerMessage::Feedback { values }))
+    }
+}
+
+/// Streams the metric of `channel` (repeatable, default `A3`) within
+/// `session_id` / `subject_id`, if given.
+#[utoipa::path(
+    get,
+    path = "/live/feedback",
+    tag = "live",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Repeatable; default `A3`"),
+        FeedbackQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 101, description = "WebSocket of `feedback` messages; clients may send `configure`"),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn feedback_ws(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<FeedbackQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let bad_request = |e: String| ApiError::bad_request(e).into_response();
+    let channels = match raw.channels() {
+        Ok(channels) if channels.len() > MAX_CHANNELS => {
+            return bad_request(format!("at most {} channels per stream", MAX_CHANNELS))
+        }
+        Ok(channels) => channels,
+        Err(e) => return bad_request(e),
+    };
+    if filter.from.is_some() || filter.to.is_some() {
+        return bad_request(
+            "from / to are not accepted; the stream follows the newest samples".to_string(),
+        );
+    }
+    let settings = match params.settings() {
+        Ok(settings) => settings,
+        Err(e) => return bad_request(e),
+    };
+    let feedback = Feedback {
+        tracks: channels.iter().map(|_| Track::default()).collect(),
+        channels,
+        filter,
+        settings,
+    };
+    ws.on_upgrade(move |socket| feedback_session(socket, state, feedback))
+}
+
+async fn feedback_session(mut socket: WebSocket, state: AppState, mut feedback: Feedback) {
+    let _client = metrics::LiveClient::connect("/live/feedback");
+    let mut ticker = feedback.settings.ticker();
+    let mut reply = Some(feedback.configured());
+    let mut stopping = std::pin::pin!(shutdown::requested());
+    loop {
+        if let Some(message) = reply.take() {
+            let text = match serde_json::to_string(&message) {
+                Ok(text) => text,
+                Err(e) => {
+                    tracing::error!("failed to encode websocket message: {}", e);
+                    continue;
+                }
+            };
+            if socket.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+
+        reply = tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(ClientMessage::Configure { metric, window, rate, smoothing }) => {
+                        match feedback.configure(metric, window, rate, smoothing) {
+                            Ok(()) => {
+                                ticker = feedback.settings.ticker();
+                                Some(feedback.configured())
+                            }
+                            Err(message) => Some(ServerMessage::Error { message }),
+                        }
+                    }
+                    Err(e) => Some(ServerMessage::Error { message: e.to_string() }),
+                },
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => None,
+            },
+            _ = ticker.tick() => match feedback.measure(&state).await {
+                Ok(values) => values,
+                Err(message) => Some(ServerMessage::Error { message }),
+            },
+            _ = &mut stopping => {
+                let _ = socket.send(shutdown::going_away()).await;
+                break;
+            }
+        };
+    }
+}
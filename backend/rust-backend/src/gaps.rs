@@ -0,0 +1,344 @@
+//! Gaps in the sample stream of a session, where a headset dropped out or
+//! samples were lost on the way.
+//!
+//! A gap is a step between consecutive samples of a channel longer than
+//! `min_intervals` sample intervals, the interval being that of the
+//! channel's [sample rate](crate::metadata), else the median step of the
+//! channel in the session. `GET /sessions/{id}/gaps` lists them; the `gaps`
+//! [job](crate::jobs) looks for new ones in the samples written since its
+//! previous run and logs each as a warning. Steps are taken over rows and
+//! samples packed into [blocks](crate::store::blocks) alike.
+
+use crate::error::ApiError;
+use crate::jobs::Job;
+use crate::store::blocks;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{metadata, AppState, EegSample, SampleFilter};
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use utoipa::{IntoParams, ToSchema};
+
+/// Steps longer than this many sample intervals are gaps, unless the
+/// request says otherwise; the background check always uses it.
+pub const DEFAULT_MIN_INTERVALS: f64 = 2.0;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GapQuery {
+    /// Only this channel; every channel of the session by default.
+    channel: Option<String>,
+    /// Shortest gap, in sample intervals (default 2, at least 1).
+    min_intervals: Option<f64>,
+}
+
+impl Validate for GapQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(n) = self.min_intervals {
+            errors.check(
+                n.is_finite() && n >= 1.0,
+                "min_intervals",
+                "must be at least 1",
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Gap {
+    /// The last sample before the gap.
+    pub start: DateTime<Utc>,
+    /// The first sample after it.
+    pub end: DateTime<Utc>,
+    /// Seconds from `start` to `end`.
+    pub duration: f64,
+    /// Samples the channel's rate puts in between.
+    pub missing: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChannelGaps {
+    pub channel: String,
+    /// Hz, as registered or estimated; null with fewer than two samples.
+    pub sample_rate: Option<f64>,
+    pub gaps: Vec<Gap>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SessionGaps {
+    pub session_id: i32,
+    pub min_intervals: f64,
+    pub channels: Vec<ChannelGaps>,
+}
+
+/// Channels with samples in session `id`, in hardware order.
+async fn session_channels(pool: &PgPool, id: i32) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT s.channel FROM (SELECT channel FROM eeg_samples WHERE session_id = $1 \
+         UNION SELECT channel FROM sample_blocks WHERE session_id = $1) s \
+         LEFT JOIN channels c ON c.name = s.channel \
+         ORDER BY c.hardware_index NULLS LAST, s.channel",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Times of the samples of `channel` in session `id` from `from` on, rows
+/// and blocks merged, oldest first.
+async fn times(
+    pool: &PgPool,
+    id: i32,
+    channel: &str,
+    from: Option<DateTime<Utc>>,
+) -> Result<Vec<DateTime<Utc>>, sqlx::Error> {
+    let filter = SampleFilter {
+        from,
+        session_id: Some(id),
+        ..SampleFilter::default()
+    };
+    let mut query: QueryBuilder<Postgres> =
+        QueryBuilder::new("SELECT id, ts, channel, value FROM eeg_samples WHERE channel = ");
+    query.push_bind(channel);
+    filter.push_to(&mut query);
+    query.push(" ORDER BY ts, id");
+    let mut packed = blocks::select(Some(&[channel.to_string()]), &filter);
+    let mut samples = blocks::Merged::new(
+        query.build_query_as::<EegSample>().fetch(pool),
+        packed.build_query_as().fetch(pool),
+        filter,
+    );
+    let mut times = Vec::new();
+    while let Some(sample) = samples.next().await? {
+        times.push(sample.ts);
+    }
+    Ok(times)
+}
+
+/// Seconds from each of `times` to the next.
+fn steps(times: &[DateTime<Utc>]) -> impl Iterator<Item = f64> + '_ {
+    times
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6)
+}
+
+/// Median step in seconds between the samples of `channel` in session `id`,
+/// steps of zero left out.
+async fn median_step(pool: &PgPool, id: i32, channel: &str) -> Result<Option<f64>, sqlx::Error> {
+    let times = times(pool, id, channel, None).await?;
+    let mut steps: Vec<f64> = steps(&times).filter(|step| *step > 0.0).collect();
+    Ok(median(&mut steps))
+}
+
+/// The median of `values`, halfway between the middle two of an even number.
+fn median(values: &mut [f64]) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    match n {
+        0 => None,
+        _ if n % 2 == 1 => Some(values[n / 2]),
+        _ => Some((values[n / 2 - 1] + values[n / 2]) / 2.0),
+    }
+}
+
+/// Sample rate of `channel` in session `id`: the [metadata](crate::metadata)
+/// one, else estimated from its median step.
+async fn rate(
+    pool: &PgPool,
+    id: i32,
+    channel: &str,
+    registered: Option<f64>,
+) -> Result<Option<f64>, sqlx::Error> {
+    if registered.is_some() {
+        return Ok(registered);
+    }
+    Ok(median_step(pool, id, channel).await?.map(|step| 1.0 / step))
+}
+
+/// Gaps of `channel` in session `id` longer than `min_intervals` at `rate`
+/// Hz, oldest first; with `after`, only those ending at or after it.
+async fn detect(
+    pool: &PgPool,
+    id: i32,
+    channel: &str,
+    rate: f64,
+    min_intervals: f64,
+    after: Option<DateTime<Utc>>,
+) -> Result<Vec<Gap>, sqlx::Error> {
+    // From the last sample before `after`, so that a gap spanning it is
+    // found once; a block starting before `after` bounds it from below.
+    let from = match after {
+        Some(after) => Some(
+            sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+                "SELECT GREATEST( \
+                   (SELECT max(ts) FROM eeg_samples WHERE session_id = $1 AND channel = $2 AND ts < $3), \
+                   (SELECT max(start_ts) FROM sample_blocks \
+                    WHERE session_id = $1 AND channel = $2 AND start_ts < $3))",
+            )
+            .bind(id)
+            .bind(channel)
+            .bind(after)
+            .fetch_one(pool)
+            .await?
+            .unwrap_or(after),
+        ),
+        None => None,
+    };
+    let times = times(pool, id, channel, from).await?;
+    let longest = min_intervals / rate;
+    Ok(times
+        .windows(2)
+        .zip(steps(&times))
+        .filter(|(pair, step)| *step > longest && after.is_none_or(|after| pair[1] >= after))
+        .map(|(pair, duration)| Gap {
+            start: pair[0],
+            end: pair[1],
+            duration,
+            missing: ((duration * rate).round() as i64 - 1).max(0),
+        })
+        .collect())
+}
+
+/// Gaps in the samples of session `id`.
+#[utoipa::path(
+    get,
+    path = "/sessions/{id}/gaps",
+    tag = "sessions",
+    params(("id" = i32, Path), GapQuery),
+    responses(
+        (status = 200, body = SessionGaps),
+        (status = 404, description = "Unknown session"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn list_gaps(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Valid(Query(params)): Valid<Query<GapQuery>>,
+) -> Result<Json<SessionGaps>, ApiError> {
+    let pool = state.reader();
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM sessions WHERE id = $1)")
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    if !exists {
+        return Err(ApiError::not_found(format!("unknown session {}", id)));
+    }
+    let min_intervals = params.min_intervals.unwrap_or(DEFAULT_MIN_INTERVALS);
+    let names = match params.channel {
+        Some(channel) => vec![channel],
+        None => session_channels(pool, id).await?,
+    };
+    let metadata = metadata::of(pool, &names, Some(id)).await?;
+    let mut channels = Vec::with_capacity(names.len());
+    for (channel, metadata) in names.into_iter().zip(metadata) {
+        let sample_rate = rate(pool, id, &channel, metadata.sample_rate).await?;
+        let gaps = match sample_rate {
+            Some(rate) => detect(pool, id, &channel, rate, min_intervals, None).await?,
+            None => Vec::new(),
+        };
+        channels.push(ChannelGaps {
+            channel,
+            sample_rate,
+            gaps,
+        });
+    }
+    Ok(Json(SessionGaps {
+        session_id: id,
+        min_intervals,
+        channels,
+    }))
+}
+
+/// The background check: gaps ending in the samples written since the
+/// previous run, by session and channel.
+pub struct Scan {
+    pool: PgPool,
+    since: DateTime<Utc>,
+}
+
+impl Scan {
+    /// A scan whose first run looks back `every`.
+    pub fn new(pool: PgPool, every: std::time::Duration) -> Self {
+        Self {
+            pool,
+            since: Utc::now() - Duration::from_std(every).unwrap_or(Duration::zero()),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl Job for Scan {
+    async fn run(&mut self) -> Result<String, String> {
+        let started = Utc::now();
+        let written: Vec<(i32, String)> = sqlx::query_as(
+            "SELECT DISTINCT session_id, channel FROM eeg_samples \
+             WHERE ts >= $1 AND session_id IS NOT NULL ORDER BY session_id, channel",
+        )
+        .bind(self.since)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let mut found = 0;
+        let mut sessions = std::collections::BTreeSet::new();
+        for (session_id, channel) in written {
+            let registered =
+                metadata::of(&self.pool, std::slice::from_ref(&channel), Some(session_id))
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .remove(0)
+                    .sample_rate;
+            let Some(rate) = rate(&self.pool, session_id, &channel, registered)
+                .await
+                .map_err(|e| e.to_string())?
+            else {
+                continue;
+            };
+            let gaps = detect(
+                &self.pool,
+                session_id,
+                &channel,
+                rate,
+                DEFAULT_MIN_INTERVALS,
+                Some(self.since),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            for gap in &gaps {
+                tracing::warn!(
+                    "session {} channel {}: {:.3} s gap from {} to {}",
+                    session_id,
+                    channel,
+                    gap.duration,
+                    gap.start,
+                    gap.end
+                );
+            }
+            if !gaps.is_empty() {
+                found += gaps.len();
+                sessions.insert(session_id);
+            }
+        }
+        self.since = started;
+        if found == 0 {
+            return Ok("no gaps".to_string());
+        }
+        Ok(format!("{} gaps in sessions {:?}", found, sessions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_interpolates_between_the_middle_steps() {
+        assert_eq!(median(&mut []), None);
+        assert_eq!(median(&mut [0.004, 0.002, 0.008]), Some(0.004));
+        assert_eq!(median(&mut [0.004, 0.002, 0.008, 0.006]), Some(0.005));
+    }
+}
@@ -0,0 +1,303 @@
+//! GraphQL endpoint for dashboard widgets that need different data shapes.
+//!
+//! Queries are served at `POST /v1/graphql` (GraphiQL on `GET /v1/graphql`)
+//! and subscriptions over WebSocket at `/v1/graphql/ws`. Subject-identifying fields
+//! are null for callers who may not see them, as on `/subjects`.
+
+use crate::auth::Principal;
+use crate::store::{blocks, postgres::fetch_sample_page};
+use crate::{fetch_live_points, notify, roles, shutdown, AppState, SampleFilter};
+use async_graphql::{
+    http::GraphiQLSource, Context, EmptyMutation, Object, Result, Schema, SimpleObject,
+    Subscription,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use axum::{
+    extract::{OriginalUri, State},
+    response::{Html, IntoResponse},
+    routing::get,
+    Extension, Router,
+};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
+use sqlx::PgPool;
+
+pub type EegSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+#[derive(SimpleObject)]
+pub struct Sample {
+    id: i32,
+    ts: DateTime<Utc>,
+    channel: String,
+    value: f64,
+}
+
+#[derive(SimpleObject)]
+pub struct Channel {
+    name: String,
+    sample_count: i64,
+    last_id: i32,
+}
+
+#[derive(SimpleObject, sqlx::FromRow)]
+pub struct Session {
+    id: i32,
+    subject_id: Option<i32>,
+    device: Option<String>,
+    started_at: DateTime<Utc>,
+    ended_at: Option<DateTime<Utc>>,
+    notes: Option<String>,
+}
+
+#[derive(SimpleObject, sqlx::FromRow)]
+pub struct Subject {
+    id: i32,
+    code: Option<String>,
+    birth_year: Option<i32>,
+    sex: Option<String>,
+    handedness: Option<String>,
+    notes: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl Subject {
+    /// The subject, without its identifying fields unless `identify`.
+    fn shown(mut self, identify: bool) -> Self {
+        if !identify {
+            self.code = None;
+            self.birth_year = None;
+            self.notes = None;
+        }
+        self
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct LiveBatch {
+    channel: String,
+    points: Vec<Sample>,
+    last_id: i32,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Samples of one channel, newest first. `sinceId` and `beforeId` bound the id range;
+    /// `sessionId` and `subjectId` scope it to one recording or one subject.
+    #[allow(clippy::too_many_arguments)]
+    async fn samples(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = "A3")] channel: String,
+        #[graphql(default = 100)] limit: i32,
+        since_id: Option<i32>,
+        before_id: Option<i32>,
+        session_id: Option<i32>,
+        subject_id: Option<i32>,
+    ) -> Result<Vec<Sample>> {
+        let pool = ctx.data::<PgPool>()?;
+        let filter = SampleFilter {
+            session_id,
+            subject_id,
+            ..SampleFilter::default()
+        };
+        // With a `before_id` the page runs newest first.
+        let (samples, _) = fetch_sample_page(
+            pool,
+            &channel,
+            Some(before_id.unwrap_or(i32::MAX)),
+            since_id,
+            &filter,
+            limit.clamp(0, 1000),
+        )
+        .await?;
+
+        Ok(samples
+            .into_iter()
+            .map(|s| Sample {
+                id: s.id,
+                ts: s.ts,
+                channel: s.channel,
+                value: s.value,
+            })
+            .collect())
+    }
+
+    /// Recording sessions, newest first. `active` selects sessions without an end time.
+    async fn sessions(
+        &self,
+        ctx: &Context<'_>,
+        subject_id: Option<i32>,
+        active: Option<bool>,
+        #[graphql(default = 100)] limit: i32,
+    ) -> Result<Vec<Session>> {
+        let pool = ctx.data::<PgPool>()?;
+        Ok(sqlx::query_as(
+            "SELECT id, subject_id, device, started_at, ended_at, notes FROM sessions \
+             WHERE ($1::int4 IS NULL OR subject_id = $1) AND ($2::bool IS NULL OR (ended_at IS NULL) = $2) \
+             ORDER BY started_at DESC, id DESC LIMIT $3",
+        )
+        .bind(subject_id)
+        .bind(active)
+        .bind(limit.clamp(0, 1000))
+        .fetch_all(pool)
+        .await?)
+    }
+
+    async fn session(&self, ctx: &Context<'_>, id: i32) -> Result<Option<Session>> {
+        let pool = ctx.data::<PgPool>()?;
+        Ok(sqlx::query_as(
+            "SELECT id, subject_id, device, started_at, ended_at, notes FROM sessions WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?)
+    }
+
+    /// Subjects ordered by code; `code` matches a prefix.
+    async fn subjects(
+        &self,
+        ctx: &Context<'_>,
+        code: Option<String>,
+        #[graphql(default = 100)] limit: i32,
+    ) -> Result<Vec<Subject>> {
+        let pool = ctx.data::<PgPool>()?;
+        let identify = roles::may_identify(ctx.data_opt::<Principal>());
+        if code.is_some() && !identify {
+            return Err("only clinicians may search subjects by code".into());
+        }
+        let subjects: Vec<Subject> = sqlx::query_as(
+            "SELECT id, code, birth_year, sex, handedness, notes, created_at FROM subjects \
+             WHERE ($1::text IS NULL OR starts_with(code, $1)) ORDER BY code LIMIT $2",
+        )
+        .bind(code)
+        .bind(limit.clamp(0, 1000))
+        .fetch_all(pool)
+        .await?;
+        Ok(subjects
+            .into_iter()
+            .map(|subject| subject.shown(identify))
+            .collect())
+    }
+
+    async fn subject(&self, ctx: &Context<'_>, id: i32) -> Result<Option<Subject>> {
+        let pool = ctx.data::<PgPool>()?;
+        let identify = roles::may_identify(ctx.data_opt::<Principal>());
+        let subject: Option<Subject> = sqlx::query_as(
+            "SELECT id, code, birth_year, sex, handedness, notes, created_at FROM subjects WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(subject.map(|subject| subject.shown(identify)))
+    }
+
+    /// Channels that have stored samples.
+    async fn channels(&self, ctx: &Context<'_>) -> Result<Vec<Channel>> {
+        let pool = ctx.data::<PgPool>()?;
+        let rows = blocks::channel_counts(pool).await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(name, sample_count, last_id)| Channel {
+                name,
+                sample_count,
+                last_id,
+            })
+            .collect())
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// New points for a channel, pushed as they are written.
+    async fn live_points(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = "A3")] channel: String,
+        #[graphql(default = 0)] since_id: i32,
+        #[graphql(default = 200)] limit: i32,
+        session_id: Option<i32>,
+    ) -> Result<impl Stream<Item = Result<LiveBatch>>> {
+        let pool = ctx.data::<PgPool>()?.clone();
+        let limit = limit.clamp(1, 1000);
+        let filter = SampleFilter {
+            session_id,
+            ..SampleFilter::default()
+        };
+        let wakeup = notify::Wakeup::default();
+
+        Ok(stream::unfold(
+            (pool, channel, since_id, wakeup),
+            move |(pool, channel, mut since_id, mut wakeup)| async move {
+                loop {
+                    wakeup.wait(&channel).await;
+                    if shutdown::is_requested() {
+                        return None;
+                    }
+                    let batch =
+                        match fetch_live_points(&pool, &channel, since_id, &filter, limit).await {
+                            Ok(points) if points.is_empty() => continue,
+                            Ok(points) => {
+                                since_id = points.last().map(|p| p.id).unwrap_or(since_id);
+                                wakeup.again();
+                                Ok(LiveBatch {
+                                    channel: channel.clone(),
+                                    points: points
+                                        .into_iter()
+                                        .map(|p| Sample {
+                                            id: p.id,
+                                            ts: p.ts,
+                                            channel: channel.clone(),
+                                            value: p.value,
+                                        })
+                                        .collect(),
+                                    last_id: since_id,
+                                })
+                            }
+                            Err(e) => Err(e.into()),
+                        };
+                    return Some((batch, (pool, channel, since_id, wakeup)));
+                }
+            },
+        ))
+    }
+}
+
+pub fn schema(pool: PgPool) -> EegSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(pool)
+        .finish()
+}
+
+async fn graphql_handler(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    let mut request = req.into_inner();
+    if let Some(Extension(principal)) = principal {
+        request = request.data(principal);
+    }
+    state.schema.execute(request).await.into()
+}
+
+/// GraphiQL, querying the path it is served at (`/graphql` or `/v1/graphql`).
+async fn graphiql(OriginalUri(uri): OriginalUri) -> impl IntoResponse {
+    let endpoint = uri.path();
+    Html(
+        GraphiQLSource::build()
+            .endpoint(endpoint)
+            .subscription_endpoint(&format!("{}/ws", endpoint))
+            .finish(),
+    )
+}
+
+pub fn routes(schema: EegSchema) -> Router<AppState> {
+    Router::new()
+        .route("/graphql", get(graphiql).post(graphql_handler))
+        .route_service("/graphql/ws", GraphQLSubscription::new(schema))
+}
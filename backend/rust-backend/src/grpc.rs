@@ -0,0 +1,255 @@
+//! gRPC service (`proto/eeg.proto`) served next to the REST API.
+
+use crate::api::samples::check_device;
+use crate::devices::keys::{self, ApiKey};
+use crate::ingest::{self, NewSample};
+use crate::roles::{self, Role};
+use crate::store::blocks;
+use crate::{audit, auth, fetch_live_points, notify, shutdown, SampleFilter};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::StreamExt;
+use serde_json::json;
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+pub mod proto {
+    tonic::include_proto!("eeg.v1");
+}
+
+use proto::eeg_service_server::{EegService, EegServiceServer};
+
+pub struct EegGrpc {
+    pool: PgPool,
+}
+
+/// Logs `e` and answers without its details, as the REST routes do.
+fn db_status(e: sqlx::Error) -> Status {
+    tracing::error!("internal error: {}", e);
+    Status::internal("internal error")
+}
+
+/// Checks that the caller holds one of `allowed`. Device API keys are only
+/// accepted by `IngestSamples`.
+// Status is what every tonic handler returns.
+#[allow(clippy::result_large_err)]
+fn authorize<T>(request: &Request<T>, allowed: &[Role]) -> Result<(), Status> {
+    if request.extensions().get::<ApiKey>().is_some() {
+        if allowed.contains(&Role::Device) {
+            return Ok(());
+        }
+        return Err(Status::permission_denied(
+            "API keys are only accepted by IngestSamples",
+        ));
+    }
+    match request.extensions().get::<auth::Principal>() {
+        Some(principal) => roles::check(principal, allowed).map_err(Status::permission_denied),
+        None => Ok(()),
+    }
+}
+
+#[tonic::async_trait]
+impl EegService for EegGrpc {
+    type StreamSamplesStream = ReceiverStream<Result<proto::SampleBatch, Status>>;
+
+    async fn stream_samples(
+        &self,
+        request: Request<proto::StreamSamplesRequest>,
+    ) -> Result<Response<Self::StreamSamplesStream>, Status> {
+        authorize(&request, roles::READERS)?;
+        let principal = request.extensions().get::<auth::Principal>().cloned();
+        let req = request.into_inner();
+        if let Some(principal) = &principal {
+            let params = json!({
+                "channel": req.channel,
+                "since_id": req.since_id,
+                "session_id": (req.session_id != 0).then_some(req.session_id),
+                "subject_id": (req.subject_id != 0).then_some(req.subject_id),
+            });
+            audit::record_call(principal, "StreamSamples", "read", params).await;
+        }
+        let channel = if req.channel.is_empty() {
+            "A3".to_string()
+        } else {
+            req.channel
+        };
+        let limit = if req.limit > 0 {
+            req.limit.min(1000)
+        } else {
+            200
+        };
+        let mut since_id = req.since_id;
+        let filter = SampleFilter {
+            session_id: (req.session_id != 0).then_some(req.session_id),
+            subject_id: (req.subject_id != 0).then_some(req.subject_id),
+            ..SampleFilter::default()
+        };
+        let pool = self.pool.clone();
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            let mut wakeup = notify::Wakeup::default();
+            while !tx.is_closed() {
+                wakeup.wait(&channel).await;
+                if shutdown::is_requested() {
+                    break;
+                }
+                let batch = match fetch_live_points(&pool, &channel, since_id, &filter, limit).await
+                {
+                    Ok(points) if points.is_empty() => continue,
+                    Ok(points) => {
+                        since_id = points.last().map(|p| p.id).unwrap_or(since_id);
+                        wakeup.again();
+                        Ok(proto::SampleBatch {
+                            channel: channel.clone(),
+                            samples: points
+                                .into_iter()
+                                .map(|p| proto::Sample {
+                                    id: p.id,
+                                    ts: p.ts.to_rfc3339_opts(SecondsFormat::Micros, true),
+                                    channel: channel.clone(),
+                                    value: p.value,
+                                })
+                                .collect(),
+                            last_id: since_id,
+                        })
+                    }
+                    Err(e) => Err(db_status(e)),
+                };
+                if tx.send(batch).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn ingest_samples(
+        &self,
+        request: Request<Streaming<proto::IngestSamplesRequest>>,
+    ) -> Result<Response<proto::IngestSamplesResponse>, Status> {
+        authorize(&request, roles::INGEST)?;
+        if let Some(principal) = request.extensions().get::<auth::Principal>() {
+            audit::record_call(principal, "IngestSamples", "write", json!({})).await;
+        }
+        let key = request.extensions().get::<ApiKey>().cloned();
+        let mut stream = request.into_inner();
+        let mut inserted = 0u64;
+        let mut last_id = 0;
+
+        while let Some(message) = stream.next().await {
+            let samples = message?
+                .samples
+                .into_iter()
+                .enumerate()
+                .map(|(i, s)| {
+                    let ts = DateTime::parse_from_rfc3339(&s.ts).map_err(|e| {
+                        format!("sample {}: ts must be an RFC 3339 timestamp: {}", i, e)
+                    })?;
+                    Ok(NewSample {
+                        channel: s.channel,
+                        ts: ts.with_timezone(&Utc),
+                        value: s.value,
+                        session_id: (s.session_id != 0).then_some(s.session_id),
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()
+                .map_err(Status::invalid_argument)?;
+            if samples.is_empty() {
+                continue;
+            }
+            ingest::validate_batch(&samples).map_err(Status::invalid_argument)?;
+            check_device(None, key.as_ref(), &samples, None).map_err(|e| {
+                if e.status == axum::http::StatusCode::FORBIDDEN {
+                    Status::permission_denied(e.message)
+                } else {
+                    Status::invalid_argument(e.message)
+                }
+            })?;
+            let ids = ingest::insert_batch(&self.pool, samples)
+                .await
+                .map_err(|e| {
+                    if ingest::is_unknown_session(&e) {
+                        Status::invalid_argument("unknown session_id")
+                    } else {
+                        db_status(e)
+                    }
+                })?;
+            inserted += ids.len() as u64;
+            last_id = ids.last().copied().unwrap_or(last_id);
+        }
+
+        Ok(Response::new(proto::IngestSamplesResponse {
+            inserted,
+            last_id,
+        }))
+    }
+
+    async fn list_channels(
+        &self,
+        request: Request<proto::ListChannelsRequest>,
+    ) -> Result<Response<proto::ListChannelsResponse>, Status> {
+        authorize(&request, roles::READERS)?;
+        if let Some(principal) = request.extensions().get::<auth::Principal>() {
+            audit::record_call(principal, "ListChannels", "read", json!({})).await;
+        }
+        let rows = blocks::channel_counts(&self.pool)
+            .await
+            .map_err(db_status)?;
+
+        Ok(Response::new(proto::ListChannelsResponse {
+            channels: rows
+                .into_iter()
+                .map(|(name, sample_count, last_id)| proto::Channel {
+                    name,
+                    sample_count,
+                    last_id,
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// Checks the `x-api-key` metadata of devices and attaches their [`ApiKey`],
+/// or else the `authorization: Bearer` metadata when JWT authentication is
+/// on, and attaches the [`auth::Principal`].
+// The signature is tonic's interceptor signature.
+#[allow(clippy::result_large_err)]
+fn authenticate(mut request: Request<()>) -> Result<Request<()>, Status> {
+    if let Some(value) = request.metadata().get(auth::API_KEY_HEADER) {
+        let key = value
+            .to_str()
+            .ok()
+            .and_then(keys::lookup)
+            .ok_or_else(|| Status::unauthenticated("invalid API key"))?;
+        request.extensions_mut().insert(key);
+        return Ok(request);
+    }
+    if !auth::enabled() {
+        return Ok(request);
+    }
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+    let principal = auth::verify_now(token.trim()).map_err(Status::unauthenticated)?;
+    request.extensions_mut().insert(principal);
+    Ok(request)
+}
+
+/// Serves the gRPC API on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr, pool: PgPool) -> Result<(), tonic::transport::Error> {
+    tracing::info!("gRPC listening on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(EegServiceServer::with_interceptor(
+            EegGrpc { pool },
+            authenticate,
+        ))
+        .serve_with_shutdown(addr, shutdown::requested())
+        .await
+}
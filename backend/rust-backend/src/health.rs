@@ -0,0 +1,107 @@
+//! Liveness and readiness probes.
+//!
+//! `/healthz` only shows that the process answers, so an orchestrator
+//! restarts it when it hangs. `/readyz` checks what serving needs: a
+//! database that answers, a schema without pending or failed migrations,
+//! and ingest writers that keep up. A read replica is reported, but reads
+//! fall back to the primary, so it does not count. It answers `503` while any check fails
+//! or once shutdown has started, so traffic moves elsewhere without a
+//! restart.
+
+use crate::{migrations, pipeline, shutdown, AppState};
+use axum::{extract::State, http::StatusCode, Json};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+/// Longest wait for the database before it counts as unreachable.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    security(()),
+    responses((status = 200, description = "The process is up; also served at `/health`", body = String))
+)]
+pub async fn healthz() -> &'static str {
+    "OK"
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    security(()),
+    responses(
+        (status = 200, description = "`{ ready, shutting_down, checks: { database, migrations, ingest, replica } }`; `replica` only when one is configured", body = serde_json::Value),
+        (status = 503, description = "Not ready; same body", body = serde_json::Value)
+    )
+)]
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    let started = Instant::now();
+    let database = match tokio::time::timeout(
+        DATABASE_TIMEOUT,
+        sqlx::query_scalar::<_, i32>("SELECT 1").fetch_one(&state.pool),
+    )
+    .await
+    {
+        Ok(Ok(_)) => json!({
+            "ok": true,
+            "latency_ms": started.elapsed().as_secs_f64() * 1000.0,
+        }),
+        Ok(Err(e)) => json!({ "ok": false, "error": e.to_string() }),
+        Err(_) => json!({
+            "ok": false,
+            "error": format!("no answer within {:?}", DATABASE_TIMEOUT),
+        }),
+    };
+
+    let migrations = if database["ok"] == true {
+        match tokio::time::timeout(DATABASE_TIMEOUT, migrations::status(&state.pool)).await {
+            Ok(Ok(status)) => json!({
+                "ok": status.pending.is_empty() && status.failed.is_empty(),
+                "applied": status.applied.len(),
+                "pending": status.pending,
+                "failed": status.failed,
+                "unknown": status.unknown,
+            }),
+            Ok(Err(e)) => json!({ "ok": false, "error": e.to_string() }),
+            Err(_) => json!({ "ok": false, "error": "timed out" }),
+        }
+    } else {
+        json!({ "ok": false, "error": "database unreachable" })
+    };
+
+    let queues = pipeline::queue_depths();
+    let ingest = json!({
+        "ok": !queues.values().any(|queue| queue.is_full()),
+        "queues": queues,
+    });
+
+    let stopping = shutdown::is_requested();
+    let ready =
+        !stopping && database["ok"] == true && migrations["ok"] == true && ingest["ok"] == true;
+    let mut checks = json!({
+        "database": database,
+        "migrations": migrations,
+        "ingest": ingest,
+    });
+    // Reads fall back to the primary, so a replica that is down is reported
+    // without making the backend unready.
+    if let Some(replica) = &state.replica {
+        checks["replica"] = json!({ "ok": replica.is_up() });
+    }
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "shutting_down": stopping,
+            "checks": checks,
+        })),
+    )
+}
@@ -0,0 +1,201 @@
+//! Electrode impedance checks (`impedances` table).
+//!
+//! Amplifiers report the impedance of each electrode, typically at the start
+//! of a recording; a check is stored as one row per channel, in kΩ, under
+//! the session it was taken for. [`/quality`](crate::quality) reports the
+//! latest check of each channel and warns above [`threshold_kohm`].
+
+use crate::channels::validate_name;
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{AppState, SampleFilter};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use utoipa::{IntoParams, ToSchema};
+
+/// Impedance above which `/quality` warns, unless `IMPEDANCE_MAX_KOHM` is set.
+const DEFAULT_MAX_KOHM: f64 = 10.0;
+
+/// Channels accepted in one check.
+const MAX_CHECK_CHANNELS: usize = 512;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Impedance {
+    pub id: i32,
+    pub session_id: i32,
+    pub channel: String,
+    pub measured_at: DateTime<Utc>,
+    pub kohm: f64,
+}
+
+/// Body of `POST /sessions/{id}/impedances`: `{"impedances": {"A3": 4.2}}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ImpedanceCheck {
+    /// Defaults to now.
+    measured_at: Option<DateTime<Utc>>,
+    /// kΩ per channel.
+    impedances: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImpedanceListQuery {
+    channel: Option<String>,
+    /// Only the most recent value of each channel.
+    #[serde(default)]
+    latest: bool,
+}
+
+const COLUMNS: &str = "id, session_id, channel, measured_at, kohm";
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::not_found(format!("unknown session {}", id))
+}
+
+/// Warning threshold in kΩ, from `IMPEDANCE_MAX_KOHM`.
+pub fn threshold_kohm() -> f64 {
+    std::env::var("IMPEDANCE_MAX_KOHM")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v: &f64| v.is_finite() && *v > 0.0)
+        .unwrap_or(DEFAULT_MAX_KOHM)
+}
+
+impl Validate for ImpedanceCheck {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(
+            !self.impedances.is_empty() && self.impedances.len() <= MAX_CHECK_CHANNELS,
+            "impedances",
+            format!("must list 1 to {} channels", MAX_CHECK_CHANNELS),
+        );
+        for (channel, kohm) in &self.impedances {
+            let field = format!("impedances.{}", channel);
+            if let Err(e) = validate_name(channel) {
+                errors.add(&field, e);
+            }
+            errors.check(
+                kohm.is_finite() && *kohm >= 0.0,
+                &field,
+                "must be a non-negative number of kΩ",
+            );
+        }
+    }
+}
+
+async fn session_exists(pool: &PgPool, id: i32) -> Result<bool, sqlx::Error> {
+    let (exists,): (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM sessions WHERE id = $1)")
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    Ok(exists)
+}
+
+/// The latest impedance of `channel` within the session or subject of
+/// `filter`, or of any session if neither is set.
+pub async fn latest(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+) -> Result<Option<Impedance>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM impedances WHERE channel = $1 \
+         AND ($2::int4 IS NULL OR session_id = $2) \
+         AND ($3::int4 IS NULL OR session_id IN (SELECT id FROM sessions WHERE subject_id = $3)) \
+         ORDER BY measured_at DESC, id DESC LIMIT 1",
+        COLUMNS
+    ))
+    .bind(channel)
+    .bind(filter.session_id)
+    .bind(filter.subject_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Checks of session `id`, newest first.
+#[utoipa::path(
+    get,
+    path = "/sessions/{id}/impedances",
+    tag = "sessions",
+    params(("id" = i32, Path), ImpedanceListQuery),
+    responses(
+        (status = 200, description = "Newest first", body = Vec<Impedance>),
+        (status = 404, description = "Unknown session")
+    )
+)]
+pub async fn list_impedances(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Parsed(Query(params)): Parsed<Query<ImpedanceListQuery>>,
+) -> Result<Json<Vec<Impedance>>, ApiError> {
+    if !session_exists(&state.pool, id).await? {
+        return Err(not_found(id));
+    }
+    let distinct = if params.latest {
+        "DISTINCT ON (channel)"
+    } else {
+        ""
+    };
+    let order = if params.latest {
+        "channel, measured_at DESC, id DESC"
+    } else {
+        "measured_at DESC, channel, id"
+    };
+    let impedances = sqlx::query_as(&format!(
+        "SELECT {} {} FROM impedances \
+         WHERE session_id = $1 AND ($2::text IS NULL OR channel = $2) ORDER BY {}",
+        distinct, COLUMNS, order
+    ))
+    .bind(id)
+    .bind(&params.channel)
+    .fetch_all(&state.pool)
+    .await?;
+    Ok(Json(impedances))
+}
+
+/// Stores one check of session `id`.
+#[utoipa::path(
+    post,
+    path = "/sessions/{id}/impedances",
+    tag = "sessions",
+    params(("id" = i32, Path)),
+    request_body = ImpedanceCheck,
+    responses(
+        (status = 201, description = "The stored values", body = Vec<Impedance>),
+        (status = 404, description = "Unknown session"),
+        (status = 422, description = "No, too many or invalid values, named in `details.fields`")
+    )
+)]
+pub async fn create_impedances(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Valid(Json(check)): Valid<Json<ImpedanceCheck>>,
+) -> Result<(StatusCode, Json<Vec<Impedance>>), ApiError> {
+    let channels: Vec<&str> = check.impedances.keys().map(String::as_str).collect();
+    let values: Vec<f64> = check.impedances.values().copied().collect();
+    let inserted = sqlx::query_as(&format!(
+        "INSERT INTO impedances (session_id, channel, measured_at, kohm) \
+         SELECT $1, channel, COALESCE($2, now()), kohm \
+         FROM UNNEST($3::text[], $4::float8[]) AS t (channel, kohm) \
+         RETURNING {}",
+        COLUMNS
+    ))
+    .bind(id)
+    .bind(check.measured_at)
+    .bind(&channels)
+    .bind(&values)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| match &e {
+        // 23503: foreign_key_violation on session_id.
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => not_found(id),
+        _ => e.into(),
+    })?;
+    Ok((StatusCode::CREATED, Json(inserted)))
+}
@@ -0,0 +1,607 @@
+//! CSV bulk import (`POST /import/csv`).
+//!
+//! The request carries a `mapping` field with a JSON [`Mapping`], followed
+//! by the `file` field. The first record of the file is the header; each
+//! further record gives one timestamp and a value for each mapped channel,
+//! where empty cells mean there is no sample. Records are parsed as they
+//! arrive and copied into `eeg_samples` through one binary COPY, so the
+//! samples of a file appear all at once, or not at all if it fails.
+//!
+//! Rows that fail to parse are skipped, and up to [`MAX_REPORTED_ERRORS`] of
+//! them are listed with their line number. The import fails once more than
+//! `max_errors` rows are rejected. Imports are tracked as jobs: `GET /imports`
+//! shows their progress while the upload is running.
+
+use super::{bad_request, multipart_error};
+use crate::error::ApiError;
+use crate::ingest::NewSample;
+use crate::pipeline::SampleCopy;
+use crate::validation::{FieldErrors, Parsed, Validate};
+use crate::{channels, notify, tiers, AppState};
+use axum::{
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use csv_core::{ReadRecordResult, Reader, ReaderBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+/// Samples buffered before they are sent to the COPY.
+const ROWS_PER_SEND: usize = 10_000;
+
+const DEFAULT_MAX_ERRORS: u64 = 1_000;
+
+/// Rejected rows listed in a job.
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// How long finished jobs are kept.
+const MAX_AGE: chrono::Duration = chrono::Duration::hours(24);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// RFC 3339, or `YYYY-MM-DD hh:mm:ss[.f]` in UTC.
+    #[default]
+    Rfc3339,
+    /// Seconds since the Unix epoch.
+    Unix,
+    UnixMs,
+    UnixUs,
+    /// Seconds since the mapping's `start`.
+    Relative,
+}
+
+/// Channel columns: a list of headers used as channel names, or channel
+/// names mapped to headers.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum ChannelColumns {
+    Same(Vec<String>),
+    Renamed(BTreeMap<String, String>),
+}
+
+/// The `mapping` field of `POST /import/csv`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Mapping {
+    /// Header of the timestamp column.
+    timestamp: String,
+    #[serde(default)]
+    timestamp_format: TimestampFormat,
+    start: Option<DateTime<Utc>>,
+    channels: ChannelColumns,
+    /// Field separator; `,` by default.
+    delimiter: Option<char>,
+    session_id: Option<i32>,
+    max_errors: Option<u64>,
+}
+
+impl Mapping {
+    /// (channel, header) pairs.
+    fn channels(&self) -> Vec<(String, String)> {
+        match &self.channels {
+            ChannelColumns::Same(headers) => {
+                headers.iter().map(|h| (h.clone(), h.clone())).collect()
+            }
+            ChannelColumns::Renamed(map) => {
+                map.iter().map(|(c, h)| (c.clone(), h.clone())).collect()
+            }
+        }
+    }
+
+    fn parse_ts(&self, text: &str) -> Result<DateTime<Utc>, String> {
+        let seconds = |scale: f64| -> Result<DateTime<Utc>, String> {
+            let value: f64 = text
+                .parse()
+                .map_err(|_| format!("invalid timestamp {:?}", text))?;
+            let micros = value * scale;
+            if !micros.is_finite() {
+                return Err(format!("invalid timestamp {:?}", text));
+            }
+            DateTime::from_timestamp_micros(micros.round() as i64)
+                .ok_or_else(|| format!("timestamp {:?} is out of range", text))
+        };
+        match self.timestamp_format {
+            TimestampFormat::Rfc3339 => DateTime::parse_from_rfc3339(text)
+                .map(|ts| ts.with_timezone(&Utc))
+                .or_else(|_| {
+                    NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f")
+                        .map(|ts| ts.and_utc())
+                })
+                .map_err(|_| format!("invalid timestamp {:?}", text)),
+            TimestampFormat::Unix => seconds(1e6),
+            TimestampFormat::UnixMs => seconds(1e3),
+            TimestampFormat::UnixUs => seconds(1.0),
+            TimestampFormat::Relative => {
+                let start = self.start.unwrap_or_default();
+                Ok(start + (seconds(1e6)? - DateTime::UNIX_EPOCH))
+            }
+        }
+    }
+}
+
+impl Validate for Mapping {
+    fn validate(&self, errors: &mut FieldErrors) {
+        let channels = self.channels();
+        errors.check(
+            !channels.is_empty(),
+            "channels",
+            "must name at least one channel",
+        );
+        for (channel, _) in &channels {
+            if let Err(e) = channels::check_accepted(channel) {
+                errors.add("channels", e);
+            }
+        }
+        errors.check(
+            !matches!(self.timestamp_format, TimestampFormat::Relative) || self.start.is_some(),
+            "start",
+            "is required with timestamp_format \"relative\"",
+        );
+        errors.check(
+            self.delimiter.is_none_or(|d| d.is_ascii() && d != '"'),
+            "delimiter",
+            "must be an ASCII character other than '\"'",
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[schema(as = ImportJobStatus)]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RowError {
+    /// Record number in the file, counting the header as line 1.
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[schema(as = ImportJob)]
+pub struct Job {
+    pub id: u64,
+    pub status: JobStatus,
+    pub file_name: Option<String>,
+    pub channels: Vec<String>,
+    pub session_id: Option<i32>,
+    /// Bytes of the file received so far.
+    pub bytes: u64,
+    /// Data rows read so far.
+    pub rows: u64,
+    /// Samples sent to the database so far; stored once the job is done.
+    pub samples: u64,
+    /// Rows skipped because of an error.
+    pub rejected: u64,
+    pub errors: Vec<RowError>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+pub type Jobs = Arc<Mutex<BTreeMap<u64, Job>>>;
+
+/// Forgets jobs that finished more than [`MAX_AGE`] ago.
+fn expire(jobs: &Jobs) {
+    let cutoff = Utc::now() - MAX_AGE;
+    jobs.lock()
+        .unwrap()
+        .retain(|_, job| job.finished_at.is_none_or(|at| at > cutoff));
+}
+
+fn update(jobs: &Jobs, id: u64, change: impl FnOnce(&mut Job)) {
+    if let Some(job) = jobs.lock().unwrap().get_mut(&id) {
+        change(job);
+    }
+}
+
+/// Incremental CSV parser over the chunks of an upload.
+struct Parser {
+    reader: Reader,
+    output: Vec<u8>,
+    ends: Vec<usize>,
+    output_len: usize,
+    ends_len: usize,
+    line: u64,
+}
+
+impl Parser {
+    fn new(delimiter: u8) -> Self {
+        Self {
+            reader: ReaderBuilder::new().delimiter(delimiter).build(),
+            output: vec![0; 4096],
+            ends: vec![0; 64],
+            output_len: 0,
+            ends_len: 0,
+            line: 0,
+        }
+    }
+
+    /// Parses `input` and calls `record` with the line and fields of each
+    /// complete record. An incomplete last record is kept for the next call;
+    /// `last` marks the end of the file.
+    fn feed(&mut self, mut input: &[u8], last: bool, mut record: impl FnMut(u64, &[&[u8]])) {
+        loop {
+            let (result, read, written, ends) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[read..];
+            self.output_len += written;
+            self.ends_len += ends;
+            match result {
+                ReadRecordResult::InputEmpty if !last => return,
+                ReadRecordResult::InputEmpty => {}
+                ReadRecordResult::OutputFull => self.output.resize(self.output.len() * 2, 0),
+                ReadRecordResult::OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+                ReadRecordResult::Record => {
+                    self.line += 1;
+                    let mut fields = Vec::with_capacity(self.ends_len);
+                    let mut start = 0;
+                    for &end in &self.ends[..self.ends_len] {
+                        fields.push(&self.output[start..end]);
+                        start = end;
+                    }
+                    record(self.line, &fields);
+                    self.output_len = 0;
+                    self.ends_len = 0;
+                }
+                ReadRecordResult::End => return,
+            }
+        }
+    }
+}
+
+/// Column positions, resolved from the header.
+struct Columns {
+    width: usize,
+    timestamp: usize,
+    /// (column, channel)
+    channels: Vec<(usize, String)>,
+}
+
+/// State of a running import between records.
+struct Load<'m> {
+    mapping: &'m Mapping,
+    max_errors: u64,
+    columns: Option<Columns>,
+    batch: Vec<NewSample>,
+    rows: u64,
+    rejected: u64,
+    errors: Vec<RowError>,
+    earliest_us: i64,
+    /// Error that ends the import.
+    fatal: Option<String>,
+}
+
+impl<'m> Load<'m> {
+    fn new(mapping: &'m Mapping) -> Self {
+        Self {
+            mapping,
+            max_errors: mapping.max_errors.unwrap_or(DEFAULT_MAX_ERRORS),
+            columns: None,
+            batch: Vec::with_capacity(ROWS_PER_SEND),
+            rows: 0,
+            rejected: 0,
+            errors: Vec::new(),
+            earliest_us: i64::MAX,
+            fatal: None,
+        }
+    }
+
+    fn header(&self, fields: &[&[u8]]) -> Result<Columns, String> {
+        let headers: Vec<String> = fields
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                let header = String::from_utf8_lossy(f);
+                let header = if i == 0 {
+                    header.trim_start_matches('\u{feff}')
+                } else {
+                    &header
+                };
+                header.trim().to_string()
+            })
+            .collect();
+        let position = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h == name)
+                .ok_or_else(|| format!("the header has no column {:?}", name))
+        };
+        let timestamp = position(&self.mapping.timestamp)?;
+        let channels = self
+            .mapping
+            .channels()
+            .into_iter()
+            .map(|(channel, header)| Ok((position(&header)?, channel)))
+            .collect::<Result<_, String>>()?;
+        Ok(Columns {
+            width: headers.len(),
+            timestamp,
+            channels,
+        })
+    }
+
+    fn row(&self, columns: &Columns, fields: &[&[u8]]) -> Result<Vec<NewSample>, String> {
+        if fields.len() != columns.width {
+            return Err(format!(
+                "expected {} fields, found {}",
+                columns.width,
+                fields.len()
+            ));
+        }
+        let field = |i: usize| {
+            std::str::from_utf8(fields[i])
+                .map(str::trim)
+                .map_err(|_| "invalid UTF-8".to_string())
+        };
+        let ts = self.mapping.parse_ts(field(columns.timestamp)?)?;
+        let mut samples = Vec::with_capacity(columns.channels.len());
+        for (column, channel) in &columns.channels {
+            let text = field(*column)?;
+            if text.is_empty() {
+                continue;
+            }
+            let value: f64 = text
+                .parse()
+                .ok()
+                .filter(|v: &f64| v.is_finite())
+                .ok_or_else(|| format!("invalid value {:?} for {}", text, channel))?;
+            samples.push(NewSample {
+                channel: channel.clone(),
+                ts,
+                value,
+                session_id: self.mapping.session_id,
+            });
+        }
+        Ok(samples)
+    }
+
+    fn record(&mut self, line: u64, fields: &[&[u8]]) {
+        if self.fatal.is_some() {
+            return;
+        }
+        let Some(columns) = &self.columns else {
+            match self.header(fields) {
+                Ok(columns) => self.columns = Some(columns),
+                Err(e) => self.fatal = Some(e),
+            }
+            return;
+        };
+        if fields.len() == 1 && fields[0].is_empty() {
+            return;
+        }
+        self.rows += 1;
+        match self.row(columns, fields) {
+            Ok(samples) => {
+                if let Some(sample) = samples.first() {
+                    self.earliest_us = self.earliest_us.min(sample.ts.timestamp_micros());
+                }
+                self.batch.extend(samples);
+            }
+            Err(message) => {
+                self.rejected += 1;
+                if self.errors.len() < MAX_REPORTED_ERRORS {
+                    self.errors.push(RowError { line, message });
+                }
+                if self.rejected > self.max_errors {
+                    self.fatal = Some(format!("more than {} rows were rejected", self.max_errors));
+                }
+            }
+        }
+    }
+}
+
+/// Reads the rest of the upload into the COPY; returns the earliest sample
+/// time in µs since the Unix epoch.
+async fn load(
+    field: &mut axum::extract::multipart::Field<'_>,
+    copy: &mut SampleCopy<'_>,
+    mapping: &Mapping,
+    jobs: &Jobs,
+    id: u64,
+) -> Result<i64, ApiError> {
+    let mut parser = Parser::new(mapping.delimiter.map_or(b',', |d| d as u8));
+    let mut state = Load::new(mapping);
+    let mut bytes = 0u64;
+    let mut samples = 0u64;
+    loop {
+        let chunk = field.chunk().await.map_err(multipart_error)?;
+        let last = chunk.is_none();
+        let input = chunk.as_deref().unwrap_or_default();
+        bytes += input.len() as u64;
+        parser.feed(input, last, |line, fields| state.record(line, fields));
+        if last && state.columns.is_none() && state.fatal.is_none() {
+            state.fatal = Some("the file has no header".to_string());
+        }
+
+        if last || state.batch.len() >= ROWS_PER_SEND {
+            copy.send(&state.batch).await.map_err(super::db_error)?;
+            samples += state.batch.len() as u64;
+            state.batch.clear();
+        }
+        update(jobs, id, |job| {
+            job.bytes = bytes;
+            job.rows = state.rows;
+            job.samples = samples;
+            job.rejected = state.rejected;
+            job.errors.clone_from(&state.errors);
+        });
+        if let Some(e) = state.fatal.take() {
+            return Err(bad_request(e));
+        }
+        if last {
+            return Ok(state.earliest_us);
+        }
+    }
+}
+
+/// Imports samples from a CSV upload; `201` with the finished job.
+#[utoipa::path(
+    post,
+    path = "/import/csv",
+    tag = "imports",
+    request_body(content_type = "multipart/form-data", description = "Field `mapping`: the column mapping as JSON (see the `Mapping` schema), then field `file`: the CSV file"),
+    responses(
+        (status = 201, description = "The finished job", body = Job),
+        (status = 400, description = "Invalid file, or too many rejected rows"),
+        (status = 422, description = "Invalid mapping, named in `details.fields`"),
+        (status = 413, description = "Upload larger than `server.import_max_bytes`")
+    )
+)]
+pub async fn import_csv(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<Job>), ApiError> {
+    let mut mapping: Option<Mapping> = None;
+    let mut field = loop {
+        match multipart.next_field().await.map_err(multipart_error)? {
+            Some(field) if field.name() == Some("file") => break field,
+            Some(field) if field.name() == Some("mapping") => {
+                let text = field.text().await.map_err(multipart_error)?;
+                mapping = Some(
+                    serde_json::from_str(&text)
+                        .map_err(|e| bad_request(format!("invalid mapping: {}", e)))?,
+                );
+            }
+            Some(_) => {}
+            None => return Err(bad_request("missing multipart field \"file\"")),
+        }
+    };
+    let mapping =
+        mapping.ok_or_else(|| bad_request("the \"mapping\" field must precede \"file\""))?;
+    let mut errors = FieldErrors::default();
+    errors.within("mapping", &mapping);
+    if !errors.is_empty() {
+        return Err(errors.into());
+    }
+    if let Some(session_id) = mapping.session_id {
+        let exists: Option<i32> = sqlx::query_scalar("SELECT id FROM sessions WHERE id = $1")
+            .bind(session_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(super::db_error)?;
+        if exists.is_none() {
+            return Err(bad_request(format!("unknown session {}", session_id)));
+        }
+    }
+
+    expire(&state.imports);
+    let job = Job {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        status: JobStatus::Running,
+        file_name: field.file_name().map(str::to_string),
+        channels: mapping.channels().into_iter().map(|(c, _)| c).collect(),
+        session_id: mapping.session_id,
+        bytes: 0,
+        rows: 0,
+        samples: 0,
+        rejected: 0,
+        errors: Vec::new(),
+        error: None,
+        created_at: Utc::now(),
+        finished_at: None,
+    };
+    let id = job.id;
+    state.imports.lock().unwrap().insert(id, job);
+
+    let result = async {
+        let mut conn = state.pool.acquire().await.map_err(super::db_error)?;
+        let mut copy = SampleCopy::start(&mut conn)
+            .await
+            .map_err(super::db_error)?;
+        match load(&mut field, &mut copy, &mapping, &state.imports, id).await {
+            Ok(earliest_us) => {
+                let (samples, written) = copy.finish().await.map_err(super::db_error)?;
+                notify::written(&mut *conn, written.iter().map(String::as_str))
+                    .await
+                    .map_err(super::db_error)?;
+                Ok((samples, earliest_us))
+            }
+            Err(e) => {
+                let _ = copy.abort(e.message.clone()).await;
+                Err(e)
+            }
+        }
+    }
+    .await;
+
+    let mut jobs = state.imports.lock().unwrap();
+    let job = jobs.get_mut(&id).expect("running jobs are not expired");
+    job.finished_at = Some(Utc::now());
+    match result {
+        Ok((samples, earliest_us)) => {
+            if samples > 0 {
+                tiers::mark_dirty(earliest_us);
+            }
+            job.status = JobStatus::Done;
+            job.samples = samples;
+            Ok((StatusCode::CREATED, Json(job.clone())))
+        }
+        Err(mut e) => {
+            job.status = JobStatus::Failed;
+            job.samples = 0;
+            job.error = Some(e.message.clone());
+            e.message = format!("import {} failed: {}", id, e.message);
+            Err(e)
+        }
+    }
+}
+
+/// Imports of the last 24 hours, newest first.
+#[utoipa::path(
+    get,
+    path = "/imports",
+    tag = "imports",
+    responses((status = 200, description = "Imports of the last 24 hours, newest first", body = Vec<Job>))
+)]
+pub async fn list_imports(State(state): State<AppState>) -> Json<Vec<Job>> {
+    expire(&state.imports);
+    Json(
+        state
+            .imports
+            .lock()
+            .unwrap()
+            .values()
+            .rev()
+            .cloned()
+            .collect(),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/imports/{id}",
+    tag = "imports",
+    params(("id" = u64, Path)),
+    responses(
+        (status = 200, body = Job),
+        (status = 404, description = "Unknown import")
+    )
+)]
+pub async fn get_import(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<u64>>,
+) -> Result<Json<Job>, ApiError> {
+    state
+        .imports
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("unknown import {}", id)))
+}
@@ -0,0 +1,453 @@
+//! EDF(+) and BDF(+) reader for `POST /import/edf`
+//! (<https://www.edfplus.info/specs/edfplus.html>).
+//!
+//! Every ordinary signal becomes a channel. The name is the label without
+//! the EDF+ signal type (`EEG Fp1` is stored as `Fp1`), the unit is the
+//! physical dimension, and the rate is the signal's samples per data record
+//! divided by the record duration. Digital values are converted to physical
+//! ones with the signal's ranges. A sample is timestamped at its offset
+//! within its record. A record starts at the onset of its timekeeping
+//! annotation, so EDF+D gaps are kept. Without one (plain EDF), records
+//! follow each other.
+//!
+//! Annotations other than timekeeping become events. Header times are taken
+//! as UTC, like the exporter writes them. For EDF+ files, the patient code
+//! links an existing subject with that code, and the recording equipment
+//! becomes the session's device.
+
+use super::{
+    bad_request, channel_name, create_session, dedupe_names, event_label, finish, insert_events,
+    multipart_error, register_channels, ImportChannel, ImportEvent, ImportQuery, ImportSummary,
+    Upload,
+};
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::export::edf::{Variant, TAL_DURATION, TAL_TEXT};
+use crate::ingest::NewSample;
+use crate::pipeline::SampleCopy;
+use crate::validation::Parsed;
+use crate::{notify, AppState};
+use axum::{
+    extract::{Multipart, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+
+const FIXED_HEADER: usize = 256;
+const SIGNAL_HEADER: usize = 256;
+
+/// Samples buffered before they are sent to the COPY.
+const ROWS_PER_SEND: usize = 10_000;
+
+/// Signal types of the EDF+ label convention `<type> <sensor>`.
+const SIGNAL_TYPES: &[&str] = &[
+    "EEG", "ECG", "EOG", "ERG", "EMG", "MEG", "MCG", "EP", "Temp", "Resp", "SaO2", "Light",
+    "Sound", "Event",
+];
+
+struct Signal {
+    label: String,
+    unit: String,
+    samples_per_record: usize,
+    annotations: bool,
+    /// Physical value of digital 0 and per digital step.
+    offset: f64,
+    gain: f64,
+}
+
+struct Header {
+    variant: Variant,
+    patient: String,
+    recording: String,
+    start: DateTime<Utc>,
+    /// Number of data records; `None` if the header gives -1 (unknown).
+    records: Option<usize>,
+    record_seconds: f64,
+    signals: Vec<Signal>,
+}
+
+impl Header {
+    fn record_bytes(&self) -> usize {
+        let samples: usize = self.signals.iter().map(|s| s.samples_per_record).sum();
+        samples * self.variant.sample_bytes()
+    }
+
+    /// An EDF+ subfield of the patient or recording field.
+    fn subfield(field: &str, index: usize) -> Option<String> {
+        field
+            .split_whitespace()
+            .nth(index)
+            .filter(|v| *v != "X")
+            .map(|v| v.replace('_', " "))
+    }
+
+    fn subject_code(&self) -> Option<String> {
+        Self::subfield(&self.patient, 0)
+    }
+
+    fn device(&self) -> Option<String> {
+        if self.recording.starts_with("Startdate ") {
+            Self::subfield(&self.recording, 4)
+        } else {
+            None
+        }
+    }
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().to_string()
+}
+
+fn number<T: std::str::FromStr>(bytes: &[u8], what: &str) -> Result<T, String> {
+    let value = text(bytes);
+    value
+        .parse()
+        .map_err(|_| format!("invalid {} {:?} in header", what, value))
+}
+
+/// Start of the recording from the `dd.mm.yy` and `hh.mm.ss` fields. The
+/// EDF+ `Startdate dd-MMM-yyyy` subfield gives the century when present.
+fn start_time(date: &str, time: &str, recording: &str) -> Result<DateTime<Utc>, String> {
+    let parts = |s: &str| -> Option<Vec<u32>> {
+        let parts: Option<Vec<u32>> = s.split(['.', ':']).map(|p| p.parse().ok()).collect();
+        parts.filter(|p| p.len() == 3)
+    };
+    let (Some(d), Some(t)) = (parts(date), parts(time)) else {
+        return Err(format!("invalid start date/time {:?} {:?}", date, time));
+    };
+    let plus_year = recording
+        .strip_prefix("Startdate ")
+        .and_then(|r| r.split_whitespace().next())
+        .and_then(|d| NaiveDate::parse_from_str(d, "%d-%b-%Y").ok())
+        .map(|d| chrono::Datelike::year(&d));
+    let year = match plus_year {
+        Some(year) => year,
+        None if d[2] >= 85 => 1900 + d[2] as i32,
+        None => 2000 + d[2] as i32,
+    };
+    NaiveDate::from_ymd_opt(year, d[1], d[0])
+        .and_then(|date| date.and_hms_opt(t[0], t[1], t[2]))
+        .map(|start| Utc.from_utc_datetime(&start))
+        .ok_or_else(|| format!("invalid start date/time {:?} {:?}", date, time))
+}
+
+fn parse_fixed(bytes: &[u8]) -> Result<(Header, usize), String> {
+    let variant = match &bytes[..8] {
+        b"0       " => Variant::Edf,
+        b"\xffBIOSEMI" => Variant::Bdf,
+        _ => return Err("not an EDF or BDF file".to_string()),
+    };
+    let patient = text(&bytes[8..88]);
+    let recording = text(&bytes[88..168]);
+    let start = start_time(&text(&bytes[168..176]), &text(&bytes[176..184]), &recording)?;
+    let header_bytes: usize = number(&bytes[184..192], "header size")?;
+    let records: i64 = number(&bytes[236..244], "number of records")?;
+    let record_seconds: f64 = number(&bytes[244..252], "record duration")?;
+    let ns: usize = number(&bytes[252..256], "number of signals")?;
+
+    if ns == 0 {
+        return Err("the file has no signals".to_string());
+    }
+    if header_bytes != FIXED_HEADER + ns * SIGNAL_HEADER {
+        return Err(format!(
+            "header size {} does not match {} signals",
+            header_bytes, ns
+        ));
+    }
+    if !record_seconds.is_finite() || record_seconds < 0.0 {
+        return Err("record duration must not be negative".to_string());
+    }
+    let records = match records {
+        -1 => None,
+        n if n >= 0 => Some(n as usize),
+        n => return Err(format!("invalid number of records {}", n)),
+    };
+    let header = Header {
+        variant,
+        patient,
+        recording,
+        start,
+        records,
+        record_seconds,
+        signals: Vec::with_capacity(ns),
+    };
+    Ok((header, ns))
+}
+
+fn parse_signals(header: &mut Header, ns: usize, bytes: &[u8]) -> Result<(), String> {
+    // Each field is stored for all signals before the next field.
+    let field = |offset: usize, width: usize, i: usize| {
+        let start = ns * offset + i * width;
+        &bytes[start..start + width]
+    };
+    let annotation_label = format!("{} Annotations", header.variant.name());
+    for i in 0..ns {
+        let label = text(field(0, 16, i));
+        let annotations = label == "EDF Annotations" || label == annotation_label;
+        let physical_min: f64 = number(field(104, 8, i), "physical minimum")?;
+        let physical_max: f64 = number(field(112, 8, i), "physical maximum")?;
+        let digital_min: f64 = number(field(120, 8, i), "digital minimum")?;
+        let digital_max: f64 = number(field(128, 8, i), "digital maximum")?;
+        let samples_per_record: usize = number(field(216, 8, i), "samples per record")?;
+        if !annotations {
+            if digital_max <= digital_min || physical_max == physical_min {
+                return Err(format!("signal {:?} has an empty range", label));
+            }
+            if samples_per_record == 0 || header.record_seconds == 0.0 {
+                return Err(format!("signal {:?} has no sample rate", label));
+            }
+        }
+        let gain = if annotations {
+            0.0
+        } else {
+            (physical_max - physical_min) / (digital_max - digital_min)
+        };
+        header.signals.push(Signal {
+            unit: text(field(96, 8, i)),
+            label,
+            samples_per_record,
+            annotations,
+            offset: physical_min - digital_min * gain,
+            gain,
+        });
+    }
+    Ok(())
+}
+
+/// Channel name of a signal label, without the EDF+ signal type.
+fn signal_name(label: &str) -> String {
+    let sensor = match label.split_once(' ') {
+        Some((kind, sensor)) if SIGNAL_TYPES.contains(&kind) && !sensor.trim().is_empty() => sensor,
+        _ => label,
+    };
+    channel_name(sensor)
+}
+
+/// Reads the onset and texts of each TAL in an annotation signal.
+fn parse_tals(bytes: &[u8]) -> Result<Vec<(f64, f64, Vec<String>)>, String> {
+    let mut tals = Vec::new();
+    for tal in bytes.split(|&b| b == 0).filter(|t| !t.is_empty()) {
+        let mut parts = tal.split(|&b| b == TAL_TEXT);
+        let time = parts.next().unwrap_or_default();
+        let (onset, duration) = match time.iter().position(|&b| b == TAL_DURATION) {
+            Some(i) => (&time[..i], Some(&time[i + 1..])),
+            None => (time, None),
+        };
+        let onset: f64 = text(onset)
+            .parse()
+            .map_err(|_| format!("invalid annotation onset {:?}", text(onset)))?;
+        let duration: f64 = match duration {
+            Some(d) => text(d)
+                .parse()
+                .map_err(|_| format!("invalid annotation duration {:?}", text(d)))?,
+            None => 0.0,
+        };
+        let texts = parts
+            .map(|p| String::from_utf8_lossy(p).into_owned())
+            .collect();
+        tals.push((onset, duration.max(0.0), texts));
+    }
+    Ok(tals)
+}
+
+fn digital(bytes: &[u8], variant: Variant) -> i32 {
+    match variant {
+        Variant::Edf => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+        Variant::Bdf => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8,
+    }
+}
+
+struct Copied {
+    events: Vec<ImportEvent>,
+    ended_at: DateTime<Utc>,
+}
+
+/// Reads the data records and copies their samples.
+async fn copy_records(
+    upload: &mut Upload<'_>,
+    copy: &mut SampleCopy<'_>,
+    header: &Header,
+    channels: &[ImportChannel],
+    session_id: i32,
+) -> Result<Copied, ApiError> {
+    let record_bytes = header.record_bytes();
+    let width = header.variant.sample_bytes();
+    let at = |seconds: f64| header.start + Duration::microseconds((seconds * 1e6).round() as i64);
+    let mut events = Vec::new();
+    let mut batch: Vec<NewSample> = Vec::with_capacity(ROWS_PER_SEND);
+    let mut ended_at = header.start;
+
+    let mut record = 0usize;
+    while header.records.is_none_or(|n| record < n) {
+        if record_bytes == 0 || !upload.fill(record_bytes).await? {
+            if header.records.is_none() && upload.at_end().await? {
+                break;
+            }
+            return Err(bad_request(format!(
+                "the file ends in data record {}",
+                record + 1
+            )));
+        }
+        let data = upload.take(record_bytes).to_vec();
+
+        let mut onset = None;
+        let mut offset = 0;
+        for signal in &header.signals {
+            let bytes = &data[offset..offset + signal.samples_per_record * width];
+            offset += bytes.len();
+            if !signal.annotations {
+                continue;
+            }
+            for (i, (tal_onset, duration, texts)) in parse_tals(bytes)
+                .map_err(bad_request)?
+                .into_iter()
+                .enumerate()
+            {
+                let mut texts = texts.iter();
+                if i == 0 && onset.is_none() {
+                    // Timekeeping TAL: the record start, with an empty first text.
+                    onset = Some(tal_onset);
+                    texts.next();
+                }
+                for text in texts {
+                    let label = event_label(text);
+                    if !label.is_empty() {
+                        events.push(ImportEvent {
+                            ts: at(tal_onset),
+                            duration,
+                            label,
+                        });
+                    }
+                }
+            }
+        }
+        let onset = onset.unwrap_or(record as f64 * header.record_seconds);
+
+        let mut offset = 0;
+        let mut channel = channels.iter();
+        for signal in &header.signals {
+            let bytes = &data[offset..offset + signal.samples_per_record * width];
+            offset += bytes.len();
+            if signal.annotations {
+                continue;
+            }
+            let name = &channel.next().unwrap().name;
+            let step = header.record_seconds / signal.samples_per_record as f64;
+            for (i, sample) in bytes.chunks_exact(width).enumerate() {
+                batch.push(NewSample {
+                    channel: name.clone(),
+                    ts: at(onset + i as f64 * step),
+                    value: signal.offset + digital(sample, header.variant) as f64 * signal.gain,
+                    session_id: Some(session_id),
+                });
+            }
+            if batch.len() >= ROWS_PER_SEND {
+                copy.send(&batch).await.map_err(super::db_error)?;
+                batch.clear();
+            }
+        }
+        ended_at = ended_at.max(at(onset + header.record_seconds));
+        record += 1;
+    }
+    copy.send(&batch).await.map_err(super::db_error)?;
+
+    Ok(Copied { events, ended_at })
+}
+
+/// Imports an EDF(+) or BDF(+) file as a new session.
+#[utoipa::path(
+    post,
+    path = "/import/edf",
+    tag = "imports",
+    params(ImportQuery),
+    request_body(content_type = "multipart/form-data", description = "Field `file`: an EDF(+) or BDF(+) file"),
+    responses(
+        (status = 201, description = "The new session and its channels", body = ImportSummary),
+        (status = 400, description = "Invalid file, unknown subject or device"),
+        (status = 403, description = "New channels in the file and no `admin` role"),
+        (status = 413, description = "Upload larger than `server.import_max_bytes`")
+    )
+)]
+pub async fn import_edf(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Parsed(Query(query)): Parsed<Query<ImportQuery>>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<ImportSummary>), ApiError> {
+    let field = loop {
+        match multipart.next_field().await.map_err(multipart_error)? {
+            Some(field) if field.name() == Some("file") => break field,
+            Some(_) => {}
+            None => return Err(bad_request("missing multipart field \"file\"")),
+        }
+    };
+    let mut upload = Upload::new(field);
+    if !upload.fill(FIXED_HEADER).await? {
+        return Err(bad_request("the file is shorter than an EDF header"));
+    }
+    let (mut header, ns) = parse_fixed(upload.take(FIXED_HEADER)).map_err(bad_request)?;
+    if !upload.fill(ns * SIGNAL_HEADER).await? {
+        return Err(bad_request("the file ends in the signal headers"));
+    }
+    parse_signals(&mut header, ns, upload.take(ns * SIGNAL_HEADER)).map_err(bad_request)?;
+
+    let mut channels: Vec<ImportChannel> = header
+        .signals
+        .iter()
+        .filter(|s| !s.annotations)
+        .map(|s| ImportChannel {
+            name: signal_name(&s.label),
+            label: s.label.clone(),
+            unit: if s.unit.is_empty() {
+                "a.u.".to_string()
+            } else {
+                s.unit.clone()
+            },
+            sample_rate: Some(s.samples_per_record as f64 / header.record_seconds),
+        })
+        .collect();
+    dedupe_names(&mut channels);
+
+    let notes = format!(
+        "Imported from {}",
+        upload.file_name.as_deref().unwrap_or("an EDF upload")
+    );
+    let mut tx = state.pool.begin().await.map_err(super::db_error)?;
+    let session = create_session(
+        &mut tx,
+        &query,
+        header.start,
+        header.subject_code().as_deref(),
+        header.device().as_deref(),
+        notes,
+    )
+    .await?;
+    let created_channels = register_channels(&mut tx, principal.as_deref(), &channels).await?;
+
+    let mut copy = SampleCopy::start(&mut tx).await.map_err(super::db_error)?;
+    let copied = match copy_records(&mut upload, &mut copy, &header, &channels, session.id).await {
+        Ok(copied) => copied,
+        Err(e) => {
+            let _ = copy.abort(e.message.clone()).await;
+            return Err(e);
+        }
+    };
+    let (samples, written) = copy.finish().await.map_err(super::db_error)?;
+    notify::written(&mut *tx, written.iter().map(String::as_str))
+        .await
+        .map_err(super::db_error)?;
+    insert_events(&mut tx, session.id, &copied.events).await?;
+    let session = finish(tx, session, copied.ended_at, &created_channels).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ImportSummary {
+            session,
+            channels: channels.into_iter().map(|c| c.name).collect(),
+            created_channels,
+            samples,
+            events: copied.events.len(),
+        }),
+    ))
+}
@@ -0,0 +1,331 @@
+//! Imports of recordings from files (`POST /import/...`).
+//!
+//! A recording import creates a session and stores everything in one
+//! transaction, so a file that fails to parse leaves nothing behind: the
+//! session, channels that are not registered yet, the samples (through a
+//! binary COPY) and the events. Channels that already exist are reused as
+//! long as they are enabled and in the file's unit. CSV imports load samples
+//! of registered channels, see [`csv`].
+//!
+//! Uploads are `multipart/form-data` with the file in a field named `file`
+//! (other fields before it are skipped unless the format uses them); it is
+//! parsed while it is received, so its size is bounded only by
+//! `IMPORT_MAX_BYTES`.
+
+pub mod csv;
+pub mod edf;
+
+use crate::auth::Principal;
+use crate::channels;
+use crate::config;
+use crate::error::ApiError;
+use crate::roles::{self, Role};
+use crate::sessions::Session;
+use crate::tiers;
+use axum::extract::multipart::{Field, MultipartError};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Postgres, Transaction};
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_LABEL_LEN: usize = 128;
+
+/// Query options of an import; they override what is read from the file.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    pub subject_id: Option<i32>,
+    pub device: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// Response of an import.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportSummary {
+    pub session: Session,
+    /// Channels the samples were stored under, in file order.
+    pub channels: Vec<String>,
+    /// Channels that were registered by the import.
+    pub created_channels: Vec<String>,
+    pub samples: u64,
+    pub events: usize,
+}
+
+/// A channel read from a file.
+pub struct ImportChannel {
+    pub name: String,
+    pub label: String,
+    pub unit: String,
+    pub sample_rate: Option<f64>,
+}
+
+/// An event read from a file.
+pub struct ImportEvent {
+    pub ts: DateTime<Utc>,
+    pub duration: f64,
+    pub label: String,
+}
+
+/// Largest accepted upload, `server.import_max_bytes`.
+pub fn max_upload_bytes() -> usize {
+    config::get().server.import_max_bytes
+}
+
+pub(crate) fn db_error(e: sqlx::Error) -> ApiError {
+    // 23503: foreign_key_violation on subject_id.
+    match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            ApiError::bad_request("unknown subject_id")
+        }
+        _ => e.into(),
+    }
+}
+
+pub(crate) fn multipart_error(e: MultipartError) -> ApiError {
+    ApiError::new(e.status(), e.body_text())
+}
+
+pub(crate) fn bad_request(message: impl Into<String>) -> ApiError {
+    ApiError::bad_request(message.into())
+}
+
+/// The uploaded file, read in chunks as it arrives.
+pub struct Upload<'a> {
+    field: Field<'a>,
+    buf: Vec<u8>,
+    pos: usize,
+    /// File name given by the client, if any.
+    pub file_name: Option<String>,
+}
+
+impl<'a> Upload<'a> {
+    /// Reads the `file` field of an upload.
+    pub fn new(field: Field<'a>) -> Self {
+        Upload {
+            file_name: field.file_name().map(str::to_string),
+            field,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Buffers at least `n` bytes; false if the file ends before.
+    pub async fn fill(&mut self, n: usize) -> Result<bool, ApiError> {
+        while self.buf.len() - self.pos < n {
+            let Some(chunk) = self.field.chunk().await.map_err(multipart_error)? else {
+                return Ok(false);
+            };
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+            self.buf.extend_from_slice(&chunk);
+        }
+        Ok(true)
+    }
+
+    /// Whether no bytes are buffered and the file has ended.
+    pub async fn at_end(&mut self) -> Result<bool, ApiError> {
+        Ok(!self.fill(1).await?)
+    }
+
+    /// Consumes `n` buffered bytes; call [`fill`](Self::fill) first.
+    pub fn take(&mut self, n: usize) -> &[u8] {
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        bytes
+    }
+}
+
+/// A channel name from a label in a file: characters not allowed in names
+/// become `_`, and the name is cut to the maximum length.
+pub fn channel_name(label: &str) -> String {
+    let name: String = label
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_NAME_LEN)
+        .collect();
+    if name.is_empty() {
+        "channel".to_string()
+    } else {
+        name
+    }
+}
+
+/// Makes the names of `channels` distinct by suffixing repeats with `-2`,
+/// `-3`, ...
+pub fn dedupe_names(channels: &mut [ImportChannel]) {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for channel in channels.iter_mut() {
+        let count = seen.entry(channel.name.clone()).or_insert(0);
+        *count += 1;
+        if *count > 1 {
+            let suffix = format!("-{}", count);
+            let keep = MAX_NAME_LEN - suffix.len();
+            channel.name.truncate(keep);
+            channel.name.push_str(&suffix);
+        }
+    }
+}
+
+/// `label` cut to the length allowed for event labels.
+pub fn event_label(label: &str) -> String {
+    let label = label.trim();
+    let mut end = label.len().min(MAX_LABEL_LEN);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    label[..end].to_string()
+}
+
+/// Creates the session of an import. `subject_code` and `device` come from
+/// the file and are used when the query does not set them; a subject is only
+/// linked if one with that code exists.
+pub async fn create_session(
+    conn: &mut PgConnection,
+    query: &ImportQuery,
+    started_at: DateTime<Utc>,
+    subject_code: Option<&str>,
+    device: Option<&str>,
+    notes: String,
+) -> Result<Session, ApiError> {
+    let subject_id = match (query.subject_id, subject_code) {
+        (Some(id), _) => Some(id),
+        (None, Some(code)) => sqlx::query_scalar("SELECT id FROM subjects WHERE code = $1")
+            .bind(code)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(db_error)?,
+        (None, None) => None,
+    };
+    sqlx::query_as(
+        "INSERT INTO sessions (subject_id, device, started_at, notes) VALUES ($1, $2, $3, $4) \
+         RETURNING id, subject_id, device, started_at, ended_at, notes",
+    )
+    .bind(subject_id)
+    .bind(query.device.as_deref().or(device))
+    .bind(started_at)
+    .bind(query.notes.clone().unwrap_or(notes))
+    .fetch_one(conn)
+    .await
+    .map_err(db_error)
+}
+
+/// Registers the channels of a file that do not exist yet and returns their
+/// names. Existing channels must be enabled and have the file's unit, and
+/// registering one needs the `admin` role of `principal`, if any.
+pub async fn register_channels(
+    conn: &mut PgConnection,
+    principal: Option<&Principal>,
+    channels: &[ImportChannel],
+) -> Result<Vec<String>, ApiError> {
+    let names: Vec<&str> = channels.iter().map(|c| c.name.as_str()).collect();
+    let existing: Vec<(String, bool, String)> =
+        sqlx::query_as("SELECT name, enabled, unit FROM channels WHERE name = ANY($1)")
+            .bind(&names)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(db_error)?;
+    let existing: HashMap<String, (bool, String)> = existing
+        .into_iter()
+        .map(|(name, enabled, unit)| (name, (enabled, unit)))
+        .collect();
+
+    let mut created = Vec::new();
+    for channel in channels {
+        match existing.get(&channel.name) {
+            Some((false, _)) => {
+                return Err(bad_request(format!(
+                    "channel {:?} is disabled",
+                    channel.name
+                )))
+            }
+            Some((true, unit)) if *unit != channel.unit => {
+                return Err(bad_request(format!(
+                    "channel {:?} is registered in {} but the file stores {}",
+                    channel.name, unit, channel.unit
+                )))
+            }
+            Some(_) => {}
+            None => {
+                if let Some(principal) = principal {
+                    roles::check(principal, &[Role::Admin]).map_err(|e| {
+                        let message = format!("registering channel {:?} {}", channel.name, e);
+                        ApiError::new(StatusCode::FORBIDDEN, message)
+                    })?;
+                }
+                sqlx::query(
+                    "INSERT INTO channels (name, label, unit, sample_rate, enabled) \
+                     VALUES ($1, $2, $3, $4, true)",
+                )
+                .bind(&channel.name)
+                .bind(&channel.label)
+                .bind(&channel.unit)
+                .bind(channel.sample_rate)
+                .execute(&mut *conn)
+                .await
+                .map_err(db_error)?;
+                created.push(channel.name.clone());
+            }
+        }
+    }
+    Ok(created)
+}
+
+/// Stores the events of an import in its session.
+pub async fn insert_events(
+    conn: &mut PgConnection,
+    session_id: i32,
+    events: &[ImportEvent],
+) -> Result<(), ApiError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let ts: Vec<DateTime<Utc>> = events.iter().map(|e| e.ts).collect();
+    let durations: Vec<f64> = events.iter().map(|e| e.duration).collect();
+    let labels: Vec<&str> = events.iter().map(|e| e.label.as_str()).collect();
+    sqlx::query(
+        "INSERT INTO events (session_id, ts, duration, label) \
+         SELECT $1, * FROM UNNEST($2::timestamptz[], $3::float8[], $4::text[])",
+    )
+    .bind(session_id)
+    .bind(&ts)
+    .bind(&durations)
+    .bind(&labels)
+    .execute(conn)
+    .await
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Sets the end of an imported session and commits the import; afterwards
+/// the new channels accept samples and the overview tiers are refreshed
+/// from the session start.
+pub async fn finish(
+    mut tx: Transaction<'_, Postgres>,
+    mut session: Session,
+    ended_at: DateTime<Utc>,
+    created_channels: &[String],
+) -> Result<Session, ApiError> {
+    let ended_at = ended_at.max(session.started_at);
+    sqlx::query("UPDATE sessions SET ended_at = $2 WHERE id = $1")
+        .bind(session.id)
+        .bind(ended_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    channels::register(created_channels);
+    tiers::mark_dirty(session.started_at.timestamp_micros());
+    session.ended_at = Some(ended_at);
+    Ok(session)
+}
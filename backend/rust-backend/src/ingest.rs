@@ -0,0 +1,352 @@
+//! Shared write path for every way samples enter the backend.
+//!
+//! Devices that retry on an unreliable link number their batches: a batch
+//! stored with [`insert_batch_once`] under a sequence number the device has
+//! already used is recognised and not stored again. Samples of trigger
+//! channels are stored as [`triggers`] changes, without ids.
+//!
+//! The sample type, the binary frame decoder and the series of values
+//! without timestamps live in the `eeg-ingest` crate and are re-exported
+//! here.
+
+use crate::validation::{self, FieldErrors, Validate};
+use crate::{cache, channels, metrics, notify, pipeline, ring, tiers, triggers};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+pub use eeg_ingest::frames::{decode_frames, ts_from_micros, FrameHeader};
+pub use eeg_ingest::series::{next_start, ChannelValues, Series};
+pub use eeg_ingest::NewSample;
+
+/// Foreign key of `ingest_batches` on the device registry.
+const DEVICE_CONSTRAINT: &str = "ingest_batches_device_serial_fkey";
+
+/// Upper bound on the number of samples accepted by one ingest request.
+pub const MAX_BATCH_SIZE: usize = 10_000;
+
+impl Validate for NewSample {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.channel.trim().is_empty() {
+            errors.add("channel", "must not be empty");
+        } else if let Err(e) = channels::check_accepted(&self.channel) {
+            errors.add("channel", e);
+        }
+        errors.check(self.value.is_finite(), "value", "must be a finite number");
+    }
+}
+
+/// Batch size, under `samples`, and every sample, under its index.
+impl Validate for [NewSample] {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.is_empty(), "samples", "must not be empty");
+        errors.check(
+            self.len() <= MAX_BATCH_SIZE,
+            "samples",
+            format!("at most {} per batch", MAX_BATCH_SIZE),
+        );
+        for (i, sample) in self.iter().enumerate() {
+            errors.within(&format!("[{}]", i), sample);
+        }
+    }
+}
+
+impl Validate for Vec<NewSample> {
+    fn validate(&self, errors: &mut FieldErrors) {
+        self.as_slice().validate(errors)
+    }
+}
+
+impl Validate for ChannelValues {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.channel.trim().is_empty() {
+            errors.add("channel", "must not be empty");
+        } else if let Err(e) = channels::check_accepted(&self.channel) {
+            errors.add("channel", e);
+        }
+        for (i, value) in self.values.iter().enumerate() {
+            errors.check(
+                value.is_finite(),
+                &format!("values[{}]", i),
+                "must be a finite number",
+            );
+        }
+    }
+}
+
+/// As for a batch of samples, with at most [`MAX_BATCH_SIZE`] values in
+/// all and each channel once.
+impl Validate for Series {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.check(!self.channels.is_empty(), "channels", "must not be empty");
+        let values: usize = self.channels.iter().map(|c| c.values.len()).sum();
+        errors.check(values > 0, "channels", "must have values");
+        errors.check(
+            values <= MAX_BATCH_SIZE,
+            "channels",
+            format!("at most {} values per batch", MAX_BATCH_SIZE),
+        );
+        if let Some(rate) = self.sample_rate {
+            errors.check(
+                rate.is_finite() && rate > 0.0,
+                "sample_rate",
+                "must be a positive number",
+            );
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            errors.within(&format!("channels[{}]", i), channel);
+            if self.channels[..i]
+                .iter()
+                .any(|c| c.channel == channel.channel)
+            {
+                errors.add(format!("channels[{}].channel", i), "is given twice");
+            }
+        }
+    }
+}
+
+/// Checks a batch as [`Validate`] does, for the ingest paths without
+/// per-field errors.
+pub fn validate_batch(samples: &[NewSample]) -> Result<(), String> {
+    validation::check(samples).map_err(|e| e.to_string())
+}
+
+/// Outcome of [`insert_batch_once`].
+#[derive(Debug)]
+pub enum Sequenced {
+    /// The batch was new; the ids of its samples, in order.
+    Stored(Vec<i32>),
+    /// The batch was stored before; the highest sequence number the device
+    /// has sent.
+    AlreadySeen { last_seq: i64 },
+}
+
+/// Inserts all samples with a single UNNEST statement and returns their ids.
+pub async fn insert_batch(pool: &PgPool, samples: Vec<NewSample>) -> Result<Vec<i32>, sqlx::Error> {
+    Ok(insert(pool, samples, None).await?.unwrap_or_default())
+}
+
+/// Inserts `samples` as batch `seq` of the device `serial`, unless that
+/// batch was stored before. The batch is recorded in `ingest_batches` by the
+/// statement that stores its samples, so a retry racing the original waits
+/// for it and is then recognised.
+pub async fn insert_batch_once(
+    pool: &PgPool,
+    serial: &str,
+    seq: i64,
+    samples: Vec<NewSample>,
+) -> Result<Sequenced, sqlx::Error> {
+    if samples.is_empty() {
+        return Ok(Sequenced::Stored(Vec::new()));
+    }
+    if let Some(ids) = insert(pool, samples, Some((serial, seq))).await? {
+        return Ok(Sequenced::Stored(ids));
+    }
+    let last_seq: i64 =
+        sqlx::query_scalar("SELECT max(seq) FROM ingest_batches WHERE device_serial = $1")
+            .bind(serial)
+            .fetch_one(pool)
+            .await?;
+    Ok(Sequenced::AlreadySeen { last_seq })
+}
+
+/// Forgets the batches received before `cutoff`, except the latest of each
+/// device, and returns how many.
+pub async fn prune_batches(pool: &PgPool, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM ingest_batches b WHERE received_at < $1 \
+         AND seq < (SELECT max(seq) FROM ingest_batches l WHERE l.device_serial = b.device_serial)",
+    )
+    .bind(cutoff)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// The ids of the stored samples, or `None` if `batch` was stored before.
+async fn insert(
+    pool: &PgPool,
+    mut samples: Vec<NewSample>,
+    batch: Option<(&str, i64)>,
+) -> Result<Option<Vec<i32>>, sqlx::Error> {
+    let changes = triggers::divert(&mut samples);
+    if samples.is_empty() {
+        if let Some((serial, seq)) = batch {
+            let recorded = sqlx::query(
+                "INSERT INTO ingest_batches (device_serial, seq, samples) VALUES ($1, $2, $3) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(serial)
+            .bind(seq)
+            .bind(changes.len() as i32)
+            .execute(pool)
+            .await?;
+            if recorded.rows_affected() == 0 {
+                return Ok(None);
+            }
+        }
+        triggers::store(pool, &changes).await?;
+        return Ok(Some(Vec::new()));
+    }
+
+    let mut ts = Vec::with_capacity(samples.len());
+    let mut channels = Vec::with_capacity(samples.len());
+    let mut values = Vec::with_capacity(samples.len());
+    let mut sessions = Vec::with_capacity(samples.len());
+    let mut earliest_us = i64::MAX;
+    for sample in &samples {
+        earliest_us = earliest_us.min(sample.ts.timestamp_micros());
+        ts.push(sample.ts);
+        channels.push(sample.channel.as_str());
+        values.push(sample.value);
+        sessions.push(sample.session_id);
+    }
+
+    let started = Instant::now();
+    let query = match batch {
+        None => sqlx::query_as(
+            "INSERT INTO eeg_samples (ts, channel, value, session_id) \
+             SELECT * FROM UNNEST($1::timestamptz[], $2::text[], $3::float8[], $4::int4[]) \
+             RETURNING id",
+        ),
+        // Nothing is stored when the batch is already recorded.
+        Some(_) => sqlx::query_as(
+            "WITH batch AS ( \
+               INSERT INTO ingest_batches (device_serial, seq, samples) \
+               VALUES ($5, $6, cardinality($1::timestamptz[])) \
+               ON CONFLICT DO NOTHING RETURNING seq) \
+             INSERT INTO eeg_samples (ts, channel, value, session_id) \
+             SELECT s.* FROM UNNEST($1::timestamptz[], $2::text[], $3::float8[], $4::int4[]) AS s \
+             WHERE EXISTS (SELECT 1 FROM batch) \
+             RETURNING id",
+        ),
+    };
+    let mut query = query
+        .bind(&ts)
+        .bind(&channels)
+        .bind(&values)
+        .bind(&sessions);
+    if let Some((serial, seq)) = batch {
+        query = query.bind(serial).bind(seq);
+    }
+    let ids: Vec<(i32,)> = query.fetch_all(pool).await?;
+    if ids.is_empty() {
+        return Ok(None);
+    }
+    triggers::store(pool, &changes).await?;
+    if let Err(e) = notify::written(pool, channels.iter().copied()).await {
+        tracing::warn!("cannot notify the samples stored: {}", e);
+    }
+
+    if earliest_us != i64::MAX {
+        tiers::mark_dirty(earliest_us);
+    }
+    metrics::record_ingested(channels);
+    let ids: Vec<i32> = ids.into_iter().map(|(id,)| id).collect();
+    ring::record(started, ids.iter().copied().zip(&samples));
+    cache::invalidate(samples.iter().map(|s| (s.channel.as_str(), s.session_id)));
+    Ok(Some(ids))
+}
+
+/// Whether an insert failed because a sample named a session that does not exist.
+pub fn is_unknown_session(e: &sqlx::Error) -> bool {
+    // 23503: foreign_key_violation; session_id is the only foreign key of
+    // eeg_samples.
+    matches!(e, sqlx::Error::Database(db) if db.code().as_deref() == Some("23503")
+        && db.constraint() != Some(DEVICE_CONSTRAINT))
+}
+
+/// Whether an insert failed because its batch named a device that does not exist.
+pub fn is_unknown_device(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.constraint() == Some(DEVICE_CONSTRAINT))
+}
+
+/// Spawns the COPY writer that stores batches sent by a background acquisition source.
+///
+/// Batches are buffered and flushed by [`pipeline::spawn`]; failures are
+/// logged under `source` rather than stopping the source.
+pub fn spawn_writer(pool: PgPool, source: &'static str) -> mpsc::Sender<Vec<NewSample>> {
+    pipeline::spawn(pool, source, pipeline::CopyConfig::from_config())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(channel: &str, value: f64) -> NewSample {
+        NewSample {
+            channel: channel.to_string(),
+            ts: DateTime::from_timestamp(1_800_000_000, 0).unwrap(),
+            value,
+            session_id: None,
+        }
+    }
+
+    fn values(channel: &str, values: &[f64]) -> ChannelValues {
+        ChannelValues {
+            channel: channel.to_string(),
+            values: values.to_vec(),
+        }
+    }
+
+    #[test]
+    fn batches_name_the_samples_that_break_a_rule() {
+        channels::accept_any();
+        let batch = vec![
+            sample("A3", 1.0),
+            sample(" ", 2.0),
+            sample("A4", f64::NAN),
+            sample("A 4", f64::INFINITY),
+        ];
+        let errors = validation::check(&batch).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "[1].channel: must not be empty; [2].value: must be a finite number; \
+             [3].channel: name may only contain letters, digits, '_', '-' and '.'; \
+             [3].value: must be a finite number"
+        );
+        assert!(validation::check(&batch[..1]).is_ok());
+
+        let empty: Vec<NewSample> = Vec::new();
+        let errors = validation::check(&empty).unwrap_err();
+        assert_eq!(errors.to_string(), "samples: must not be empty");
+        let full: Vec<NewSample> = (0..=MAX_BATCH_SIZE).map(|_| sample("A3", 0.0)).collect();
+        let errors = validation::check(&full).unwrap_err();
+        assert_eq!(errors.to_string(), "samples: at most 10000 per batch");
+    }
+
+    #[test]
+    fn series_check_rates_values_and_repeated_channels() {
+        channels::accept_any();
+        let series = |sample_rate, channels| Series {
+            start: None,
+            sample_rate,
+            session_id: None,
+            channels,
+        };
+        let ok = series(
+            Some(250.0),
+            vec![values("A3", &[1.0, 2.0]), values("A4", &[])],
+        );
+        assert!(validation::check(&ok).is_ok());
+
+        let errors = validation::check(&series(None, Vec::new())).unwrap_err();
+        assert_eq!(errors.to_string(), "channels: must not be empty");
+        let errors = validation::check(&series(Some(0.0), vec![values("A3", &[])])).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "channels: must have values; sample_rate: must be a positive number"
+        );
+
+        let repeated = series(
+            None,
+            vec![values("A3", &[1.0]), values("A3", &[2.0, f64::NAN])],
+        );
+        let errors = validation::check(&repeated).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "channels[1].values[1]: must be a finite number; channels[1].channel: is given twice"
+        );
+    }
+}
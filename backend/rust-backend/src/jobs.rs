@@ -0,0 +1,167 @@
+//! Scheduler of the periodic background jobs, and their status under
+//! `/admin/jobs`.
+//!
+//! Each job runs on its own task every `jobs.<name>_secs`: the aggregate
+//! [tiers](crate::tiers) catch up with new samples, [retention](crate::retention)
+//! policies are enforced, and the [quality](crate::quality) of the channels
+//! written to since the previous scan is measured and their sessions are
+//! checked for [gaps](crate::gaps). A run that takes longer
+//! than the interval delays the next one rather than overlapping it.
+//! `POST /admin/jobs/{name}/run` starts a run at once, and the interval
+//! counts from there.
+
+use crate::error::ApiError;
+use crate::validation::Parsed;
+use axum::{extract::Path, http::StatusCode, Json};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::MissedTickBehavior;
+
+/// One kind of periodic work.
+#[axum::async_trait]
+pub trait Job: Send + 'static {
+    /// Runs once, returning a summary of what was done.
+    async fn run(&mut self) -> Result<String, String>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LastRun {
+    started_at: String,
+    duration_ms: u64,
+    summary: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Status {
+    name: &'static str,
+    interval_secs: u64,
+    running: bool,
+    runs: u64,
+    failures: u64,
+    /// When the next run is due; `None` while one is running.
+    next_run_at: Option<String>,
+    last_run: Option<LastRun>,
+}
+
+struct Scheduled {
+    status: Status,
+    /// Starts a run at once.
+    run_now: Arc<Notify>,
+}
+
+static JOBS: Mutex<BTreeMap<&'static str, Scheduled>> = Mutex::new(BTreeMap::new());
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn update(name: &str, change: impl FnOnce(&mut Status)) {
+    if let Some(scheduled) = JOBS.lock().unwrap().get_mut(name) {
+        change(&mut scheduled.status);
+    }
+}
+
+/// Runs `job` every `every`, starting now; a zero interval leaves it off.
+pub fn schedule(name: &'static str, every: Duration, mut job: impl Job) {
+    if every.is_zero() {
+        tracing::info!("job {} is off", name);
+        return;
+    }
+    let run_now = Arc::new(Notify::new());
+    let scheduled = Scheduled {
+        status: Status {
+            name,
+            interval_secs: every.as_secs(),
+            running: false,
+            runs: 0,
+            failures: 0,
+            next_run_at: Some(timestamp(Utc::now())),
+            last_run: None,
+        },
+        run_now: run_now.clone(),
+    };
+    JOBS.lock().unwrap().insert(name, scheduled);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = run_now.notified() => ticker.reset(),
+            }
+            let started_at = Utc::now();
+            let clock = Instant::now();
+            update(name, |status| {
+                status.running = true;
+                status.next_run_at = None;
+            });
+            let outcome = job.run().await;
+            if let Err(e) = &outcome {
+                tracing::error!("job {} failed: {}", name, e);
+            }
+            let duration_ms = clock.elapsed().as_millis() as u64;
+            update(name, |status| {
+                status.running = false;
+                status.runs += 1;
+                status.failures += u64::from(outcome.is_err());
+                status.next_run_at = Some(timestamp(started_at.max(Utc::now()) + every));
+                status.last_run = Some(LastRun {
+                    started_at: timestamp(started_at),
+                    duration_ms,
+                    summary: outcome.as_ref().ok().cloned(),
+                    error: outcome.err(),
+                });
+            });
+        }
+    });
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/jobs",
+    tag = "admin",
+    responses((status = 200, description = "`{ jobs: [{ name, interval_secs, running, runs, failures, next_run_at, last_run: { started_at, duration_ms, summary, error } }] }`", body = serde_json::Value))
+)]
+pub async fn list_jobs() -> Json<serde_json::Value> {
+    let jobs: Vec<Status> = JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|scheduled| scheduled.status.clone())
+        .collect();
+    Json(serde_json::json!({ "jobs": jobs }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/jobs/{name}/run",
+    tag = "admin",
+    params(("name" = String, Path, description = "`tiers`, `retention` or `quality`")),
+    responses(
+        (status = 202, description = "The run was started; `{ name, interval_secs, running, ... }` as before it", body = serde_json::Value),
+        (status = 404, description = "Unknown or disabled job"),
+        (status = 409, description = "The job is running")
+    )
+)]
+pub async fn run_job(
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let jobs = JOBS.lock().unwrap();
+    let Some(scheduled) = jobs.get(name.as_str()) else {
+        return Err(ApiError::not_found(format!("unknown job {:?}", name)));
+    };
+    if scheduled.status.running {
+        return Err(ApiError::conflict(format!("job {} is running", name)));
+    }
+    scheduled.run_now.notify_one();
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!(scheduled.status)),
+    ))
+}
@@ -0,0 +1,312 @@
+//! Optional Lab Streaming Layer recorder.
+//!
+//! liblsl is loaded at runtime so the backend builds and runs without it;
+//! the recorder only starts when `lsl.enabled` (`LSL_ENABLED`) is set. Every resolved stream
+//! of the configured type gets its own blocking reader thread that pulls
+//! chunks and hands them to the shared ingest path.
+
+use crate::config;
+use crate::ingest::{self, NewSample};
+use libloading::{Library, Symbol};
+use sqlx::PgPool;
+use std::collections::HashSet;
+use std::ffi::{c_char, c_double, c_float, c_int, c_ulong, c_void, CStr, CString};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Samples pulled per channel in one `lsl_pull_chunk_f` call.
+const CHUNK_FRAMES: usize = 512;
+
+/// Upper bound on streams picked up by one resolve pass.
+const MAX_STREAMS: usize = 32;
+
+const RESOLVE_TIMEOUT_SECS: f64 = 2.0;
+const PULL_TIMEOUT_SECS: f64 = 0.2;
+const RESOLVE_RETRY: Duration = Duration::from_secs(5);
+
+type StreamInfo = *mut c_void;
+type Inlet = *mut c_void;
+type XmlPtr = *mut c_void;
+
+#[derive(Debug, Clone)]
+pub struct LslConfig {
+    /// Value of the stream `type` property to record, usually "EEG".
+    pub stream_type: String,
+    /// Path or name passed to the dynamic loader.
+    pub library: String,
+}
+
+impl LslConfig {
+    /// The recorder of the `[lsl]` settings; `None` if disabled.
+    pub fn from_config(config: &config::LslConfig) -> Option<Self> {
+        config.enabled.then(|| Self {
+            stream_type: config.stream_type.clone(),
+            library: config.library.clone(),
+        })
+    }
+}
+
+/// The subset of the liblsl C API used by the recorder.
+struct Lsl {
+    _lib: Library,
+    resolve_byprop: unsafe extern "C" fn(
+        *mut StreamInfo,
+        u32,
+        *const c_char,
+        *const c_char,
+        c_int,
+        c_double,
+    ) -> c_int,
+    create_inlet: unsafe extern "C" fn(StreamInfo, i32, i32, c_int) -> Inlet,
+    open_stream: unsafe extern "C" fn(Inlet, c_double, *mut i32),
+    get_fullinfo: unsafe extern "C" fn(Inlet, c_double, *mut i32) -> StreamInfo,
+    get_name: unsafe extern "C" fn(StreamInfo) -> *const c_char,
+    get_channel_count: unsafe extern "C" fn(StreamInfo) -> c_int,
+    get_desc: unsafe extern "C" fn(StreamInfo) -> XmlPtr,
+    child: unsafe extern "C" fn(XmlPtr, *const c_char) -> XmlPtr,
+    first_child: unsafe extern "C" fn(XmlPtr) -> XmlPtr,
+    next_sibling: unsafe extern "C" fn(XmlPtr) -> XmlPtr,
+    empty: unsafe extern "C" fn(XmlPtr) -> c_int,
+    child_value_n: unsafe extern "C" fn(XmlPtr, *const c_char) -> *const c_char,
+    pull_chunk_f: unsafe extern "C" fn(
+        Inlet,
+        *mut c_float,
+        *mut c_double,
+        c_ulong,
+        c_ulong,
+        c_double,
+        *mut i32,
+    ) -> c_ulong,
+    time_correction: unsafe extern "C" fn(Inlet, c_double, *mut i32) -> c_double,
+    local_clock: unsafe extern "C" fn() -> c_double,
+    destroy_inlet: unsafe extern "C" fn(Inlet),
+    destroy_streaminfo: unsafe extern "C" fn(StreamInfo),
+}
+
+// liblsl is thread-safe; the handle only holds function pointers.
+unsafe impl Send for Lsl {}
+unsafe impl Sync for Lsl {}
+
+impl Lsl {
+    fn load(path: &str) -> Result<Self, libloading::Error> {
+        // SAFETY: symbol signatures mirror lsl_c.h from liblsl 1.16.
+        unsafe {
+            let lib = Library::new(path)?;
+            macro_rules! sym {
+                ($name:literal) => {{
+                    let s: Symbol<_> = lib.get($name)?;
+                    *s
+                }};
+            }
+            Ok(Self {
+                resolve_byprop: sym!(b"lsl_resolve_byprop\0"),
+                create_inlet: sym!(b"lsl_create_inlet\0"),
+                open_stream: sym!(b"lsl_open_stream\0"),
+                get_fullinfo: sym!(b"lsl_get_fullinfo\0"),
+                get_name: sym!(b"lsl_get_name\0"),
+                get_channel_count: sym!(b"lsl_get_channel_count\0"),
+                get_desc: sym!(b"lsl_get_desc\0"),
+                child: sym!(b"lsl_child\0"),
+                first_child: sym!(b"lsl_first_child\0"),
+                next_sibling: sym!(b"lsl_next_sibling\0"),
+                empty: sym!(b"lsl_empty\0"),
+                child_value_n: sym!(b"lsl_child_value_n\0"),
+                pull_chunk_f: sym!(b"lsl_pull_chunk_f\0"),
+                time_correction: sym!(b"lsl_time_correction\0"),
+                local_clock: sym!(b"lsl_local_clock\0"),
+                destroy_inlet: sym!(b"lsl_destroy_inlet\0"),
+                destroy_streaminfo: sym!(b"lsl_destroy_streaminfo\0"),
+                _lib: lib,
+            })
+        }
+    }
+}
+
+fn cstr_to_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    // SAFETY: liblsl returns NUL-terminated strings owned by the stream info.
+    unsafe { CStr::from_ptr(ptr) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Channel labels from `<desc><channels><channel><label>`, falling back to
+/// `<stream name>-<index>` when the stream does not describe its channels.
+fn channel_labels(lsl: &Lsl, info: StreamInfo, stream_name: &str, count: usize) -> Vec<String> {
+    let mut labels = Vec::with_capacity(count);
+    let channels_tag = CString::new("channels").unwrap();
+    let label_tag = CString::new("label").unwrap();
+    // SAFETY: every pointer comes from liblsl and stays valid while `info` lives.
+    unsafe {
+        let channels = (lsl.child)((lsl.get_desc)(info), channels_tag.as_ptr());
+        let mut ch = (lsl.first_child)(channels);
+        while labels.len() < count && (lsl.empty)(ch) == 0 {
+            labels.push(cstr_to_string((lsl.child_value_n)(ch, label_tag.as_ptr())));
+            ch = (lsl.next_sibling)(ch);
+        }
+    }
+    for (i, label) in labels.iter_mut().enumerate() {
+        if label.trim().is_empty() {
+            *label = format!("{}-{}", stream_name, i);
+        }
+    }
+    while labels.len() < count {
+        labels.push(format!("{}-{}", stream_name, labels.len()));
+    }
+    labels
+}
+
+/// Starts the recorder if it is enabled and liblsl can be loaded.
+pub fn spawn(config: LslConfig, pool: PgPool) {
+    let lsl = match Lsl::load(&config.library) {
+        Ok(lsl) => Arc::new(lsl),
+        Err(e) => {
+            tracing::error!(
+                "LSL recorder disabled: cannot load {}: {}",
+                config.library,
+                e
+            );
+            return;
+        }
+    };
+
+    let tx = ingest::spawn_writer(pool, "LSL recorder");
+    std::thread::spawn(move || resolve_loop(lsl, config, tx));
+}
+
+/// Periodically resolves streams and starts a reader for each new one.
+fn resolve_loop(lsl: Arc<Lsl>, config: LslConfig, tx: mpsc::Sender<Vec<NewSample>>) {
+    let prop = CString::new("type").unwrap();
+    let value = match CString::new(config.stream_type.clone()) {
+        Ok(v) => v,
+        Err(_) => {
+            tracing::error!("LSL recorder disabled: stream type contains a NUL byte");
+            return;
+        }
+    };
+    let recording: Arc<Mutex<HashSet<String>>> = Arc::default();
+
+    while !tx.is_closed() {
+        let mut found: [StreamInfo; MAX_STREAMS] = [std::ptr::null_mut(); MAX_STREAMS];
+        // SAFETY: `found` has room for MAX_STREAMS handles.
+        let n = unsafe {
+            (lsl.resolve_byprop)(
+                found.as_mut_ptr(),
+                MAX_STREAMS as u32,
+                prop.as_ptr(),
+                value.as_ptr(),
+                1,
+                RESOLVE_TIMEOUT_SECS,
+            )
+        };
+
+        for &info in found.iter().take(n.max(0) as usize) {
+            let name = cstr_to_string(unsafe { (lsl.get_name)(info) });
+            if !recording.lock().unwrap().insert(name.clone()) {
+                unsafe { (lsl.destroy_streaminfo)(info) };
+                continue;
+            }
+            tracing::info!("LSL recorder: recording stream {}", name);
+            let lsl = lsl.clone();
+            let tx = tx.clone();
+            let recording = recording.clone();
+            let info = info as usize;
+            std::thread::spawn(move || {
+                read_stream(lsl, info as StreamInfo, &name, tx);
+                recording.lock().unwrap().remove(&name);
+            });
+        }
+
+        std::thread::sleep(RESOLVE_RETRY);
+    }
+}
+
+/// Pulls chunks from one stream until the inlet fails or ingest shuts down.
+fn read_stream(lsl: Arc<Lsl>, info: StreamInfo, name: &str, tx: mpsc::Sender<Vec<NewSample>>) {
+    let mut ec: i32 = 0;
+    // SAFETY: `info` is a resolved stream handle owned by this thread.
+    let inlet = unsafe { (lsl.create_inlet)(info, 360, 0, 1) };
+    unsafe { (lsl.destroy_streaminfo)(info) };
+    if inlet.is_null() {
+        tracing::error!("LSL recorder: cannot open inlet for {}", name);
+        return;
+    }
+
+    let full = unsafe { (lsl.get_fullinfo)(inlet, RESOLVE_TIMEOUT_SECS, &mut ec) };
+    let count = if full.is_null() {
+        0
+    } else {
+        unsafe { (lsl.get_channel_count)(full) }.max(0) as usize
+    };
+    if ec != 0 || count == 0 {
+        tracing::error!("LSL recorder: no channel info for {} (error {})", name, ec);
+        if !full.is_null() {
+            unsafe { (lsl.destroy_streaminfo)(full) };
+        }
+        unsafe { (lsl.destroy_inlet)(inlet) };
+        return;
+    }
+    let labels = channel_labels(&lsl, full, name, count);
+    unsafe { (lsl.destroy_streaminfo)(full) };
+    unsafe { (lsl.open_stream)(inlet, RESOLVE_TIMEOUT_SECS, &mut ec) };
+
+    let mut data = vec![0f32; CHUNK_FRAMES * count];
+    let mut stamps = vec![0f64; CHUNK_FRAMES];
+
+    loop {
+        ec = 0;
+        // SAFETY: both buffers are sized for CHUNK_FRAMES frames of `count` channels.
+        let pulled = unsafe {
+            (lsl.pull_chunk_f)(
+                inlet,
+                data.as_mut_ptr(),
+                stamps.as_mut_ptr(),
+                data.len() as c_ulong,
+                stamps.len() as c_ulong,
+                PULL_TIMEOUT_SECS,
+                &mut ec,
+            )
+        } as usize;
+        if ec != 0 {
+            tracing::warn!("LSL recorder: stream {} ended (error {})", name, ec);
+            break;
+        }
+        if pulled == 0 {
+            continue;
+        }
+
+        // Map LSL clock stamps onto wall-clock time via the local clock offset.
+        let correction = unsafe { (lsl.time_correction)(inlet, PULL_TIMEOUT_SECS, &mut ec) };
+        let correction = if ec == 0 { correction } else { 0.0 };
+        let wall_offset = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0)
+            - unsafe { (lsl.local_clock)() };
+
+        let frames = pulled / count;
+        let mut samples = Vec::with_capacity(pulled);
+        for (frame, &stamp) in data.chunks_exact(count).take(frames).zip(&stamps) {
+            let us = ((stamp + correction + wall_offset) * 1_000_000.0).round() as i64;
+            let Some(ts) = ingest::ts_from_micros(us) else {
+                continue;
+            };
+            for (label, &value) in labels.iter().zip(frame) {
+                samples.push(NewSample {
+                    channel: label.clone(),
+                    ts,
+                    value: value as f64,
+                    session_id: None,
+                });
+            }
+        }
+        if tx.blocking_send(samples).is_err() {
+            break;
+        }
+    }
+
+    unsafe { (lsl.destroy_inlet)(inlet) };
+}
@@ -1,131 +1,349 @@
-use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    routing::get,
-    Json, Router,
-};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+mod aggregate;
+mod alerts;
+mod analysis;
+mod api;
+mod audit;
+mod auth;
+mod cache;
+mod calibration;
+mod channels;
+mod cli;
+mod config;
+mod cors;
+mod db;
+mod devices;
+mod dsp;
+mod encoding;
+mod epoch_sets;
+mod error;
+mod events;
+mod export;
+mod feedback;
+mod gaps;
+mod graphql;
+mod grpc;
+mod health;
+mod impedances;
+mod import;
+mod ingest;
+mod jobs;
+mod lsl;
+mod metadata;
+mod metrics;
+mod migrations;
+mod montages;
+mod mqtt;
+mod notify;
+mod openapi;
+mod pipeline;
+mod portable;
+mod quality;
+mod rate_limit;
+mod replay;
+mod retention;
+mod ring;
+mod roles;
+mod sessions;
+mod shutdown;
+mod store;
+mod subjects;
+mod telemetry;
+mod tiers;
+mod timezone;
+mod triggers;
+mod udp;
+mod validation;
+mod versioning;
+mod webhooks;
+
+use axum::Router;
+use eeg_dsp::downsample;
 use sqlx::PgPool;
 use std::net::SocketAddr;
-use tracing_subscriber;
+use std::sync::Arc;
+use tower_http::compression::predicate::{
+    DefaultPredicate, NotForContentType, Predicate, SizeAbove,
+};
+use tower_http::compression::CompressionLayer;
+
+// The sample types and queries the route modules share, as `crate::...`.
+use api::{ChannelQuery, SampleFilter};
+use store::postgres::{fetch_live_points, fetch_window_samples};
+use store::{EegSample, LivePoint};
 
 #[derive(Clone)]
 struct AppState {
     pool: PgPool,
+    /// Read replica, if one is configured; see [`AppState::reader`].
+    replica: Option<db::Replica>,
+    /// Ingest, sample pages and live polls, over `pool` and `replica`.
+    store: Arc<dyn store::SampleStore>,
+    schema: graphql::EegSchema,
+    /// Whether TimescaleDB is installed, so aggregations can use `time_bucket`.
+    timescale: bool,
+    /// Outcome of the latest retention pass per policy target.
+    retention: retention::RetentionStatus,
+    /// CSV import jobs.
+    imports: import::csv::Jobs,
+    /// ICA decomposition jobs.
+    ica: analysis::ica::Jobs,
+    /// Current alert states.
+    alerts: alerts::AlertStates,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
-struct EegSample {
-    id: i32,
-    ts: String,
-    channel: String,
-    value: f64,
+impl AppState {
+    /// Pool for read-only handlers: the replica while it answers, else the
+    /// primary.
+    fn reader(&self) -> &PgPool {
+        db::reader(&self.pool, self.replica.as_ref())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LivePoint {
-    id: i32,
-    ts: String,
-    value: f64,
-}
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = cli::parse();
+    telemetry::init();
+    let config = config::init(cli.config.as_deref())?;
 
-#[derive(Debug, Deserialize)]
-struct LiveQuery {
-    channel: Option<String>,
-    since_id: Option<i32>,
-    limit: Option<i32>,
+    if config.database.is_sqlite() {
+        return match cli.command() {
+            cli::Command::Serve(args) => portable::serve(config, args).await,
+            _ => Err("this command needs Postgres; only serve runs on SQLite".into()),
+        };
+    }
+    let pool = db::connect(&config.database).await?;
+    cache::init(&config.cache)?;
+    match cli.command() {
+        cli::Command::Serve(args) => serve(config, pool, args).await,
+        command => {
+            let result = cli::run(command, &pool).await;
+            cache::flush().await;
+            result.map_err(Into::into)
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    tracing_subscriber::fmt::init();
+/// Runs the REST, WebSocket and gRPC servers and the background tasks.
+async fn serve(
+    config: &'static config::Config,
+    pool: PgPool,
+    args: cli::ServeArgs,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if config.database.run_migrations {
+        for version in migrations::run(&pool).await? {
+            tracing::info!("applied migration {}", migrations::describe(version));
+        }
+    }
 
-    let database_url = std::env::var("DATABASE_URL")
-        .unwrap_or_else(|_| "postgres://eeg_user:secret@db:5432/eeg".to_string());
-    let pool = PgPool::connect(&database_url).await?;
+    if auth::init(&config.auth).await? {
+        tracing::info!("JWT authentication enabled");
+    } else {
+        tracing::warn!("no JWT key configured; data routes are open");
+    }
 
-    let state = AppState { pool };
+    match channels::load(&pool).await {
+        Ok(count) => tracing::info!("{} channels registered", count),
+        Err(e) => tracing::error!("failed to load channel registry: {}", e),
+    }
+    channels::spawn_reload(pool.clone());
+    match devices::registry::load(&pool).await {
+        Ok(count) => tracing::info!("{} devices registered", count),
+        Err(e) => tracing::error!("failed to load device registry: {}", e),
+    }
+    devices::registry::spawn_reload(pool.clone());
+    match devices::keys::load(&pool).await {
+        Ok(count) => tracing::info!("{} device API keys active", count),
+        Err(e) => tracing::error!("failed to load device API keys: {}", e),
+    }
+    devices::keys::spawn_reload(pool.clone());
+    match roles::load(&pool).await {
+        Ok(count) => tracing::info!("roles assigned to {} users", count),
+        Err(e) => tracing::error!("failed to load role assignments: {}", e),
+    }
+    roles::spawn_reload(pool.clone());
+    audit::spawn(pool.clone());
+    rate_limit::spawn_cleanup();
 
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/health", get(health))
-        .route("/dbtest", get(dbtest))
-        .route("/samples", get(get_samples))
-        .route("/live", get(get_live))
-        .with_state(state);
+    if let Some(lsl) = lsl::LslConfig::from_config(&config.lsl) {
+        lsl::spawn(lsl, pool.clone());
+    }
+    if let Some(mqtt) = mqtt::MqttConfig::from_config(&config.mqtt) {
+        mqtt::spawn(mqtt, pool.clone());
+    }
+    if let Some(udp) = udp::UdpConfig::from_config(&config.udp) {
+        udp::spawn(udp, pool.clone()).await?;
+    }
+    match devices::from_config(&config.device, args.simulate) {
+        Ok(Some(source)) => {
+            let serial = config.device.serial.clone();
+            if let Err(e) = devices::spawn(source, serial, pool.clone()).await {
+                tracing::error!("device driver disabled: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("device driver disabled: {}", e),
+    }
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
-    tracing::info!("listening on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
-    Ok(())
-}
+    shutdown::listen();
 
-async fn root() -> &'static str {
-    "Rust EEG Backend"
-}
+    let grpc_addr = SocketAddr::new(config.server.bind.ip(), config.server.grpc_port);
+    let grpc_pool = pool.clone();
+    let grpc = tokio::spawn(async move {
+        if let Err(e) = grpc::serve(grpc_addr, grpc_pool).await {
+            tracing::error!("gRPC server stopped: {}", e);
+        }
+    });
 
-async fn health() -> &'static str {
-    "OK"
-}
+    let timescale = aggregate::detect_timescale(&pool).await;
+    tracing::info!(
+        "TimescaleDB {}",
+        if timescale {
+            "detected"
+        } else {
+            "not installed"
+        }
+    );
 
-async fn dbtest(State(state): State<AppState>) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    let row: (i32,) = sqlx::query_as("SELECT 1")
-        .fetch_one(&state.pool)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-    Ok(Json(json!({"ok": true, "value": row.0})))
-}
+    match notify::detect(&pool).await {
+        Ok(true) => tracing::info!("sample notifications sent by the database trigger"),
+        Ok(false) => tracing::info!("sample notifications sent by the backend after inserts"),
+        Err(e) => tracing::warn!("cannot detect the sample notification trigger: {}", e),
+    }
+    notify::spawn(pool.clone());
 
-async fn get_samples(
-    State(state): State<AppState>,
-    Query(params): Query<LiveQuery>,
-) -> Result<Json<Vec<EegSample>>, (StatusCode, String)> {
-    let channel = params.channel.unwrap_or_else(|| "A3".to_string());
-    let limit = params.limit.unwrap_or(100).min(1000);
+    let retention = retention::RetentionStatus::default();
+    jobs::schedule(
+        "tiers",
+        config.jobs.tiers_interval(),
+        tiers::Refresh::new(pool.clone(), timescale),
+    );
+    jobs::schedule(
+        "retention",
+        config.jobs.retention_interval(),
+        retention::Enforce {
+            pool: pool.clone(),
+            timescale,
+            status: retention.clone(),
+        },
+    );
+    export::queue::spawn(pool.clone());
+    jobs::schedule(
+        "exports",
+        config.jobs.exports_interval(),
+        export::queue::Expire { pool: pool.clone() },
+    );
+    jobs::schedule(
+        "quality",
+        config.jobs.quality_interval(),
+        quality::Scan::new(pool.clone(), config.jobs.quality_interval()),
+    );
+    jobs::schedule(
+        "gaps",
+        config.jobs.gaps_interval(),
+        gaps::Scan::new(pool.clone(), config.jobs.gaps_interval()),
+    );
+    jobs::schedule(
+        "blocks",
+        config.jobs.blocks_interval(),
+        store::blocks::Pack { pool: pool.clone() },
+    );
+    dsp::pipeline::spawn(pool.clone());
+    let alerts = alerts::AlertStates::default();
+    alerts::spawn(pool.clone(), alerts.clone());
 
-    let samples: Vec<EegSample> = sqlx::query_as(
-        "SELECT id, ts, channel, value FROM eeg_samples WHERE channel = $1 ORDER BY id DESC LIMIT $2",
-    )
-    .bind(&channel)
-    .bind(limit)
-    .fetch_all(&state.pool)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let schema = graphql::schema(pool.clone());
+    let replica = db::replica(&config.database)?;
+    let state = AppState {
+        pool: pool.clone(),
+        store: Arc::new(store::PgStore::new(pool.clone(), replica.clone())),
+        replica,
+        schema: schema.clone(),
+        timescale,
+        retention,
+        imports: import::csv::Jobs::default(),
+        ica: analysis::ica::Jobs::default(),
+        alerts,
+    };
 
-    Ok(Json(samples))
-}
+    let public = api::public();
 
-async fn get_live(
-    State(state): State<AppState>,
-    Query(params): Query<LiveQuery>,
-) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    let channel = params.channel.unwrap_or_else(|| "A3".to_string());
-    let since_id = params.since_id.unwrap_or(0);
-    let limit = params.limit.unwrap_or(200).min(1000);
+    let api = api::routes(schema);
+    // The unversioned paths are deprecated aliases of the current version.
+    let legacy = if config.server.legacy_routes {
+        api.clone()
+            .route_layer(axum::middleware::from_fn(versioning::deprecated))
+    } else {
+        Router::new()
+    };
 
-    let points: Vec<(i32, String, f64)> = sqlx::query_as(
-        "SELECT id, ts, value FROM eeg_samples WHERE channel = $1 AND id > $2 ORDER BY id ASC LIMIT $3",
+    let app = Router::new()
+        .nest(&format!("/{}", versioning::CURRENT), api)
+        .merge(legacy)
+        .route_layer(axum::middleware::from_fn(rate_limit::limit))
+        .route_layer(axum::middleware::from_fn(auth::require))
+        .merge(public)
+        .fallback(versioning::fallback)
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(telemetry::trace))
+        .with_state(state);
+    // Parquet files are compressed already; SSE, gRPC and images are left
+    // alone by the default predicate.
+    let app = if config.server.compression {
+        app.layer(
+            CompressionLayer::new().compress_when(
+                DefaultPredicate::new()
+                    .and(SizeAbove::new(config.server.compression_min_bytes))
+                    .and(NotForContentType::const_new(
+                        "application/vnd.apache.parquet",
+                    )),
+            ),
+        )
+    } else {
+        app
+    };
+    let app = match cors::layer(&config.cors) {
+        Some(cors) => {
+            tracing::info!(
+                "CORS enabled for {}",
+                config.cors.allowed_origins.join(", ")
+            );
+            app.layer(cors)
+        }
+        None => app,
+    };
+
+    let addr = config.server.bind;
+    tracing::info!("listening on {}", addr);
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .bind(&channel)
-    .bind(since_id)
-    .bind(limit)
-    .fetch_all(&state.pool)
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-    let last_id = points.last().map(|(id, _, _)| *id).unwrap_or(since_id);
-    let response_points: Vec<LivePoint> = points
-        .into_iter()
-        .map(|(id, ts, value)| LivePoint { id, ts, value })
-        .collect();
-
-    Ok(Json(json!({
-        "points": response_points,
-        "last_id": last_id,
-        "channel": channel,
-    })))
+    .with_graceful_shutdown(shutdown::requested());
+    // WebSocket sessions run outside the server once upgraded, so they are
+    // waited for separately.
+    let drained = async {
+        let served = server.await;
+        let _ = grpc.await;
+        metrics::live_clients_closed().await;
+        served
+    };
+    let timeout = config.server.shutdown_timeout();
+    tokio::select! {
+        served = drained => served?,
+        _ = shutdown::expired(timeout) => {
+            tracing::warn!("connections still open after {:?}, closing them", timeout)
+        }
+    }
+    let flushed = tokio::time::timeout(timeout, pipeline::drain()).await;
+    if flushed.is_err() {
+        tracing::warn!("ingest writers still flushing after {:?}", timeout);
+    }
+    if tokio::time::timeout(timeout, audit::close()).await.is_err() {
+        tracing::warn!("audit log still flushing after {:?}", timeout);
+    }
+    pool.close().await;
+    tracing::info!("shutdown complete");
+    Ok(())
 }
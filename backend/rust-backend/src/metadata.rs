@@ -0,0 +1,393 @@
+//! Sample rate, unit and scale of channels, which clients need to place
+//! samples in time and read them as physical values.
+//!
+//! The [channel registry](crate::channels) holds them per channel: stored
+//! values times `scale` are in `unit`, sampled at `sample_rate` Hz. A session
+//! may override any of the three per channel (`session_channels` table), as
+//! for a recording made with another amplifier gain; a channel with no rate
+//! in either takes that of the session's device. `/samples` and `/live`
+//! return the result as `metadata` next to the samples.
+//!
+//! Values are returned as stored unless the request asks for a [`Unit`]:
+//! with `unit=uV` or `unit=mV` they are multiplied by the channel's scale
+//! and converted from its unit, which must then be a voltage.
+
+use crate::channels::{self, validate_name};
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Metadata {
+    /// Hz; null when neither the channel, the session nor its device has one.
+    pub sample_rate: Option<f64>,
+    pub unit: String,
+    /// Stored values times `scale` are in `unit`.
+    pub scale: f64,
+}
+
+impl Default for Metadata {
+    /// That of a channel missing from the registry.
+    fn default() -> Self {
+        Metadata {
+            sample_rate: None,
+            unit: "uV".to_string(),
+            scale: 1.0,
+        }
+    }
+}
+
+/// Unit values are returned in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Unit {
+    #[serde(rename = "uV")]
+    Microvolts,
+    #[serde(rename = "mV")]
+    Millivolts,
+    /// As stored.
+    #[serde(rename = "raw")]
+    Raw,
+}
+
+/// The `unit` query parameter of the sample, live and export routes.
+#[derive(Debug, Clone, Copy, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UnitQuery {
+    /// `uV` or `mV` for values times the channel's scale, converted from
+    /// its unit; `raw` (default) for the stored values.
+    pub unit: Option<Unit>,
+}
+
+impl Metadata {
+    /// Factor from stored values to `unit`, after which `self` describes
+    /// the converted values.
+    pub fn convert(&mut self, channel: &str, unit: Unit) -> Result<f64, String> {
+        let (name, microvolts) = match unit {
+            Unit::Raw => return Ok(1.0),
+            Unit::Microvolts => ("uV", 1.0),
+            Unit::Millivolts => ("mV", 1e3),
+        };
+        let from = eeg_export::units::microvolts(&self.unit).ok_or_else(|| {
+            format!(
+                "channel {:?} is in {:?}, which does not convert to {}",
+                channel, self.unit, name
+            )
+        })?;
+        let factor = self.scale * from / microvolts;
+        self.unit = name.to_string();
+        self.scale = 1.0;
+        Ok(factor)
+    }
+}
+
+/// The values a session sets for a channel; null ones are the channel's.
+/// Also the body of `PUT /sessions/{id}/channels/{channel}`, which replaces
+/// them.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Override {
+    sample_rate: Option<f64>,
+    unit: Option<String>,
+    scale: Option<f64>,
+}
+
+/// One entry of `GET /sessions/{id}/channels`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SessionChannel {
+    pub channel: String,
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    pub overrides: Override,
+}
+
+type Row = (
+    String,
+    Option<f64>,
+    String,
+    f64,
+    Option<f64>,
+    Option<String>,
+    Option<f64>,
+);
+
+impl From<Row> for SessionChannel {
+    fn from(row: Row) -> Self {
+        SessionChannel {
+            channel: row.0,
+            metadata: Metadata {
+                sample_rate: row.1,
+                unit: row.2,
+                scale: row.3,
+            },
+            overrides: Override {
+                sample_rate: row.4,
+                unit: row.5,
+                scale: row.6,
+            },
+        }
+    }
+}
+
+impl Validate for Override {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(rate) = self.sample_rate {
+            errors.check(
+                rate.is_finite() && rate > 0.0,
+                "sample_rate",
+                "must be a positive number",
+            );
+        }
+        if let Some(unit) = &self.unit {
+            errors.check(!unit.trim().is_empty(), "unit", "must not be empty");
+        }
+        if let Some(scale) = self.scale {
+            errors.check(
+                scale.is_finite() && scale != 0.0,
+                "scale",
+                "must be a finite non-zero number",
+            );
+        }
+    }
+}
+
+/// Metadata of `channels` within session `session_id`, or as registered if
+/// it is `None`. Channels missing from the registry are left out; callers
+/// fall back to [`Metadata::default`].
+pub async fn resolve(
+    pool: &PgPool,
+    channels: &[String],
+    session_id: Option<i32>,
+) -> Result<HashMap<String, Metadata>, sqlx::Error> {
+    let rows: Vec<(String, Option<f64>, String, f64)> = sqlx::query_as(
+        "SELECT c.name, COALESCE(o.sample_rate, c.sample_rate, d.sample_rate), \
+         COALESCE(o.unit, c.unit), COALESCE(o.scale, c.scale) \
+         FROM channels c \
+         LEFT JOIN session_channels o ON o.channel = c.name AND o.session_id = $2 \
+         LEFT JOIN sessions s ON s.id = $2 \
+         LEFT JOIN devices d ON d.serial = s.device \
+         WHERE c.name = ANY($1)",
+    )
+    .bind(channels)
+    .bind(session_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(name, sample_rate, unit, scale)| {
+            let metadata = Metadata {
+                sample_rate,
+                unit,
+                scale,
+            };
+            (name, metadata)
+        })
+        .collect())
+}
+
+/// [`resolve`] in the order of `channels`, with [`Metadata::default`] for
+/// those missing from the registry.
+pub async fn of(
+    pool: &PgPool,
+    channels: &[String],
+    session_id: Option<i32>,
+) -> Result<Vec<Metadata>, sqlx::Error> {
+    let mut resolved = resolve(pool, channels, session_id).await?;
+    Ok(channels
+        .iter()
+        .map(|channel| resolved.remove(channel).unwrap_or_default())
+        .collect())
+}
+
+/// Factors from stored values of `channels` in session `session_id` to
+/// `unit`, by channel; empty for stored values.
+pub async fn factors(
+    pool: &PgPool,
+    channels: &[String],
+    session_id: Option<i32>,
+    unit: Option<Unit>,
+) -> Result<HashMap<String, f64>, ApiError> {
+    let Some(unit) = unit.filter(|unit| *unit != Unit::Raw) else {
+        return Ok(HashMap::new());
+    };
+    let metadata = of(pool, channels, session_id).await?;
+    channels
+        .iter()
+        .zip(metadata)
+        .map(|(channel, mut metadata)| {
+            let factor = metadata
+                .convert(channel, unit)
+                .map_err(ApiError::bad_request)?;
+            Ok((channel.clone(), factor))
+        })
+        .collect()
+}
+
+/// Checks that every channel of session `id` converts to `unit`, as
+/// [`crate::export::SessionExport::convert`] will.
+pub async fn check_session(pool: &PgPool, id: i32, unit: Option<Unit>) -> Result<(), ApiError> {
+    let Some(unit) = unit.filter(|unit| *unit != Unit::Raw) else {
+        return Ok(());
+    };
+    for mut channel in session_channels(pool, id, None).await? {
+        channel
+            .metadata
+            .convert(&channel.channel, unit)
+            .map_err(ApiError::bad_request)?;
+    }
+    Ok(())
+}
+
+/// Metadata of the channels of session `id` with samples or overrides, or
+/// of just `channel`.
+async fn session_channels(
+    pool: &PgPool,
+    id: i32,
+    channel: Option<&str>,
+) -> Result<Vec<SessionChannel>, sqlx::Error> {
+    let rows: Vec<Row> = sqlx::query_as(
+        "SELECT c.name, COALESCE(o.sample_rate, c.sample_rate, d.sample_rate), \
+         COALESCE(o.unit, c.unit), COALESCE(o.scale, c.scale), \
+         o.sample_rate, o.unit, o.scale \
+         FROM channels c \
+         JOIN sessions s ON s.id = $1 \
+         LEFT JOIN session_channels o ON o.channel = c.name AND o.session_id = s.id \
+         LEFT JOIN devices d ON d.serial = s.device \
+         WHERE ($2::text IS NULL OR c.name = $2) \
+         AND (o.channel IS NOT NULL \
+              OR c.name IN (SELECT channel FROM eeg_samples WHERE session_id = $1 \
+                            UNION SELECT channel FROM sample_blocks WHERE session_id = $1)) \
+         ORDER BY c.hardware_index NULLS LAST, c.name",
+    )
+    .bind(id)
+    .bind(channel)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(SessionChannel::from).collect())
+}
+
+async fn session_exists(pool: &PgPool, id: i32) -> Result<bool, sqlx::Error> {
+    let (exists,): (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM sessions WHERE id = $1)")
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    Ok(exists)
+}
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::not_found(format!("unknown session {}", id))
+}
+
+/// Channels of session `id` with their sample rate, unit and scale.
+#[utoipa::path(
+    get,
+    path = "/sessions/{id}/channels",
+    tag = "sessions",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, description = "Channels with samples or overrides, in hardware order", body = Vec<SessionChannel>),
+        (status = 404, description = "Unknown session")
+    )
+)]
+pub async fn list_session_channels(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<Json<Vec<SessionChannel>>, ApiError> {
+    if !session_exists(&state.pool, id).await? {
+        return Err(not_found(id));
+    }
+    Ok(Json(session_channels(&state.pool, id, None).await?))
+}
+
+/// Sets the sample rate, unit and scale of `channel` in session `id`.
+#[utoipa::path(
+    put,
+    path = "/sessions/{id}/channels/{channel}",
+    tag = "sessions",
+    params(("id" = i32, Path), ("channel" = String, Path)),
+    request_body = Override,
+    responses(
+        (status = 200, description = "The channel's metadata in the session", body = SessionChannel),
+        (status = 400, description = "Invalid channel name"),
+        (status = 404, description = "Unknown session or channel"),
+        (status = 422, description = "Invalid values, named in `details.fields`")
+    )
+)]
+pub async fn put_session_channel(
+    State(state): State<AppState>,
+    Parsed(Path((id, channel))): Parsed<Path<(i32, String)>>,
+    Valid(Json(input)): Valid<Json<Override>>,
+) -> Result<Json<SessionChannel>, ApiError> {
+    validate_name(&channel).map_err(|e| ApiError::bad_request(format!("channel {}", e)))?;
+    if !session_exists(&state.pool, id).await? {
+        return Err(not_found(id));
+    }
+    if !channels::is_known(&channel) {
+        return Err(ApiError::not_found(format!("unknown channel {}", channel)));
+    }
+
+    sqlx::query(
+        "INSERT INTO session_channels (session_id, channel, sample_rate, unit, scale) \
+         VALUES ($1, $2, $3, $4, $5) \
+         ON CONFLICT (session_id, channel) DO UPDATE \
+         SET sample_rate = $3, unit = $4, scale = $5",
+    )
+    .bind(id)
+    .bind(&channel)
+    .bind(input.sample_rate)
+    .bind(input.unit.as_deref().map(str::trim))
+    .bind(input.scale)
+    .execute(&state.pool)
+    .await
+    .map_err(|e| match &e {
+        // 23503: the session or channel was deleted in the meantime.
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            ApiError::not_found(format!("unknown session {} or channel {}", id, channel))
+        }
+        _ => e.into(),
+    })?;
+    let mut updated = session_channels(&state.pool, id, Some(&channel)).await?;
+    updated
+        .pop()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("unknown channel {}", channel)))
+}
+
+/// Removes the overrides of `channel` in session `id`, back to the channel's
+/// registered values.
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}/channels/{channel}",
+    tag = "sessions",
+    params(("id" = i32, Path), ("channel" = String, Path)),
+    responses(
+        (status = 204, description = "Removed"),
+        (status = 404, description = "No overrides for the channel in the session")
+    )
+)]
+pub async fn delete_session_channel(
+    State(state): State<AppState>,
+    Parsed(Path((id, channel))): Parsed<Path<(i32, String)>>,
+) -> Result<StatusCode, ApiError> {
+    let deleted =
+        sqlx::query("DELETE FROM session_channels WHERE session_id = $1 AND channel = $2")
+            .bind(id)
+            .bind(&channel)
+            .execute(&state.pool)
+            .await?
+            .rows_affected();
+    if deleted == 0 {
+        return Err(ApiError::not_found(format!(
+            "no overrides for channel {} in session {}",
+            channel, id
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,347 @@
+//! Prometheus metrics (`GET /metrics`, text exposition format 0.0.4).
+//!
+//! Counters live in process memory and start at zero when the backend
+//! starts. HTTP requests are recorded by the [`track`] middleware under
+//! their route template (`/sessions/:id`), so label values stay bounded.
+
+use crate::{cache, pipeline, ring, AppState};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the request latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Route label of requests that matched no route.
+const UNMATCHED: &str = "unmatched";
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| value <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Requests per method, route and status.
+static REQUESTS: Mutex<BTreeMap<(String, String, u16), u64>> = Mutex::new(BTreeMap::new());
+/// Request latencies per method and route.
+static LATENCIES: Mutex<BTreeMap<(String, String), Histogram>> = Mutex::new(BTreeMap::new());
+/// Stored samples per channel.
+static INGESTED: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+/// Connected live clients per endpoint.
+static LIVE_CLIENTS: Mutex<BTreeMap<&'static str, i64>> = Mutex::new(BTreeMap::new());
+
+/// Middleware recording the count, status and latency of every request.
+pub async fn track(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED.to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = started.elapsed().as_secs_f64();
+    let status = response.status().as_u16();
+    *REQUESTS
+        .lock()
+        .unwrap()
+        .entry((method.clone(), path.clone(), status))
+        .or_default() += 1;
+    LATENCIES
+        .lock()
+        .unwrap()
+        .entry((method, path))
+        .or_default()
+        .observe(elapsed);
+    response
+}
+
+/// Counts stored samples by channel.
+pub fn record_ingested<'a>(channels: impl IntoIterator<Item = &'a str>) {
+    let mut counts: BTreeMap<&str, u64> = BTreeMap::new();
+    for channel in channels {
+        *counts.entry(channel).or_default() += 1;
+    }
+    let mut ingested = INGESTED.lock().unwrap();
+    for (channel, count) in counts {
+        match ingested.get_mut(channel) {
+            Some(total) => *total += count,
+            None => {
+                ingested.insert(channel.to_string(), count);
+            }
+        }
+    }
+}
+
+/// Counts a live client of `endpoint` until dropped.
+pub struct LiveClient(&'static str);
+
+impl LiveClient {
+    pub fn connect(endpoint: &'static str) -> Self {
+        *LIVE_CLIENTS.lock().unwrap().entry(endpoint).or_default() += 1;
+        Self(endpoint)
+    }
+}
+
+impl Drop for LiveClient {
+    fn drop(&mut self) {
+        *LIVE_CLIENTS.lock().unwrap().entry(self.0).or_default() -= 1;
+    }
+}
+
+/// Resolves once every live client has disconnected.
+pub async fn live_clients_closed() {
+    while LIVE_CLIENTS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|count| *count > 0)
+    {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Escapes a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render(state: &AppState) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "http_requests_total",
+        "counter",
+        "HTTP requests by method, route and status.",
+    );
+    for ((method, path, status), count) in REQUESTS.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "http_requests_total{{method=\"{}\",path=\"{}\",status=\"{}\"}} {}",
+            method,
+            label(path),
+            status,
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "http_request_duration_seconds",
+        "histogram",
+        "HTTP request latency by method and route.",
+    );
+    for ((method, path), histogram) in LATENCIES.lock().unwrap().iter() {
+        let labels = format!("method=\"{}\",path=\"{}\"", method, label(path));
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_sum{{{}}} {}",
+            labels, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_count{{{}}} {}",
+            labels, histogram.count
+        );
+    }
+
+    header(
+        &mut out,
+        "eeg_ingested_samples_total",
+        "counter",
+        "Samples stored through the ingest endpoints and background sources, by channel.",
+    );
+    for (channel, count) in INGESTED.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "eeg_ingested_samples_total{{channel=\"{}\"}} {}",
+            label(channel),
+            count
+        );
+    }
+
+    let writers = pipeline::flush_stats();
+    let mut writer_counter = |name: &str, help: &str, value: fn(&pipeline::FlushStats) -> u64| {
+        header(&mut out, name, "counter", help);
+        for (source, stats) in &writers {
+            let _ = writeln!(
+                out,
+                "{}{{source=\"{}\"}} {}",
+                name,
+                label(source),
+                value(stats)
+            );
+        }
+    };
+    writer_counter(
+        "eeg_ingest_flushes_total",
+        "COPY flushes of background sources.",
+        |s| s.flushes,
+    );
+    writer_counter(
+        "eeg_ingest_failed_flushes_total",
+        "Failed COPY flushes of background sources.",
+        |s| s.failed_flushes,
+    );
+    writer_counter(
+        "eeg_ingest_rejected_samples_total",
+        "Samples of background sources dropped by validation.",
+        |s| s.rejected_rows,
+    );
+
+    for (name, help, value) in [
+        (
+            "eeg_db_pool_connections",
+            "Open database connections.",
+            state.pool.size() as usize,
+        ),
+        (
+            "eeg_db_pool_idle_connections",
+            "Idle database connections.",
+            state.pool.num_idle(),
+        ),
+    ] {
+        header(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    if let Some(replica) = &state.replica {
+        for (name, help, value) in [
+            (
+                "eeg_db_replica_up",
+                "Whether the read replica answered its latest check.",
+                replica.is_up() as usize,
+            ),
+            (
+                "eeg_db_replica_pool_connections",
+                "Open read replica connections.",
+                replica.pool.size() as usize,
+            ),
+        ] {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+    }
+
+    if let Some((hits, misses, errors)) = cache::stats() {
+        for (name, help, value) in [
+            (
+                "eeg_cache_hits_total",
+                "Aggregate and PSD answers served from the cache.",
+                hits,
+            ),
+            (
+                "eeg_cache_misses_total",
+                "Aggregate and PSD answers computed and cached.",
+                misses,
+            ),
+            (
+                "eeg_cache_errors_total",
+                "Cache requests to Redis that failed.",
+                errors,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+    }
+
+    let (hits, misses, held) = ring::stats();
+    for (name, help, value) in [
+        (
+            "eeg_live_buffer_hits_total",
+            "Live polls answered from the in-memory window.",
+            hits,
+        ),
+        (
+            "eeg_live_buffer_misses_total",
+            "Live polls the in-memory window could not answer.",
+            misses,
+        ),
+    ] {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    header(
+        &mut out,
+        "eeg_live_buffer_samples",
+        "gauge",
+        "Samples held in the in-memory live windows.",
+    );
+    let _ = writeln!(out, "eeg_live_buffer_samples {}", held);
+
+    header(
+        &mut out,
+        "eeg_live_clients",
+        "gauge",
+        "Connected WebSocket and SSE clients by endpoint.",
+    );
+    for (endpoint, count) in LIVE_CLIENTS.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "eeg_live_clients{{endpoint=\"{}\"}} {}",
+            endpoint, count
+        );
+    }
+    out
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "health",
+    security(()),
+    responses((status = 200, description = "Prometheus text format", body = String, content_type = "text/plain"))
+)]
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        render(&state),
+    )
+}
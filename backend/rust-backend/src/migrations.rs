@@ -0,0 +1,71 @@
+//! Versioned schema migrations from `migrations/`, embedded in the binary.
+//!
+//! `serve` applies pending migrations before it starts (unless
+//! `database.run_migrations` is off) and `migrate` applies them on demand.
+//! sqlx records applied versions with their checksums in
+//! `_sqlx_migrations` and holds an advisory lock while migrating, so
+//! replicas starting together apply each migration once. Applied files must
+//! not be edited; schema changes go into a new, higher-numbered file.
+
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::PgPool;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Versions that are embedded and applied, embedded and not applied yet,
+/// or applied by a newer backend.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub applied: Vec<i64>,
+    pub pending: Vec<i64>,
+    pub unknown: Vec<i64>,
+    /// Versions whose last run failed and must be repaired by hand.
+    pub failed: Vec<i64>,
+}
+
+/// Compares the embedded migrations with those recorded in the database.
+pub async fn status(pool: &PgPool) -> Result<Status, sqlx::Error> {
+    let recorded: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(pool)
+        .await?;
+    let rows: Vec<(i64, bool)> = if recorded {
+        sqlx::query_as("SELECT version, success FROM _sqlx_migrations ORDER BY version")
+            .fetch_all(pool)
+            .await?
+    } else {
+        Vec::new()
+    };
+    let mut status = Status::default();
+    for migration in MIGRATOR.iter() {
+        match rows
+            .iter()
+            .find(|(version, _)| *version == migration.version)
+        {
+            Some((version, true)) => status.applied.push(*version),
+            Some((version, false)) => status.failed.push(*version),
+            None => status.pending.push(migration.version),
+        }
+    }
+    status.unknown = rows
+        .iter()
+        .map(|(version, _)| *version)
+        .filter(|version| MIGRATOR.iter().all(|m| m.version != *version))
+        .collect();
+    Ok(status)
+}
+
+/// Applies the pending migrations and returns their versions.
+pub async fn run(pool: &PgPool) -> Result<Vec<i64>, MigrateError> {
+    let pending = status(pool).await?.pending;
+    MIGRATOR.run(pool).await?;
+    Ok(pending)
+}
+
+/// Description of an embedded migration.
+pub fn describe(version: i64) -> String {
+    MIGRATOR
+        .iter()
+        .find(|m| m.version == version)
+        .map(|m| format!("{} {}", m.version, m.description))
+        .unwrap_or_else(|| version.to_string())
+}
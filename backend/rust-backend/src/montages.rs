@@ -0,0 +1,325 @@
+//! Stored montages (`montages` table): named channel layouts and
+//! derivations that `/samples`, `/samples/filter` and the live endpoints
+//! return with `montage=<name>`.
+//!
+//! A derivation is a channel against a reference written as for the
+//! `reference` parameter (see [`dsp::reference`](crate::dsp::reference)):
+//! `none`, one or more channels, or `average`, the mean of every channel the
+//! montage reads.
+
+use crate::channels::validate_name;
+use crate::dsp::reference::{parse_reference, Derivation};
+use crate::error::ApiError;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{config, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json as Jsonb;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+
+/// One signal of a montage, as stored and returned.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MontageDerivation {
+    /// Name the signal is returned under; by default `channel`, or
+    /// `channel-reference` against a single channel.
+    #[serde(default)]
+    pub name: String,
+    pub channel: String,
+    /// `none`, comma-separated channels or `average`; stored values when
+    /// absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Montage {
+    pub name: String,
+    pub description: Option<String>,
+    #[schema(value_type = Vec<MontageDerivation>)]
+    pub derivations: Jsonb<Vec<MontageDerivation>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `POST /montages` (with `name`) and `PUT /montages/{name}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MontageInput {
+    name: Option<String>,
+    description: Option<String>,
+    derivations: Option<Vec<MontageDerivation>>,
+}
+
+impl Montage {
+    /// Distinct channels the montage reads, in order of appearance.
+    fn channels(derivations: &[MontageDerivation]) -> Vec<String> {
+        let mut channels: Vec<String> = Vec::new();
+        for derivation in derivations {
+            if !channels.contains(&derivation.channel) {
+                channels.push(derivation.channel.clone());
+            }
+        }
+        channels
+    }
+
+    /// The derivations with their references resolved.
+    pub fn resolve(&self) -> Result<Vec<Derivation>, String> {
+        let channels = Self::channels(&self.derivations);
+        self.derivations
+            .iter()
+            .map(|d| {
+                let reference = d
+                    .reference
+                    .as_deref()
+                    .map(|text| parse_reference(text, &channels))
+                    .transpose()
+                    .map_err(|e| format!("derivation {:?}: {}", d.name, e))?;
+                Ok(Derivation {
+                    name: d.name.clone(),
+                    channel: d.channel.clone(),
+                    reference: reference.unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The name of a derivation of `channel` against `reference` that gives
+/// none: the channel, or `channel-reference` against a single channel.
+fn default_name(channel: &str, reference: &[String]) -> String {
+    match reference {
+        [cathode] => format!("{}-{}", channel, cathode),
+        _ => channel.to_string(),
+    }
+}
+
+/// Fills in the default names of checked derivations.
+fn fill_names(derivations: &mut [MontageDerivation]) {
+    let channels = Montage::channels(derivations);
+    for derivation in derivations.iter_mut().filter(|d| d.name.is_empty()) {
+        let reference = derivation
+            .reference
+            .as_deref()
+            .and_then(|text| parse_reference(text, &channels).ok())
+            .unwrap_or_default();
+        derivation.name = default_name(&derivation.channel, &reference);
+    }
+}
+
+/// Checks the derivations: `channel`, `reference` and `name` (defaults
+/// included) of each, and that no name appears twice.
+fn check_derivations(errors: &mut FieldErrors, derivations: &[MontageDerivation]) {
+    let max = config::get().limits.max_query_channels;
+    errors.check(
+        !derivations.is_empty() && derivations.len() <= max,
+        "derivations",
+        format!("must list 1 to {} derivations", max),
+    );
+    let channels = Montage::channels(derivations);
+    let mut names: Vec<String> = Vec::new();
+    for (i, derivation) in derivations.iter().enumerate() {
+        let field = |name: &str| format!("derivations[{}].{}", i, name);
+        if let Err(e) = validate_name(&derivation.channel) {
+            errors.add(field("channel"), e);
+        }
+        let reference = match derivation
+            .reference
+            .as_deref()
+            .map(|text| parse_reference(text, &channels))
+            .transpose()
+        {
+            Ok(reference) => reference.unwrap_or_default(),
+            Err(e) => {
+                errors.add(field("reference"), e);
+                continue;
+            }
+        };
+        for name in &reference {
+            if let Err(e) = validate_name(name) {
+                errors.add(field("reference"), format!("{:?}: {}", name, e));
+            }
+        }
+        let name = if derivation.name.is_empty() {
+            default_name(&derivation.channel, &reference)
+        } else {
+            derivation.name.clone()
+        };
+        if let Err(e) = validate_name(&name) {
+            errors.add(field("name"), e);
+        }
+        if names.contains(&name) {
+            errors.add(field("name"), format!("{:?} appears more than once", name));
+        }
+        names.push(name);
+    }
+}
+
+impl Validate for MontageInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(Err(e)) = self.name.as_deref().map(validate_name) {
+            errors.add("name", e);
+        }
+        if let Some(derivations) = &self.derivations {
+            check_derivations(errors, derivations);
+        }
+    }
+}
+
+const COLUMNS: &str = "name, description, derivations, created_at";
+
+fn not_found(name: &str) -> ApiError {
+    ApiError::not_found(format!("unknown montage {:?}", name))
+}
+
+async fn fetch(pool: &PgPool, name: &str) -> Result<Montage, ApiError> {
+    sqlx::query_as(&format!("SELECT {} FROM montages WHERE name = $1", COLUMNS))
+        .bind(name)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| not_found(name))
+}
+
+/// The resolved derivations of montage `name`; `404` if it does not exist.
+pub async fn derivations(pool: &PgPool, name: &str) -> Result<Vec<Derivation>, ApiError> {
+    fetch(pool, name)
+        .await?
+        .resolve()
+        .map_err(ApiError::internal)
+}
+
+#[utoipa::path(
+    get,
+    path = "/montages",
+    tag = "montages",
+    responses((status = 200, body = Vec<Montage>))
+)]
+pub async fn list_montages(State(state): State<AppState>) -> Result<Json<Vec<Montage>>, ApiError> {
+    let montages = sqlx::query_as(&format!("SELECT {} FROM montages ORDER BY name", COLUMNS))
+        .fetch_all(&state.pool)
+        .await?;
+    Ok(Json(montages))
+}
+
+#[utoipa::path(
+    get,
+    path = "/montages/{name}",
+    tag = "montages",
+    params(("name" = String, Path)),
+    responses(
+        (status = 200, body = Montage),
+        (status = 404, description = "Unknown montage")
+    )
+)]
+pub async fn get_montage(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<Json<Montage>, ApiError> {
+    fetch(&state.pool, &name).await.map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/montages",
+    tag = "montages",
+    request_body = MontageInput,
+    responses(
+        (status = 201, body = Montage),
+        (status = 409, description = "Name taken"),
+        (status = 422, description = "Missing or invalid name or derivations, named in `details.fields`")
+    )
+)]
+pub async fn create_montage(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<MontageInput>>,
+) -> Result<(StatusCode, Json<Montage>), ApiError> {
+    let mut errors = FieldErrors::default();
+    errors.check(input.name.is_some(), "name", "is required");
+    errors.check(input.derivations.is_some(), "derivations", "is required");
+    let (Some(name), Some(mut derivations)) = (input.name, input.derivations) else {
+        return Err(errors.into());
+    };
+    fill_names(&mut derivations);
+
+    let montage: Option<Montage> = sqlx::query_as(&format!(
+        "INSERT INTO montages (name, description, derivations) VALUES ($1, $2, $3) \
+         ON CONFLICT (name) DO NOTHING RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(&input.description)
+    .bind(Jsonb(derivations))
+    .fetch_optional(&state.pool)
+    .await?;
+    let montage =
+        montage.ok_or_else(|| ApiError::conflict(format!("montage {:?} already exists", name)))?;
+    Ok((StatusCode::CREATED, Json(montage)))
+}
+
+/// Updates the given fields; omitted fields keep their value.
+#[utoipa::path(
+    put,
+    path = "/montages/{name}",
+    tag = "montages",
+    params(("name" = String, Path)),
+    request_body = MontageInput,
+    responses(
+        (status = 200, body = Montage),
+        (status = 400, description = "Another name in the body"),
+        (status = 404, description = "Unknown montage"),
+        (status = 422, description = "Invalid derivations, named in `details.fields`")
+    )
+)]
+pub async fn update_montage(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+    Valid(Json(input)): Valid<Json<MontageInput>>,
+) -> Result<Json<Montage>, ApiError> {
+    if input.name.as_deref().is_some_and(|n| n != name) {
+        return Err(ApiError::bad_request("montages cannot be renamed"));
+    }
+    let derivations = input.derivations.map(|mut derivations| {
+        fill_names(&mut derivations);
+        Jsonb(derivations)
+    });
+    sqlx::query_as(&format!(
+        "UPDATE montages SET description = COALESCE($2, description), \
+         derivations = COALESCE($3, derivations) WHERE name = $1 RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&name)
+    .bind(&input.description)
+    .bind(derivations)
+    .fetch_optional(&state.pool)
+    .await?
+    .map(Json)
+    .ok_or_else(|| not_found(&name))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/montages/{name}",
+    tag = "montages",
+    params(("name" = String, Path)),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "Unknown montage")
+    )
+)]
+pub async fn delete_montage(
+    State(state): State<AppState>,
+    Parsed(Path(name)): Parsed<Path<String>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM montages WHERE name = $1")
+        .bind(&name)
+        .execute(&state.pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(not_found(&name));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
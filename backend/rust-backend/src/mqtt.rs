@@ -0,0 +1,141 @@
+//! MQTT ingest bridge for wireless headsets publishing to a broker.
+//!
+//! Payloads are either JSON (one sample object or an array of them, as for
+//! `POST /samples` and `POST /samples/batch`) or binary frames in the
+//! `POST /samples/binary` format. Binary frames take their channel names
+//! from `MQTT_BINARY_CHANNELS`, or, for single-channel frames, from the
+//! first `+` segment of the matched topic.
+
+use crate::config;
+use crate::ingest::{self, NewSample};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Subscription filter, e.g. `eeg/+/samples`.
+    pub topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub binary_channels: Option<Vec<String>>,
+}
+
+impl MqttConfig {
+    /// The bridge of the `[mqtt]` settings; `None` without a host.
+    pub fn from_config(config: &config::MqttConfig) -> Option<Self> {
+        Some(Self {
+            host: config.host.clone()?,
+            port: config.port,
+            client_id: config.client_id.clone(),
+            topic: config.topic.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            binary_channels: Some(config.binary_channels.clone()).filter(|c| !c.is_empty()),
+        })
+    }
+}
+
+/// Returns the topic segment matched by the first `+` in `filter`.
+fn wildcard_segment<'a>(filter: &str, topic: &'a str) -> Option<&'a str> {
+    filter
+        .split('/')
+        .zip(topic.split('/'))
+        .find(|(f, _)| *f == "+")
+        .map(|(_, t)| t)
+}
+
+/// Decodes one MQTT payload into samples.
+pub fn parse_payload(
+    config: &MqttConfig,
+    topic: &str,
+    payload: &[u8],
+) -> Result<Vec<NewSample>, String> {
+    let first = payload.iter().find(|b| !b.is_ascii_whitespace());
+    let samples = match first {
+        Some(b'{') => {
+            vec![serde_json::from_slice::<NewSample>(payload).map_err(|e| e.to_string())?]
+        }
+        Some(b'[') => {
+            serde_json::from_slice::<Vec<NewSample>>(payload).map_err(|e| e.to_string())?
+        }
+        Some(_) => {
+            let channels = match &config.binary_channels {
+                Some(channels) => channels.clone(),
+                None => {
+                    let name = wildcard_segment(&config.topic, topic).ok_or_else(|| {
+                        "binary payload needs MQTT_BINARY_CHANNELS or a `+` topic segment"
+                            .to_string()
+                    })?;
+                    vec![name.to_string()]
+                }
+            };
+            ingest::decode_frames(payload, &channels)?
+        }
+        None => return Err("empty payload".to_string()),
+    };
+    ingest::validate_batch(&samples)?;
+    Ok(samples)
+}
+
+/// Starts the subscriber; it reconnects with exponential backoff.
+pub fn spawn(config: MqttConfig, pool: PgPool) {
+    let tx = ingest::spawn_writer(pool, "MQTT bridge");
+    tokio::spawn(run(config, tx));
+}
+
+async fn run(config: MqttConfig, tx: mpsc::Sender<Vec<NewSample>>) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(user), Some(pass)) = (&config.username, &config.password) {
+        options.set_credentials(user, pass);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!(
+                    "MQTT bridge connected to {}:{}, subscribing to {}",
+                    config.host,
+                    config.port,
+                    config.topic
+                );
+                backoff = MIN_BACKOFF;
+                if let Err(e) = client.subscribe(&config.topic, QoS::AtLeastOnce).await {
+                    tracing::error!("MQTT bridge cannot subscribe: {}", e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                match parse_payload(&config, &publish.topic, &publish.payload) {
+                    Ok(samples) => {
+                        if tx.send(samples).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("MQTT bridge dropped payload on {}: {}", publish.topic, e)
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!(
+                    "MQTT bridge connection error: {}; retrying in {:?}",
+                    e,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
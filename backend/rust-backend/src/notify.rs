@@ -0,0 +1,184 @@
+//! Push updates of stored samples over Postgres `LISTEN` / `NOTIFY`.
+//!
+//! Migration 0014 adds a statement-level trigger that notifies
+//! `eeg_samples` once per channel of each insert, with the channel and the
+//! `application_name` of the writer. TimescaleDB has no transition tables
+//! on hypertables, so there migration 0015 drops the trigger and the
+//! backend's writers notify the same way with [`written`] after each
+//! insert and COPY; samples inserted by other clients are then not
+//! announced, and streams find them on their next poll.
+//! [`spawn`] listens on one pooled connection and wakes the live streams of
+//! the channel (`/live/ws`, `/live/sse`, and the gRPC and GraphQL
+//! subscriptions), which read at once instead of on their next poll. While
+//! the listener is connected, streams also check on their own every
+//! [`IDLE_POLL`]; while it is not, or with `streaming.notify` off, they
+//! poll every `streaming.poll_interval_ms`.
+//!
+//! Samples stored by another backend, or by any other client, name a
+//! different [`db::application_name`]; the [live buffer](ring) stops
+//! keeping their channel, since this backend does not see all of its
+//! samples.
+
+use crate::{config, db, ring};
+use sqlx::postgres::PgListener;
+use sqlx::{PgExecutor, PgPool};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::Instant;
+
+/// Notification channel of the trigger.
+const CHANNEL: &str = "eeg_samples";
+
+/// How often streams check without a notification while the listener is
+/// connected, in case one was missed.
+pub const IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// Wait before reconnecting after the listener failed.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The channel that got samples, or `None` for every channel, after
+/// notifications may have been missed.
+static WAKEUPS: LazyLock<broadcast::Sender<Option<Arc<str>>>> =
+    LazyLock::new(|| broadcast::channel(1024).0);
+
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the trigger notifies inserts into `eeg_samples`, as [`detect`]
+/// found; writers notify with [`written`] where it does not.
+static TRIGGER: AtomicBool = AtomicBool::new(true);
+
+/// Checks whether `eeg_samples` has the notifying trigger, before the
+/// backend writes samples.
+pub async fn detect(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let trigger = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pg_trigger \
+         WHERE tgrelid = 'eeg_samples'::regclass AND tgname = 'eeg_samples_notify')",
+    )
+    .fetch_one(pool)
+    .await?;
+    TRIGGER.store(trigger, Ordering::Relaxed);
+    Ok(trigger)
+}
+
+/// Notifies `eeg_samples` once per channel of `channels` that `executor`
+/// just stored samples of, as the trigger does, unless the trigger does.
+/// In a transaction the notifications go out when it commits.
+pub async fn written<'e, 'c>(
+    executor: impl PgExecutor<'e>,
+    channels: impl IntoIterator<Item = &'c str>,
+) -> Result<(), sqlx::Error> {
+    if TRIGGER.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let channels: BTreeSet<&str> = channels.into_iter().collect();
+    if channels.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        "SELECT pg_notify($1, name || ' ' || current_setting('application_name')) \
+         FROM unnest($2::text[]) AS name",
+    )
+    .bind(CHANNEL)
+    .bind(channels.into_iter().collect::<Vec<_>>())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Listens for new samples until the backend stops, if `streaming.notify`
+/// is on.
+pub fn spawn(pool: PgPool) {
+    if !config::get().streaming.notify {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen(&pool).await {
+                tracing::warn!("sample notifications failed, live streams poll: {}", e);
+            }
+            if LISTENING.swap(false, Ordering::Relaxed) {
+                let _ = WAKEUPS.send(None);
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    });
+}
+
+async fn listen(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    loop {
+        if !LISTENING.swap(true, Ordering::Relaxed) {
+            tracing::info!("live streams wake on sample notifications");
+        }
+        while let Some(notification) = listener.try_recv().await? {
+            let (channel, writer) = notification
+                .payload()
+                .split_once(' ')
+                .unwrap_or((notification.payload(), ""));
+            if writer != db::application_name() && ring::share(channel) {
+                tracing::info!(
+                    "channel {} is also written by {:?}; its live polls read the database",
+                    channel,
+                    writer
+                );
+            }
+            let _ = WAKEUPS.send(Some(channel.into()));
+        }
+        // The connection was lost and is made again on the next receive;
+        // what was notified meanwhile is gone.
+        LISTENING.store(false, Ordering::Relaxed);
+        let _ = WAKEUPS.send(None);
+        tracing::warn!("sample notifications interrupted, reconnecting");
+    }
+}
+
+/// When a live stream should look for new samples.
+pub struct Wakeup {
+    wakeups: broadcast::Receiver<Option<Arc<str>>>,
+    next: Instant,
+    again: bool,
+}
+
+impl Default for Wakeup {
+    fn default() -> Self {
+        Self {
+            wakeups: WAKEUPS.subscribe(),
+            next: Instant::now(),
+            again: false,
+        }
+    }
+}
+
+impl Wakeup {
+    /// Makes the next [`wait`](Self::wait) return at once, for a stream
+    /// that read new samples and may have more.
+    pub fn again(&mut self) {
+        self.again = true;
+    }
+
+    /// Waits until `channel` may have new samples; the first call returns
+    /// at once.
+    pub async fn wait(&mut self, channel: &str) {
+        if !std::mem::take(&mut self.again) {
+            loop {
+                tokio::select! {
+                    wakeup = self.wakeups.recv() => match wakeup {
+                        Ok(Some(stored)) if *stored != *channel => continue,
+                        Ok(_) | Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => break,
+                    },
+                    _ = tokio::time::sleep_until(self.next) => break,
+                }
+            }
+        }
+        let interval = if LISTENING.load(Ordering::Relaxed) {
+            IDLE_POLL
+        } else {
+            config::get().streaming.poll_interval()
+        };
+        self.next = Instant::now() + interval;
+    }
+}
@@ -0,0 +1,281 @@
+//! OpenAPI description of the REST API (`GET /api-docs/openapi.json`) and a
+//! Swagger UI to browse it (`GET /swagger-ui`).
+//!
+//! Paths come from the `#[utoipa::path]` attributes on the handlers, and
+//! request and response schemas from their `ToSchema` and `IntoParams`
+//! derives, so the document follows the code. Handlers that answer with ad
+//! hoc JSON describe its shape in the response description. Error responses
+//! all share the [`ApiError`](crate::error::ApiError) body. WebSocket and SSE routes are listed, but their messages are
+//! described in the README. GraphQL (`/v1/graphql`) has its own schema, shown
+//! by GraphiQL at the same route. Handler paths are written without the
+//! [version](crate::versioning) prefix, which is added here.
+
+use crate::versioning;
+use axum::{response::Html, Json};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{Content, Ref, RefOr};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "EEG backend",
+        description = "Storage, streaming and analysis of EEG recordings."
+    ),
+    security(("bearer" = [])),
+    modifiers(&Security, &Versioned, &Errors),
+    tags(
+        (name = "health", description = "Probes and diagnostics"),
+        (name = "samples", description = "Stored samples: ingest, windows and downsampling"),
+        (name = "live", description = "Newly stored samples, polled or streamed"),
+        (name = "analysis", description = "Spectral analysis, epochs, artifacts and ICA"),
+        (name = "epoch sets", description = "Stored epoch extractions"),
+        (name = "pipelines", description = "Live processing pipelines"),
+        (name = "montages", description = "Named channel layouts and derivations"),
+        (name = "channels", description = "Channel registry"),
+        (name = "devices", description = "Device registry"),
+        (name = "subjects", description = "Pseudonymous study subjects"),
+        (name = "sessions", description = "Recording sessions and impedance checks"),
+        (name = "events", description = "Annotations and detected events"),
+        (name = "alerts", description = "Alert rules and their states"),
+        (name = "imports", description = "EDF and CSV imports"),
+        (name = "exports", description = "Parquet export jobs"),
+        (name = "admin", description = "Background jobs, retention, API keys, audit log and roles"),
+    ),
+    paths(
+        crate::api::root,
+        crate::api::dbtest,
+        crate::api::samples::get_samples,
+        crate::api::samples::create_sample,
+        crate::api::samples::create_samples_batch,
+        crate::api::samples::create_samples_binary,
+        crate::api::samples::create_samples_series,
+        crate::api::live::get_live,
+        crate::api::live::live_ws,
+        crate::api::live::live_sse,
+        crate::aggregate::get_aggregate,
+        crate::aggregate::get_buckets,
+        crate::alerts::list_rules,
+        crate::alerts::get_rule,
+        crate::alerts::create_rule,
+        crate::alerts::update_rule,
+        crate::alerts::delete_rule,
+        crate::alerts::list_alerts,
+        crate::analysis::artifacts::detect_artifacts,
+        crate::analysis::bandpower::get_bandpower,
+        crate::analysis::coherence::get_coherence,
+        crate::analysis::epochs::get_epochs,
+        crate::analysis::erp::get_erp,
+        crate::analysis::histogram::get_histogram,
+        crate::analysis::hjorth::get_hjorth,
+        crate::analysis::ica::create_ica,
+        crate::analysis::ica::list_ica,
+        crate::analysis::ica::get_ica,
+        crate::analysis::ica::delete_ica,
+        crate::analysis::ica::get_components,
+        crate::analysis::ica::set_excluded,
+        crate::analysis::ica::apply_ica,
+        crate::analysis::psd::get_psd,
+        crate::analysis::spectrogram::get_spectrogram,
+        crate::analysis::stats::get_stats,
+        crate::audit::list_entries,
+        crate::calibration::list_calibrations,
+        crate::calibration::create_calibration,
+        crate::calibration::delete_calibration,
+        crate::channels::list_channels,
+        crate::channels::get_channel,
+        crate::channels::create_channel,
+        crate::channels::update_channel,
+        crate::channels::delete_channel,
+        crate::devices::keys::list_keys,
+        crate::devices::keys::create_key,
+        crate::devices::keys::revoke_key,
+        crate::devices::registry::list_devices,
+        crate::devices::registry::get_device,
+        crate::devices::registry::create_device,
+        crate::devices::registry::update_device,
+        crate::devices::registry::delete_device,
+        crate::devices::clock::get_clock,
+        crate::dsp::get_filtered,
+        crate::dsp::pipeline::list_pipelines,
+        crate::dsp::pipeline::get_pipeline,
+        crate::dsp::pipeline::create_pipeline,
+        crate::dsp::pipeline::update_pipeline,
+        crate::dsp::pipeline::delete_pipeline,
+        crate::epoch_sets::create_epoch_set,
+        crate::epoch_sets::list_epoch_sets,
+        crate::epoch_sets::get_epoch_set,
+        crate::epoch_sets::get_epoch_set_epochs,
+        crate::epoch_sets::delete_epoch_set,
+        crate::events::list_events,
+        crate::events::get_event,
+        crate::events::create_event,
+        crate::events::update_event,
+        crate::events::delete_event,
+        crate::triggers::list_triggers,
+        crate::export::csv::export_csv,
+        crate::export::export_session,
+        crate::export::parquet::create_export,
+        crate::export::queue::create_export,
+        crate::export::queue::list_exports,
+        crate::export::queue::get_export,
+        crate::export::queue::download_export,
+        crate::export::queue::delete_export,
+        crate::feedback::feedback_ws,
+        crate::gaps::list_gaps,
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::impedances::list_impedances,
+        crate::impedances::create_impedances,
+        crate::import::csv::import_csv,
+        crate::import::csv::list_imports,
+        crate::import::csv::get_import,
+        crate::import::edf::import_edf,
+        crate::metadata::list_session_channels,
+        crate::metadata::put_session_channel,
+        crate::metadata::delete_session_channel,
+        crate::metrics::get_metrics,
+        crate::montages::list_montages,
+        crate::montages::get_montage,
+        crate::montages::create_montage,
+        crate::montages::update_montage,
+        crate::montages::delete_montage,
+        crate::pipeline::get_metrics,
+        crate::quality::get_quality,
+        crate::jobs::list_jobs,
+        crate::jobs::run_job,
+        crate::replay::replay_ws,
+        crate::retention::list_policies,
+        crate::retention::update_policy,
+        crate::roles::list_assignments,
+        crate::roles::put_assignment,
+        crate::sessions::list_sessions,
+        crate::sessions::get_session,
+        crate::sessions::create_session,
+        crate::sessions::update_session,
+        crate::subjects::list_subjects,
+        crate::subjects::get_subject,
+        crate::subjects::create_subject,
+        crate::subjects::update_subject,
+        crate::subjects::delete_subject,
+        crate::tiers::get_overview,
+    ),
+    // Schemas referenced only from query parameters or descriptions.
+    components(schemas(
+        crate::error::ApiError,
+        crate::analysis::artifacts::Mode,
+        crate::analysis::psd::Method,
+        crate::analysis::spectrogram::Scale,
+        crate::downsample::Method,
+        crate::dsp::spectrum::Window,
+        crate::import::csv::Mapping,
+        crate::timezone::TimeFormat,
+    ))
+)]
+pub struct ApiDoc;
+
+/// Adds the security schemes the paths refer to.
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        // Taken from the crate manifest, which has no license.
+        openapi.info.license = None;
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                crate::auth::API_KEY_HEADER,
+                "Device API key; ingest routes only",
+            ))),
+        );
+    }
+}
+
+/// Paths served without a version prefix.
+const UNVERSIONED: &[&str] = &["/", "/healthz", "/readyz", "/metrics"];
+
+/// Puts the data routes under the current version.
+struct Versioned;
+
+impl Modify for Versioned {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let paths = std::mem::take(&mut openapi.paths.paths);
+        openapi.paths.paths = paths
+            .into_iter()
+            .map(|(path, item)| {
+                if UNVERSIONED.contains(&path.as_str()) {
+                    (path, item)
+                } else {
+                    (format!("/{}{}", versioning::CURRENT, path), item)
+                }
+            })
+            .collect();
+    }
+}
+
+/// Gives every error response the `ApiError` body.
+struct Errors;
+
+impl Modify for Errors {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let schema = Ref::from_schema_name("ApiError");
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.delete,
+                &mut item.patch,
+            ];
+            for operation in operations.into_iter().flatten() {
+                for (status, response) in operation.responses.responses.iter_mut() {
+                    let RefOr::T(response) = response else {
+                        continue;
+                    };
+                    if status.as_str() >= "400" && response.content.is_empty() {
+                        response.content.insert(
+                            "application/json".to_string(),
+                            Content::new(Some(schema.clone())),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Swagger UI, loaded from a CDN so it need not be bundled with the backend.
+pub async fn swagger_ui() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
+
+const SWAGGER_UI: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>EEG backend API</title>
+  <link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://cdn.jsdelivr.net/npm/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    SwaggerUIBundle({ url: "/api-docs/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
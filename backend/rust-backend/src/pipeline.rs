@@ -0,0 +1,458 @@
+//! Buffered `COPY ... FROM STDIN (FORMAT binary)` writer for high-rate sources.
+//!
+//! Background sources (LSL, MQTT, UDP, devices) push batches into a
+//! per-source buffer that is flushed when it reaches `ingest.flush_rows`
+//! samples or every `ingest.flush_ms`, whichever comes first. Flush latency
+//! and batch sizes are recorded per source and served at `/ingest/metrics`.
+//! On shutdown the writers flush what is buffered and stop; see [`drain`].
+//! Samples of trigger channels are stored as [`triggers`] changes.
+//! Imports use [`SampleCopy`] directly.
+
+use crate::ingest::NewSample;
+use crate::validation;
+use crate::{cache, channels, config, metrics, notify, ring, shutdown, tiers, triggers};
+use axum::Json;
+use serde::Serialize;
+use sqlx::postgres::PgCopyIn;
+use sqlx::{PgConnection, PgPool};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const COPY_STATEMENT: &str =
+    "COPY eeg_samples (ts, channel, value, session_id) FROM STDIN (FORMAT binary)";
+
+/// The writers' COPY, with ids taken from the sequence beforehand so that
+/// the samples can be [recorded](ring::record).
+const COPY_WITH_IDS_STATEMENT: &str =
+    "COPY eeg_samples (id, ts, channel, value, session_id) FROM STDIN (FORMAT binary)";
+
+/// `PGCOPY\n\377\r\n\0`, then a zero flags field and a zero-length extension area.
+const COPY_HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+
+/// Microseconds between the Unix epoch and the Postgres epoch (2000-01-01).
+const PG_EPOCH_OFFSET_US: i64 = 946_684_800_000_000;
+
+static METRICS: Mutex<BTreeMap<&'static str, FlushStats>> = Mutex::new(BTreeMap::new());
+
+static WRITERS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Writer inputs, held weakly so that sources going away still closes them.
+static QUEUES: Mutex<BTreeMap<&'static str, mpsc::WeakSender<Vec<NewSample>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Batches a writer holds before its sources wait.
+const QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CopyConfig {
+    pub flush_rows: usize,
+    pub flush_interval: Duration,
+}
+
+impl CopyConfig {
+    /// The `[ingest]` settings of the [configuration](config).
+    pub fn from_config() -> Self {
+        let ingest = &config::get().ingest;
+        Self {
+            flush_rows: ingest.flush_rows,
+            flush_interval: Duration::from_millis(ingest.flush_ms),
+        }
+    }
+}
+
+/// Batches waiting for a writer.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QueueDepth {
+    pub batches: usize,
+    pub capacity: usize,
+}
+
+impl QueueDepth {
+    /// Sources block until the writer catches up.
+    pub fn is_full(&self) -> bool {
+        self.batches >= self.capacity
+    }
+}
+
+/// Queue depth of every running writer.
+pub fn queue_depths() -> BTreeMap<&'static str, QueueDepth> {
+    QUEUES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(source, queue)| {
+            let tx = queue.upgrade()?;
+            Some((
+                *source,
+                QueueDepth {
+                    batches: tx.max_capacity() - tx.capacity(),
+                    capacity: tx.max_capacity(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Flush counters for one source.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FlushStats {
+    pub flushes: u64,
+    pub failed_flushes: u64,
+    pub rows: u64,
+    pub rejected_rows: u64,
+    pub last_batch_rows: usize,
+    pub max_batch_rows: usize,
+    pub last_flush_ms: f64,
+    pub max_flush_ms: f64,
+    pub total_flush_ms: f64,
+}
+
+/// Flush counters of every source.
+pub fn flush_stats() -> BTreeMap<&'static str, FlushStats> {
+    METRICS.lock().unwrap().clone()
+}
+
+fn record(source: &'static str, update: impl FnOnce(&mut FlushStats)) {
+    update(METRICS.lock().unwrap().entry(source).or_default());
+}
+
+/// Appends one sample as a binary COPY row, led by its id when given.
+fn encode_row(out: &mut Vec<u8>, id: Option<i32>, sample: &NewSample) {
+    let us = sample.ts.timestamp_micros();
+    match id {
+        Some(id) => {
+            out.extend_from_slice(&5i16.to_be_bytes());
+            out.extend_from_slice(&4i32.to_be_bytes());
+            out.extend_from_slice(&id.to_be_bytes());
+        }
+        None => out.extend_from_slice(&4i16.to_be_bytes()),
+    }
+    out.extend_from_slice(&8i32.to_be_bytes());
+    out.extend_from_slice(&(us - PG_EPOCH_OFFSET_US).to_be_bytes());
+    out.extend_from_slice(&(sample.channel.len() as i32).to_be_bytes());
+    out.extend_from_slice(sample.channel.as_bytes());
+    out.extend_from_slice(&8i32.to_be_bytes());
+    out.extend_from_slice(&sample.value.to_be_bytes());
+    match sample.session_id {
+        Some(id) => {
+            out.extend_from_slice(&4i32.to_be_bytes());
+            out.extend_from_slice(&id.to_be_bytes());
+        }
+        None => out.extend_from_slice(&(-1i32).to_be_bytes()),
+    }
+}
+
+/// Encodes samples and their ids as a binary COPY stream.
+fn encode(samples: &[&NewSample], ids: &[i32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(COPY_HEADER.len() + samples.len() * 68 + 2);
+    out.extend_from_slice(COPY_HEADER);
+    for (sample, id) in samples.iter().zip(ids) {
+        encode_row(&mut out, Some(*id), sample);
+    }
+    out.extend_from_slice(&(-1i16).to_be_bytes());
+    out
+}
+
+/// A COPY into `eeg_samples` over one connection, fed in parts; used by
+/// imports, which copy within the transaction that creates their session
+/// and channels. Samples are not validated against the channel registry,
+/// which does not know channels of the open transaction yet.
+pub struct SampleCopy<'c> {
+    copy: PgCopyIn<&'c mut PgConnection>,
+    /// Channel and session of the samples sent.
+    written: BTreeSet<(String, Option<i32>)>,
+}
+
+impl<'c> SampleCopy<'c> {
+    pub async fn start(conn: &'c mut PgConnection) -> Result<SampleCopy<'c>, sqlx::Error> {
+        let mut copy = conn.copy_in_raw(COPY_STATEMENT).await?;
+        copy.send(COPY_HEADER).await?;
+        Ok(Self {
+            copy,
+            written: BTreeSet::new(),
+        })
+    }
+
+    pub async fn send(&mut self, samples: &[NewSample]) -> Result<(), sqlx::Error> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let mut out = Vec::with_capacity(samples.len() * 64);
+        for sample in samples {
+            encode_row(&mut out, None, sample);
+            let written = (sample.channel.clone(), sample.session_id);
+            if !self.written.contains(&written) {
+                self.written.insert(written);
+            }
+        }
+        self.copy.send(out).await?;
+        Ok(())
+    }
+
+    /// Completes the COPY and returns the rows copied and the channels
+    /// written, for [`notify::written`] on the connection of the copy. The
+    /// copied channels are [forgotten](ring::forget) by the live windows
+    /// and their cached answers [invalidated](cache::invalidate).
+    pub async fn finish(mut self) -> Result<(u64, Vec<String>), sqlx::Error> {
+        self.copy.send((-1i16).to_be_bytes().as_slice()).await?;
+        let rows = self.copy.finish().await?;
+        let written = || self.written.iter().map(|(c, s)| (c.as_str(), *s));
+        ring::forget(written().map(|(channel, _)| channel));
+        cache::invalidate(written());
+        let mut channels: Vec<String> = self.written.into_iter().map(|(c, _)| c).collect();
+        channels.dedup();
+        Ok((rows, channels))
+    }
+
+    pub async fn abort(self, message: impl Into<String>) -> Result<(), sqlx::Error> {
+        self.copy.abort(message).await
+    }
+}
+
+/// Takes ids for `samples` from the sequence, copies them in and returns
+/// the ids.
+async fn copy_in(pool: &PgPool, samples: &[&NewSample]) -> Result<Vec<i32>, sqlx::Error> {
+    let mut ids: Vec<i32> = sqlx::query_scalar(
+        "SELECT nextval(pg_get_serial_sequence('eeg_samples', 'id'))::int4 \
+         FROM generate_series(1, $1)",
+    )
+    .bind(samples.len() as i32)
+    .fetch_all(pool)
+    .await?;
+    ids.sort_unstable();
+
+    let mut copy = pool.copy_in_raw(COPY_WITH_IDS_STATEMENT).await?;
+    if let Err(e) = copy.send(encode(samples, &ids)).await {
+        let _ = copy.abort(e.to_string()).await;
+        return Err(e);
+    }
+    copy.finish().await?;
+    Ok(ids)
+}
+
+async fn flush(pool: &PgPool, source: &'static str, buffer: &mut Vec<NewSample>) {
+    if buffer.is_empty() {
+        return;
+    }
+    let samples = std::mem::take(buffer);
+    let (changes, valid): (Vec<&NewSample>, Vec<&NewSample>) = samples
+        .iter()
+        .filter(|s| validation::check(*s).is_ok())
+        .partition(|s| channels::is_trigger(&s.channel));
+    let rows = valid.len();
+    let rejected = (samples.len() - rows - changes.len()) as u64;
+    if rejected > 0 {
+        tracing::warn!("{} dropped {} invalid samples", source, rejected);
+    }
+    if let Err(e) = triggers::store(pool, changes.iter().copied()).await {
+        tracing::error!(
+            "{} failed to store {} trigger samples: {}",
+            source,
+            changes.len(),
+            e
+        );
+    }
+
+    let started = Instant::now();
+    let result = if rows > 0 {
+        copy_in(pool, &valid).await
+    } else {
+        Ok(Vec::new())
+    };
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    match &result {
+        Ok(ids) => {
+            if let Some(earliest_us) = valid.iter().map(|s| s.ts.timestamp_micros()).min() {
+                tiers::mark_dirty(earliest_us);
+            }
+            metrics::record_ingested(valid.iter().map(|s| s.channel.as_str()));
+            ring::record(started, ids.iter().copied().zip(valid.iter().copied()));
+            cache::invalidate(valid.iter().map(|s| (s.channel.as_str(), s.session_id)));
+            let channels = valid.iter().map(|s| s.channel.as_str());
+            if let Err(e) = notify::written(pool, channels).await {
+                tracing::warn!("{} cannot notify the samples copied: {}", source, e);
+            }
+        }
+        Err(e) => tracing::error!("{} failed to copy {} samples: {}", source, rows, e),
+    }
+    record(source, |stats| {
+        stats.rejected_rows += rejected;
+        match result {
+            Ok(ids) => {
+                stats.flushes += 1;
+                stats.rows += ids.len() as u64;
+                stats.last_batch_rows = rows;
+                stats.max_batch_rows = stats.max_batch_rows.max(rows);
+                stats.last_flush_ms = elapsed_ms;
+                stats.max_flush_ms = stats.max_flush_ms.max(elapsed_ms);
+                stats.total_flush_ms += elapsed_ms;
+            }
+            Err(_) => stats.failed_flushes += 1,
+        }
+    });
+}
+
+/// Spawns the buffering COPY writer for `source` and returns its input.
+pub fn spawn(
+    pool: PgPool,
+    source: &'static str,
+    config: CopyConfig,
+) -> mpsc::Sender<Vec<NewSample>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<NewSample>>(QUEUE_CAPACITY);
+    record(source, |_| {});
+    QUEUES.lock().unwrap().insert(source, tx.downgrade());
+
+    let writer = tokio::spawn(async move {
+        let mut buffer: Vec<NewSample> = Vec::with_capacity(config.flush_rows);
+        let mut ticker = tokio::time::interval(config.flush_interval);
+        let mut stopping = std::pin::pin!(shutdown::requested());
+        loop {
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Some(samples) => {
+                        buffer.extend(samples);
+                        if buffer.len() >= config.flush_rows {
+                            flush(&pool, source, &mut buffer).await;
+                        }
+                    }
+                    None => {
+                        flush(&pool, source, &mut buffer).await;
+                        break;
+                    }
+                },
+                _ = ticker.tick() => flush(&pool, source, &mut buffer).await,
+                _ = &mut stopping => {
+                    // Sources get one flush interval to hand over what they
+                    // hold; closing the channel then stops them.
+                    let grace = tokio::time::sleep(config.flush_interval);
+                    tokio::pin!(grace);
+                    loop {
+                        tokio::select! {
+                            received = rx.recv() => match received {
+                                Some(samples) => buffer.extend(samples),
+                                None => break,
+                            },
+                            _ = &mut grace => break,
+                        }
+                    }
+                    rx.close();
+                    while let Some(samples) = rx.recv().await {
+                        buffer.extend(samples);
+                    }
+                    let rows = buffer.len();
+                    flush(&pool, source, &mut buffer).await;
+                    tracing::info!("{} stopped after flushing {} samples", source, rows);
+                    break;
+                }
+            }
+        }
+    });
+    WRITERS.lock().unwrap().push(writer);
+
+    tx
+}
+
+/// Waits for every writer to flush and stop, which they do once shutdown is
+/// requested or their sources go away.
+pub async fn drain() {
+    let writers = std::mem::take(&mut *WRITERS.lock().unwrap());
+    for writer in writers {
+        let _ = writer.await;
+    }
+}
+
+/// Flush counters per source, with the mean flush latency.
+#[utoipa::path(
+    get,
+    path = "/ingest/metrics",
+    operation_id = "get_ingest_metrics",
+    tag = "samples",
+    responses((status = 200, description = "`{ sources: { <source>: { flushes, rows, ..., avg_flush_ms, avg_batch_rows } } }`", body = serde_json::Value))
+)]
+pub async fn get_metrics() -> Json<serde_json::Value> {
+    let sources: serde_json::Map<String, serde_json::Value> = flush_stats()
+        .into_iter()
+        .map(|(source, stats)| {
+            let avg_flush_ms = if stats.flushes > 0 {
+                stats.total_flush_ms / stats.flushes as f64
+            } else {
+                0.0
+            };
+            let avg_batch_rows = if stats.flushes > 0 {
+                stats.rows as f64 / stats.flushes as f64
+            } else {
+                0.0
+            };
+            let mut value = serde_json::to_value(stats).unwrap_or_default();
+            value["avg_flush_ms"] = avg_flush_ms.into();
+            value["avg_batch_rows"] = avg_batch_rows.into();
+            (source.to_string(), value)
+        })
+        .collect();
+    Json(serde_json::json!({ "sources": sources }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn sample(channel: &str, value: f64, session_id: Option<i32>) -> NewSample {
+        NewSample {
+            channel: channel.to_string(),
+            // One second and 250 µs after the Postgres epoch.
+            ts: DateTime::from_timestamp(946_684_801, 250_000).unwrap(),
+            value,
+            session_id,
+        }
+    }
+
+    /// A field of a binary COPY row: its length, then its bytes.
+    fn field(out: &mut Vec<u8>, bytes: &[u8]) {
+        out.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+        out.extend_from_slice(bytes);
+    }
+
+    #[test]
+    fn rows_carry_each_column_with_its_length() {
+        let mut row = Vec::new();
+        encode_row(&mut row, None, &sample("Fz", -1.5, Some(7)));
+        let mut expected = 4i16.to_be_bytes().to_vec();
+        field(&mut expected, &1_000_250i64.to_be_bytes());
+        field(&mut expected, b"Fz");
+        field(&mut expected, &(-1.5f64).to_be_bytes());
+        field(&mut expected, &7i32.to_be_bytes());
+        assert_eq!(row, expected);
+    }
+
+    #[test]
+    fn streams_lead_rows_with_their_ids_and_end_with_a_trailer() {
+        let first = sample("Cz", 2.0, None);
+        let second = sample("Oz", 0.25, Some(3));
+        let stream = encode(&[&first, &second], &[41, 42]);
+
+        let mut expected = COPY_HEADER.to_vec();
+        expected.extend_from_slice(&5i16.to_be_bytes());
+        field(&mut expected, &41i32.to_be_bytes());
+        field(&mut expected, &1_000_250i64.to_be_bytes());
+        field(&mut expected, b"Cz");
+        field(&mut expected, &2.0f64.to_be_bytes());
+        expected.extend_from_slice(&(-1i32).to_be_bytes());
+        expected.extend_from_slice(&5i16.to_be_bytes());
+        field(&mut expected, &42i32.to_be_bytes());
+        field(&mut expected, &1_000_250i64.to_be_bytes());
+        field(&mut expected, b"Oz");
+        field(&mut expected, &0.25f64.to_be_bytes());
+        field(&mut expected, &3i32.to_be_bytes());
+        expected.extend_from_slice(&(-1i16).to_be_bytes());
+        assert_eq!(stream, expected);
+        assert_eq!(&stream[..11], b"PGCOPY\n\xff\r\n\0");
+
+        let empty = encode(&[], &[]);
+        assert_eq!(empty, [COPY_HEADER, &(-1i16).to_be_bytes()].concat());
+    }
+}
@@ -0,0 +1,242 @@
+//! The portable backend: `serve` on a [`SqliteStore`], for a laptop in the
+//! field or another small deployment without a Postgres container.
+//!
+//! Only the core sample routes are served — ingest, `/samples` pages and
+//! `/live` polls — with the response shapes of the full backend, so the
+//! frontend works unchanged. Everything that needs Postgres is left out:
+//! authentication, the channel and device registries, sessions and
+//! subjects, events, analysis and exports. Any valid channel name is
+//! accepted, and `subject_id` filters are rejected.
+
+use crate::api::live::LivePollQuery;
+use crate::encoding::{self, Encoded};
+use crate::error::ApiError;
+use crate::ingest::NewSample;
+use crate::store::{self, SampleStore, SqliteStore};
+use crate::validation::{check_limit, FieldErrors, Valid, Validate};
+use crate::{
+    channels, cli, config, cors, devices, health, metrics, shutdown, telemetry, versioning,
+    ChannelQuery, SampleFilter,
+};
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+type Store = Arc<dyn SampleStore>;
+
+/// Typed part of `/samples` here; `channel`, `before_id` and `after_id` are
+/// read by [`ChannelQuery`].
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    limit: Option<i32>,
+}
+
+impl Validate for PageQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_limit(errors, self.limit.map(i64::from));
+    }
+}
+
+/// Runs the portable routes on the SQLite file of `database.url`.
+pub async fn serve(
+    config: &'static config::Config,
+    args: cli::ServeArgs,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if config.auth.enabled() {
+        return Err(
+            "authentication needs Postgres; unset the auth keys to serve from SQLite".into(),
+        );
+    }
+    let sqlite = SqliteStore::open(&config.database.url).await?;
+    tracing::info!("storing samples in {}", config.database.url);
+    tracing::warn!("SQLite storage has no authentication; data routes are open");
+    let store: Store = Arc::new(sqlite);
+    channels::accept_any();
+
+    match devices::from_config(&config.device, args.simulate) {
+        Ok(Some(source)) => {
+            if config.device.serial.is_some() {
+                tracing::warn!("device.serial ignored: SQLite storage has no device registry");
+            }
+            devices::run(
+                source,
+                None,
+                store::spawn_writer(store.clone(), "device driver"),
+            );
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("device driver disabled: {}", e),
+    }
+
+    shutdown::listen();
+
+    let public = Router::new()
+        .route("/", get(crate::api::root))
+        .route("/health", get(health::healthz))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(readyz));
+
+    let api = Router::new()
+        .route("/samples", get(get_samples).post(create_sample))
+        .route("/samples/batch", post(create_samples_batch))
+        .route("/live", get(get_live));
+    let legacy = if config.server.legacy_routes {
+        api.clone()
+            .route_layer(axum::middleware::from_fn(versioning::deprecated))
+    } else {
+        Router::new()
+    };
+
+    let app = Router::new()
+        .nest(&format!("/{}", versioning::CURRENT), api)
+        .merge(legacy)
+        .merge(public)
+        .fallback(versioning::fallback)
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(telemetry::trace))
+        .with_state(store);
+    let app = match cors::layer(&config.cors) {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+
+    let addr = config.server.bind;
+    tracing::info!("listening on {}", addr);
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown::requested())
+    .await?;
+    tracing::info!("shutdown complete");
+    Ok(())
+}
+
+/// `503` while the database does not answer or once shutdown has started.
+async fn readyz(State(store): State<Store>) -> (StatusCode, Json<serde_json::Value>) {
+    let database = store.ping().await;
+    let ready = database.is_ok() && !shutdown::is_requested();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let database = match database {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => json!({ "ok": false, "error": e.message }),
+    };
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "shutting_down": shutdown::is_requested(),
+            "checks": { "database": database },
+        })),
+    )
+}
+
+async fn create_sample(
+    State(store): State<Store>,
+    Valid(Json(sample)): Valid<Json<NewSample>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let ids = store.insert(vec![sample]).await?;
+    Ok((StatusCode::CREATED, Json(json!({"id": ids.first()}))))
+}
+
+async fn create_samples_batch(
+    State(store): State<Store>,
+    Valid(Json(samples)): Valid<Json<Vec<NewSample>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let ids = store.insert(samples).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({"inserted": ids.len(), "ids": ids})),
+    ))
+}
+
+/// Pages of one or more channels, as `/samples` without its window options.
+async fn get_samples(
+    State(store): State<Store>,
+    Valid(Query(params)): Valid<Query<PageQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    headers: HeaderMap,
+) -> Result<Encoded<serde_json::Value>, ApiError> {
+    let format = encoding::Format::from_headers(&headers);
+    let channels = raw.channels().map_err(ApiError::bad_request)?;
+    let before_ids = raw.ids("before_id", channels.len())?;
+    let after_ids = raw.ids("after_id", channels.len())?;
+    let limit = params.limit.unwrap_or(100);
+
+    let mut pages = futures::future::try_join_all(
+        channels
+            .iter()
+            .enumerate()
+            .map(|(i, channel)| store.page(channel, before_ids[i], after_ids[i], &filter, limit)),
+    )
+    .await?;
+    if pages.len() == 1 {
+        let (samples, next_cursor) = pages.remove(0);
+        return Ok(Encoded(
+            format,
+            json!({ "samples": samples, "next_cursor": next_cursor }),
+        ));
+    }
+    let grouped: Vec<_> = channels
+        .iter()
+        .zip(pages)
+        .map(|(channel, (samples, next_cursor))| {
+            json!({
+                "channel": channel,
+                "samples": samples,
+                "next_cursor": next_cursor,
+            })
+        })
+        .collect();
+    Ok(Encoded(format, json!({ "channels": grouped })))
+}
+
+/// New points of one or more channels, as `/live`.
+async fn get_live(
+    State(store): State<Store>,
+    Valid(Query(params)): Valid<Query<LivePollQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    headers: HeaderMap,
+) -> Result<Encoded<serde_json::Value>, ApiError> {
+    let format = encoding::Format::from_headers(&headers);
+    let channels = raw.channels().map_err(ApiError::bad_request)?;
+    let since_ids = raw.ids("since_id", channels.len())?;
+    let limit = params.limit.unwrap_or(200);
+
+    let batches =
+        futures::future::try_join_all(channels.iter().zip(&since_ids).map(
+            |(channel, since_id)| store.since(channel, since_id.unwrap_or(0), &filter, limit),
+        ))
+        .await?;
+    let mut grouped: Vec<_> = channels
+        .into_iter()
+        .zip(since_ids)
+        .zip(batches)
+        .map(|((channel, since_id), points)| {
+            let last_id = points.last().map(|p| p.id).unwrap_or(since_id.unwrap_or(0));
+            json!({
+                "points": points,
+                "last_id": last_id,
+                "channel": channel,
+            })
+        })
+        .collect();
+    if grouped.len() == 1 {
+        return Ok(Encoded(format, grouped.remove(0)));
+    }
+    Ok(Encoded(format, json!({ "channels": grouped })))
+}
@@ -0,0 +1,408 @@
+//! Signal quality of the latest samples of each channel (`GET /quality`,
+//! and `quality` messages on `/live/ws` and `/live/sse`).
+//!
+//! Metrics are computed over the [`Options::window`] ending at the channel's
+//! newest sample:
+//!
+//! - `rms`: root mean square after removing the mean.
+//! - `line_noise_ratio`: share of the power above 0.5 Hz that lies within
+//!   2 Hz of the mains frequency (Welch, one-second Hann segments).
+//! - `flat_seconds`: the longest run of identical consecutive values. A
+//!   channel is `flat` when it covers at least half the window.
+//! - `clipped_ratio`: share of samples in runs of three or more at the
+//!   window's minimum or maximum, or at or beyond `rail`. A channel is
+//!   `clipped` above 1%.
+//!
+//! A line noise ratio above 0.5 makes a channel `noisy`; statuses are
+//! reported in that order of precedence, after `no_data`.
+//!
+//! Each channel also carries its latest [impedance check](crate::impedances)
+//! (within `session_id` / `subject_id`, if given) and a warning when it is
+//! above `max_impedance`.
+
+use crate::aggregate::parse_width;
+use crate::dsp::spectrum::{Stft, Window};
+use crate::dsp::{channel_rate, segments};
+use crate::error::ApiError;
+use crate::jobs::Job;
+use crate::validation::{Parsed, Valid};
+use crate::{channels, fetch_window_samples, impedances, AppState, ChannelQuery, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, QueryBuilder};
+use std::collections::BTreeMap;
+use utoipa::IntoParams;
+
+const DEFAULT_WINDOW_SECONDS: f64 = 2.0;
+const MAX_WINDOW_SECONDS: f64 = 60.0;
+const DEFAULT_LINE: f64 = 50.0;
+
+/// Half-width of the mains band, in Hz.
+const LINE_BAND: f64 = 2.0;
+/// Power below this frequency (drift, DC) is left out of the total.
+const MIN_FREQUENCY: f64 = 0.5;
+
+const FLAT_SHARE: f64 = 0.5;
+const CLIPPED_RATIO: f64 = 0.01;
+const NOISY_RATIO: f64 = 0.5;
+/// Consecutive samples at an extreme that count as clipping.
+const CLIP_RUN: usize = 3;
+
+/// Quality parameters shared by `/quality` and the live endpoints.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct QualityQuery {
+    /// Window ending at the newest sample, e.g. `2s`.
+    quality_window: Option<String>,
+    /// Mains frequency in Hz.
+    line: Option<f64>,
+    /// Absolute value at which the amplifier rails.
+    rail: Option<f64>,
+    /// Impedance in kΩ above which a warning is added.
+    max_impedance: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub window: Duration,
+    pub line: f64,
+    pub rail: Option<f64>,
+    pub max_impedance: f64,
+}
+
+impl QualityQuery {
+    pub fn options(&self) -> Result<Options, String> {
+        let seconds = match self.quality_window.as_deref() {
+            Some(text) => parse_width(text)?,
+            None => DEFAULT_WINDOW_SECONDS,
+        };
+        if seconds > MAX_WINDOW_SECONDS {
+            return Err(format!(
+                "quality_window must be at most {}s",
+                MAX_WINDOW_SECONDS
+            ));
+        }
+        let line = self.line.unwrap_or(DEFAULT_LINE);
+        if !(line.is_finite() && line > 0.0) {
+            return Err("line must be a positive frequency in Hz".to_string());
+        }
+        if let Some(rail) = self.rail {
+            if !(rail.is_finite() && rail > 0.0) {
+                return Err("rail must be positive".to_string());
+            }
+        }
+        let max_impedance = self
+            .max_impedance
+            .unwrap_or_else(impedances::threshold_kohm);
+        if !(max_impedance.is_finite() && max_impedance > 0.0) {
+            return Err("max_impedance must be a positive number of kΩ".to_string());
+        }
+        Ok(Options {
+            window: Duration::microseconds((seconds * 1e6) as i64),
+            line,
+            rail: self.rail,
+            max_impedance,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Good,
+    NoData,
+    Flat,
+    Clipped,
+    Noisy,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Quality {
+    pub channel: String,
+    pub status: Status,
+    /// Newest sample of the channel, if any.
+    pub latest: Option<DateTime<Utc>>,
+    /// Samples in the window.
+    pub samples: usize,
+    pub sample_rate: Option<f64>,
+    pub rms: Option<f64>,
+    /// Null when the window is too short or `line` is above Nyquist.
+    pub line_noise_ratio: Option<f64>,
+    pub flat_seconds: f64,
+    pub clipped_ratio: f64,
+    /// Latest impedance check of the channel, in kΩ.
+    pub impedance_kohm: Option<f64>,
+    pub impedance_measured_at: Option<DateTime<Utc>>,
+    pub warnings: Vec<String>,
+}
+
+/// Timestamp of the channel's newest sample matching `filter`.
+pub(crate) async fn latest_ts(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let mut query = QueryBuilder::new("SELECT max(ts) FROM eeg_samples WHERE channel = ");
+    query.push_bind(channel);
+    filter.push_to(&mut query);
+    let (latest,): (Option<DateTime<Utc>>,) = query.build_query_as().fetch_one(pool).await?;
+    Ok(latest)
+}
+
+/// Share of the power above [`MIN_FREQUENCY`] within [`LINE_BAND`] of `line`.
+fn line_noise_ratio(runs: &[&[f64]], rate: f64, line: f64) -> Option<f64> {
+    if line + LINE_BAND >= rate / 2.0 {
+        return None;
+    }
+    let longest = runs.iter().map(|r| r.len()).max().unwrap_or(0);
+    let nperseg = (rate.round() as usize).min(longest);
+    let stft = Stft::new(
+        Window::Hann,
+        nperseg,
+        nperseg / 2,
+        nperseg.next_power_of_two(),
+    )
+    .ok()?;
+    let (psd, _) = stft.welch(runs, rate)?;
+    let frequencies = stft.frequencies(rate);
+    let (mut line_power, mut total) = (0.0, 0.0);
+    for (f, p) in frequencies.iter().zip(&psd) {
+        if *f >= MIN_FREQUENCY {
+            total += p;
+            if (f - line).abs() <= LINE_BAND {
+                line_power += p;
+            }
+        }
+    }
+    (total > 0.0).then(|| line_power / total)
+}
+
+/// Quality of the latest `options.window` of `channel`.
+pub async fn measure(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+    options: &Options,
+) -> Result<Quality, ApiError> {
+    let latest = latest_ts(pool, channel, filter).await?;
+    let impedance = impedances::latest(pool, channel, filter).await?;
+    let mut warnings = Vec::new();
+    if let Some(impedance) = impedance
+        .as_ref()
+        .filter(|i| i.kohm > options.max_impedance)
+    {
+        warnings.push(format!(
+            "impedance {} kΩ at {} is above {} kΩ",
+            impedance.kohm,
+            impedance.measured_at.to_rfc3339(),
+            options.max_impedance
+        ));
+    }
+    let mut quality = Quality {
+        channel: channel.to_string(),
+        status: Status::NoData,
+        latest,
+        samples: 0,
+        sample_rate: None,
+        rms: None,
+        line_noise_ratio: None,
+        flat_seconds: 0.0,
+        clipped_ratio: 0.0,
+        impedance_kohm: impedance.as_ref().map(|i| i.kohm),
+        impedance_measured_at: impedance.map(|i| i.measured_at),
+        warnings,
+    };
+    let Some(latest) = latest else {
+        return Ok(quality);
+    };
+    let window = SampleFilter {
+        from: Some(latest - options.window),
+        to: Some(latest + Duration::microseconds(1)),
+        ..*filter
+    };
+    let samples = fetch_window_samples(pool, channel, &window).await?;
+    let rate = channel_rate(pool, channel, &samples).await?;
+    quality.samples = samples.len();
+    quality.sample_rate = rate;
+    let (Some(rate), true) = (rate, samples.len() >= 2) else {
+        return Ok(quality);
+    };
+
+    let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let rms =
+        (values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64).sqrt();
+
+    let mut flat_us = 0;
+    let mut run_start = 0;
+    for i in 1..=samples.len() {
+        if i == samples.len() || samples[i].value != samples[run_start].value {
+            flat_us = flat_us.max(
+                (samples[i - 1].ts - samples[run_start].ts)
+                    .num_microseconds()
+                    .unwrap_or(0),
+            );
+            run_start = i;
+        }
+    }
+
+    let (low, high) = values
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+            (lo.min(v), hi.max(v))
+        });
+    let extreme = |v: f64| high > low && (v == low || v == high);
+    let railed = |v: f64| options.rail.is_some_and(|rail| v.abs() >= rail);
+    let mut clipped = values.iter().filter(|&&v| railed(v)).count();
+    let mut run_start = 0;
+    for i in 1..=values.len() {
+        if i == values.len() || values[i] != values[run_start] {
+            let value = values[run_start];
+            if i - run_start >= CLIP_RUN && extreme(value) && !railed(value) {
+                clipped += i - run_start;
+            }
+            run_start = i;
+        }
+    }
+
+    let runs: Vec<&[f64]> = segments(&samples, rate)
+        .into_iter()
+        .map(|r| &values[r])
+        .collect();
+    quality.rms = Some(rms);
+    quality.line_noise_ratio = line_noise_ratio(&runs, rate, options.line);
+    quality.flat_seconds = flat_us as f64 / 1e6;
+    quality.clipped_ratio = clipped as f64 / values.len() as f64;
+    let window_seconds = options.window.num_microseconds().unwrap_or(0) as f64 / 1e6;
+    quality.status = if quality.flat_seconds >= FLAT_SHARE * window_seconds {
+        Status::Flat
+    } else if quality.clipped_ratio > CLIPPED_RATIO {
+        Status::Clipped
+    } else if quality.line_noise_ratio.is_some_and(|r| r > NOISY_RATIO) {
+        Status::Noisy
+    } else {
+        Status::Good
+    };
+    Ok(quality)
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Good => "good",
+            Status::NoData => "no_data",
+            Status::Flat => "flat",
+            Status::Clipped => "clipped",
+            Status::Noisy => "noisy",
+        }
+    }
+}
+
+/// The `quality` [job](crate::jobs): measures, with the default options,
+/// every enabled channel written to since the previous scan, and logs the
+/// channels whose status changes.
+pub struct Scan {
+    pool: PgPool,
+    since: DateTime<Utc>,
+    statuses: BTreeMap<String, Status>,
+}
+
+impl Scan {
+    /// A scan whose first run looks back `every`.
+    pub fn new(pool: PgPool, every: std::time::Duration) -> Self {
+        Self {
+            pool,
+            since: Utc::now() - Duration::from_std(every).unwrap_or(Duration::zero()),
+            statuses: BTreeMap::new(),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl Job for Scan {
+    async fn run(&mut self) -> Result<String, String> {
+        let options = QualityQuery::default().options()?;
+        let started = Utc::now();
+        let filter = SampleFilter {
+            from: Some(self.since),
+            ..SampleFilter::default()
+        };
+        let mut counts = BTreeMap::new();
+        for channel in channels::enabled() {
+            let quality = measure(&self.pool, &channel, &filter, &options)
+                .await
+                .map_err(|e| format!("quality of {}: {}", channel, e.message))?;
+            if quality.status == Status::NoData {
+                continue;
+            }
+            *counts.entry(quality.status.name()).or_insert(0) += 1;
+            let previous = self.statuses.insert(channel.clone(), quality.status);
+            if previous.is_some_and(|previous| previous == quality.status) {
+                continue;
+            }
+            if quality.status == Status::Good {
+                if previous.is_some() {
+                    tracing::info!("channel {} quality is good again", channel);
+                }
+            } else {
+                tracing::warn!("channel {} quality is {}", channel, quality.status.name());
+            }
+        }
+        self.since = started;
+        if counts.is_empty() {
+            return Ok("no channel written to".to_string());
+        }
+        let counts: Vec<String> = counts
+            .into_iter()
+            .map(|(status, count)| format!("{} {}", count, status))
+            .collect();
+        Ok(counts.join(", "))
+    }
+}
+
+/// Quality of the requested channels, or of every enabled channel.
+#[utoipa::path(
+    get,
+    path = "/quality",
+    tag = "analysis",
+    params(
+        ("channel" = Option<Vec<String>>, Query, description = "Channels, comma-separated or repeated; every enabled channel by default"),
+        QualityQuery,
+        SampleFilter
+    ),
+    responses(
+        (status = 200, description = "`{ window_seconds, line, max_impedance, channels: [{ channel, status, latest, samples, rms, line_noise_ratio, flat_seconds, clipped_ratio, warnings, ... }] }`", body = serde_json::Value),
+        (status = 400, description = "Invalid parameters"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn get_quality(
+    State(state): State<AppState>,
+    Parsed(Query(params)): Parsed<Query<QualityQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bad_request = ApiError::bad_request;
+    let channels = if raw.values("channel").is_empty() {
+        channels::enabled()
+    } else {
+        raw.channels().map_err(bad_request)?
+    };
+    let options = params.options().map_err(bad_request)?;
+
+    let mut results = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        results.push(measure(state.reader(), channel, &filter, &options).await?);
+    }
+    Ok(Json(json!({
+        "window_seconds": options.window.num_microseconds().unwrap_or(0) as f64 / 1e6,
+        "line": options.line,
+        "max_impedance": options.max_impedance,
+        "channels": results,
+    })))
+}
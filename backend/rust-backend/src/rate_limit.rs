@@ -0,0 +1,207 @@
+//! Per-client rate limits on the data routes (token buckets).
+//!
+//! Requests with a device API key are counted against the key, everything
+//! else against the client address, so a dashboard polling in a tight loop
+//! uses up its own allowance and not that of the devices ingesting samples.
+//! Each client may make `burst` requests at once and `requests_per_second`
+//! on average ([`RateLimitConfig`]); further requests are answered with 429
+//! and a `Retry-After` header. Health probes and `/metrics` are not limited;
+//! a WebSocket or SSE stream counts once, when it is opened.
+
+use crate::config::{self, RateLimitConfig};
+use crate::devices::keys::ApiKey;
+use crate::error::ApiError;
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often full buckets are dropped.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Client {
+    ApiKey(i32),
+    Address(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+static BUCKETS: Mutex<BTreeMap<Client, Bucket>> = Mutex::new(BTreeMap::new());
+
+/// The client address: the peer, or the last `X-Forwarded-For` entry when
+/// the proxy in front of the backend is trusted to set it.
+fn address(request: &Request, config: &RateLimitConfig) -> Option<IpAddr> {
+    if config.trust_forwarded_for {
+        let forwarded = request
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .next_back()
+            .and_then(|entry| entry.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip())
+}
+
+/// Takes a token from the bucket of `client`, or returns how long until
+/// one is available.
+fn take(client: Client, rate: f64, burst: u32) -> Result<(), Duration> {
+    let burst = f64::from(burst);
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap();
+    let bucket = buckets.entry(client).or_insert(Bucket {
+        tokens: burst,
+        updated: now,
+    });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+}
+
+/// Middleware enforcing the limits; runs after [`auth::require`](crate::auth::require),
+/// which identifies API keys.
+pub async fn limit(request: Request, next: Next) -> Response {
+    let config = &config::get().rate_limit;
+    let (client, rate, burst) = match request.extensions().get::<ApiKey>() {
+        Some(key) => (
+            Client::ApiKey(key.id),
+            config.api_key_requests_per_second,
+            config.api_key_burst,
+        ),
+        None => match address(&request, config) {
+            Some(address) => (
+                Client::Address(address),
+                config.requests_per_second,
+                config.burst,
+            ),
+            None => return next.run(request).await,
+        },
+    };
+    if rate <= 0.0 {
+        return next.run(request).await;
+    }
+    match take(client, rate, burst) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let error = ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("rate limit exceeded; retry in {} s", seconds),
+            )
+            .with_details(json!({ "retry_after_secs": seconds }));
+            ([(header::RETRY_AFTER, seconds.to_string())], error).into_response()
+        }
+    }
+}
+
+/// Starts the task that drops the buckets of clients that have been idle
+/// long enough for them to be full again.
+pub fn spawn_cleanup() {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(CLEANUP_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let config = &config::get().rate_limit;
+            let now = Instant::now();
+            BUCKETS.lock().unwrap().retain(|client, bucket| {
+                let (rate, burst) = match client {
+                    Client::ApiKey(_) => (config.api_key_requests_per_second, config.api_key_burst),
+                    Client::Address(_) => (config.requests_per_second, config.burst),
+                };
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * rate < f64::from(burst)
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn request(forwarded: Option<&str>) -> Request {
+        let mut builder = Request::builder().uri("/samples");
+        if let Some(value) = forwarded {
+            builder = builder.header("x-forwarded-for", value);
+        }
+        let mut request = builder.body(Body::empty()).unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 40000));
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill_at_the_rate() {
+        let client = Client::ApiKey(-1);
+        assert_eq!(take(client, 20.0, 2), Ok(()));
+        assert_eq!(take(client, 20.0, 2), Ok(()));
+        let wait = take(client, 20.0, 2).unwrap_err();
+        assert!(
+            wait > Duration::ZERO && wait <= Duration::from_millis(50),
+            "{:?}",
+            wait
+        );
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(take(client, 20.0, 2), Ok(()));
+        assert!(take(client, 20.0, 2).is_err());
+
+        // A long pause refills the bucket to its burst, not beyond.
+        BUCKETS.lock().unwrap().get_mut(&client).unwrap().updated -= Duration::from_secs(60);
+        assert_eq!(take(client, 20.0, 2), Ok(()));
+        assert_eq!(take(client, 20.0, 2), Ok(()));
+        assert!(take(client, 20.0, 2).is_err());
+    }
+
+    #[test]
+    fn keys_and_addresses_have_their_own_buckets() {
+        let key = Client::ApiKey(-2);
+        let address = Client::Address(IpAddr::from([192, 0, 2, 7]));
+        let other = Client::Address(IpAddr::from([192, 0, 2, 8]));
+        assert_eq!(take(key, 0.01, 1), Ok(()));
+        assert!(take(key, 0.01, 1).is_err());
+        assert_eq!(take(address, 0.01, 1), Ok(()));
+        assert!(take(address, 0.01, 1).is_err());
+        assert_eq!(take(other, 0.01, 1), Ok(()));
+    }
+
+    #[test]
+    fn forwarded_addresses_count_only_behind_a_trusted_proxy() {
+        let mut config = RateLimitConfig::default();
+        let forwarded = request(Some("198.51.100.1, 203.0.113.9"));
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(address(&forwarded, &config), Some(peer));
+        config.trust_forwarded_for = true;
+        assert_eq!(
+            address(&forwarded, &config),
+            Some(IpAddr::from([203, 0, 113, 9]))
+        );
+        assert_eq!(address(&request(Some("garbage")), &config), Some(peer));
+        assert_eq!(address(&request(None), &config), Some(peer));
+    }
+}
@@ -0,0 +1,435 @@
+//! `GET /sessions/{id}/replay`: a WebSocket playing a stored session back
+//! as a live stream, for reviewing recordings in the live UI.
+//!
+//! Samples and events are sent with their stored timestamps, at the pace
+//! they were recorded times `speed`: the replay clock starts at the
+//! session's first sample or event, and every [`TICK`] the stream sends
+//! what was recorded up to the clock's position, as `points` messages like
+//! those of `/live/ws` and one `event` message per event. Clients pause,
+//! resume, seek and change the speed with control messages. Samples packed
+//! into [blocks](crate::store::blocks) play as the rows do.
+
+use crate::error::ApiError;
+use crate::events::Event;
+use crate::store::blocks;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{metrics, shutdown, AppState, ChannelQuery, EegSample, LivePoint, SampleFilter};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+use utoipa::IntoParams;
+
+/// How often the stream sends what the clock passed.
+const TICK: Duration = Duration::from_millis(50);
+/// Most samples read per tick; a stream that falls behind catches up on
+/// the following ticks.
+const MAX_ROWS: i64 = 20_000;
+const MAX_SPEED: f64 = 100.0;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReplayQuery {
+    /// Pace relative to the recording; 2 plays twice as fast.
+    speed: Option<f64>,
+    /// Seconds into the recording to start at.
+    offset: Option<f64>,
+}
+
+fn check_speed(speed: f64) -> Result<(), String> {
+    if speed > 0.0 && speed <= MAX_SPEED {
+        Ok(())
+    } else {
+        Err(format!("must be above 0 and at most {}", MAX_SPEED))
+    }
+}
+
+impl Validate for ReplayQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Err(e) = self.speed.map_or(Ok(()), check_speed) {
+            errors.add("speed", e);
+        }
+        errors.check(
+            self.offset.is_none_or(|o| o >= 0.0),
+            "offset",
+            "must not be negative",
+        );
+    }
+}
+
+/// Messages a client may send.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ClientMessage {
+    Pause,
+    Resume,
+    /// Jumps to `offset` seconds into the recording.
+    Seek {
+        offset: f64,
+    },
+    Speed {
+        speed: f64,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// Sent on connecting and after each control message.
+    Replay {
+        session_id: i32,
+        channels: Vec<String>,
+        /// First and last sample or event of the recording.
+        started_at: DateTime<Utc>,
+        ended_at: DateTime<Utc>,
+        duration_seconds: f64,
+        speed: f64,
+        /// Position of the clock, in seconds into the recording.
+        offset: f64,
+        paused: bool,
+    },
+    Points {
+        channel: String,
+        points: Vec<LivePoint>,
+        last_id: i32,
+    },
+    Event(Event),
+    /// The clock reached the end of the recording; seeking plays on.
+    Ended,
+    Error {
+        message: String,
+    },
+}
+
+/// Position in the recording, in µs after its start, moving at `speed`
+/// while not paused.
+struct Clock {
+    /// Position at `since`.
+    at_us: i64,
+    since: Instant,
+    speed: f64,
+    paused: bool,
+}
+
+impl Clock {
+    fn position_us(&self) -> i64 {
+        if self.paused {
+            return self.at_us;
+        }
+        self.at_us + (self.since.elapsed().as_secs_f64() * self.speed * 1e6) as i64
+    }
+
+    fn set(&mut self, at_us: i64) {
+        self.at_us = at_us;
+        self.since = Instant::now();
+    }
+}
+
+struct Replay {
+    session_id: i32,
+    channels: Vec<String>,
+    started_at: DateTime<Utc>,
+    ended_at: DateTime<Utc>,
+    clock: Clock,
+    /// Last sample and event sent, by timestamp and id.
+    samples_sent: (DateTime<Utc>, i32),
+    events_sent: (DateTime<Utc>, i32),
+    ended: bool,
+}
+
+impl Replay {
+    fn duration_us(&self) -> i64 {
+        (self.ended_at - self.started_at)
+            .num_microseconds()
+            .unwrap_or(i64::MAX)
+    }
+
+    fn state(&self) -> ServerMessage {
+        ServerMessage::Replay {
+            session_id: self.session_id,
+            channels: self.channels.clone(),
+            started_at: self.started_at,
+            ended_at: self.ended_at,
+            duration_seconds: self.duration_us() as f64 / 1e6,
+            speed: self.clock.speed,
+            offset: self.clock.position_us().min(self.duration_us()) as f64 / 1e6,
+            paused: self.clock.paused,
+        }
+    }
+
+    /// Moves the clock to `offset` seconds; what precedes it is not sent.
+    fn seek(&mut self, offset: f64) -> Result<(), String> {
+        let at_us = (offset * 1e6) as i64;
+        if !(0..=self.duration_us()).contains(&at_us) {
+            return Err(format!(
+                "offset must be 0 to {} seconds",
+                self.duration_us() as f64 / 1e6
+            ));
+        }
+        self.clock.set(at_us);
+        let at = self.started_at + chrono::Duration::microseconds(at_us);
+        self.samples_sent = (at, 0);
+        self.events_sent = (at, 0);
+        if std::mem::take(&mut self.ended) {
+            self.clock.paused = false;
+        }
+        Ok(())
+    }
+
+    fn control(&mut self, message: ClientMessage) -> Result<(), String> {
+        match message {
+            ClientMessage::Pause => {
+                let at_us = self.clock.position_us();
+                self.clock.set(at_us);
+                self.clock.paused = true;
+            }
+            ClientMessage::Resume => {
+                self.clock.since = Instant::now();
+                self.clock.paused = false;
+            }
+            ClientMessage::Seek { offset } => self.seek(offset)?,
+            ClientMessage::Speed { speed } => {
+                check_speed(speed).map_err(|e| format!("speed {}", e))?;
+                let at_us = self.clock.position_us();
+                self.clock.set(at_us);
+                self.clock.speed = speed;
+            }
+        }
+        Ok(())
+    }
+
+    /// What was recorded between the last message and the clock, oldest
+    /// first.
+    async fn advance(&mut self, pool: &PgPool) -> Result<Vec<ServerMessage>, sqlx::Error> {
+        if self.ended {
+            return Ok(Vec::new());
+        }
+        let position_us = self.clock.position_us();
+        let until = self.started_at + chrono::Duration::microseconds(position_us);
+        let mut messages = Vec::new();
+
+        let events: Vec<Event> = sqlx::query_as(
+            "SELECT id, session_id, ts, duration, label, metadata FROM events \
+             WHERE session_id = $1 AND (ts, id) > ($2, $3) AND ts <= $4 ORDER BY ts, id",
+        )
+        .bind(self.session_id)
+        .bind(self.events_sent.0)
+        .bind(self.events_sent.1)
+        .bind(until)
+        .fetch_all(pool)
+        .await?;
+        if let Some(last) = events.last() {
+            self.events_sent = (last.ts, last.id);
+        }
+        messages.extend(events.into_iter().map(ServerMessage::Event));
+
+        let query = sqlx::query_as(
+            "SELECT id, ts, channel, value FROM eeg_samples \
+             WHERE session_id = $1 AND channel = ANY($2) AND (ts, id) > ($3, $4) AND ts <= $5 ORDER BY ts, id LIMIT $6",
+        )
+        .bind(self.session_id)
+        .bind(&self.channels)
+        .bind(self.samples_sent.0)
+        .bind(self.samples_sent.1)
+        .bind(until)
+        .bind(MAX_ROWS);
+        let filter = SampleFilter {
+            from: Some(self.samples_sent.0),
+            to: Some(until + chrono::Duration::microseconds(1)),
+            session_id: Some(self.session_id),
+            subject_id: None,
+        };
+        let mut packed = blocks::select(Some(&self.channels), &filter);
+        let mut samples = blocks::Merged::new(
+            query.fetch(pool),
+            packed.build_query_as().fetch(pool),
+            filter,
+        );
+        let mut rows: Vec<EegSample> = Vec::new();
+        while (rows.len() as i64) < MAX_ROWS {
+            match samples.next().await? {
+                Some(s) if (s.ts, s.id) > self.samples_sent => rows.push(s),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let caught_up = (rows.len() as i64) < MAX_ROWS;
+        if let Some(last) = rows.last() {
+            self.samples_sent = (last.ts, last.id);
+        }
+        let mut by_channel: BTreeMap<String, Vec<LivePoint>> = BTreeMap::new();
+        for row in rows {
+            by_channel.entry(row.channel).or_default().push(LivePoint {
+                id: row.id,
+                ts: row.ts,
+                value: row.value,
+            });
+        }
+        messages.extend(by_channel.into_iter().map(|(channel, points)| {
+            let last_id = points.last().map_or(0, |p| p.id);
+            ServerMessage::Points {
+                channel,
+                points,
+                last_id,
+            }
+        }));
+
+        if caught_up && position_us >= self.duration_us() {
+            self.ended = true;
+            let end = self.duration_us();
+            self.clock.set(end);
+            self.clock.paused = true;
+            messages.push(ServerMessage::Ended);
+        }
+        Ok(messages)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/{id}/replay",
+    tag = "sessions",
+    params(
+        ("id" = i32, Path),
+        ("channel" = Option<Vec<String>>, Query, description = "Repeatable; default every channel of the session"),
+        ReplayQuery
+    ),
+    responses(
+        (status = 101, description = "WebSocket of `replay`, `points`, `event` and `ended` messages; clients may send `pause`, `resume`, `seek` and `speed`"),
+        (status = 400, description = "The session has no samples"),
+        (status = 404, description = "Unknown session"),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn replay_ws(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Valid(Query(params)): Valid<Query<ReplayQuery>>,
+    Valid(Query(raw)): Valid<Query<ChannelQuery>>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let pool = state.reader();
+    let exists: Option<i32> = sqlx::query_scalar("SELECT id FROM sessions WHERE id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    if exists.is_none() {
+        return Err(ApiError::not_found(format!("unknown session {}", id)));
+    }
+    let mut channels: Vec<String> = Vec::new();
+    for name in raw.values("channel") {
+        if !channels.iter().any(|c| c == name) {
+            channels.push(name.to_string());
+        }
+    }
+    if channels.is_empty() {
+        channels = sqlx::query_scalar(
+            "SELECT channel FROM eeg_samples WHERE session_id = $1 \
+             UNION SELECT channel FROM sample_blocks WHERE session_id = $1 ORDER BY channel",
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+    }
+    let (started_at, ended_at): (Option<DateTime<Utc>>, Option<DateTime<Utc>>) = sqlx::query_as(
+        "SELECT min(ts), max(ts) FROM ( \
+               SELECT ts FROM eeg_samples WHERE session_id = $1 AND channel = ANY($2) \
+               UNION ALL SELECT start_ts FROM sample_blocks WHERE session_id = $1 AND channel = ANY($2) \
+               UNION ALL SELECT end_ts FROM sample_blocks WHERE session_id = $1 AND channel = ANY($2) \
+               UNION ALL SELECT ts FROM events WHERE session_id = $1 \
+             ) recorded",
+    )
+    .bind(id)
+    .bind(&channels)
+    .fetch_one(pool)
+    .await?;
+    let (Some(started_at), Some(ended_at)) = (started_at, ended_at) else {
+        return Err(ApiError::bad_request(format!(
+            "session {} has no samples",
+            id
+        )));
+    };
+
+    let mut replay = Replay {
+        session_id: id,
+        channels,
+        started_at,
+        ended_at,
+        clock: Clock {
+            at_us: 0,
+            since: Instant::now(),
+            speed: params.speed.unwrap_or(1.0),
+            paused: false,
+        },
+        samples_sent: (started_at, 0),
+        events_sent: (started_at, 0),
+        ended: false,
+    };
+    if let Some(offset) = params.offset {
+        replay.seek(offset).map_err(ApiError::bad_request)?;
+    }
+    Ok(ws.on_upgrade(move |socket| replay_session(socket, state, replay)))
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> bool {
+    let text = match serde_json::to_string(message) {
+        Ok(text) => text,
+        Err(e) => {
+            tracing::error!("failed to encode websocket message: {}", e);
+            return true;
+        }
+    };
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+async fn replay_session(mut socket: WebSocket, state: AppState, mut replay: Replay) {
+    let _client = metrics::LiveClient::connect("/sessions/replay");
+    replay.clock.since = Instant::now();
+    let mut ticker = tokio::time::interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut stopping = std::pin::pin!(shutdown::requested());
+    let mut replies = vec![replay.state()];
+    loop {
+        for reply in replies.drain(..) {
+            if !send(&mut socket, &reply).await {
+                return;
+            }
+        }
+
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(message) => match replay.control(message) {
+                            Ok(()) => replay.state(),
+                            Err(message) => ServerMessage::Error { message },
+                        },
+                        Err(e) => ServerMessage::Error { message: e.to_string() },
+                    };
+                    replies.push(reply);
+                }
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {}
+            },
+            _ = ticker.tick(), if !replay.clock.paused => {
+                match replay.advance(state.reader()).await {
+                    Ok(messages) => replies = messages,
+                    Err(e) => replies.push(ServerMessage::Error { message: e.to_string() }),
+                }
+            }
+            _ = &mut stopping => {
+                let _ = socket.send(shutdown::going_away()).await;
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Retention policies for raw samples and aggregate tiers.
+//!
+//! Policies live in `retention_policies` and are enforced by the `retention`
+//! [job](crate::jobs), hourly by default: raw samples are removed with
+//! `drop_chunks` on TimescaleDB (whole chunks, cheap) and with `DELETE`
+//! otherwise, and their packed [blocks](crate::store::blocks) with `DELETE`;
+//! tier tables are always pruned with `DELETE`. The job also forgets the
+//! [ingest](crate::ingest) batch sequence numbers older than
+//! `ingest.sequence_window_secs`.
+
+use crate::aggregate;
+use crate::cache;
+use crate::config;
+use crate::error::ApiError;
+use crate::ingest;
+use crate::jobs::Job;
+use crate::ring;
+use crate::tiers::TIERS;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::AppState;
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+/// Outcome of the latest enforcement pass per target.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunInfo {
+    pub ran_at: String,
+    pub removed: Option<i64>,
+    pub error: Option<String>,
+}
+
+pub type RetentionStatus = Arc<Mutex<HashMap<String, RunInfo>>>;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Policy {
+    pub target: String,
+    pub max_age_seconds: i64,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PolicyUpdate {
+    /// Maximum age such as `7d`, `12h` or `365d`.
+    max_age: Option<String>,
+    enabled: Option<bool>,
+}
+
+impl Validate for PolicyUpdate {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(Err(e)) = self.max_age.as_deref().map(aggregate::parse_width) {
+            errors.add("max_age", e);
+        }
+    }
+}
+
+/// Table that a policy target prunes: `raw` or one of the tier names.
+fn table_for(target: &str) -> Option<&'static str> {
+    if target == "raw" {
+        return Some("eeg_samples");
+    }
+    TIERS.iter().find(|t| t.name == target).map(|t| t.table)
+}
+
+async fn load_policies(pool: &PgPool) -> Result<Vec<Policy>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT target, max_age_seconds, enabled FROM retention_policies ORDER BY target",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Removes data older than `cutoff` for one target, returning rows removed
+/// when the database reports them; a raw block counts as one.
+async fn enforce_one(
+    pool: &PgPool,
+    timescale: bool,
+    target: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<Option<i64>, sqlx::Error> {
+    let Some(table) = table_for(target) else {
+        return Ok(None);
+    };
+    // Blocks go once their last sample is past the cutoff.
+    let packed = if table == "eeg_samples" {
+        sqlx::query("DELETE FROM sample_blocks WHERE end_ts < $1")
+            .bind(cutoff)
+            .execute(pool)
+            .await?
+            .rows_affected() as i64
+    } else {
+        0
+    };
+    if table == "eeg_samples" && timescale {
+        let dropped: Vec<(String,)> =
+            sqlx::query_as("SELECT drop_chunks('eeg_samples', older_than => $1)::TEXT")
+                .bind(cutoff)
+                .fetch_all(pool)
+                .await?;
+        tracing::info!(
+            "retention: dropped {} raw chunks and {} blocks",
+            dropped.len(),
+            packed
+        );
+        ring::forget_before(cutoff);
+        cache::invalidate_all();
+        return Ok(None);
+    }
+    let column = if table == "eeg_samples" {
+        "ts"
+    } else {
+        "bucket"
+    };
+    let result = sqlx::query(&format!("DELETE FROM {} WHERE {} < $1", table, column))
+        .bind(cutoff)
+        .execute(pool)
+        .await?;
+    if table == "eeg_samples" {
+        ring::forget_before(cutoff);
+        cache::invalidate_all();
+    }
+    Ok(Some(result.rows_affected() as i64 + packed))
+}
+
+/// Applies every enabled policy once and records the outcome, returning the
+/// policies applied and the rows deleted.
+pub async fn enforce(
+    pool: &PgPool,
+    timescale: bool,
+    status: &RetentionStatus,
+) -> Result<(usize, i64), String> {
+    let policies = load_policies(pool)
+        .await
+        .map_err(|e| format!("cannot load retention policies: {}", e))?;
+    let mut applied = 0;
+    let mut removed = 0;
+    let mut failed = Vec::new();
+    for policy in policies.into_iter().filter(|p| p.enabled) {
+        applied += 1;
+        let now = Utc::now();
+        let cutoff = now - chrono::Duration::seconds(policy.max_age_seconds);
+        let outcome = enforce_one(pool, timescale, &policy.target, cutoff).await;
+        if let Err(e) = &outcome {
+            tracing::error!("retention for {} failed: {}", policy.target, e);
+            failed.push(policy.target.clone());
+        }
+        let info = RunInfo {
+            ran_at: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            removed: outcome.as_ref().ok().copied().flatten(),
+            error: outcome.err().map(|e| e.to_string()),
+        };
+        removed += info.removed.unwrap_or(0);
+        status.lock().unwrap().insert(policy.target, info);
+    }
+    if failed.is_empty() {
+        Ok((applied, removed))
+    } else {
+        Err(format!("retention failed for {}", failed.join(", ")))
+    }
+}
+
+/// The `retention` [job](crate::jobs).
+pub struct Enforce {
+    pub pool: PgPool,
+    pub timescale: bool,
+    pub status: RetentionStatus,
+}
+
+#[axum::async_trait]
+impl Job for Enforce {
+    async fn run(&mut self) -> Result<String, String> {
+        let (applied, removed) = enforce(&self.pool, self.timescale, &self.status).await?;
+        let window = config::get().ingest.sequence_window_secs;
+        let cutoff = Utc::now() - chrono::Duration::seconds(window as i64);
+        let forgotten = ingest::prune_batches(&self.pool, cutoff)
+            .await
+            .map_err(|e| format!("cannot prune ingest batches: {}", e))?;
+        Ok(format!(
+            "{} policies applied, {} rows deleted, {} batch sequence numbers forgotten",
+            applied, removed, forgotten
+        ))
+    }
+}
+
+fn policy_json(policy: &Policy, status: &HashMap<String, RunInfo>) -> serde_json::Value {
+    serde_json::json!({
+        "target": policy.target,
+        "max_age_seconds": policy.max_age_seconds,
+        "enabled": policy.enabled,
+        "last_run": status.get(&policy.target),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/retention",
+    tag = "admin",
+    responses((status = 200, description = "`{ policies: [{ target, max_age_seconds, enabled, last_run: { ran_at, removed, error } }] }`", body = serde_json::Value))
+)]
+pub async fn list_policies(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let policies = load_policies(&state.pool).await?;
+    let status = state.retention.lock().unwrap().clone();
+    let policies: Vec<_> = policies.iter().map(|p| policy_json(p, &status)).collect();
+    Ok(Json(serde_json::json!({ "policies": policies })))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/retention/{target}",
+    tag = "admin",
+    params(("target" = String, Path, description = "`raw` or a tier name")),
+    request_body = PolicyUpdate,
+    responses(
+        (status = 200, description = "`{ target, max_age_seconds, enabled, last_run }`", body = serde_json::Value),
+        (status = 404, description = "Unknown target"),
+        (status = 422, description = "Invalid `max_age`, named in `details.fields`")
+    )
+)]
+pub async fn update_policy(
+    State(state): State<AppState>,
+    Parsed(Path(target)): Parsed<Path<String>>,
+    Valid(Json(update)): Valid<Json<PolicyUpdate>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if table_for(&target).is_none() {
+        return Err(ApiError::not_found(format!(
+            "unknown retention target {:?}",
+            target
+        )));
+    }
+    let max_age = update
+        .max_age
+        .as_deref()
+        .map(aggregate::parse_width)
+        .transpose()
+        .map_err(ApiError::bad_request)?
+        .map(|secs| secs.round() as i64);
+
+    let policy: Policy = sqlx::query_as(
+        "INSERT INTO retention_policies (target, max_age_seconds, enabled) \
+         VALUES ($1, COALESCE($2, 604800), COALESCE($3, TRUE)) \
+         ON CONFLICT (target) DO UPDATE SET \
+         max_age_seconds = COALESCE($2, retention_policies.max_age_seconds), \
+         enabled = COALESCE($3, retention_policies.enabled) \
+         RETURNING target, max_age_seconds, enabled",
+    )
+    .bind(&target)
+    .bind(max_age)
+    .bind(update.enabled)
+    .fetch_one(&state.pool)
+    .await?;
+
+    let status = state.retention.lock().unwrap().clone();
+    Ok(Json(policy_json(&policy, &status)))
+}
@@ -0,0 +1,196 @@
+//! In-memory window of the latest samples per channel, for live polling.
+//!
+//! Every ingest path records the samples it stores, with their ids, and the
+//! latest `streaming.buffer_samples` of each channel are kept. A live poll
+//! (`/live`, its WebSocket and SSE streams, and the gRPC and GraphQL
+//! subscriptions) whose `since_id` falls within a channel's window is
+//! answered from memory; a first poll, an older range or a `subject_id`
+//! filter reads the database. A window holds every sample of its channel
+//! with an id above its floor, so both give the same points.
+//!
+//! Imports do not record their samples. Once one finishes, the windows of
+//! its channels are emptied and start again with the next insert that
+//! begins afterwards, whose ids follow the imported ones. A channel that
+//! another backend or client writes to, as [notified](crate::notify), is
+//! no longer kept: this backend does not see all of its samples.
+
+use crate::config;
+use crate::ingest::NewSample;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+struct Entry {
+    id: i32,
+    ts: DateTime<Utc>,
+    value: f64,
+    session_id: Option<i32>,
+}
+
+/// The samples of one channel with ids above `floor`, by id; none are
+/// vouched for until the first is recorded.
+#[derive(Default)]
+struct Window {
+    floor: Option<i32>,
+    entries: VecDeque<Entry>,
+    /// When the window was last emptied; inserts that began earlier may
+    /// have ids below the unrecorded ones.
+    fence: Option<Instant>,
+    /// Written by others; nothing is kept.
+    shared: bool,
+}
+
+impl Window {
+    /// Drops the entries up to `id` and no longer vouches for them.
+    fn raise_floor(&mut self, id: i32) {
+        if self.floor.is_some_and(|floor| id > floor) {
+            self.floor = Some(id);
+            let keep = self.entries.partition_point(|e| e.id <= id);
+            self.entries.drain(..keep);
+        }
+    }
+}
+
+static WINDOWS: Mutex<BTreeMap<String, Window>> = Mutex::new(BTreeMap::new());
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Records stored samples and their ids, from an insert that began at
+/// `started`.
+pub fn record<'a>(started: Instant, stored: impl IntoIterator<Item = (i32, &'a NewSample)>) {
+    let capacity = config::get().streaming.buffer_samples;
+    if capacity == 0 {
+        return;
+    }
+    let mut windows = WINDOWS.lock().unwrap();
+    for (id, sample) in stored {
+        let window = match windows.get_mut(&sample.channel) {
+            Some(window) => window,
+            None => windows.entry(sample.channel.clone()).or_default(),
+        };
+        if window.shared || window.fence.is_some_and(|fence| started < fence) {
+            continue;
+        }
+        let floor = *window.floor.get_or_insert(id - 1);
+        if id <= floor {
+            continue;
+        }
+        // Concurrent inserts may finish out of id order.
+        let at = window.entries.partition_point(|e| e.id < id);
+        if window.entries.get(at).is_some_and(|e| e.id == id) {
+            continue;
+        }
+        window.entries.insert(
+            at,
+            Entry {
+                id,
+                ts: sample.ts,
+                value: sample.value,
+                session_id: sample.session_id,
+            },
+        );
+        while window.entries.len() > capacity {
+            if let Some(oldest) = window.entries.pop_front() {
+                window.floor = Some(oldest.id);
+            }
+        }
+    }
+}
+
+/// Empties the windows of `channels`, into which samples were stored
+/// without being recorded.
+pub fn forget<'a>(channels: impl IntoIterator<Item = &'a str>) {
+    let now = Instant::now();
+    let mut windows = WINDOWS.lock().unwrap();
+    for channel in channels {
+        let window = Window {
+            fence: Some(now),
+            shared: windows.get(channel).is_some_and(|w| w.shared),
+            ..Window::default()
+        };
+        windows.insert(channel.to_string(), window);
+    }
+}
+
+/// Stops keeping `channel`, which is also written elsewhere. Returns
+/// whether it was kept until now.
+pub fn share(channel: &str) -> bool {
+    let mut windows = WINDOWS.lock().unwrap();
+    let window = windows.entry(channel.to_string()).or_default();
+    let newly = !window.shared;
+    *window = Window {
+        shared: true,
+        ..Window::default()
+    };
+    newly
+}
+
+/// Forgets samples older than `cutoff`, which retention has removed.
+pub fn forget_before(cutoff: DateTime<Utc>) {
+    let mut windows = WINDOWS.lock().unwrap();
+    for window in windows.values_mut() {
+        let newest_expired = window
+            .entries
+            .iter()
+            .filter(|e| e.ts < cutoff)
+            .map(|e| e.id)
+            .max();
+        if let Some(id) = newest_expired {
+            window.raise_floor(id);
+        }
+    }
+}
+
+/// What a live poll selects besides its channel.
+pub struct Select {
+    pub since_id: i32,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub session_id: Option<i32>,
+    pub limit: usize,
+}
+
+/// `(id, ts, value)` of up to `limit` samples of `channel` after `since_id`,
+/// by id, or `None` when the window does not reach back to `since_id`.
+pub fn since(channel: &str, select: &Select) -> Option<Vec<(i32, DateTime<Utc>, f64)>> {
+    let windows = WINDOWS.lock().unwrap();
+    let Some(window) = windows
+        .get(channel)
+        .filter(|w| w.floor.is_some_and(|floor| select.since_id >= floor))
+    else {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        return None;
+    };
+    HITS.fetch_add(1, Ordering::Relaxed);
+    let start = window.entries.partition_point(|e| e.id <= select.since_id);
+    Some(
+        window
+            .entries
+            .range(start..)
+            .filter(|e| select.from.is_none_or(|from| e.ts >= from))
+            .filter(|e| select.to.is_none_or(|to| e.ts < to))
+            .filter(|e| select.session_id.is_none() || e.session_id == select.session_id)
+            .take(select.limit)
+            .map(|e| (e.id, e.ts, e.value))
+            .collect(),
+    )
+}
+
+/// Live polls answered from memory and from the database, and samples held.
+pub fn stats() -> (u64, u64, usize) {
+    let held = WINDOWS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|w| w.entries.len())
+        .sum();
+    (
+        HITS.load(Ordering::Relaxed),
+        MISSES.load(Ordering::Relaxed),
+        held,
+    )
+}
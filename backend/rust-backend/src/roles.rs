@@ -0,0 +1,378 @@
+//! Role-based access control for authenticated requests.
+//!
+//! Users hold roles through `role_assignments`, keyed by the `sub` claim of
+//! their tokens; a token's roles are looked up when it is verified. Each
+//! route needs one of the roles [`allowed`] lists for it:
+//!
+//! - `device` — sample ingest, and nothing else
+//! - `admin` — `/admin/*`, changes to devices and channels, saved
+//!   pipelines (which register the channels they derive) and alert rules
+//!   (which call webhooks)
+//! - `clinician` — changes to subjects and sessions, including imports and
+//!   applied ICA decompositions, which create sessions; the only role that
+//!   sees subject-identifying fields (see [`may_identify`])
+//! - `clinician` or `researcher` — changes to events
+//! - `researcher` — everything else, including montages, which `admin` and
+//!   `clinician` may do too
+//!
+//! Imports that register channels not known yet also need `admin`; their
+//! handlers check that through [`check`].
+//!
+//! Device API keys count as `device`. While authentication is off there is
+//! no principal and nothing is enforced. Like the device registry,
+//! assignments are cached in memory and reloaded periodically.
+
+use crate::auth::{self, Principal};
+use crate::error::ApiError;
+use crate::validation::Parsed;
+use crate::{versioning, AppState};
+use axum::{
+    extract::{Path, State},
+    http::Method,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+static ASSIGNMENTS: RwLock<BTreeMap<String, Vec<Role>>> = RwLock::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Clinician,
+    Researcher,
+    Device,
+}
+
+/// Roles that may read data and run analyses.
+pub const READERS: &[Role] = &[Role::Admin, Role::Clinician, Role::Researcher];
+
+/// Roles that may ingest samples.
+pub const INGEST: &[Role] = &[Role::Device];
+
+/// Roles that may annotate recordings with events.
+pub const ANNOTATORS: &[Role] = &[Role::Clinician, Role::Researcher];
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Clinician => "clinician",
+            Role::Researcher => "researcher",
+            Role::Device => "device",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "clinician" => Ok(Role::Clinician),
+            "researcher" => Ok(Role::Researcher),
+            "device" => Ok(Role::Device),
+            _ => Err(format!(
+                "unknown role {:?}; expected admin, clinician, researcher or device",
+                s
+            )),
+        }
+    }
+}
+
+/// Roles that may call `method` on the route `path` (its pattern, as in
+/// `/v1/subjects/:id`, with or without the version prefix).
+pub fn allowed(method: &Method, path: &str) -> &'static [Role] {
+    let path = versioning::unversioned(path);
+    let read = method == Method::GET || method == Method::HEAD;
+    if method == Method::POST && auth::INGEST_ROUTES.contains(&path) {
+        return INGEST;
+    }
+    if path.starts_with("/admin/") {
+        return &[Role::Admin];
+    }
+    if read {
+        return READERS;
+    }
+    let area = |prefix: &str| path.starts_with(prefix);
+    if area("/devices") || area("/channels") || area("/pipelines") || area("/alerts") {
+        return &[Role::Admin];
+    }
+    if area("/subjects")
+        || area("/sessions")
+        || area("/import/")
+        || path == "/analysis/ica/:id/apply"
+    {
+        return &[Role::Clinician];
+    }
+    if area("/events") {
+        return ANNOTATORS;
+    }
+    // Montages only derive what readers may read anyway.
+    READERS
+}
+
+/// Checks that `principal` holds one of `roles`.
+pub fn check(principal: &Principal, roles: &[Role]) -> Result<(), String> {
+    if principal.roles.iter().any(|role| roles.contains(role)) {
+        return Ok(());
+    }
+    let names: Vec<&str> = roles.iter().map(|role| role.as_str()).collect();
+    Err(format!("requires the role {}", names.join(" or ")))
+}
+
+/// Whether the caller may see subject-identifying fields: clinicians may,
+/// and everyone while authentication is off.
+pub fn may_identify(principal: Option<&Principal>) -> bool {
+    principal.is_none_or(|p| p.roles.contains(&Role::Clinician))
+}
+
+/// The cached roles of `principal`.
+pub fn of(principal: &str) -> Vec<Role> {
+    ASSIGNMENTS
+        .read()
+        .unwrap()
+        .get(principal)
+        .cloned()
+        .unwrap_or_default()
+}
+
+async fn fetch(pool: &PgPool) -> Result<BTreeMap<String, Vec<Role>>, sqlx::Error> {
+    let rows: Vec<(String, String)> =
+        sqlx::query_as("SELECT principal, role FROM role_assignments ORDER BY principal, role")
+            .fetch_all(pool)
+            .await?;
+    let mut assignments: BTreeMap<String, Vec<Role>> = BTreeMap::new();
+    for (principal, role) in rows {
+        if let Ok(role) = role.parse() {
+            assignments.entry(principal).or_default().push(role);
+        }
+    }
+    for roles in assignments.values_mut() {
+        roles.sort();
+    }
+    Ok(assignments)
+}
+
+/// Replaces the cached assignments with the contents of `role_assignments`
+/// and returns the number of principals with roles.
+pub async fn load(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let assignments = fetch(pool).await?;
+    let count = assignments.len();
+    *ASSIGNMENTS.write().unwrap() = assignments;
+    Ok(count)
+}
+
+/// Starts the task that periodically reloads the assignments.
+pub fn spawn_reload(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = load(&pool).await {
+                tracing::error!("role assignment reload failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Grants `roles` to `principal`, keeping the roles it already holds.
+pub async fn grant(pool: &PgPool, principal: &str, roles: &[Role]) -> Result<(), sqlx::Error> {
+    let names: Vec<&str> = roles.iter().map(|role| role.as_str()).collect();
+    sqlx::query(
+        "INSERT INTO role_assignments (principal, role) SELECT $1, UNNEST($2::text[]) \
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(principal)
+    .bind(&names)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Revokes `roles` from `principal`.
+pub async fn revoke(pool: &PgPool, principal: &str, roles: &[Role]) -> Result<(), sqlx::Error> {
+    let names: Vec<&str> = roles.iter().map(|role| role.as_str()).collect();
+    sqlx::query("DELETE FROM role_assignments WHERE principal = $1 AND role = ANY($2)")
+        .bind(principal)
+        .bind(&names)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// A principal and its roles.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Assignment {
+    principal: String,
+    roles: Vec<Role>,
+}
+
+/// Body of `PUT /admin/roles/{principal}`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AssignmentInput {
+    roles: Vec<Role>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/roles",
+    tag = "admin",
+    responses((status = 200, body = Vec<Assignment>))
+)]
+pub async fn list_assignments(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Assignment>>, ApiError> {
+    let assignments = fetch(&state.pool).await?;
+    Ok(Json(
+        assignments
+            .into_iter()
+            .map(|(principal, roles)| Assignment { principal, roles })
+            .collect(),
+    ))
+}
+
+/// Replaces the roles of a principal; an empty list removes them all.
+#[utoipa::path(
+    put,
+    path = "/admin/roles/{principal}",
+    tag = "admin",
+    params(("principal" = String, Path, description = "The `sub` claim of the user's tokens")),
+    request_body = AssignmentInput,
+    responses(
+        (status = 200, body = Assignment),
+        (status = 400, description = "Empty principal")
+    )
+)]
+pub async fn put_assignment(
+    State(state): State<AppState>,
+    Parsed(Path(principal)): Parsed<Path<String>>,
+    Parsed(Json(input)): Parsed<Json<AssignmentInput>>,
+) -> Result<Json<Assignment>, ApiError> {
+    if principal.trim().is_empty() {
+        return Err(ApiError::bad_request("principal must not be empty"));
+    }
+    let mut roles = input.roles;
+    roles.sort();
+    roles.dedup();
+    let names: Vec<&str> = roles.iter().map(|role| role.as_str()).collect();
+
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("DELETE FROM role_assignments WHERE principal = $1")
+        .bind(&principal)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query("INSERT INTO role_assignments (principal, role) SELECT $1, UNNEST($2::text[])")
+        .bind(&principal)
+        .bind(&names)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    let mut assignments = ASSIGNMENTS.write().unwrap();
+    if roles.is_empty() {
+        assignments.remove(&principal);
+    } else {
+        assignments.insert(principal.clone(), roles.clone());
+    }
+    Ok(Json(Assignment { principal, roles }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(roles: &[Role]) -> Principal {
+        Principal {
+            subject: "user-1".to_string(),
+            roles: roles.to_vec(),
+        }
+    }
+
+    #[test]
+    fn routes_allow_the_roles_of_their_area() {
+        for version in ["", "/v1"] {
+            let allowed = |method, path: &str| allowed(&method, &format!("{}{}", version, path));
+            assert_eq!(allowed(Method::POST, "/samples/batch"), INGEST);
+            assert_eq!(allowed(Method::GET, "/samples"), READERS);
+            assert_eq!(allowed(Method::POST, "/samples/aggregate"), READERS);
+            assert_eq!(allowed(Method::GET, "/admin/api-keys"), [Role::Admin]);
+            assert_eq!(allowed(Method::DELETE, "/devices/:serial"), [Role::Admin]);
+            assert_eq!(allowed(Method::PUT, "/channels/:name"), [Role::Admin]);
+            assert_eq!(allowed(Method::GET, "/channels"), READERS);
+            assert_eq!(allowed(Method::POST, "/subjects"), [Role::Clinician]);
+            assert_eq!(allowed(Method::PATCH, "/sessions/:id"), [Role::Clinician]);
+            assert_eq!(allowed(Method::HEAD, "/sessions/:id"), READERS);
+            assert_eq!(allowed(Method::POST, "/import/edf"), [Role::Clinician]);
+            assert_eq!(allowed(Method::POST, "/import/csv"), [Role::Clinician]);
+            assert_eq!(allowed(Method::GET, "/imports/:id"), READERS);
+            let apply = "/analysis/ica/:id/apply";
+            assert_eq!(allowed(Method::POST, apply), [Role::Clinician]);
+            assert_eq!(allowed(Method::POST, "/analysis/ica"), READERS);
+            assert_eq!(allowed(Method::POST, "/pipelines"), [Role::Admin]);
+            assert_eq!(allowed(Method::PUT, "/pipelines/:name"), [Role::Admin]);
+            assert_eq!(allowed(Method::GET, "/pipelines/:name"), READERS);
+            assert_eq!(allowed(Method::POST, "/alerts/rules"), [Role::Admin]);
+            assert_eq!(
+                allowed(Method::DELETE, "/alerts/rules/:name"),
+                [Role::Admin]
+            );
+            assert_eq!(allowed(Method::POST, "/events"), ANNOTATORS);
+            assert_eq!(allowed(Method::PATCH, "/events/:id"), ANNOTATORS);
+            assert_eq!(allowed(Method::PUT, "/montages/:name"), READERS);
+            assert_eq!(allowed(Method::POST, "/graphql"), READERS);
+        }
+    }
+
+    #[test]
+    fn principals_need_one_of_the_roles() {
+        let clinician = principal(&[Role::Clinician]);
+        assert_eq!(check(&clinician, READERS), Ok(()));
+        assert_eq!(check(&clinician, &[Role::Clinician]), Ok(()));
+        assert_eq!(
+            check(&clinician, &[Role::Admin]),
+            Err("requires the role admin".to_string())
+        );
+        assert_eq!(
+            check(&principal(&[Role::Device]), READERS),
+            Err("requires the role admin or clinician or researcher".to_string())
+        );
+        assert!(check(&principal(&[]), INGEST).is_err());
+    }
+
+    #[test]
+    fn only_clinicians_see_subject_identifying_fields() {
+        assert!(may_identify(None));
+        assert!(may_identify(Some(&principal(&[
+            Role::Researcher,
+            Role::Clinician
+        ]))));
+        assert!(!may_identify(Some(&principal(&[
+            Role::Admin,
+            Role::Researcher
+        ]))));
+    }
+
+    #[test]
+    fn roles_parse_from_their_names() {
+        for role in [Role::Admin, Role::Clinician, Role::Researcher, Role::Device] {
+            assert_eq!(role.as_str().parse(), Ok(role));
+        }
+        assert_eq!(
+            "Admin".parse::<Role>(),
+            Err(
+                "unknown role \"Admin\"; expected admin, clinician, researcher or device"
+                    .to_string()
+            )
+        );
+    }
+}
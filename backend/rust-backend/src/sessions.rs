@@ -0,0 +1,205 @@
+//! Recording sessions that group samples (`sessions` table).
+//!
+//! Samples carry an optional `session_id`; `/samples` and `/live` accept
+//! `session_id` to scope reads to one recording. Sessions belong to a
+//! [subject](crate::subjects) through `subject_id`.
+
+use crate::error::ApiError;
+use crate::validation::{check_limit, FieldErrors, Parsed, Valid, Validate};
+use crate::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Session {
+    pub id: i32,
+    pub subject_id: Option<i32>,
+    pub device: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub notes: Option<String>,
+}
+
+/// Body of `POST /sessions` and `PATCH /sessions/{id}`; omitted fields are
+/// left unchanged on update.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SessionInput {
+    subject_id: Option<i32>,
+    device: Option<String>,
+    started_at: Option<DateTime<Utc>>,
+    ended_at: Option<DateTime<Utc>>,
+    notes: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SessionListQuery {
+    subject_id: Option<i32>,
+    device: Option<String>,
+    /// `true` for sessions without an end time, `false` for finished ones.
+    active: Option<bool>,
+    limit: Option<i64>,
+}
+
+impl Validate for SessionListQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_limit(errors, self.limit);
+    }
+}
+
+const COLUMNS: &str = "id, subject_id, device, started_at, ended_at, notes";
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    // 23514: check_violation, raised when ended_at precedes started_at;
+    // 23503: foreign_key_violation on subject_id.
+    match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23514") => {
+            ApiError::bad_request("ended_at must not be before started_at")
+        }
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            ApiError::bad_request("unknown subject_id")
+        }
+        _ => e.into(),
+    }
+}
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::not_found(format!("unknown session {}", id))
+}
+
+impl Validate for SessionInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let (Some(start), Some(end)) = (self.started_at, self.ended_at) {
+            errors.check(end >= start, "ended_at", "must not be before started_at");
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "sessions",
+    params(SessionListQuery),
+    responses((status = 200, description = "Newest first", body = Vec<Session>), (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`"))
+)]
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<SessionListQuery>>,
+) -> Result<Json<Vec<Session>>, ApiError> {
+    let sessions = sqlx::query_as(&format!(
+        "SELECT {} FROM sessions \
+         WHERE ($1::int4 IS NULL OR subject_id = $1) AND ($2::text IS NULL OR device = $2) \
+         AND ($3::bool IS NULL OR (ended_at IS NULL) = $3) \
+         ORDER BY started_at DESC, id DESC LIMIT $4",
+        COLUMNS
+    ))
+    .bind(params.subject_id)
+    .bind(&params.device)
+    .bind(params.active)
+    .bind(params.limit.unwrap_or(100))
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(sessions))
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/{id}",
+    tag = "sessions",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, body = Session),
+        (status = 404, description = "Unknown session")
+    )
+)]
+pub async fn get_session(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<Json<Session>, ApiError> {
+    sqlx::query_as(&format!("SELECT {} FROM sessions WHERE id = $1", COLUMNS))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| not_found(id))
+}
+
+/// Opens a session; `started_at` defaults to now.
+#[utoipa::path(
+    post,
+    path = "/sessions",
+    tag = "sessions",
+    request_body = SessionInput,
+    responses(
+        (status = 201, body = Session),
+        (status = 400, description = "Unknown `subject_id`"),
+        (status = 422, description = "`ended_at` before `started_at`, named in `details.fields`")
+    )
+)]
+pub async fn create_session(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<SessionInput>>,
+) -> Result<(StatusCode, Json<Session>), ApiError> {
+    let session = sqlx::query_as(&format!(
+        "INSERT INTO sessions (subject_id, device, started_at, ended_at, notes) \
+         VALUES ($1, $2, COALESCE($3, now()), $4, $5) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(input.subject_id)
+    .bind(&input.device)
+    .bind(input.started_at)
+    .bind(input.ended_at)
+    .bind(&input.notes)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+/// Updates the given fields, e.g. `{"ended_at": "..."}` to close a session.
+#[utoipa::path(
+    patch,
+    path = "/sessions/{id}",
+    tag = "sessions",
+    params(("id" = i32, Path)),
+    request_body = SessionInput,
+    responses(
+        (status = 200, body = Session),
+        (status = 400, description = "Unknown `subject_id`, or `ended_at` before the stored `started_at`"),
+        (status = 422, description = "`ended_at` before `started_at`, named in `details.fields`"),
+        (status = 404, description = "Unknown session")
+    )
+)]
+pub async fn update_session(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Valid(Json(input)): Valid<Json<SessionInput>>,
+) -> Result<Json<Session>, ApiError> {
+    sqlx::query_as(&format!(
+        "UPDATE sessions SET \
+         subject_id = COALESCE($2, subject_id), device = COALESCE($3, device), \
+         started_at = COALESCE($4, started_at), ended_at = COALESCE($5, ended_at), \
+         notes = COALESCE($6, notes) \
+         WHERE id = $1 RETURNING {}",
+        COLUMNS
+    ))
+    .bind(id)
+    .bind(input.subject_id)
+    .bind(&input.device)
+    .bind(input.started_at)
+    .bind(input.ended_at)
+    .bind(&input.notes)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .map(Json)
+    .ok_or_else(|| not_found(id))
+}
@@ -0,0 +1,73 @@
+//! Graceful shutdown on SIGTERM or SIGINT.
+//!
+//! Once a signal arrives the REST and gRPC servers stop accepting
+//! connections and finish the requests in flight, live streams close, and the ingest writers flush what they buffered
+//! before the pool is closed. Whatever is still running after
+//! `server.shutdown_timeout_ms` is dropped.
+
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::watch;
+
+static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn sender() -> &'static watch::Sender<bool> {
+    SHUTDOWN.get_or_init(|| watch::channel(false).0)
+}
+
+fn trigger() {
+    sender().send_replace(true);
+}
+
+pub fn is_requested() -> bool {
+    *sender().borrow()
+}
+
+/// Resolves once shutdown has been requested.
+pub async fn requested() {
+    let mut rx = sender().subscribe();
+    let _ = rx.wait_for(|requested| *requested).await;
+}
+
+/// Resolves `timeout` after shutdown was requested.
+pub async fn expired(timeout: Duration) {
+    requested().await;
+    tokio::time::sleep(timeout).await;
+}
+
+/// The close frame WebSocket sessions end with on shutdown.
+pub fn going_away() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: "server shutting down".into(),
+    }))
+}
+
+/// Waits for SIGTERM or SIGINT and starts shutting down.
+pub fn listen() {
+    tokio::spawn(async {
+        let interrupt = tokio::signal::ctrl_c();
+        #[cfg(unix)]
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => {
+                    signal.recv().await;
+                }
+                Err(e) => {
+                    tracing::error!("cannot listen for SIGTERM: {}", e);
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        let name = tokio::select! {
+            _ = interrupt => "SIGINT",
+            _ = terminate => "SIGTERM",
+        };
+        tracing::info!("{} received, shutting down", name);
+        trigger();
+    });
+}
@@ -0,0 +1,495 @@
+//! Packed storage of raw samples (`sample_blocks`).
+//!
+//! One `eeg_samples` row per sample and channel takes some 60 bytes of disk
+//! for 8 bytes of value. The `blocks` [job](crate::jobs), off unless
+//! `jobs.blocks_secs` is set, packs the samples older than
+//! `storage.block_after_secs` into one block per channel, session and
+//! `storage.block_ms` of time since the epoch, encoded as [`eeg_blocks`]
+//! describes, and deletes their rows. Samples keep their ids and values.
+//!
+//! Reads of samples merge blocks back in: windows of `/samples` and of the
+//! routes built on [`fetch_window_samples`](super::postgres::fetch_window_samples),
+//! pages by id, live reads, replays, gaps, CSV and Parquet exports and
+//! session exports through [`Merged`] and [`page`]. Routes that compute in
+//! SQL (aggregates and tiers, histograms, stats) add the samples of
+//! [`for_each`] to what the database summed up, and channel listings count
+//! blocks with [`channel_counts`]. Artifact detection and ICA merge blocks
+//! too, re-referencing adds the samples of [`for_each`] to the means, the
+//! span of fixed-length epochs takes in [`span`], and session channel
+//! lists and series continuation look at `sample_blocks` next to the rows.
+
+use crate::config;
+use crate::jobs::Job;
+use crate::{EegSample, SampleFilter};
+use chrono::{DateTime, Utc};
+use eeg_blocks::Point;
+use futures::stream::{BoxStream, Peekable};
+use futures::StreamExt;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::pin::Pin;
+
+/// Rows packed per transaction.
+const PACK_ROWS: i64 = 100_000;
+
+/// A block as read back.
+#[derive(Debug, sqlx::FromRow)]
+pub struct Block {
+    pub channel: String,
+    pub session_id: Option<i32>,
+    pub start_ts: DateTime<Utc>,
+    pub first_id: i32,
+    pub last_id: i32,
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    /// The samples of the block that `filter` keeps; blocks are selected
+    /// by channel and session already.
+    pub fn unpack(&self, filter: &SampleFilter) -> Result<Vec<EegSample>, sqlx::Error> {
+        let points = eeg_blocks::decode(&self.data).map_err(|e| {
+            sqlx::Error::Decode(
+                format!("block of {:?} at {}: {}", self.channel, self.start_ts, e).into(),
+            )
+        })?;
+        Ok(points
+            .into_iter()
+            .filter_map(|p| {
+                let ts = DateTime::from_timestamp_micros(p.ts_us)?;
+                let kept =
+                    filter.from.is_none_or(|from| ts >= from) && filter.to.is_none_or(|to| ts < to);
+                kept.then(|| EegSample {
+                    id: p.id,
+                    ts,
+                    channel: self.channel.clone(),
+                    value: p.value,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Appends the predicates of `filter` for blocks holding samples it keeps.
+fn push_filter(filter: &SampleFilter, query: &mut QueryBuilder<'_, Postgres>) {
+    if let Some(from) = filter.from {
+        query.push(" AND end_ts >= ").push_bind(from);
+    }
+    if let Some(to) = filter.to {
+        query.push(" AND start_ts < ").push_bind(to);
+    }
+    if let Some(session_id) = filter.session_id {
+        query.push(" AND session_id = ").push_bind(session_id);
+    }
+    if let Some(subject_id) = filter.subject_id {
+        query
+            .push(" AND session_id IN (SELECT id FROM sessions WHERE subject_id = ")
+            .push_bind(subject_id)
+            .push(")");
+    }
+}
+
+/// Blocks of `channels`, or of every channel, that may hold samples
+/// `filter` keeps, oldest first, for [`Merged`].
+pub fn select(
+    channels: Option<&[String]>,
+    filter: &SampleFilter,
+) -> QueryBuilder<'static, Postgres> {
+    let mut query = matching(COLUMNS, channels, filter);
+    query.push(" ORDER BY start_ts");
+    query
+}
+
+/// The columns of a [`Block`].
+const COLUMNS: &str = "channel, session_id, start_ts, first_id, last_id, data";
+
+/// `SELECT columns` of the blocks of `channels`, or of every channel, that
+/// may hold samples `filter` keeps.
+fn matching(
+    columns: &str,
+    channels: Option<&[String]>,
+    filter: &SampleFilter,
+) -> QueryBuilder<'static, Postgres> {
+    let mut query = QueryBuilder::new(format!("SELECT {} FROM sample_blocks WHERE TRUE", columns));
+    if let Some(channels) = channels {
+        query
+            .push(" AND channel = ANY(")
+            .push_bind(channels.to_vec())
+            .push(")");
+    }
+    push_filter(filter, &mut query);
+    query
+}
+
+/// Times of the first and last samples of `channels`, or of every
+/// channel, packed in blocks that `filter` keeps. Only blocks across an
+/// end of the window are unpacked; the others span from `start_ts` to
+/// `end_ts`.
+pub async fn span(
+    pool: &PgPool,
+    channels: Option<&[String]>,
+    filter: &SampleFilter,
+) -> Result<Option<(DateTime<Utc>, DateTime<Utc>)>, sqlx::Error> {
+    let mut inside = matching("min(start_ts), max(end_ts)", channels, filter);
+    if let Some(from) = filter.from {
+        inside.push(" AND start_ts >= ").push_bind(from);
+    }
+    if let Some(to) = filter.to {
+        inside.push(" AND end_ts < ").push_bind(to);
+    }
+    let (first, last): (Option<DateTime<Utc>>, Option<DateTime<Utc>>) =
+        inside.build_query_as().fetch_one(pool).await?;
+    let mut span = first.zip(last);
+    if filter.from.is_none() && filter.to.is_none() {
+        return Ok(span);
+    }
+
+    let mut across = matching(COLUMNS, channels, filter);
+    across.push(" AND (FALSE");
+    if let Some(from) = filter.from {
+        across.push(" OR start_ts < ").push_bind(from);
+    }
+    if let Some(to) = filter.to {
+        across.push(" OR end_ts >= ").push_bind(to);
+    }
+    across.push(")");
+    let mut blocks = across.build_query_as::<Block>().fetch(pool);
+    while let Some(block) = blocks.next().await.transpose()? {
+        for sample in block.unpack(filter)? {
+            span = Some(match span {
+                Some((first, last)) => (first.min(sample.ts), last.max(sample.ts)),
+                None => (sample.ts, sample.ts),
+            });
+        }
+    }
+    Ok(span)
+}
+
+/// Calls `f` with each sample of `channels`, or of every channel, packed in
+/// blocks that `filter` keeps, block by block, for reads that sum up rows
+/// in SQL and add the packed samples to the result.
+pub async fn for_each(
+    pool: &PgPool,
+    channels: Option<&[String]>,
+    filter: &SampleFilter,
+    mut f: impl FnMut(EegSample),
+) -> Result<(), sqlx::Error> {
+    let mut query = select(channels, filter);
+    let mut blocks = query.build_query_as::<Block>().fetch(pool);
+    while let Some(block) = blocks.next().await.transpose()? {
+        block.unpack(filter)?.into_iter().for_each(&mut f);
+    }
+    Ok(())
+}
+
+/// Every channel with samples, rows and blocks together: its name, number
+/// of samples and largest sample id.
+pub async fn channel_counts(pool: &PgPool) -> Result<Vec<(String, i64, i32)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT channel, SUM(samples)::BIGINT, MAX(last_id) FROM ( \
+           SELECT channel, COUNT(*) AS samples, MAX(id) AS last_id FROM eeg_samples GROUP BY channel \
+           UNION ALL SELECT channel, SUM(samples), MAX(last_id) FROM sample_blocks GROUP BY channel \
+         ) counts GROUP BY channel ORDER BY channel",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// A sample ordered by time, then id.
+struct ByTime(EegSample);
+
+impl ByTime {
+    fn key(&self) -> (DateTime<Utc>, i32) {
+        (self.0.ts, self.0.id)
+    }
+}
+
+impl PartialEq for ByTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for ByTime {}
+
+impl PartialOrd for ByTime {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByTime {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// The key of the next item of `stream`, an error of the stream being
+/// taken out of it.
+async fn peek<T, K>(
+    stream: &mut Peekable<BoxStream<'_, Result<T, sqlx::Error>>>,
+    key: impl Fn(&T) -> K,
+) -> Result<Option<K>, sqlx::Error> {
+    match Pin::new(&mut *stream).peek().await {
+        None => Ok(None),
+        Some(Ok(item)) => Ok(Some(key(item))),
+        Some(Err(_)) => match stream.next().await {
+            Some(Err(e)) => Err(e),
+            _ => unreachable!("peeked an error"),
+        },
+    }
+}
+
+/// Rows ordered by `ts, id` merged with the samples of blocks ordered by
+/// `start_ts`, as from [`select`] with the same filter, in the same order.
+/// Blocks are unpacked as the merge reaches them.
+pub struct Merged<'a> {
+    rows: Peekable<BoxStream<'a, Result<EegSample, sqlx::Error>>>,
+    blocks: Peekable<BoxStream<'a, Result<Block, sqlx::Error>>>,
+    /// Samples of the blocks unpacked so far that were not returned yet.
+    open: BinaryHeap<Reverse<ByTime>>,
+    filter: SampleFilter,
+}
+
+impl<'a> Merged<'a> {
+    pub fn new(
+        rows: BoxStream<'a, Result<EegSample, sqlx::Error>>,
+        blocks: BoxStream<'a, Result<Block, sqlx::Error>>,
+        filter: SampleFilter,
+    ) -> Self {
+        Merged {
+            rows: rows.peekable(),
+            blocks: blocks.peekable(),
+            open: BinaryHeap::new(),
+            filter,
+        }
+    }
+
+    pub async fn next(&mut self) -> Result<Option<EegSample>, sqlx::Error> {
+        loop {
+            let row = peek(&mut self.rows, |s| (s.ts, s.id)).await?;
+            let open = self.open.peek().map(|Reverse(s)| s.key());
+            let earliest = match (row, open) {
+                (Some(row), Some(open)) => Some(row.min(open)),
+                (row, open) => row.or(open),
+            };
+            // A block starting no later than the earliest sample at hand
+            // may hold earlier ones.
+            let start = peek(&mut self.blocks, |b| b.start_ts).await?;
+            if start.is_some_and(|start| earliest.is_none_or(|(ts, _)| start <= ts)) {
+                if let Some(block) = self.blocks.next().await.transpose()? {
+                    let samples = block.unpack(&self.filter)?;
+                    self.open
+                        .extend(samples.into_iter().map(|s| Reverse(ByTime(s))));
+                }
+                continue;
+            }
+            return match (row, open) {
+                (Some(row), Some(open)) if open < row => Ok(self.open.pop().map(|s| s.0 .0)),
+                (Some(_), _) => self.rows.next().await.transpose(),
+                (None, _) => Ok(self.open.pop().map(|s| s.0 .0)),
+            };
+        }
+    }
+}
+
+/// The samples of `channel` in blocks with ids strictly between `after_id`
+/// and `before_id` that `filter` keeps: the `limit` nearest to `after_id`
+/// when `ascending`, else to `before_id`, in that order.
+pub async fn page(
+    pool: &PgPool,
+    channel: &str,
+    before_id: i32,
+    after_id: i32,
+    filter: &SampleFilter,
+    ascending: bool,
+    limit: usize,
+) -> Result<Vec<EegSample>, sqlx::Error> {
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {} FROM sample_blocks WHERE channel = ",
+        COLUMNS
+    ));
+    query
+        .push_bind(channel)
+        .push(" AND last_id > ")
+        .push_bind(after_id)
+        .push(" AND first_id < ")
+        .push_bind(before_id);
+    push_filter(filter, &mut query);
+    query.push(if ascending {
+        " ORDER BY first_id"
+    } else {
+        " ORDER BY last_id DESC"
+    });
+    let mut samples: Vec<EegSample> = Vec::new();
+    if limit == 0 {
+        return Ok(samples);
+    }
+    let mut blocks = query.build_query_as::<Block>().fetch(pool);
+    while let Some(block) = blocks.next().await.transpose()? {
+        // Later blocks start beyond the `limit` samples already found.
+        if samples.len() >= limit {
+            let last = samples[limit - 1].id;
+            if (ascending && block.first_id > last) || (!ascending && block.last_id < last) {
+                break;
+            }
+        }
+        samples.extend(
+            block
+                .unpack(filter)?
+                .into_iter()
+                .filter(|s| s.id > after_id && s.id < before_id),
+        );
+        if ascending {
+            samples.sort_by_key(|s| s.id);
+        } else {
+            samples.sort_by_key(|s| Reverse(s.id));
+        }
+        samples.truncate(limit);
+    }
+    Ok(samples)
+}
+
+/// A row to pack.
+#[derive(sqlx::FromRow)]
+struct Row {
+    id: i32,
+    ts: DateTime<Utc>,
+    channel: String,
+    value: f64,
+    session_id: Option<i32>,
+}
+
+/// A block to store.
+struct Packed {
+    channel: String,
+    session_id: Option<i32>,
+    points: Vec<Point>,
+}
+
+/// Groups `rows`, ordered by channel, session and time, into blocks of
+/// `block_us` microseconds since the epoch.
+fn group(rows: Vec<Row>, block_us: i64) -> Vec<Packed> {
+    let mut blocks: Vec<Packed> = Vec::new();
+    let mut bucket = None;
+    for row in rows {
+        let ts_us = row.ts.timestamp_micros();
+        let key = (row.channel, row.session_id, ts_us.div_euclid(block_us));
+        let point = Point {
+            id: row.id,
+            ts_us,
+            value: row.value,
+        };
+        match blocks.last_mut() {
+            Some(block) if bucket.as_ref() == Some(&key) => block.points.push(point),
+            _ => {
+                blocks.push(Packed {
+                    channel: key.0.clone(),
+                    session_id: key.1,
+                    points: vec![point],
+                });
+                bucket = Some(key);
+            }
+        }
+    }
+    blocks
+}
+
+/// Packs the rows older than `cutoff` in one transaction of at most
+/// [`PACK_ROWS`], returning the samples and blocks written and whether rows
+/// were left over.
+async fn pack_some(
+    pool: &PgPool,
+    cutoff: DateTime<Utc>,
+    block_us: i64,
+) -> Result<(usize, usize, bool), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let rows: Vec<Row> = sqlx::query_as(
+        "SELECT id, ts, channel, value, session_id FROM eeg_samples WHERE ts < $1 \
+         ORDER BY channel, session_id, ts, id LIMIT $2 FOR UPDATE",
+    )
+    .bind(cutoff)
+    .bind(PACK_ROWS)
+    .fetch_all(&mut tx)
+    .await?;
+    let more = rows.len() as i64 == PACK_ROWS;
+    let mut blocks = group(rows, block_us);
+    // The last block of a full batch may go on in the rows left over.
+    if more && blocks.len() > 1 {
+        blocks.pop();
+    }
+    if blocks.is_empty() {
+        return Ok((0, 0, false));
+    }
+    let ids: Vec<i32> = blocks
+        .iter()
+        .flat_map(|b| b.points.iter().map(|p| p.id))
+        .collect();
+    // Bind parameters are limited to 65535 per statement.
+    for chunk in blocks.chunks(5000) {
+        let mut query = QueryBuilder::new(
+            "INSERT INTO sample_blocks (channel, session_id, start_ts, end_ts, first_id, last_id, \
+             samples, min_value, max_value, data) ",
+        );
+        query.push_values(chunk, |mut row, block| {
+            let points = &block.points;
+            let ts = |us| DateTime::from_timestamp_micros(us).unwrap_or_default();
+            let (first_id, last_id) = points.iter().fold((i32::MAX, i32::MIN), |(lo, hi), p| {
+                (lo.min(p.id), hi.max(p.id))
+            });
+            let (min, max) = points
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), p| {
+                    (lo.min(p.value), hi.max(p.value))
+                });
+            row.push_bind(block.channel.clone())
+                .push_bind(block.session_id)
+                .push_bind(ts(points[0].ts_us))
+                .push_bind(ts(points[points.len() - 1].ts_us))
+                .push_bind(first_id)
+                .push_bind(last_id)
+                .push_bind(points.len() as i32)
+                .push_bind(min)
+                .push_bind(max)
+                .push_bind(eeg_blocks::encode(points));
+        });
+        query.build().execute(&mut tx).await?;
+    }
+    sqlx::query("DELETE FROM eeg_samples WHERE id = ANY($1) AND ts < $2")
+        .bind(&ids)
+        .bind(cutoff)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok((ids.len(), blocks.len(), more))
+}
+
+/// The `blocks` [job](crate::jobs).
+pub struct Pack {
+    pub pool: PgPool,
+}
+
+#[axum::async_trait]
+impl Job for Pack {
+    async fn run(&mut self) -> Result<String, String> {
+        let storage = &config::get().storage;
+        let block_us = storage.block_ms as i64 * 1000;
+        // Up to the start of a block, so that none is cut at the cutoff.
+        let before = Utc::now() - chrono::Duration::seconds(storage.block_after_secs as i64);
+        let before_us = before.timestamp_micros();
+        let cutoff = DateTime::from_timestamp_micros(before_us - before_us.rem_euclid(block_us))
+            .unwrap_or(before);
+        let (mut samples, mut blocks) = (0, 0);
+        loop {
+            let (packed, written, more) = pack_some(&self.pool, cutoff, block_us)
+                .await
+                .map_err(|e| format!("cannot pack samples: {}", e))?;
+            samples += packed;
+            blocks += written;
+            if !more {
+                break;
+            }
+        }
+        Ok(format!("{} samples packed into {} blocks", samples, blocks))
+    }
+}
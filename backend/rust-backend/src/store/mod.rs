@@ -0,0 +1,107 @@
+//! The sample data layer behind [`SampleStore`]: storing samples and reading
+//! them back by id, as the ingest, `/samples` and `/live` routes do.
+//!
+//! [`PgStore`] is the Postgres store of the full backend. [`SqliteStore`]
+//! keeps samples in one SQLite file, for small portable deployments such as
+//! a laptop in the field; with a `sqlite:` `database.url`, `serve` runs the
+//! [portable](crate::portable) routes on it instead of the full backend.
+
+pub mod blocks;
+pub mod postgres;
+pub mod sqlite;
+
+pub use postgres::PgStore;
+pub use sqlite::SqliteStore;
+
+use crate::error::ApiError;
+use crate::ingest::{NewSample, Sequenced};
+use crate::validation;
+use crate::SampleFilter;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct EegSample {
+    pub id: i32,
+    pub ts: DateTime<Utc>,
+    pub channel: String,
+    pub value: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LivePoint {
+    pub id: i32,
+    pub ts: DateTime<Utc>,
+    pub value: f64,
+}
+
+/// Batches buffered by [`spawn_writer`] before sources wait.
+const WRITER_QUEUE: usize = 64;
+
+#[axum::async_trait]
+pub trait SampleStore: Send + Sync {
+    /// Stores `samples` and returns their ids, in order.
+    async fn insert(&self, samples: Vec<NewSample>) -> Result<Vec<i32>, ApiError>;
+
+    /// Stores `samples` as batch `seq` of the device `serial`, unless that
+    /// batch was stored before; see [`crate::ingest::insert_batch_once`].
+    async fn insert_once(
+        &self,
+        serial: &str,
+        seq: i64,
+        samples: Vec<NewSample>,
+    ) -> Result<Sequenced, ApiError>;
+
+    /// One page of `channel`: newest first below `before_id`, or oldest
+    /// first above `after_id` when only it is given, with the cursor of the
+    /// next page if there is one.
+    async fn page(
+        &self,
+        channel: &str,
+        before_id: Option<i32>,
+        after_id: Option<i32>,
+        filter: &SampleFilter,
+        limit: i32,
+    ) -> Result<(Vec<EegSample>, Option<i32>), ApiError>;
+
+    /// Samples of `channel` after `since_id`, oldest first.
+    async fn since(
+        &self,
+        channel: &str,
+        since_id: i32,
+        filter: &SampleFilter,
+        limit: i32,
+    ) -> Result<Vec<LivePoint>, ApiError>;
+
+    /// Checks that the database answers.
+    async fn ping(&self) -> Result<(), ApiError>;
+}
+
+/// Spawns a task storing the batches sent by a background acquisition
+/// source. Invalid samples are dropped, and failures are logged under
+/// `source` rather than stopping it.
+pub fn spawn_writer(
+    store: Arc<dyn SampleStore>,
+    source: &'static str,
+) -> mpsc::Sender<Vec<NewSample>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<NewSample>>(WRITER_QUEUE);
+    tokio::spawn(async move {
+        while let Some(mut samples) = rx.recv().await {
+            let received = samples.len();
+            samples.retain(|s| validation::check(s).is_ok());
+            let rows = samples.len();
+            if rows < received {
+                tracing::warn!("{} dropped {} invalid samples", source, received - rows);
+            }
+            if rows == 0 {
+                continue;
+            }
+            if let Err(e) = store.insert(samples).await {
+                tracing::error!("{} failed to store {} samples: {}", source, rows, e.message);
+            }
+        }
+    });
+    tx
+}
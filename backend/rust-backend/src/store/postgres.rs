@@ -0,0 +1,248 @@
+//! [`SampleStore`] on the Postgres `eeg_samples` table, through the same
+//! queries as the rest of the backend: reads go to the read replica while
+//! it answers and are served from the [live buffer](crate::ring) when it
+//! reaches back far enough. Windows, pages and live reads include the
+//! samples packed into [blocks](blocks).
+
+use super::{blocks, SampleStore};
+use crate::error::ApiError;
+use crate::ingest::{self, NewSample, Sequenced};
+use crate::{config, db, ring, EegSample, LivePoint, SampleFilter};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, QueryBuilder};
+
+#[derive(Clone)]
+pub struct PgStore {
+    pool: PgPool,
+    replica: Option<db::Replica>,
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool, replica: Option<db::Replica>) -> Self {
+        Self { pool, replica }
+    }
+
+    fn reader(&self) -> &PgPool {
+        db::reader(&self.pool, self.replica.as_ref())
+    }
+}
+
+#[axum::async_trait]
+impl SampleStore for PgStore {
+    async fn insert(&self, samples: Vec<NewSample>) -> Result<Vec<i32>, ApiError> {
+        ingest::insert_batch(&self.pool, samples)
+            .await
+            .map_err(|e| {
+                if ingest::is_unknown_session(&e) {
+                    ApiError::bad_request("unknown session_id")
+                } else {
+                    e.into()
+                }
+            })
+    }
+
+    async fn insert_once(
+        &self,
+        serial: &str,
+        seq: i64,
+        samples: Vec<NewSample>,
+    ) -> Result<Sequenced, ApiError> {
+        ingest::insert_batch_once(&self.pool, serial, seq, samples)
+            .await
+            .map_err(|e| {
+                if ingest::is_unknown_device(&e) {
+                    ApiError::bad_request(format!("unknown device {:?}", serial))
+                } else if ingest::is_unknown_session(&e) {
+                    ApiError::bad_request("unknown session_id")
+                } else {
+                    e.into()
+                }
+            })
+    }
+
+    async fn page(
+        &self,
+        channel: &str,
+        before_id: Option<i32>,
+        after_id: Option<i32>,
+        filter: &SampleFilter,
+        limit: i32,
+    ) -> Result<(Vec<EegSample>, Option<i32>), ApiError> {
+        Ok(fetch_sample_page(self.reader(), channel, before_id, after_id, filter, limit).await?)
+    }
+
+    async fn since(
+        &self,
+        channel: &str,
+        since_id: i32,
+        filter: &SampleFilter,
+        limit: i32,
+    ) -> Result<Vec<LivePoint>, ApiError> {
+        Ok(fetch_live_points(self.reader(), channel, since_id, filter, limit).await?)
+    }
+
+    async fn ping(&self) -> Result<(), ApiError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+/// A channel's samples in the filtered window, oldest first; `400` if there
+/// are more than `limits.max_window_rows`.
+#[tracing::instrument(skip_all, fields(otel.kind = "client", db.system = "postgresql", channel = channel, db.rows))]
+pub async fn fetch_window_samples(
+    pool: &PgPool,
+    channel: &str,
+    filter: &SampleFilter,
+) -> Result<Vec<EegSample>, ApiError> {
+    let max_rows = config::get().limits.max_window_rows;
+    let mut query =
+        QueryBuilder::new("SELECT id, ts, channel, value FROM eeg_samples WHERE channel = ");
+    query.push_bind(channel);
+    filter.push_to(&mut query);
+    query
+        .push(" ORDER BY ts, id LIMIT ")
+        .push_bind(max_rows + 1);
+    let mut packed = blocks::select(Some(&[channel.to_string()]), filter);
+    let mut merged = blocks::Merged::new(
+        query.build_query_as().fetch(pool),
+        packed.build_query_as().fetch(pool),
+        *filter,
+    );
+    let mut samples = Vec::new();
+    while samples.len() as i64 <= max_rows {
+        match merged.next().await? {
+            Some(sample) => samples.push(sample),
+            None => break,
+        }
+    }
+    tracing::Span::current().record("db.rows", samples.len());
+    if samples.len() as i64 > max_rows {
+        return Err(ApiError::bad_request(format!(
+                "channel {:?} has more than {} samples in the window; narrow from/to or use /samples/overview",
+                channel, config::get().limits.max_window_rows
+            )));
+    }
+    Ok(samples)
+}
+
+/// One page of a channel and the cursor for the next one, if any.
+#[tracing::instrument(skip_all, fields(otel.kind = "client", db.system = "postgresql", channel = channel, limit = limit, db.rows))]
+pub async fn fetch_sample_page(
+    pool: &PgPool,
+    channel: &str,
+    before_id: Option<i32>,
+    after_id: Option<i32>,
+    filter: &SampleFilter,
+    limit: i32,
+) -> Result<(Vec<EegSample>, Option<i32>), sqlx::Error> {
+    let ascending = after_id.is_some() && before_id.is_none();
+
+    let mut query =
+        QueryBuilder::new("SELECT id, ts, channel, value FROM eeg_samples WHERE channel = ");
+    query
+        .push_bind(channel)
+        .push(" AND id > ")
+        .push_bind(after_id.unwrap_or(0))
+        .push(" AND id < ")
+        .push_bind(before_id.unwrap_or(i32::MAX));
+    filter.push_to(&mut query);
+    query
+        .push(if ascending {
+            " ORDER BY id ASC"
+        } else {
+            " ORDER BY id DESC"
+        })
+        .push(" LIMIT ")
+        .push_bind(limit as i64 + 1);
+    let mut samples: Vec<EegSample> = query.build_query_as().fetch_all(pool).await?;
+    let packed = blocks::page(
+        pool,
+        channel,
+        before_id.unwrap_or(i32::MAX),
+        after_id.unwrap_or(0),
+        filter,
+        ascending,
+        limit as usize + 1,
+    )
+    .await?;
+    if !packed.is_empty() {
+        samples.extend(packed);
+        if ascending {
+            samples.sort_by_key(|s| s.id);
+        } else {
+            samples.sort_by_key(|s| std::cmp::Reverse(s.id));
+        }
+    }
+    tracing::Span::current().record("db.rows", samples.len());
+
+    let has_more = samples.len() > limit as usize;
+    samples.truncate(limit as usize);
+    let next_cursor = if has_more {
+        samples.last().map(|s| s.id)
+    } else {
+        None
+    };
+    Ok((samples, next_cursor))
+}
+
+/// Samples of `channel` after `since_id`, from its [`ring`] window when the
+/// window reaches back that far, else from the database.
+pub async fn fetch_live_points(
+    pool: &PgPool,
+    channel: &str,
+    since_id: i32,
+    filter: &SampleFilter,
+    limit: i32,
+) -> Result<Vec<LivePoint>, sqlx::Error> {
+    let recent = if filter.subject_id.is_none() {
+        ring::since(
+            channel,
+            &ring::Select {
+                since_id,
+                from: filter.from,
+                to: filter.to,
+                session_id: filter.session_id,
+                limit: limit.max(0) as usize,
+            },
+        )
+    } else {
+        None
+    };
+    let points = match recent {
+        Some(points) => points,
+        None => {
+            let mut query =
+                QueryBuilder::new("SELECT id, ts, value FROM eeg_samples WHERE channel = ");
+            query
+                .push_bind(channel)
+                .push(" AND id > ")
+                .push_bind(since_id);
+            filter.push_to(&mut query);
+            query.push(" ORDER BY id ASC LIMIT ").push_bind(limit);
+            let mut points: Vec<(i32, DateTime<Utc>, f64)> =
+                query.build_query_as().fetch_all(pool).await?;
+            let packed = blocks::page(
+                pool,
+                channel,
+                i32::MAX,
+                since_id,
+                filter,
+                true,
+                limit.max(0) as usize,
+            )
+            .await?;
+            if !packed.is_empty() {
+                points.extend(packed.into_iter().map(|s| (s.id, s.ts, s.value)));
+                points.sort_by_key(|p| p.0);
+                points.truncate(limit.max(0) as usize);
+            }
+            points
+        }
+    };
+
+    Ok(points
+        .into_iter()
+        .map(|(id, ts, value)| LivePoint { id, ts, value })
+        .collect())
+}
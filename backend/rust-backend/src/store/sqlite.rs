@@ -0,0 +1,190 @@
+//! [`SampleStore`] in a SQLite file, for deployments without a database
+//! server.
+//!
+//! The schema comes from `sqlite-migrations/`, applied when the store is
+//! opened. Samples keep their timestamps as µs since the Unix epoch, and
+//! `session_id` as given: there are no sessions, subjects, channel or
+//! device registries, nor batch sequence numbers, in the file.
+
+use super::SampleStore;
+use crate::error::ApiError;
+use crate::ingest::{NewSample, Sequenced};
+use crate::{config, EegSample, LivePoint, SampleFilter};
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a write waits for another to finish.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+fn micros(ts: DateTime<Utc>) -> i64 {
+    ts.timestamp_micros()
+}
+
+fn timestamp(us: i64) -> DateTime<Utc> {
+    Utc.timestamp_micros(us).single().unwrap_or_default()
+}
+
+impl SqliteStore {
+    /// Opens the file of `url`, creating it if missing, and applies the
+    /// pending migrations.
+    pub async fn open(url: &str) -> Result<Self, String> {
+        let options = SqliteConnectOptions::from_str(url)
+            .map_err(|e| format!("invalid database.url: {}", e))?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let config = &config::get().database;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections)
+            .acquire_timeout(Duration::from_millis(config.acquire_timeout_ms))
+            .connect_with(options)
+            .await
+            .map_err(|e| format!("cannot open {}: {}", url, e))?;
+        sqlx::migrate!("./sqlite-migrations")
+            .run(&pool)
+            .await
+            .map_err(|e| format!("cannot migrate {}: {}", url, e))?;
+        Ok(Self { pool })
+    }
+
+    fn push_filter(
+        query: &mut QueryBuilder<'_, Sqlite>,
+        filter: &SampleFilter,
+    ) -> Result<(), ApiError> {
+        if filter.subject_id.is_some() {
+            return Err(ApiError::bad_request(
+                "subject_id is not supported with SQLite storage",
+            ));
+        }
+        if let Some(from) = filter.from {
+            query.push(" AND ts >= ").push_bind(micros(from));
+        }
+        if let Some(to) = filter.to {
+            query.push(" AND ts < ").push_bind(micros(to));
+        }
+        if let Some(session_id) = filter.session_id {
+            query.push(" AND session_id = ").push_bind(session_id);
+        }
+        Ok(())
+    }
+}
+
+#[axum::async_trait]
+impl SampleStore for SqliteStore {
+    async fn insert(&self, samples: Vec<NewSample>) -> Result<Vec<i32>, ApiError> {
+        let mut tx = self.pool.begin().await?;
+        let mut ids = Vec::with_capacity(samples.len());
+        for sample in &samples {
+            let id: i64 = sqlx::query_scalar(
+                "INSERT INTO eeg_samples (ts, channel, value, session_id) \
+                 VALUES ($1, $2, $3, $4) RETURNING id",
+            )
+            .bind(micros(sample.ts))
+            .bind(&sample.channel)
+            .bind(sample.value)
+            .bind(sample.session_id)
+            .fetch_one(&mut tx)
+            .await?;
+            ids.push(id as i32);
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+
+    async fn insert_once(
+        &self,
+        _serial: &str,
+        _seq: i64,
+        _samples: Vec<NewSample>,
+    ) -> Result<Sequenced, ApiError> {
+        Err(ApiError::bad_request(
+            "seq is not supported with SQLite storage",
+        ))
+    }
+
+    async fn page(
+        &self,
+        channel: &str,
+        before_id: Option<i32>,
+        after_id: Option<i32>,
+        filter: &SampleFilter,
+        limit: i32,
+    ) -> Result<(Vec<EegSample>, Option<i32>), ApiError> {
+        let ascending = after_id.is_some() && before_id.is_none();
+        let mut query =
+            QueryBuilder::new("SELECT id, ts, channel, value FROM eeg_samples WHERE channel = ");
+        query
+            .push_bind(channel)
+            .push(" AND id > ")
+            .push_bind(after_id.unwrap_or(0))
+            .push(" AND id < ")
+            .push_bind(before_id.unwrap_or(i32::MAX));
+        Self::push_filter(&mut query, filter)?;
+        query
+            .push(if ascending {
+                " ORDER BY id ASC"
+            } else {
+                " ORDER BY id DESC"
+            })
+            .push(" LIMIT ")
+            .push_bind(limit as i64 + 1);
+        let rows: Vec<(i64, i64, String, f64)> =
+            query.build_query_as().fetch_all(&self.pool).await?;
+
+        let has_more = rows.len() > limit as usize;
+        let samples: Vec<EegSample> = rows
+            .into_iter()
+            .take(limit as usize)
+            .map(|(id, ts, channel, value)| EegSample {
+                id: id as i32,
+                ts: timestamp(ts),
+                channel,
+                value,
+            })
+            .collect();
+        let next_cursor = if has_more {
+            samples.last().map(|s| s.id)
+        } else {
+            None
+        };
+        Ok((samples, next_cursor))
+    }
+
+    async fn since(
+        &self,
+        channel: &str,
+        since_id: i32,
+        filter: &SampleFilter,
+        limit: i32,
+    ) -> Result<Vec<LivePoint>, ApiError> {
+        let mut query = QueryBuilder::new("SELECT id, ts, value FROM eeg_samples WHERE channel = ");
+        query
+            .push_bind(channel)
+            .push(" AND id > ")
+            .push_bind(since_id);
+        Self::push_filter(&mut query, filter)?;
+        query.push(" ORDER BY id ASC LIMIT ").push_bind(limit);
+        let rows: Vec<(i64, i64, f64)> = query.build_query_as().fetch_all(&self.pool).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, ts, value)| LivePoint {
+                id: id as i32,
+                ts: timestamp(ts),
+                value,
+            })
+            .collect())
+    }
+
+    async fn ping(&self) -> Result<(), ApiError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+//! Pseudonymous study subjects (`subjects` table).
+//!
+//! Subjects are identified by a code rather than a name; sessions reference
+//! them through `subject_id`, and `/samples` and `/live` accept `subject_id`
+//! to read every session recorded from one subject. The code, birth year
+//! and notes identify a subject and are only shown to clinicians (see
+//! [`roles::may_identify`]).
+
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::validation::{check_limit, FieldErrors, Parsed, Valid, Validate};
+use crate::{roles, AppState};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Datelike, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+const MAX_CODE_LEN: usize = 64;
+const SEXES: &[&str] = &["female", "male", "other", "unknown"];
+const HANDEDNESS: &[&str] = &["left", "right", "ambidextrous", "unknown"];
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Subject {
+    pub id: i32,
+    /// `None` once [redacted](Subject::redact).
+    pub code: Option<String>,
+    pub birth_year: Option<i32>,
+    pub sex: Option<String>,
+    pub handedness: Option<String>,
+    pub notes: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `POST /subjects` (with `code`) and `PATCH /subjects/{id}`;
+/// omitted fields are left unchanged on update.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SubjectInput {
+    code: Option<String>,
+    birth_year: Option<i32>,
+    sex: Option<String>,
+    handedness: Option<String>,
+    notes: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubjectListQuery {
+    /// Only subjects whose code starts with this prefix.
+    code: Option<String>,
+    limit: Option<i64>,
+}
+
+impl Validate for SubjectListQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_limit(errors, self.limit);
+    }
+}
+
+const COLUMNS: &str = "id, code, birth_year, sex, handedness, notes, created_at";
+
+fn db_error(e: sqlx::Error) -> ApiError {
+    // 23505: unique_violation on code, 23503: foreign_key_violation when
+    // deleting a subject that sessions still reference.
+    match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23505") => {
+            ApiError::conflict("a subject with this code already exists")
+        }
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23503") => {
+            ApiError::conflict("subject still has sessions")
+        }
+        _ => e.into(),
+    }
+}
+
+fn not_found(id: i32) -> ApiError {
+    ApiError::not_found(format!("unknown subject {}", id))
+}
+
+fn check_code(errors: &mut FieldErrors, code: &str) {
+    if code.is_empty() || code.len() > MAX_CODE_LEN {
+        errors.add("code", format!("must be 1 to {} characters", MAX_CODE_LEN));
+    }
+    errors.check(
+        code.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-')),
+        "code",
+        "may only contain letters, digits, '_' and '-'",
+    );
+}
+
+fn check_choice(errors: &mut FieldErrors, field: &str, value: &Option<String>, allowed: &[&str]) {
+    if let Some(v) = value {
+        errors.check(
+            allowed.contains(&v.as_str()),
+            field,
+            format!("must be one of {}", allowed.join(", ")),
+        );
+    }
+}
+
+impl Subject {
+    /// Clears the fields that identify the subject.
+    pub fn redact(&mut self) {
+        self.code = None;
+        self.birth_year = None;
+        self.notes = None;
+    }
+}
+
+/// `subject`, redacted unless `principal` may identify subjects.
+fn shown(mut subject: Subject, principal: Option<&Principal>) -> Subject {
+    if !roles::may_identify(principal) {
+        subject.redact();
+    }
+    subject
+}
+
+impl Validate for SubjectInput {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(code) = &self.code {
+            check_code(errors, code);
+        }
+        if let Some(year) = self.birth_year {
+            errors.check(
+                (1900..=Utc::now().year()).contains(&year),
+                "birth_year",
+                "must be between 1900 and the current year",
+            );
+        }
+        check_choice(errors, "sex", &self.sex, SEXES);
+        check_choice(errors, "handedness", &self.handedness, HANDEDNESS);
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/subjects",
+    tag = "subjects",
+    params(SubjectListQuery),
+    responses((status = 200, description = "Identifying fields are cleared unless the caller is a clinician", body = Vec<Subject>), (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`"))
+)]
+pub async fn list_subjects(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Valid(Query(params)): Valid<Query<SubjectListQuery>>,
+) -> Result<Json<Vec<Subject>>, ApiError> {
+    let principal = principal.as_deref();
+    if params.code.is_some() && !roles::may_identify(principal) {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "only clinicians may search subjects by code",
+        ));
+    }
+    let subjects: Vec<Subject> = sqlx::query_as(&format!(
+        "SELECT {} FROM subjects WHERE ($1::text IS NULL OR starts_with(code, $1)) \
+         ORDER BY code LIMIT $2",
+        COLUMNS
+    ))
+    .bind(&params.code)
+    .bind(params.limit.unwrap_or(100))
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(
+        subjects
+            .into_iter()
+            .map(|subject| shown(subject, principal))
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/subjects/{id}",
+    tag = "subjects",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, description = "Identifying fields are cleared unless the caller is a clinician", body = Subject),
+        (status = 404, description = "Unknown subject")
+    )
+)]
+pub async fn get_subject(
+    State(state): State<AppState>,
+    principal: Option<Extension<Principal>>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<Json<Subject>, ApiError> {
+    sqlx::query_as(&format!("SELECT {} FROM subjects WHERE id = $1", COLUMNS))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .map(|subject| Json(shown(subject, principal.as_deref())))
+        .ok_or_else(|| not_found(id))
+}
+
+#[utoipa::path(
+    post,
+    path = "/subjects",
+    tag = "subjects",
+    request_body = SubjectInput,
+    responses(
+        (status = 201, body = Subject),
+        (status = 422, description = "Missing or invalid code, or invalid field, named in `details.fields`"),
+        (status = 409, description = "Code taken")
+    )
+)]
+pub async fn create_subject(
+    State(state): State<AppState>,
+    Valid(Json(input)): Valid<Json<SubjectInput>>,
+) -> Result<(StatusCode, Json<Subject>), ApiError> {
+    if input.code.is_none() {
+        return Err(FieldErrors::single("code", "is required").into());
+    }
+
+    let subject = sqlx::query_as(&format!(
+        "INSERT INTO subjects (code, birth_year, sex, handedness, notes) \
+         VALUES ($1, $2, $3, $4, $5) RETURNING {}",
+        COLUMNS
+    ))
+    .bind(&input.code)
+    .bind(input.birth_year)
+    .bind(&input.sex)
+    .bind(&input.handedness)
+    .bind(&input.notes)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok((StatusCode::CREATED, Json(subject)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/subjects/{id}",
+    tag = "subjects",
+    params(("id" = i32, Path)),
+    request_body = SubjectInput,
+    responses(
+        (status = 200, body = Subject),
+        (status = 422, description = "Invalid field, named in `details.fields`"),
+        (status = 404, description = "Unknown subject"),
+        (status = 409, description = "Code taken")
+    )
+)]
+pub async fn update_subject(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+    Valid(Json(input)): Valid<Json<SubjectInput>>,
+) -> Result<Json<Subject>, ApiError> {
+    sqlx::query_as(&format!(
+        "UPDATE subjects SET \
+         code = COALESCE($2, code), birth_year = COALESCE($3, birth_year), \
+         sex = COALESCE($4, sex), handedness = COALESCE($5, handedness), \
+         notes = COALESCE($6, notes) \
+         WHERE id = $1 RETURNING {}",
+        COLUMNS
+    ))
+    .bind(id)
+    .bind(&input.code)
+    .bind(input.birth_year)
+    .bind(&input.sex)
+    .bind(&input.handedness)
+    .bind(&input.notes)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .map(Json)
+    .ok_or_else(|| not_found(id))
+}
+
+/// Deletes a subject that no session references.
+#[utoipa::path(
+    delete,
+    path = "/subjects/{id}",
+    tag = "subjects",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "Unknown subject"),
+        (status = 409, description = "Subject still has sessions")
+    )
+)]
+pub async fn delete_subject(
+    State(state): State<AppState>,
+    Parsed(Path(id)): Parsed<Path<i32>>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM subjects WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(not_found(id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
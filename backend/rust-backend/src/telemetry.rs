@@ -0,0 +1,420 @@
+//! Logging and distributed tracing.
+//!
+//! Log lines go to stderr, filtered by `RUST_LOG`. When an OTLP endpoint is
+//! configured, `INFO` spans of HTTP requests and everything beneath them
+//! (handlers, database queries, signal processing) are also exported as
+//! OTLP/HTTP JSON. Requests carrying a W3C `traceparent` header join the
+//! caller's trace and follow its sampling decision; other requests start a
+//! trace of their own. Spans outside requests, such as background tasks,
+//! are not exported.
+//!
+//! Spans are batched (up to [`MAX_BATCH`] or every [`EXPORT_INTERVAL`]) and
+//! dropped when the collector cannot keep up; a failed export is logged and
+//! not retried.
+
+use crate::webhooks;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header::HeaderName,
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Instrument, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Finished spans waiting for export; more are dropped.
+const QUEUE_SPANS: usize = 4096;
+const MAX_BATCH: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_SERVICE_NAME: &str = "eeg-backend";
+
+const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// OTLP `SpanKind`.
+const KIND_INTERNAL: i32 = 1;
+const KIND_SERVER: i32 = 2;
+const KIND_CLIENT: i32 = 3;
+
+/// OTLP `StatusCode` of failed spans.
+const STATUS_ERROR: i32 = 2;
+
+/// Where spans are exported, from `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or
+/// `{OTEL_EXPORTER_OTLP_ENDPOINT}/v1/traces`.
+fn endpoint() -> Option<String> {
+    let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+    var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").or_else(|| {
+        var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .map(|base| format!("{}/v1/traces", base.trim_end_matches('/')))
+    })
+}
+
+/// Installs the log output and, if configured, the OTLP exporter. Must be
+/// called within the Tokio runtime.
+pub fn init() {
+    let endpoint = endpoint();
+    let (otlp, invalid) = match &endpoint {
+        Some(url) => match webhooks::check_url(url) {
+            Ok(()) => (Some(OtlpLayer::spawn(url.clone())), None),
+            Err(e) => (None, Some(e)),
+        },
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(otlp.map(|layer| layer.with_filter(LevelFilter::INFO)))
+        .init();
+    if let Some(e) = invalid {
+        tracing::error!("OTLP export disabled: {}", e);
+    }
+}
+
+/// Middleware opening the `server` span of a request.
+pub async fn trace(request: Request, next: Next) -> Response {
+    let method = request.method().as_str().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string());
+    let traceparent = request
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", method, route.as_deref().unwrap_or("unmatched")),
+        otel.kind = "server",
+        http.request.method = %method,
+        http.route = route.as_deref().unwrap_or(""),
+        url.path = request.uri().path(),
+        traceparent = %traceparent,
+        enduser.id = tracing::field::Empty,
+        http.response.status_code = tracing::field::Empty,
+        otel.status_code = tracing::field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    let status = response.status();
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    response
+}
+
+/// A remote parent from a `traceparent` header: trace id, span id and
+/// whether it is sampled.
+fn parse_traceparent(header: &str) -> Option<(u128, u64, bool)> {
+    let mut parts = header.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    let flags = parts.next()?;
+    if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 {
+        return None;
+    }
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace_id, 16)
+        .ok()
+        .filter(|id| *id != 0)?;
+    let span_id = u64::from_str_radix(span_id, 16)
+        .ok()
+        .filter(|id| *id != 0)?;
+    let flags = u8::from_str_radix(flags.get(..2)?, 16).ok()?;
+    Some((trace_id, span_id, flags & 1 == 1))
+}
+
+fn random_id() -> u128 {
+    uuid::Uuid::new_v4().as_u128()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
+
+/// A span being recorded, kept in the span's extensions.
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    sampled: bool,
+    name: String,
+    kind: i32,
+    error: bool,
+    start: SystemTime,
+    attributes: Vec<(String, Value)>,
+}
+
+/// Collects span fields; `otel.*` fields set the span's name, kind and
+/// status instead of becoming attributes.
+struct Fields<'a> {
+    data: &'a mut SpanData,
+    traceparent: Option<String>,
+}
+
+impl Fields<'_> {
+    fn set(&mut self, field: &Field, value: Value) {
+        match (field.name(), &value) {
+            ("otel.name", Value::String(name)) => self.data.name.clone_from(name),
+            ("otel.kind", Value::String(kind)) => {
+                self.data.kind = match kind.as_str() {
+                    "server" => KIND_SERVER,
+                    "client" => KIND_CLIENT,
+                    _ => KIND_INTERNAL,
+                }
+            }
+            ("otel.status_code", Value::String(code)) => self.data.error = code == "ERROR",
+            ("traceparent", Value::String(header)) => self.traceparent = Some(header.clone()),
+            (name, _) => {
+                let value = match value {
+                    Value::Bool(b) => json!({ "boolValue": b }),
+                    Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+                    // 64-bit integers are strings in OTLP JSON.
+                    Value::Number(n) => json!({ "intValue": n.to_string() }),
+                    other => json!({ "stringValue": other.as_str().unwrap_or_default() }),
+                };
+                match self.data.attributes.iter_mut().find(|(key, _)| key == name) {
+                    Some((_, existing)) => *existing = value,
+                    None => self.data.attributes.push((name.to_string(), value)),
+                }
+            }
+        }
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, json!(format!("{:?}", value)));
+    }
+}
+
+/// Layer turning closed spans into OTLP spans for the exporter task.
+struct OtlpLayer {
+    spans: mpsc::Sender<Value>,
+}
+
+impl OtlpLayer {
+    fn spawn(url: String) -> Self {
+        let (spans, receiver) = mpsc::channel(QUEUE_SPANS);
+        let service = std::env::var("OTEL_SERVICE_NAME")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+        tokio::spawn(export(url, service, receiver));
+        Self { spans }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut data = SpanData {
+            trace_id: 0,
+            span_id: random_id() as u64,
+            parent_span_id: None,
+            sampled: false,
+            name: attrs.metadata().name().to_string(),
+            kind: KIND_INTERNAL,
+            error: false,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        let mut fields = Fields {
+            data: &mut data,
+            traceparent: None,
+        };
+        attrs.record(&mut fields);
+        let traceparent = fields.traceparent;
+
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            extensions
+                .get::<SpanData>()
+                .map(|p| (p.trace_id, p.span_id, p.sampled))
+        });
+        let remote = traceparent.as_deref().and_then(parse_traceparent);
+        match parent.or(remote) {
+            Some((trace_id, parent_span_id, sampled)) => {
+                data.trace_id = trace_id;
+                data.parent_span_id = Some(parent_span_id);
+                data.sampled = sampled;
+            }
+            None => {
+                data.trace_id = random_id();
+                data.sampled = data.kind == KIND_SERVER;
+            }
+        }
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut Fields {
+                data,
+                traceparent: None,
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        if !data.sampled {
+            return;
+        }
+        let attributes: Vec<Value> = data
+            .attributes
+            .into_iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        let mut otlp = json!({
+            "traceId": format!("{:032x}", data.trace_id),
+            "spanId": format!("{:016x}", data.span_id),
+            "name": data.name,
+            "kind": data.kind,
+            "startTimeUnixNano": unix_nanos(data.start),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": attributes,
+        });
+        if let Some(parent) = data.parent_span_id {
+            otlp["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        if data.error {
+            otlp["status"] = json!({ "code": STATUS_ERROR });
+        }
+        // A full queue drops the span rather than blocking the request.
+        let _ = self.spans.try_send(otlp);
+    }
+}
+
+/// Sends the queued spans to `url` in batches.
+async fn export(url: String, service: String, mut spans: mpsc::Receiver<Value>) {
+    let mut ticker = tokio::time::interval(EXPORT_INTERVAL);
+    let mut batch = Vec::new();
+    let mut open = true;
+    while open || !batch.is_empty() {
+        let flush = tokio::select! {
+            span = spans.recv(), if open => match span {
+                Some(span) => {
+                    batch.push(span);
+                    batch.len() >= MAX_BATCH
+                }
+                None => {
+                    open = false;
+                    true
+                }
+            },
+            _ = ticker.tick() => true,
+        };
+        if !flush || batch.is_empty() {
+            continue;
+        }
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{ "key": "service.name", "value": { "stringValue": service } }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                    "spans": std::mem::take(&mut batch),
+                }],
+            }],
+        });
+        if let Err(e) = webhooks::post_json(&url, &body).await {
+            tracing::warn!("OTLP export to {} failed: {}", url, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn traceparents_name_the_remote_parent() {
+        let parent = parse_traceparent(&format!(" 00-{}-{}-01\r\n", TRACE, SPAN));
+        assert_eq!(
+            parent,
+            Some((0x4bf92f3577b34da6a3ce929d0e0e4736, 0x00f067aa0ba902b7, true))
+        );
+        let unsampled = parse_traceparent(&format!("00-{}-{}-00", TRACE, SPAN));
+        assert_eq!(unsampled.map(|(_, _, sampled)| sampled), Some(false));
+        // Later versions may append fields; version 00 may not.
+        let later = parse_traceparent(&format!("01-{}-{}-03-extra", TRACE, SPAN));
+        assert_eq!(later.map(|(_, _, sampled)| sampled), Some(true));
+        assert_eq!(
+            parse_traceparent(&format!("00-{}-{}-01-extra", TRACE, SPAN)),
+            None
+        );
+    }
+
+    #[test]
+    fn malformed_traceparents_are_ignored() {
+        let zeros = "0".repeat(32);
+        for header in [
+            String::new(),
+            format!("00-{}-{}", TRACE, SPAN),
+            format!("ff-{}-{}-01", TRACE, SPAN),
+            format!("0-{}-{}-01", TRACE, SPAN),
+            format!("00-{}-{}-01", &TRACE[1..], SPAN),
+            format!("00-{}-{}0-01", TRACE, SPAN),
+            format!("00-{}-{}-01", zeros, SPAN),
+            format!("00-{}-{}-01", TRACE, &zeros[..16]),
+            format!("00-{}-{}-01", TRACE.replace('a', "g"), SPAN),
+            format!("00-{}-{}-zz", TRACE, SPAN),
+            format!("00-{}-{}-1", TRACE, SPAN),
+        ] {
+            assert_eq!(parse_traceparent(&header), None, "{}", header);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! Pre-aggregated 1s/10s/1min min/max/avg tiers for long time spans.
+//!
+//! The `tiers` [job](crate::jobs) keeps `eeg_agg_1s`, `eeg_agg_10s` and
+//! `eeg_agg_1m` up to date: each tier is rebuilt from its watermark (or from
+//! the earliest sample written since the last run, to pick up late ingest)
+//! out of the next finer level. Tiers store `sum` and `count` so averages stay exact
+//! when coarser buckets are combined. The finest tier is built from rows and
+//! packed [blocks](crate::store::blocks) together.
+
+use crate::aggregate::{self, Bucket, Summary};
+use crate::error::ApiError;
+use crate::jobs::Job;
+use crate::store::blocks;
+use crate::validation::{FieldErrors, Parsed, Valid, Validate};
+use crate::{timezone, AppState, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use utoipa::IntoParams;
+
+/// Earliest sample `ts` (µs since epoch) written since the last refresh.
+static DIRTY_SINCE_US: AtomicI64 = AtomicI64::new(i64::MAX);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tier {
+    pub name: &'static str,
+    pub table: &'static str,
+    pub width_secs: f64,
+}
+
+/// Tiers from finest to coarsest; each is built from the previous one.
+pub const TIERS: [Tier; 3] = [
+    Tier {
+        name: "1s",
+        table: "eeg_agg_1s",
+        width_secs: 1.0,
+    },
+    Tier {
+        name: "10s",
+        table: "eeg_agg_10s",
+        width_secs: 10.0,
+    },
+    Tier {
+        name: "1m",
+        table: "eeg_agg_1m",
+        width_secs: 60.0,
+    },
+];
+
+/// Records that samples at or after `ts_us` were written.
+pub fn mark_dirty(ts_us: i64) {
+    DIRTY_SINCE_US.fetch_min(ts_us, Ordering::Relaxed);
+}
+
+/// The `tiers` [job](crate::jobs), which maintains every tier.
+pub struct Refresh {
+    pool: PgPool,
+    timescale: bool,
+    watermarks: [Option<DateTime<Utc>>; 3],
+}
+
+impl Refresh {
+    pub fn new(pool: PgPool, timescale: bool) -> Self {
+        Self {
+            pool,
+            timescale,
+            watermarks: [None; 3],
+        }
+    }
+}
+
+#[axum::async_trait]
+impl Job for Refresh {
+    async fn run(&mut self) -> Result<String, String> {
+        match refresh(&self.pool, self.timescale, &mut self.watermarks).await {
+            Ok(Some(start)) => Ok(format!(
+                "rebuilt from {}",
+                start.to_rfc3339_opts(SecondsFormat::Secs, true)
+            )),
+            Ok(None) => Ok("rebuilt from the first sample".to_string()),
+            Err(e) => Err(format!("aggregate tier refresh failed: {}", e)),
+        }
+    }
+}
+
+/// Brings every tier up to date, returning where the finest one was rebuilt
+/// from.
+async fn refresh(
+    pool: &PgPool,
+    timescale: bool,
+    watermarks: &mut [Option<DateTime<Utc>>; 3],
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let dirty = DIRTY_SINCE_US.swap(i64::MAX, Ordering::Relaxed);
+    let dirty = (dirty != i64::MAX)
+        .then(|| DateTime::<Utc>::from_timestamp_micros(dirty))
+        .flatten();
+    let now = Utc::now();
+
+    let result = async {
+        let mut changed_from = dirty;
+        let mut finest = None;
+        for (i, tier) in TIERS.iter().enumerate() {
+            let start = match (watermarks[i], changed_from) {
+                (Some(mark), Some(changed)) => Some(mark.min(changed)),
+                (Some(mark), None) => Some(mark),
+                // First run: resume after the newest stored bucket.
+                (None, changed) => match (last_bucket(pool, tier).await?, changed) {
+                    (Some(last), Some(changed)) => Some(last.min(changed)),
+                    (last, None) => last,
+                    (None, Some(_)) => None,
+                },
+            };
+            let source = if i == 0 { None } else { Some(TIERS[i - 1]) };
+            rebuild(pool, timescale, *tier, source, start, now).await?;
+            // Re-process the still-open bucket on the next run.
+            let open_bucket = chrono::Duration::milliseconds((tier.width_secs * 1000.0) as i64);
+            watermarks[i] = Some(now - open_bucket);
+            if i == 0 {
+                finest = start;
+            }
+            changed_from = start;
+        }
+        Ok(finest)
+    }
+    .await;
+
+    if result.is_err() {
+        if let Some(dirty) = dirty {
+            mark_dirty(dirty.timestamp_micros());
+        }
+    }
+    result
+}
+
+async fn last_bucket(pool: &PgPool, tier: &Tier) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar(&format!("SELECT MAX(bucket) FROM {}", tier.table))
+        .fetch_one(pool)
+        .await
+}
+
+/// Upserts buckets of `tier` covering `[start, now)` from raw samples, rows
+/// and blocks, or from `source`.
+async fn rebuild(
+    pool: &PgPool,
+    timescale: bool,
+    tier: Tier,
+    source: Option<Tier>,
+    start: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    let start = start.unwrap_or(DateTime::UNIX_EPOCH);
+    let select = match source {
+        None => format!(
+            "SELECT channel, b, MIN(min), MAX(max), SUM(sum), SUM(count) FROM ( \
+               SELECT channel, {} AS b, MIN(value) AS min, MAX(value) AS max, \
+               SUM(value) AS sum, COUNT(*) AS count \
+               FROM eeg_samples WHERE ts >= {} AND ts < $2 \
+               GROUP BY channel, b \
+               UNION ALL SELECT * FROM UNNEST($4::TEXT[], $5::TIMESTAMPTZ[], $6::FLOAT8[], \
+               $7::FLOAT8[], $8::FLOAT8[], $9::INT8[]) \
+             ) parts GROUP BY channel, b",
+            aggregate::bucket_expr(timescale, "$3", "ts"),
+            aggregate::bucket_expr(timescale, "$3", "$1::timestamptz"),
+        ),
+        Some(source) => format!(
+            "SELECT channel, {} AS b, MIN(min), MAX(max), SUM(sum), SUM(count) \
+             FROM {} WHERE bucket >= {} AND bucket < $2 \
+             GROUP BY channel, b",
+            aggregate::bucket_expr(timescale, "$3", "bucket"),
+            source.table,
+            aggregate::bucket_expr(timescale, "$3", "$1::timestamptz"),
+        ),
+    };
+    let sql = format!(
+        "INSERT INTO {} (channel, bucket, min, max, sum, count) {} \
+         ON CONFLICT (channel, bucket) DO UPDATE SET \
+         min = EXCLUDED.min, max = EXCLUDED.max, sum = EXCLUDED.sum, count = EXCLUDED.count",
+        tier.table, select
+    );
+    let query = sqlx::query(&sql)
+        .bind(start)
+        .bind(now)
+        .bind(tier.width_secs);
+    let query = match source {
+        Some(_) => query,
+        None => {
+            let packed = packed_buckets(pool, tier.width_secs, start, now).await?;
+            let mut columns = (vec![], vec![], vec![], vec![], vec![], vec![]);
+            for ((channel, bucket), s) in packed {
+                columns.0.push(channel);
+                columns.1.push(bucket);
+                columns.2.push(s.min);
+                columns.3.push(s.max);
+                columns.4.push(s.sum());
+                columns.5.push(s.count);
+            }
+            query
+                .bind(columns.0)
+                .bind(columns.1)
+                .bind(columns.2)
+                .bind(columns.3)
+                .bind(columns.4)
+                .bind(columns.5)
+        }
+    };
+    query.execute(pool).await?;
+    Ok(())
+}
+
+/// [`Summary`]s of the packed samples of every channel in the buckets of
+/// `width_secs` from the one holding `start` up to `now`.
+async fn packed_buckets(
+    pool: &PgPool,
+    width_secs: f64,
+    start: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<BTreeMap<(String, DateTime<Utc>), Summary>, sqlx::Error> {
+    let filter = SampleFilter {
+        from: Some(aggregate::bucket_of(width_secs, start)),
+        to: Some(now),
+        ..SampleFilter::default()
+    };
+    let mut buckets = BTreeMap::new();
+    blocks::for_each(pool, None, &filter, |s| {
+        let bucket = aggregate::bucket_of(width_secs, s.ts);
+        aggregate::merge_into(&mut buckets, (s.channel, bucket), Summary::of(s.value));
+    })
+    .await?;
+    Ok(buckets)
+}
+
+/// Picks the finest tier that keeps `span_secs` within `max_points` buckets.
+pub fn pick_tier(span_secs: f64, max_points: i64) -> Tier {
+    TIERS
+        .iter()
+        .copied()
+        .find(|t| span_secs / t.width_secs <= max_points as f64)
+        .unwrap_or(TIERS[TIERS.len() - 1])
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OverviewQuery {
+    channel: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// Maximum number of buckets to return (default 1000).
+    max_points: Option<i64>,
+    /// Zone of local `from` / `to`, documented with [`timezone::TimeQuery`].
+    #[param(ignore)]
+    tz: Option<timezone::Zone>,
+}
+
+impl Validate for OverviewQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        aggregate::check_range(errors, self.from.as_deref(), self.to.as_deref(), self.tz);
+    }
+}
+
+/// Reads a range from the best tier, re-bucketing the coarsest one when the
+/// span is too long even for 1-minute buckets.
+#[utoipa::path(
+    get,
+    path = "/samples/overview",
+    tag = "samples",
+    params(OverviewQuery, timezone::TimeQuery),
+    responses(
+        (status = 200, description = "As `/samples/aggregate`, plus the `tier` read", body = serde_json::Value),
+        (status = 422, description = "Invalid range, named in `details.fields`")
+    )
+)]
+pub async fn get_overview(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<OverviewQuery>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let channel = params.channel.unwrap_or_else(|| "A3".to_string());
+    let max_points = params
+        .max_points
+        .unwrap_or(1000)
+        .clamp(1, aggregate::MAX_BUCKETS);
+    let (from, to) = aggregate::parse_range(
+        params.from.as_deref(),
+        params.to.as_deref(),
+        times.tz.unwrap_or_default(),
+    )?;
+
+    let span = (to - from).num_milliseconds() as f64 / 1000.0;
+    let tier = pick_tier(span, max_points);
+    let width = tier.width_secs.max((span / max_points as f64).ceil());
+
+    let sql = format!(
+        "SELECT {} AS b, MIN(min), MAX(max), SUM(sum) / SUM(count)::FLOAT, SUM(count)::BIGINT \
+         FROM {} WHERE channel = $1 AND bucket >= $2 AND bucket < $3 \
+         GROUP BY b ORDER BY b",
+        aggregate::bucket_expr(state.timescale, "$4", "bucket"),
+        tier.table
+    );
+    let rows: Vec<(DateTime<Utc>, f64, f64, f64, i64)> = sqlx::query_as(&sql)
+        .bind(&channel)
+        .bind(from)
+        .bind(to)
+        .bind(width)
+        .fetch_all(state.reader())
+        .await?;
+
+    let buckets: Vec<Bucket> = rows
+        .into_iter()
+        .map(|(ts, min, max, avg, count)| Bucket {
+            ts: ts.to_rfc3339_opts(SecondsFormat::Millis, true),
+            min,
+            max,
+            avg,
+            count,
+        })
+        .collect();
+
+    let mut body = serde_json::json!({
+        "channel": channel,
+        "tier": tier.name,
+        "bucket_seconds": width,
+        "from": from.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "to": to.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "buckets": buckets,
+    });
+    times.apply(&mut body);
+    Ok(Json(body))
+}
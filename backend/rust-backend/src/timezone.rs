@@ -0,0 +1,254 @@
+//! Time zones of the times clients send and read.
+//!
+//! Everything is stored and compared in UTC. `from` / `to` take an RFC 3339
+//! timestamp with any offset, epoch milliseconds, or a local date and time
+//! (or a date, for its midnight) without an offset, read in the request's
+//! `tz` (UTC by default). A `+` of an offset that reached us as a space, as
+//! an unencoded `+` in a query string does, is read as `+`.
+//!
+//! Responses carry RFC 3339 UTC timestamps unless the request asks for
+//! another [`TimeQuery::tz`] or for epoch milliseconds with
+//! `time_format=epoch_ms`; [`TimeQuery::apply`] rewrites a JSON body so.
+
+use chrono::{
+    DateTime, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc,
+};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+use utoipa::{IntoParams, ToSchema};
+
+/// An IANA time zone such as `Europe/Berlin`, or a fixed offset such as
+/// `+02:00`; `UTC` and `Z` are UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Fixed(FixedOffset),
+    Named(chrono_tz::Tz),
+}
+
+impl Default for Zone {
+    fn default() -> Self {
+        Zone::Fixed(FixedOffset::east_opt(0).unwrap())
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Zone::Fixed(offset) => write!(f, "{}", offset),
+            Zone::Named(tz) => f.write_str(tz.name()),
+        }
+    }
+}
+
+impl FromStr for Zone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(offset) = parse_offset(s.trim_end()) {
+            return Ok(Zone::Fixed(offset));
+        }
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("utc") || s == "Z" {
+            return Ok(Zone::default());
+        }
+        s.parse::<chrono_tz::Tz>().map(Zone::Named).map_err(|_| {
+            format!(
+                "unknown time zone {:?}; expected a name such as Europe/Berlin or an offset such as +02:00",
+                s
+            )
+        })
+    }
+}
+
+/// `+HH:MM` or `-HH:MM`, also when the `+` arrived as a space.
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let (sign, rest) = match s.as_bytes().first()? {
+        b'+' | b' ' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (i32, i32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+impl<'de> Deserialize<'de> for Zone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Zone {
+    /// The UTC instant of local time `local` here; an error when the zone
+    /// skips it or passes it twice, as around daylight saving changes.
+    pub fn resolve(&self, local: NaiveDateTime) -> Result<DateTime<Utc>, String> {
+        let resolved = match self {
+            Zone::Fixed(offset) => offset.from_local_datetime(&local).map(|t| t.to_utc()),
+            Zone::Named(tz) => tz.from_local_datetime(&local).map(|t| t.to_utc()),
+        };
+        match resolved {
+            LocalResult::Single(ts) => Ok(ts),
+            LocalResult::Ambiguous(..) => {
+                Err(format!("{} is ambiguous in {}; add an offset", local, self))
+            }
+            LocalResult::None => Err(format!("{} does not exist in {}", local, self)),
+        }
+    }
+
+    /// `ts` as RFC 3339 in this zone's offset at that instant.
+    pub fn format(&self, ts: DateTime<Utc>) -> String {
+        match self {
+            Zone::Fixed(offset) if offset.local_minus_utc() == 0 => {
+                ts.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            }
+            Zone::Fixed(offset) => ts
+                .with_timezone(offset)
+                .to_rfc3339_opts(SecondsFormat::AutoSi, false),
+            Zone::Named(tz) => ts
+                .with_timezone(tz)
+                .to_rfc3339_opts(SecondsFormat::AutoSi, false),
+        }
+    }
+}
+
+/// A time as a client sent it, before a zone is known for local times.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instant {
+    At(DateTime<Utc>),
+    Local(NaiveDateTime),
+}
+
+impl Instant {
+    pub fn resolve(self, zone: Zone) -> Result<DateTime<Utc>, String> {
+        match self {
+            Instant::At(ts) => Ok(ts),
+            Instant::Local(local) => zone.resolve(local),
+        }
+    }
+}
+
+impl FromStr for Instant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if let Ok(ms) = s.parse::<i64>() {
+            return DateTime::from_timestamp_millis(ms)
+                .map(Instant::At)
+                .ok_or_else(|| format!("epoch milliseconds {} are out of range", ms));
+        }
+        if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Instant::At(ts.to_utc()));
+        }
+        // `...T10:00:00 01:00`: an offset whose `+` was decoded as a space.
+        if let Some((local, offset)) = s.rsplit_once(' ').filter(|(local, _)| local.contains(':')) {
+            if let Some(offset) = parse_offset(&format!("+{}", offset)) {
+                if let Ok(Instant::Local(local)) = local.parse::<Instant>() {
+                    return Zone::Fixed(offset).resolve(local).map(Instant::At);
+                }
+            }
+        }
+        [
+            "%Y-%m-%dT%H:%M:%S%.f",
+            "%Y-%m-%d %H:%M:%S%.f",
+            "%Y-%m-%dT%H:%M",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })
+        .map(Instant::Local)
+        .ok_or_else(|| {
+            format!(
+                "{:?} is not an RFC 3339 timestamp, epoch milliseconds or a local date and time",
+                s
+            )
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Instant {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeFormat {
+    /// RFC 3339 strings.
+    #[default]
+    Rfc3339,
+    /// Integer milliseconds since 1970-01-01 UTC.
+    EpochMs,
+}
+
+/// How response timestamps are written.
+#[derive(Debug, Clone, Copy, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TimeQuery {
+    /// Time zone of returned timestamps, and of `from` / `to` given without
+    /// an offset: an IANA name (`Europe/Berlin`) or an offset (`+02:00`);
+    /// UTC by default.
+    #[param(value_type = Option<String>)]
+    pub tz: Option<Zone>,
+    /// `rfc3339` (default) or `epoch_ms`.
+    pub time_format: Option<TimeFormat>,
+}
+
+impl TimeQuery {
+    /// Rewrites the RFC 3339 UTC timestamps in `body` for this query.
+    /// `metadata` objects of events are left as their clients sent them.
+    pub fn apply(&self, body: &mut Value) {
+        let format = self.time_format.unwrap_or_default();
+        if format == TimeFormat::Rfc3339 && self.tz.is_none() {
+            return;
+        }
+        let zone = self.tz.unwrap_or_default();
+        rewrite(body, &|ts| match format {
+            TimeFormat::Rfc3339 => Value::String(zone.format(ts)),
+            TimeFormat::EpochMs => Value::from(ts.timestamp_millis()),
+        });
+    }
+
+    /// `body` serialized and passed through [`TimeQuery::apply`].
+    pub fn json(&self, body: &impl serde::Serialize) -> Result<Value, serde_json::Error> {
+        let mut value = serde_json::to_value(body)?;
+        self.apply(&mut value);
+        Ok(value)
+    }
+}
+
+fn rewrite(value: &mut Value, to: &impl Fn(DateTime<Utc>) -> Value) {
+    match value {
+        Value::String(s) if s.ends_with('Z') => {
+            if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+                *value = to(ts.to_utc());
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| rewrite(v, to)),
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if key != "metadata" {
+                    rewrite(value, to);
+                }
+            }
+        }
+        _ => {}
+    }
+}
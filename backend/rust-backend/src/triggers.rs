@@ -0,0 +1,152 @@
+//! Digital trigger channels (`kind` `trigger` in the channel registry).
+//!
+//! A trigger channel carries a code that holds until the next one, usually
+//! 0 between stimuli. Ingest diverts its samples from `eeg_samples` into
+//! `trigger_changes`, keeping only those whose code differs from the one
+//! before in the same channel and session; they are not given sample ids.
+//! Every change to a non-zero code also records a marker event
+//! `trigger:<code>` with `source` `trigger` in its metadata.
+//!
+//! Imports copy trigger channels into `eeg_samples` as any other channel.
+
+use crate::channels;
+use crate::error::ApiError;
+use crate::ingest::NewSample;
+use crate::validation::{check_limit, FieldErrors, Parsed, Valid, Validate};
+use crate::{timezone, AppState, SampleFilter};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, QueryBuilder};
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_LIMIT: i64 = 1000;
+
+/// A change of code on a trigger channel.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Transition {
+    pub id: i32,
+    pub channel: String,
+    pub session_id: Option<i32>,
+    pub ts: DateTime<Utc>,
+    pub value: f64,
+    /// The code before; null for the channel's first in the session.
+    pub previous: Option<f64>,
+}
+
+/// Typed part of `GET /triggers`; `from`, `to`, `session_id` and
+/// `subject_id` are read as a [`SampleFilter`].
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TriggerQuery {
+    /// Only changes of this channel.
+    channel: Option<String>,
+    /// Only changes to this code.
+    value: Option<f64>,
+    /// At most this many, 1000 by default.
+    limit: Option<i64>,
+}
+
+impl Validate for TriggerQuery {
+    fn validate(&self, errors: &mut FieldErrors) {
+        check_limit(errors, self.limit);
+    }
+}
+
+/// Takes the samples of trigger channels out of `samples`.
+pub fn divert(samples: &mut Vec<NewSample>) -> Vec<NewSample> {
+    if !samples.iter().any(|s| channels::is_trigger(&s.channel)) {
+        return Vec::new();
+    }
+    let (triggers, signal) = std::mem::take(samples)
+        .into_iter()
+        .partition(|s| channels::is_trigger(&s.channel));
+    *samples = signal;
+    triggers
+}
+
+/// Stores the changes among `samples` of trigger channels, and an event
+/// for each change to a non-zero code, in one statement. Changes already
+/// stored, as from a retried batch, are skipped with their events.
+pub async fn store<'a>(
+    pool: &PgPool,
+    samples: impl IntoIterator<Item = &'a NewSample>,
+) -> Result<(), sqlx::Error> {
+    let (mut ts, mut channels, mut values, mut sessions) = (vec![], vec![], vec![], vec![]);
+    for sample in samples {
+        ts.push(sample.ts);
+        channels.push(sample.channel.as_str());
+        values.push(sample.value);
+        sessions.push(sample.session_id);
+    }
+    if ts.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        "WITH batch AS ( \
+           SELECT s.*, lag(s.value) OVER (PARTITION BY s.channel, s.session_id ORDER BY s.ts) AS before \
+           FROM UNNEST($1::timestamptz[], $2::text[], $3::float8[], $4::int4[]) \
+             AS s(ts, channel, value, session_id)), \
+         changes AS ( \
+           INSERT INTO trigger_changes (channel, session_id, ts, value) \
+           SELECT b.channel, b.session_id, b.ts, b.value FROM batch b \
+           WHERE b.value IS DISTINCT FROM COALESCE(b.before, ( \
+             SELECT t.value FROM trigger_changes t \
+             WHERE t.channel = b.channel AND t.session_id IS NOT DISTINCT FROM b.session_id \
+             AND t.ts < b.ts ORDER BY t.ts DESC LIMIT 1)) \
+           ON CONFLICT DO NOTHING \
+           RETURNING channel, session_id, ts, value) \
+         INSERT INTO events (session_id, ts, label, metadata) \
+         SELECT session_id, ts, 'trigger:' || value, \
+           jsonb_build_object('source', 'trigger', 'channel', channel, 'value', value) \
+         FROM changes WHERE value <> 0",
+    )
+    .bind(&ts)
+    .bind(&channels)
+    .bind(&values)
+    .bind(&sessions)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Changes of trigger channels, oldest first.
+#[utoipa::path(
+    get,
+    path = "/triggers",
+    tag = "events",
+    params(TriggerQuery, SampleFilter, timezone::TimeQuery),
+    responses(
+        (status = 200, description = "Oldest first", body = Vec<Transition>),
+        (status = 422, description = "Parameters that do not parse or are out of range, named in `details.fields`")
+    )
+)]
+pub async fn list_triggers(
+    State(state): State<AppState>,
+    Valid(Query(params)): Valid<Query<TriggerQuery>>,
+    Valid(Query(filter)): Valid<Query<SampleFilter>>,
+    Parsed(Query(times)): Parsed<Query<timezone::TimeQuery>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut query = QueryBuilder::new(
+        "SELECT id, channel, session_id, ts, value, \
+         (SELECT p.value FROM trigger_changes p \
+          WHERE p.channel = t.channel AND p.session_id IS NOT DISTINCT FROM t.session_id \
+          AND p.ts < t.ts ORDER BY p.ts DESC LIMIT 1) AS previous \
+         FROM trigger_changes t WHERE TRUE",
+    );
+    if let Some(channel) = &params.channel {
+        query.push(" AND channel = ").push_bind(channel);
+    }
+    if let Some(value) = params.value {
+        query.push(" AND value = ").push_bind(value);
+    }
+    filter.push_to(&mut query);
+    query
+        .push(" ORDER BY ts, id LIMIT ")
+        .push_bind(params.limit.unwrap_or(DEFAULT_LIMIT));
+    let transitions: Vec<Transition> = query.build_query_as().fetch_all(state.reader()).await?;
+    Ok(Json(times.json(&transitions).map_err(ApiError::internal)?))
+}
@@ -0,0 +1,346 @@
+//! Optional UDP listener for acquisition hardware on the local network.
+//!
+//! Each datagram carries consecutive samples of one channel. All fields
+//! are little-endian:
+//!
+//! | offset | type | field                                |
+//! |--------|------|--------------------------------------|
+//! | 0      | u32  | sequence number, per sender/channel  |
+//! | 4      | u16  | channel id                           |
+//! | 6      | u16  | value count                          |
+//! | 8      | i64  | timestamp of the first value, µs     |
+//! | 16     | f32… | values, spaced by the sample rate    |
+//!
+//! Packets carry their own timestamps, so late packets are stored as they
+//! arrive; duplicates and packets older than the reorder window are dropped,
+//! and gaps in the sequence are counted as losses.
+
+use crate::config;
+use crate::ingest::{self, NewSample};
+use crate::shutdown;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+pub const HEADER_LEN: usize = 16;
+
+/// Sequence numbers remembered per sender/channel for reordering and dedup.
+const REORDER_WINDOW: u32 = 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_DATAGRAM: usize = 65_536;
+
+#[derive(Debug, Clone)]
+pub struct UdpConfig {
+    pub bind: SocketAddr,
+    pub sample_rate: f64,
+    /// Channel names indexed by channel id; ids beyond the list map to `CH<id>`.
+    pub channels: Vec<String>,
+}
+
+impl UdpConfig {
+    /// The listener of the `[udp]` settings; `None` without a bind address.
+    pub fn from_config(config: &config::UdpConfig) -> Option<Self> {
+        Some(Self {
+            bind: config.bind?,
+            sample_rate: config.sample_rate,
+            channels: config.channels.clone(),
+        })
+    }
+
+    fn channel_name(&self, id: u16) -> String {
+        self.channels
+            .get(id as usize)
+            .cloned()
+            .unwrap_or_else(|| format!("CH{}", id))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Datagram {
+    pub seq: u32,
+    pub channel_id: u16,
+    pub start_us: i64,
+    pub values: Vec<f32>,
+}
+
+impl Datagram {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HEADER_LEN {
+            return Err(format!(
+                "datagram shorter than the {}-byte header",
+                HEADER_LEN
+            ));
+        }
+        let seq = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let channel_id = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let count = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+        let mut start = [0u8; 8];
+        start.copy_from_slice(&bytes[8..16]);
+        let start_us = i64::from_le_bytes(start);
+
+        let body = &bytes[HEADER_LEN..];
+        if body.len() != count * 4 {
+            return Err(format!(
+                "header declares {} values but the body holds {} bytes",
+                count,
+                body.len()
+            ));
+        }
+        let values = body
+            .chunks_exact(4)
+            .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+            .collect();
+        Ok(Self {
+            seq,
+            channel_id,
+            start_us,
+            values,
+        })
+    }
+}
+
+/// Per-stream sequence bookkeeping.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    highest: Option<u32>,
+    seen: HashSet<u32>,
+    order: VecDeque<u32>,
+    pub dropped: u64,
+    pub reordered: u64,
+    pub duplicates: u64,
+}
+
+impl SequenceTracker {
+    /// Records `seq` and reports whether the packet should be stored.
+    pub fn accept(&mut self, seq: u32) -> bool {
+        if self.seen.contains(&seq) {
+            self.duplicates += 1;
+            return false;
+        }
+        match self.highest {
+            None => self.highest = Some(seq),
+            Some(high) => {
+                let ahead = seq.wrapping_sub(high);
+                if ahead == 0 {
+                    self.duplicates += 1;
+                    return false;
+                } else if ahead < u32::MAX / 2 {
+                    // New packet; anything skipped is provisionally lost.
+                    self.dropped += (ahead - 1) as u64;
+                    self.highest = Some(seq);
+                } else if high.wrapping_sub(seq) < REORDER_WINDOW {
+                    // Late packet filling an earlier gap.
+                    self.dropped = self.dropped.saturating_sub(1);
+                    self.reordered += 1;
+                } else {
+                    return false;
+                }
+            }
+        }
+        self.seen.insert(seq);
+        self.order.push_back(seq);
+        if self.order.len() > REORDER_WINDOW as usize {
+            if let Some(old) = self.order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Binds the socket and starts the listener task.
+pub async fn spawn(config: UdpConfig, pool: PgPool) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(config.bind).await?;
+    tracing::info!("UDP ingest listening on {}", config.bind);
+    let tx = ingest::spawn_writer(pool, "UDP listener");
+    tokio::spawn(run(socket, config, tx));
+    Ok(())
+}
+
+async fn run(socket: UdpSocket, config: UdpConfig, tx: mpsc::Sender<Vec<NewSample>>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut trackers: HashMap<(SocketAddr, u16), SequenceTracker> = HashMap::new();
+    let mut pending: Vec<NewSample> = Vec::new();
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    let period_us = 1_000_000.0 / config.sample_rate;
+    let mut stopping = std::pin::pin!(shutdown::requested());
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = match received {
+                    Ok(r) => r,
+                    Err(e) => {
+                        tracing::warn!("UDP listener receive error: {}", e);
+                        continue;
+                    }
+                };
+                let datagram = match Datagram::parse(&buf[..len]) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        tracing::warn!("UDP listener dropped datagram from {}: {}", from, e);
+                        continue;
+                    }
+                };
+                let tracker = trackers.entry((from, datagram.channel_id)).or_default();
+                let before = tracker.dropped;
+                if !tracker.accept(datagram.seq) {
+                    continue;
+                }
+                if tracker.dropped > before {
+                    tracing::debug!(
+                        "UDP listener: {} packets missing from {} channel {}",
+                        tracker.dropped - before,
+                        from,
+                        datagram.channel_id
+                    );
+                }
+
+                let channel = config.channel_name(datagram.channel_id);
+                for (i, &value) in datagram.values.iter().enumerate() {
+                    if !value.is_finite() {
+                        continue;
+                    }
+                    let offset = (i as f64 * period_us).round() as i64;
+                    let Some(ts) = ingest::ts_from_micros(datagram.start_us + offset) else {
+                        continue;
+                    };
+                    pending.push(NewSample {
+                        channel: channel.clone(),
+                        ts,
+                        value: value as f64,
+                        session_id: None,
+                    });
+                }
+            }
+            _ = flush.tick() => {
+                if pending.is_empty() {
+                    continue;
+                }
+                if tx.send(std::mem::take(&mut pending)).await.is_err() {
+                    return;
+                }
+            }
+            _ = &mut stopping => {
+                if !pending.is_empty() {
+                    let _ = tx.send(pending).await;
+                }
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(seq: u32, channel_id: u16, count: u16, start_us: i64, values: &[f32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&seq.to_le_bytes());
+        bytes.extend_from_slice(&channel_id.to_le_bytes());
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes.extend_from_slice(&start_us.to_le_bytes());
+        for value in values {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn datagrams_decode_little_endian_fields() {
+        let bytes = datagram(70_000, 3, 2, 1_700_000_000_123_456, &[1.5, -20.25]);
+        assert_eq!(
+            Datagram::parse(&bytes),
+            Ok(Datagram {
+                seq: 70_000,
+                channel_id: 3,
+                start_us: 1_700_000_000_123_456,
+                values: vec![1.5, -20.25],
+            })
+        );
+        let empty = Datagram::parse(&datagram(1, 0, 0, -5, &[])).unwrap();
+        assert_eq!((empty.start_us, empty.values.len()), (-5, 0));
+    }
+
+    #[test]
+    fn malformed_datagrams_are_rejected() {
+        assert_eq!(
+            Datagram::parse(&[0; HEADER_LEN - 1]),
+            Err("datagram shorter than the 16-byte header".to_string())
+        );
+        assert_eq!(
+            Datagram::parse(&datagram(1, 0, 3, 0, &[1.0, 2.0])),
+            Err("header declares 3 values but the body holds 8 bytes".to_string())
+        );
+        let mut trailing = datagram(1, 0, 1, 0, &[1.0]);
+        trailing.push(0);
+        assert!(Datagram::parse(&trailing).is_err());
+    }
+
+    #[test]
+    fn channel_ids_name_the_configured_channels() {
+        let config = UdpConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+            sample_rate: 250.0,
+            channels: vec!["Fz".to_string(), "Cz".to_string()],
+        };
+        assert_eq!(config.channel_name(1), "Cz");
+        assert_eq!(config.channel_name(2), "CH2");
+    }
+
+    #[test]
+    fn late_packets_fill_gaps_and_duplicates_are_dropped() {
+        let mut tracker = SequenceTracker::default();
+        assert!(tracker.accept(10));
+        assert!(tracker.accept(11));
+        assert!(tracker.accept(14));
+        assert_eq!(tracker.dropped, 2);
+
+        assert!(tracker.accept(12));
+        assert_eq!((tracker.dropped, tracker.reordered), (1, 1));
+        assert!(!tracker.accept(12));
+        assert!(!tracker.accept(14));
+        assert_eq!(tracker.duplicates, 2);
+        assert!(tracker.accept(15));
+        assert_eq!(
+            (tracker.dropped, tracker.reordered, tracker.duplicates),
+            (1, 1, 2)
+        );
+    }
+
+    #[test]
+    fn packets_older_than_the_window_are_dropped() {
+        let mut tracker = SequenceTracker::default();
+        assert!(tracker.accept(5000));
+        assert!(!tracker.accept(5000 - REORDER_WINDOW));
+        assert!(tracker.accept(5001 - REORDER_WINDOW));
+        assert_eq!((tracker.reordered, tracker.duplicates), (1, 0));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut tracker = SequenceTracker::default();
+        assert!(tracker.accept(u32::MAX - 1));
+        assert!(tracker.accept(1));
+        assert_eq!(tracker.dropped, 2);
+        assert!(tracker.accept(u32::MAX));
+        assert!(tracker.accept(0));
+        assert_eq!((tracker.dropped, tracker.reordered), (0, 2));
+    }
+
+    #[test]
+    fn the_window_forgets_old_sequence_numbers() {
+        let mut tracker = SequenceTracker::default();
+        for seq in 0..=REORDER_WINDOW + 10 {
+            assert!(tracker.accept(seq));
+        }
+        assert_eq!(tracker.seen.len(), REORDER_WINDOW as usize);
+        assert!(!tracker.seen.contains(&0));
+        assert!(!tracker.accept(0));
+        assert_eq!(tracker.duplicates, 0);
+    }
+}
@@ -0,0 +1,412 @@
+//! Validation of query parameters and JSON bodies.
+//!
+//! Request types implement [`Validate`] and handlers extract them as
+//! `Valid<Query<T>>` or `Valid<Json<T>>`; types without rules, and paths,
+//! are extracted as `Parsed<Query<T>>`, `Parsed<Json<T>>` and
+//! `Parsed<Path<T>>`. Parameters that do not parse or break a rule are
+//! answered with `422 Unprocessable Entity` before the handler runs, naming
+//! every offending field:
+//!
+//! ```json
+//! {"code": "unprocessable_entity",
+//!  "message": "invalid request: limit: must be 1 to 1000; to: must be after from",
+//!  "details": {"fields": {"limit": "must be 1 to 1000", "to": "must be after from"}}}
+//! ```
+//!
+//! Fields are named as in the request: `limit`, or `[3].value` for the
+//! fourth sample of a batch. Errors about the query or body as a whole,
+//! such as a missing field, are reported under `query` or `body`. JSON
+//! that is not well-formed is `400`, and a body without
+//! `Content-Type: application/json` is `415`. A path segment that does not
+//! parse, such as `/sessions/abc`, is `400`.
+
+use crate::error::ApiError;
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::PathRejection, FromRequest, FromRequestParts, Path, Query, Request},
+    http::{header, request::Parts, StatusCode},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::fmt;
+
+/// Rules a request type checks beyond what its fields' types express.
+pub trait Validate {
+    /// Adds a message to `errors` for each field that breaks a rule.
+    fn validate(&self, errors: &mut FieldErrors);
+}
+
+/// Messages per field, in the order they were found; the first one counts
+/// when a field breaks several rules.
+#[derive(Debug, Default)]
+pub struct FieldErrors(Vec<(String, String)>);
+
+impl FieldErrors {
+    /// One message, for a rule the handler checks once the request has
+    /// been extracted, such as a field required on create only.
+    pub fn single(field: impl Into<String>, message: impl Into<String>) -> Self {
+        let mut errors = FieldErrors::default();
+        errors.add(field, message);
+        errors
+    }
+
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        let field = field.into();
+        if !self.0.iter().any(|(f, _)| *f == field) {
+            self.0.push((field, message.into()));
+        }
+    }
+
+    /// Adds `message` for `field` unless `ok`.
+    pub fn check(&mut self, ok: bool, field: &str, message: impl Into<String>) {
+        if !ok {
+            self.add(field, message);
+        }
+    }
+
+    /// Validates `value` as the part of the request at `prefix`, such as
+    /// `[3]` for an index or `filter` for a nested object.
+    pub fn within(&mut self, prefix: &str, value: &(impl Validate + ?Sized)) {
+        let mut inner = FieldErrors::default();
+        value.validate(&mut inner);
+        for (field, message) in inner.0 {
+            let path = if field.starts_with('[') {
+                format!("{}{}", prefix, field)
+            } else {
+                format!("{}.{}", prefix, field)
+            };
+            self.add(path, message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for FieldErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (field, message)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}: {}", field, message)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<FieldErrors> for ApiError {
+    fn from(errors: FieldErrors) -> Self {
+        let fields: serde_json::Map<String, serde_json::Value> = errors
+            .0
+            .iter()
+            .map(|(field, message)| (field.clone(), json!(message)))
+            .collect();
+        ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("invalid request: {}", errors),
+        )
+        .with_details(json!({ "fields": fields }))
+    }
+}
+
+/// The rules `value` breaks, if any.
+pub fn check(value: &(impl Validate + ?Sized)) -> Result<(), FieldErrors> {
+    let mut errors = FieldErrors::default();
+    value.validate(&mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Most rows one page of a list may ask for.
+pub const MAX_LIMIT: i64 = 1000;
+
+/// Checks a page's `limit` against 1 to [`MAX_LIMIT`].
+pub fn check_limit(errors: &mut FieldErrors, limit: Option<i64>) {
+    if let Some(limit) = limit {
+        errors.check(
+            (1..=MAX_LIMIT).contains(&limit),
+            "limit",
+            format!("must be 1 to {}", MAX_LIMIT),
+        );
+    }
+}
+
+/// An extractor, `Query` or `Json`, whose value has been validated.
+pub struct Valid<E>(pub E);
+
+/// An extractor, `Query`, `Path` or `Json`, for requests without rules of
+/// their own: values that do not parse are answered with an [`ApiError`],
+/// in the same shape as for [`Valid`], instead of axum's plain-text
+/// rejection.
+pub struct Parsed<E>(pub E);
+
+/// The field a deserialization error is about, or `whole` when it is about
+/// the query or body as a whole.
+fn parse_error<E: fmt::Display>(error: serde_path_to_error::Error<E>, whole: &str) -> ApiError {
+    let path = error.path().to_string();
+    let field = if path == "." { whole } else { &path };
+    let mut errors = FieldErrors::default();
+    errors.add(field, error.inner().to_string());
+    errors.into()
+}
+
+fn parse_query<T: DeserializeOwned>(parts: &Parts) -> Result<T, ApiError> {
+    let query = parts.uri.query().unwrap_or_default();
+    let deserializer =
+        serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+    serde_path_to_error::deserialize(deserializer).map_err(|e| parse_error(e, "query"))
+}
+
+/// The media type of a `Content-Type` value, without its parameters and in
+/// lower case: `application/octet-stream` for
+/// `Application/Octet-Stream; charset=binary`.
+pub fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether `content_type` is `application/json` or `application/*+json`.
+fn is_json(content_type: &str) -> bool {
+    let essence = media_type(content_type);
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+async fn parse_json<T, S>(request: Request, state: &S) -> Result<T, ApiError>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    let json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(is_json);
+    if !json {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected Content-Type: application/json",
+        ));
+    }
+    let body = Bytes::from_request(request, state)
+        .await
+        .map_err(|e| ApiError::new(e.status(), e.body_text()))?;
+    let mut deserializer = serde_json::Deserializer::from_slice(&body);
+    let value: T = match serde_path_to_error::deserialize(&mut deserializer) {
+        Ok(value) => value,
+        Err(e) if e.inner().is_data() => return Err(parse_error(e, "body")),
+        Err(e) => {
+            return Err(ApiError::bad_request(format!(
+                "invalid JSON: {}",
+                e.inner()
+            )))
+        }
+    };
+    deserializer
+        .end()
+        .map_err(|e| ApiError::bad_request(format!("invalid JSON: {}", e)))?;
+    Ok(value)
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        if rejection.status().is_server_error() {
+            ApiError::internal(rejection.body_text())
+        } else {
+            ApiError::new(rejection.status(), rejection.body_text())
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Valid<Query<T>>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, ApiError> {
+        let value: T = parse_query(parts)?;
+        check(&value)?;
+        Ok(Valid(Query(value)))
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Parsed<Query<T>>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, ApiError> {
+        Ok(Parsed(Query(parse_query(parts)?)))
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Parsed<Path<T>>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ApiError> {
+        Ok(Parsed(Path::from_request_parts(parts, state).await?))
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Valid<Json<T>>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, ApiError> {
+        let value: T = parse_json(request, state).await?;
+        check(&value)?;
+        Ok(Valid(Json(value)))
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Parsed<Json<T>>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, ApiError> {
+        Ok(Parsed(Json(parse_json(request, state).await?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Page {
+        limit: Option<i64>,
+        name: String,
+    }
+
+    impl Validate for Page {
+        fn validate(&self, errors: &mut FieldErrors) {
+            check_limit(errors, self.limit);
+            errors.check(!self.name.is_empty(), "name", "must not be empty");
+        }
+    }
+
+    struct Pages(Vec<Page>);
+
+    impl Validate for Pages {
+        fn validate(&self, errors: &mut FieldErrors) {
+            for (i, page) in self.0.iter().enumerate() {
+                errors.within(&format!("[{}]", i), page);
+            }
+        }
+    }
+
+    #[test]
+    fn the_first_message_of_a_field_counts() {
+        let mut errors = FieldErrors::default();
+        assert!(errors.is_empty());
+        errors.add("to", "must be after from");
+        errors.check(true, "limit", "never added");
+        errors.check(false, "to", "must be before now");
+        errors.add("limit", "must be 1 to 1000");
+        assert_eq!(
+            errors.to_string(),
+            "to: must be after from; limit: must be 1 to 1000"
+        );
+    }
+
+    #[test]
+    fn limits_run_from_one_to_the_maximum() {
+        let page = |limit| Page {
+            limit,
+            name: "a".to_string(),
+        };
+        assert!(check(&page(None)).is_ok());
+        assert!(check(&page(Some(1))).is_ok());
+        assert!(check(&page(Some(MAX_LIMIT))).is_ok());
+        for limit in [0, -1, MAX_LIMIT + 1] {
+            let errors = check(&page(Some(limit))).unwrap_err();
+            assert_eq!(errors.to_string(), "limit: must be 1 to 1000");
+        }
+    }
+
+    #[test]
+    fn nested_fields_are_named_by_their_path() {
+        let pages = Pages(vec![
+            Page {
+                limit: Some(10),
+                name: "a".to_string(),
+            },
+            Page {
+                limit: Some(0),
+                name: String::new(),
+            },
+        ]);
+        let errors = check(&pages).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "[1].limit: must be 1 to 1000; [1].name: must not be empty"
+        );
+
+        let mut errors = FieldErrors::default();
+        errors.within("filter", &pages);
+        errors.within("page", &pages.0[1]);
+        assert_eq!(
+            errors.to_string(),
+            "filter[1].limit: must be 1 to 1000; filter[1].name: must not be empty; \
+             page.limit: must be 1 to 1000; page.name: must not be empty"
+        );
+    }
+
+    #[test]
+    fn field_errors_are_unprocessable_with_each_field() {
+        let mut errors = FieldErrors::single("limit", "must be 1 to 1000");
+        errors.add("to", "must be after from");
+        let error = ApiError::from(errors);
+        assert_eq!(error.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            error.message,
+            "invalid request: limit: must be 1 to 1000; to: must be after from"
+        );
+        assert_eq!(
+            error.details,
+            Some(json!({ "fields": { "limit": "must be 1 to 1000", "to": "must be after from" } }))
+        );
+    }
+
+    #[test]
+    fn media_types_drop_parameters_and_case() {
+        assert_eq!(
+            media_type("Application/Octet-Stream; charset=binary"),
+            "application/octet-stream"
+        );
+        assert_eq!(media_type(" application/json "), "application/json");
+        assert_eq!(media_type(""), "");
+        assert!(is_json("application/json; charset=utf-8"));
+        assert!(is_json("application/problem+json"));
+        assert!(!is_json("text/json"));
+    }
+}
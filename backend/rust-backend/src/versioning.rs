@@ -0,0 +1,93 @@
+//! API versions of the REST data routes.
+//!
+//! Data routes are served under a major version prefix, `/v1`. Breaking
+//! changes to a route's parameters or payloads go into the next version
+//! (`/v2`), served next to the previous one until clients have moved;
+//! additive changes, such as new optional parameters or response fields,
+//! are made within a version. A request for a version this backend does not
+//! serve is answered with `404` naming the [`SUPPORTED`] ones. Health
+//! probes, `/metrics` and the API docs are not versioned.
+//!
+//! During the deprecation window, while `server.legacy_routes` is on, the
+//! data routes also answer at their unversioned paths, as before `/v1`.
+//! Those responses carry `Deprecation` and a `Link` to the `/v1` path with
+//! `rel="successor-version"`, plus `Sunset` once `server.legacy_sunset` is
+//! set (RFC 9745, RFC 8594).
+
+use crate::config;
+use crate::error::ApiError;
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+/// Version that the unversioned paths are aliases of.
+pub const CURRENT: &str = "v1";
+
+/// Versions served, oldest first.
+pub const SUPPORTED: &[&str] = &[CURRENT];
+
+/// When the unversioned paths were deprecated (2026-10-14), as a Unix time.
+const LEGACY_DEPRECATED_AT: i64 = 1791936000;
+
+/// `route`, a path or route pattern, without its version prefix.
+pub fn unversioned(route: &str) -> &str {
+    SUPPORTED
+        .iter()
+        .find_map(|version| {
+            route
+                .strip_prefix('/')?
+                .strip_prefix(version)
+                .filter(|rest| rest.starts_with('/'))
+        })
+        .unwrap_or(route)
+}
+
+/// Middleware on the unversioned paths announcing their deprecation.
+pub async fn deprecated(request: Request, next: Next) -> Response {
+    let successor = format!(
+        "</{}{}>; rel=\"successor-version\"",
+        CURRENT,
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        "deprecation",
+        HeaderValue::from_str(&format!("@{}", LEGACY_DEPRECATED_AT)).unwrap(),
+    );
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.append(header::LINK, link);
+    }
+    if let Some(sunset) = config::get().server.legacy_sunset {
+        let date = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.insert("sunset", HeaderValue::from_str(&date).unwrap());
+    }
+    response
+}
+
+/// Answers requests that match no route; those for an unknown version say
+/// which versions exist.
+pub async fn fallback(uri: Uri) -> Response {
+    let version = uri.path().trim_start_matches('/').split('/').next();
+    match version {
+        Some(version)
+            if version.len() > 1
+                && version.starts_with('v')
+                && version[1..].bytes().all(|b| b.is_ascii_digit())
+                && !SUPPORTED.contains(&version) =>
+        {
+            ApiError::not_found(format!(
+                "unsupported API version {}; this backend serves {}",
+                version,
+                SUPPORTED.join(", ")
+            ))
+            .with_details(json!({ "supported": SUPPORTED }))
+            .into_response()
+        }
+        _ => ApiError::not_found(format!("no route for {}", uri.path())).into_response(),
+    }
+}
@@ -0,0 +1,187 @@
+//! Outgoing webhooks: JSON `POST`s to configured URLs, and the JSON `GET`s
+//! of other outgoing fetches such as JWKS.
+//!
+//! Requests go out over HTTP/1.1, through TLS with the Mozilla root
+//! certificates for `https` URLs. A delivery succeeds on any `2xx` answer;
+//! it is not retried.
+
+use axum::http::{header, Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper_util::rt::TokioIo;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// Connecting, sending and reading the response status.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_URL_LEN: usize = 2048;
+
+/// Largest response body read by [`get_json`].
+const MAX_RESPONSE_BYTES: usize = 1 << 20;
+
+/// Where a webhook URL points.
+struct Target {
+    https: bool,
+    host: String,
+    port: u16,
+    authority: String,
+    path: String,
+}
+
+fn target(url: &str) -> Result<Target, String> {
+    if url.len() > MAX_URL_LEN {
+        return Err(format!(
+            "webhook URL must be at most {} characters",
+            MAX_URL_LEN
+        ));
+    }
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| format!("invalid webhook URL {:?}: {}", url, e))?;
+    let https = match uri.scheme_str() {
+        Some("http") => false,
+        Some("https") => true,
+        _ => return Err("webhook URL must start with http:// or https://".to_string()),
+    };
+    let authority = uri
+        .authority()
+        .filter(|a| !a.host().is_empty() && !a.as_str().contains('@'))
+        .ok_or_else(|| format!("webhook URL {:?} needs a host and no credentials", url))?;
+    Ok(Target {
+        https,
+        host: authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        port: authority.port_u16().unwrap_or(if https { 443 } else { 80 }),
+        authority: authority.to_string(),
+        path: uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/")
+            .to_string(),
+    })
+}
+
+/// Checks that `url` can be delivered to.
+pub fn check_url(url: &str) -> Result<(), String> {
+    target(url).map(|_| ())
+}
+
+fn tls() -> TlsConnector {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let config = CONFIG.get_or_init(|| {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    });
+    TlsConnector::from(config.clone())
+}
+
+/// Sends `request` and returns the status, with the body if `read_body`.
+async fn send<S>(
+    io: S,
+    request: Request<Full<Bytes>>,
+    read_body: bool,
+) -> Result<(StatusCode, Bytes), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(io))
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    if !read_body {
+        return Ok((status, Bytes::new()));
+    }
+    let body = Limited::new(response.into_body(), MAX_RESPONSE_BYTES)
+        .collect()
+        .await
+        .map_err(|e| format!("cannot read the response: {}", e))?
+        .to_bytes();
+    Ok((status, body))
+}
+
+async fn exchange(
+    target: &Target,
+    request: Request<Full<Bytes>>,
+    read_body: bool,
+) -> Result<(StatusCode, Bytes), String> {
+    let tcp = TcpStream::connect((target.host.as_str(), target.port))
+        .await
+        .map_err(|e| format!("cannot connect to {}: {}", target.authority, e))?;
+    let (status, body) = if target.https {
+        let name = ServerName::try_from(target.host.as_str()).map_err(|e| e.to_string())?;
+        let stream = tls()
+            .connect(name, tcp)
+            .await
+            .map_err(|e| format!("TLS with {} failed: {}", target.authority, e))?;
+        send(stream, request, read_body).await?
+    } else {
+        send(tcp, request, read_body).await?
+    };
+    if !status.is_success() {
+        return Err(format!("{} answered {}", target.authority, status));
+    }
+    Ok((status, body))
+}
+
+async fn post(url: &str, body: &serde_json::Value) -> Result<(), String> {
+    let target = target(url)?;
+    let request = Request::post(target.path.as_str())
+        .header(header::HOST, target.authority.as_str())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::USER_AGENT, "eeg-backend")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .map_err(|e| e.to_string())?;
+    exchange(&target, request, false).await.map(|_| ())
+}
+
+async fn get(url: &str) -> Result<serde_json::Value, String> {
+    let target = target(url)?;
+    let request = Request::get(target.path.as_str())
+        .header(header::HOST, target.authority.as_str())
+        .header(header::ACCEPT, "application/json")
+        .header(header::USER_AGENT, "eeg-backend")
+        .body(Full::new(Bytes::new()))
+        .map_err(|e| e.to_string())?;
+    let (_, body) = exchange(&target, request, true).await?;
+    serde_json::from_slice(&body)
+        .map_err(|e| format!("{} sent invalid JSON: {}", target.authority, e))
+}
+
+/// Posts `body` to `url`, failing after [`TIMEOUT`].
+pub async fn post_json(url: &str, body: &serde_json::Value) -> Result<(), String> {
+    tokio::time::timeout(TIMEOUT, post(url, body))
+        .await
+        .map_err(|_| format!("no answer within {}s", TIMEOUT.as_secs()))?
+}
+
+/// Fetches the JSON document at `url`, failing after [`TIMEOUT`].
+pub async fn get_json(url: &str) -> Result<serde_json::Value, String> {
+    tokio::time::timeout(TIMEOUT, get(url))
+        .await
+        .map_err(|_| format!("no answer within {}s", TIMEOUT.as_secs()))?
+}
@@ -0,0 +1,327 @@
+//! The admin routes, device API keys, and authentication with roles and
+//! the audit log.
+
+mod common;
+
+use common::{ok_json, send, Backend, ADMIN};
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const SECRET: &str = "integration-test-secret";
+
+/// An HS256 token for `subject`, valid for an hour.
+fn token(subject: &str) -> String {
+    let claims = json!({
+        "sub": subject,
+        "exp": chrono::Utc::now().timestamp() + 3600,
+    });
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn jobs_and_retention() {
+    let backend = Backend::start().await;
+    // The jobs first run at startup.
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(30);
+    loop {
+        let jobs = backend.json("/admin/jobs").await;
+        let tiers = jobs["jobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|job| job["name"] == "tiers")
+            .cloned()
+            .unwrap();
+        if tiers["running"] == false {
+            break;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{}", tiers);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let (status, job) = send(backend.post("/admin/jobs/tiers/run")).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", job);
+    assert_eq!(job["name"], "tiers");
+    let (status, _) = send(backend.post("/admin/jobs/nope/run")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let policy = ok_json(
+        backend
+            .put("/admin/retention/raw")
+            .json(&json!({ "max_age": "30d" })),
+    )
+    .await;
+    assert_eq!(policy["max_age_seconds"], 30 * 86400);
+    assert_eq!(policy["enabled"], true);
+    let policies = backend.json("/admin/retention").await;
+    assert!(policies["policies"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|p| p["target"] == "raw" && p["max_age_seconds"] == 30 * 86400));
+    let (status, body) = send(
+        backend
+            .put("/admin/retention/raw")
+            .json(&json!({ "max_age": "soon" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fields"]["max_age"].is_string(), "{}", body);
+    let (status, _) = send(backend.put("/admin/retention/nope").json(&json!({}))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let metrics = backend.json("/ingest/metrics").await;
+    assert!(metrics["sources"].is_object(), "{}", metrics);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn api_keys() {
+    let backend = Backend::start().await;
+    let device = json!({ "serial": "KEYDEV", "model": "cyton", "channel_map": ["Fz", "Cz"], "sample_rate": 250.0 });
+    let (status, _) = send(backend.post("/devices").json(&device)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = send(
+        backend
+            .post("/admin/api-keys")
+            .json(&json!({ "device": "NODEV", "name": "amp" })),
+    )
+    .await;
+    assert!(status.is_client_error(), "{}", status);
+
+    let input = json!({ "device": "KEYDEV", "name": "amp", "channels": ["Fz"] });
+    let (status, created) = send(backend.post("/admin/api-keys").json(&input)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", created);
+    let key = created["key"].as_str().unwrap().to_string();
+    assert!(key.starts_with(created["prefix"].as_str().unwrap()));
+    let listed = backend.json("/admin/api-keys").await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert!(listed[0].get("key").is_none(), "{}", listed);
+
+    // Keys work on ingest, for their channels, and nowhere else.
+    let sample =
+        |channel: &str| json!({ "channel": channel, "ts": "2030-01-01T00:00:00Z", "value": 1.0 });
+    let with_key = |request: reqwest::RequestBuilder| request.header("X-API-Key", &key);
+    let (status, _) = send(with_key(backend.post("/samples")).json(&sample("Fz"))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = send(with_key(backend.post("/samples")).json(&sample("Cz"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(with_key(backend.get("/channels"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(
+        backend
+            .post("/samples")
+            .header("X-API-Key", "eeg_wrong")
+            .json(&sample("Fz")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let path = format!("/admin/api-keys/{}", created["id"]);
+    let revoked = backend.delete(&path).send().await.unwrap();
+    assert_eq!(revoked.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(with_key(backend.post("/samples")).json(&sample("Fz"))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(backend.json("/admin/api-keys").await, json!([]));
+    let all = backend.json("/admin/api-keys?revoked=true").await;
+    assert!(!all[0]["revoked_at"].is_null(), "{}", all);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn rate_limits() {
+    let backend = Backend::start_with(&[
+        ("RATE_LIMIT_RPS", "0.01"),
+        ("RATE_LIMIT_BURST", "4"),
+        ("RATE_LIMIT_API_KEY_RPS", "0.01"),
+        ("RATE_LIMIT_API_KEY_BURST", "2"),
+    ])
+    .await;
+    let device = json!({ "serial": "RATEDEV", "model": "cyton", "channel_map": ["Fz"], "sample_rate": 250.0 });
+    let (status, _) = send(backend.post("/devices").json(&device)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let input = json!({ "device": "RATEDEV", "name": "amp" });
+    let (status, created) = send(backend.post("/admin/api-keys").json(&input)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", created);
+    let key = created["key"].as_str().unwrap().to_string();
+
+    // Two requests of the burst of this address are left.
+    for _ in 0..2 {
+        let (status, _) = send(backend.get("/channels")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let limited = backend.get("/channels").send().await.unwrap();
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = limited.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=100).contains(&retry_after), "{}", retry_after);
+    let body: Value = limited.json().await.unwrap();
+    assert_eq!(body["code"], "too_many_requests");
+    assert_eq!(body["details"]["retry_after_secs"], retry_after);
+    let health = format!("{}/health", backend.url);
+    let (status, _) = send(backend.http.get(health)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The key has a bucket of its own.
+    let sample = json!({ "channel": "Fz", "ts": "2030-01-01T00:00:00Z", "value": 1.0 });
+    let ingest = || {
+        backend
+            .post("/samples")
+            .header("X-API-Key", &key)
+            .json(&sample)
+    };
+    for _ in 0..2 {
+        let (status, _) = send(ingest()).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let (status, body) = send(ingest()).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", body);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn authentication() {
+    let backend = Backend::start_with(&[("JWT_SECRET", SECRET)]).await;
+    let admin = token(ADMIN);
+    let researcher = token("someone");
+
+    let response = backend.get("/channels").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["www-authenticate"], "Bearer");
+    let (status, _) = send(backend.get("/channels").bearer_auth("garbage")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let health = backend.http.get(format!("{}/health", backend.url));
+    assert_eq!(send(health).await.0, StatusCode::OK);
+
+    // Users without roles are refused until they are granted one.
+    let (status, body) = send(backend.get("/channels").bearer_auth(&researcher)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(
+        body["message"].as_str().unwrap().contains("researcher"),
+        "{}",
+        body
+    );
+    let granted = ok_json(
+        backend
+            .put("/admin/roles/someone")
+            .bearer_auth(&admin)
+            .json(&json!({ "roles": ["researcher"] })),
+    )
+    .await;
+    assert_eq!(granted["roles"], json!(["researcher"]));
+    let roles = ok_json(backend.get("/admin/roles").bearer_auth(&admin)).await;
+    assert_eq!(roles.as_array().unwrap().len(), 2);
+    ok_json(backend.get("/channels").bearer_auth(&researcher)).await;
+    let (status, _) = send(backend.get("/admin/roles").bearer_auth(&researcher)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Only clinicians see the codes of subjects.
+    let path = format!("/subjects/{}", backend.seed.subject_id);
+    let subject = ok_json(backend.get(&path).bearer_auth(&researcher)).await;
+    assert!(subject["code"].is_null(), "{}", subject);
+
+    // Writes that register channels need admin, session writes clinician.
+    let pipeline = json!({ "name": "alpha", "source": "Fz", "stages": [] });
+    let (status, body) = send(
+        backend
+            .post("/pipelines")
+            .bearer_auth(&researcher)
+            .json(&pipeline),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    let edf = backend
+        .get(&format!(
+            "/sessions/{}/export?format=edf",
+            backend.seed.session_id
+        ))
+        .bearer_auth(&admin)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap()
+        .to_vec();
+    let import = |edf: Vec<u8>, token: &str| {
+        let form = Form::new().part("file", Part::bytes(edf).file_name("session.edf"));
+        backend
+            .post("/import/edf")
+            .bearer_auth(token)
+            .multipart(form)
+    };
+    let (status, _) = send(import(edf.clone(), &researcher)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let clinician = token("doctor");
+    let input = json!({ "roles": ["clinician"] });
+    ok_json(
+        backend
+            .put("/admin/roles/doctor")
+            .bearer_auth(&admin)
+            .json(&input),
+    )
+    .await;
+    let (status, body) = send(import(edf.clone(), &clinician)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let at = edf.windows(6).position(|w| w == b"EEG Fz").unwrap();
+    let mut renamed = edf;
+    renamed[at..at + 6].copy_from_slice(b"EEG Fq");
+    let (status, body) = send(import(renamed, &clinician)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(
+        body["message"],
+        "registering channel \"Fq\" requires the role admin"
+    );
+
+    // Browser clients pass the token in the query.
+    let (status, _) = send(backend.get(&format!("/channels?access_token={}", researcher))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    let entries: Value = loop {
+        let audit = ok_json(
+            backend
+                .get("/admin/audit?principal=someone")
+                .bearer_auth(&admin),
+        )
+        .await;
+        if audit["entries"].as_array().unwrap().len() >= 5 {
+            break audit["entries"].clone();
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{}", audit);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    };
+    let statuses: Vec<i64> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["status"].as_i64().unwrap())
+        .collect();
+    assert!(
+        statuses.contains(&403) && statuses.contains(&200),
+        "{:?}",
+        statuses
+    );
+    let read = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["route"] == "/v1/subjects/:id")
+        .unwrap();
+    assert_eq!(read["subject_id"], backend.seed.subject_id);
+    assert!(
+        entries.to_string().find("access_token").is_none(),
+        "{}",
+        entries
+    );
+}
@@ -0,0 +1,372 @@
+//! The analyses of `/analysis`, on the seeded mix of 10 Hz and 22 Hz.
+
+mod common;
+
+use common::{ok_json, send, Backend, EVENTS, EVENT_LABEL, SAMPLES};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+fn array(value: &Value) -> &Vec<Value> {
+    value.as_array().unwrap()
+}
+
+fn floats(value: &Value) -> Vec<f64> {
+    array(value).iter().map(|v| v.as_f64().unwrap()).collect()
+}
+
+/// Frequency of the largest value of `power`.
+fn peak(frequencies: &[f64], power: &[f64]) -> f64 {
+    let (i, _) = power
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap();
+    frequencies[i]
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn spectra() {
+    let backend = Backend::start().await;
+    let window = backend.seed.window();
+
+    let psd = backend
+        .json(&format!("/analysis/psd?channel=Oz&{}", window))
+        .await;
+    let f = peak(&floats(&psd["frequencies"]), &floats(&psd["power"]));
+    assert!((f - 10.0).abs() < 1.0, "peak at {} Hz", f);
+
+    // Half the squared amplitude: 8 µV alpha and 5 µV beta on Fz, 20 µV
+    // alpha and 2 µV beta on Oz.
+    let band = |body: &Value, name: &str| {
+        array(&body["bands"])
+            .iter()
+            .find(|b| b["name"] == name)
+            .unwrap()["absolute"]
+            .as_f64()
+            .unwrap()
+    };
+    let fz = backend
+        .json(&format!("/analysis/bandpower?channel=Fz&{}", window))
+        .await;
+    let oz = backend
+        .json(&format!("/analysis/bandpower?channel=Oz&{}", window))
+        .await;
+    for (body, alpha, beta) in [(&fz, 32.0, 12.5), (&oz, 200.0, 2.0)] {
+        assert!((band(body, "alpha") / alpha - 1.0).abs() < 0.05, "{}", body);
+        assert!((band(body, "beta") / beta - 1.0).abs() < 0.05, "{}", body);
+    }
+    let custom = backend
+        .json(&format!(
+            "/analysis/bandpower?channel=Fz&bands=low:20-24&{}",
+            window
+        ))
+        .await;
+    assert_eq!(array(&custom["bands"]).len(), 1);
+
+    let coherence = backend
+        .json(&format!(
+            "/analysis/coherence?channel=Fz,Oz&band=alpha&{}",
+            window
+        ))
+        .await;
+    let pair = &coherence["pairs"][0];
+    assert_eq!(pair["channels"], json!(["Fz", "Oz"]));
+    assert!(
+        pair["bands"][0]["coherence"].as_f64().unwrap() > 0.99,
+        "{}",
+        pair
+    );
+    let (status, _) = send(backend.get(&format!("/analysis/coherence?{}", window))).await;
+    assert!(status.is_client_error(), "{}", status);
+
+    let spectrogram = backend
+        .json(&format!(
+            "/analysis/spectrogram?channel=Oz&fmax=40&max_times=5&{}",
+            window
+        ))
+        .await;
+    let times = array(&spectrogram["times"]).len();
+    assert!(times > 0 && times <= 5, "{}", times);
+    let power = array(&spectrogram["power"]);
+    assert_eq!(power.len(), times);
+    let frequencies = floats(&spectrogram["frequencies"]);
+    let f = peak(&frequencies, &floats(&power[0]));
+    assert!((f - 10.0).abs() < 2.0, "peak at {} Hz", f);
+    let (status, body) = send(backend.get(&format!(
+        "/analysis/spectrogram?channel=Oz&fmin=40&fmax=10&max_times=0&{}",
+        window
+    )))
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let fields = &body["details"]["fields"];
+    assert!(
+        fields["fmax"].is_string() && fields["max_times"].is_string(),
+        "{}",
+        body
+    );
+    for (path, field) in [
+        ("/analysis/psd?nperseg=1", "nperseg"),
+        ("/analysis/psd?nfft=100", "nfft"),
+        ("/analysis/spectrogram?nperseg=64&noverlap=64", "noverlap"),
+        ("/analysis/bandpower?window=2h", "window"),
+        ("/analysis/bandpower?bands=alpha:13-8", "bands"),
+        ("/analysis/coherence?channels=Fz,Oz&band=nope", "band"),
+        ("/analysis/hjorth?step=soon", "step"),
+        ("/samples/filter?bandpass=8", "bandpass"),
+    ] {
+        let path = format!("{}&channel=Oz&{}", path, window);
+        let (status, body) = send(backend.get(&path)).await;
+        assert_eq!(
+            status,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}: {}",
+            path,
+            body
+        );
+        assert!(body["details"]["fields"][field].is_string(), "{}", body);
+    }
+
+    let hjorth = backend
+        .json(&format!("/analysis/hjorth?channel=Fz&window=2s&{}", window))
+        .await;
+    let windows = array(&hjorth["windows"]);
+    assert_eq!(windows.len(), 5);
+    assert!(windows
+        .iter()
+        .all(|w| w["activity"].as_f64().unwrap() > 0.0));
+
+    // The recording starts on a whole second, so each window holds whole
+    // periods of both sines: a zero mean and the variance 32 + 12.5.
+    let stats = backend
+        .json(&format!("/analysis/stats?channel=Fz&window=1s&{}", window))
+        .await;
+    let windows = array(&stats["windows"]);
+    assert_eq!(windows.len() as i64, common::SECONDS);
+    for w in windows {
+        assert_eq!(w["count"], common::SAMPLE_RATE as i64);
+        assert!(w["mean"].as_f64().unwrap().abs() < 1e-6, "{}", w);
+        assert!(
+            (w["std"].as_f64().unwrap() - 44.5f64.sqrt()).abs() < 1e-6,
+            "{}",
+            w
+        );
+        let (min, max) = (w["min"].as_f64().unwrap(), w["max"].as_f64().unwrap());
+        assert!((w["ptp"].as_f64().unwrap() - (max - min)).abs() < 1e-9);
+    }
+    let both = backend
+        .json(&format!(
+            "/analysis/stats?channel=Fz,Oz&window=5s&{}",
+            window
+        ))
+        .await;
+    let channels = array(&both["channels"]);
+    assert_eq!(channels.len(), 2);
+    // Aligned to the epoch, 5 s windows need not start with the recording.
+    let counts = array(&channels[1]["windows"])
+        .iter()
+        .map(|w| w["count"].as_i64().unwrap());
+    assert_eq!(counts.sum::<i64>(), SAMPLES);
+    let (status, body) = send(backend.get(&format!("/analysis/stats?window=0s&{}", window))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fields"]["window"].is_string(), "{}", body);
+
+    let histogram = backend
+        .json(&format!(
+            "/analysis/histogram?channel=Oz&bins=20&{}",
+            window
+        ))
+        .await;
+    let bins = array(&histogram["bins"]);
+    assert_eq!(bins.len(), 20);
+    let counts: Vec<i64> = bins.iter().map(|b| b["count"].as_i64().unwrap()).collect();
+    assert_eq!(counts.iter().sum::<i64>(), SAMPLES);
+    assert_eq!(histogram["below"], 0);
+    assert_eq!(histogram["above"], 0);
+    assert_eq!(bins[19]["end"], histogram["max"]);
+    // A sine spends most of its time near its peaks.
+    assert!(
+        counts[0] > counts[10] && counts[19] > counts[10],
+        "{:?}",
+        counts
+    );
+    let clipped = backend
+        .json(&format!(
+            "/analysis/histogram?channel=Oz&bins=4&min=-10&max=10&{}",
+            window
+        ))
+        .await;
+    let inside: i64 = array(&clipped["bins"])
+        .iter()
+        .map(|b| b["count"].as_i64().unwrap())
+        .sum();
+    let outside = clipped["below"].as_i64().unwrap() + clipped["above"].as_i64().unwrap();
+    assert!(outside > 0);
+    assert_eq!(inside + outside, SAMPLES);
+    let (status, _) = send(backend.get(&format!(
+        "/analysis/histogram?bins=0&min=1&max=0&{}",
+        window
+    )))
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn epochs_and_erp() {
+    let backend = Backend::start().await;
+    let query = format!(
+        "channel=Fz,Cz&event_label={}&pre=0.1&post=0.2&session_id={}",
+        EVENT_LABEL, backend.seed.session_id
+    );
+
+    let epochs = backend.json(&format!("/analysis/epochs?{}", query)).await;
+    assert_eq!(epochs["candidates"].as_i64().unwrap(), EVENTS);
+    assert_eq!(epochs["rejected"], 0);
+    assert_eq!(array(&epochs["epochs"]).len() as i64, EVENTS);
+    assert_eq!(array(&epochs["times"]).len(), 76);
+
+    // The events fall on whole seconds, so every epoch is the same and
+    // the average equals each of them.
+    let erp = backend.json(&format!("/analysis/erp?{}", query)).await;
+    let channels = array(&erp["channels"]);
+    assert_eq!(channels.len(), 2);
+    let average = floats(&channels[0]["erp"]);
+    let first = floats(&epochs["epochs"][0]["data"][0]);
+    assert_eq!(average.len(), first.len());
+    for (a, b) in average.iter().zip(&first) {
+        assert!((a - b).abs() < 1e-6, "{} against {}", a, b);
+    }
+
+    let fixed = backend
+        .json(&format!(
+            "/analysis/epochs?channel=Fz&length=1&step=1&session_id={}",
+            backend.seed.session_id
+        ))
+        .await;
+    // The last second ends after the last sample.
+    assert_eq!(array(&fixed["epochs"]).len() as i64, common::SECONDS - 1);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn artifacts() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+    // A one-sample pop of 500 µV on Cz.
+    let pop: f64 = sqlx::query_scalar(
+        "UPDATE eeg_samples SET value = 500 \
+         WHERE id = (SELECT id FROM eeg_samples WHERE channel = 'Cz' ORDER BY id OFFSET 1200 LIMIT 1) \
+         RETURNING value",
+    )
+    .fetch_one(&backend.pool)
+    .await
+    .unwrap();
+    assert_eq!(pop, 500.0);
+
+    let detected = ok_json(backend.post(&format!(
+        "/analysis/artifacts?channel=Cz&session_id={}&dry_run=true",
+        session_id
+    )))
+    .await;
+    assert!(!array(&detected["events"]).is_empty(), "{}", detected);
+    let events: i64 = sqlx::query_scalar("SELECT count(*) FROM events")
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap();
+    assert_eq!(events, EVENTS);
+
+    let stored = ok_json(backend.post(&format!(
+        "/analysis/artifacts?channel=Cz&session_id={}",
+        session_id
+    )))
+    .await;
+    let events: i64 = sqlx::query_scalar("SELECT count(*) FROM events")
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap();
+    assert_eq!(events, EVENTS + array(&stored["events"]).len() as i64);
+    let clean = ok_json(backend.post(&format!(
+        "/analysis/artifacts?channel=Oz&session_id={}&dry_run=true",
+        session_id
+    )))
+    .await;
+    assert_eq!(clean["events"], json!([]));
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn ica() {
+    let backend = Backend::start().await;
+    let (status, job) = send(backend.post(&format!(
+        "/analysis/ica?channel=Fz,Cz,Pz&components=2&seed=1&session_id={}",
+        backend.seed.session_id
+    )))
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", job);
+    let path = format!("/analysis/ica/{}", job["id"]);
+
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
+    let job = loop {
+        let job = backend.json(&path).await;
+        if job["status"] != "running" && job["status"] != "queued" {
+            break job;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{}", job);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+    assert_eq!(job["status"], "done", "{}", job);
+    assert_eq!(job["instants"].as_i64().unwrap(), SAMPLES);
+    assert_eq!(array(&backend.json("/analysis/ica").await).len(), 1);
+
+    // The channels mix two sources, which come back as the two components.
+    let components = backend.json(&format!("{}/components", path)).await;
+    let sources = array(&components["sources"]);
+    assert_eq!(sources.len(), 2);
+    let correlation = |source: &[f64], hz: f64| {
+        let wave: Vec<f64> = (0..source.len())
+            .map(|i| (std::f64::consts::TAU * hz * i as f64 / common::SAMPLE_RATE).sin())
+            .collect();
+        let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+        dot(source, &wave) / (dot(source, source) * dot(&wave, &wave)).sqrt()
+    };
+    let mut matched: Vec<f64> = sources
+        .iter()
+        .map(|source| {
+            let source = floats(source);
+            assert_eq!(source.len() as i64, SAMPLES);
+            let (alpha, beta) = (correlation(&source, 10.0), correlation(&source, 22.0));
+            if alpha.abs() > beta.abs() {
+                assert!(alpha.abs() > 0.99, "{} {}", alpha, beta);
+                10.0
+            } else {
+                assert!(beta.abs() > 0.99, "{} {}", alpha, beta);
+                22.0
+            }
+        })
+        .collect();
+    matched.sort_by(f64::total_cmp);
+    assert_eq!(matched, vec![10.0, 22.0]);
+
+    let excluded = ok_json(
+        backend
+            .put(&format!("{}/exclude", path))
+            .json(&json!({ "components": [0] })),
+    )
+    .await;
+    assert_eq!(excluded["excluded"], json!([0]));
+    let applied = ok_json(backend.post(&format!("{}/apply?suffix=_clean", path))).await;
+    assert_eq!(
+        applied["created_channels"],
+        json!(["Fz_clean", "Cz_clean", "Pz_clean"])
+    );
+    assert_eq!(applied["samples"].as_i64().unwrap(), 3 * SAMPLES);
+    assert_ne!(applied["session"]["id"], backend.seed.session_id);
+    let cleaned = backend.json("/samples?channel=Fz_clean&limit=1").await;
+    assert_eq!(array(&cleaned["samples"]).len(), 1);
+
+    let deleted = backend.delete(&path).send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get(&path)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
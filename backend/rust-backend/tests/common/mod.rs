@@ -0,0 +1,428 @@
+//! The harness of the integration tests: a fresh database, migrated by the
+//! backend's `migrate` command and seeded with a known recording, and the
+//! backend binary serving it on free ports.
+//!
+//! The database is a Postgres container started through Docker, or, with
+//! `TEST_DATABASE_URL` set, a new database on that server that is dropped
+//! again afterwards. Each test starts a harness of its own, so tests run in
+//! parallel without seeing each other's data.
+
+#![allow(dead_code)]
+
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::path::PathBuf;
+use std::process::Stdio;
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+use testcontainers_modules::testcontainers::{ContainerAsync, ImageExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Channels of the seeded recording, each a mix of 20 µV of 10 Hz
+/// (alpha) and 5 µV of 22 Hz (beta) with these weights; see [`value`].
+pub const CHANNELS: [(&str, f64, f64); 4] = [
+    ("Fz", 0.4, 1.0),
+    ("Cz", 0.6, 0.8),
+    ("Pz", 0.8, 0.6),
+    ("Oz", 1.0, 0.4),
+];
+pub const SAMPLE_RATE: f64 = 250.0;
+/// Seconds of the seeded recording.
+pub const SECONDS: i64 = 10;
+/// Samples per channel.
+pub const SAMPLES: i64 = SECONDS * SAMPLE_RATE as i64;
+/// Label of the seeded events, one per second from 1 s to 8 s.
+pub const EVENT_LABEL: &str = "stim";
+pub const EVENTS: i64 = 8;
+
+/// Principal seeded with the `admin` role, for tests with authentication.
+pub const ADMIN: &str = "test-admin";
+
+/// How long the backend gets to migrate and start listening.
+const STARTUP: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Ids of the seeded rows.
+#[derive(Debug, Clone)]
+pub struct Seed {
+    pub subject_id: i32,
+    pub session_id: i32,
+    /// Timestamp of the first sample; sample `i` is `i / SAMPLE_RATE` later.
+    pub start: DateTime<Utc>,
+}
+
+impl Seed {
+    /// `from` and `to` covering the whole recording, as query parameters.
+    pub fn window(&self) -> String {
+        format!(
+            "from={}&to={}",
+            self.start
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            (self.start + Duration::seconds(SECONDS))
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        )
+        .replace('+', "%2B")
+    }
+}
+
+enum Database {
+    Container(Box<ContainerAsync<Postgres>>),
+    /// A database created on the server of `TEST_DATABASE_URL`.
+    Created {
+        admin_url: String,
+        name: String,
+    },
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        let Database::Created { admin_url, name } = self else {
+            return;
+        };
+        let admin_url = admin_url.clone();
+        let statement = format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name);
+        // Dropped from inside the test's runtime, so on a thread of its own.
+        let dropped = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async {
+                let pool = PgPoolOptions::new()
+                    .max_connections(1)
+                    .connect(&admin_url)
+                    .await?;
+                sqlx::query(&statement).execute(&pool).await?;
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+            })
+        })
+        .join();
+        if let Ok(Err(e)) = dropped {
+            eprintln!("failed to drop test database {}: {}", name, e);
+        }
+    }
+}
+
+/// A running backend on a seeded database of its own.
+pub struct Backend {
+    pub url: String,
+    pub grpc_url: String,
+    pub http: reqwest::Client,
+    /// Pool on the backend's database, for checking what handlers stored.
+    pub pool: PgPool,
+    pub seed: Seed,
+    pub export_dir: PathBuf,
+    pub log: PathBuf,
+    // Dropped in order: the backend before its database.
+    child: tokio::process::Child,
+    _database: Database,
+}
+
+impl Backend {
+    pub async fn start() -> Self {
+        Self::start_with(&[]).await
+    }
+
+    /// Starts the backend with extra environment variables.
+    pub async fn start_with(env: &[(&str, &str)]) -> Self {
+        Self::start_prepared(env, &[]).await
+    }
+
+    /// Starts the backend with extra environment variables, after running
+    /// `statements` on the migrated and seeded database.
+    pub async fn start_prepared(env: &[(&str, &str)], statements: &[&str]) -> Self {
+        let (database, url) = create_database().await;
+        let binary = env!("CARGO_BIN_EXE_rust_backend");
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("backend-{}", &id[..12]));
+        let export_dir = dir.join("exports");
+        std::fs::create_dir_all(&export_dir).unwrap();
+        let log = dir.join("backend.log");
+
+        let command = |args: &[&str]| {
+            let mut command = tokio::process::Command::new(binary);
+            command
+                .args(args)
+                .current_dir(&dir)
+                .env_clear()
+                .env("DATABASE_URL", &url)
+                .env("RUST_LOG", "info")
+                .stdin(Stdio::null())
+                .stdout(Stdio::from(open_log(&log)))
+                .stderr(Stdio::from(open_log(&log)));
+            command
+        };
+        let migrated = command(&["migrate"]).status().await.unwrap();
+        assert!(migrated.success(), "migrate failed; see {}", log.display());
+
+        let pool = PgPoolOptions::new()
+            .max_connections(4)
+            .connect(&url)
+            .await
+            .unwrap();
+        let seed = seed(&pool).await;
+        for statement in statements {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        let port = free_port();
+        let grpc_port = free_port();
+        let mut serve = command(&["serve"]);
+        serve
+            .env("BIND_ADDR", format!("127.0.0.1:{}", port))
+            .env("GRPC_PORT", grpc_port.to_string())
+            .env("EXPORT_DIR", &export_dir)
+            .env("RATE_LIMIT_RPS", "0")
+            .env("LIVE_POLL_MS", "50")
+            .envs(env.iter().copied())
+            .kill_on_drop(true);
+        let child = serve.spawn().unwrap();
+
+        let mut backend = Backend {
+            url: format!("http://127.0.0.1:{}", port),
+            grpc_url: format!("http://127.0.0.1:{}", grpc_port),
+            http: reqwest::Client::new(),
+            pool,
+            seed,
+            export_dir,
+            log,
+            child,
+            _database: database,
+        };
+        backend.wait_ready().await;
+        backend
+    }
+
+    async fn wait_ready(&mut self) {
+        let deadline = tokio::time::Instant::now() + STARTUP;
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("backend exited with {}; see {}", status, self.log.display());
+            }
+            let ready = self.http.get(format!("{}/readyz", self.url)).send().await;
+            if ready.is_ok_and(|r| r.status().is_success()) {
+                return;
+            }
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "backend not ready after {:?}; see {}",
+                STARTUP,
+                self.log.display()
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    }
+
+    /// URL of `path` under the current API version.
+    pub fn v1(&self, path: &str) -> String {
+        format!("{}/v1{}", self.url, path)
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.http.get(self.v1(path))
+    }
+
+    pub fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        self.http.post(self.v1(path))
+    }
+
+    pub fn put(&self, path: &str) -> reqwest::RequestBuilder {
+        self.http.put(self.v1(path))
+    }
+
+    pub fn patch(&self, path: &str) -> reqwest::RequestBuilder {
+        self.http.patch(self.v1(path))
+    }
+
+    pub fn delete(&self, path: &str) -> reqwest::RequestBuilder {
+        self.http.delete(self.v1(path))
+    }
+
+    /// The JSON body of a successful `GET` of `path`.
+    pub async fn json(&self, path: &str) -> Value {
+        ok_json(self.get(path)).await
+    }
+
+    /// Opens a WebSocket on `path` under the current version.
+    pub async fn ws(&self, path: &str) -> Socket {
+        let url = self.v1(path).replacen("http", "ws", 1);
+        let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        socket
+    }
+}
+
+/// Sends `request` and returns its JSON body, failing on any status but 2xx.
+pub async fn ok_json(request: reqwest::RequestBuilder) -> Value {
+    let response = request.send().await.unwrap();
+    let status = response.status();
+    let url = response.url().clone();
+    let body = response.text().await.unwrap();
+    assert!(status.is_success(), "{} returned {}: {}", url, status, body);
+    serde_json::from_str(&body).unwrap_or_else(|e| panic!("{} returned {}: {}", url, e, body))
+}
+
+/// The status of `request` and its body as JSON, or `null` when it is not.
+pub async fn send(request: reqwest::RequestBuilder) -> (reqwest::StatusCode, Value) {
+    let response = request.send().await.unwrap();
+    let status = response.status();
+    let body = response.text().await.unwrap();
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+/// The next text or binary message of `socket` as JSON, within `secs`.
+pub async fn next_json(socket: &mut Socket, secs: u64) -> Value {
+    let deadline = std::time::Duration::from_secs(secs);
+    loop {
+        let message = tokio::time::timeout(deadline, socket.next())
+            .await
+            .expect("no message in time")
+            .expect("socket closed")
+            .unwrap();
+        match message {
+            Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+            Message::Ping(payload) => socket.send(Message::Pong(payload)).await.unwrap(),
+            Message::Close(frame) => panic!("socket closed: {:?}", frame),
+            _ => {}
+        }
+    }
+}
+
+fn open_log(path: &std::path::Path) -> std::fs::File {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap()
+}
+
+/// A port nothing listens on right now.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn create_database() -> (Database, String) {
+    if let Ok(admin_url) = std::env::var("TEST_DATABASE_URL") {
+        let name = format!("eeg_test_{}", uuid::Uuid::new_v4().simple());
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&admin_url)
+            .await
+            .expect("cannot connect to TEST_DATABASE_URL");
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        let (base, params) = match admin_url.split_once('?') {
+            Some((base, params)) => (base, format!("?{}", params)),
+            None => (admin_url.as_str(), String::new()),
+        };
+        let server = base.rsplit_once('/').map_or(base, |(server, _)| server);
+        let url = format!("{}/{}{}", server, name, params);
+        return (Database::Created { admin_url, name }, url);
+    }
+    let container = Postgres::default()
+        .with_tag("15-alpine")
+        .start()
+        .await
+        .expect("cannot start Postgres; is Docker running? Or set TEST_DATABASE_URL");
+    let host = container.get_host().await.unwrap();
+    let port = container.get_host_port_ipv4(5432).await.unwrap();
+    let url = format!("postgres://postgres:postgres@{}:{}/postgres", host, port);
+    (Database::Container(Box::new(container)), url)
+}
+
+/// The seeded value of sample `i` of `channel`.
+pub fn value(channel: &str, i: i64) -> f64 {
+    let (_, alpha, beta) = CHANNELS.iter().find(|(name, ..)| *name == channel).unwrap();
+    let t = i as f64 / SAMPLE_RATE;
+    alpha * 20.0 * (std::f64::consts::TAU * 10.0 * t).sin()
+        + beta * 5.0 * (std::f64::consts::TAU * 22.0 * t).sin()
+}
+
+/// Registers [`CHANNELS`] and stores ten seconds of each in a session of
+/// subject `S01`, with an event every second, and makes [`ADMIN`] an admin.
+async fn seed(pool: &PgPool) -> Seed {
+    let start = (Utc::now() - Duration::minutes(1))
+        .duration_trunc(Duration::seconds(1))
+        .unwrap();
+    for (i, (name, ..)) in CHANNELS.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO channels (name, label, sample_rate, hardware_index) \
+             VALUES ($1, $1, $2, $3)",
+        )
+        .bind(name)
+        .bind(SAMPLE_RATE)
+        .bind(i as i32 + 2)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+    let subject_id: i32 =
+        sqlx::query_scalar("INSERT INTO subjects (code) VALUES ('S01') RETURNING id")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+    let session_id: i32 = sqlx::query_scalar(
+        "INSERT INTO sessions (subject_id, device, started_at, ended_at) \
+         VALUES ($1, 'sim', $2, $2 + make_interval(secs => $3)) RETURNING id",
+    )
+    .bind(subject_id)
+    .bind(start)
+    .bind(SECONDS as f64)
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    let names: Vec<&str> = CHANNELS.iter().map(|c| c.0).collect();
+    let alphas: Vec<f64> = CHANNELS.iter().map(|c| c.1).collect();
+    let betas: Vec<f64> = CHANNELS.iter().map(|c| c.2).collect();
+    sqlx::query(
+        "INSERT INTO eeg_samples (ts, channel, value, session_id) \
+         SELECT $1 + make_interval(secs => i / $2), c.name, \
+                c.alpha * 20 * sin(2 * pi() * 10 * i / $2) \
+                + c.beta * 5 * sin(2 * pi() * 22 * i / $2), $3 \
+         FROM generate_series(0, $4 - 1) AS i, \
+              unnest($5::text[], $6::float8[], $7::float8[]) AS c(name, alpha, beta) \
+         ORDER BY i, c.name",
+    )
+    .bind(start)
+    .bind(SAMPLE_RATE)
+    .bind(session_id)
+    .bind(SAMPLES)
+    .bind(&names)
+    .bind(&alphas)
+    .bind(&betas)
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO events (session_id, ts, label) \
+         SELECT $1, $2 + make_interval(secs => s), $3 FROM generate_series(1, $4) AS s",
+    )
+    .bind(session_id)
+    .bind(start)
+    .bind(EVENT_LABEL)
+    .bind(EVENTS)
+    .execute(pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO role_assignments (principal, role) VALUES ($1, 'admin')")
+        .bind(ADMIN)
+        .execute(pool)
+        .await
+        .unwrap();
+    Seed {
+        subject_id,
+        session_id,
+        start,
+    }
+}
@@ -0,0 +1,197 @@
+//! Exports of samples and sessions, the export queue, and the CSV and EDF
+//! imports.
+
+mod common;
+
+use common::{send, Backend, CHANNELS, EVENTS, SAMPLES};
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Polls `path` until its `status` leaves `queued` and `running`.
+async fn finished(backend: &Backend, path: &str) -> Value {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(30);
+    loop {
+        let job = backend.json(path).await;
+        if job["status"] != "queued" && job["status"] != "running" {
+            return job;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{}", job);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn download(request: reqwest::RequestBuilder) -> (String, Vec<u8>) {
+    let response = request.send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK, "{}", response.url());
+    let content_type = response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    (content_type, response.bytes().await.unwrap().to_vec())
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn sample_exports() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+
+    let (content_type, csv) = download(backend.get(&format!(
+        "/samples/export.csv?channel=Fz,Oz&session_id={}",
+        session_id
+    )))
+    .await;
+    assert!(content_type.starts_with("text/csv"), "{}", content_type);
+    let csv = String::from_utf8(csv).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("id,ts,channel,value"));
+    assert_eq!(lines.count() as i64, 2 * SAMPLES);
+
+    let (status, job) = send(backend.post(&format!(
+        "/samples/export.parquet?channel=Fz&session_id={}",
+        session_id
+    )))
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", job);
+    let job = finished(&backend, &format!("/exports/{}", job["id"])).await;
+    assert_eq!(job["status"], "done", "{}", job);
+    assert_eq!(job["rows"].as_i64().unwrap(), SAMPLES);
+    let url = format!("{}{}", backend.url, job["download_url"].as_str().unwrap());
+    let (_, parquet) = download(backend.http.get(url)).await;
+    assert_eq!(&parquet[..4], b"PAR1");
+    assert_eq!(parquet.len() as i64, job["bytes"].as_i64().unwrap());
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn session_exports() {
+    let backend = Backend::start().await;
+    let path = format!("/sessions/{}/export", backend.seed.session_id);
+    let magic: [(&str, &[u8]); 6] = [
+        ("edf", b"0       "),
+        ("bdf", b"\xffBIOSEMI"),
+        ("xdf", b"XDF:"),
+        ("fif", b"\x00\x00\x00\x64"),
+        ("nwb", b"\x89HDF\r\n\x1a\n"),
+        ("brainvision", b"session-"),
+    ];
+    for (format, magic) in magic {
+        let (content_type, file) =
+            download(backend.get(&format!("{}?format={}", path, format))).await;
+        assert!(
+            file.starts_with(magic),
+            "{} starts with {:?}",
+            format,
+            &file[..8]
+        );
+        let expected = match format {
+            "nwb" => "application/x-hdf5",
+            "brainvision" => "application/x-tar",
+            _ => "application/octet-stream",
+        };
+        assert_eq!(content_type, expected, "{}", format);
+    }
+    let (status, _) = send(backend.get(&format!("{}?format=doc", path))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(backend.get("/sessions/999999/export")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn export_queue() {
+    let backend = Backend::start().await;
+    assert_eq!(backend.json("/exports").await, json!([]));
+
+    let request = json!({ "format": "edf", "session_id": backend.seed.session_id });
+    let (status, job) = send(backend.post("/exports").json(&request)).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", job);
+    let path = format!("/exports/{}", job["id"]);
+    let job = finished(&backend, &path).await;
+    assert_eq!(job["status"], "done", "{}", job);
+    assert_eq!(backend.json("/exports").await.as_array().unwrap().len(), 1);
+
+    let (_, file) = download(backend.get(&format!("{}/file", path))).await;
+    assert!(file.starts_with(b"0       "));
+    assert_eq!(file.len() as i64, job["bytes"].as_i64().unwrap());
+    let files = std::fs::read_dir(&backend.export_dir).unwrap().count();
+    assert_eq!(files, 1);
+
+    let (status, _) = send(backend.post("/exports").json(&json!({ "format": "doc" }))).await;
+    assert!(status.is_client_error(), "{}", status);
+
+    let deleted = backend.delete(&path).send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get(&path)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(std::fs::read_dir(&backend.export_dir).unwrap().count(), 0);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn imports() {
+    let backend = Backend::start().await;
+
+    let csv = "time,a,b\n\
+               2030-01-01T00:00:00Z,1.5,2\n\
+               2030-01-01T00:00:00.004Z,1.6,\n\
+               bad,1,1\n";
+    let form = Form::new()
+        .text(
+            "mapping",
+            r#"{"timestamp":"time","channels":{"A3":"a","A4":"b"}}"#,
+        )
+        .part("file", Part::text(csv).file_name("samples.csv"));
+    let (status, job) = send(backend.post("/import/csv").multipart(form)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", job);
+    assert_eq!(job["status"], "done");
+    assert_eq!(job["samples"], 3);
+    assert_eq!(job["rejected"], 1);
+    assert_eq!(job["errors"][0]["line"], 4);
+    let listed = backend.json("/imports").await;
+    assert_eq!(listed[0]["id"], job["id"]);
+    assert_eq!(
+        backend.json(&format!("/imports/{}", job["id"])).await["file_name"],
+        "samples.csv"
+    );
+
+    // An exported session comes back as a new one with the same samples.
+    let edf = backend
+        .get(&format!(
+            "/sessions/{}/export?format=edf",
+            backend.seed.session_id
+        ))
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let form = Form::new().part("file", Part::bytes(edf.to_vec()).file_name("session.edf"));
+    let (status, imported) = send(
+        backend
+            .post(&format!(
+                "/import/edf?subject_id={}&notes=copy",
+                backend.seed.subject_id
+            ))
+            .multipart(form),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", imported);
+    assert_eq!(
+        imported["samples"].as_i64().unwrap(),
+        CHANNELS.len() as i64 * SAMPLES
+    );
+    assert_eq!(imported["events"].as_i64().unwrap(), EVENTS);
+    assert_eq!(imported["session"]["notes"], "copy");
+    assert_ne!(imported["session"]["id"], backend.seed.session_id);
+
+    let form = Form::new().part(
+        "file",
+        Part::bytes(b"not an edf".to_vec()).file_name("x.edf"),
+    );
+    let (status, _) = send(backend.post("/import/edf").multipart(form)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
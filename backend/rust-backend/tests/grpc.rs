@@ -0,0 +1,92 @@
+//! The gRPC service `eeg.v1.EegService`, through the generated client.
+
+mod common;
+
+use common::{Backend, CHANNELS, SAMPLES};
+use futures::StreamExt;
+use std::time::Duration;
+
+mod pb {
+    include!(concat!(env!("OUT_DIR"), "/client/eeg.v1.rs"));
+}
+
+use pb::eeg_service_client::EegServiceClient;
+use pb::{IngestSamplesRequest, ListChannelsRequest, NewSample, StreamSamplesRequest};
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn eeg_service() {
+    let backend = Backend::start().await;
+    let mut client = EegServiceClient::connect(backend.grpc_url.clone())
+        .await
+        .unwrap();
+
+    let channels = client
+        .list_channels(ListChannelsRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .channels;
+    assert_eq!(channels.len(), CHANNELS.len());
+    assert!(channels.iter().all(|c| c.sample_count == SAMPLES));
+    let fz = channels.iter().find(|c| c.name == "Fz").unwrap().last_id;
+
+    let mut stream = client
+        .stream_samples(StreamSamplesRequest {
+            channel: "Fz".to_string(),
+            since_id: fz,
+            limit: 10,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    let batch = |from: usize| IngestSamplesRequest {
+        samples: (from..from + 3)
+            .map(|i| NewSample {
+                channel: "Fz".to_string(),
+                ts: format!("2030-01-01T00:00:00.{:03}Z", i * 4),
+                value: i as f64,
+                session_id: 0,
+            })
+            .collect(),
+    };
+    let ingested = client
+        .ingest_samples(futures::stream::iter([batch(0), batch(3)]))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(ingested.inserted, 6);
+    assert!(ingested.last_id > fz);
+
+    // Batches of at most `limit` until the last ingested sample.
+    let mut values = Vec::new();
+    while values.len() < 6 {
+        let batch = tokio::time::timeout(Duration::from_secs(10), stream.next())
+            .await
+            .expect("no batch in time")
+            .expect("stream ended")
+            .unwrap();
+        assert_eq!(batch.channel, "Fz");
+        assert!(batch.samples.len() <= 10);
+        values.extend(batch.samples.iter().map(|s| s.value));
+        if values.len() == 6 {
+            assert_eq!(batch.last_id, ingested.last_id);
+        }
+    }
+    assert_eq!(values, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+
+    let unknown = client
+        .ingest_samples(futures::stream::iter([IngestSamplesRequest {
+            samples: vec![NewSample {
+                channel: "nope".to_string(),
+                ts: "2030-01-01T00:00:00Z".to_string(),
+                value: 1.0,
+                session_id: 0,
+            }],
+        }]))
+        .await
+        .unwrap_err();
+    assert_eq!(unknown.code(), tonic::Code::InvalidArgument, "{}", unknown);
+}
@@ -0,0 +1,69 @@
+//! The unversioned routes: health checks, metrics and the API documentation,
+//! and how unversioned paths are handled.
+
+mod common;
+
+use common::{ok_json, send, Backend};
+use reqwest::StatusCode;
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn health_metrics_and_docs() {
+    let backend = Backend::start().await;
+    let http = &backend.http;
+    let url = |path: &str| format!("{}{}", backend.url, path);
+
+    let root = http.get(url("/")).send().await.unwrap();
+    assert_eq!(root.text().await.unwrap(), "Rust EEG Backend");
+    for path in ["/health", "/healthz"] {
+        let response = http.get(url(path)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", path);
+    }
+
+    let ready = ok_json(http.get(url("/readyz"))).await;
+    assert_eq!(ready["ready"], true);
+    assert_eq!(ready["checks"]["database"]["ok"], true);
+    assert_eq!(
+        ready["checks"]["migrations"]["pending"],
+        serde_json::json!([])
+    );
+    assert_eq!(
+        ready["checks"]["migrations"]["failed"],
+        serde_json::json!([])
+    );
+
+    assert_eq!(backend.json("/dbtest").await["value"], 1);
+
+    let metrics = http.get(url("/metrics")).send().await.unwrap();
+    let metrics = metrics.text().await.unwrap();
+    assert!(metrics.contains("http_requests_total{method=\"GET\",path=\"/readyz\""));
+
+    let openapi = ok_json(http.get(url("/api-docs/openapi.json"))).await;
+    let paths = openapi["paths"].as_object().unwrap();
+    for path in ["/v1/samples", "/v1/live/ws", "/v1/sessions/{id}/export"] {
+        assert!(paths.contains_key(path), "{} is not documented", path);
+    }
+    let swagger = http.get(url("/swagger-ui")).send().await.unwrap();
+    assert!(swagger.text().await.unwrap().contains("<html"));
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn unversioned_paths() {
+    let backend = Backend::start().await;
+
+    let legacy = backend
+        .http
+        .get(format!("{}/samples?channel=Fz&limit=1", backend.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(legacy.status(), StatusCode::OK);
+    assert!(legacy.headers().contains_key("deprecation"));
+    let body: serde_json::Value = legacy.json().await.unwrap();
+    assert_eq!(body["samples"].as_array().unwrap().len(), 1);
+
+    let (status, body) = send(backend.http.get(format!("{}/v9/samples", backend.url))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "not_found");
+}
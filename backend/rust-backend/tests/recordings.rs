@@ -0,0 +1,580 @@
+//! Subjects, sessions and what is recorded with them: channel metadata,
+//! impedance checks, events and stored epoch sets.
+
+mod common;
+
+use common::{ok_json, send, Backend, CHANNELS, EVENTS, EVENT_LABEL};
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn subjects_and_sessions() {
+    let backend = Backend::start().await;
+    let seeded = backend
+        .json(&format!("/subjects/{}", backend.seed.subject_id))
+        .await;
+    assert_eq!(seeded["code"], "S01");
+
+    let subject = json!({ "code": "S02", "birth_year": 1990, "handedness": "left" });
+    let (status, created) = send(backend.post("/subjects").json(&subject)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let subject_id = created["id"].as_i64().unwrap();
+    let (status, _) = send(backend.post("/subjects").json(&subject)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let found = backend.json("/subjects?code=S02").await;
+    assert_eq!(found[0]["id"], subject_id);
+    let updated = ok_json(
+        backend
+            .patch(&format!("/subjects/{}", subject_id))
+            .json(&json!({ "notes": "pilot" })),
+    )
+    .await;
+    assert_eq!(updated["notes"], "pilot");
+    assert_eq!(updated["birth_year"], 1990);
+
+    let (status, session) = send(
+        backend
+            .post("/sessions")
+            .json(&json!({ "subject_id": subject_id, "device": "sim" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let session_id = session["id"].as_i64().unwrap();
+    assert!(session["ended_at"].is_null());
+    let active = backend.json("/sessions?active=true").await;
+    assert_eq!(active.as_array().unwrap().len(), 1);
+    assert_eq!(active[0]["id"], session_id);
+    let ended = ok_json(
+        backend
+            .patch(&format!("/sessions/{}", session_id))
+            .json(&json!({ "ended_at": "2099-01-01T00:00:00Z" })),
+    )
+    .await;
+    assert_eq!(ended["ended_at"], "2099-01-01T00:00:00Z");
+    assert_eq!(backend.json("/sessions?active=true").await, json!([]));
+    let of_subject = backend
+        .json(&format!("/sessions?subject_id={}", subject_id))
+        .await;
+    assert_eq!(of_subject.as_array().unwrap().len(), 1);
+    assert_eq!(
+        backend.json(&format!("/sessions/{}", session_id)).await["device"],
+        "sim"
+    );
+
+    // Subjects with sessions are kept.
+    let (status, _) = send(backend.delete(&format!("/subjects/{}", subject_id))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (_, empty) = send(backend.post("/subjects").json(&json!({ "code": "S03" }))).await;
+    let deleted = backend
+        .delete(&format!("/subjects/{}", empty["id"]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get(&format!("/subjects/{}", empty["id"]))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    for (path, input, field) in [
+        ("/subjects", json!({ "code": "S 04" }), "code"),
+        ("/subjects", json!({ "birth_year": 1990 }), "code"),
+        ("/subjects", json!({ "code": "S04", "sex": "x" }), "sex"),
+        (
+            "/sessions",
+            json!({ "started_at": "2024-01-02T00:00:00Z", "ended_at": "2024-01-01T00:00:00Z" }),
+            "ended_at",
+        ),
+        ("/events", json!({ "ts": "2024-01-01T00:00:00Z" }), "label"),
+        (
+            "/events",
+            json!({ "ts": "2024-01-01T00:00:00Z", "label": "x", "duration": -1 }),
+            "duration",
+        ),
+    ] {
+        let (status, body) = send(backend.post(path).json(&input)).await;
+        assert_eq!(
+            status,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}: {}",
+            path,
+            body
+        );
+        assert!(body["details"]["fields"][field].is_string(), "{}", body);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn channel_metadata() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+    let path = format!("/sessions/{}/channels", session_id);
+    let channels = backend.json(&path).await;
+    assert_eq!(channels.as_array().unwrap().len(), CHANNELS.len());
+    assert_eq!(channels[0]["channel"], "Fz");
+    assert_eq!(channels[0]["sample_rate"], 250.0);
+    assert_eq!(channels[0]["unit"], "uV");
+    assert_eq!(channels[0]["overrides"]["scale"], json!(null));
+
+    let set = json!({ "unit": "nV", "scale": 0.5 });
+    let cz = ok_json(backend.put(&format!("{}/Cz", path)).json(&set)).await;
+    assert_eq!(cz["unit"], "nV");
+    assert_eq!(cz["scale"], 0.5);
+    assert_eq!(cz["sample_rate"], 250.0);
+    assert_eq!(cz["overrides"]["unit"], "nV");
+
+    // Reads within the session carry the override, others the registry's.
+    let samples = backend
+        .json(&format!(
+            "/samples?channel=Fz,Cz&limit=1&session_id={}",
+            session_id
+        ))
+        .await;
+    assert_eq!(samples["channels"][0]["metadata"]["scale"], 1.0);
+    assert_eq!(samples["channels"][1]["metadata"]["unit"], "nV");
+    let live = backend.json("/live?channel=Cz&limit=1").await;
+    assert_eq!(live["metadata"]["unit"], "uV");
+    let resampled = backend
+        .json(&format!(
+            "/samples?channel=Cz&resample=100&session_id={}",
+            session_id
+        ))
+        .await;
+    assert_eq!(resampled["metadata"]["sample_rate"], 100.0);
+
+    let (status, body) = send(
+        backend
+            .put(&format!("{}/Cz", path))
+            .json(&json!({ "scale": 0 })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fields"]["scale"].is_string(), "{}", body);
+    let (status, _) = send(backend.put(&format!("{}/nope", path)).json(&set)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(backend.get("/sessions/999999/channels")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, body) = send(backend.get("/sessions/abc/channels")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "bad_request", "{}", body);
+    let (status, body) = send(
+        backend
+            .put(&format!("{}/Cz", path))
+            .header("content-type", "application/json")
+            .body("{\"scale\":"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "bad_request", "{}", body);
+
+    let deleted = backend
+        .delete(&format!("{}/Cz", path))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let channels = backend.json(&path).await;
+    assert!(channels
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|c| c["unit"] == "uV"));
+    let deleted = backend
+        .delete(&format!("{}/Cz", path))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(deleted.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn unit_conversion() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+    let path = format!("/sessions/{}/channels", session_id);
+    let set = json!({ "unit": "mV", "scale": 0.5 });
+    ok_json(backend.put(&format!("{}/Cz", path)).json(&set)).await;
+
+    let read = |unit: &str| {
+        format!(
+            "/samples?channel=Fz,Cz&limit=5&session_id={}&unit={}",
+            session_id, unit
+        )
+    };
+    let raw = backend.json(&read("raw")).await;
+    let micro = backend.json(&read("uV")).await;
+    let milli = backend.json(&read("mV")).await;
+    for (i, factors) in [(0, [1.0, 1e-3]), (1, [500.0, 0.5])] {
+        assert_eq!(micro["channels"][i]["metadata"]["unit"], "uV");
+        assert_eq!(milli["channels"][i]["metadata"]["unit"], "mV");
+        assert_eq!(milli["channels"][i]["metadata"]["scale"], 1.0);
+        for j in 0..5 {
+            let stored = raw["channels"][i]["samples"][j]["value"].as_f64().unwrap();
+            for (body, factor) in [(&micro, factors[0]), (&milli, factors[1])] {
+                let value = body["channels"][i]["samples"][j]["value"].as_f64().unwrap();
+                assert!(
+                    (value - stored * factor).abs() < 1e-9,
+                    "{} {}",
+                    value,
+                    stored
+                );
+            }
+        }
+    }
+    assert_eq!(raw["channels"][1]["metadata"]["unit"], "mV");
+    assert_eq!(raw["channels"][1]["metadata"]["scale"], 0.5);
+
+    let live = backend
+        .json(&format!(
+            "/live?channel=Cz&limit=1&session_id={}&unit=uV",
+            session_id
+        ))
+        .await;
+    assert_eq!(live["metadata"]["unit"], "uV");
+    let csv = backend
+        .get(&format!(
+            "/samples/export.csv?channel=Cz&session_id={}&unit=uV",
+            session_id
+        ))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let first: f64 = csv
+        .lines()
+        .nth(1)
+        .unwrap()
+        .rsplit(',')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let stored = backend
+        .json(&format!(
+            "/samples?channel=Cz&after_id=0&limit=1&session_id={}",
+            session_id
+        ))
+        .await["samples"][0]["value"]
+        .as_f64()
+        .unwrap();
+    assert!(
+        (first - stored * 500.0).abs() < 1e-9,
+        "{} {}",
+        first,
+        stored
+    );
+    let export = format!("/sessions/{}/export?format=edf&unit=mV", session_id);
+    let (status, _) = send(backend.get(&export)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // A unit that is not a voltage does not convert.
+    let set = json!({ "unit": "%" });
+    ok_json(backend.put(&format!("{}/Oz", path)).json(&set)).await;
+    let (status, body) = send(backend.get(&format!(
+        "/samples?channel=Oz&session_id={}&unit=uV",
+        session_id
+    )))
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    let (status, _) = send(backend.get(&export)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let request = json!({ "format": "edf", "session_id": session_id, "unit": "uV" });
+    let (status, _) = send(backend.post("/exports").json(&request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(backend.get(&format!(
+        "/samples?channel=Oz&session_id={}&unit=raw",
+        session_id
+    )))
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+/// The summary of the `gaps` job once it has run more than `runs` times.
+async fn gap_check(backend: &Backend, runs: i64) -> serde_json::Value {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
+    loop {
+        let jobs = backend.json("/admin/jobs").await;
+        let job = jobs["jobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|job| job["name"] == "gaps")
+            .cloned()
+            .unwrap();
+        if job["running"] == false && job["runs"].as_i64().unwrap() > runs {
+            return job["last_run"]["summary"].clone();
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{}", job);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn gaps() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+    let path = format!("/sessions/{}/gaps", session_id);
+    let gaps = backend.json(&path).await;
+    assert_eq!(gaps["min_intervals"], 2.0);
+    let channels = gaps["channels"].as_array().unwrap();
+    assert_eq!(channels.len(), CHANNELS.len());
+    assert!(channels.iter().all(|c| c["gaps"] == json!([])), "{}", gaps);
+
+    // Half a second of Fz goes missing two seconds in.
+    let start = backend.seed.start;
+    sqlx::query("DELETE FROM eeg_samples WHERE channel = 'Fz' AND ts >= $1 AND ts < $2")
+        .bind(start + chrono::Duration::seconds(2))
+        .bind(start + chrono::Duration::milliseconds(2500))
+        .execute(&backend.pool)
+        .await
+        .unwrap();
+    let gaps = backend.json(&path).await;
+    let fz = &gaps["channels"][0];
+    assert_eq!(fz["channel"], "Fz");
+    assert_eq!(fz["sample_rate"], 250.0);
+    assert_eq!(fz["gaps"].as_array().unwrap().len(), 1, "{}", fz);
+    let gap = &fz["gaps"][0];
+    assert!(
+        (gap["duration"].as_f64().unwrap() - 0.504).abs() < 1e-9,
+        "{}",
+        gap
+    );
+    assert_eq!(gap["missing"], 125);
+    assert_eq!(gaps["channels"][1]["gaps"], json!([]));
+    let cz = backend.json(&format!("{}?channel=Cz", path)).await;
+    assert_eq!(cz["channels"].as_array().unwrap().len(), 1);
+    let long = backend.json(&format!("{}?min_intervals=200", path)).await;
+    assert_eq!(long["channels"][0]["gaps"], json!([]));
+
+    let (status, _) = send(backend.get(&format!("{}?min_intervals=0.5", path))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = send(backend.get("/sessions/999999/gaps")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // The background check finds the gap up to a sample written after its
+    // previous run.
+    let runs = gap_check(&backend, 0).await;
+    assert!(runs.is_string(), "{}", runs);
+    sqlx::query(
+        "INSERT INTO eeg_samples (ts, channel, value, session_id) VALUES (now(), 'Fz', 0, $1)",
+    )
+    .bind(session_id)
+    .execute(&backend.pool)
+    .await
+    .unwrap();
+    let (status, job) = send(backend.post("/admin/jobs/gaps/run")).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", job);
+    let summary = gap_check(&backend, job["runs"].as_i64().unwrap()).await;
+    assert_eq!(summary, format!("1 gaps in sessions {{{}}}", session_id));
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn impedances() {
+    let backend = Backend::start().await;
+    let path = format!("/sessions/{}/impedances", backend.seed.session_id);
+    for kohm in [20.0, 4.5] {
+        let check = json!({ "impedances": { "Fz": kohm, "Cz": 12.0 } });
+        let (status, stored) = send(backend.post(&path).json(&check)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(stored.as_array().unwrap().len(), 2);
+    }
+    let all = backend.json(&path).await;
+    assert_eq!(all.as_array().unwrap().len(), 4);
+    let latest = backend
+        .json(&format!("{}?channel=Fz&latest=true", path))
+        .await;
+    assert_eq!(latest.as_array().unwrap().len(), 1);
+    assert_eq!(latest[0]["kohm"], 4.5);
+
+    let (status, _) = send(
+        backend
+            .post(&path)
+            .json(&json!({ "impedances": { "Fz": -1.0 } })),
+    )
+    .await;
+    assert!(status.is_client_error(), "{}", status);
+    let (status, _) = send(backend.get("/sessions/999999/impedances")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn events() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+    let seeded = backend
+        .json(&format!(
+            "/events?session_id={}&label={}",
+            session_id, EVENT_LABEL
+        ))
+        .await;
+    assert_eq!(seeded.as_array().unwrap().len() as i64, EVENTS);
+    let limited = backend
+        .json(&format!("/events?session_id={}&limit=2", session_id))
+        .await;
+    assert_eq!(limited.as_array().unwrap().len(), 2);
+
+    let event = json!({
+        "label": "blink",
+        "ts": "2030-01-01T00:00:00Z",
+        "session_id": session_id,
+        "duration": 0.3,
+        "metadata": { "eye": "left" },
+    });
+    let (status, created) = send(backend.post("/events").json(&event)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let path = format!("/events/{}", created["id"]);
+    assert_eq!(backend.json(&path).await["metadata"]["eye"], "left");
+    let updated = ok_json(
+        backend
+            .patch(&path)
+            .json(&json!({ "label": "double blink" })),
+    )
+    .await;
+    assert_eq!(updated["label"], "double blink");
+    assert_eq!(updated["duration"], 0.3);
+    let blinks = backend.json("/events?label=double%20blink").await;
+    assert_eq!(blinks.as_array().unwrap().len(), 1);
+
+    let (status, _) = send(
+        backend
+            .post("/events")
+            .json(&json!({ "label": "bad", "ts": "2030-01-01T00:00:00Z", "duration": -1.0 })),
+    )
+    .await;
+    assert!(status.is_client_error(), "{}", status);
+
+    let deleted = backend.delete(&path).send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get(&path)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn triggers() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+    let channel = json!({ "name": "TRIG", "kind": "trigger" });
+    let (status, created) = send(backend.post("/channels").json(&channel)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["kind"], "trigger");
+    let (status, body) = send(
+        backend
+            .post("/channels")
+            .json(&json!({ "name": "TRIG2", "kind": "digital" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fields"]["kind"].is_string(), "{}", body);
+
+    let codes = [0.0, 0.0, 5.0, 5.0, 5.0, 0.0, 0.0, 7.0];
+    let samples: Vec<_> = codes
+        .iter()
+        .enumerate()
+        .map(|(i, code)| {
+            json!({
+                "channel": "TRIG",
+                "ts": backend.seed.start + chrono::Duration::milliseconds(4 * i as i64),
+                "value": code,
+                "session_id": session_id,
+            })
+        })
+        .collect();
+    // A retry finds every change stored.
+    for _ in 0..2 {
+        let (status, stored) = send(backend.post("/samples/batch").json(&samples)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(stored["inserted"], 0);
+    }
+    let stored: i64 = sqlx::query_scalar("SELECT count(*) FROM eeg_samples WHERE channel = 'TRIG'")
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+
+    let changes = backend
+        .json(&format!("/triggers?channel=TRIG&session_id={}", session_id))
+        .await;
+    let pairs: Vec<_> = changes
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| (c["previous"].clone(), c["value"].clone()))
+        .collect();
+    assert_eq!(
+        pairs,
+        [
+            (json!(null), json!(0.0)),
+            (json!(0.0), json!(5.0)),
+            (json!(5.0), json!(0.0)),
+            (json!(0.0), json!(7.0)),
+        ]
+    );
+    let fives = backend.json("/triggers?channel=TRIG&value=5").await;
+    assert_eq!(fives.as_array().unwrap().len(), 1);
+
+    let marked = backend
+        .json(&format!(
+            "/events?session_id={}&label=trigger:5",
+            session_id
+        ))
+        .await;
+    let marked = marked.as_array().unwrap();
+    assert_eq!(marked.len(), 1);
+    assert_eq!(marked[0]["ts"], changes[1]["ts"]);
+    assert_eq!(marked[0]["metadata"]["source"], "trigger");
+    let sevens = backend.json("/events?label=trigger:7").await;
+    assert_eq!(sevens.as_array().unwrap().len(), 1);
+
+    let (status, single) = send(backend.post("/samples").json(&json!({
+        "channel": "TRIG",
+        "ts": backend.seed.start + chrono::Duration::seconds(1),
+        "value": 7.0,
+        "session_id": session_id,
+    })))
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(single["id"], json!(null));
+    let changes = backend.json("/triggers?channel=TRIG").await;
+    assert_eq!(changes.as_array().unwrap().len(), 4);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn epoch_sets() {
+    let backend = Backend::start().await;
+    let (status, set) = send(backend.post(&format!(
+        "/epoch-sets?channel=Fz,Cz&name=stim&event_label={}&pre=0.1&post=0.4&session_id={}",
+        EVENT_LABEL, backend.seed.session_id
+    )))
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", set);
+    assert_eq!(set["channels"], json!(["Fz", "Cz"]));
+    assert_eq!(set["epochs"].as_i64().unwrap(), EVENTS);
+    // 0.1 s before to 0.4 s after each event, at 250 Hz.
+    assert_eq!(set["times"].as_array().unwrap().len(), 126);
+
+    let path = format!("/epoch-sets/{}", set["id"]);
+    assert_eq!(backend.json(&path).await["name"], "stim");
+    assert_eq!(
+        backend.json("/epoch-sets").await.as_array().unwrap().len(),
+        1
+    );
+    let page = backend
+        .json(&format!("{}/epochs?offset=2&limit=3", path))
+        .await;
+    let epochs = page["epochs"].as_array().unwrap();
+    assert_eq!(epochs.len(), 3);
+    assert_eq!(epochs[0]["data"].as_array().unwrap().len(), 2);
+
+    let deleted = backend.delete(&path).send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get(&path)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
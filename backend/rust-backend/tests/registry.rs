@@ -0,0 +1,481 @@
+//! The registries: channels and their calibrations, devices, montages,
+//! pipelines and alert rules, and their effect on ingest and reads.
+
+mod common;
+
+use common::{ok_json, send, value, Backend};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn channels() {
+    let backend = Backend::start().await;
+    let listed = backend.json("/channels").await;
+    let names: Vec<&str> = listed
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c["name"].as_str().unwrap())
+        .collect();
+    assert!(
+        names.contains(&"Fz") && names.contains(&"A3"),
+        "{:?}",
+        names
+    );
+
+    let input = json!({ "name": "T7", "label": "T7", "sample_rate": 250.0 });
+    let (status, created) = send(backend.post("/channels").json(&input)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["unit"], "uV");
+    let (status, _) = send(backend.post("/channels").json(&input)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // Registered channels take samples at once; disabled ones do not.
+    let sample = json!({ "channel": "T7", "ts": "2030-01-01T00:00:00Z", "value": 1.0 });
+    let (status, _) = send(backend.post("/samples").json(&sample)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let updated = ok_json(
+        backend
+            .put("/channels/T7")
+            .json(&json!({ "label": "left temporal", "enabled": false })),
+    )
+    .await;
+    assert_eq!(updated["label"], "left temporal");
+    assert_eq!(updated["enabled"], false);
+    assert_eq!(backend.json("/channels/T7").await["enabled"], false);
+    let (status, body) = send(backend.post("/samples").json(&sample)).await;
+    assert!(status.is_client_error(), "{}", status);
+    assert!(
+        body["message"].as_str().unwrap().contains("disabled"),
+        "{}",
+        body
+    );
+
+    let deleted = backend.delete("/channels/A4").send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get("/channels/A4")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let amplitude = json!({ "type": "amplitude" });
+    for (path, input, field) in [
+        ("/channels", json!({ "label": "T8" }), "name"),
+        ("/channels", json!({ "name": "T8", "scale": 0 }), "scale"),
+        (
+            "/devices",
+            json!({ "serial": "D1", "model": "m", "channel_map": ["Fz", "Fz"], "sample_rate": 250 }),
+            "channel_map[1]",
+        ),
+        ("/devices", json!({ "serial": "D1" }), "sample_rate"),
+        (
+            "/montages",
+            json!({ "name": "m", "derivations": [{ "channel": "Fz", "reference": "x y" }] }),
+            "derivations[0].reference",
+        ),
+        ("/pipelines", json!({ "name": "p" }), "stages"),
+        (
+            "/alerts/rules",
+            json!({ "name": "r", "metric": amplitude, "above": 1, "below": 2 }),
+            "below",
+        ),
+        (
+            "/alerts/rules",
+            json!({ "name": "r", "metric": amplitude, "above": 1, "window_seconds": 0 }),
+            "window_seconds",
+        ),
+    ] {
+        let (status, body) = send(backend.post(path).json(&input)).await;
+        assert_eq!(
+            status,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}: {}",
+            path,
+            body
+        );
+        assert!(body["details"]["fields"][field].is_string(), "{}", body);
+    }
+}
+
+/// Values of the five newest Fz samples of the seeded session.
+async fn values(backend: &Backend, calibrated: bool) -> Vec<f64> {
+    let path = format!(
+        "/samples?channel=Fz&limit=5&session_id={}&calibrated={}",
+        backend.seed.session_id, calibrated
+    );
+    backend.json(&path).await["samples"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["value"].as_f64().unwrap())
+        .collect()
+}
+
+fn close(actual: &[f64], expected: &[f64]) {
+    assert_eq!(actual.len(), expected.len());
+    for (a, e) in actual.iter().zip(expected) {
+        assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn calibrations() {
+    let backend = Backend::start().await;
+    let raw = values(&backend, false).await;
+    assert_eq!(values(&backend, true).await, raw);
+
+    let path = "/channels/Fz/calibrations";
+    let input = json!({ "gain": 2.0, "offset": 1.0, "calibrated_at": "2000-01-01T00:00:00Z" });
+    let (status, everywhere) = send(backend.post(path).json(&input)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", everywhere);
+    assert!(everywhere["device"].is_null());
+    let expected: Vec<f64> = raw.iter().map(|v| v * 2.0 + 1.0).collect();
+    close(&values(&backend, true).await, &expected);
+
+    // The session's device has its own, which take precedence.
+    let device = json!({ "serial": "sim", "model": "simulator", "channel_map": ["Fz"], "sample_rate": 250.0 });
+    let (status, _) = send(backend.post("/devices").json(&device)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let input = json!({ "device": "sim", "gain": 3.0, "calibrated_at": "2000-01-01T00:00:00Z" });
+    let (status, for_device) = send(backend.post(path).json(&input)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", for_device);
+    assert_eq!(for_device["offset"], 0.0);
+    let expected: Vec<f64> = raw.iter().map(|v| v * 3.0).collect();
+    close(&values(&backend, true).await, &expected);
+    let live = backend
+        .json(&format!(
+            "/live?channel=Fz&limit=3&calibrated=true&session_id={}",
+            backend.seed.session_id
+        ))
+        .await;
+    assert_eq!(live["points"].as_array().unwrap().len(), 3);
+
+    assert_eq!(backend.json(path).await.as_array().unwrap().len(), 2);
+    let listed = backend.json(&format!("{}?device=sim", path)).await;
+    assert_eq!(listed[0]["id"], for_device["id"]);
+
+    for (input, expected) in [
+        (json!({ "gain": 0.0 }), StatusCode::UNPROCESSABLE_ENTITY),
+        (
+            json!({ "gain": 1.0, "device": "nope" }),
+            StatusCode::BAD_REQUEST,
+        ),
+    ] {
+        let (status, body) = send(backend.post(path).json(&input)).await;
+        assert_eq!(status, expected, "{}", body);
+    }
+    let (status, _) = send(
+        backend
+            .post("/channels/nope/calibrations")
+            .json(&json!({ "gain": 1.0 })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, body) =
+        send(backend.get("/samples?channel=Fz&reference=Cz&calibrated=true")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(
+        body["details"]["fields"]["calibrated"].is_string(),
+        "{}",
+        body
+    );
+
+    let deleted = backend
+        .delete(&format!("{}/{}", path, for_device["id"]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let expected: Vec<f64> = raw.iter().map(|v| v * 2.0 + 1.0).collect();
+    close(&values(&backend, true).await, &expected);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn devices() {
+    let backend = Backend::start().await;
+    assert_eq!(backend.json("/devices").await, json!([]));
+
+    let device = json!({
+        "serial": "DEV1",
+        "model": "cyton",
+        "channel_map": ["Fz", "Cz"],
+        "sample_rate": 250.0,
+    });
+    let (status, created) = send(backend.post("/devices").json(&device)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["channel_map"], json!(["Fz", "Cz"]));
+    assert_eq!(backend.json("/devices").await.as_array().unwrap().len(), 1);
+    let updated = ok_json(
+        backend
+            .put("/devices/DEV1")
+            .json(&json!({ "notes": "lab 2" })),
+    )
+    .await;
+    assert_eq!(updated["notes"], "lab 2");
+    assert_eq!(backend.json("/devices/DEV1").await["notes"], "lab 2");
+
+    // Samples sent for a device are checked against its channel map.
+    let sample =
+        |channel: &str| json!({ "channel": channel, "ts": "2030-01-01T00:00:00Z", "value": 1.0 });
+    let (status, _) = send(backend.post("/samples?device=DEV1").json(&sample("Cz"))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = send(backend.post("/samples?device=DEV1").json(&sample("Oz"))).await;
+    assert!(status.is_client_error(), "{}", status);
+
+    let deleted = backend.delete("/devices/DEV1").send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get("/devices/DEV1")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn clock_drift() {
+    let backend = Backend::start().await;
+    let device =
+        json!({ "serial": "CLK", "model": "cyton", "channel_map": ["Fz"], "sample_rate": 250.0 });
+    let (status, _) = send(backend.post("/devices").json(&device)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let session_id: i32 = sqlx::query_scalar(
+        "INSERT INTO sessions (device, started_at) VALUES ('CLK', now() - interval '1 hour') \
+         RETURNING id",
+    )
+    .fetch_one(&backend.pool)
+    .await
+    .unwrap();
+    let unpaired = backend
+        .json(&format!("/devices/CLK/clock?session_id={}", session_id))
+        .await;
+    assert!(unpaired["clock"].is_null(), "{}", unpaired);
+
+    // A device 2 s behind the server.
+    let sent = chrono::Utc::now() - chrono::Duration::seconds(2);
+    let batch = json!([{ "channel": "Fz", "ts": sent, "value": 1.0, "session_id": session_id }]);
+    let path = format!(
+        "/samples/batch?device=CLK&device_time={}",
+        sent.timestamp_millis()
+    );
+    let (status, body) = send(backend.post(&path).json(&batch)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let clock = backend.json("/devices/CLK/clock").await["clock"].clone();
+    assert_eq!(clock["points"], 1);
+    assert_eq!(clock["drift_ppm"], 0.0);
+    let offset = clock["offset_ms"].as_f64().unwrap();
+    assert!((2000.0..3000.0).contains(&offset), "{}", offset);
+    let (status, _) = send(backend.post("/samples/batch?device_time=0").json(&batch)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // A server clock 100 ppm faster, 0.5 s ahead at the first pair.
+    sqlx::query("DELETE FROM clock_sync")
+        .execute(&backend.pool)
+        .await
+        .unwrap();
+    let first = chrono::Utc::now() - chrono::Duration::minutes(30);
+    for k in 0..10 {
+        let device_time = first + chrono::Duration::seconds(100 * k);
+        let received_at = device_time + chrono::Duration::microseconds(500_000 + 10_000 * k);
+        sqlx::query(
+            "INSERT INTO clock_sync (device_serial, device_time, received_at) VALUES ('CLK', $1, $2)",
+        )
+        .bind(device_time)
+        .bind(received_at)
+        .execute(&backend.pool)
+        .await
+        .unwrap();
+    }
+    let clock = backend
+        .json(&format!("/devices/CLK/clock?session_id={}", session_id))
+        .await["clock"]
+        .clone();
+    assert_eq!(clock["points"], 10);
+    assert!(
+        (clock["drift_ppm"].as_f64().unwrap() - 100.0).abs() < 1e-3,
+        "{}",
+        clock
+    );
+    assert!(
+        (clock["offset_ms"].as_f64().unwrap() - 590.0).abs() < 1e-3,
+        "{}",
+        clock
+    );
+
+    let last = first + chrono::Duration::seconds(900);
+    let batch = json!([
+        { "channel": "Fz", "ts": last, "value": 2.0, "session_id": session_id },
+        { "channel": "Fz", "ts": last + chrono::Duration::seconds(100), "value": 3.0, "session_id": session_id },
+    ]);
+    let (status, _) = send(backend.post("/samples/batch").json(&batch)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let body = backend
+        .json(&format!(
+            "/samples?channel=Fz&session_id={}&clock_corrected=true&time_format=epoch_ms",
+            session_id
+        ))
+        .await;
+    assert_eq!(body["clock"]["points"], 10);
+    let samples = body["samples"].as_array().unwrap();
+    let shifts: Vec<i64> = samples
+        .iter()
+        .map(|s| s["ts_corrected"].as_i64().unwrap() - s["ts"].as_i64().unwrap())
+        .collect();
+    // Newest first: 100 s after the last pair, then at it.
+    assert_eq!(&shifts[..2], [600, 590]);
+    let (status, body) = send(backend.get("/samples?channel=Fz&clock_corrected=true")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(
+        body["details"]["fields"]["clock_corrected"].is_string(),
+        "{}",
+        body
+    );
+
+    let (status, _) = send(backend.get(&format!(
+        "/devices/CLK/clock?session_id={}",
+        backend.seed.session_id
+    )))
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(backend.get("/devices/nope/clock")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn montages() {
+    let backend = Backend::start().await;
+    let builtin = backend.json("/montages").await;
+    assert!(builtin
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|m| m["name"] == "double_banana"));
+
+    let montage = json!({
+        "name": "midline",
+        "description": "midline bipolar",
+        "derivations": [
+            { "name": "Fz-Cz", "channel": "Fz", "reference": "Cz" },
+            { "name": "Pz-Oz", "channel": "Pz", "reference": "Oz" },
+        ],
+    });
+    let (status, _) = send(backend.post("/montages").json(&montage)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let updated = ok_json(
+        backend
+            .put("/montages/midline")
+            .json(&json!({ "description": "two pairs" })),
+    )
+    .await;
+    assert_eq!(updated["description"], "two pairs");
+    assert_eq!(updated["derivations"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        backend.json("/montages/midline").await["description"],
+        "two pairs"
+    );
+
+    let page = backend
+        .json("/samples?montage=midline&after_id=0&limit=2")
+        .await;
+    let channels = page["channels"].as_array().unwrap();
+    assert_eq!(channels[0]["channel"], "Fz-Cz");
+    let derived = channels[1]["samples"][1]["value"].as_f64().unwrap();
+    assert!((derived - (value("Pz", 1) - value("Oz", 1))).abs() < 1e-9);
+
+    let deleted = backend.delete("/montages/midline").send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get("/montages/midline")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn pipelines() {
+    let backend = Backend::start().await;
+    assert_eq!(backend.json("/pipelines").await, json!([]));
+
+    let pipeline = json!({
+        "name": "clean",
+        "channels": ["Fz"],
+        "stages": [{ "type": "notch", "freq": 50.0 }],
+    });
+    let (status, created) = send(backend.post("/pipelines").json(&pipeline)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["stages"][0]["type"], "notch");
+    assert_eq!(created["enabled"], true);
+    let (status, _) = send(
+        backend
+            .post("/pipelines")
+            .json(&json!({ "name": "bad", "stages": [{ "type": "nope" }] })),
+    )
+    .await;
+    assert!(status.is_client_error(), "{}", status);
+
+    let updated = ok_json(
+        backend
+            .put("/pipelines/clean")
+            .json(&json!({ "enabled": false })),
+    )
+    .await;
+    assert_eq!(updated["enabled"], false);
+    assert_eq!(backend.json("/pipelines/clean").await["enabled"], false);
+    assert_eq!(
+        backend.json("/pipelines").await.as_array().unwrap().len(),
+        1
+    );
+
+    let deleted = backend.delete("/pipelines/clean").send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get("/pipelines/clean")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn alert_rules() {
+    let backend = Backend::start().await;
+    let rule = json!({
+        "name": "loud",
+        "metric": { "type": "amplitude" },
+        "channels": ["Fz"],
+        "above": 1000.0,
+    });
+    let (status, created) = send(backend.post("/alerts/rules").json(&rule)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["window_seconds"], 1.0);
+    assert_eq!(
+        backend
+            .json("/alerts/rules")
+            .await
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    let updated = ok_json(backend.put("/alerts/rules/loud").json(&json!({
+        "metric": { "type": "rms" },
+        "channels": ["Fz", "Oz"],
+        "above": 500.0,
+    })))
+    .await;
+    assert_eq!(updated["metric"]["type"], "rms");
+    assert_eq!(backend.json("/alerts/rules/loud").await["above"], 500.0);
+
+    let alerts: Value = backend.json("/alerts").await;
+    assert!(
+        alerts
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|a| a["rule"] == "loud"),
+        "{}",
+        alerts
+    );
+    let (status, _) = send(backend.get("/alerts?status=nope")).await;
+    assert!(status.is_client_error(), "{}", status);
+
+    let deleted = backend.delete("/alerts/rules/loud").send().await.unwrap();
+    assert_eq!(deleted.status(), StatusCode::NO_CONTENT);
+    let (status, _) = send(backend.get("/alerts/rules/loud")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
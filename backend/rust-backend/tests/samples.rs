@@ -0,0 +1,891 @@
+//! Sample ingest and the `/samples` reads: pages, windows, aggregates and
+//! filtered views of the seeded recording.
+
+mod common;
+
+use common::{ok_json, send, value, Backend, CHANNELS, EVENTS, SAMPLES};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+async fn count(backend: &Backend, channel: &str) -> i64 {
+    sqlx::query_scalar("SELECT count(*) FROM eeg_samples WHERE channel = $1")
+        .bind(channel)
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap()
+}
+
+fn samples(body: &Value) -> &Vec<Value> {
+    body["samples"].as_array().unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn ingest() {
+    let backend = Backend::start().await;
+    let ts = "2030-01-01T00:00:00Z";
+
+    let (status, body) = send(
+        backend
+            .post("/samples")
+            .json(&json!({ "channel": "A3", "ts": ts, "value": 1.5 })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(body["id"].as_i64().is_some());
+
+    let batch: Vec<Value> = (0..10)
+        .map(|i| json!({ "channel": "A4", "ts": ts, "value": i as f64 }))
+        .collect();
+    let (status, body) = send(backend.post("/samples/batch").json(&batch)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["inserted"], 10);
+    assert_eq!(body["ids"].as_array().unwrap().len(), 10);
+
+    // Version 1 header: two channels at 250 Hz, then three frames.
+    let mut frames = vec![1u8, 0];
+    frames.extend_from_slice(&2u16.to_le_bytes());
+    frames.extend_from_slice(&250f32.to_le_bytes());
+    frames.extend_from_slice(&1_893_456_000_000_000i64.to_le_bytes());
+    for value in [1f32, -1.0, 2.0, -2.0, 3.0, -3.0] {
+        frames.extend_from_slice(&value.to_le_bytes());
+    }
+    let (status, body) = send(
+        backend
+            .post("/samples/binary?channels=A3,A4")
+            .header("content-type", "application/octet-stream")
+            .body(frames),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    assert_eq!(body["inserted"], 6);
+
+    assert_eq!(count(&backend, "A3").await, 4);
+    assert_eq!(count(&backend, "A4").await, 13);
+
+    let (status, body) = send(
+        backend
+            .post("/samples")
+            .json(&json!({ "channel": "nope", "ts": ts, "value": 1.0 })),
+    )
+    .await;
+    assert!(status.is_client_error(), "{}", status);
+    assert!(
+        body["message"].as_str().unwrap().contains("nope"),
+        "{}",
+        body
+    );
+    let (status, _) = send(
+        backend
+            .post("/samples")
+            .json(&json!({ "channel": "A3", "value": 1.0 })),
+    )
+    .await;
+    assert!(status.is_client_error(), "{}", status);
+    assert_eq!(count(&backend, "A3").await, 4);
+}
+
+/// Offsets in µs from `start` of the samples of `channel` in `session_id`
+/// after it, oldest first.
+async fn offsets_us(
+    backend: &Backend,
+    channel: &str,
+    session_id: Option<i32>,
+    start: chrono::DateTime<chrono::Utc>,
+) -> Vec<i64> {
+    let times: Vec<chrono::DateTime<chrono::Utc>> = sqlx::query_scalar(
+        "SELECT ts FROM eeg_samples WHERE channel = $1 \
+         AND session_id IS NOT DISTINCT FROM $2 AND ts >= $3 ORDER BY ts",
+    )
+    .bind(channel)
+    .bind(session_id)
+    .bind(start)
+    .fetch_all(&backend.pool)
+    .await
+    .unwrap();
+    times
+        .iter()
+        .map(|ts| (*ts - start).num_microseconds().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn series_ingest() {
+    let backend = Backend::start().await;
+    let session_id = backend.seed.session_id;
+    let end = backend.seed.start + chrono::Duration::seconds(common::SECONDS);
+
+    // Continuing the seeded recording at the channels' 250 Hz, with a
+    // channel that lost its last value.
+    let series = json!({
+        "session_id": session_id,
+        "channels": [
+            { "channel": "Fz", "values": [1.0, 2.0, 3.0] },
+            { "channel": "Oz", "values": [4.0, 5.0] },
+        ],
+    });
+    let (status, body) = send(backend.post("/samples/series").json(&series)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    assert_eq!(body["inserted"], 5);
+    assert_eq!(
+        offsets_us(&backend, "Fz", Some(session_id), end).await,
+        [0, 4000, 8000]
+    );
+    assert_eq!(
+        offsets_us(&backend, "Oz", Some(session_id), end).await,
+        [0, 4000]
+    );
+    let (status, _) = send(backend.post("/samples/series").json(&series)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        offsets_us(&backend, "Oz", Some(session_id), end).await,
+        [0, 4000, 8000, 12000]
+    );
+
+    // A3 has no registered rate.
+    let start: chrono::DateTime<chrono::Utc> = "2030-01-01T00:00:00Z".parse().unwrap();
+    let series = |rate: Option<f64>| {
+        json!({
+            "start": start,
+            "sample_rate": rate,
+            "channels": [{ "channel": "A3", "values": [1.0, 2.0, 3.0] }],
+        })
+    };
+    let (status, _) = send(backend.post("/samples/series").json(&series(None))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(backend.post("/samples/series").json(&series(Some(500.0)))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(
+        offsets_us(&backend, "A3", None, start).await,
+        [0, 2000, 4000]
+    );
+
+    for (series, expected) in [
+        // Not the registered rate.
+        (
+            json!({ "session_id": session_id, "sample_rate": 500.0,
+                    "channels": [{ "channel": "Fz", "values": [1.0] }] }),
+            StatusCode::BAD_REQUEST,
+        ),
+        // Nothing to continue from.
+        (
+            json!({ "channels": [{ "channel": "Fz", "values": [1.0] }] }),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            json!({ "start": start, "channels": [
+                { "channel": "Fz", "values": [1.0] },
+                { "channel": "Fz", "values": [2.0] },
+            ] }),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            json!({ "start": start, "channels": [{ "channel": "Fz", "values": [] }] }),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+    ] {
+        let (status, body) = send(backend.post("/samples/series").json(&series)).await;
+        assert_eq!(status, expected, "{}", body);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn sequenced_ingest() {
+    let backend = Backend::start().await;
+    let device = json!({ "serial": "AMP1", "model": "cyton", "channel_map": ["A3", "A4"], "sample_rate": 250.0 });
+    let (status, _) = send(backend.post("/devices").json(&device)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let batch: Vec<Value> = (0..5)
+        .map(|i| json!({ "channel": "A3", "ts": "2030-01-01T00:00:00Z", "value": i as f64 }))
+        .collect();
+    let post = |seq: i64| {
+        backend
+            .post(&format!("/samples/batch?device=AMP1&seq={}", seq))
+            .json(&batch)
+    };
+
+    let (status, body) = send(post(7)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    assert_eq!(body["inserted"], 5);
+    let (status, body) = send(post(7)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        json!({ "inserted": 0, "ids": [], "already_seen": true, "last_seq": 7 })
+    );
+    // Numbers below the latest are new batches unless they were seen.
+    let (status, _) = send(post(3)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, body) = send(post(3)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["last_seq"], 7);
+    assert_eq!(count(&backend, "A3").await, 10);
+
+    // Concurrent retries store the batch once.
+    let retries: Vec<_> = (0..4).map(|_| send(post(8))).collect();
+    let statuses: Vec<StatusCode> = futures::future::join_all(retries)
+        .await
+        .into_iter()
+        .map(|(status, _)| status)
+        .collect();
+    assert_eq!(
+        statuses
+            .iter()
+            .filter(|s| **s == StatusCode::CREATED)
+            .count(),
+        1,
+        "{:?}",
+        statuses
+    );
+    assert_eq!(count(&backend, "A3").await, 15);
+
+    let single = json!({ "channel": "A4", "ts": "2030-01-01T00:00:00Z", "value": 1.0 });
+    let path = "/samples?device=AMP1&seq=9";
+    let (status, _) = send(backend.post(path).json(&single)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, body) = send(backend.post(path).json(&single)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["id"], Value::Null);
+    assert_eq!(body["already_seen"], true);
+    assert_eq!(count(&backend, "A4").await, 1);
+
+    // A rejected batch does not use up its number.
+    let unknown = json!([{ "channel": "A3", "ts": "2030-01-01T00:00:00Z", "value": 1.0, "session_id": 999999 }]);
+    let unknown_session = || {
+        backend
+            .post("/samples/batch?device=AMP1&seq=10")
+            .json(&unknown)
+    };
+    let (status, _) = send(unknown_session()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(post(10)).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, body) = send(backend.post("/samples/batch?seq=11").json(&batch)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(
+        body["message"].as_str().unwrap().contains("device"),
+        "{}",
+        body
+    );
+    let (status, body) = send(post(-1)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fields"]["seq"].is_string(), "{}", body);
+
+    let batches: i64 = sqlx::query_scalar("SELECT count(*) FROM ingest_batches")
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap();
+    assert_eq!(batches, 5);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn pages() {
+    let backend = Backend::start().await;
+    let seed = &backend.seed;
+
+    // Newest first, then the next page through the cursor.
+    let first = backend.json("/samples?channel=Fz&limit=100").await;
+    assert_eq!(samples(&first).len(), 100);
+    let cursor = first["next_cursor"].as_i64().unwrap();
+    let next = backend
+        .json(&format!(
+            "/samples?channel=Fz&limit=100&before_id={}",
+            cursor
+        ))
+        .await;
+    let ids = |page: &Value| -> Vec<i64> {
+        samples(page)
+            .iter()
+            .map(|s| s["id"].as_i64().unwrap())
+            .collect()
+    };
+    let (first_ids, next_ids) = (ids(&first), ids(&next));
+    assert!(first_ids.windows(2).all(|w| w[0] > w[1]));
+    assert!(first_ids.last() > next_ids.first());
+
+    // Oldest first from the start, with the seeded values.
+    let oldest = backend.json("/samples?channel=Fz&after_id=0&limit=3").await;
+    for (i, sample) in samples(&oldest).iter().enumerate() {
+        assert_eq!(sample["channel"], "Fz");
+        let stored = sample["value"].as_f64().unwrap();
+        assert!((stored - value("Fz", i as i64)).abs() < 1e-9, "{}", sample);
+    }
+    let ts: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(samples(&oldest)[1]["ts"].clone()).unwrap();
+    assert_eq!(ts - seed.start, chrono::Duration::milliseconds(4));
+
+    let grouped = backend.json("/samples?channel=Fz,Cz&limit=5").await;
+    let channels = grouped["channels"].as_array().unwrap();
+    assert_eq!(channels.len(), 2);
+    assert_eq!(channels[1]["channel"], "Cz");
+    assert_eq!(samples(&channels[1]).len(), 5);
+
+    let window = backend
+        .json(&format!("/samples?channel=Fz&{}&points=50", seed.window()))
+        .await;
+    let points = samples(&window);
+    assert!(!points.is_empty() && points.len() <= 50, "{}", points.len());
+
+    let window = backend
+        .json(&format!(
+            "/samples?channel=Fz&session_id={}&{}&include_events=true&limit=1",
+            seed.session_id,
+            seed.window()
+        ))
+        .await;
+    assert_eq!(window["events"].as_array().unwrap().len() as i64, EVENTS);
+
+    let subject = backend
+        .json(&format!(
+            "/samples?channel=Oz&subject_id={}&limit=1000",
+            seed.subject_id
+        ))
+        .await;
+    assert_eq!(samples(&subject).len(), 1000);
+    let none = backend
+        .json("/samples?channel=Oz&subject_id=999999&limit=10")
+        .await;
+    assert!(samples(&none).is_empty());
+
+    // Fz against Cz, directly and through a montage.
+    let referenced = backend
+        .json("/samples?channel=Fz&reference=Cz&after_id=0&limit=2")
+        .await;
+    let v = samples(&referenced)[1]["value"].as_f64().unwrap();
+    assert!(
+        (v - (value("Fz", 1) - value("Cz", 1))).abs() < 1e-9,
+        "{}",
+        v
+    );
+
+    let (status, body) = send(backend.get("/samples?channel=Fz&limit=not-a-number")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fields"]["limit"].is_string(), "{}", body);
+    let (status, body) = send(backend.get("/samples?channel=Fz&notch=abc")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "unprocessable_entity", "{}", body);
+    assert!(body["details"]["fields"]["notch"].is_string(), "{}", body);
+    for (query, field) in [
+        ("notch=0", "notch"),
+        ("notch=50&notch_harmonics=99", "notch_harmonics"),
+        ("points=50&before_id=10", "before_id"),
+        ("resample=100&after_id=0", "after_id"),
+        ("channel=Cz&before_id=1,2,3", "before_id"),
+        ("reference=Cz&calibrated=true", "calibrated"),
+    ] {
+        let path = format!("/samples?channel=Fz&{}", query);
+        let (status, body) = send(backend.get(&path)).await;
+        assert_eq!(
+            status,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}: {}",
+            path,
+            body
+        );
+        assert!(body["details"]["fields"][field].is_string(), "{}", body);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn encodings() {
+    let backend = Backend::start().await;
+    let get = |accept: &'static str| {
+        backend
+            .get("/samples?channel=Pz&limit=3")
+            .header("accept", accept)
+    };
+    let json = ok_json(get("application/json")).await;
+
+    let msgpack = get("application/msgpack").send().await.unwrap();
+    assert_eq!(msgpack.headers()["content-type"], "application/msgpack");
+    let decoded: Value = rmp_serde::from_slice(&msgpack.bytes().await.unwrap()).unwrap();
+    assert_eq!(decoded["samples"].as_array().unwrap().len(), 3);
+    assert_eq!(decoded["samples"][0]["id"], json["samples"][0]["id"]);
+
+    let cbor = get("application/cbor").send().await.unwrap();
+    assert_eq!(cbor.headers()["content-type"], "application/cbor");
+    let decoded: Value = ciborium::from_reader(&cbor.bytes().await.unwrap()[..]).unwrap();
+    assert_eq!(decoded["samples"][2]["value"], json["samples"][2]["value"]);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn aggregates_and_filters() {
+    let backend = Backend::start().await;
+    let window = backend.seed.window();
+    let per_second = SAMPLES / common::SECONDS;
+
+    let aggregate = backend
+        .json(&format!(
+            "/samples/aggregate?channel=Fz&bucket=1s&{}",
+            window
+        ))
+        .await;
+    let buckets = aggregate["buckets"].as_array().unwrap();
+    assert_eq!(buckets.len() as i64, common::SECONDS);
+    for bucket in buckets {
+        assert_eq!(bucket["count"].as_i64().unwrap(), per_second);
+        assert!(bucket["avg"].as_f64().unwrap().abs() < 1e-6);
+        assert!(bucket["max"].as_f64().unwrap() > 0.0);
+    }
+
+    let buckets = backend
+        .json(&format!("/samples/buckets?channel=Fz&buckets=2&{}", window))
+        .await;
+    let counts: Vec<i64> = buckets["buckets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|b| b["count"].as_i64().unwrap())
+        .collect();
+    assert_eq!(counts, vec![SAMPLES / 2, SAMPLES / 2]);
+
+    let overview = backend
+        .json(&format!(
+            "/samples/overview?channel=Fz&max_points=4&{}",
+            window
+        ))
+        .await;
+    assert_eq!(overview["channel"], "Fz");
+    assert!(overview["tier"].is_string(), "{}", overview);
+    assert!(overview["buckets"].is_array());
+
+    let reversed = "from=2030-01-01T00:00:01Z&to=2030-01-01T00:00:00Z";
+    for (path, field) in [
+        (
+            format!("/samples/aggregate?channel=Fz&bucket=0.5ms&{}", window),
+            "bucket",
+        ),
+        (format!("/samples/aggregate?channel=Fz&{}", reversed), "to"),
+        (
+            format!("/samples/buckets?channel=Fz&buckets=0&{}", window),
+            "buckets",
+        ),
+        (format!("/samples/buckets?channel=Fz&{}", reversed), "to"),
+        (format!("/samples/overview?channel=Fz&{}", reversed), "to"),
+    ] {
+        let (status, body) = send(backend.get(&path)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", path);
+        assert!(body["details"]["fields"][field].is_string(), "{}", body);
+    }
+
+    // The 8-12 Hz band keeps the alpha, about 8 µV on Fz, and drops beta.
+    let filtered = backend
+        .json(&format!(
+            "/samples/filter?channel=Fz&bandpass=8,12&{}",
+            window
+        ))
+        .await;
+    assert_eq!(filtered["sample_rate"], 250.0);
+    let values: Vec<f64> = samples(&filtered)
+        .iter()
+        .skip(500)
+        .map(|s| s["value"].as_f64().unwrap())
+        .collect();
+    let peak = values.iter().fold(0f64, |peak, v| peak.max(v.abs()));
+    assert!((peak - 8.0).abs() < 1.0, "{}", peak);
+
+    let quality = backend
+        .json(&format!("/quality?channel={}&{}", names(), window))
+        .await;
+    let channels = quality["channels"].as_array().unwrap();
+    assert_eq!(channels.len(), CHANNELS.len());
+    assert!(
+        channels.iter().all(|c| c["status"] == "good"),
+        "{}",
+        quality
+    );
+}
+
+fn names() -> String {
+    CHANNELS.map(|c| c.0).join(",")
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn time_zones() {
+    let backend = Backend::start().await;
+    let start = backend.seed.start;
+    let first = |query: &str| {
+        format!(
+            "/samples?channel=Fz&after_id=0&limit=1&session_id={}&{}",
+            backend.seed.session_id, query
+        )
+    };
+    let ts = |body: &Value| body["samples"][0]["ts"].clone();
+    let utc = backend.json(&first("")).await;
+    let stored = chrono::DateTime::parse_from_rfc3339(ts(&utc).as_str().unwrap()).unwrap();
+    assert_eq!(stored, start);
+
+    let local = backend.json(&first("tz=%2B05:30")).await;
+    let local = ts(&local);
+    assert!(local.as_str().unwrap().ends_with("+05:30"), "{}", local);
+    assert_eq!(
+        chrono::DateTime::parse_from_rfc3339(local.as_str().unwrap()).unwrap(),
+        start
+    );
+    let epoch = backend.json(&first("time_format=epoch_ms")).await;
+    assert_eq!(ts(&epoch), json!(start.timestamp_millis()));
+    let event = backend
+        .json(&format!(
+            "/events?session_id={}&limit=1&tz=Europe/Berlin",
+            backend.seed.session_id
+        ))
+        .await;
+    assert!(
+        !event[0]["ts"].as_str().unwrap().ends_with('Z'),
+        "{}",
+        event
+    );
+
+    // One second in, given as an offset, epoch milliseconds or a local time.
+    let second = start + chrono::Duration::seconds(1);
+    let offset = second
+        .with_timezone(&chrono::FixedOffset::east_opt(19800).unwrap())
+        .to_rfc3339();
+    let naive = second.naive_utc() + chrono::Duration::minutes(330);
+    // The unencoded `+` of the offset arrives as a space.
+    for from in [
+        format!("from={}", offset),
+        format!("from={}", second.timestamp_millis()),
+        format!("from={}&tz=%2B05:30", naive.format("%Y-%m-%dT%H:%M:%S")),
+    ] {
+        let body = backend
+            .json(&first(&format!("{}&time_format=epoch_ms", from)))
+            .await;
+        assert_eq!(ts(&body), json!(second.timestamp_millis()), "{}", from);
+    }
+
+    for query in [
+        "tz=Mars/Olympus",
+        "from=yesterday",
+        "from=2024-10-27T02:30:00&tz=Europe/Berlin",
+        "time_format=iso",
+    ] {
+        let (status, body) = send(backend.get(&first(query))).await;
+        assert_eq!(
+            status,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}: {}",
+            query,
+            body
+        );
+    }
+    let path = "/samples/aggregate?channel=Fz&from=2024-03-31T02:30:00&tz=Europe/Berlin";
+    let (status, body) = send(backend.get(path)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fields"]["from"].is_string(), "{}", body);
+}
+
+/// The `name` job once it has run more than `runs` times.
+async fn job_after(backend: &Backend, name: &str, runs: i64) -> Value {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
+    loop {
+        let jobs = backend.json("/admin/jobs").await;
+        let job = jobs["jobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|job| job["name"] == name)
+            .cloned()
+            .unwrap();
+        if job["running"] == false && job["runs"].as_i64().unwrap() > runs {
+            return job;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{}", job);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn packed_samples() {
+    let backend = Backend::start_with(&[
+        ("JOB_BLOCKS_SECS", "3600"),
+        ("STORAGE_BLOCK_AFTER_SECS", "1"),
+    ])
+    .await;
+    let seed = &backend.seed;
+
+    // The first run at startup packs the whole recording, a block per
+    // channel and second.
+    let job = job_after(&backend, "blocks", 0).await;
+    let channels = CHANNELS.len() as i64;
+    assert_eq!(
+        job["last_run"]["summary"],
+        format!(
+            "{} samples packed into {} blocks",
+            channels * SAMPLES,
+            channels * common::SECONDS
+        ),
+        "{}",
+        job
+    );
+    assert_eq!(count(&backend, "Fz").await, 0);
+    let bytes: i64 = sqlx::query_scalar("SELECT sum(octet_length(data))::int8 FROM sample_blocks")
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap();
+    assert!(bytes < channels * SAMPLES * 12, "{} bytes", bytes);
+
+    // Windows, pages and exports read the blocks back.
+    let window = backend
+        .json(&format!(
+            "/samples?channel=Fz&{}&points=10000",
+            seed.window()
+        ))
+        .await;
+    let points = samples(&window);
+    assert_eq!(points.len() as i64, SAMPLES);
+    for (i, sample) in points.iter().enumerate() {
+        let stored = sample["value"].as_f64().unwrap();
+        assert!((stored - value("Fz", i as i64)).abs() < 1e-9, "{}", sample);
+    }
+    let oldest = backend.json("/samples?channel=Fz&after_id=0&limit=3").await;
+    let ts: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(samples(&oldest)[1]["ts"].clone()).unwrap();
+    assert_eq!(ts - seed.start, chrono::Duration::milliseconds(4));
+    let first = backend.json("/samples?channel=Oz&limit=300").await;
+    let ids: Vec<i64> = samples(&first)
+        .iter()
+        .map(|s| s["id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(ids.len(), 300);
+    assert!(ids.windows(2).all(|w| w[0] > w[1]));
+    let next = backend
+        .json(&format!(
+            "/samples?channel=Oz&limit=300&before_id={}",
+            first["next_cursor"]
+        ))
+        .await;
+    assert!(samples(&next)[0]["id"].as_i64().unwrap() < ids[299]);
+
+    // Aggregates over the packed range count the blocks as rows.
+    let values: Vec<f64> = (0..SAMPLES).map(|i| value("Fz", i)).collect();
+    let lowest = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let highest = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let mean = values.iter().sum::<f64>() / SAMPLES as f64;
+    let aggregate = backend
+        .json(&format!(
+            "/samples/aggregate?channel=Fz&bucket=1s&{}",
+            seed.window()
+        ))
+        .await;
+    let buckets = aggregate["buckets"].as_array().unwrap();
+    let counts: Vec<i64> = buckets
+        .iter()
+        .map(|b| b["count"].as_i64().unwrap())
+        .collect();
+    assert_eq!(counts.iter().sum::<i64>(), SAMPLES, "{}", aggregate);
+    let min = buckets.iter().map(|b| b["min"].as_f64().unwrap());
+    assert!((min.fold(f64::INFINITY, f64::min) - lowest).abs() < 1e-9);
+    let max = buckets.iter().map(|b| b["max"].as_f64().unwrap());
+    assert!((max.fold(f64::NEG_INFINITY, f64::max) - highest).abs() < 1e-9);
+    let sum: f64 = buckets
+        .iter()
+        .map(|b| b["avg"].as_f64().unwrap() * b["count"].as_f64().unwrap())
+        .sum();
+    assert!((sum / SAMPLES as f64 - mean).abs() < 1e-9, "{}", aggregate);
+    let strip = backend
+        .json(&format!(
+            "/samples/buckets?channel=Fz&buckets=20&{}",
+            seed.window()
+        ))
+        .await;
+    let counts = strip["buckets"].as_array().unwrap().iter();
+    assert!(
+        counts
+            .map(|b| b["count"].as_i64().unwrap())
+            .all(|n| n == SAMPLES / 20),
+        "{}",
+        strip
+    );
+    let stats = backend
+        .json(&format!(
+            "/analysis/stats?channel=Fz&window=1s&{}",
+            seed.window()
+        ))
+        .await;
+    let windows = stats["windows"].as_array().unwrap();
+    let counted: i64 = windows.iter().map(|w| w["count"].as_i64().unwrap()).sum();
+    assert_eq!(counted, SAMPLES, "{}", stats);
+    let histogram = backend
+        .json(&format!(
+            "/analysis/histogram?channel=Fz&bins=10&{}",
+            seed.window()
+        ))
+        .await;
+    assert_eq!(histogram["count"], SAMPLES);
+    let binned: i64 = histogram["bins"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|b| b["count"].as_i64().unwrap())
+        .sum();
+    assert_eq!(binned, SAMPLES, "{}", histogram);
+
+    // So do live reads, gaps, replays' channels and channel listings.
+    let live = backend.json("/live?channel=Fz&since_id=0&limit=100").await;
+    assert_eq!(live["points"].as_array().unwrap().len(), 100, "{}", live);
+    let gaps = backend
+        .json(&format!("/sessions/{}/gaps?channel=Fz", seed.session_id))
+        .await;
+    assert_eq!(gaps["channels"][0]["gaps"], json!([]), "{}", gaps);
+    let gaps = backend
+        .json(&format!("/sessions/{}/gaps", seed.session_id))
+        .await;
+    assert_eq!(
+        gaps["channels"].as_array().unwrap().len(),
+        CHANNELS.len(),
+        "{}",
+        gaps
+    );
+    let query = json!({ "query": "{ channels { name sampleCount } samples(channel: \"Fz\", limit: 2) { id } }" });
+    let body = ok_json(backend.post("/graphql").json(&query)).await;
+    let fz = body["data"]["channels"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|c| c["name"] == "Fz")
+        .cloned();
+    assert_eq!(fz.unwrap()["sampleCount"], SAMPLES, "{}", body);
+    assert_eq!(body["data"]["samples"].as_array().unwrap().len(), 2);
+
+    // Re-referencing, fixed-length epochs, session channels and ICA read
+    // the blocks too.
+    let referenced = backend
+        .json(&format!(
+            "/samples?channel=Fz&reference=Cz&points=10000&{}",
+            seed.window()
+        ))
+        .await;
+    let points = samples(&referenced);
+    assert_eq!(points.len() as i64, SAMPLES, "{}", referenced);
+    let v = points[1]["value"].as_f64().unwrap();
+    assert!((v - (value("Fz", 1) - value("Cz", 1))).abs() < 1e-9);
+    let fixed = backend
+        .json(&format!(
+            "/analysis/epochs?channel=Fz&length=1&step=1&session_id={}",
+            seed.session_id
+        ))
+        .await;
+    assert_eq!(
+        fixed["epochs"].as_array().unwrap().len() as i64,
+        common::SECONDS - 1
+    );
+    let listed = backend
+        .json(&format!("/sessions/{}/channels", seed.session_id))
+        .await;
+    assert_eq!(listed.as_array().unwrap().len(), CHANNELS.len());
+    let (status, job) = send(backend.post(&format!(
+        "/analysis/ica?channel=Fz,Cz,Pz&components=2&seed=1&session_id={}",
+        seed.session_id
+    )))
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", job);
+    let path = format!("/analysis/ica/{}", job["id"]);
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(30);
+    let job = loop {
+        let job = backend.json(&path).await;
+        if job["status"] != "running" && job["status"] != "queued" {
+            break job;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "{}", job);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+    assert_eq!(job["status"], "done", "{}", job);
+    assert_eq!(job["instants"].as_i64().unwrap(), SAMPLES);
+
+    // A pop stored as a row between packed samples is found against them.
+    sqlx::query(
+        "INSERT INTO eeg_samples (ts, channel, value, session_id) VALUES ($1, 'Pz', 500, $2)",
+    )
+    .bind(seed.start + chrono::Duration::microseconds(4_802_000))
+    .bind(seed.session_id)
+    .execute(&backend.pool)
+    .await
+    .unwrap();
+    let detected = ok_json(backend.post(&format!(
+        "/analysis/artifacts?channel=Pz&session_id={}&dry_run=true",
+        seed.session_id
+    )))
+    .await;
+    let events = detected["events"].as_array().unwrap();
+    assert!(
+        events.iter().any(|e| e["label"] == "artifact:pop"),
+        "{}",
+        detected
+    );
+
+    let csv = backend
+        .get(&format!(
+            "/samples/export.csv?channel=Fz,Oz&session_id={}",
+            seed.session_id
+        ))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(csv.lines().count() as i64, 2 * SAMPLES + 1);
+    let (status, _) =
+        send(backend.get(&format!("/sessions/{}/export?format=edf", seed.session_id))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // A series without `start` continues after the packed samples.
+    let series = json!({
+        "session_id": seed.session_id,
+        "channels": [{ "channel": "Fz", "values": [1.0, 2.0] }],
+    });
+    let (status, body) = send(backend.post("/samples/series").json(&series)).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let end = seed.start + chrono::Duration::seconds(common::SECONDS);
+    assert_eq!(
+        offsets_us(&backend, "Fz", Some(seed.session_id), end).await,
+        [0, 4000]
+    );
+
+    // New samples stay rows next to the blocks.
+    let now = chrono::Utc::now();
+    let (status, _) = send(backend.post("/samples").json(&json!({
+        "channel": "Fz", "ts": now, "value": 1.5, "session_id": seed.session_id
+    })))
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let all = backend
+        .json(&format!(
+            "/samples?channel=Fz&session_id={}&points=10000&from={}",
+            seed.session_id,
+            (seed.start - chrono::Duration::seconds(1)).timestamp_millis()
+        ))
+        .await;
+    let points = samples(&all);
+    assert_eq!(points.len() as i64, SAMPLES + 3);
+    assert_eq!(points[SAMPLES as usize + 2]["value"], 1.5);
+
+    // Retention removes blocks past the cutoff.
+    ok_json(
+        backend
+            .put("/admin/retention/raw")
+            .json(&json!({ "max_age": "30s" })),
+    )
+    .await;
+    let runs = job_after(&backend, "retention", 0).await["runs"]
+        .as_i64()
+        .unwrap();
+    let (status, _) = send(backend.post("/admin/jobs/retention/run")).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    job_after(&backend, "retention", runs).await;
+    let blocks: i64 = sqlx::query_scalar("SELECT count(*) FROM sample_blocks")
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap();
+    assert_eq!(blocks, 0);
+    assert_eq!(count(&backend, "Fz").await, 1);
+}
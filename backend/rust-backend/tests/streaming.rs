@@ -0,0 +1,388 @@
+//! The live routes: polls, the WebSocket and SSE streams, neurofeedback,
+//! session replay and GraphQL subscriptions.
+
+mod common;
+
+use common::{next_json, Backend, Socket, EVENTS, SAMPLES};
+use futures::{SinkExt, StreamExt};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+async fn last_id(backend: &Backend, channel: &str) -> i64 {
+    let id: i32 = sqlx::query_scalar("SELECT max(id) FROM eeg_samples WHERE channel = $1")
+        .bind(channel)
+        .fetch_one(&backend.pool)
+        .await
+        .unwrap();
+    id.into()
+}
+
+async fn post_sample(backend: &Backend, channel: &str, value: f64) -> i64 {
+    let response = backend
+        .post("/samples")
+        .json(&json!({ "channel": channel, "ts": chrono::Utc::now(), "value": value }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    response.json::<Value>().await.unwrap()["id"]
+        .as_i64()
+        .unwrap()
+}
+
+/// Reads `socket` until a message of `kind`, within `secs` per message.
+async fn next_of(socket: &mut Socket, kind: &str, secs: u64) -> Value {
+    loop {
+        let message = next_json(socket, secs).await;
+        if message["type"] == kind {
+            return message;
+        }
+        assert_ne!(message["type"], "error", "{}", message);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn live_poll() {
+    let backend = Backend::start().await;
+    let since = last_id(&backend, "Cz").await;
+
+    let empty = backend
+        .json(&format!("/live?channel=Cz&since_id={}", since))
+        .await;
+    assert_eq!(empty["points"], json!([]));
+    assert_eq!(empty["last_id"], since);
+
+    let id = post_sample(&backend, "Cz", 42.0).await;
+    let polled = backend
+        .json(&format!("/live?channel=Cz&since_id={}", since))
+        .await;
+    assert_eq!(polled["points"][0]["id"], id);
+    assert_eq!(polled["points"][0]["value"], 42.0);
+    assert_eq!(polled["last_id"], id);
+
+    let grouped = backend.json("/live?channel=Fz,Oz&limit=10").await;
+    let channels = grouped["channels"].as_array().unwrap();
+    assert_eq!(channels.len(), 2);
+    assert_eq!(channels[1]["points"].as_array().unwrap().len(), 10);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn live_websocket() {
+    let backend = Backend::start().await;
+    let since = last_id(&backend, "Fz").await;
+    let mut socket = backend
+        .ws(&format!("/live/ws?channel=Fz&since_id={}", since))
+        .await;
+
+    let id = post_sample(&backend, "Fz", 7.5).await;
+    let points = next_of(&mut socket, "points", 10).await;
+    assert_eq!(points["channel"], "Fz");
+    assert_eq!(points["points"][0]["id"], id);
+    assert_eq!(points["points"][0]["value"], 7.5);
+    assert_eq!(points["last_id"], id);
+
+    // Switching the stream to another channel.
+    let since = last_id(&backend, "Pz").await;
+    let subscribe = json!({ "type": "subscribe", "channel": "Pz", "since_id": since });
+    socket
+        .send(Message::Text(subscribe.to_string()))
+        .await
+        .unwrap();
+    let subscribed = next_of(&mut socket, "subscribed", 10).await;
+    assert_eq!(subscribed["channel"], "Pz");
+    assert_eq!(subscribed["since_id"], since);
+    let id = post_sample(&backend, "Pz", -3.0).await;
+    let points = next_of(&mut socket, "points", 10).await;
+    assert_eq!(points["channel"], "Pz");
+    assert_eq!(points["last_id"], id);
+    assert!(points.get("stats").is_none());
+    socket.close(None).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn live_stats() {
+    let backend = Backend::start().await;
+    // The first second of Oz: whole periods of 20 µV at 10 Hz and 2 µV at
+    // 22 Hz, so an RMS of sqrt(200 + 2) and no 50 Hz.
+    let mut socket = backend
+        .ws("/live/ws?channel=Oz&since_id=0&limit=250&stats=true")
+        .await;
+    let points = next_of(&mut socket, "points", 10).await;
+    let stats = &points["stats"];
+    assert_eq!(stats["samples"], 250, "{}", stats);
+    assert!(
+        (stats["rms"].as_f64().unwrap() - 202f64.sqrt()).abs() < 1e-6,
+        "{}",
+        stats
+    );
+    assert!(stats["line_noise"].as_f64().unwrap() < 1e-6, "{}", stats);
+    socket.close(None).await.unwrap();
+
+    // With the mains at 10 Hz the alpha is line noise, of RMS 20 / sqrt(2).
+    let mut socket = backend
+        .ws("/live/ws?channel=Oz&since_id=0&limit=250&stats=true&line=10")
+        .await;
+    let points = next_of(&mut socket, "points", 10).await;
+    let line_noise = points["stats"]["line_noise"].as_f64().unwrap();
+    assert!(
+        (line_noise - 20.0 / 2f64.sqrt()).abs() < 1e-6,
+        "{}",
+        line_noise
+    );
+
+    let subscribe = json!({ "type": "subscribe", "channel": "Oz", "since_id": 0, "stats": false });
+    socket
+        .send(Message::Text(subscribe.to_string()))
+        .await
+        .unwrap();
+    let subscribed = next_of(&mut socket, "subscribed", 10).await;
+    assert_eq!(subscribed["stats"], false);
+    let points = next_of(&mut socket, "points", 10).await;
+    assert!(points.get("stats").is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn live_sse() {
+    let backend = Backend::start().await;
+    let since = last_id(&backend, "Oz").await;
+    let response = backend
+        .get(&format!(
+            "/live/sse?channel=Oz&since_id={}&stats=true",
+            since
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/event-stream"));
+
+    let id = post_sample(&backend, "Oz", 11.0).await;
+    let mut body = response.bytes_stream();
+    let mut text = String::new();
+    let data = loop {
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(10), body.next())
+            .await
+            .expect("no event in time")
+            .expect("stream ended")
+            .unwrap();
+        text.push_str(std::str::from_utf8(&chunk).unwrap());
+        // Events are separated by a blank line.
+        let complete = text.rfind("\n\n").map(|end| text[..end].to_string());
+        let points = complete.and_then(|events| {
+            events
+                .split("\n\n")
+                .find(|event| event.contains("event: points"))
+                .map(str::to_string)
+        });
+        if let Some(event) = points {
+            break event;
+        }
+    };
+    let data: String = data
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .collect();
+    let data: Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(data["points"][0]["id"], id);
+    assert_eq!(data["points"][0]["value"], 11.0);
+    assert_eq!(
+        data["stats"],
+        json!({ "samples": 1, "rms": 0.0, "line_noise": null })
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn feedback() {
+    let backend = Backend::start().await;
+    let mut socket = backend
+        .ws("/live/feedback?channel=Fz,Oz&metric=bandpower&band=alpha&rate=10")
+        .await;
+    let configured = next_of(&mut socket, "configured", 10).await;
+    assert_eq!(configured["channels"], json!(["Fz", "Oz"]));
+    assert_eq!(configured["metric"]["band"], "alpha");
+
+    // Alpha power grows with the square of its weight: 0.4 on Fz, 1 on Oz.
+    let feedback = next_of(&mut socket, "feedback", 10).await;
+    let values = feedback["values"].as_array().unwrap();
+    let power = |channel: &str| {
+        values.iter().find(|v| v["channel"] == channel).unwrap()["value"]
+            .as_f64()
+            .unwrap()
+    };
+    let ratio = power("Oz") / power("Fz");
+    assert!((ratio - 1.0 / 0.16).abs() < 0.5, "{}", feedback);
+
+    let configure = json!({ "type": "configure", "metric": { "type": "rms" } });
+    socket
+        .send(Message::Text(configure.to_string()))
+        .await
+        .unwrap();
+    let configured = next_of(&mut socket, "configured", 10).await;
+    assert_eq!(configured["metric"]["type"], "rms");
+    socket.close(None).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn replay() {
+    let backend = Backend::start().await;
+    let mut socket = backend
+        .ws(&format!(
+            "/sessions/{}/replay?speed=100",
+            backend.seed.session_id
+        ))
+        .await;
+
+    let header = next_of(&mut socket, "replay", 10).await;
+    assert_eq!(header["session_id"], backend.seed.session_id);
+    assert_eq!(header["speed"], 100.0);
+    let mut points = BTreeMap::<String, i64>::new();
+    let mut events = 0;
+    loop {
+        let message = next_json(&mut socket, 10).await;
+        match message["type"].as_str().unwrap() {
+            "points" => {
+                let channel = message["channel"].as_str().unwrap().to_string();
+                *points.entry(channel).or_default() +=
+                    message["points"].as_array().unwrap().len() as i64;
+            }
+            "event" => events += 1,
+            "ended" => break,
+            _ => panic!("unexpected {}", message),
+        }
+    }
+    assert_eq!(events, EVENTS);
+    assert_eq!(points.len(), common::CHANNELS.len());
+    assert!(points.values().all(|n| *n == SAMPLES), "{:?}", points);
+
+    // Seeking back plays the end of the recording again.
+    let seek = json!({ "type": "seek", "offset": 9.5 });
+    socket.send(Message::Text(seek.to_string())).await.unwrap();
+    let header = next_of(&mut socket, "replay", 10).await;
+    let offset = header["offset"].as_f64().unwrap();
+    assert!((9.5..10.0).contains(&offset), "{}", offset);
+    next_of(&mut socket, "points", 10).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn graphql() {
+    let backend = Backend::start().await;
+    let query = json!({
+        "query": "{ channels { name sampleCount } \
+                  samples(channel: \"Cz\", limit: 2) { id channel value } \
+                  sessions { id subjectId } }"
+    });
+    let body = common::ok_json(backend.post("/graphql").json(&query)).await;
+    assert!(body["errors"].is_null(), "{}", body);
+    let data = &body["data"];
+    let cz = data["channels"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|c| c["name"] == "Cz")
+        .unwrap();
+    assert_eq!(cz["sampleCount"], SAMPLES);
+    assert_eq!(data["samples"].as_array().unwrap().len(), 2);
+    assert_eq!(data["sessions"][0]["id"], backend.seed.session_id);
+
+    let graphiql = backend.get("/graphql").send().await.unwrap();
+    assert!(graphiql.text().await.unwrap().contains("graphiql"));
+
+    let mut request = backend
+        .v1("/graphql/ws")
+        .replacen("http", "ws", 1)
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        "sec-websocket-protocol",
+        "graphql-transport-ws".parse().unwrap(),
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    let init = json!({ "type": "connection_init" });
+    socket.send(Message::Text(init.to_string())).await.unwrap();
+    assert_eq!(next_json(&mut socket, 10).await["type"], "connection_ack");
+    let since = last_id(&backend, "Cz").await;
+    let subscription = format!(
+        "subscription {{ livePoints(channel: \"Cz\", sinceId: {}) {{ lastId points {{ id value }} }} }}",
+        since
+    );
+    let subscribe = json!({ "id": "1", "type": "subscribe", "payload": { "query": subscription } });
+    socket
+        .send(Message::Text(subscribe.to_string()))
+        .await
+        .unwrap();
+    let id = post_sample(&backend, "Cz", 5.0).await;
+    let next = next_json(&mut socket, 10).await;
+    assert_eq!(next["type"], "next", "{}", next);
+    let batch = &next["payload"]["data"]["livePoints"];
+    assert_eq!(batch["lastId"], id);
+    assert_eq!(batch["points"][0]["value"], 5.0);
+}
+
+/// Posts a batch of samples on three channels and counts the notifications
+/// of `eeg_samples` per channel.
+async fn notifications(backend: &Backend) -> BTreeMap<String, usize> {
+    let mut listener = sqlx::postgres::PgListener::connect_with(&backend.pool)
+        .await
+        .unwrap();
+    listener.listen("eeg_samples").await.unwrap();
+    let ts = chrono::Utc::now();
+    let batch: Vec<Value> = (0..12)
+        .map(|i| {
+            let channel = ["Fz", "Cz", "Oz"][i % 3];
+            json!({ "channel": channel, "ts": ts, "value": i as f64 })
+        })
+        .collect();
+    let response = backend
+        .post("/samples/batch")
+        .json(&batch)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let mut counts = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(1);
+    while let Ok(notification) = tokio::time::timeout_at(deadline, listener.recv()).await {
+        let notification = notification.unwrap();
+        let (channel, _writer) = notification.payload().split_once(' ').unwrap();
+        *counts.entry(channel.to_string()).or_default() += 1;
+    }
+    counts
+}
+
+#[tokio::test]
+#[ignore = "needs Docker or TEST_DATABASE_URL; run with --ignored"]
+async fn notify_per_channel() {
+    let once = BTreeMap::from([
+        ("Cz".to_string(), 1),
+        ("Fz".to_string(), 1),
+        ("Oz".to_string(), 1),
+    ]);
+
+    let backend = Backend::start().await;
+    assert_eq!(notifications(&backend).await, once);
+
+    // Without the trigger, as on a hypertable, the backend notifies itself.
+    let backend = Backend::start_prepared(
+        &[],
+        &[
+            "DROP TRIGGER eeg_samples_notify ON eeg_samples",
+            "DROP FUNCTION eeg_samples_notify_statement()",
+        ],
+    )
+    .await;
+    assert_eq!(notifications(&backend).await, once);
+}
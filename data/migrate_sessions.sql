@@ -0,0 +1,22 @@
+-- Adds recording sessions to a database created before they existed. Run it
+-- before the backend first starts against such a database, so that the
+-- migrations (backend/rust-backend/migrations) find the initial schema;
+-- databases created by the migrations do not need it.
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS sessions (
+  id SERIAL PRIMARY KEY,
+  subject TEXT,
+  device TEXT,
+  started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  ended_at TIMESTAMPTZ,
+  notes TEXT,
+  CHECK (ended_at IS NULL OR ended_at >= started_at)
+);
+
+ALTER TABLE eeg_samples ADD COLUMN IF NOT EXISTS session_id INTEGER REFERENCES sessions(id);
+
+CREATE INDEX IF NOT EXISTS idx_eeg_samples_session_channel_id
+ON eeg_samples(session_id, channel, id) WHERE session_id IS NOT NULL;
+
+COMMIT;
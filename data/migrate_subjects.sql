@@ -0,0 +1,32 @@
+-- Adds subjects to a database created before they existed, turning the
+-- free-text sessions.subject into a reference to subjects. Existing subject
+-- strings become subject codes. Run it after migrate_sessions.sql and before
+-- the backend first starts against such a database; databases created by the
+-- migrations (backend/rust-backend/migrations) do not need it.
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS subjects (
+  id SERIAL PRIMARY KEY,
+  code TEXT NOT NULL UNIQUE,
+  birth_year INTEGER,
+  sex TEXT,
+  handedness TEXT,
+  notes TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE sessions ADD COLUMN subject_id INTEGER REFERENCES subjects(id);
+
+INSERT INTO subjects (code)
+SELECT DISTINCT subject FROM sessions WHERE subject IS NOT NULL
+ON CONFLICT (code) DO NOTHING;
+
+UPDATE sessions SET subject_id = subjects.id
+FROM subjects WHERE subjects.code = sessions.subject;
+
+ALTER TABLE sessions DROP COLUMN subject;
+
+CREATE INDEX IF NOT EXISTS idx_sessions_subject_id
+ON sessions(subject_id);
+
+COMMIT;
@@ -0,0 +1,12 @@
+-- Upgrades a database created before `ts` became TIMESTAMPTZ: the text
+-- `ts` column is dropped and `sample_time` (already populated by every
+-- ingest path) takes its name. Run it once, before the backend first starts
+-- against such a database; databases created by the migrations
+-- (backend/rust-backend/migrations) do not need it.
+BEGIN;
+
+ALTER TABLE eeg_samples DROP COLUMN ts;
+ALTER TABLE eeg_samples RENAME COLUMN sample_time TO ts;
+ALTER INDEX IF EXISTS idx_eeg_samples_channel_time RENAME TO idx_eeg_samples_channel_ts;
+
+COMMIT;